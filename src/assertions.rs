@@ -0,0 +1,393 @@
+//! Assertions about LLM responses, checked after schema validation.
+//!
+//! JSON Schema can describe the shape of a response, but not things like "the
+//! citation must look like a citation" or "the model must not refuse". Prompts
+//! may list extra checks as `[[assert]]` tables, and compare responses to
+//! their input records using `[[invariant]]` tables.
+
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use regex::Regex;
+use schemars::JsonSchema;
+use toml_span::{DeserError, de_helpers::TableHelper};
+
+use crate::{async_utils::io::JsonObject, prelude::*, toml_utils::custom_deser_error};
+
+/// What to do when an assertion fails.
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnAssertionFailure {
+    /// Treat the failure as transient, and ask the model again.
+    #[default]
+    Retry,
+
+    /// Fail the record immediately.
+    Fail,
+}
+
+/// An assertion about a response, as written in a prompt.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ResponseAssertion {
+    /// The response field to check, as a dotted path like `citation` or
+    /// `items.0.name`. Defaults to the entire response, as JSON. Missing fields
+    /// are treated as empty strings.
+    #[serde(default)]
+    pub field: Option<String>,
+
+    /// A regular expression which must match the field.
+    #[serde(default)]
+    pub matches: Option<String>,
+
+    /// A regular expression which must not match the field.
+    #[serde(default)]
+    pub not_matches: Option<String>,
+
+    /// A string which the field must contain.
+    #[serde(default)]
+    pub contains: Option<String>,
+
+    /// A string which the field must not contain.
+    #[serde(default)]
+    pub not_contains: Option<String>,
+
+    /// What to do if this assertion fails. Defaults to `retry`.
+    #[serde(default)]
+    pub on_failure: OnAssertionFailure,
+}
+
+impl<'de> toml_span::Deserialize<'de> for OnAssertionFailure {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        match value.take_string(None)?.as_ref() {
+            "retry" => Ok(OnAssertionFailure::Retry),
+            "fail" => Ok(OnAssertionFailure::Fail),
+            _ => Err(custom_deser_error(
+                value.span,
+                "on_failure must be \"retry\" or \"fail\"",
+            )),
+        }
+    }
+}
+
+impl<'de> toml_span::Deserialize<'de> for ResponseAssertion {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        let mut th = TableHelper::new(value)?;
+        let field = th.optional("field");
+        let matches = th.optional("matches");
+        let not_matches = th.optional("not_matches");
+        let contains = th.optional("contains");
+        let not_contains = th.optional("not_contains");
+        let on_failure = th.optional("on_failure").unwrap_or_default();
+        th.finalize(None)?;
+        Ok(Self {
+            field,
+            matches,
+            not_matches,
+            contains,
+            not_contains,
+            on_failure,
+        })
+    }
+}
+
+/// What to do when an invariant doesn't hold.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum OnInvariantFailure {
+    /// Keep the response, and record the mismatch in the output record's
+    /// `errors`.
+    #[default]
+    Warn,
+
+    /// Fail the record.
+    Fail,
+}
+
+/// An invariant relating a response field to an input field, like "`date` must
+/// equal the input's `expected_date`". Invariants are checked once a response
+/// has passed schema validation and assertions, and are never retried.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ResponseInvariant {
+    /// The response field to check, as a dotted path like `date` or
+    /// `parties.0.name`. Missing fields are treated as empty strings.
+    pub field: String,
+
+    /// The input field which `field` must equal. Records where this input
+    /// field is missing, null or empty are not checked. Values are compared as
+    /// text, so the CSV value `"12"` equals the number `12`.
+    pub equals_input: String,
+
+    /// What to do if this invariant doesn't hold. Defaults to `warn`.
+    #[serde(default)]
+    pub on_failure: OnInvariantFailure,
+}
+
+impl<'de> toml_span::Deserialize<'de> for OnInvariantFailure {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        match value.take_string(None)?.as_ref() {
+            "warn" => Ok(OnInvariantFailure::Warn),
+            "fail" => Ok(OnInvariantFailure::Fail),
+            _ => Err(custom_deser_error(
+                value.span,
+                "on_failure must be \"warn\" or \"fail\"",
+            )),
+        }
+    }
+}
+
+impl<'de> toml_span::Deserialize<'de> for ResponseInvariant {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        let mut th = TableHelper::new(value)?;
+        let field = th.required("field")?;
+        let equals_input = th.required("equals_input")?;
+        let on_failure = th.optional("on_failure").unwrap_or_default();
+        th.finalize(None)?;
+        Ok(Self {
+            field,
+            equals_input,
+            on_failure,
+        })
+    }
+}
+
+impl FromStr for ResponseInvariant {
+    type Err = anyhow::Error;
+
+    /// Parse an invariant written as `FIELD=INPUT_FIELD`, as used by
+    /// `--invariant`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((field, equals_input))
+                if !field.trim().is_empty() && !equals_input.trim().is_empty() =>
+            {
+                Ok(Self {
+                    field: field.trim().to_owned(),
+                    equals_input: equals_input.trim().to_owned(),
+                    on_failure: OnInvariantFailure::default(),
+                })
+            }
+            _ => Err(anyhow!(
+                "invariant must look like FIELD=INPUT_FIELD, found {s:?}"
+            )),
+        }
+    }
+}
+
+impl ResponseInvariant {
+    /// Get the value our response field must equal for a record with
+    /// `bindings`, or `None` if the record shouldn't be checked.
+    pub fn expected(&self, bindings: &JsonObject) -> Option<String> {
+        let expected = field_text(bindings.get(&self.equals_input));
+        (!expected.trim().is_empty()).then_some(expected)
+    }
+
+    /// Check that our field in `response` equals `expected`, returning an
+    /// error describing any mismatch.
+    pub fn check(&self, response: &Value, expected: &str) -> Result<()> {
+        let actual = field_text(lookup_field(response, &self.field));
+        if actual.trim() == expected.trim() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "invariant_failed: {} is {actual:?}, but input {} is {expected:?}",
+                self.field,
+                self.equals_input
+            ))
+        }
+    }
+}
+
+/// Look up the value at a dotted `path` like `items.0.name` in `value`.
+fn lookup_field<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, key| match value {
+        Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+        _ => value.get(key),
+    })
+}
+
+/// Convert a field to text for checking. Missing fields and nulls are empty.
+fn field_text(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// A single check within an assertion.
+#[derive(Debug)]
+enum Check {
+    Matches(Regex),
+    NotMatches(Regex),
+    Contains(String),
+    NotContains(String),
+}
+
+/// A [`ResponseAssertion`] with its regular expressions compiled.
+#[derive(Debug)]
+pub struct CompiledAssertion {
+    /// The field to check.
+    field: Option<String>,
+
+    /// Our checks.
+    checks: Vec<Check>,
+
+    /// What to do on failure.
+    on_failure: OnAssertionFailure,
+}
+
+impl CompiledAssertion {
+    /// Compile an assertion.
+    pub fn new(assertion: &ResponseAssertion) -> Result<Self> {
+        let compile = |re: &str| {
+            Regex::new(re).with_context(|| format!("invalid assertion regex {re:?}"))
+        };
+        let mut checks = vec![];
+        if let Some(re) = &assertion.matches {
+            checks.push(Check::Matches(compile(re)?));
+        }
+        if let Some(re) = &assertion.not_matches {
+            checks.push(Check::NotMatches(compile(re)?));
+        }
+        if let Some(s) = &assertion.contains {
+            checks.push(Check::Contains(s.clone()));
+        }
+        if let Some(s) = &assertion.not_contains {
+            checks.push(Check::NotContains(s.clone()));
+        }
+        if checks.is_empty() {
+            return Err(anyhow!(
+                "assertion must specify matches, not_matches, contains or not_contains"
+            ));
+        }
+        Ok(Self {
+            field: assertion.field.clone(),
+            checks,
+            on_failure: assertion.on_failure,
+        })
+    }
+
+    /// What to do if this assertion fails.
+    pub fn on_failure(&self) -> OnAssertionFailure {
+        self.on_failure
+    }
+
+    /// Check `response`, returning an error describing the first failure.
+    pub fn check(&self, response: &Value) -> Result<()> {
+        let (name, text) = match &self.field {
+            None => ("response", response.to_string()),
+            Some(field) => (field.as_str(), field_text(lookup_field(response, field))),
+        };
+        for check in &self.checks {
+            let failure = match check {
+                Check::Matches(re) if !re.is_match(&text) => {
+                    format!("does not match {:?}", re.as_str())
+                }
+                Check::NotMatches(re) if re.is_match(&text) => {
+                    format!("matches {:?}", re.as_str())
+                }
+                Check::Contains(s) if !text.contains(s.as_str()) => {
+                    format!("does not contain {s:?}")
+                }
+                Check::NotContains(s) if text.contains(s.as_str()) => {
+                    format!("contains {s:?}")
+                }
+                _ => continue,
+            };
+            return Err(anyhow!("assertion_failed: {name} {failure}: {text:?}"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assertion(field: &str) -> ResponseAssertion {
+        ResponseAssertion {
+            field: Some(field.to_owned()),
+            matches: None,
+            not_matches: None,
+            contains: None,
+            not_contains: None,
+            on_failure: OnAssertionFailure::Retry,
+        }
+    }
+
+    #[test]
+    fn assertions_check_fields() {
+        let response = json!({
+            "citation": "410 U.S. 113",
+            "full_markdown": "I cannot read this page.",
+            "items": [{ "name": "a" }],
+        });
+
+        let citation = CompiledAssertion::new(&ResponseAssertion {
+            matches: Some(r"^\d+ U\.S\. \d+$".to_owned()),
+            ..assertion("citation")
+        })
+        .unwrap();
+        assert!(citation.check(&response).is_ok());
+
+        let refusal = CompiledAssertion::new(&ResponseAssertion {
+            not_contains: Some("I cannot".to_owned()),
+            ..assertion("full_markdown")
+        })
+        .unwrap();
+        let err = refusal.check(&response).unwrap_err().to_string();
+        assert!(err.starts_with("assertion_failed: full_markdown contains"));
+
+        let nested = CompiledAssertion::new(&ResponseAssertion {
+            contains: Some("a".to_owned()),
+            ..assertion("items.0.name")
+        })
+        .unwrap();
+        assert!(nested.check(&response).is_ok());
+
+        assert!(CompiledAssertion::new(&assertion("citation")).is_err());
+    }
+
+    #[test]
+    fn invariants_compare_fields_to_input() {
+        let invariant = "date = expected_date".parse::<ResponseInvariant>().unwrap();
+        assert_eq!(invariant.field, "date");
+        assert_eq!(invariant.equals_input, "expected_date");
+        assert_eq!(invariant.on_failure, OnInvariantFailure::Warn);
+        assert!("date".parse::<ResponseInvariant>().is_err());
+
+        // Records without the input field aren't checked.
+        let mut bindings = JsonObject::new();
+        assert_eq!(invariant.expected(&bindings), None);
+        bindings.insert("expected_date".to_owned(), json!(""));
+        assert_eq!(invariant.expected(&bindings), None);
+
+        bindings.insert("expected_date".to_owned(), json!("2024-01-01"));
+        let expected = invariant.expected(&bindings).unwrap();
+        assert!(
+            invariant
+                .check(&json!({ "date": "2024-01-01" }), &expected)
+                .is_ok()
+        );
+        let err = invariant
+            .check(&json!({ "date": "2024-01-02" }), &expected)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "invariant_failed: date is \"2024-01-02\", but input expected_date is \"2024-01-01\""
+        );
+
+        // CSV strings match JSON numbers.
+        let count = "counts.0=expected_count"
+            .parse::<ResponseInvariant>()
+            .unwrap();
+        bindings.insert("expected_count".to_owned(), json!("12"));
+        let expected = count.expected(&bindings).unwrap();
+        assert!(count.check(&json!({ "counts": [12] }), &expected).is_ok());
+    }
+}
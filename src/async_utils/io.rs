@@ -1,7 +1,7 @@
 //! I/O utilities.
 //!
-//! This module is responsible for reading JSON, TOML, JSONL, and CSV files, and
-//! writing JSONL files. There are a few complicating factors:
+//! This module is responsible for reading JSON, TOML, JSONL, CSV and YAML
+//! files, and writing JSONL files. There are a few complicating factors:
 //!
 //! 1. We use async streams from Tokio, because that's an easy way to handle a
 //!    large (but limited) number of failible network operations in parallel.
@@ -11,22 +11,41 @@
 //! In general, Tokio and async Rust involve some occasional magic. We try to
 //! keep all of it in this file.
 
-use std::{error, fmt, pin::Pin, sync::Arc, task::Context, vec};
+use std::{
+    collections::{HashMap, VecDeque},
+    error,
+    ffi::OsStr,
+    fmt,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    vec,
+};
 
+use async_compression::tokio::{
+    bufread::{GzipDecoder, ZstdDecoder},
+    write::{GzipEncoder, ZstdEncoder},
+};
+use clap::ValueEnum;
 use codespan_reporting::{
     diagnostic::Diagnostic, files::SimpleFile, term::termcolor::WriteColor,
 };
-use futures::{TryStreamExt, pin_mut, stream::StreamExt as _};
+use futures::{
+    TryStreamExt, pin_mut,
+    stream::{self, StreamExt as _},
+};
 use peekable::tokio::AsyncPeekable;
 use serde_json::Map;
+use tokio::sync::mpsc;
 use tokio::{
-    fs::File,
+    fs::{File, OpenOptions},
     io::{
         AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt as _, AsyncWrite,
         AsyncWriteExt as _, BufReader, BufWriter, ReadBuf,
     },
 };
-use tokio_stream::wrappers::LinesStream;
+use tokio_stream::wrappers::{LinesStream, ReceiverStream};
 
 use crate::{
     prelude::*,
@@ -34,13 +53,31 @@ use crate::{
     ui::{ProgressConfig, Ui},
 };
 
-use super::{BoxedStream, size_hint::WithSizeHintExt};
+use super::{
+    BoxedStream,
+    blocking_iter_streams::spawn_blocking_propagating_panics,
+    record_splitters::{JsonArraySplitter, for_each_yaml_record},
+    size_hint::WithSizeHintExt,
+};
+
+/// The format of a record input stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InputFormat {
+    /// One JSON object per line.
+    JsonLines,
+    /// A single top-level JSON array of records.
+    JsonArray,
+    /// YAML documents, or a top-level YAML list of records.
+    Yaml,
+    /// CSV with a header row.
+    Csv,
+}
 
-/// A smart async reader that uses [`AsyncPeekable`] to detect whether the input is JSON
-/// or JSONL, or something else.
+/// A smart async reader that uses [`AsyncPeekable`] to detect whether the input is JSONL,
+/// a JSON array, YAML, or something else.
 pub struct SmartReader {
-    /// Do we expect our input to be either JSONL or JSONL?
-    is_json_like: bool,
+    /// What format do we expect our input to be in?
+    format: InputFormat,
 
     /// A human-readable description of the input source, for error messages.
     description: String,
@@ -57,47 +94,179 @@ impl SmartReader {
         description: String,
         reader: impl AsyncRead + Unpin + Send + Sync + 'static,
     ) -> Result<Self> {
-        let reader = BufReader::new(reader);
+        let reader = BufReader::new(DecodingReader::new(
+            description.clone(),
+            BufReader::new(reader),
+        ));
         let mut peekable = AsyncPeekable::new(Box::new(reader));
         let mut buffer = vec![0; 1];
         peekable.peek_exact(&mut buffer).await?;
-        let is_json_like = buffer[0] == b'{';
+        let format = match buffer[0] {
+            b'{' => InputFormat::JsonLines,
+            b'[' => InputFormat::JsonArray,
+            _ => InputFormat::Csv,
+        };
         Ok(Self {
-            is_json_like,
+            format,
             description,
             reader: Box::pin(BufReader::new(peekable)),
         })
     }
 
-    /// Create a new `SmartReader` from a [`Path`].
+    /// Create a new `SmartReader` from a [`Path`]. Files ending in `.gz` or
+    /// `.zst` will be decompressed.
     pub async fn new_from_path(path: &Path) -> Result<Self> {
-        let ext = path.extension().unwrap_or_default();
-        let is_json_like = ext == "json" || ext == "jsonl";
+        let ext = uncompressed_extension(path).unwrap_or_default();
         let file = File::open(path)
             .await
             .with_context(|| format!("Failed to open file at path: {path:?}"))?;
+        let file = BufReader::new(file);
+        let reader: Pin<Box<dyn AsyncBufRead + Unpin + Send + Sync + 'static>> =
+            match Compression::from_path(path) {
+                None => Box::pin(file),
+                Some(Compression::Gzip) => {
+                    let mut decoder = GzipDecoder::new(file);
+                    decoder.multiple_members(true);
+                    Box::pin(BufReader::new(decoder))
+                }
+                Some(Compression::Zstd) => {
+                    let mut decoder = ZstdDecoder::new(file);
+                    decoder.multiple_members(true);
+                    Box::pin(BufReader::new(decoder))
+                }
+            };
+        let description = path.to_string_lossy().into_owned();
+        let reader = DecodingReader::new(description.clone(), reader);
+        let mut reader: Pin<Box<dyn AsyncBufRead + Unpin + Send + Sync + 'static>> =
+            Box::pin(BufReader::new(reader));
+
+        // `.json` files may contain either JSONL or a single array.
+        let format = match ext.to_str() {
+            Some("jsonl") => InputFormat::JsonLines,
+            Some("json") => {
+                let buf = reader.fill_buf().await?;
+                match buf.iter().find(|b| !b.is_ascii_whitespace()) {
+                    Some(b'[') => InputFormat::JsonArray,
+                    _ => InputFormat::JsonLines,
+                }
+            }
+            Some("yaml" | "yml") => InputFormat::Yaml,
+            _ => InputFormat::Csv,
+        };
         Ok(Self {
-            is_json_like,
-            description: path.to_string_lossy().into_owned(),
-            reader: Box::pin(BufReader::new(file)),
+            format,
+            description,
+            reader,
         })
     }
 
     /// Create a new `SmartReader` from either a [`Path`] or standard input.
-    pub async fn new_from_path_or_stdin(path: Option<&Path>) -> Result<Self> {
+    /// Standard input has no extension to tell us it's YAML, so we need
+    /// `yaml_stdin` for that.
+    pub async fn new_from_path_or_stdin(
+        path: Option<&Path>,
+        yaml_stdin: bool,
+    ) -> Result<Self> {
         match path {
             Some(path) => Self::new_from_path(path).await,
             None => {
                 let stdin = tokio::io::stdin();
-                Self::new_from_reader("stdin".to_owned(), stdin).await
+                let mut reader = Self::new_from_reader("stdin".to_owned(), stdin).await?;
+                if yaml_stdin {
+                    reader.format = InputFormat::Yaml;
+                }
+                Ok(reader)
             }
         }
     }
 
     /// Is our input JSON-like?
     pub fn is_json_like(&self) -> bool {
-        self.is_json_like
+        matches!(self.format, InputFormat::JsonLines | InputFormat::JsonArray)
     }
+
+    /// Read records from a single JSON array or YAML stream, if that's what we
+    /// have. We parse one record at a time, so memory use stays bounded.
+    fn into_split_records(self) -> Result<JsonStream, Self> {
+        let description = Arc::new(self.description.clone());
+        match self.format {
+            InputFormat::JsonArray => Ok(read_json_array(self, description)),
+            InputFormat::Yaml => Ok(read_yaml(self, description)),
+            InputFormat::JsonLines | InputFormat::Csv => Err(self),
+        }
+    }
+}
+
+/// Read the elements of a top-level JSON array.
+fn read_json_array(reader: SmartReader, description: Arc<String>) -> JsonStream {
+    let state = (reader, JsonArraySplitter::default(), VecDeque::new());
+    stream::try_unfold(state, move |(mut reader, mut splitter, mut pending)| {
+        let description = description.clone();
+        async move {
+            loop {
+                if let Some(element) = pending.pop_front() {
+                    let value =
+                        serde_json::from_slice::<Value>(&element).with_context(|| {
+                            format!(
+                                "Failed to parse JSON array element in {description:?}"
+                            )
+                        })?;
+                    return Ok(Some((value, (reader, splitter, pending))));
+                }
+                let buf = reader.fill_buf().await?;
+                if buf.is_empty() {
+                    splitter.finish().with_context(|| {
+                        format!("Failed to read JSON array from {description:?}")
+                    })?;
+                    return Ok(None);
+                }
+                let len = buf.len();
+                splitter.feed(buf, &mut pending).with_context(|| {
+                    format!("Failed to read JSON array from {description:?}")
+                })?;
+                reader.consume(len);
+            }
+        }
+    })
+    .boxed()
+}
+
+/// How many parsed YAML records to buffer ahead of our consumer.
+const YAML_RECORD_BUFFER: usize = 100;
+
+/// Read YAML records, either one per document or one per top-level list item.
+///
+/// Our YAML parser needs the whole text up front, so unlike our other formats,
+/// YAML input is held in memory. We still parse it on a background thread, and
+/// hand records on one at a time as they're deserialized.
+fn read_yaml(mut reader: SmartReader, description: Arc<String>) -> JsonStream {
+    let (sender, receiver) = mpsc::channel(YAML_RECORD_BUFFER);
+    let parse = tokio::spawn(async move {
+        let mut text = String::new();
+        reader.read_to_string(&mut text).await?;
+        spawn_blocking_propagating_panics(move || {
+            for_each_yaml_record(&text, |record| {
+                sender
+                    .blocking_send(Ok(record))
+                    .map_err(|_| anyhow!("nobody is reading our YAML records"))
+            })
+        })
+        .await
+    });
+
+    // Once every record has been sent, report any error.
+    let finished = stream::once(parse).filter_map(move |joined| {
+        let description = description.clone();
+        async move {
+            joined
+                .map_err(anyhow::Error::new)
+                .and_then(|result| result)
+                .with_context(|| format!("Failed to read YAML from {description:?}"))
+                .err()
+                .map(Err)
+        }
+    });
+    ReceiverStream::new(receiver).chain(finished).boxed()
 }
 
 impl AsyncRead for SmartReader {
@@ -127,6 +296,94 @@ impl AsyncBufRead for SmartReader {
     }
 }
 
+/// Converts input text to UTF-8.
+///
+/// Excel and other Windows tools often produce UTF-16 files, or UTF-8 files
+/// with a byte-order mark. We detect these using the BOM, and strip it. Invalid
+/// UTF-8 is replaced with U+FFFD, with a warning, instead of failing the run.
+pub struct DecodingReader<R> {
+    /// A human-readable description of the input source, for warnings.
+    description: String,
+
+    /// Our underlying reader.
+    inner: R,
+
+    /// Our decoder, which starts as UTF-8 with BOM sniffing.
+    decoder: encoding_rs::Decoder,
+
+    /// Decoded output which hasn't been read yet.
+    output: Vec<u8>,
+
+    /// How much of `output` has been read.
+    output_pos: usize,
+
+    /// Have we reached the end of our input?
+    finished: bool,
+
+    /// Have we already warned about invalid input?
+    warned: bool,
+}
+
+impl<R: AsyncBufRead + Unpin> DecodingReader<R> {
+    /// Create a new `DecodingReader`.
+    pub fn new(description: String, inner: R) -> Self {
+        Self {
+            description,
+            inner,
+            decoder: encoding_rs::UTF_8.new_decoder(),
+            output: vec![],
+            output_pos: 0,
+            finished: false,
+            warned: false,
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for DecodingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = Pin::get_mut(self);
+        loop {
+            // Return any output we already have.
+            if this.output_pos < this.output.len() {
+                let len = buf.remaining().min(this.output.len() - this.output_pos);
+                buf.put_slice(&this.output[this.output_pos..this.output_pos + len]);
+                this.output_pos += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            // Decode another chunk of input. An empty chunk means we've hit
+            // the end, and need to flush the decoder.
+            let input = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+            let last = input.is_empty();
+            let max_len = this
+                .decoder
+                .max_utf8_buffer_length(input.len())
+                .ok_or_else(|| std::io::Error::other("input chunk too large"))?;
+            this.output.resize(max_len, 0);
+            let (_, read, written, had_replacements) =
+                this.decoder.decode_to_utf8(input, &mut this.output, last);
+            this.output.truncate(written);
+            this.output_pos = 0;
+            Pin::new(&mut this.inner).consume(read);
+            if had_replacements && !this.warned {
+                warn!(
+                    "Replaced invalid characters in {:?} with U+FFFD",
+                    this.description
+                );
+                this.warned = true;
+            }
+            this.finished = last;
+        }
+    }
+}
+
 /// Read JSON or TOML file as a [`Value`].
 pub async fn read_json_or_toml_as_json_value(path: &Path) -> Result<Value> {
     let mut reader = SmartReader::new_from_path(path).await?;
@@ -146,32 +403,75 @@ pub async fn read_json_or_toml_as_json_value(path: &Path) -> Result<Value> {
     }
 }
 
-/// Read TOML or JSON from a file.
-pub async fn read_json_or_toml<T>(path: &Path) -> Result<T>
-where
-    T: serde::de::DeserializeOwned + toml_span::Deserialize<'static>,
-{
-    let mut reader = SmartReader::new_from_path(path).await?;
-    let mut data = String::new();
-    // Read all at once because our parsing libraries don't do async I/O.
-    reader
-        .read_to_string(&mut data)
-        .await
-        .with_context(|| format!("Failed to read file at path: {path:?}"))?;
-    if reader.is_json_like() {
-        serde_json::from_str(&data)
-            .with_context(|| format!("Failed to parse JSON from file at path: {path:?}"))
-    } else {
-        match from_toml_str(&data) {
-            Ok(value) => Ok(value),
-            Err(err) => {
-                let file = SimpleFile::new(path.display().to_string(), data);
-                let diagnostics = err
-                    .errors
-                    .into_iter()
-                    .map(|e| e.to_diagnostic(()))
-                    .collect::<Vec<_>>();
-                Err(DiagnosticsError { file, diagnostics }.into())
+/// The text of a TOML or JSON document, before parsing.
+pub struct JsonOrTomlText {
+    /// Where the text came from, for error messages.
+    pub name: String,
+
+    /// The text itself.
+    pub data: String,
+
+    /// Is this JSON, rather than TOML?
+    is_json: bool,
+}
+
+impl JsonOrTomlText {
+    /// Wrap text without a file extension to tell us whether it's JSON. A TOML
+    /// document can't start with `{`, so anything which does is JSON.
+    pub fn new(name: String, data: String) -> Self {
+        let is_json = data.trim_start().starts_with('{');
+        Self {
+            name,
+            data,
+            is_json,
+        }
+    }
+
+    /// Read a file, or standard input if `path` is `-`.
+    pub async fn read(path: &Path) -> Result<Self> {
+        if path == Path::new("-") {
+            let mut data = String::new();
+            tokio::io::stdin()
+                .read_to_string(&mut data)
+                .await
+                .context("Failed to read standard input")?;
+            return Ok(Self::new("stdin".to_owned(), data));
+        }
+
+        let mut reader = SmartReader::new_from_path(path).await?;
+        let mut data = String::new();
+        // Read all at once because our parsing libraries don't do async I/O.
+        reader
+            .read_to_string(&mut data)
+            .await
+            .with_context(|| format!("Failed to read file at path: {path:?}"))?;
+        Ok(Self {
+            name: path.display().to_string(),
+            data,
+            is_json: reader.is_json_like(),
+        })
+    }
+
+    /// Parse our text, reporting TOML errors as a [`DiagnosticsError`].
+    pub fn parse<T>(self) -> Result<T>
+    where
+        T: serde::de::DeserializeOwned + toml_span::Deserialize<'static>,
+    {
+        if self.is_json {
+            serde_json::from_str(&self.data)
+                .with_context(|| format!("Failed to parse JSON from {}", self.name))
+        } else {
+            match from_toml_str(&self.data) {
+                Ok(value) => Ok(value),
+                Err(err) => {
+                    let file = SimpleFile::new(self.name, self.data);
+                    let diagnostics = err
+                        .errors
+                        .into_iter()
+                        .map(|e| e.to_diagnostic(()))
+                        .collect::<Vec<_>>();
+                    Err(DiagnosticsError { file, diagnostics }.into())
+                }
             }
         }
     }
@@ -221,6 +521,7 @@ impl error::Error for DiagnosticsError {}
 pub async fn count_jsonl_or_csv_records(
     ui: &Ui,
     path: &Path,
+    csv_delimiter: Option<u8>,
 ) -> Result<(usize, Option<usize>)> {
     // If this isn't a file, we can't count records. This may happen if our
     // input is a named pipe from a tool like Pachyderm.
@@ -236,18 +537,27 @@ pub async fn count_jsonl_or_csv_records(
     });
 
     // Count records.
-    let reader = SmartReader::new_from_path_or_stdin(Some(path)).await?;
-    let count = if reader.is_json_like() {
-        let lines = LinesStream::new(reader.lines());
-        lines
-            .try_fold(0, |acc, _line| async move { Ok(acc + 1) })
-            .await?
-    } else {
-        csv_async::AsyncReaderBuilder::new()
-            .create_reader(reader)
-            .into_byte_records()
-            .try_fold(0, |acc, _record| async move { Ok(acc + 1) })
-            .await?
+    let reader = SmartReader::new_from_path_or_stdin(Some(path), false).await?;
+    let count = match reader.into_split_records() {
+        Ok(records) => {
+            records
+                .try_fold(0, |acc, _record| async move { Ok(acc + 1) })
+                .await?
+        }
+        Err(reader) if reader.is_json_like() => {
+            let lines = LinesStream::new(reader.lines());
+            lines
+                .try_fold(0, |acc, _line| async move { Ok(acc + 1) })
+                .await?
+        }
+        Err(reader) => {
+            csv_async::AsyncReaderBuilder::new()
+                .delimiter(csv_delimiter_for_path(Some(path), csv_delimiter))
+                .create_reader(reader)
+                .into_byte_records()
+                .try_fold(0, |acc, _record| async move { Ok(acc + 1) })
+                .await?
+        }
     };
     spinner.finish_with_message(format!("Found {count} records"));
     Ok((count, Some(count)))
@@ -259,16 +569,50 @@ pub type JsonObject = Map<String, Value>;
 /// A stream of [`serde_json::Value`] values.
 pub type JsonStream = BoxedStream<Result<Value>>;
 
-/// Read JSONL or CSV from a file or stdin.
+/// Choose a CSV delimiter, defaulting to tabs for `.tsv` files and commas
+/// otherwise.
+fn csv_delimiter_for_path(path: Option<&Path>, csv_delimiter: Option<u8>) -> u8 {
+    csv_delimiter.unwrap_or_else(|| {
+        if path.and_then(uncompressed_extension) == Some(OsStr::new("tsv")) {
+            b'\t'
+        } else {
+            b','
+        }
+    })
+}
+
+/// Parse a `--csv-delimiter` argument, which may be a single ASCII character
+/// or `tab`.
+pub fn parse_csv_delimiter(arg: &str) -> Result<u8, String> {
+    match arg {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if arg.len() == 1 && arg.is_ascii() => Ok(arg.as_bytes()[0]),
+        _ => Err(format!(
+            "expected a single ASCII character or `tab`, got {arg:?}"
+        )),
+    }
+}
+
+/// Read JSONL, CSV, a JSON array or YAML from a file or stdin. Standard input
+/// is only read as YAML if `yaml_stdin` is set.
 ///
 /// This function returns an async [`Stream`] of JSON [`Map`] objects.
-pub async fn read_jsonl_or_csv(ui: Ui, path: Option<&Path>) -> Result<JsonStream> {
+pub async fn read_jsonl_or_csv(
+    ui: Ui,
+    path: Option<&Path>,
+    csv_delimiter: Option<u8>,
+    yaml_stdin: bool,
+) -> Result<JsonStream> {
     let size_hint = match path {
-        Some(path) => count_jsonl_or_csv_records(&ui, path).await?,
+        Some(path) => count_jsonl_or_csv_records(&ui, path, csv_delimiter).await?,
         None => (0, None),
     };
 
-    let reader = SmartReader::new_from_path_or_stdin(path).await?;
+    let reader = SmartReader::new_from_path_or_stdin(path, yaml_stdin).await?;
+    let reader = match reader.into_split_records() {
+        Ok(records) => return Ok(records.with_size_hint(size_hint).boxed()),
+        Err(reader) => reader,
+    };
     let description = Arc::new(reader.description.clone());
     if reader.is_json_like() {
         let lines = LinesStream::new(reader.lines()).with_size_hint(size_hint);
@@ -286,7 +630,9 @@ pub async fn read_jsonl_or_csv(ui: Ui, path: Option<&Path>) -> Result<JsonStream
             }
         })))
     } else {
-        let mut reader = csv_async::AsyncReaderBuilder::new().create_reader(reader);
+        let mut reader = csv_async::AsyncReaderBuilder::new()
+            .delimiter(csv_delimiter_for_path(path, csv_delimiter))
+            .create_reader(reader);
         let headers = Arc::new(
             reader
                 .headers()
@@ -321,29 +667,309 @@ pub async fn read_jsonl_or_csv(ui: Ui, path: Option<&Path>) -> Result<JsonStream
     }
 }
 
+/// Compression formats for input and output files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// gzip (`.gz`).
+    Gzip,
+    /// Zstandard (`.zst`).
+    Zstd,
+}
+
+impl Compression {
+    /// Guess the compression format of a file from its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" | "gzip" => Some(Compression::Gzip),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Get the extension of `path`, ignoring any compression extension. So
+/// `input.jsonl.gz` has the extension `jsonl`.
+pub fn uncompressed_extension(path: &Path) -> Option<&OsStr> {
+    if Compression::from_path(path).is_some() {
+        Path::new(path.file_stem()?).extension()
+    } else {
+        path.extension()
+    }
+}
+
 /// Create an [`AsyncWrite`] for a file or stdout.
+///
+/// Output is compressed using `compression`, or if that's `None`, using the
+/// compression format implied by the extension of `path`. Callers must call
+/// `shutdown` when done, so that compressed output is properly terminated.
 pub async fn create_writer(
     path: Option<&Path>,
+    compression: Option<Compression>,
 ) -> Result<Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>> {
-    match path {
+    let writer: Box<dyn AsyncWrite + Unpin + Send + Sync + 'static> = match path {
         Some(path) => {
             let file = File::create(path)
                 .await
                 .with_context(|| format!("Failed to create file at path: {path:?}"))?;
-            Ok(Box::new(file))
+            Box::new(file)
         }
-        None => Ok(Box::new(tokio::io::stdout())),
+        None => Box::new(tokio::io::stdout()),
+    };
+    Ok(compress_writer(
+        writer,
+        compression.or_else(|| path.and_then(Compression::from_path)),
+    ))
+}
+
+/// Open `path` for appending, creating it if necessary. Compressed output is
+/// appended as a new gzip member or zstd frame, which decompresses as if it
+/// were part of the original stream.
+pub async fn create_appending_writer(
+    path: &Path,
+    compression: Option<Compression>,
+) -> Result<Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open file for appending: {path:?}"))?;
+    Ok(compress_writer(
+        Box::new(file),
+        compression.or_else(|| Compression::from_path(path)),
+    ))
+}
+
+/// Wrap `writer` in an encoder for `compression`, if any.
+fn compress_writer(
+    writer: Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>,
+    compression: Option<Compression>,
+) -> Box<dyn AsyncWrite + Unpin + Send + Sync + 'static> {
+    match compression {
+        None => writer,
+        Some(Compression::Gzip) => Box::new(GzipEncoder::new(writer)),
+        Some(Compression::Zstd) => Box::new(ZstdEncoder::new(writer)),
+    }
+}
+
+/// Notified after each output record has been written.
+///
+/// Message-queue inputs use this to acknowledge records only after their
+/// output has been safely written.
+#[async_trait]
+pub trait OutputAck: Send + Sync {
+    /// Acknowledge an output record, which will contain `id` and `status`
+    /// fields.
+    async fn ack(&self, record: &Value) -> Result<()>;
+}
+
+/// A field which queue inputs add to each input record, holding a unique
+/// number for the message it came from. We remove it before processing the
+/// record, and add it back to the output record before acknowledging it, so
+/// that the queue can tell which message to acknowledge. See
+/// [`crate::queues::work::track_ack_tokens`].
+pub const ACK_TOKEN_FIELD: &str = "_prompt_scaler_ack_token";
+
+/// Convert a record ID into a string key, for matching output records with
+/// their inputs.
+///
+/// String IDs are used as-is, and other IDs are serialized as JSON. This
+/// matches how we write IDs to CSV output, so that we can match up output
+/// records in either format.
+pub fn id_key(id: &Value) -> String {
+    match id {
+        Value::String(id) => id.clone(),
+        _ => id.to_string(),
+    }
+}
+
+/// How carefully to write an output file.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteOpts {
+    /// Flush our output every this many records.
+    pub flush_every: Option<NonZeroUsize>,
+
+    /// `fsync` local output files each time we flush them because of
+    /// `flush_every`, and once we're done.
+    pub fsync: bool,
+
+    /// Write local output files to a temporary path, and rename them into
+    /// place once we're done.
+    pub atomic: bool,
+}
+
+/// A local output file, which we may need to sync or rename.
+struct OutputTarget {
+    /// Where our output should end up.
+    path: PathBuf,
+
+    /// Where we're writing our output.
+    writing_path: PathBuf,
+
+    /// Should we `fsync` our output?
+    fsync: bool,
+}
+
+impl OutputTarget {
+    /// Prepare to write to `path`, if it's a local file.
+    fn new(path: Option<&Path>, write_opts: WriteOpts) -> Result<Option<Self>> {
+        let Some(path) = path else {
+            if write_opts.atomic {
+                return Err(anyhow!("--atomic-output requires --out"));
+            }
+            return Ok(None);
+        };
+        let writing_path = if write_opts.atomic {
+            let mut writing_path = path.as_os_str().to_owned();
+            writing_path.push(".tmp");
+            PathBuf::from(writing_path)
+        } else {
+            path.to_owned()
+        };
+        Ok(Some(Self {
+            path: path.to_owned(),
+            writing_path,
+            fsync: write_opts.fsync,
+        }))
+    }
+
+    /// Sync any data we have written to disk, if we've been asked to.
+    async fn sync(&self) -> Result<()> {
+        if self.fsync {
+            sync_file(&self.writing_path).await?;
+        }
+        Ok(())
     }
+
+    /// Sync our output, and move it into place if necessary.
+    async fn finish(self) -> Result<()> {
+        self.sync().await?;
+        if self.writing_path != self.path {
+            tokio::fs::rename(&self.writing_path, &self.path)
+                .await
+                .with_context(|| {
+                    format!("cannot rename {:?} to {:?}", self.writing_path, self.path)
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Sync any data written to the local file at `path` to disk.
+async fn sync_file(path: &Path) -> Result<()> {
+    File::open(path)
+        .await
+        .with_context(|| format!("cannot open {path:?} to sync it"))?
+        .sync_data()
+        .await
+        .with_context(|| format!("cannot sync {path:?}"))
 }
 
 /// Write a stream of JSON [`Map`] objects to either standard output or a file.
-pub async fn write_output(path: Option<&Path>, stream: JsonStream) -> Result<()> {
-    let mut writer = BufWriter::new(create_writer(path).await?);
+///
+/// Keys are written in the order they appear in each object. If `pretty` is
+/// true, we indent each object over several lines, which is easier to read but
+/// is no longer valid JSONL.
+///
+/// If `ack` is provided, we flush after each record and then acknowledge it.
+pub async fn write_output(
+    path: Option<&Path>,
+    compression: Option<Compression>,
+    pretty: bool,
+    stream: JsonStream,
+    ack: Option<&dyn OutputAck>,
+) -> Result<()> {
+    write_output_with_opts(path, compression, pretty, stream, ack, WriteOpts::default())
+        .await
+}
+
+/// Like [`write_output`], but flush, sync and rename our output as specified
+/// by `write_opts`.
+pub async fn write_output_with_opts(
+    path: Option<&Path>,
+    compression: Option<Compression>,
+    pretty: bool,
+    stream: JsonStream,
+    ack: Option<&dyn OutputAck>,
+    write_opts: WriteOpts,
+) -> Result<()> {
+    let target = OutputTarget::new(path, write_opts)?;
+    let compression = compression.or_else(|| path.and_then(Compression::from_path));
+    let writer = create_writer(
+        target.as_ref().map(|target| target.writing_path.as_path()),
+        compression,
+    )
+    .await?;
+    write_json_lines(
+        writer,
+        pretty,
+        stream,
+        ack,
+        write_opts.flush_every,
+        target.as_ref(),
+    )
+    .await?;
+    match target {
+        Some(target) => target.finish().await,
+        None => Ok(()),
+    }
+}
+
+/// Like [`write_output_with_opts`], but append to any existing output at
+/// `path` instead of replacing it. We always write to `path` directly, so
+/// `write_opts.atomic` is ignored.
+pub async fn append_output(
+    path: &Path,
+    compression: Option<Compression>,
+    pretty: bool,
+    stream: JsonStream,
+    ack: Option<&dyn OutputAck>,
+    write_opts: WriteOpts,
+) -> Result<()> {
+    let write_opts = WriteOpts {
+        atomic: false,
+        ..write_opts
+    };
+    let target = OutputTarget::new(Some(path), write_opts)?;
+    let writer = create_appending_writer(path, compression).await?;
+    write_json_lines(
+        writer,
+        pretty,
+        stream,
+        ack,
+        write_opts.flush_every,
+        target.as_ref(),
+    )
+    .await?;
+    match target {
+        Some(target) => target.finish().await,
+        None => Ok(()),
+    }
+}
+
+/// Write a stream of JSON objects to `writer`, one per line (unless `pretty`
+/// is true), acknowledging each one once it has been flushed. We also flush
+/// every `flush_every` records, syncing `target` if we have one.
+async fn write_json_lines(
+    writer: Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>,
+    pretty: bool,
+    stream: JsonStream,
+    ack: Option<&dyn OutputAck>,
+    flush_every: Option<NonZeroUsize>,
+    target: Option<&OutputTarget>,
+) -> Result<()> {
+    let mut writer = BufWriter::new(writer);
+    let mut written = 0;
     pin_mut!(stream);
     while let Some(map) = stream.next().await {
         let map = map?;
-        let json = serde_json::to_string(&map)
-            .with_context(|| format!("Failed to serialize JSON from map: {map:?}"))?;
+        let json = if pretty {
+            serde_json::to_string_pretty(&map)
+        } else {
+            serde_json::to_string(&map)
+        };
+        let json =
+            json.with_context(|| format!("Failed to serialize JSON from map: {map:?}"))?;
         writer
             .write_all(json.as_bytes())
             .await
@@ -352,29 +978,615 @@ pub async fn write_output(path: Option<&Path>, stream: JsonStream) -> Result<()>
             .write_all(b"\n")
             .await
             .context("Failed to write newline to output")?;
+        written += 1;
+        let flush_due = is_flush_due(flush_every, written);
+        if ack.is_some() || flush_due {
+            writer.flush().await.context("Failed to flush output")?;
+        }
+        if flush_due && let Some(target) = target {
+            target.sync().await?;
+        }
+        if let Some(ack) = ack {
+            ack.ack(&map).await?;
+        }
+    }
+    writer.shutdown().await.context("Failed to flush output")?;
+    Ok(())
+}
+
+/// Should we flush our output after writing `written` records?
+fn is_flush_due(flush_every: Option<NonZeroUsize>, written: usize) -> bool {
+    flush_every.is_some_and(|flush_every| written.is_multiple_of(flush_every.get()))
+}
+
+/// Placeholder in a partitioned output path for the partition value.
+const PARTITION_FIELD_PLACEHOLDER: &str = "{field}";
+
+/// Placeholder in a partitioned output path for the part number.
+const PARTITION_PART_PLACEHOLDER: &str = "{n}";
+
+/// The most partition files we keep open at once. Beyond this, we close the
+/// least recently used file, and reopen it for appending if we need it again.
+const MAX_OPEN_PARTITION_WRITERS: usize = 64;
+
+/// A partition in a partitioned output.
+struct Partition {
+    /// Our current part number.
+    part: usize,
+
+    /// How many bytes we've written to the current part.
+    written: u64,
+
+    /// Have we created the file for our current part yet?
+    created: bool,
+
+    /// Our current part, if it's open.
+    writer: Option<BufWriter<Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>>>,
+
+    /// The unescaped value of the first record in this partition, so we can
+    /// warn if a different value maps to the same partition.
+    source: String,
+
+    /// Have we already warned about a different value in this partition?
+    warned_collision: bool,
+}
+
+/// Write a stream of JSON objects to a set of JSONL files, choosing a file for
+/// each record based on the value of the (possibly dotted) field
+/// `partition_by`.
+///
+/// `path_template` must contain `{field}`, which is replaced with the
+/// record's partition value, and `{n}`, which is replaced with a part number.
+/// Once a part grows past `max_part_bytes` (before compression), we start a
+/// new one. We keep at most [`MAX_OPEN_PARTITION_WRITERS`] files open at
+/// once.
+///
+/// If `ack` is provided, we flush after each record and then acknowledge it.
+/// Every `write_opts.flush_every` records, we flush every open file, syncing
+/// them if `write_opts.fsync` is set. We also sync each file when we close it.
+pub async fn write_partitioned_output(
+    path_template: &str,
+    compression: Option<Compression>,
+    partition_by: &str,
+    max_part_bytes: u64,
+    stream: JsonStream,
+    ack: Option<&dyn OutputAck>,
+    write_opts: WriteOpts,
+) -> Result<()> {
+    for placeholder in [PARTITION_FIELD_PLACEHOLDER, PARTITION_PART_PLACEHOLDER] {
+        if !path_template.contains(placeholder) {
+            return Err(anyhow!(
+                "--partition-by requires an --out path containing {placeholder}, such as out/{{field}}/part-{{n}}.jsonl"
+            ));
+        }
+    }
+
+    let mut partitions = HashMap::<String, Partition>::new();
+    // Partitions with open writers, least recently used first.
+    let mut open = VecDeque::<String>::new();
+    let mut records_written = 0;
+    pin_mut!(stream);
+    while let Some(map) = stream.next().await {
+        let map = map?;
+        let mut json = serde_json::to_string(&map)
+            .with_context(|| format!("Failed to serialize JSON from map: {map:?}"))?;
+        json.push('\n');
+
+        // Find our partition, starting a new part if the current one is full.
+        let partition = partition_value(&map, partition_by);
+        let source = partition_source(&map, partition_by).unwrap_or_default();
+        let state = partitions
+            .entry(partition.clone())
+            .or_insert_with(|| Partition {
+                part: 0,
+                written: 0,
+                created: false,
+                writer: None,
+                source: source.clone(),
+                warned_collision: false,
+            });
+        if state.source != source && !state.warned_collision {
+            warn!(
+                "--partition-by values {:?} and {source:?} are both written to partition {partition:?}",
+                state.source
+            );
+            state.warned_collision = true;
+        }
+        if state.created && state.written >= max_part_bytes {
+            if let Some(writer) = state.writer.take() {
+                let path = partition_path(path_template, &partition, state.part);
+                close_partition_writer(writer, &path, write_opts.fsync).await?;
+                open.retain(|p| p != &partition);
+            }
+            state.part += 1;
+            state.written = 0;
+            state.created = false;
+        }
+
+        // Open our writer if necessary, closing the least recently used one
+        // if we have too many.
+        if partitions[&partition].writer.is_none() {
+            while open.len() >= MAX_OPEN_PARTITION_WRITERS {
+                let lru = open
+                    .pop_front()
+                    .expect("open partitions should not be empty");
+                if let Some(state) = partitions.get_mut(&lru)
+                    && let Some(writer) = state.writer.take()
+                {
+                    let path = partition_path(path_template, &lru, state.part);
+                    close_partition_writer(writer, &path, write_opts.fsync).await?;
+                }
+            }
+            let state = partitions
+                .get_mut(&partition)
+                .expect("partition should exist");
+            let path = partition_path(path_template, &partition, state.part);
+            let writer = if state.created {
+                create_appending_writer(&path, compression).await?
+            } else {
+                create_partition_writer(&path, compression).await?
+            };
+            state.writer = Some(BufWriter::new(writer));
+            state.created = true;
+        } else {
+            open.retain(|p| p != &partition);
+        }
+        open.push_back(partition.clone());
+
+        let state = partitions
+            .get_mut(&partition)
+            .expect("partition should exist");
+        let writer = state
+            .writer
+            .as_mut()
+            .expect("partition writer should be open");
+        writer
+            .write_all(json.as_bytes())
+            .await
+            .context("Failed to write JSON to output")?;
+        state.written += u64::try_from(json.len())?;
+        records_written += 1;
+        if ack.is_some() {
+            writer.flush().await.context("Failed to flush output")?;
+        }
+        if is_flush_due(write_opts.flush_every, records_written) {
+            for name in &open {
+                let state = partitions
+                    .get_mut(name)
+                    .expect("open partition should exist");
+                if let Some(writer) = &mut state.writer {
+                    writer.flush().await.context("Failed to flush output")?;
+                    if write_opts.fsync {
+                        sync_file(&partition_path(path_template, name, state.part))
+                            .await?;
+                    }
+                }
+            }
+        }
+        if let Some(ack) = ack {
+            ack.ack(&map).await?;
+        }
+    }
+    for (name, state) in &mut partitions {
+        if let Some(writer) = state.writer.take() {
+            let path = partition_path(path_template, name, state.part);
+            close_partition_writer(writer, &path, write_opts.fsync).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Flush and close a partition file, syncing it at `path` if `fsync` is set.
+async fn close_partition_writer(
+    mut writer: BufWriter<Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>>,
+    path: &Path,
+    fsync: bool,
+) -> Result<()> {
+    writer.shutdown().await.context("Failed to flush output")?;
+    if fsync {
+        sync_file(path).await?;
     }
-    writer.flush().await.context("Failed to flush output")?;
     Ok(())
 }
 
-/// Write output as CSV.
+/// Create a file in a partitioned output, including any parent directories.
+async fn create_partition_writer(
+    path: &Path,
+    compression: Option<Compression>,
+) -> Result<Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create directory: {parent:?}"))?;
+    }
+    create_writer(Some(path), compression).await
+}
+
+/// Get the unescaped partition value for a record, if it has one.
+fn partition_source(record: &Value, partition_by: &str) -> Option<String> {
+    let value = partition_by
+        .split('.')
+        .try_fold(record, |value, key| value.get(key));
+    match value {
+        None | Some(Value::Null) => None,
+        Some(value) => Some(id_key(value)).filter(|value| !value.is_empty()),
+    }
+}
+
+/// Get the partition value for a record, made safe for use in a path.
+///
+/// Different values may map to the same partition, such as `a/b` and `a_b`.
+fn partition_value(record: &Value, partition_by: &str) -> String {
+    let Some(value) = partition_source(record, partition_by) else {
+        return "_missing".to_owned();
+    };
+    value
+        .chars()
+        .enumerate()
+        .map(|(idx, c)| match c {
+            // Don't allow hidden files or `..`.
+            '.' if idx == 0 => '_',
+            c if c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '=' | '@') => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Build the path for a part of a partition.
+fn partition_path(path_template: &str, partition: &str, part: usize) -> PathBuf {
+    PathBuf::from(
+        path_template
+            .replace(PARTITION_FIELD_PLACEHOLDER, partition)
+            .replace(PARTITION_PART_PLACEHOLDER, &format!("{part:05}")),
+    )
+}
+
+/// Write output as CSV, flushing, syncing and renaming it as specified by
+/// `write_opts`.
+///
+/// If `ack` is provided, we flush after each record and then acknowledge it.
 pub async fn write_output_csv<T>(
     path: Option<&Path>,
+    compression: Option<Compression>,
     stream: BoxedStream<Result<T>>,
+    ack: Option<&dyn OutputAck>,
+    write_opts: WriteOpts,
 ) -> Result<()>
 where
     T: serde::Serialize,
 {
-    let writer = BufWriter::new(create_writer(path).await?);
-    let mut writer = csv_async::AsyncSerializer::from_writer(writer);
+    let target = OutputTarget::new(path, write_opts)?;
+    let compression = compression.or_else(|| path.and_then(Compression::from_path));
+    let writer = create_writer(
+        target.as_ref().map(|target| target.writing_path.as_path()),
+        compression,
+    )
+    .await?;
+    let mut writer = csv_async::AsyncSerializer::from_writer(BufWriter::new(writer));
+    let mut written = 0;
     pin_mut!(stream);
     while let Some(record) = stream.next().await {
         let record = record?;
         writer
-            .serialize(record)
+            .serialize(&record)
             .await
             .context("Failed to write CSV record to output")?;
+        written += 1;
+        let flush_due = is_flush_due(write_opts.flush_every, written);
+        if ack.is_some() || flush_due {
+            writer.flush().await.context("Failed to flush output")?;
+        }
+        if flush_due && let Some(target) = &target {
+            target.sync().await?;
+        }
+        if let Some(ack) = ack {
+            let record = serde_json::to_value(&record)
+                .context("Failed to convert CSV record to JSON")?;
+            ack.ack(&record).await?;
+        }
     }
     writer.flush().await.context("Failed to flush output")?;
-    Ok(())
+    writer
+        .into_inner()
+        .await
+        .map_err(|_| anyhow!("Failed to flush output"))?
+        .shutdown()
+        .await
+        .context("Failed to flush output")?;
+    match target {
+        Some(target) => target.finish().await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use futures::stream;
+
+    use super::*;
+
+    #[test]
+    fn partition_values_are_safe_path_components() {
+        let record = json!({
+            "passthrough_data": { "customer": "../acme/co", "year": 2024 },
+        });
+        assert_eq!(
+            partition_value(&record, "passthrough_data.customer"),
+            "_._acme_co"
+        );
+        assert_eq!(partition_value(&record, "passthrough_data.year"), "2024");
+        assert_eq!(partition_value(&record, "missing"), "_missing");
+    }
+
+    #[test]
+    fn csv_delimiters_are_parsed() {
+        assert_eq!(parse_csv_delimiter(";"), Ok(b';'));
+        assert_eq!(parse_csv_delimiter("tab"), Ok(b'\t'));
+        assert!(parse_csv_delimiter("ab").is_err());
+        assert_eq!(
+            csv_delimiter_for_path(Some(Path::new("in.tsv.gz")), None),
+            b'\t'
+        );
+        assert_eq!(csv_delimiter_for_path(None, None), b',');
+    }
+
+    #[tokio::test]
+    async fn utf16_and_boms_are_decoded() {
+        let mut utf16 = vec![0xFF, 0xFE];
+        utf16.extend("id\n1\n".encode_utf16().flat_map(|c| c.to_le_bytes()));
+        let inputs = [
+            utf16,
+            b"\xEF\xBB\xBFid\n1\n".to_vec(),
+            b"id\n\xFF\n".to_vec(),
+        ];
+        let expected = ["id\n1\n", "id\n1\n", "id\n\u{FFFD}\n"];
+        for (input, expected) in inputs.into_iter().zip(expected) {
+            let mut reader =
+                SmartReader::new_from_reader("test".to_owned(), Cursor::new(input))
+                    .await
+                    .unwrap();
+            assert!(!reader.is_json_like());
+            let mut data = String::new();
+            reader.read_to_string(&mut data).await.unwrap();
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn json_arrays_and_yaml_are_read_as_records() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let inputs = [
+            ("input.json", "[\n  {\"id\": 1},\n  {\"id\": 2}\n]\n"),
+            ("input.yaml", "- id: 1\n- id: 2\n"),
+            ("flow.yaml", "[{id: 1}, {id: 2}]\n"),
+            ("documents.yml", "id: 1\n---\nid: 2\n"),
+        ];
+        for (name, data) in inputs {
+            let path = dir.path().join(name);
+            tokio::fs::write(&path, data).await.unwrap();
+            let records =
+                read_jsonl_or_csv(Ui::init_for_tests(), Some(&path), None, false)
+                    .await
+                    .unwrap()
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap();
+            assert_eq!(records, vec![json!({ "id": 1 }), json!({ "id": 2 })]);
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_output_round_trips() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in ["out.jsonl.gz", "out.jsonl.zst"] {
+            let path = dir.path().join(name);
+            let records = vec![Ok(json!({ "id": 1 })), Ok(json!({ "id": 2 }))];
+            write_output(
+                Some(&path),
+                None,
+                false,
+                stream::iter(records).boxed(),
+                None,
+            )
+            .await
+            .unwrap();
+
+            let mut reader = SmartReader::new_from_path(&path).await.unwrap();
+            assert!(reader.is_json_like());
+            let mut data = String::new();
+            reader.read_to_string(&mut data).await.unwrap();
+            assert_eq!(data, "{\"id\":1}\n{\"id\":2}\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn atomic_output_is_renamed_once_complete() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("out.jsonl");
+        let tmp_path = dir.path().join("out.jsonl.tmp");
+        let write_opts = WriteOpts {
+            flush_every: NonZeroUsize::new(1),
+            fsync: true,
+            atomic: true,
+        };
+
+        // A failed run leaves only the temporary file.
+        let records = vec![Ok(json!({ "id": 1 })), Err(anyhow!("interrupted"))];
+        let result = write_output_with_opts(
+            Some(&path),
+            None,
+            false,
+            stream::iter(records).boxed(),
+            None,
+            write_opts,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_to_string(&tmp_path).unwrap(), "{\"id\":1}\n");
+
+        // A complete run is renamed into place.
+        let records = vec![Ok(json!({ "id": 1 })), Ok(json!({ "id": 2 }))];
+        write_output_with_opts(
+            Some(&path),
+            None,
+            false,
+            stream::iter(records).boxed(),
+            None,
+            write_opts,
+        )
+        .await
+        .unwrap();
+        assert!(!tmp_path.exists());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"id\":1}\n{\"id\":2}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn appended_output_round_trips() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in ["out.jsonl", "out.jsonl.gz", "out.jsonl.zst"] {
+            let path = dir.path().join(name);
+            for id in [1, 2] {
+                let records = vec![Ok(json!({ "id": id }))];
+                append_output(
+                    &path,
+                    None,
+                    false,
+                    stream::iter(records).boxed(),
+                    None,
+                    WriteOpts::default(),
+                )
+                .await
+                .unwrap();
+            }
+
+            let mut reader = SmartReader::new_from_path(&path).await.unwrap();
+            let mut data = String::new();
+            reader.read_to_string(&mut data).await.unwrap();
+            assert_eq!(data, "{\"id\":1}\n{\"id\":2}\n");
+        }
+    }
+
+    /// Records 1 and 2. Before yielding record 2, check that record 1 has
+    /// already been flushed to `path`.
+    fn records_checking_flush(path: PathBuf) -> JsonStream {
+        stream::iter([1, 2])
+            .then(move |id| {
+                let path = path.clone();
+                async move {
+                    if id == 2 {
+                        let data = std::fs::read_to_string(&path).unwrap();
+                        assert!(data.contains("\"id\":1"), "not flushed: {data:?}");
+                    }
+                    Ok(json!({ "id": id, "customer": "a" }))
+                }
+            })
+            .boxed()
+    }
+
+    #[tokio::test]
+    async fn appended_and_partitioned_output_honor_flush_every() {
+        let write_opts = WriteOpts {
+            flush_every: NonZeroUsize::new(1),
+            fsync: true,
+            atomic: false,
+        };
+        let dir = tempfile::TempDir::new().unwrap();
+
+        let path = dir.path().join("out.jsonl");
+        let records = records_checking_flush(path.clone());
+        append_output(&path, None, false, records, None, write_opts)
+            .await
+            .unwrap();
+
+        let template = dir.path().join("{field}/part-{n}.jsonl");
+        let records = records_checking_flush(dir.path().join("a/part-00000.jsonl"));
+        write_partitioned_output(
+            template.to_str().unwrap(),
+            None,
+            "customer",
+            1024,
+            records,
+            None,
+            write_opts,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn partitioned_output_rotates_parts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let template = dir.path().join("{field}/part-{n}.jsonl");
+        let records = vec![
+            Ok(json!({ "id": 1, "customer": "a" })),
+            Ok(json!({ "id": 2, "customer": "b" })),
+            Ok(json!({ "id": 3, "customer": "a" })),
+        ];
+        write_partitioned_output(
+            template.to_str().unwrap(),
+            None,
+            "customer",
+            1,
+            stream::iter(records).boxed(),
+            None,
+            WriteOpts::default(),
+        )
+        .await
+        .unwrap();
+
+        let read = |path: &str| std::fs::read_to_string(dir.path().join(path)).unwrap();
+        assert_eq!(
+            read("a/part-00000.jsonl"),
+            "{\"id\":1,\"customer\":\"a\"}\n"
+        );
+        assert_eq!(
+            read("a/part-00001.jsonl"),
+            "{\"id\":3,\"customer\":\"a\"}\n"
+        );
+        assert_eq!(
+            read("b/part-00000.jsonl"),
+            "{\"id\":2,\"customer\":\"b\"}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn partitioned_output_reopens_closed_partitions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let template = dir.path().join("{field}/part-{n}.jsonl.gz");
+        let partitions = MAX_OPEN_PARTITION_WRITERS + 2;
+        let records = (0..2 * partitions)
+            .map(|id| Ok(json!({ "id": id, "customer": id % partitions })))
+            .collect::<Vec<_>>();
+        write_partitioned_output(
+            template.to_str().unwrap(),
+            None,
+            "customer",
+            1024,
+            stream::iter(records).boxed(),
+            None,
+            WriteOpts::default(),
+        )
+        .await
+        .unwrap();
+
+        for customer in 0..partitions {
+            let path = dir.path().join(format!("{customer}/part-00000.jsonl.gz"));
+            let mut reader = SmartReader::new_from_path(&path).await.unwrap();
+            let mut data = String::new();
+            reader.read_to_string(&mut data).await.unwrap();
+            let second = customer + partitions;
+            assert_eq!(
+                data,
+                format!(
+                    "{{\"id\":{customer},\"customer\":{customer}}}\n{{\"id\":{second},\"customer\":{customer}}}\n"
+                )
+            );
+        }
+    }
 }
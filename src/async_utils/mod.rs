@@ -7,17 +7,19 @@
 //! Based on previous Rust experience, you should be able to leave this code
 //! unchanged for years.
 
-use std::pin::Pin;
+use std::{pin::Pin, time::Duration};
 
 use anyhow::anyhow;
 use futures::Stream;
-use tokio::task::JoinHandle;
+use tokio::task::{AbortHandle, JoinHandle};
 
 use crate::prelude::*;
 
 pub mod blocking_iter_streams;
 pub mod io;
+pub mod record_splitters;
 pub mod size_hint;
+pub mod spool;
 
 /// A type alias for a boxed future. This is used to make it easier to work with
 /// with complex futures.
@@ -27,17 +29,30 @@ pub type BoxedFuture<Output> = Pin<Box<dyn Future<Output = Output> + Send>>;
 /// streams that return complex types.
 pub type BoxedStream<Item> = Pin<Box<dyn Stream<Item = Item> + Send>>;
 
+/// How long to wait for background workers to finish once we've written all
+/// our output, before cancelling them. By then, all our records are done, so
+/// workers should only be cleaning up.
+pub const WORKER_SHUTDOWN_GRACE: Duration = Duration::from_secs(120);
+
 /// A handle for one or more background workers. This can be awaited
 /// to wait for all workers to complete normally.
+///
+/// Dropping a [`JoinWorker`] leaves its workers running. To stop them, call
+/// [`JoinWorker::abort`], or use [`JoinWorker::drain`] to stop them if they
+/// don't finish in time.
 pub struct JoinWorker {
     /// The task handle.
     future: BoxedFuture<Result<()>>,
+
+    /// Handles for cancelling our tasks.
+    abort_handles: Vec<AbortHandle>,
 }
 
 impl JoinWorker {
     /// Create a new worker handle from a [`JoinHandle`].
     pub fn from_handle(handle: JoinHandle<Result<()>>) -> Self {
         Self {
+            abort_handles: vec![handle.abort_handle()],
             future: Box::pin(async move { handle.await.context("could not join task")? }),
         }
     }
@@ -49,12 +64,74 @@ impl JoinWorker {
     pub fn noop() -> Self {
         Self {
             future: Box::pin(async { Ok(()) }),
+            abort_handles: vec![],
+        }
+    }
+
+    /// Run `next` once our workers have completed successfully. Aborting the
+    /// result still aborts our original workers.
+    pub fn and_then<Fut>(self, next: Fut) -> Self
+    where
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let future = self.future;
+        Self {
+            future: Box::pin(async move {
+                future.await?;
+                next.await
+            }),
+            abort_handles: self.abort_handles,
+        }
+    }
+
+    /// Wait up to `grace` for the worker to finish any remaining work, and
+    /// cancel it if it doesn't. This keeps a hung request or cleanup step from
+    /// hanging the whole process after our output is written.
+    pub async fn drain(self, grace: Duration) -> Result<()> {
+        let abort_handles = self.abort_handles.clone();
+        match tokio::time::timeout(grace, self.future).await {
+            Ok(result) => result,
+            Err(_) => {
+                let still_running = abort_handles
+                    .iter()
+                    .filter(|handle| !handle.is_finished())
+                    .count();
+                warn!(
+                    grace_secs = grace.as_secs(),
+                    still_running, "Cancelling background workers which did not finish"
+                );
+                for handle in &abort_handles {
+                    handle.abort();
+                }
+                Err(anyhow!(
+                    "{still_running} background worker(s) were still running {}s after \
+                     all output was written, and were cancelled. This usually means \
+                     a request or cleanup step hung",
+                    grace.as_secs()
+                ))
+            }
         }
     }
 
-    /// Wait for the worker to complete.
-    pub async fn join(self) -> Result<()> {
-        self.future.await
+    /// Finish a run once its output has been written. If `result` is an
+    /// error, we couldn't write our output, so we stop any remaining work
+    /// instead of leaving it running. Otherwise, we let our workers finish
+    /// cleaning up, as with [`JoinWorker::drain`].
+    pub async fn finish(self, result: Result<()>, grace: Duration) -> Result<()> {
+        if let Err(err) = result {
+            self.abort();
+            return Err(err);
+        }
+        self.drain(grace).await
+    }
+
+    /// Cancel our workers without waiting for them. Use this when we're giving
+    /// up on a run, so that in-flight work stops instead of running on in the
+    /// background.
+    pub fn abort(self) {
+        for handle in &self.abort_handles {
+            handle.abort();
+        }
     }
 }
 
@@ -106,10 +183,57 @@ pub fn check_for_command_failure(
             stderr,
         ))
     } else {
+        // No exit code means we were killed by a signal, which may be because
+        // we exceeded a `--process-max-*` limit.
         Err(anyhow!(
-            "{} failed with error output:\n{}",
+            "{} was killed by a signal (perhaps by a process limit) with error output:\n{}",
             command_name,
             stderr,
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_cancels_hung_workers() {
+        let handle = tokio::spawn(async {
+            futures::future::pending::<()>().await;
+            Ok(())
+        });
+        let abort_handle = handle.abort_handle();
+        let err = JoinWorker::from_handle(handle)
+            .drain(Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("1 background worker(s)"), "{err}");
+        tokio::task::yield_now().await;
+        assert!(abort_handle.is_finished());
+    }
+
+    #[tokio::test]
+    async fn and_then_runs_after_workers() {
+        let worker = JoinWorker::from_handle(tokio::spawn(async { Ok(()) }))
+            .and_then(async { Err(anyhow!("cleanup failed")) });
+        let err = worker.drain(Duration::from_secs(10)).await.unwrap_err();
+        assert_eq!(err.to_string(), "cleanup failed");
+    }
+
+    #[tokio::test]
+    async fn finish_aborts_workers_after_errors() {
+        let handle = tokio::spawn(async {
+            futures::future::pending::<()>().await;
+            Ok(())
+        });
+        let abort_handle = handle.abort_handle();
+        let err = JoinWorker::from_handle(handle)
+            .finish(Err(anyhow!("output failed")), Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "output failed");
+        tokio::task::yield_now().await;
+        assert!(abort_handle.is_finished());
+    }
+}
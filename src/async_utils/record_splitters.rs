@@ -0,0 +1,261 @@
+//! Splitters for input files which contain many records in a single
+//! document, like a JSON array or a YAML list.
+//!
+//! We feed JSON arrays to [`JsonArraySplitter`] a chunk at a time, and it
+//! hands back the text of each complete record. This way, we only ever need to
+//! hold one record in memory, no matter how large the file is. Our YAML parser
+//! needs the whole text up front, but we still hand back one record at a time.
+
+use std::{collections::VecDeque, fmt};
+
+use serde::de::{
+    self, DeserializeSeed, MapAccess, SeqAccess, Visitor, value::MapAccessDeserializer,
+};
+
+use crate::prelude::*;
+
+/// Splits a top-level JSON array into the text of its elements.
+#[derive(Debug, Default)]
+pub struct JsonArraySplitter {
+    /// Have we seen the opening `[`?
+    started: bool,
+
+    /// Have we seen the closing `]`?
+    finished: bool,
+
+    /// How deeply nested are we inside the current element?
+    depth: usize,
+
+    /// Are we inside a string?
+    in_string: bool,
+
+    /// Was the previous byte a backslash inside a string?
+    escaped: bool,
+
+    /// Have we finished any elements yet?
+    seen_element: bool,
+
+    /// The text of the current element.
+    current: Vec<u8>,
+}
+
+impl JsonArraySplitter {
+    /// Feed `bytes` to the splitter, pushing the text of each complete element
+    /// onto `elements`.
+    pub fn feed(&mut self, bytes: &[u8], elements: &mut VecDeque<Vec<u8>>) -> Result<()> {
+        for &b in bytes {
+            if self.finished {
+                if !b.is_ascii_whitespace() {
+                    return Err(anyhow!("unexpected data after JSON array"));
+                }
+            } else if !self.started {
+                match b {
+                    b'[' => self.started = true,
+                    _ if b.is_ascii_whitespace() => {}
+                    _ => return Err(anyhow!("expected JSON array to start with `[`")),
+                }
+            } else if self.in_string {
+                self.current.push(b);
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.in_string = false;
+                }
+            } else {
+                match b {
+                    b'"' => {
+                        self.in_string = true;
+                        self.current.push(b);
+                    }
+                    b'{' | b'[' => {
+                        self.depth += 1;
+                        self.current.push(b);
+                    }
+                    b'}' | b']' if self.depth > 0 => {
+                        self.depth -= 1;
+                        self.current.push(b);
+                    }
+                    b']' => {
+                        self.finished = true;
+                        if !self.current.is_empty() || self.seen_element {
+                            self.finish_element(elements)?;
+                        }
+                    }
+                    b',' if self.depth == 0 => self.finish_element(elements)?,
+                    _ if b.is_ascii_whitespace() && self.depth == 0 => {}
+                    _ => self.current.push(b),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that we saw a complete array.
+    pub fn finish(&self) -> Result<()> {
+        if self.finished {
+            Ok(())
+        } else {
+            Err(anyhow!("JSON array is incomplete"))
+        }
+    }
+
+    /// Hand back the current element.
+    fn finish_element(&mut self, elements: &mut VecDeque<Vec<u8>>) -> Result<()> {
+        if self.current.is_empty() {
+            return Err(anyhow!("empty element in JSON array"));
+        }
+        elements.push_back(std::mem::take(&mut self.current));
+        self.seen_element = true;
+        Ok(())
+    }
+}
+
+/// Parse a YAML stream, calling `handle_record` on each record. Each document
+/// is a record, unless it's a top-level list, in which case each list item is a
+/// record.
+///
+/// Lists are deserialized one item at a time, so we never build the whole list
+/// as JSON. Anchors, aliases and flow-style lists work as usual, because the
+/// YAML parser sees the entire document.
+pub fn for_each_yaml_record(
+    text: &str,
+    mut handle_record: impl FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    for document in serde_yaml_ng::Deserializer::from_str(text) {
+        YamlRecords {
+            handle_record: &mut handle_record,
+        }
+        .deserialize(document)?;
+    }
+    Ok(())
+}
+
+/// Deserializes a single YAML document, passing each record to
+/// `handle_record`.
+struct YamlRecords<'a, F> {
+    /// Called on each record.
+    handle_record: &'a mut F,
+}
+
+impl<'de, F: FnMut(Value) -> Result<()>> DeserializeSeed<'de> for YamlRecords<'_, F> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, F: FnMut(Value) -> Result<()>> Visitor<'de> for YamlRecords<'_, F> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a list of records, or a single record")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(record) = seq.next_element::<Value>()? {
+            (self.handle_record)(record)
+                .map_err(|err| <A::Error as de::Error>::custom(format!("{err:#}")))?;
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<(), A::Error> {
+        let record = Value::deserialize(MapAccessDeserializer::new(map))?;
+        (self.handle_record)(record).map_err(|err| de::Error::custom(format!("{err:#}")))
+    }
+
+    /// Empty documents have no records.
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split_json(input: &str, chunk_size: usize) -> Result<Vec<Value>> {
+        let mut splitter = JsonArraySplitter::default();
+        let mut elements = VecDeque::new();
+        for chunk in input.as_bytes().chunks(chunk_size) {
+            splitter.feed(chunk, &mut elements)?;
+        }
+        splitter.finish()?;
+        elements
+            .iter()
+            .map(|e| Ok(serde_json::from_slice(e)?))
+            .collect()
+    }
+
+    #[test]
+    fn json_arrays_are_split_incrementally() {
+        let input =
+            r#" [ {"id": 1, "text": "a, [b] \"}\""}, {"id": [2, {"x": 3}]}, 4 ] "#;
+        for chunk_size in [1, 3, 100] {
+            assert_eq!(
+                split_json(input, chunk_size).unwrap(),
+                vec![
+                    json!({ "id": 1, "text": "a, [b] \"}\"" }),
+                    json!({ "id": [2, { "x": 3 }] }),
+                    json!(4),
+                ]
+            );
+        }
+        assert_eq!(split_json("[]", 1).unwrap(), Vec::<Value>::new());
+        assert!(split_json("[1,,2]", 1).is_err());
+        assert!(split_json("[1,]", 1).is_err());
+        assert!(split_json("[1, 2", 1).is_err());
+        assert!(split_json("[1] 2", 1).is_err());
+        assert!(split_json("{}", 1).is_err());
+    }
+
+    fn split_yaml(input: &str) -> Result<Vec<Value>> {
+        let mut records = vec![];
+        for_each_yaml_record(input, |record| {
+            records.push(record);
+            Ok(())
+        })?;
+        Ok(records)
+    }
+
+    #[test]
+    fn yaml_lists_and_documents_are_split() {
+        let list = "# Records\n- id: 1\n  tags:\n  - a\n- id: 2\n";
+        assert_eq!(
+            split_yaml(list).unwrap(),
+            vec![json!({ "id": 1, "tags": ["a"] }), json!({ "id": 2 })]
+        );
+
+        let documents = "---\nid: 1\nitems:\n- x\n---\nid: 2\n";
+        assert_eq!(
+            split_yaml(documents).unwrap(),
+            vec![json!({ "id": 1, "items": ["x"] }), json!({ "id": 2 })]
+        );
+
+        let flow = "[{id: 1}, {id: 2}]\n";
+        assert_eq!(
+            split_yaml(flow).unwrap(),
+            vec![json!({ "id": 1 }), json!({ "id": 2 })]
+        );
+
+        // Aliases may refer to anchors in earlier records.
+        let aliases = "- id: 1\n  tags: &tags [a, b]\n- id: 2\n  tags: *tags\n";
+        assert_eq!(
+            split_yaml(aliases).unwrap(),
+            vec![
+                json!({ "id": 1, "tags": ["a", "b"] }),
+                json!({ "id": 2, "tags": ["a", "b"] }),
+            ]
+        );
+
+        assert_eq!(split_yaml("# Nothing here\n").unwrap(), Vec::<Value>::new());
+        assert!(split_yaml("- id: 1\n- [unclosed\n").is_err());
+        assert!(split_yaml("just a string\n").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! A disk-backed spool for sorting streams which are too large to buffer in
+//! memory.
+
+use std::{cmp::Ordering, io::SeekFrom, marker::PhantomData};
+
+use futures::{StreamExt as _, stream};
+use serde::de::DeserializeOwned;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _, BufWriter},
+};
+
+use super::BoxedStream;
+use crate::prelude::*;
+
+/// Where a spooled record lives on disk.
+#[derive(Debug)]
+struct SpoolEntry<K> {
+    /// The sort key for this record.
+    key: K,
+
+    /// The offset of the serialized record in our spool file.
+    offset: u64,
+
+    /// The length of the serialized record.
+    len: usize,
+}
+
+/// A spool which writes records to an anonymous temporary file, keeping only
+/// their sort keys in memory, and then reads them back in sorted order.
+pub struct SortedSpool<K, T> {
+    /// Our temporary file. This will be deleted automatically when closed.
+    file: BufWriter<File>,
+
+    /// The records we've written so far.
+    entries: Vec<SpoolEntry<K>>,
+
+    /// The number of bytes written so far.
+    written: u64,
+
+    /// We serialize and deserialize `T`.
+    _phantom: PhantomData<fn(T) -> T>,
+}
+
+impl<K, T> SortedSpool<K, T>
+where
+    K: Send + 'static,
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    /// Create a new, empty spool.
+    pub fn new() -> Result<Self> {
+        let file = tempfile::tempfile().context("cannot create spool file")?;
+        Ok(Self {
+            file: BufWriter::new(File::from_std(file)),
+            entries: vec![],
+            written: 0,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Write a record to the spool.
+    pub async fn push(&mut self, key: K, record: &T) -> Result<()> {
+        let data = serde_json::to_vec(record).context("cannot serialize spool record")?;
+        self.file
+            .write_all(&data)
+            .await
+            .context("cannot write to spool file")?;
+        self.entries.push(SpoolEntry {
+            key,
+            offset: self.written,
+            len: data.len(),
+        });
+        self.written += u64::try_from(data.len()).expect("record too large");
+        Ok(())
+    }
+
+    /// Sort our records using `compare`, and return them as a stream. The sort
+    /// is stable, so records with equal keys are returned in the order they
+    /// were pushed.
+    pub async fn into_sorted_stream(
+        mut self,
+        compare: impl Fn(&K, &K) -> Ordering,
+    ) -> Result<BoxedStream<Result<T>>> {
+        self.file.flush().await.context("cannot flush spool file")?;
+        let file = self.file.into_inner();
+        self.entries.sort_by(|a, b| compare(&a.key, &b.key));
+
+        let entries = self.entries.into_iter();
+        Ok(
+            stream::unfold((file, entries), |(mut file, mut entries)| async move {
+                let entry = entries.next()?;
+                let record = read_entry::<K, T>(&mut file, &entry).await;
+                Some((record, (file, entries)))
+            })
+            .boxed(),
+        )
+    }
+}
+
+/// Read a single record back from our spool file.
+async fn read_entry<K, T>(file: &mut File, entry: &SpoolEntry<K>) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    file.seek(SeekFrom::Start(entry.offset))
+        .await
+        .context("cannot seek in spool file")?;
+    let mut data = vec![0; entry.len];
+    file.read_exact(&mut data)
+        .await
+        .context("cannot read from spool file")?;
+    serde_json::from_slice(&data).context("cannot deserialize spool record")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sorted_spool() {
+        let mut spool = SortedSpool::<usize, Value>::new().unwrap();
+        spool.push(2, &json!({ "id": "c" })).await.unwrap();
+        spool.push(0, &json!({ "id": "a" })).await.unwrap();
+        spool.push(1, &json!({ "id": "b1" })).await.unwrap();
+        spool.push(1, &json!({ "id": "b2" })).await.unwrap();
+        let sorted = spool
+            .into_sorted_stream(|a, b| a.cmp(b))
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            sorted,
+            vec![
+                json!({ "id": "a" }),
+                json!({ "id": "b1" }),
+                json!({ "id": "b2" }),
+                json!({ "id": "c" }),
+            ]
+        );
+    }
+}
@@ -1,38 +1,180 @@
 //! The `chat` subcommand.
 
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
 use clap::Args;
-use futures::StreamExt;
+use futures::{StreamExt as _, stream};
 
 use crate::{
-    async_utils::io::read_json_or_toml,
+    assertions::{OnInvariantFailure, ResponseInvariant},
+    async_utils::{BoxedStream, WORKER_SHUTDOWN_GRACE},
+    cmd::{OutputFormat, read_prompt},
     drivers::LlmOpts,
+    email::expand_email_inputs,
+    large_fields::{FieldOffload, offload_large_fields},
+    manifest::RunManifest,
+    model_weights::ModelWeights,
     prelude::*,
-    prompt::ChatPrompt,
+    prompt::ResponseFormat,
+    prompt_router::PromptRouter,
     queues::{
-        chat::{ChatInput, ChatStreamInfo, process_chat_stream},
-        work::{WorkInput, WorkOutput},
+        chat::{ChatInput, ChatOutput, ChatStreamInfo, process_chat_stream},
+        work::{WorkInput, WorkInputStreamInfo, WorkOutput, track_ack_tokens},
     },
+    rate_limit::latest_provider_rate_limits,
+    result_store::{Column, ColumnType, output_columns},
+    run_dir::apply_run_dir,
+    spend_ledger::{RUN_TAG_KEY, SpendTracker, check_budget},
     ui::{ProgressConfig, Ui},
+    webhook::Webhook,
 };
 
+/// A `--prompt` argument, which may name its prompt, as in `NAME=PATH`.
+#[derive(Clone, Debug)]
+pub struct PromptArg {
+    /// The name of the prompt, if it has one.
+    pub name: Option<String>,
+
+    /// The path to the prompt, or `-` for standard input.
+    pub path: PathBuf,
+}
+
+impl FromStr for PromptArg {
+    type Err = String;
+
+    /// Parse `NAME=PATH` or `PATH`. Names may only contain letters, digits,
+    /// `-` and `_`, so paths containing `=` are usually still read as paths.
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let is_name = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+        match arg.split_once('=') {
+            Some((name, path)) if is_name(name) && !path.is_empty() => Ok(Self {
+                name: Some(name.to_owned()),
+                path: PathBuf::from(path),
+            }),
+            _ if arg.is_empty() => Err("expected [NAME=]PATH".to_owned()),
+            _ => Ok(Self {
+                name: None,
+                path: PathBuf::from(arg),
+            }),
+        }
+    }
+}
+
 /// Chat command line arguments.
 #[derive(Debug, Args)]
 pub struct ChatOpts {
-    /// Input data, in CSV or JSONL format. Defaults to standard input.
+    /// Input data, in CSV or JSONL format. May also be an `sqs://`,
+    /// `kafka://`, `nats://` or `postgres://` URL. Defaults to standard input.
     pub input_path: Option<PathBuf>,
 
     /// Model to use by default.
     #[clap(short = 'm', long, default_value = "gpt-4o-mini")]
     pub model: String,
 
-    /// Prompt, in TOML or JSON format.
-    #[clap(short = 'p', long = "prompt")]
-    pub prompt_path: PathBuf,
+    /// Choose a model at random for each record, with the given relative
+    /// weights, as in `gpt-4o-mini=0.9,gpt-4.1=0.1`. Each output record
+    /// includes the `model` which served it. The choice depends only on the
+    /// record's `id` and `--seed`, so reruns send each record to the same
+    /// model.
+    #[clap(long, value_name = "MODEL=WEIGHT,...", conflicts_with = "model")]
+    pub model_weights: Option<ModelWeights>,
+
+    /// Prompt, in TOML or JSON format. Use `-` to read the prompt from
+    /// standard input, if input records come from a file. To compare prompts,
+    /// repeat this as `--prompt NAME=PATH`, and every record will be run
+    /// against each prompt, with one output record per prompt.
+    #[clap(
+        short = 'p',
+        long = "prompt",
+        value_name = "[NAME=]PATH",
+        required_unless_present_any = ["prompt_router_path", "prompt_inline"]
+    )]
+    pub prompts: Vec<PromptArg>,
+
+    /// Prompt text, in TOML or JSON format. Use instead of `--prompt` when
+    /// writing a prompt file would be awkward.
+    #[clap(long, value_name = "TOML", conflicts_with = "prompts")]
+    pub prompt_inline: Option<String>,
+
+    /// A prompt router, in TOML or JSON format, which chooses a prompt for each
+    /// record based on the value of an input field. Use instead of `--prompt`.
+    #[clap(
+        long = "prompt-router",
+        conflicts_with_all = ["prompts", "prompt_inline"]
+    )]
+    pub prompt_router_path: Option<PathBuf>,
+
+    /// Treat each input record's `path` as an `.eml` or `.msg` email. Its
+    /// headers and body are available to the prompt as `{{email.subject}}`,
+    /// `{{email.body}}` and so on, and each PDF or image attachment becomes a
+    /// child record with an `{{attachment.path}}` binding. This can't be used
+    /// with `--run-dir` or queue inputs, which track records by input ID.
+    #[clap(long)]
+    pub email_input: bool,
 
-    /// Output location, in JSONL format. Defaults to standard output.
+    /// Write any top-level input string field larger than this many bytes to
+    /// a temporary file, and replace it with the file's path. Only fields
+    /// which prompts include using `{{text-file-contents FIELD}}` (and never
+    /// as `{{FIELD}}`) are offloaded. Each file is deleted once its record is
+    /// done. This keeps memory use down for records with very long text.
+    #[clap(long, value_name = "BYTES")]
+    pub offload_fields_over: Option<u64>,
+
+    /// If any prompt has an `[input_schema]`, check this many input records
+    /// against it before making any LLM requests. Queue input isn't checked,
+    /// because we'd hold messages while waiting for more to arrive.
+    #[clap(long, default_value = "100", value_name = "N")]
+    pub check_input_records: usize,
+
+    /// Remove these dotted response fields before writing output, like
+    /// `reasoning,parties.notes`. Responses are still validated with these
+    /// fields. Adds to any `omit_response_fields` in the prompt.
+    #[clap(long, value_delimiter = ',', value_name = "FIELDS")]
+    pub omit_response_fields: Vec<String>,
+
+    /// Check that a response field equals an input field, like
+    /// `date=expected_date`, whenever the input field is present. May be
+    /// repeated. Adds to any `[[invariant]]` tables in the prompt.
+    #[clap(long = "invariant", value_name = "FIELD=INPUT_FIELD")]
+    pub invariants: Vec<ResponseInvariant>,
+
+    /// What to do when an `--invariant` doesn't hold: record a warning in the
+    /// output record's `errors`, or fail the record.
+    #[clap(long, value_enum, default_value = "warn")]
+    pub on_invariant_failure: OnInvariantFailure,
+
+    /// Output location, in JSONL format. May also be a `kafka://`, `nats://`
+    /// or `postgres://` URL. Defaults to standard output.
     #[clap(short = 'o', long = "out")]
     pub output_path: Option<PathBuf>,
 
+    /// After the run, compare our cost estimate with the spend logged by
+    /// LiteLLM, and warn if they differ. We tag each request with a unique
+    /// `prompt_scaler_run` tag, so we only count this run's requests.
+    /// LiteLLM's spend is also added to any `--spend-ledger`.
+    #[clap(long)]
+    pub reconcile_spend: bool,
+
+    /// How many seconds to wait for LiteLLM to write its spend logs before
+    /// reconciling. LiteLLM writes them in batches, every minute by default.
+    #[clap(
+        long,
+        value_name = "SECS",
+        default_value_t = 70,
+        requires = "reconcile_spend"
+    )]
+    pub reconcile_spend_delay: u64,
+
     /// Stream-related options.
     #[clap(flatten)]
     pub stream_opts: super::StreamOpts,
@@ -45,14 +187,267 @@ pub struct ChatOpts {
 /// Run the `chat` subcommand.
 #[instrument(level = "debug", skip_all)]
 pub async fn cmd_chat(ui: &Ui, opts: &ChatOpts) -> Result<()> {
+    let started = SystemTime::now();
+
     // Open up our input stream and convert to records.
-    let input =
-        WorkInput::<ChatInput>::read_stream(ui.clone(), opts.input_path.as_deref())
-            .await?;
+    let WorkInputStreamInfo { stream: input, ack } = WorkInput::<ChatInput>::read_stream(
+        ui.clone(),
+        opts.input_path.as_deref(),
+        &opts.stream_opts,
+    )
+    .await?;
     let input = opts.stream_opts.apply_stream_input_opts(input);
 
-    // Read our prompt.
-    let prompt = read_json_or_toml::<ChatPrompt>(opts.prompt_path.as_ref()).await?;
+    // Message queues track records by ID, so they would acknowledge an email
+    // without waiting for its attachments.
+    if opts.email_input && ack.is_some() {
+        return Err(anyhow!("--email-input cannot be used with queue input"));
+    }
+
+    // Our `--run-dir` checkpoint tracks records by input ID, so it can't
+    // handle inputs which we turn into several output records. Check this
+    // before we filter our input using the checkpoint.
+    if opts.stream_opts.run_dir.is_some() {
+        if opts.prompts.iter().any(|arg| arg.name.is_some()) {
+            return Err(anyhow!("Named prompts cannot be used with --run-dir"));
+        }
+        if opts.email_input {
+            return Err(anyhow!("--email-input cannot be used with --run-dir"));
+        }
+    }
+
+    // Skip anything an earlier attempt at this `--run-dir` already finished.
+    // This gives us an `ack` even for file input, so remember whether we're
+    // reading from a queue first.
+    let queue_input = ack.is_some();
+    let (input, ack) =
+        apply_run_dir(&opts.stream_opts, opts.output_path.as_deref(), input, ack).await?;
+
+    // Fan out email attachments, which we keep in a scratch directory until
+    // their records are done.
+    let email_scratch_dir = if opts.email_input {
+        Some(Arc::new(
+            tempfile::TempDir::with_prefix("email-attachments")
+                .context("cannot create email attachment directory")?,
+        ))
+    } else {
+        None
+    };
+    let input = match &email_scratch_dir {
+        Some(scratch_dir) => expand_email_inputs(input, scratch_dir.clone()),
+        None => input,
+    };
+
+    // Read our prompts.
+    let prompts = if let Some(router_path) = &opts.prompt_router_path {
+        PromptRouter::from_path(router_path).await?
+    } else if opts.prompts.iter().any(|arg| arg.name.is_some()) {
+        read_named_prompts(opts).await?
+    } else if opts.prompts.len() > 1 {
+        return Err(anyhow!(
+            "To use more than one --prompt, give each a name, as in --prompt NAME=PATH"
+        ));
+    } else {
+        let prompt = read_prompt(
+            opts.prompts.first().map(|arg| arg.path.as_path()),
+            opts.prompt_inline.as_deref(),
+            opts.input_path.is_none(),
+            &opts.stream_opts,
+        )
+        .await?
+        .ok_or_else(|| {
+            anyhow!("Either --prompt, --prompt-inline or --prompt-router is required")
+        })?;
+        PromptRouter::single(prompt)
+    };
+
+    // Move very large fields out of memory, or at least warn about them. We
+    // can only offload fields which our prompts read using
+    // `{{text-file-contents FIELD}}`.
+    let offload = match opts.offload_fields_over {
+        Some(threshold_bytes) => match prompts.fields_used_directly()? {
+            Some(fields_used_directly) => Some(FieldOffload {
+                threshold_bytes,
+                scratch_dir: Arc::new(
+                    tempfile::TempDir::with_prefix("offloaded-fields")
+                        .context("cannot create offloaded field directory")?,
+                ),
+                fields_used_directly,
+                warned_fields: Arc::default(),
+            }),
+            None => {
+                return Err(anyhow!(
+                    "--offload-fields-over can't tell which fields a prompt uses, \
+                     because it uses `{{{{this}}}}`, `@root` or a partial"
+                ));
+            }
+        },
+        None => None,
+    };
+    let input = offload_large_fields(input, offload.clone());
+
+    // Run every record against each named prompt. Message queues track
+    // records by ID, so they can't handle several outputs per ID.
+    let input = match prompts.names() {
+        Some(_) if ack.is_some() => {
+            return Err(anyhow!("Named prompts cannot be used with queue input"));
+        }
+        Some(names) => fan_out_to_prompts(input, names),
+        None => input,
+    };
+
+    // Apply `--omit-response-fields` and `--invariant` to every prompt.
+    let prompts = prompts
+        .try_map(|mut prompt| async move {
+            prompt
+                .omit_response_fields
+                .extend(opts.omit_response_fields.iter().cloned());
+            prompt
+                .invariants
+                .extend(opts.invariants.iter().map(|invariant| ResponseInvariant {
+                    on_failure: opts.on_invariant_failure,
+                    ..invariant.clone()
+                }));
+            Ok(prompt)
+        })
+        .await?;
+
+    // Start any MCP servers our prompts need, so that problems show up now.
+    for prompt in prompts.prompts() {
+        if !prompt.mcp_servers.is_empty() && opts.llm_opts.hedge_after.is_some() {
+            // A hedged request would run the same tool calls a second time.
+            return Err(anyhow!(
+                "--hedge-after can't be used with prompts that have [[mcp_server]], \
+                 because tool calls would run twice"
+            ));
+        }
+        if !prompt.mcp_servers.is_empty() && !opts.llm_opts.driver.supports_mcp_tools() {
+            return Err(anyhow!(
+                "Prompts with [[mcp_server]] require --driver openai"
+            ));
+        }
+        prompt.mcp_tools().await?;
+    }
+
+    // Decide which models to use.
+    let models = opts
+        .model_weights
+        .clone()
+        .unwrap_or_else(|| ModelWeights::single(opts.model.clone()));
+
+    // Fail fast if our input doesn't have the bindings our prompts expect.
+    // Queues may deliver messages slowly, and buffering them would delay the
+    // whole run and hold their visibility timeouts, so we don't check them.
+    let check_count = if queue_input {
+        0
+    } else {
+        opts.check_input_records
+    };
+    let input = check_input_records(input, &prompts, check_count).await?;
+
+    // Check our output format before we start making LLM requests.
+    let output_format = opts.stream_opts.output_format(opts.output_path.as_deref());
+    let duckdb_columns = match output_format {
+        OutputFormat::Jsonl => None,
+        OutputFormat::Csv => {
+            // Classification prompts only return a `label`, which fits in a
+            // CSV column. Other responses don't.
+            let all_classify = prompts
+                .prompts()
+                .all(|prompt| prompt.response_schema.classify_labels().is_some());
+            if !all_classify {
+                return Err(anyhow!(
+                    "CSV output is only supported by `ocr`, and by `chat` with `classify` prompts"
+                ));
+            }
+            None
+        }
+        OutputFormat::Duckdb => {
+            // Include the columns for every prompt we might use.
+            let mut columns = Vec::<Column>::new();
+            for prompt in prompts.prompts() {
+                let schema = prompt.response_json_schema().await?;
+                for column in output_columns(&schema, Some("response")) {
+                    // Don't create columns for fields we're going to omit.
+                    let omitted = prompt
+                        .omit_response_fields
+                        .iter()
+                        .any(|field| column.path[1..] == [field.as_str()]);
+                    if !omitted && !columns.iter().any(|c| c.name == column.name) {
+                        columns.push(column);
+                    }
+                }
+            }
+            let has_text_prompt = prompts
+                .prompts()
+                .any(|prompt| prompt.response_format == ResponseFormat::Text);
+            if has_text_prompt && !columns.iter().any(|c| c.name == "response") {
+                columns.push(Column::top_level("response", ColumnType::Varchar));
+            }
+            if prompts.names().is_some()
+                && !columns.iter().any(|c| c.name == "prompt_name")
+            {
+                columns.push(Column::top_level("prompt_name", ColumnType::Varchar));
+            }
+            let has_fallbacks = !opts.llm_opts.fallback_models.is_empty();
+            if (!models.is_single() || has_fallbacks)
+                && !columns.iter().any(|c| c.name == "model")
+            {
+                columns.push(Column::top_level("model", ColumnType::Varchar));
+            }
+            if has_fallbacks && !columns.iter().any(|c| c.name == "fallback_position") {
+                columns.push(Column::top_level("fallback_position", ColumnType::BigInt));
+            }
+            if opts.llm_opts.hedge_after.is_some()
+                && !columns.iter().any(|c| c.name == "hedged_requests")
+            {
+                columns.push(Column::top_level("hedged_requests", ColumnType::BigInt));
+            }
+            if opts.llm_opts.hedge_after.is_some()
+                && !columns.iter().any(|c| c.name == "hedged_prompt_tokens")
+            {
+                columns.push(Column::top_level(
+                    "hedged_prompt_tokens",
+                    ColumnType::BigInt,
+                ));
+            }
+            if opts.llm_opts.include_raw_response.is_some()
+                && !columns.iter().any(|c| c.name == "raw_response")
+            {
+                columns.push(Column::top_level("raw_response", ColumnType::Json));
+            }
+            Some(columns)
+        }
+    };
+
+    // Make sure we haven't already used up our budget.
+    check_budget(&opts.stream_opts).await?;
+
+    // Tag our requests, so we can find our own spend in LiteLLM's logs.
+    let mut llm_opts = opts.llm_opts.clone();
+    let run_tag = if opts.reconcile_spend {
+        if !llm_opts.driver.supports_tags() {
+            return Err(anyhow!(
+                "--reconcile-spend needs a driver which supports --tag"
+            ));
+        }
+        let run_id = uuid::Uuid::new_v4().to_string();
+        let run_tag = format!("{RUN_TAG_KEY}:{run_id}");
+        llm_opts.tags.push((RUN_TAG_KEY.to_owned(), run_id));
+        Some(run_tag)
+    } else {
+        None
+    };
+
+    // Start delivering records to our webhook, if we have one.
+    let webhook = Webhook::start(&opts.stream_opts.webhook_opts)?;
+
+    // Record how this run was configured.
+    RunManifest::new("chat", &models.to_string())
+        .with_llm_opts(&llm_opts)
+        .with_stream_opts(&opts.stream_opts)
+        .write(opts.stream_opts.manifest_path.as_deref())
+        .await?;
 
     // Configure our progress bar.
     let pb = ui.new_from_size_hint(
@@ -71,21 +466,193 @@ pub async fn cmd_chat(ui: &Ui, opts: &ChatOpts) -> Result<()> {
     } = process_chat_stream(
         opts.stream_opts.job_count,
         input,
-        prompt,
-        opts.model.to_owned(),
-        opts.llm_opts.to_owned(),
+        prompts,
+        models.clone(),
+        llm_opts,
+        opts.stream_opts.record_limits(),
     )
     .await?;
 
     // Resolve our individual LLM requests concurrently, and convert them back to JSON.
-    let output = pb
-        .wrap_stream(opts.stream_opts.apply_stream_buffering_opts(futures))
+    let output = opts.stream_opts.apply_stream_buffering_opts(&pb, futures);
+
+    // Show the latest rate limits reported by the provider, if any.
+    let rate_limit_pb = pb.clone();
+    let output = output
+        .inspect(move |_| {
+            if let Some(limits) = latest_provider_rate_limits() {
+                rate_limit_pb.set_message(format!("Running LLM prompts ({limits})"));
+            }
+        })
         .boxed();
+    let spend = SpendTracker::default();
+    let output = spend.wrap_stream(output);
+    let output = webhook.wrap_stream(output);
+    let (output, ack) = track_ack_tokens(output, ack);
 
     // Write out our output.
-    WorkOutput::write_stream(ui, opts.output_path.as_deref(), output, &opts.stream_opts)
+    let written = match (output_format, duckdb_columns) {
+        (_, Some(columns)) => {
+            WorkOutput::write_stream_to_duckdb(
+                ui,
+                opts.output_path.as_deref(),
+                output,
+                &opts.stream_opts,
+                &columns,
+                ack.as_deref(),
+            )
+            .await
+        }
+        (OutputFormat::Csv, None) => {
+            WorkOutput::<ChatOutput>::write_stream_to_csv(
+                ui,
+                opts.output_path.as_deref(),
+                output,
+                &opts.stream_opts,
+                ack.as_deref(),
+            )
+            .await
+        }
+        (_, None) => {
+            WorkOutput::write_stream(
+                ui,
+                opts.output_path.as_deref(),
+                output,
+                &opts.stream_opts,
+                ack.as_deref(),
+            )
+            .await
+        }
+    };
+    let reported_cost = match &run_tag {
+        Some(run_tag) if written.is_ok() => {
+            let delay = Duration::from_secs(opts.reconcile_spend_delay);
+            spend.reconcile_with_litellm(started, run_tag, delay).await
+        }
+        _ => None,
+    };
+    let recorded = spend
+        .record(&opts.stream_opts, &models.to_string(), reported_cost)
+        .await;
+    let delivered = webhook.finish().await;
+    worker
+        .finish(written.and(recorded).and(delivered), WORKER_SHUTDOWN_GRACE)
         .await?;
+    drop(email_scratch_dir);
+    drop(offload);
+    Ok(())
+}
+
+/// Read the prompts given as `--prompt NAME=PATH`.
+async fn read_named_prompts(opts: &ChatOpts) -> Result<PromptRouter> {
+    let mut prompts = BTreeMap::new();
+    for arg in &opts.prompts {
+        let Some(name) = &arg.name else {
+            return Err(anyhow!(
+                "When using named prompts, every --prompt needs a name: {}",
+                arg.path.display()
+            ));
+        };
+        if prompts.contains_key(name) {
+            return Err(anyhow!("Duplicate prompt name: {name}"));
+        }
+        let prompt = read_prompt(
+            Some(&arg.path),
+            None,
+            opts.input_path.is_none(),
+            &opts.stream_opts,
+        )
+        .await?
+        .expect("prompt path was given");
+        prompts.insert(name.clone(), prompt);
+    }
+    Ok(PromptRouter::named(prompts))
+}
+
+/// Turn each input record into one record per named prompt.
+fn fan_out_to_prompts(
+    input: BoxedStream<Result<WorkInput<ChatInput>>>,
+    names: Vec<String>,
+) -> BoxedStream<Result<WorkInput<ChatInput>>> {
+    input
+        .flat_map(move |record| {
+            let records = match record {
+                Ok(record) => names
+                    .iter()
+                    .map(|name| {
+                        Ok(WorkInput {
+                            id: record.id.clone(),
+                            skip_processing: record.skip_processing,
+                            passthrough_data: record.passthrough_data.clone(),
+                            ack_token: None,
+                            data: ChatInput {
+                                prompt_name: Some(name.clone()),
+                                ..record.data.clone()
+                            },
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+                Err(err) => vec![Err(err)],
+            };
+            stream::iter(records)
+        })
+        .boxed()
+}
+
+/// Check up to `count` input records against the `[input_schema]` of the
+/// prompt each one would use, failing with a list of any missing or mistyped
+/// columns. Returns a stream containing all the input records.
+async fn check_input_records(
+    mut input: BoxedStream<Result<WorkInput<ChatInput>>>,
+    prompts: &PromptRouter,
+    count: usize,
+) -> Result<BoxedStream<Result<WorkInput<ChatInput>>>> {
+    if count == 0
+        || prompts
+            .prompts()
+            .all(|prompt| prompt.input_schema.is_none())
+    {
+        return Ok(input);
+    }
+
+    // We only show the first few problems, because if one record is wrong,
+    // usually they all are.
+    const MAX_PROBLEMS_SHOWN: usize = 10;
+    let mut head = Vec::with_capacity(count);
+    let mut problems = vec![];
+    while head.len() < count {
+        let Some(record) = input.next().await else {
+            break;
+        };
+        if let Ok(record) = &record
+            && let Ok(prompt) = prompts.route_named(
+                record.data.prompt_name.as_deref(),
+                &record.data.template_bindings,
+            )
+            && let Some(input_schema) = &prompt.input_schema
+        {
+            for problem in
+                input_schema.check(&record.data.template_bindings, &prompt.constants)
+            {
+                problems.push(format!("record {}: {problem}", record.id));
+            }
+        }
+        head.push(record);
+    }
+    if problems.is_empty() {
+        return Ok(stream::iter(head).chain(input).boxed());
+    }
 
-    // Wait for our work queue's background task to exit.
-    worker.join().await
+    let mut msg = "Input records do not match the prompt's input_schema:".to_owned();
+    for problem in problems.iter().take(MAX_PROBLEMS_SHOWN) {
+        msg.push_str("\n  ");
+        msg.push_str(problem);
+    }
+    if problems.len() > MAX_PROBLEMS_SHOWN {
+        msg.push_str(&format!(
+            "\n  ...and {} more",
+            problems.len() - MAX_PROBLEMS_SHOWN
+        ));
+    }
+    Err(anyhow!(msg))
 }
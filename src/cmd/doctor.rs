@@ -0,0 +1,380 @@
+//! The `doctor` subcommand.
+
+use std::{fmt, io::ErrorKind};
+
+use clap::Args;
+use futures::{StreamExt as _, stream};
+use keen_retry::RetryResult;
+use tokio::process::Command;
+
+use crate::{
+    async_utils::io::{JsonObject, write_output},
+    drivers::{DriverType, LlmOpts},
+    litellm::{litellm_model_info, litellm_model_info_available},
+    prelude::*,
+    prompt::ChatPrompt,
+    s3::create_s3_client,
+    schema::compat::SchemaTransforms,
+    toml_utils::from_toml_str,
+    ui::Ui,
+};
+
+/// A tiny prompt for checking API access. The echo driver accepts this, too.
+const CHECK_PROMPT: &str = r#"
+[response_schema]
+description = "A health check response."
+
+[response_schema.properties.echo]
+description = "The word OK."
+
+[[messages]]
+user.text = "This is a health check. Reply with the word OK."
+"#;
+
+/// Command line arguments for the `doctor` subcommand.
+#[derive(Debug, Args)]
+pub struct DoctorOpts {
+    /// Model to check.
+    #[clap(short = 'm', long, default_value = "gpt-4o-mini")]
+    pub model: String,
+
+    /// Don't send a test request to the model. Credentials are still loaded.
+    #[clap(long)]
+    pub skip_request: bool,
+
+    /// Check that we can list objects in this S3 location, like
+    /// `s3://bucket/prefix`. May be repeated.
+    #[clap(long = "s3-path", value_name = "URI")]
+    pub s3_paths: Vec<String>,
+
+    /// Write the report to this path, in JSONL format, with one record per
+    /// check. Defaults to standard output.
+    #[clap(short = 'o', long = "out")]
+    pub output_path: Option<PathBuf>,
+
+    /// Our LLM options.
+    #[clap(flatten)]
+    pub llm_opts: LlmOpts,
+}
+
+/// The outcome of a check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CheckStatus {
+    /// Everything looks good.
+    Pass,
+    /// Some features may not work.
+    Warn,
+    /// A run will probably fail.
+    Fail,
+}
+
+impl CheckStatus {
+    /// An emoji for displaying this status.
+    fn emoji(self) -> &'static str {
+        match self {
+            CheckStatus::Pass => "✅",
+            CheckStatus::Warn => "⚠️",
+            CheckStatus::Fail => "❌",
+        }
+    }
+}
+
+/// The result of a single check.
+#[derive(Debug, Serialize)]
+struct CheckResult {
+    /// What we checked.
+    check: String,
+
+    /// Did it pass?
+    status: CheckStatus,
+
+    /// What we found, and what to do about it.
+    message: String,
+}
+
+impl CheckResult {
+    /// Create a new check result.
+    fn new(
+        check: impl Into<String>,
+        status: CheckStatus,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            check: check.into(),
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.check, self.message)
+    }
+}
+
+/// The `doctor` subcommand.
+#[instrument(level = "debug", skip_all)]
+pub async fn cmd_doctor(ui: &Ui, opts: &DoctorOpts) -> Result<()> {
+    let mut results = vec![
+        check_binary(
+            "pdftocairo",
+            "-v",
+            CheckStatus::Fail,
+            "needed for PDF input. Install poppler-utils (or poppler on macOS)",
+        )
+        .await,
+        check_binary(
+            "pdfinfo",
+            "-v",
+            CheckStatus::Fail,
+            "needed for PDF input. Install poppler-utils (or poppler on macOS)",
+        )
+        .await,
+        check_binary(
+            "tesseract",
+            "--version",
+            CheckStatus::Warn,
+            "needed for `ocr --model tesseract`. Install tesseract-ocr",
+        )
+        .await,
+        check_binary(
+            "ffmpeg",
+            "-version",
+            CheckStatus::Warn,
+            "needed for video frames. Install ffmpeg",
+        )
+        .await,
+    ];
+    results.push(check_litellm(opts).await);
+    results.push(check_api(opts).await);
+    for s3_path in &opts.s3_paths {
+        results.push(check_s3(s3_path).await);
+    }
+
+    for result in &results {
+        ui.display_message(result.status.emoji(), &result.to_string());
+    }
+    let failures = results
+        .iter()
+        .filter(|result| result.status == CheckStatus::Fail)
+        .count();
+
+    let rows = results
+        .iter()
+        .map(|result| serde_json::to_value(result).context("cannot serialize check"))
+        .collect::<Vec<_>>();
+    write_output(
+        opts.output_path.as_deref(),
+        None,
+        false,
+        stream::iter(rows).boxed(),
+        None,
+    )
+    .await?;
+
+    if failures > 0 {
+        Err(anyhow!("{failures} health check(s) failed"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that we can run `program`. If we can't, report `missing_status`,
+/// explaining that it's `needed_for`.
+async fn check_binary(
+    program: &str,
+    version_arg: &str,
+    missing_status: CheckStatus,
+    needed_for: &str,
+) -> CheckResult {
+    match Command::new(program).arg(version_arg).output().await {
+        Ok(output) => {
+            // Many tools print their version to stderr, so look at both.
+            let text = [&output.stdout[..], &output.stderr[..]].concat();
+            let version = String::from_utf8_lossy(&text)
+                .lines()
+                .map(str::trim)
+                .find(|line| !line.is_empty())
+                .unwrap_or("installed")
+                .to_owned();
+            CheckResult::new(program, CheckStatus::Pass, version)
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => CheckResult::new(
+            program,
+            missing_status,
+            format!("not found on PATH, {needed_for}"),
+        ),
+        Err(err) => CheckResult::new(
+            program,
+            missing_status,
+            format!("cannot run: {err}, {needed_for}"),
+        ),
+    }
+}
+
+/// Check whether LiteLLM knows about our model.
+async fn check_litellm(opts: &DoctorOpts) -> CheckResult {
+    const CHECK: &str = "litellm";
+    let driver = opts.llm_opts.driver;
+    if driver == DriverType::Echo {
+        return CheckResult::new(CHECK, CheckStatus::Pass, "not used by echo driver");
+    }
+    if !litellm_model_info_available().await {
+        return CheckResult::new(
+            CHECK,
+            CheckStatus::Warn,
+            "no LiteLLM model info available, so cost estimates and context window \
+             checks are disabled. Set LITELLM_API_BASE to use a LiteLLM proxy",
+        );
+    }
+    match litellm_model_info(&opts.model).await {
+        Some(info) => {
+            let max_input_tokens = info
+                .model_info
+                .max_input_tokens
+                .map_or_else(|| "unknown".to_owned(), |max| max.to_string());
+            CheckResult::new(
+                CHECK,
+                CheckStatus::Pass,
+                format!(
+                    "{} via {} (max input tokens: {max_input_tokens})",
+                    opts.model, info.model_info.litellm_provider
+                ),
+            )
+        }
+        // With the `openai` driver, we're probably talking to LiteLLM, which
+        // will reject models it doesn't know.
+        None if driver == DriverType::OpenAI => CheckResult::new(
+            CHECK,
+            CheckStatus::Fail,
+            format!(
+                "LiteLLM does not list model {:?}. Check the model name against \
+                 your proxy's model list",
+                opts.model
+            ),
+        ),
+        None => CheckResult::new(
+            CHECK,
+            CheckStatus::Warn,
+            format!(
+                "LiteLLM does not list model {:?}, so cost estimates are disabled",
+                opts.model
+            ),
+        ),
+    }
+}
+
+/// Check that we can create our driver, and optionally that it can answer a
+/// tiny request.
+async fn check_api(opts: &DoctorOpts) -> CheckResult {
+    let check = format!("{} driver", opts.llm_opts.driver);
+    let fail = |err: anyhow::Error| {
+        CheckResult::new(
+            &check,
+            CheckStatus::Fail,
+            format!("{err:#}. {}", credentials_hint(opts.llm_opts.driver)),
+        )
+    };
+
+    let driver = match opts.llm_opts.driver.create_driver(&opts.llm_opts).await {
+        Ok(driver) => driver,
+        Err(err) => return fail(err),
+    };
+    if opts.skip_request {
+        return CheckResult::new(
+            &check,
+            CheckStatus::Pass,
+            "client created (skipped test request)",
+        );
+    }
+
+    let prompt = match from_toml_str::<ChatPrompt>(CHECK_PROMPT) {
+        Ok(prompt) => prompt,
+        Err(err) => return fail(anyhow!("invalid built-in prompt: {err:?}")),
+    };
+    let schema = match prompt.response_json_schema().await {
+        Ok(schema) => schema,
+        Err(err) => return fail(err),
+    };
+    let prompt = match prompt.render(&JsonObject::new()) {
+        Ok(prompt) => prompt,
+        Err(err) => return fail(err),
+    };
+    let model_info = litellm_model_info(&opts.model).await;
+    let transforms =
+        SchemaTransforms::for_model(opts.llm_opts.driver, &opts.model, model_info);
+    let schema = match transforms.apply(&schema) {
+        Ok(schema) => schema,
+        Err(err) => return fail(err),
+    };
+    let result = driver
+        .chat_completion(&opts.model, model_info, &prompt, schema, &opts.llm_opts)
+        .await;
+    if let Err(err) = driver.cleanup().await {
+        warn!("Could not clean up after test request: {err:#}");
+    }
+    match result {
+        RetryResult::Ok { .. } => CheckResult::new(
+            &check,
+            CheckStatus::Pass,
+            format!("{} answered a test request", opts.model),
+        ),
+        RetryResult::Transient { error, .. } | RetryResult::Fatal { error, .. } => {
+            fail(error.context(format!("{} failed a test request", opts.model)))
+        }
+    }
+}
+
+/// Where to look when a driver can't authenticate.
+fn credentials_hint(driver: DriverType) -> &'static str {
+    match driver {
+        DriverType::OpenAI => {
+            "Check OPENAI_API_KEY and OPENAI_API_BASE (or LITELLM_API_KEY and \
+             LITELLM_API_BASE)"
+        }
+        DriverType::Native => {
+            "Check the API key for your model's provider, such as GEMINI_API_KEY \
+             or ANTHROPIC_API_KEY"
+        }
+        DriverType::Bedrock => "Check your AWS credentials, AWS_REGION and model access",
+        DriverType::Vertex => {
+            "Check GCP_PROJECT and Application Default Credentials, or VERTEX_API_KEY"
+        }
+        DriverType::Echo => "The echo driver should always work",
+    }
+}
+
+/// Check that we can list objects under an S3 URI.
+async fn check_s3(s3_path: &str) -> CheckResult {
+    let result = async {
+        let spec = s3_path.strip_prefix("s3://").unwrap_or(s3_path);
+        let (bucket, prefix) = spec.split_once('/').unwrap_or((spec, ""));
+        if bucket.is_empty() {
+            return Err(anyhow!("invalid S3 URI"));
+        }
+        create_s3_client()
+            .await?
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .max_keys(1)
+            .send()
+            .await
+            .context("cannot list objects")?;
+        Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => CheckResult::new(s3_path, CheckStatus::Pass, "can list objects"),
+        Err(err) => CheckResult::new(
+            s3_path,
+            CheckStatus::Fail,
+            format!(
+                "{err:#}. Check your AWS credentials and the bucket policy, which \
+                 must allow s3:ListBucket"
+            ),
+        ),
+    }
+}
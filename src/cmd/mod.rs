@@ -1,16 +1,39 @@
 //! Command-line entry points.
 
-use clap::Args;
-use futures::StreamExt as _;
+use std::{cmp::Ordering, num::NonZeroUsize, time::Duration};
+
+use clap::{Args, ValueEnum};
+use futures::{StreamExt as _, TryStreamExt as _, stream};
+use indicatif::ProgressBar;
+use serde::de::DeserializeOwned;
 
 use crate::{
-    async_utils::{BoxedFuture, BoxedStream},
+    async_utils::{
+        BoxedFuture, BoxedStream,
+        io::{
+            Compression, JsonOrTomlText, WriteOpts, parse_csv_delimiter,
+            uncompressed_extension,
+        },
+        spool::SortedSpool,
+    },
     prelude::*,
+    prompt::ChatPrompt,
+    queues::work::{IdFrom, RecordLimits, WorkOutput},
+    run_dir::CheckpointInterval,
+    spend_ledger::BudgetPeriod,
+    sqs::SqsOpts,
+    webhook::WebhookOpts,
 };
 
 pub mod chat;
+pub mod doctor;
 pub mod ocr;
+pub mod query;
+pub mod resume;
 pub mod schema;
+pub mod spend;
+pub mod tokens;
+pub mod transcribe;
 
 /// Common options for subcommands that process data streams.
 #[derive(Debug, Clone, Args)]
@@ -23,6 +46,34 @@ pub struct StreamOpts {
     #[clap(long, alias = "take-first")]
     pub limit: Option<usize>,
 
+    /// The delimiter for CSV input, as a single character or `tab`. Defaults
+    /// to tab for `.tsv` files, and `,` otherwise.
+    #[clap(long, value_parser = parse_csv_delimiter)]
+    pub csv_delimiter: Option<u8>,
+
+    /// Read input records from standard input as YAML. YAML input files are
+    /// recognized by their `.yaml` or `.yml` extension.
+    #[clap(long)]
+    pub yaml_stdin: bool,
+
+    /// Rename an input column before processing, as `--map id=document_id`.
+    /// May be repeated.
+    #[clap(long = "map", value_name = "FIELD=COLUMN", value_parser = parse_column_mapping)]
+    pub column_mappings: Vec<(String, String)>,
+
+    /// Copy these input columns into each output record's `passthrough_data`,
+    /// as `--passthrough-columns batch_id,case_number`. Columns are copied
+    /// under their original names, before any `--map`, and remain available
+    /// to the prompt.
+    #[clap(long, value_name = "COLUMNS", value_delimiter = ',')]
+    pub passthrough_columns: Vec<String>,
+
+    /// Generate each record's `id` from a column name, `row-number`, `uuid`
+    /// or `hash(col1,col2)`, for inputs without an `id` column. Any existing
+    /// `id` is replaced. `uuid` IDs are different on every run.
+    #[clap(long, value_name = "STRATEGY")]
+    pub id_from: Option<IdFrom>,
+
     /// Offset the start of processing by N records.
     #[clap(long, default_value = "0")]
     pub offset: usize,
@@ -32,13 +83,305 @@ pub struct StreamOpts {
     #[clap(long)]
     pub allow_reordering: bool,
 
+    /// Sort output records before writing them. This buffers all output in a
+    /// temporary file until processing finishes, so it works even with
+    /// `--allow-reordering`. It can't be used with `--run-dir` or queue
+    /// inputs, which need each output record written as soon as it's ready.
+    #[clap(long, value_enum, conflicts_with = "run_dir")]
+    pub sort_output_by: Option<SortOutputBy>,
+
     /// What portion of inputs should we allow to fail? Specified as a
     /// number between 0.0 and 1.0.
     #[clap(long, default_value = "0.01")]
     pub allowed_failure_rate: f32,
+
+    /// Fail any record which takes longer than this many seconds to process,
+    /// instead of letting it hold a worker slot indefinitely.
+    #[clap(long, value_name = "SECS")]
+    pub record_timeout: Option<u64>,
+
+    /// Fail any record whose input is larger than this many bytes, without
+    /// processing it. For `chat`, this is the size of the record as JSON. For
+    /// `ocr` and `transcribe`, it's the size of the local input file.
+    #[clap(long, value_name = "BYTES")]
+    pub max_record_bytes: Option<u64>,
+
+    /// At the end of the run, print p50, p95 and p99 LLM request latencies for
+    /// each model, including time to first byte where the driver supports it.
+    #[clap(long)]
+    pub latency_report: bool,
+
+    /// At the end of the run, write a JSON summary with record counts,
+    /// failures, cost, tokens, duration and throughput to this path, or to
+    /// standard output if the path is `-`. The summary is written even if the
+    /// run fails `--allowed-failure-rate`.
+    #[clap(long, value_name = "PATH")]
+    pub summary_json: Option<PathBuf>,
+
+    /// Output format. Defaults to guessing from the `--out` file extension,
+    /// and falling back to JSONL.
+    #[clap(long, value_enum)]
+    pub output_format: Option<OutputFormat>,
+
+    /// What to do if `--out` already exists: refuse to run (unless it's
+    /// empty), overwrite it, or append to it. DuckDB output is always added
+    /// to the existing database.
+    #[clap(long, value_enum, default_value = "error-if-exists")]
+    pub output_mode: OutputMode,
+
+    /// Compress JSONL or CSV output. Defaults to guessing from the `--out`
+    /// file extension (`.gz` or `.zst`).
+    #[clap(long, value_enum)]
+    pub compress: Option<Compression>,
+
+    /// Pretty-print JSON output, one indented object per record. This is not
+    /// valid JSONL, so it's only for debugging.
+    #[clap(long, conflicts_with = "partition_by")]
+    pub pretty: bool,
+
+    /// When writing to `--out postgres://...`, upsert records into this table.
+    /// The name is case-sensitive, and may include a schema, as in
+    /// `public.results`. The table must have a unique `id` column, and other
+    /// output fields are matched to columns by name. To hold output from named
+    /// prompts, it also needs a `prompt_name` column, with a unique constraint
+    /// on `(id, prompt_name)`. We can't connect using TLS yet, so `sslmode`
+    /// must be `disable` or `prefer`. When writing DuckDB output, append
+    /// records to this table (default: `outputs`).
+    #[clap(long)]
+    pub output_table: Option<String>,
+
+    /// Split JSONL output into one set of files per value of this output
+    /// field, such as `passthrough_data.customer`. `--out` must contain
+    /// `{field}` and `{n}`, as in `out/{field}/part-{n}.jsonl`.
+    #[clap(long)]
+    pub partition_by: Option<String>,
+
+    /// With `--partition-by`, start a new part once a file grows past this
+    /// many megabytes.
+    #[clap(long, default_value = "256", requires = "partition_by")]
+    pub partition_max_mb: u64,
+
+    /// Flush JSONL and CSV output every N records, so that less is lost if
+    /// we crash. Otherwise, output is buffered until the end of the run
+    /// (except with `--run-dir` or queue inputs, which flush every record).
+    #[clap(long, value_name = "N")]
+    pub flush_every: Option<NonZeroUsize>,
+
+    /// `fsync` JSONL and CSV output files after each `--flush-every` flush,
+    /// and at the end of the run.
+    #[clap(long)]
+    pub fsync: bool,
+
+    /// Write JSONL and CSV output to `PATH.tmp`, and only rename it to `PATH`
+    /// once every record has been written, so a partial file is never
+    /// mistaken for a complete run.
+    #[clap(long, conflicts_with_all = ["partition_by", "run_dir"])]
+    pub atomic_output: bool,
+
+    /// Write a JSON manifest describing this run, including the model and any
+    /// `--tag` values, to this path.
+    #[clap(long = "manifest", value_name = "PATH")]
+    pub manifest_path: Option<PathBuf>,
+
+    /// Keep this run's state in a directory, so that it can be continued with
+    /// `prompt-scaler resume DIR` after a crash or interruption. The directory
+    /// holds the command line, a checkpoint of finished record IDs, and
+    /// (unless overridden) the manifest, summary and `--out` output. Records
+    /// need stable IDs, so `--id-from uuid` isn't allowed.
+    #[clap(long, value_name = "DIR")]
+    pub run_dir: Option<PathBuf>,
+
+    /// With `--run-dir`, sync the output and checkpoint to disk after this
+    /// many records (like `100`) or seconds (like `30s`). Finished records
+    /// survive the process being killed either way, but those since the last
+    /// sync may be lost, and run again, if the machine crashes.
+    #[clap(
+        long,
+        value_name = "RECORDS|SECONDSs",
+        default_value = "100",
+        requires = "run_dir"
+    )]
+    pub checkpoint_interval: CheckpointInterval,
+
+    /// Are we resuming the run in `run_dir`? Set by `prompt-scaler resume`.
+    #[clap(skip)]
+    pub resume: bool,
+
+    /// Add this run's estimated spend to a JSON ledger at this path, which
+    /// is shared across runs. View totals with the `spend` subcommand.
+    #[clap(long, value_name = "PATH")]
+    pub spend_ledger: Option<PathBuf>,
+
+    /// Refuse to start if the spend ledger shows at least this many US
+    /// dollars already spent in the current `--budget-period`, across all
+    /// models.
+    #[clap(long, value_name = "USD", requires = "spend_ledger")]
+    pub budget_period_cap: Option<f64>,
+
+    /// The period used by `--budget-period-cap`, in UTC.
+    #[clap(long, value_enum, default_value = "day")]
+    pub budget_period: BudgetPeriod,
+
+    /// SQS input options.
+    #[clap(flatten)]
+    pub sqs_opts: SqsOpts,
+
+    /// Webhook output options.
+    #[clap(flatten)]
+    pub webhook_opts: WebhookOpts,
+}
+
+/// Parse a `--map FIELD=COLUMN` argument.
+fn parse_column_mapping(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((field, column)) if !field.is_empty() && !column.is_empty() => {
+            Ok((field.to_owned(), column.to_owned()))
+        }
+        _ => Err(format!("expected FIELD=COLUMN, got {arg:?}")),
+    }
+}
+
+/// Read a prompt from `--prompt PATH` or `--prompt-inline TEXT`, if either was
+/// given. A path of `-` reads the prompt from standard input, which can't also
+/// be used for input records, and can't be read again by `resume`.
+pub async fn read_prompt(
+    path: Option<&Path>,
+    inline: Option<&str>,
+    input_from_stdin: bool,
+    stream_opts: &StreamOpts,
+) -> Result<Option<ChatPrompt>> {
+    if let Some(text) = inline {
+        let text = JsonOrTomlText::new("--prompt-inline".to_owned(), text.to_owned());
+        return ChatPrompt::from_text(text).map(Some);
+    }
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    if path == Path::new("-") {
+        if input_from_stdin {
+            return Err(anyhow!(
+                "--prompt - reads the prompt from standard input, so input records \
+                 must come from a file"
+            ));
+        }
+        if stream_opts.run_dir.is_some() {
+            return Err(anyhow!(
+                "--prompt - can't be read again by `resume`, so it can't be used \
+                 with --run-dir. Use --prompt-inline or a prompt file instead"
+            ));
+        }
+    }
+    Ok(Some(ChatPrompt::read(path).await?))
+}
+
+/// Output formats we support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// JSON Lines.
+    Jsonl,
+    /// CSV (for `ocr`, `transcribe`, and `chat` with `classify` prompts).
+    Csv,
+    /// A DuckDB database file, with typed columns.
+    Duckdb,
+}
+
+/// What to do with an existing output file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputMode {
+    /// Replace the file.
+    Overwrite,
+    /// Add to the end of the file (JSONL only).
+    Append,
+    /// Fail, unless the file is empty.
+    ErrorIfExists,
+}
+
+/// How to sort output records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortOutputBy {
+    /// Sort by record ID. Numeric IDs, including numbers read from CSV files,
+    /// sort numerically, and other string IDs sort lexically.
+    Id,
+    /// Restore the original input order.
+    Input,
 }
 
 impl StreamOpts {
+    /// Get our output format, guessing from `output_path` if necessary.
+    pub fn output_format(&self, output_path: Option<&Path>) -> OutputFormat {
+        self.output_format.unwrap_or_else(|| {
+            match output_path
+                .and_then(uncompressed_extension)
+                .and_then(|ext| ext.to_str())
+            {
+                Some("csv") => OutputFormat::Csv,
+                Some("duckdb" | "ddb") => OutputFormat::Duckdb,
+                _ => OutputFormat::Jsonl,
+            }
+        })
+    }
+
+    /// Make sure `--output-mode` allows us to write to `output_path`.
+    pub async fn check_output_mode(&self, output_path: Option<&Path>) -> Result<()> {
+        // Resumed runs always append to their existing output.
+        let Some(path) = output_path
+            .filter(|path| !self.resume && !path.to_string_lossy().contains("://"))
+        else {
+            return Ok(());
+        };
+        let format = self.output_format(Some(path));
+        match self.output_mode {
+            OutputMode::Overwrite => Ok(()),
+            OutputMode::Append => {
+                if format == OutputFormat::Csv || self.partition_by.is_some() {
+                    return Err(anyhow!(
+                        "--output-mode append only supports unpartitioned JSONL output"
+                    ));
+                }
+                if self.atomic_output {
+                    return Err(anyhow!(
+                        "--output-mode append can't be used with --atomic-output"
+                    ));
+                }
+                Ok(())
+            }
+            OutputMode::ErrorIfExists => {
+                if format == OutputFormat::Duckdb || self.partition_by.is_some() {
+                    return Ok(());
+                }
+                match tokio::fs::metadata(path).await {
+                    Ok(metadata) if metadata.len() > 0 => Err(anyhow!(
+                        "{path:?} already exists. Pass --output-mode overwrite to replace \
+                         it, or --output-mode append to add to it"
+                    )),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Should we append to our output, instead of replacing it?
+    pub fn appends_output(&self) -> bool {
+        self.resume || self.output_mode == OutputMode::Append
+    }
+
+    /// Get our options for writing output files.
+    pub fn write_opts(&self) -> WriteOpts {
+        WriteOpts {
+            flush_every: self.flush_every,
+            fsync: self.fsync,
+            atomic: self.atomic_output,
+        }
+    }
+
+    /// Get our per-record limits.
+    pub fn record_limits(&self) -> RecordLimits {
+        RecordLimits {
+            timeout: self.record_timeout.map(Duration::from_secs),
+            max_bytes: self.max_record_bytes,
+        }
+    }
+
     /// Apply any necessary stream opts to our input stream.
     pub fn apply_stream_input_opts<T>(
         &self,
@@ -55,18 +398,127 @@ impl StreamOpts {
         }
     }
 
-    /// Apply our buffering options to a stream of futures.
+    /// Apply our buffering and sorting options to a stream of futures,
+    /// updating `pb` as each record completes.
     pub fn apply_stream_buffering_opts<T>(
         &self,
-        input: BoxedStream<BoxedFuture<Result<T>>>,
-    ) -> BoxedStream<Result<T>>
+        pb: &ProgressBar,
+        input: BoxedStream<BoxedFuture<Result<WorkOutput<T>>>>,
+    ) -> BoxedStream<Result<WorkOutput<T>>>
     where
-        T: 'static + Send,
+        T: Serialize + DeserializeOwned + Send + 'static,
     {
-        if self.allow_reordering {
-            input.buffer_unordered(self.job_count).boxed()
+        let Some(sort_output_by) = self.sort_output_by else {
+            let output = if self.allow_reordering {
+                input.buffer_unordered(self.job_count).boxed()
+            } else {
+                input.buffered(self.job_count).boxed()
+            };
+            return pb.clone().wrap_stream(output).boxed();
+        };
+
+        // Remember the input order of each record, so that we can restore it
+        // or use it to break ties.
+        let indexed = input.enumerate().map(|(idx, future)| {
+            async move { Ok::<_, anyhow::Error>((idx, future.await?)) }.boxed()
+        });
+        let indexed = if self.allow_reordering {
+            indexed.buffer_unordered(self.job_count).boxed()
         } else {
-            input.buffered(self.job_count).boxed()
-        }
+            indexed.buffered(self.job_count).boxed()
+        };
+        let mut indexed = pb.clone().wrap_stream(indexed);
+
+        // Spool everything to disk, then read it back in sorted order.
+        stream::once(async move {
+            let mut spool = SortedSpool::new()?;
+            while let Some(item) = indexed.next().await {
+                let (idx, output): (usize, WorkOutput<T>) = item?;
+                let id = match sort_output_by {
+                    SortOutputBy::Id => output.id.clone(),
+                    SortOutputBy::Input => Value::Null,
+                };
+                spool.push((id, idx), &output).await?;
+            }
+            spool
+                .into_sorted_stream(|(a_id, a_idx), (b_id, b_idx)| {
+                    compare_ids(a_id, b_id).then_with(|| a_idx.cmp(b_idx))
+                })
+                .await
+        })
+        .try_flatten()
+        .boxed()
+    }
+}
+
+/// Compare two record IDs. Numbers, including strings which hold numbers
+/// (like IDs read from CSV files), sort numerically before other strings. Any
+/// other values sort after both, by their JSON representation.
+fn compare_ids(a: &Value, b: &Value) -> Ordering {
+    match (numeric_id(a), numeric_id(b)) {
+        // Break ties between `"01"` and `"1"`, or numbers too close for `f64`.
+        (Some(a_num), Some(b_num)) => a_num
+            .total_cmp(&b_num)
+            .then_with(|| a.to_string().cmp(&b.to_string())),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => match (a, b) {
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::String(_), _) => Ordering::Less,
+            (_, Value::String(_)) => Ordering::Greater,
+            _ => a.to_string().cmp(&b.to_string()),
+        },
+    }
+}
+
+/// The numeric value of an ID, if it's a number or a string holding one.
+fn numeric_id(id: &Value) -> Option<f64> {
+    match id {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse::<f64>().ok().filter(|n| n.is_finite()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_ids() {
+        let mut ids = vec![
+            json!("b"),
+            json!(10),
+            json!(null),
+            json!("a"),
+            json!(2),
+            json!(1.5),
+            json!("9"),
+            json!("100"),
+        ];
+        ids.sort_by(compare_ids);
+        assert_eq!(
+            ids,
+            vec![
+                json!(1.5),
+                json!(2),
+                json!("9"),
+                json!(10),
+                json!("100"),
+                json!("a"),
+                json!("b"),
+                json!(null),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_column_mapping() {
+        assert_eq!(
+            parse_column_mapping("id=document_id"),
+            Ok(("id".to_owned(), "document_id".to_owned()))
+        );
+        assert!(parse_column_mapping("id").is_err());
+        assert!(parse_column_mapping("=document_id").is_err());
     }
 }
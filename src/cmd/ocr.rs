@@ -1,43 +1,65 @@
 //! The `ocr` subcommand.
 
-use std::ffi::OsStr;
-
 use clap::Args;
-use futures::StreamExt;
+use futures::{StreamExt as _, stream};
+use glob::{MatchOptions, Pattern, glob_with};
+use schemars::schema_for;
 
 use crate::{
-    async_utils::io::read_json_or_toml,
+    async_utils::WORKER_SHUTDOWN_GRACE,
+    cmd::{OutputFormat, read_prompt},
     drivers::LlmOpts,
+    manifest::RunManifest,
     page_iter::PageIterOptions,
     prelude::*,
-    prompt::ChatPrompt,
     queues::{
         ocr::{
-            OcrInput, OcrOutput, OcrStreamInfo, engines::llm::default_ocr_prompt,
+            OcrInput, OcrOutput, OcrStreamInfo,
+            engines::{
+                check_ocr_engine_opts, llm::default_ocr_prompt, textract::TextractOpts,
+            },
             ocr_files,
         },
-        work::{WorkInput, WorkOutput},
+        work::{WorkInput, WorkInputStreamInfo, WorkOutput, track_ack_tokens},
     },
+    result_store::output_columns,
+    run_dir::apply_run_dir,
+    spend_ledger::{SpendTracker, check_budget},
     ui::{ProgressConfig, Ui},
+    webhook::Webhook,
 };
 
 /// Command line arguments for the `ocr` subcommand.
 #[derive(Debug, Args)]
 pub struct OcrOpts {
-    /// Input data, in CSV or JSONL format. Defaults to standard input.
+    /// Input data, in CSV or JSONL format. May also be an `sqs://`,
+    /// `kafka://`, `nats://` or `postgres://` URL, or a directory of files to
+    /// OCR. Defaults to standard input.
     pub input_path: Option<PathBuf>,
 
+    /// OCR all files matching this glob pattern, such as `docs/**/*.pdf`,
+    /// instead of reading input records. Each file's ID is its path relative
+    /// to the directory containing the pattern's first wildcard.
+    #[clap(long = "glob", conflicts_with = "input_path")]
+    pub glob_pattern: Option<String>,
+
     /// Model to use by default.
     #[clap(short = 'm', long, default_value = "gemini-2.0-flash")]
     pub model: String,
 
     /// Prompt, in TOML or JSON format. The `response_schema` field will be
-    /// ignored. Defaults to a generic OCR prompt.
+    /// ignored. Defaults to a generic OCR prompt. Use `-` to read the prompt
+    /// from standard input, if input records come from a file.
     #[clap(short = 'p', long = "prompt")]
     pub prompt_path: Option<PathBuf>,
 
-    /// Output location, in CSV or JSONL format. Defaults to standard output and
-    /// JSONL.
+    /// Prompt text, in TOML or JSON format. Use instead of `--prompt` when
+    /// writing a prompt file would be awkward.
+    #[clap(long, value_name = "TOML", conflicts_with = "prompt_path")]
+    pub prompt_inline: Option<String>,
+
+    /// Output location, in CSV or JSONL format. May also be a `kafka://`,
+    /// `nats://` or `postgres://` URL. Defaults to standard output and JSONL.
     #[clap(short = 'o', long = "out")]
     pub output_path: Option<PathBuf>,
 
@@ -45,6 +67,12 @@ pub struct OcrOpts {
     #[clap(long)]
     pub include_page_breaks: bool,
 
+    /// Write a searchable copy of each input PDF to this directory, with the
+    /// OCR text added as an invisible text layer. Requires `tesseract`,
+    /// `textract` or `textract-async`.
+    #[clap(long = "write-searchable-pdf", value_name = "DIR")]
+    pub searchable_pdf_dir: Option<PathBuf>,
+
     /// Stream-related options.
     #[clap(flatten)]
     pub stream_opts: super::StreamOpts,
@@ -56,24 +84,73 @@ pub struct OcrOpts {
     /// Our LLM options.
     #[clap(flatten)]
     pub llm_opts: LlmOpts,
+
+    /// Textract-specific options.
+    #[clap(flatten)]
+    pub textract_opts: TextractOpts,
 }
 
 /// The `ocr` subcommand.
 #[instrument(level = "debug", skip_all)]
 #[allow(clippy::too_many_arguments)]
 pub async fn cmd_ocr(ui: &Ui, opts: &OcrOpts) -> Result<()> {
+    // Reject flags which don't make sense for our engine before doing any work.
+    check_ocr_engine_opts(
+        &opts.model,
+        &opts.page_iter_opts,
+        &opts.llm_opts,
+        &opts.textract_opts,
+        opts.searchable_pdf_dir.is_some(),
+    )?;
+
     // Get our OCR prompt.
-    let prompt = match opts.prompt_path.as_deref() {
-        Some(path) => read_json_or_toml::<ChatPrompt>(path).await?,
-        None => default_ocr_prompt(),
-    };
+    let prompt = read_prompt(
+        opts.prompt_path.as_deref(),
+        opts.prompt_inline.as_deref(),
+        opts.input_path.is_none() && opts.glob_pattern.is_none(),
+        &opts.stream_opts,
+    )
+    .await?
+    .unwrap_or_else(default_ocr_prompt);
 
-    // Open up our input stream and parse into records.
-    let input =
-        WorkInput::<OcrInput>::read_stream(ui.clone(), opts.input_path.as_deref())
-            .await?;
+    // Open up our input stream and parse into records, or list our input
+    // files if we were given a glob pattern or a directory.
+    let input_dir = opts.input_path.as_deref().filter(|path| path.is_dir());
+    let WorkInputStreamInfo { stream: input, ack } =
+        if let Some(pattern) = &opts.glob_pattern {
+            glob_input_stream(pattern.to_owned(), glob_base_dir(pattern))?
+        } else if let Some(dir) = input_dir {
+            let pattern = Path::new(&Pattern::escape(&dir.to_string_lossy()))
+                .join("**")
+                .join("*");
+            glob_input_stream(pattern.to_string_lossy().into_owned(), dir.to_owned())?
+        } else {
+            WorkInput::<OcrInput>::read_stream(
+                ui.clone(),
+                opts.input_path.as_deref(),
+                &opts.stream_opts,
+            )
+            .await?
+        };
     let input = opts.stream_opts.apply_stream_input_opts(input);
 
+    // Skip anything an earlier attempt at this `--run-dir` already finished.
+    let (input, ack) =
+        apply_run_dir(&opts.stream_opts, opts.output_path.as_deref(), input, ack).await?;
+
+    // Make sure we haven't already used up our budget.
+    check_budget(&opts.stream_opts).await?;
+
+    // Start delivering records to our webhook, if we have one.
+    let webhook = Webhook::start(&opts.stream_opts.webhook_opts)?;
+
+    // Record how this run was configured.
+    RunManifest::new("ocr", &opts.model)
+        .with_llm_opts(&opts.llm_opts)
+        .with_stream_opts(&opts.stream_opts)
+        .write(opts.stream_opts.manifest_path.as_deref())
+        .await?;
+
     // Configure our progress bar.
     let pb = ui.new_from_size_hint(
         &ProgressConfig {
@@ -92,31 +169,125 @@ pub async fn cmd_ocr(ui: &Ui, opts: &OcrOpts) -> Result<()> {
         opts.include_page_breaks,
         opts.page_iter_opts.to_owned(),
         opts.llm_opts.to_owned(),
+        opts.textract_opts.to_owned(),
+        opts.searchable_pdf_dir.clone(),
+        opts.stream_opts.record_limits(),
     )
     .await?;
-    let output = pb
-        .wrap_stream(opts.stream_opts.apply_stream_buffering_opts(stream))
-        .boxed();
+    let output = opts.stream_opts.apply_stream_buffering_opts(&pb, stream);
+    let spend = SpendTracker::default();
+    let output = spend.wrap_stream(output);
+    let output = webhook.wrap_stream(output);
+    let (output, ack) = track_ack_tokens(output, ack);
 
-    match opts.output_path.as_deref() {
-        Some(path) if path.extension() == Some(OsStr::new("csv")) => {
+    let written = match opts.stream_opts.output_format(opts.output_path.as_deref()) {
+        OutputFormat::Csv => {
             WorkOutput::<OcrOutput>::write_stream_to_csv(
                 ui,
                 opts.output_path.as_deref(),
                 output,
                 &opts.stream_opts,
+                ack.as_deref(),
             )
-            .await?;
+            .await
         }
-        _ => {
+        OutputFormat::Duckdb => {
+            let schema = serde_json::to_value(schema_for!(OcrOutput))
+                .context("failed to serialize OCR output schema")?;
+            WorkOutput::<OcrOutput>::write_stream_to_duckdb(
+                ui,
+                opts.output_path.as_deref(),
+                output,
+                &opts.stream_opts,
+                &output_columns(&schema, None),
+                ack.as_deref(),
+            )
+            .await
+        }
+        OutputFormat::Jsonl => {
             WorkOutput::write_stream(
                 ui,
                 opts.output_path.as_deref(),
                 output,
                 &opts.stream_opts,
+                ack.as_deref(),
             )
-            .await?;
+            .await
+        }
+    };
+    let recorded = spend.record(&opts.stream_opts, &opts.model, None).await;
+    let delivered = webhook.finish().await;
+    worker
+        .finish(written.and(recorded).and(delivered), WORKER_SHUTDOWN_GRACE)
+        .await
+}
+
+/// Build input records for each file matching `pattern`, using paths relative
+/// to `base_dir` as IDs. Hidden files are skipped unless the pattern names
+/// them explicitly.
+fn glob_input_stream(
+    pattern: String,
+    base_dir: PathBuf,
+) -> Result<WorkInputStreamInfo<OcrInput>> {
+    let options = MatchOptions {
+        require_literal_leading_dot: true,
+        ..MatchOptions::new()
+    };
+    let paths = glob_with(&pattern, options)
+        .with_context(|| format!("invalid glob pattern {pattern:?}"))?;
+    let mut records = vec![];
+    for path in paths {
+        let path = path.context("cannot read matching file")?;
+        if !path.is_file() {
+            continue;
         }
+        let id = path
+            .strip_prefix(&base_dir)
+            .unwrap_or(&path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        records.push(Ok(WorkInput {
+            id: Value::String(id),
+            skip_processing: None,
+            passthrough_data: None,
+            ack_token: None,
+            data: OcrInput {
+                path: path.to_string_lossy().into_owned(),
+                password: None,
+            },
+        }));
+    }
+    if records.is_empty() {
+        return Err(anyhow!("no files match {pattern:?}"));
+    }
+    Ok(WorkInputStreamInfo {
+        stream: stream::iter(records).boxed(),
+        ack: None,
+    })
+}
+
+/// Get the directory containing the first wildcard in a glob pattern. If there
+/// are no wildcards, this is the directory containing the named file.
+fn glob_base_dir(pattern: &str) -> PathBuf {
+    let components = Path::new(pattern).components().collect::<Vec<_>>();
+    let literal_len = components
+        .iter()
+        .position(|c| c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .unwrap_or(components.len().saturating_sub(1));
+    components[..literal_len].iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_base_dir_stops_at_first_wildcard() {
+        assert_eq!(glob_base_dir("docs/**/*.pdf"), Path::new("docs"));
+        assert_eq!(glob_base_dir("docs/2024-*/a.pdf"), Path::new("docs"));
+        assert_eq!(glob_base_dir("*.pdf"), Path::new(""));
+        assert_eq!(glob_base_dir("docs/a.pdf"), Path::new("docs"));
     }
-    worker.join().await
 }
@@ -0,0 +1,27 @@
+//! The `query` subcommand.
+
+use clap::Args;
+
+use crate::{async_utils::io::write_output, prelude::*, result_store::query_duckdb};
+
+/// Command line arguments for the `query` subcommand.
+#[derive(Debug, Args)]
+pub struct QueryOpts {
+    /// A DuckDB file written using `--output-format duckdb`.
+    pub db_path: PathBuf,
+
+    /// The SQL query to run. Results are stored in the `outputs` table by
+    /// default.
+    pub sql: String,
+
+    /// Output location, in JSONL format. Defaults to standard output.
+    #[clap(short = 'o', long = "out")]
+    pub output_path: Option<PathBuf>,
+}
+
+/// The `query` subcommand.
+#[instrument(level = "debug", skip_all)]
+pub async fn cmd_query(opts: &QueryOpts) -> Result<()> {
+    let stream = query_duckdb(&opts.db_path, &opts.sql);
+    write_output(opts.output_path.as_deref(), None, false, stream, None).await
+}
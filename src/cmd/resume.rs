@@ -0,0 +1,15 @@
+//! The `resume` subcommand.
+//!
+//! Resuming replaces our own options with those recorded in the run directory,
+//! so the real work happens in `main`, using [`crate::run_dir`].
+
+use clap::Args;
+
+use crate::prelude::*;
+
+/// Command line arguments for the `resume` subcommand.
+#[derive(Debug, Args)]
+pub struct ResumeOpts {
+    /// A directory created by `chat`, `ocr` or `transcribe` with `--run-dir`.
+    pub run_dir: PathBuf,
+}
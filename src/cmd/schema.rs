@@ -9,45 +9,132 @@ use schemars::{
 use tokio::io::AsyncWriteExt as _;
 
 use crate::{
-    async_utils::io::create_writer,
+    async_utils::io::{create_writer, read_json_or_toml_as_json_value},
     prelude::*,
     prompt::ChatPrompt,
+    prompt_router::PromptRouter,
     queues::{
         chat::{ChatInput, ChatOutput},
         ocr::{OcrInput, OcrOutput},
-        work::{WorkInput, WorkOutput},
+        transcribe::{TranscribeInput, TranscribeOutput},
+        work::{RunSummary, WorkInput, WorkOutput},
     },
+    schema::hoist_definitions,
 };
 
 /// The different schema types we support.
 ///
-/// We parse these as PascalCase, because they represent type names.
+/// We parse these as PascalCase, because they represent type names, but we
+/// also accept kebab-case aliases like `chat-input`.
+///
+/// When adding a record type, add it here and to [`SchemaType::root_schema`],
+/// and `schema --list` will include it.
 #[derive(Debug, Clone, Copy, ValueEnum)]
 #[clap(rename_all = "PascalCase")]
 pub enum SchemaType {
     /// Chat input.
+    #[value(alias = "chat-input")]
     ChatInput,
     /// Chat output.
+    #[value(alias = "chat-output")]
     ChatOutput,
     /// Chat prompt.
+    #[value(alias = "chat-prompt")]
     ChatPrompt,
     /// OCR input.
+    #[value(alias = "ocr-input")]
     OcrInput,
     /// OCR output.
+    #[value(alias = "ocr-output")]
     OcrOutput,
+    /// Transcription input.
+    #[value(alias = "transcribe-input")]
+    TranscribeInput,
+    /// Transcription output.
+    #[value(alias = "transcribe-output")]
+    TranscribeOutput,
+    /// Run summary, as written by `--summary-json`.
+    #[value(alias = "run-summary")]
+    RunSummary,
+}
+
+impl SchemaType {
+    /// The name of this schema type, as accepted on the command line and
+    /// used as the schema's title.
+    fn name(self) -> String {
+        self.to_possible_value()
+            .expect("no schema types are skipped")
+            .get_name()
+            .to_owned()
+    }
+
+    /// Is this an output record type?
+    fn is_output(self) -> bool {
+        matches!(
+            self,
+            SchemaType::ChatOutput | SchemaType::OcrOutput | SchemaType::TranscribeOutput
+        )
+    }
+
+    /// Generate the generic schema for this type.
+    fn root_schema(self, generator: SchemaGenerator) -> RootSchema {
+        let schema = match self {
+            SchemaType::ChatInput => {
+                generator.into_root_schema_for::<WorkInput<ChatInput>>()
+            }
+            SchemaType::ChatOutput => {
+                generator.into_root_schema_for::<WorkOutput<ChatOutput>>()
+            }
+            SchemaType::ChatPrompt => generator.into_root_schema_for::<ChatPrompt>(),
+            SchemaType::OcrInput => {
+                generator.into_root_schema_for::<WorkInput<OcrInput>>()
+            }
+            SchemaType::OcrOutput => {
+                generator.into_root_schema_for::<WorkOutput<OcrOutput>>()
+            }
+            SchemaType::TranscribeInput => {
+                generator.into_root_schema_for::<WorkInput<TranscribeInput>>()
+            }
+            SchemaType::TranscribeOutput => {
+                generator.into_root_schema_for::<WorkOutput<TranscribeOutput>>()
+            }
+            SchemaType::RunSummary => generator.into_root_schema_for::<RunSummary>(),
+        };
+        schema.with_title(&self.name())
+    }
 }
 
 /// Schema command line arguments.
 #[derive(Debug, Args)]
 pub struct SchemaOpts {
     /// The schema type to generate.
-    #[clap(value_enum, value_name = "TYPE")]
-    pub schema_type: SchemaType,
+    #[clap(value_enum, value_name = "TYPE", required_unless_present = "list")]
+    pub schema_type: Option<SchemaType>,
+
+    /// List the supported schema types, one per line, instead of generating a
+    /// schema.
+    #[clap(long, conflicts_with = "schema_type")]
+    pub list: bool,
 
     /// Should we inline all the subschemas?
     #[clap(long = "inline")]
     pub inline: bool,
 
+    /// For `ChatOutput`, use the response schema from this prompt, to describe
+    /// the output of a specific run.
+    #[clap(short = 'p', long = "prompt")]
+    pub prompt_path: Option<PathBuf>,
+
+    /// For `ChatOutput`, use the response schemas from every prompt in this
+    /// router. Use instead of `--prompt`.
+    #[clap(long = "prompt-router", conflicts_with = "prompt_path")]
+    pub prompt_router_path: Option<PathBuf>,
+
+    /// For output types, describe `passthrough_data` using this JSON Schema,
+    /// in JSON or TOML format.
+    #[clap(long)]
+    pub passthrough_schema: Option<PathBuf>,
+
     /// The output path to write the schema to.
     #[clap(short = 'o', long = "out")]
     pub output_path: Option<PathBuf>,
@@ -56,42 +143,95 @@ pub struct SchemaOpts {
 /// The `schema` subcommand.
 #[instrument(level = "debug", skip_all)]
 pub async fn cmd_schema(schema_opts: &SchemaOpts) -> Result<()> {
-    let mut settings = SchemaSettings::draft07();
-    if schema_opts.inline {
-        settings.inline_subschemas = true;
-    }
-    let generator = SchemaGenerator::new(settings);
-
-    // Get our schema.
-    let schema = match schema_opts.schema_type {
-        SchemaType::ChatInput => generator
-            .into_root_schema_for::<WorkInput<ChatInput>>()
-            .with_title("ChatInput"),
-        SchemaType::ChatOutput => generator
-            .into_root_schema_for::<WorkOutput<ChatOutput>>()
-            .with_title("ChatOutput"),
-        SchemaType::ChatPrompt => generator
-            .into_root_schema_for::<ChatPrompt>()
-            .with_title("ChatPrompt"),
-        SchemaType::OcrInput => generator
-            .into_root_schema_for::<WorkInput<OcrInput>>()
-            .with_title("OcrInput"),
-        SchemaType::OcrOutput => generator
-            .into_root_schema_for::<WorkOutput<OcrOutput>>()
-            .with_title("OcrOutput"),
+    let output = match schema_opts.schema_type {
+        Some(schema_type) => {
+            let mut settings = SchemaSettings::draft07();
+            if schema_opts.inline {
+                settings.inline_subschemas = true;
+            }
+            let schema = schema_type.root_schema(SchemaGenerator::new(settings));
+
+            // Specialize our schema for a particular run, if asked.
+            let schema =
+                specialize_output_schema(schema_opts, schema_type, schema).await?;
+            serde_json::to_string_pretty(&schema).context("failed to serialize schema")?
+        }
+        // `--list` is the only way to get here.
+        None => SchemaType::value_variants()
+            .iter()
+            .map(|schema_type| format!("{}\n", schema_type.name()))
+            .collect(),
     };
 
-    // Write out our schema.
-    let mut wtr = create_writer(schema_opts.output_path.as_deref()).await?;
-    let schema_str =
-        serde_json::to_string_pretty(&schema).context("failed to serialize schema")?;
-    wtr.write_all(schema_str.as_bytes())
+    // Write out our schema or list.
+    let mut wtr = create_writer(schema_opts.output_path.as_deref(), None).await?;
+    wtr.write_all(output.as_bytes())
         .await
         .context("failed to write schema")?;
-    wtr.flush().await.context("failed to flush schema")?;
+    wtr.shutdown().await.context("failed to flush schema")?;
     Ok(())
 }
 
+/// Replace the generic `response` and `passthrough_data` schemas in an output
+/// schema with the ones used by a specific run.
+async fn specialize_output_schema(
+    schema_opts: &SchemaOpts,
+    schema_type: SchemaType,
+    schema: RootSchema,
+) -> Result<Value> {
+    let mut schema =
+        serde_json::to_value(schema).context("failed to serialize schema")?;
+
+    // Look up the response schemas for our prompts, ignoring duplicates.
+    let prompts = match (&schema_opts.prompt_path, &schema_opts.prompt_router_path) {
+        (_, Some(router_path)) => Some(PromptRouter::from_path(router_path).await?),
+        (Some(prompt_path), None) => {
+            Some(PromptRouter::single(ChatPrompt::read(prompt_path).await?))
+        }
+        (None, None) => None,
+    };
+    if let Some(prompts) = prompts {
+        if !matches!(schema_type, SchemaType::ChatOutput) {
+            return Err(anyhow!(
+                "--prompt and --prompt-router can only be used with ChatOutput"
+            ));
+        }
+        let mut responses = Vec::<Value>::new();
+        for prompt in prompts.prompts() {
+            let response = prompt.response_json_schema().await?;
+            if !responses.contains(&response) {
+                responses.push(response);
+            }
+        }
+        let response = if responses.len() == 1 {
+            hoist_definitions(&mut schema, responses.remove(0), "Response_")
+        } else {
+            let responses = responses
+                .into_iter()
+                .enumerate()
+                .map(|(idx, response)| {
+                    hoist_definitions(&mut schema, response, &format!("Response{idx}_"))
+                })
+                .collect::<Vec<_>>();
+            json!({ "anyOf": responses })
+        };
+        schema["properties"]["response"] = response;
+    }
+
+    // Describe our passthrough data.
+    if let Some(path) = &schema_opts.passthrough_schema {
+        if !schema_type.is_output() {
+            return Err(anyhow!(
+                "--passthrough-schema can only be used with output types"
+            ));
+        }
+        let passthrough = read_json_or_toml_as_json_value(path).await?;
+        schema["properties"]["passthrough_data"] =
+            hoist_definitions(&mut schema, passthrough, "Passthrough_");
+    }
+    Ok(schema)
+}
+
 /// Extensions for setting the title of a root schema.
 trait WithTitle {
     /// Set the title of the schema.
@@ -112,3 +252,30 @@ impl WithTitle for RootSchema {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_schema_type_is_titled_with_its_name() {
+        for &schema_type in SchemaType::value_variants() {
+            let generator = SchemaGenerator::new(SchemaSettings::draft07());
+            let schema = schema_type.root_schema(generator);
+            let title = schema.schema.metadata.and_then(|metadata| metadata.title);
+            assert_eq!(title, Some(schema_type.name()));
+        }
+    }
+
+    #[test]
+    fn schema_types_accept_kebab_case() {
+        assert!(matches!(
+            SchemaType::from_str("ocr-output", false),
+            Ok(SchemaType::OcrOutput)
+        ));
+        assert!(matches!(
+            SchemaType::from_str("RunSummary", false),
+            Ok(SchemaType::RunSummary)
+        ));
+    }
+}
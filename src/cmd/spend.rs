@@ -0,0 +1,38 @@
+//! The `spend` subcommand.
+
+use clap::Args;
+use futures::{StreamExt as _, stream};
+
+use crate::{
+    async_utils::io::write_output,
+    prelude::*,
+    spend_ledger::{BudgetPeriod, SpendLedger},
+};
+
+/// Command line arguments for the `spend` subcommand.
+#[derive(Debug, Args)]
+pub struct SpendOpts {
+    /// A ledger written using `--spend-ledger`.
+    pub ledger_path: PathBuf,
+
+    /// Total spend by this period, in UTC.
+    #[clap(long, value_enum, default_value = "day")]
+    pub period: BudgetPeriod,
+
+    /// Output location, in JSONL format. Defaults to standard output.
+    #[clap(short = 'o', long = "out")]
+    pub output_path: Option<PathBuf>,
+}
+
+/// The `spend` subcommand.
+#[instrument(level = "debug", skip_all)]
+pub async fn cmd_spend(opts: &SpendOpts) -> Result<()> {
+    let ledger = SpendLedger::load(&opts.ledger_path).await?;
+    let rows = ledger
+        .totals(opts.period)
+        .into_iter()
+        .map(|total| serde_json::to_value(total).context("cannot serialize spend"))
+        .collect::<Vec<_>>();
+    let stream = stream::iter(rows).boxed();
+    write_output(opts.output_path.as_deref(), None, false, stream, None).await
+}
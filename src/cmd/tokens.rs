@@ -0,0 +1,111 @@
+//! The `tokens` subcommand.
+
+use clap::Args;
+use futures::{StreamExt as _, stream};
+
+use crate::{
+    async_utils::io::{parse_csv_delimiter, read_jsonl_or_csv, write_output},
+    prelude::*,
+    prompt::ChatPrompt,
+    prompt_router::PromptRouter,
+    queues::{chat::ChatInput, work::WorkInput},
+    tokens::TokenCounter,
+    ui::Ui,
+};
+
+/// Command line arguments for the `tokens` subcommand.
+#[derive(Debug, Args)]
+pub struct TokensOpts {
+    /// Sample input data, in CSV or JSONL format. Defaults to standard input.
+    pub input_path: Option<PathBuf>,
+
+    /// Model to count tokens for. May be repeated to compare models.
+    #[clap(short = 'm', long = "model", default_value = "gpt-4o-mini")]
+    pub models: Vec<String>,
+
+    /// Prompt, in TOML or JSON format.
+    #[clap(
+        short = 'p',
+        long = "prompt",
+        required_unless_present = "prompt_router_path"
+    )]
+    pub prompt_path: Option<PathBuf>,
+
+    /// A prompt router, in TOML or JSON format. Use instead of `--prompt`.
+    #[clap(long = "prompt-router", conflicts_with = "prompt_path")]
+    pub prompt_router_path: Option<PathBuf>,
+
+    /// Only count tokens for the first N records.
+    #[clap(long, default_value = "10")]
+    pub limit: usize,
+
+    /// The delimiter for CSV input, as a single character or `tab`.
+    #[clap(long, value_parser = parse_csv_delimiter)]
+    pub csv_delimiter: Option<u8>,
+
+    /// Read input records from standard input as YAML.
+    #[clap(long)]
+    pub yaml_stdin: bool,
+
+    /// Output location, in JSONL format. Defaults to standard output.
+    #[clap(short = 'o', long = "out")]
+    pub output_path: Option<PathBuf>,
+}
+
+/// The `tokens` subcommand.
+#[instrument(level = "debug", skip_all)]
+pub async fn cmd_tokens(ui: &Ui, opts: &TokensOpts) -> Result<()> {
+    // Read our prompts and their schemas.
+    let prompts = match (&opts.prompt_path, &opts.prompt_router_path) {
+        (_, Some(router_path)) => PromptRouter::from_path(router_path).await?,
+        (Some(prompt_path), None) => {
+            PromptRouter::single(ChatPrompt::read(prompt_path).await?)
+        }
+        (None, None) => {
+            return Err(anyhow!("Either --prompt or --prompt-router is required"));
+        }
+    };
+    let prompts = prompts
+        .try_map(|prompt| async move {
+            let schema = prompt.response_json_schema().await?;
+            Ok((prompt, schema))
+        })
+        .await?;
+    let counters = opts
+        .models
+        .iter()
+        .map(|model| TokenCounter::new(model))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Render each sample record and count its tokens for each model.
+    let mut input = read_jsonl_or_csv(
+        ui.clone(),
+        opts.input_path.as_deref(),
+        opts.csv_delimiter,
+        opts.yaml_stdin,
+    )
+    .await?
+    .take(opts.limit);
+    let mut rows = vec![];
+    while let Some(value) = input.next().await {
+        let record = WorkInput::<ChatInput>::from_json(value?)?;
+        let (prompt, schema) = prompts.route(&record.data.template_bindings)?;
+        let rendered = prompt
+            .render(&record.data.template_bindings)
+            .with_context(|| format!("cannot render prompt for record {}", record.id))?;
+        for counter in &counters {
+            let mut row = serde_json::to_value(counter.count_prompt(&rendered, schema))
+                .context("cannot serialize token count")?;
+            row["id"] = record.id.clone();
+            rows.push(Ok(row));
+        }
+    }
+    write_output(
+        opts.output_path.as_deref(),
+        None,
+        false,
+        stream::iter(rows).boxed(),
+        None,
+    )
+    .await
+}
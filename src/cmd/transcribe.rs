@@ -0,0 +1,159 @@
+//! The `transcribe` subcommand.
+
+use clap::Args;
+use schemars::schema_for;
+
+use crate::{
+    async_utils::WORKER_SHUTDOWN_GRACE,
+    cmd::OutputFormat,
+    manifest::RunManifest,
+    prelude::*,
+    queues::{
+        transcribe::{
+            TranscribeInput, TranscribeOutput, TranscribeStreamInfo, transcribe_files,
+        },
+        work::{WorkInput, WorkInputStreamInfo, WorkOutput, track_ack_tokens},
+    },
+    rate_limit::{RateLimit, RedisConfig},
+    result_store::output_columns,
+    run_dir::apply_run_dir,
+    spend_ledger::{SpendTracker, check_budget},
+    ui::{ProgressConfig, Ui},
+    webhook::Webhook,
+};
+
+/// Command line arguments for the `transcribe` subcommand.
+#[derive(Debug, Args)]
+pub struct TranscribeOpts {
+    /// Input data, in CSV or JSONL format. May also be an `sqs://`,
+    /// `kafka://`, `nats://` or `postgres://` URL. Defaults to standard input.
+    pub input_path: Option<PathBuf>,
+
+    /// Model to use. This may be any model supported by the OpenAI-compatible
+    /// `/audio/transcriptions` endpoint, or `whisper.cpp:/path/to/model.bin`
+    /// to run `whisper.cpp` locally.
+    #[clap(short = 'm', long, default_value = "whisper-1")]
+    pub model: String,
+
+    /// Output location, in CSV or JSONL format. May also be a `kafka://`,
+    /// `nats://` or `postgres://` URL. Defaults to standard output and JSONL.
+    #[clap(short = 'o', long = "out")]
+    pub output_path: Option<PathBuf>,
+
+    /// Split audio into chunks of at most this many seconds before
+    /// transcribing. The default keeps chunks under OpenAI's upload limit.
+    #[clap(long, default_value = "600")]
+    pub chunk_seconds: u32,
+
+    /// Rate limit for transcription requests, in the format "10/s" or "100/m".
+    #[clap(long)]
+    pub rate_limit: Option<RateLimit>,
+
+    /// Share `--rate-limit` with every process using this Redis server, as
+    /// `redis://[[USER]:PASSWORD@]HOST[:PORT][/DB]`. Percent-encode any
+    /// special characters in the password. We can't connect using TLS yet, so
+    /// `rediss://` URLs are rejected.
+    #[clap(long, value_name = "URL", requires = "rate_limit")]
+    pub rate_limit_backend: Option<RedisConfig>,
+
+    /// Stream-related options.
+    #[clap(flatten)]
+    pub stream_opts: super::StreamOpts,
+}
+
+/// The `transcribe` subcommand.
+#[instrument(level = "debug", skip_all)]
+pub async fn cmd_transcribe(ui: &Ui, opts: &TranscribeOpts) -> Result<()> {
+    // Open up our input stream and parse into records.
+    let WorkInputStreamInfo { stream: input, ack } =
+        WorkInput::<TranscribeInput>::read_stream(
+            ui.clone(),
+            opts.input_path.as_deref(),
+            &opts.stream_opts,
+        )
+        .await?;
+    let input = opts.stream_opts.apply_stream_input_opts(input);
+
+    // Skip anything an earlier attempt at this `--run-dir` already finished.
+    let (input, ack) =
+        apply_run_dir(&opts.stream_opts, opts.output_path.as_deref(), input, ack).await?;
+
+    // Make sure we haven't already used up our budget.
+    check_budget(&opts.stream_opts).await?;
+
+    // Start delivering records to our webhook, if we have one.
+    let webhook = Webhook::start(&opts.stream_opts.webhook_opts)?;
+
+    // Record how this run was configured.
+    RunManifest::new("transcribe", &opts.model)
+        .with_stream_opts(&opts.stream_opts)
+        .write(opts.stream_opts.manifest_path.as_deref())
+        .await?;
+
+    // Configure our progress bar.
+    let pb = ui.new_from_size_hint(
+        &ProgressConfig {
+            emoji: "🎙️",
+            msg: "Transcribing files",
+            done_msg: "Transcribed files",
+        },
+        input.size_hint(),
+    );
+
+    let TranscribeStreamInfo { stream, worker } = transcribe_files(
+        input,
+        opts.stream_opts.job_count,
+        opts.model.to_owned(),
+        opts.chunk_seconds,
+        opts.rate_limit.clone(),
+        opts.rate_limit_backend.clone(),
+        opts.stream_opts.record_limits(),
+    )
+    .await?;
+    let output = opts.stream_opts.apply_stream_buffering_opts(&pb, stream);
+    let spend = SpendTracker::default();
+    let output = spend.wrap_stream(output);
+    let output = webhook.wrap_stream(output);
+    let (output, ack) = track_ack_tokens(output, ack);
+
+    let written = match opts.stream_opts.output_format(opts.output_path.as_deref()) {
+        OutputFormat::Csv => {
+            WorkOutput::<TranscribeOutput>::write_stream_to_csv(
+                ui,
+                opts.output_path.as_deref(),
+                output,
+                &opts.stream_opts,
+                ack.as_deref(),
+            )
+            .await
+        }
+        OutputFormat::Duckdb => {
+            let schema = serde_json::to_value(schema_for!(TranscribeOutput))
+                .context("failed to serialize transcription output schema")?;
+            WorkOutput::<TranscribeOutput>::write_stream_to_duckdb(
+                ui,
+                opts.output_path.as_deref(),
+                output,
+                &opts.stream_opts,
+                &output_columns(&schema, None),
+                ack.as_deref(),
+            )
+            .await
+        }
+        OutputFormat::Jsonl => {
+            WorkOutput::write_stream(
+                ui,
+                opts.output_path.as_deref(),
+                output,
+                &opts.stream_opts,
+                ack.as_deref(),
+            )
+            .await
+        }
+    };
+    let recorded = spend.record(&opts.stream_opts, &opts.model, None).await;
+    let delivered = webhook.finish().await;
+    worker
+        .finish(written.and(recorded).and(delivered), WORKER_SHUTDOWN_GRACE)
+        .await
+}
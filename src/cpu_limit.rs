@@ -1,15 +1,35 @@
 //! Tools for limiting the number of concurrent CPU-bound tasks.
 
-use std::sync::LazyLock;
+use std::sync::{LazyLock, OnceLock};
 
 use tokio::sync::Semaphore;
 
 use crate::prelude::*;
 
+/// The number of CPU-bound tasks to allow at once, if set by `--cpu-jobs`.
+static CPU_JOB_COUNT: OnceLock<usize> = OnceLock::new();
+
 /// Semaphore used to limit the number of concurrent `pdfseparate` and `pdfcairo`
 /// processes.
 static CPU_SEMAPHORE: LazyLock<Semaphore> =
-    LazyLock::new(|| Semaphore::new(num_cpus::get()));
+    LazyLock::new(|| Semaphore::new(cpu_job_count()));
+
+/// Set the number of CPU-bound tasks to allow at once. This must be called
+/// at most once, before any CPU-bound work starts.
+pub fn set_cpu_job_count(count: usize) -> Result<()> {
+    if count == 0 {
+        return Err(anyhow!("--cpu-jobs must be greater than 0"));
+    }
+    CPU_JOB_COUNT
+        .set(count)
+        .map_err(|_| anyhow!("CPU job count has already been set"))
+}
+
+/// The number of CPU-bound tasks we allow at once. Defaults to the number of
+/// CPUs.
+pub fn cpu_job_count() -> usize {
+    *CPU_JOB_COUNT.get_or_init(num_cpus::get)
+}
 
 /// Call an async function while holding a permit from the CPU semaphore.
 ///
@@ -0,0 +1,166 @@
+//! Converting HTML and EPUB documents to text.
+//!
+//! Web archives and e-books are mostly markup and boilerplate. We strip out
+//! navigation, scripts and other page chrome, and convert what's left to
+//! Markdown-style text, which can be passed to a prompt or returned as OCR
+//! output.
+
+use std::{fs, sync::LazyLock};
+
+use epub::doc::EpubDoc;
+use regex::Regex;
+
+use crate::prelude::*;
+
+/// How wide to wrap our text output. We don't really want wrapping, but
+/// `html2text` needs a width, so pick something generous.
+const TEXT_WIDTH: usize = 120;
+
+/// Elements which never contain the main content of a page.
+static BOILERPLATE_REGEXES: LazyLock<Vec<Regex>> = LazyLock::new(|| {
+    [
+        "script", "style", "noscript", "template", "nav", "header", "footer", "aside",
+        "form",
+    ]
+    .iter()
+    .map(|tag| {
+        Regex::new(&format!(r"(?is)<{tag}\b[^>]*>.*?</{tag}\s*>"))
+            .expect("failed to compile regex")
+    })
+    .collect()
+});
+
+/// Elements which contain the main content of a page, if present.
+static MAIN_CONTENT_REGEXES: LazyLock<Vec<Regex>> = LazyLock::new(|| {
+    ["main", "article"]
+        .iter()
+        .map(|tag| {
+            Regex::new(&format!(r"(?is)<{tag}\b[^>]*>(.*)</{tag}\s*>"))
+                .expect("failed to compile regex")
+        })
+        .collect()
+});
+
+/// Kinds of documents we can convert to text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DocumentKind {
+    /// An HTML or XHTML page.
+    Html,
+    /// An EPUB e-book.
+    Epub,
+}
+
+impl DocumentKind {
+    /// Guess the kind of document at `path` from its extension.
+    pub fn for_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "html" | "htm" | "xhtml" => Some(DocumentKind::Html),
+            "epub" => Some(DocumentKind::Epub),
+            _ => None,
+        }
+    }
+}
+
+/// Extract the text of the document at `path`, returning one section per
+/// HTML page or EPUB chapter.
+///
+/// This performs blocking I/O.
+pub fn document_sections(kind: DocumentKind, path: &Path) -> Result<Vec<String>> {
+    match kind {
+        DocumentKind::Html => {
+            let html = fs::read_to_string(path)
+                .with_context(|| format!("failed to read {:?}", path.display()))?;
+            Ok(vec![html_to_text(&html)?])
+        }
+        DocumentKind::Epub => epub_chapters(path),
+    }
+}
+
+/// Extract the text of the document at `path`, joining sections with blank
+/// lines.
+///
+/// This performs blocking I/O.
+pub fn document_text(kind: DocumentKind, path: &Path) -> Result<String> {
+    Ok(document_sections(kind, path)?.join("\n\n"))
+}
+
+/// Extract the text of each chapter in an EPUB, in reading order. Empty
+/// chapters (cover pages, section dividers and so on) are skipped.
+fn epub_chapters(path: &Path) -> Result<Vec<String>> {
+    let mut doc = EpubDoc::new(path)
+        .map_err(|err| anyhow!("failed to open EPUB {:?}: {err}", path.display()))?;
+    let mut chapters = vec![];
+    loop {
+        if let Some((html, _mime_type)) = doc.get_current_str() {
+            let text = html_to_text(&html)?;
+            if !text.is_empty() {
+                chapters.push(text);
+            }
+        }
+        if !doc.go_next() {
+            break;
+        }
+    }
+    if chapters.is_empty() {
+        return Err(anyhow!("no text found in EPUB {:?}", path.display()));
+    }
+    Ok(chapters)
+}
+
+/// Convert an HTML page to Markdown-style text, keeping only the main content.
+pub fn html_to_text(html: &str) -> Result<String> {
+    let mut html = html.to_owned();
+    for re in BOILERPLATE_REGEXES.iter() {
+        html = re.replace_all(&html, "").into_owned();
+    }
+    for re in MAIN_CONTENT_REGEXES.iter() {
+        if let Some(content) = re.captures(&html).and_then(|caps| caps.get(1)) {
+            html = content.as_str().to_owned();
+            break;
+        }
+    }
+    let text = html2text::from_read(html.as_bytes(), TEXT_WIDTH)
+        .context("failed to convert HTML to text")?;
+    Ok(text.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_boilerplate_is_removed() {
+        let html = r#"
+            <html>
+              <head><style>p { color: red; }</style></head>
+              <body>
+                <nav><a href="/">Home</a></nav>
+                <main><h1>Title</h1><p>Body text.</p></main>
+                <footer>Copyright</footer>
+                <script>alert("hi");</script>
+              </body>
+            </html>
+        "#;
+        let text = html_to_text(html).unwrap();
+        assert!(text.contains("Title"));
+        assert!(text.contains("Body text."));
+        assert!(!text.contains("Home"));
+        assert!(!text.contains("Copyright"));
+        assert!(!text.contains("alert"));
+        assert!(!text.contains("color"));
+    }
+
+    #[test]
+    fn document_kind_from_extension() {
+        assert_eq!(
+            DocumentKind::for_path(Path::new("a/page.HTML")),
+            Some(DocumentKind::Html)
+        );
+        assert_eq!(
+            DocumentKind::for_path(Path::new("book.epub")),
+            Some(DocumentKind::Epub)
+        );
+        assert_eq!(DocumentKind::for_path(Path::new("doc.pdf")), None);
+    }
+}
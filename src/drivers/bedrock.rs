@@ -1,29 +1,32 @@
 //! AWS Bedrock driver.
 
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use aws_sdk_bedrockruntime::{
     Client,
+    config::{Builder as ConfigBuilder, timeout::TimeoutConfig},
     operation::converse::ConverseError,
     primitives::Blob,
     types::{
-        AnyToolChoice, ContentBlock, ConversationRole, ImageBlock, ImageFormat,
-        ImageSource, InferenceConfiguration, Message as BedrockMessage, StopReason,
-        SystemContentBlock, Tool, ToolChoice, ToolConfiguration, ToolInputSchema,
-        ToolResultBlock, ToolResultContentBlock, ToolSpecification, ToolUseBlock,
+        AnyToolChoice, AutoToolChoice, ContentBlock, ConversationRole, ImageBlock,
+        ImageFormat, ImageSource, InferenceConfiguration, Message as BedrockMessage,
+        StopReason, SystemContentBlock, Tool, ToolChoice, ToolConfiguration,
+        ToolInputSchema, ToolResultBlock, ToolResultContentBlock, ToolSpecification,
+        ToolUseBlock,
     },
 };
 use aws_smithy_types::{Document, Number};
-use base64::{Engine as _, prelude::BASE64_STANDARD};
 use uuid::Uuid;
 
 use crate::{
     aws::load_aws_config,
-    data_url::parse_data_url,
-    drivers::{ChatCompletionResponse, LlmOpts, LlmRetryResult, TokenUsage},
+    drivers::{
+        ChatCompletionResponse, ContentFilterError, LlmOpts, LlmRetryResult, RawResponse,
+        TokenUsage, parse_prefilled_json, prefilled_text,
+    },
     litellm::LiteLlmModel,
     prelude::*,
-    prompt::{ChatPrompt, Message, Rendered},
+    prompt::{ChatPrompt, Message, Rendered, ResponseFormat},
     retry::{
         IsKnownTransient, retry_result_ok, retry_result_transient, try_fatal,
         try_potentially_transient, try_transient,
@@ -44,11 +47,21 @@ pub struct BedrockDriver {
 }
 
 impl BedrockDriver {
-    /// Create a new native driver.
-    pub async fn new() -> Result<Self> {
+    /// Create a new Bedrock driver, which gives up on connecting after
+    /// `connect_timeout`.
+    pub async fn new(connect_timeout: Option<Duration>) -> Result<Self> {
         let config = load_aws_config().await?;
+        let mut builder = ConfigBuilder::from(&config);
+        if let Some(connect_timeout) = connect_timeout {
+            // Keep any other timeouts from our AWS config.
+            let timeouts = config
+                .timeout_config()
+                .map_or_else(TimeoutConfig::builder, TimeoutConfig::to_builder);
+            builder =
+                builder.timeout_config(timeouts.connect_timeout(connect_timeout).build());
+        }
         Ok(Self {
-            client: Client::new(&config),
+            client: Client::from_conf(builder.build()),
         })
     }
 }
@@ -61,8 +74,7 @@ impl Driver for BedrockDriver {
         model: &str,
         _model_info: Option<&LiteLlmModel>,
         prompt: &ChatPrompt<Rendered>,
-        // TODO: Why do we get this separately from the copy in `prompt`?
-        _schema: Value,
+        schema: Value,
         llm_opts: &LlmOpts,
     ) -> LlmRetryResult<ChatCompletionResponse> {
         // Figure out of inference configuration.
@@ -81,21 +93,55 @@ impl Driver for BedrockDriver {
         // Convert our prompt to a Bedrock request.
         let req = try_fatal!(prompt.to_bedrock_request().await);
 
+        // We get JSON back by asking the model to call our tool, using
+        // `schema`, which may differ from the prompt's own schema. Text
+        // responses don't need a tool.
+        let tool_config = match prompt.response_format {
+            ResponseFormat::Json => Some(try_fatal!(output_tool_config(
+                &schema,
+                prompt.prefill.is_some()
+            ))),
+            ResponseFormat::Text => None,
+        };
+
         // Send the request.
         let output = try_potentially_transient!(
             self.client
                 .converse()
                 .model_id(model)
                 .inference_config(inf_conf)
-                .tool_config(req.tool_config)
+                .set_tool_config(tool_config)
                 .set_system(req.system.map(|s| vec![s]))
                 .set_messages(Some(req.messages))
+                .set_request_metadata(
+                    (!llm_opts.tags.is_empty())
+                        .then(|| llm_opts.tag_map().into_iter().collect()),
+                )
                 .send()
                 .await
         );
 
-        // Check for odd stop reasons.
-        if output.stop_reason() != &StopReason::ToolUse {
+        // Check for odd stop reasons. With a prefill, the model may reply
+        // with text instead of calling our tool.
+        if matches!(
+            output.stop_reason(),
+            StopReason::ContentFiltered | StopReason::GuardrailIntervened
+        ) {
+            return LlmRetryResult::Fatal {
+                input: (),
+                error: ContentFilterError(format!(
+                    "Bedrock stopped with {}",
+                    output.stop_reason()
+                ))
+                .into(),
+            };
+        }
+        let prefill = prompt.prefill.as_deref();
+        let wants_text = prompt.response_format == ResponseFormat::Text;
+        let expected_stop = (!wants_text && output.stop_reason() == &StopReason::ToolUse)
+            || ((wants_text || prefill.is_some())
+                && output.stop_reason() == &StopReason::EndTurn);
+        if !expected_stop {
             return LlmRetryResult::Transient {
                 input: (),
                 error: anyhow!("Unexpected stop reason: {}", output.stop_reason()),
@@ -126,24 +172,47 @@ impl Driver for BedrockDriver {
                 blocks.len()
             ));
         }
-        if let ContentBlock::ToolUse(tool_use) = &blocks[0] {
-            if tool_use.name != OUTPUT_TOOL_NAME {
+        let response = match (&blocks[0], prefill) {
+            (ContentBlock::Text(text), _) if wants_text => {
+                Value::String(prefilled_text(prefill, text))
+            }
+            (ContentBlock::ToolUse(tool_use), _) => {
+                if tool_use.name != OUTPUT_TOOL_NAME {
+                    return retry_result_transient(anyhow!(
+                        "Bedrock response contained unexpected tool name: {}",
+                        tool_use.name
+                    ));
+                }
+                try_transient!(aws_document_to_value(&tool_use.input))
+            }
+            (ContentBlock::Text(text), Some(prefill)) => {
+                try_transient!(parse_prefilled_json(prefill, text))
+            }
+            _ => {
                 return retry_result_transient(anyhow!(
-                    "Bedrock response contained unexpected tool name: {}",
-                    tool_use.name
+                    "Bedrock response contained unexpected content block: {blocks:?}"
                 ));
             }
-            let response = try_transient!(aws_document_to_value(&tool_use.input));
-            debug!(%response, "Response");
-            retry_result_ok(ChatCompletionResponse {
-                response,
-                token_usage,
-            })
-        } else {
-            try_transient!(Err(anyhow!(
-                "Bedrock response contained unexpected content block: {blocks:?}"
-            )))
-        }
+        };
+        debug!(%response, "Response");
+
+        // Keep the raw response. Tool input arrives as a document, not as
+        // text, so we report that as JSON.
+        let stop_reason = Some(output.stop_reason().as_str().to_owned());
+        let raw_response = match &blocks[0] {
+            ContentBlock::Text(text) => {
+                RawResponse::capture(llm_opts, text, stop_reason, None)
+            }
+            _ => RawResponse::capture(llm_opts, &response.to_string(), stop_reason, None),
+        };
+
+        retry_result_ok(ChatCompletionResponse {
+            response,
+            token_usage,
+            system_fingerprint: None,
+            mean_logprob: None,
+            raw_response,
+        })
     }
 }
 
@@ -164,8 +233,6 @@ impl IsKnownTransient for ConverseError {
 struct BedrockRequest {
     /// The system prompt.
     system: Option<SystemContentBlock>,
-    /// Our tool configuration.
-    tool_config: ToolConfiguration,
     /// The messages to send.
     messages: Vec<BedrockMessage>,
 }
@@ -189,39 +256,54 @@ impl ToBedrockRequest for ChatPrompt<Rendered> {
         for message in &self.messages {
             messages.extend(message.to_bedrock_request().await?);
         }
-
-        // Set up our tool configuration, and for
-        let tool_config = ToolConfiguration::builder()
-            .tools(Tool::ToolSpec(
-                ToolSpecification::builder()
-                    .name(OUTPUT_TOOL_NAME.to_string())
-                    .description("Report the requested data".to_string())
-                    .input_schema(ToolInputSchema::Json(
-                        value_to_aws_document(
-                            &self.response_schema.to_json_schema().await?,
-                        )
-                        .context("Cannot convert JSON to AWS Document")?,
-                    ))
+        if let Some(prefill) = &self.prefill {
+            // Bedrock rejects trailing whitespace in a final assistant message.
+            messages.push(
+                BedrockMessage::builder()
+                    .role(ConversationRole::Assistant)
+                    .content(ContentBlock::Text(prefill.trim_end().to_owned()))
                     .build()
-                    .context("Cannot build Bedrock tool specification")?,
-            ))
-            // We have only one tool, so force the model to _some_ tool, and it
-            // has to call ours. This is more portable than SpecificToolChoice.
-            .tool_choice(ToolChoice::Any(AnyToolChoice::builder().build()))
-            .build()
-            .context("Cannot build Bedrock tool configuration")?;
+                    .context("Cannot build Bedrock message")?,
+            );
+        }
 
         Ok(BedrockRequest {
             system: self
                 .developer
                 .as_ref()
                 .map(|developer| SystemContentBlock::Text(developer.to_owned())),
-            tool_config,
             messages,
         })
     }
 }
 
+/// Build the configuration for the tool the model calls to report its results.
+fn output_tool_config(schema: &Value, has_prefill: bool) -> Result<ToolConfiguration> {
+    ToolConfiguration::builder()
+        .tools(Tool::ToolSpec(
+            ToolSpecification::builder()
+                .name(OUTPUT_TOOL_NAME.to_string())
+                .description("Report the requested data".to_string())
+                .input_schema(ToolInputSchema::Json(
+                    value_to_aws_document(schema)
+                        .context("Cannot convert JSON to AWS Document")?,
+                ))
+                .build()
+                .context("Cannot build Bedrock tool specification")?,
+        ))
+        // We have only one tool, so force the model to _some_ tool, and it
+        // has to call ours. This is more portable than SpecificToolChoice.
+        // Forced tool use can't be combined with a prefill, so in that
+        // case we let the model answer with text instead.
+        .tool_choice(if has_prefill {
+            ToolChoice::Auto(AutoToolChoice::builder().build())
+        } else {
+            ToolChoice::Any(AnyToolChoice::builder().build())
+        })
+        .build()
+        .context("Cannot build Bedrock tool configuration")
+}
+
 #[async_trait]
 impl ToBedrockRequest for Message {
     type Output = Vec<BedrockMessage>;
@@ -243,15 +325,16 @@ impl ToBedrockRequest for Message {
                     builder = builder.content(ContentBlock::Text(text.clone()));
                 }
                 for image in images {
-                    if let Some((mime_type, data)) = parse_data_url(image) {
-                        let format =
-                            mime_type.strip_prefix("image/").unwrap_or(&mime_type);
-                        let decoded_bytes = BASE64_STANDARD
-                            .decode(data)
-                            .context("Cannot decode base64 image data")?;
+                    if let Some(data) = image.data() {
+                        let format = data
+                            .mime_type
+                            .strip_prefix("image/")
+                            .unwrap_or(&data.mime_type);
+                        // The SDK's `Blob` owns a `Vec`, so this is one copy we
+                        // can't avoid.
                         let image_block = ImageBlock::builder()
                             .format(ImageFormat::try_parse(format)?)
-                            .source(ImageSource::Bytes(Blob::new(decoded_bytes)))
+                            .source(ImageSource::Bytes(Blob::new(data.bytes.to_vec())))
                             .build()
                             .context("Cannot build Bedrock image block")?;
                         builder = builder.content(ContentBlock::Image(image_block));
@@ -264,6 +347,18 @@ impl ToBedrockRequest for Message {
                 }
                 messages.push(builder.build().context("Cannot build Bedrock message")?);
             }
+            // Text responses are just text.
+            Message::Assistant {
+                json: Value::String(text),
+            } => {
+                messages.push(
+                    BedrockMessage::builder()
+                        .role(ConversationRole::Assistant)
+                        .content(ContentBlock::Text(text.clone()))
+                        .build()
+                        .context("Cannot build Bedrock message")?,
+                );
+            }
             Message::Assistant { json } => {
                 // We need to generate a tool use and a tool result, because Bedrock
                 let id = Uuid::new_v4().to_string();
@@ -1,8 +1,10 @@
 //! Echo driver for testing.
 //!
-//! This driver echoes back the last user message as `{ "echo": <text> }`.
-//! It validates that the response schema is an internal schema with a single
-//! "echo" string property.
+//! If the response schema is an internal schema with a single "echo" string
+//! property, this driver echoes back the last user message as
+//! `{ "echo": <text> }`. Otherwise, it returns a placeholder response built
+//! from the schema, so that users can check input files, prompt rendering,
+//! schemas and output plumbing without calling a real model.
 
 use async_trait::async_trait;
 use serde_json::Map;
@@ -10,12 +12,14 @@ use serde_json::Map;
 use crate::{
     litellm::LiteLlmModel,
     prelude::*,
-    prompt::{ChatPrompt, Message, Rendered},
+    prompt::{ChatPrompt, Message, Rendered, ResponseFormat},
     retry::retry_result_ok,
     schema::{InternalSchema, InternalSchemaDetails, ScalarType, Schema},
 };
 
-use super::{ChatCompletionResponse, Driver, LlmOpts, LlmRetryResult, TokenUsage};
+use super::{
+    ChatCompletionResponse, Driver, LlmOpts, LlmRetryResult, RawResponse, TokenUsage,
+};
 
 /// Echo driver for testing.
 #[derive(Debug)]
@@ -75,6 +79,100 @@ fn extract_last_user_message(messages: &[Message]) -> Result<String> {
     Ok(last_user_message.clone())
 }
 
+/// Build a placeholder value matching `schema`, looking up any `$ref`s in
+/// `root`. We use the first `enum` value or `anyOf` choice, the minimum for
+/// numbers, and `minItems` copies of each array item. We don't try to satisfy
+/// `pattern` or `format`.
+fn placeholder_value(schema: &Value, root: &Value) -> Value {
+    placeholder_value_at_depth(schema, root, 0)
+}
+
+/// How deeply should we nest placeholder values? Recursive schemas would
+/// otherwise go on forever.
+const MAX_PLACEHOLDER_DEPTH: usize = 32;
+
+/// Helper for [`placeholder_value`].
+fn placeholder_value_at_depth(schema: &Value, root: &Value, depth: usize) -> Value {
+    if depth > MAX_PLACEHOLDER_DEPTH {
+        return Value::Null;
+    }
+    let placeholder =
+        |schema: &Value| placeholder_value_at_depth(schema, root, depth + 1);
+    if let Some(reference) = schema.get("$ref").and_then(|r| r.as_str()) {
+        return reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+            .map_or(Value::Null, placeholder);
+    }
+    if let Some(value) = schema.get("const") {
+        return value.clone();
+    }
+    if let Some(value) = schema
+        .get("enum")
+        .and_then(|values| values.as_array())
+        .and_then(|values| values.first())
+    {
+        return value.clone();
+    }
+    for key in ["anyOf", "oneOf", "allOf"] {
+        if let Some(choice) = schema
+            .get(key)
+            .and_then(|choices| choices.as_array())
+            .and_then(|choices| choices.first())
+        {
+            return placeholder(choice);
+        }
+    }
+
+    // `type` may be a list, like `["string", "null"]`.
+    let r#type = match schema.get("type") {
+        Some(Value::String(t)) => Some(t.as_str()),
+        Some(Value::Array(types)) => types
+            .iter()
+            .filter_map(|t| t.as_str())
+            .find(|&t| t != "null")
+            .or(Some("null")),
+        _ if schema.get("properties").is_some() => Some("object"),
+        _ if schema.get("items").is_some() => Some("array"),
+        _ => None,
+    };
+    match r#type {
+        Some("object") => {
+            let mut object = Map::new();
+            if let Some(properties) = schema.get("properties").and_then(|p| p.as_object())
+            {
+                for (name, property) in properties {
+                    object.insert(name.to_owned(), placeholder(property));
+                }
+            }
+            Value::Object(object)
+        }
+        Some("array") => {
+            let count = schema.get("minItems").and_then(|n| n.as_u64()).unwrap_or(0);
+            let item = schema.get("items").map_or(Value::Null, placeholder);
+            Value::Array(vec![item; count as usize])
+        }
+        Some("string") => {
+            let min_length = schema
+                .get("minLength")
+                .and_then(|n| n.as_u64())
+                .unwrap_or(0);
+            Value::String("x".repeat(min_length.max(1) as usize))
+        }
+        Some("integer") => {
+            json!(schema.get("minimum").and_then(|n| n.as_i64()).unwrap_or(0))
+        }
+        Some("number") => json!(
+            schema
+                .get("minimum")
+                .and_then(|n| n.as_f64())
+                .unwrap_or(0.0)
+        ),
+        Some("boolean") => Value::Bool(false),
+        _ => Value::Null,
+    }
+}
+
 impl EchoDriver {
     /// Create a new echo driver.
     pub fn new() -> Self {
@@ -89,38 +187,64 @@ impl Driver for EchoDriver {
         _model: &str,
         _model_info: Option<&LiteLlmModel>,
         prompt: &ChatPrompt<Rendered>,
-        _schema: Value,
-        _llm_opts: &LlmOpts,
+        schema: Value,
+        llm_opts: &LlmOpts,
     ) -> LlmRetryResult<ChatCompletionResponse> {
-        // Validate the schema
-        if let Err(e) = validate_schema(&prompt.response_schema) {
-            return keen_retry::RetryResult::Fatal {
-                input: (),
-                error: e,
+        // Text prompts get the user's message back as text. Without an echo
+        // schema, return a placeholder response.
+        let response = if prompt.response_format == ResponseFormat::Text {
+            match extract_last_user_message(&prompt.messages) {
+                Ok(text) => Value::String(text),
+                Err(e) => {
+                    return keen_retry::RetryResult::Fatal {
+                        input: (),
+                        error: e,
+                    };
+                }
+            }
+        } else if validate_schema(&prompt.response_schema).is_err() {
+            placeholder_value(&schema, &schema)
+        } else {
+            // Extract the last user message
+            let text = match extract_last_user_message(&prompt.messages) {
+                Ok(text) => text,
+                Err(e) => {
+                    return keen_retry::RetryResult::Fatal {
+                        input: (),
+                        error: e,
+                    };
+                }
             };
-        }
 
-        // Extract the last user message
-        let text = match extract_last_user_message(&prompt.messages) {
-            Ok(text) => text,
-            Err(e) => {
-                return keen_retry::RetryResult::Fatal {
-                    input: (),
-                    error: e,
-                };
+            // Build the response JSON
+            let mut response = Map::new();
+            if prompt.cite_source.is_some() {
+                response.insert("echo_quote".to_string(), Value::String(text.clone()));
+            }
+            response.insert("echo".to_string(), Value::String(text));
+            if prompt.field_confidence {
+                response.insert("echo_confidence".to_string(), json!(1.0));
             }
+            Value::Object(response)
         };
 
-        // Build the response JSON
-        let mut response = Map::new();
-        response.insert("echo".to_string(), Value::String(text));
+        // Pretend our response was sent as text, the way most providers do.
+        let raw_text = match &response {
+            Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        let raw_response =
+            RawResponse::capture(llm_opts, &raw_text, Some("stop".to_owned()), None);
 
         retry_result_ok(ChatCompletionResponse {
-            response: Value::Object(response),
+            response,
             token_usage: Some(TokenUsage {
                 prompt_tokens: 0,
                 completion_tokens: 0,
             }),
+            system_fingerprint: None,
+            mean_logprob: None,
+            raw_response,
         })
     }
 }
@@ -205,6 +329,40 @@ mod tests {
         assert!(err.to_string().contains("internal schema"));
     }
 
+    #[test]
+    fn test_placeholder_value_matches_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "kind": { "type": "string", "enum": ["contract", "letter"] },
+                "pages": { "type": "integer", "minimum": 1 },
+                "parties": {
+                    "type": "array",
+                    "minItems": 1,
+                    "items": { "$ref": "#/definitions/Party" },
+                },
+                "notes": { "type": ["string", "null"] },
+            },
+            "definitions": {
+                "Party": {
+                    "type": "object",
+                    "properties": { "signed": { "type": "boolean" } },
+                },
+            },
+        });
+        assert_eq!(
+            placeholder_value(&schema, &schema),
+            json!({
+                "name": "x",
+                "kind": "contract",
+                "pages": 1,
+                "parties": [{ "signed": false }],
+                "notes": "x",
+            })
+        );
+    }
+
     #[test]
     fn test_extract_last_user_message_no_user_message() {
         let messages = vec![Message::Assistant {
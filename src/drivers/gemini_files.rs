@@ -0,0 +1,224 @@
+//! Uploads to the Gemini Files API.
+//!
+//! Few-shot example images and large documents are often sent with every
+//! request, and inlining a multi-megabyte `data:` URL each time wastes a lot of
+//! bandwidth. With `--upload-media-over`, the `native` driver uploads each
+//! distinct image to the [Files API](https://ai.google.dev/gemini-api/docs/files)
+//! once, and refers to it by URI in every request that uses it. We delete our
+//! uploads when the run finishes. (Google deletes them after 48 hours anyway.)
+//!
+//! Vertex AI has no equivalent API. It only accepts `gs://` URIs, which would
+//! require a GCS bucket.
+
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::OnceCell;
+
+use crate::{
+    prelude::*,
+    prompt::{ChatPrompt, Message, Rendered},
+    prompt_image::ImageData,
+};
+
+/// The base URL of the Gemini API.
+const API_BASE: &str = "https://generativelanguage.googleapis.com";
+
+/// How many times should we check whether an upload is ready?
+const MAX_STATE_CHECKS: usize = 60;
+
+/// How long should we wait between checks?
+const STATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// URIs of uploaded media, keyed by the SHA-256 digest of the media.
+pub type MediaUris = HashMap<[u8; 32], String>;
+
+/// A file uploaded to the Files API.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadedFile {
+    /// The resource name of the file, like `files/abc123`.
+    name: String,
+
+    /// The URI to use in requests.
+    uri: String,
+
+    /// `PROCESSING`, `ACTIVE` or `FAILED`.
+    #[serde(default)]
+    state: Option<String>,
+}
+
+/// The response to a completed upload.
+#[derive(Debug, Deserialize)]
+struct UploadResponse {
+    /// The uploaded file.
+    file: UploadedFile,
+}
+
+/// Media we have uploaded to the Gemini Files API during this run.
+#[derive(Debug, Default)]
+pub struct GeminiFiles {
+    /// Our HTTP client.
+    http_client: reqwest::Client,
+
+    /// Our uploads, keyed by the SHA-256 digest of their contents. Each upload
+    /// happens at most once, even if several requests need it at once.
+    uploads: Mutex<HashMap<[u8; 32], Arc<OnceCell<UploadedFile>>>>,
+}
+
+impl GeminiFiles {
+    /// Upload any images in `prompt` which are at least `min_bytes` long, and
+    /// return their URIs. Images we have already uploaded are reused.
+    pub async fn upload_prompt_media(
+        &self,
+        prompt: &ChatPrompt<Rendered>,
+        min_bytes: usize,
+    ) -> Result<MediaUris> {
+        let mut uris = MediaUris::new();
+        for message in &prompt.messages {
+            let Message::User { images, .. } = message else {
+                continue;
+            };
+            for data in images.iter().filter_map(|image| image.data()) {
+                if data.bytes.len() >= min_bytes && !uris.contains_key(&data.sha256()) {
+                    let file = self.upload(data).await?;
+                    uris.insert(data.sha256(), file.uri);
+                }
+            }
+        }
+        Ok(uris)
+    }
+
+    /// Upload `data`, unless we have already done so.
+    async fn upload(&self, data: &ImageData) -> Result<UploadedFile> {
+        let cell = self
+            .uploads
+            .lock()
+            .expect("lock poisoned")
+            .entry(data.sha256())
+            .or_default()
+            .clone();
+        cell.get_or_try_init(|| self.upload_uncached(data))
+            .await
+            .cloned()
+    }
+
+    /// Upload `data` using the resumable upload protocol, and wait for it to
+    /// become usable.
+    #[instrument(level = "debug", skip_all, fields(mime_type = %data.mime_type, len = data.bytes.len()))]
+    async fn upload_uncached(&self, data: &ImageData) -> Result<UploadedFile> {
+        let api_key = api_key()?;
+
+        // Start the upload.
+        let display_name = format!("prompt-scaler-{}", hex::encode(&data.sha256()[..8]));
+        let start = self
+            .http_client
+            .post(format!("{API_BASE}/upload/v1beta/files"))
+            .header("x-goog-api-key", &api_key)
+            .header("X-Goog-Upload-Protocol", "resumable")
+            .header("X-Goog-Upload-Command", "start")
+            .header("X-Goog-Upload-Header-Content-Length", data.bytes.len())
+            .header("X-Goog-Upload-Header-Content-Type", &data.mime_type)
+            .header("Content-Type", "application/json")
+            .body(json!({ "file": { "display_name": display_name } }).to_string())
+            .send()
+            .await
+            .context("cannot start Gemini file upload")?
+            .error_for_status()
+            .context("cannot start Gemini file upload")?;
+        let upload_url = start
+            .headers()
+            .get("x-goog-upload-url")
+            .and_then(|url| url.to_str().ok())
+            .ok_or_else(|| anyhow!("Gemini file upload did not return an upload URL"))?
+            .to_owned();
+
+        // Upload our data.
+        let body = self
+            .http_client
+            .post(upload_url)
+            .header("X-Goog-Upload-Offset", 0)
+            .header("X-Goog-Upload-Command", "upload, finalize")
+            .body(data.bytes.clone())
+            .send()
+            .await
+            .context("cannot upload Gemini file")?
+            .error_for_status()
+            .context("cannot upload Gemini file")?
+            .bytes()
+            .await
+            .context("cannot read Gemini file upload response")?;
+        let mut file = serde_json::from_slice::<UploadResponse>(&body)
+            .context("cannot parse Gemini file upload response")?
+            .file;
+        debug!(name = %file.name, uri = %file.uri, "Uploaded Gemini file");
+
+        // Documents may need processing before we can use them.
+        for _ in 0..MAX_STATE_CHECKS {
+            match file.state.as_deref() {
+                Some("PROCESSING") => {
+                    tokio::time::sleep(STATE_CHECK_INTERVAL).await;
+                    file = self.get(&api_key, &file.name).await?;
+                }
+                Some("FAILED") => {
+                    return Err(anyhow!("Gemini could not process file {}", file.name));
+                }
+                _ => return Ok(file),
+            }
+        }
+        Err(anyhow!("Gemini is still processing file {}", file.name))
+    }
+
+    /// Get the current metadata for the file `name`.
+    async fn get(&self, api_key: &str, name: &str) -> Result<UploadedFile> {
+        let body = self
+            .http_client
+            .get(format!("{API_BASE}/v1beta/{name}"))
+            .header("x-goog-api-key", api_key)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("cannot get Gemini file {name}"))?
+            .bytes()
+            .await
+            .with_context(|| format!("cannot read Gemini file {name}"))?;
+        serde_json::from_slice(&body)
+            .with_context(|| format!("cannot parse Gemini file {name}"))
+    }
+
+    /// Delete everything we uploaded. Failures are logged, not returned,
+    /// because Google will delete the files eventually anyway.
+    pub async fn delete_all(&self) -> Result<()> {
+        let uploads = std::mem::take(&mut *self.uploads.lock().expect("lock poisoned"));
+        if uploads.is_empty() {
+            return Ok(());
+        }
+        let api_key = api_key()?;
+        for file in uploads.values().filter_map(|cell| cell.get()) {
+            let result = self
+                .http_client
+                .delete(format!("{API_BASE}/v1beta/{}", file.name))
+                .header("x-goog-api-key", &api_key)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => debug!(name = %file.name, "Deleted Gemini file"),
+                Err(err) => {
+                    warn!(name = %file.name, "Could not delete Gemini file: {err}");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get our Gemini API key.
+fn api_key() -> Result<String> {
+    env::var("GEMINI_API_KEY")
+        .context("GEMINI_API_KEY must be set to upload files to the Gemini Files API")
+}
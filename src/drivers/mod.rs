@@ -4,7 +4,7 @@
 //! gateways, but when we're aiming for extremely high throughput,
 //! sometimes it's better to keep everything in native Rust.
 
-use std::{error, fmt, ops::AddAssign, pin::Pin, time::Duration};
+use std::{collections::BTreeMap, error, fmt, ops::AddAssign, pin::Pin, time::Duration};
 
 use async_trait::async_trait;
 use clap::{Args, ValueEnum};
@@ -18,29 +18,31 @@ use crate::{
     litellm::LiteLlmModel,
     prelude::*,
     prompt::{ChatPrompt, Rendered},
-    rate_limit::RateLimit,
+    rate_limit::{RateLimit, RedisConfig},
     retry::IsKnownTransient,
 };
 
 pub mod bedrock;
 pub mod echo;
+pub mod gemini_files;
 pub mod native;
 pub mod openai;
 pub mod vertex;
 
 /// Our different driver types.
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
-#[clap(rename_all = "snake_case")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum DriverType {
     /// OpenAI driver (also for LiteLLM, Ollama, etc).
     #[default]
-    #[clap(name = "openai")]
     OpenAI,
 
     /// AWS Bedrock driver.
     Bedrock,
 
-    /// Echo driver (for testing).
+    /// Echo driver, which makes no requests. It echoes the last user
+    /// message for a single `echo` field, and otherwise returns a placeholder
+    /// response matching the schema. Useful for checking prompts, schemas and
+    /// output at zero cost.
     Echo,
 
     /// Attempt to use a native driver for each specific AI.
@@ -51,11 +53,87 @@ pub enum DriverType {
 }
 
 impl DriverType {
+    /// Parse a `--driver` argument.
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        match arg {
+            "openai" => Ok(DriverType::OpenAI),
+            "bedrock" => Ok(DriverType::Bedrock),
+            "echo" => Ok(DriverType::Echo),
+            "native" => Ok(DriverType::Native),
+            "vertex" => Ok(DriverType::Vertex),
+            _ => Err(format!(
+                "expected openai, bedrock, echo, native or vertex, got {arg:?}"
+            )),
+        }
+    }
+
+    /// Does this driver support `--seed`?
+    pub fn supports_seed(&self) -> bool {
+        matches!(self, DriverType::OpenAI | DriverType::Vertex)
+    }
+
+    /// Does this driver support `--logprobs`?
+    pub fn supports_logprobs(&self) -> bool {
+        matches!(self, DriverType::OpenAI | DriverType::Vertex)
+    }
+
+    /// Does this driver support `--gbnf`?
+    pub fn supports_gbnf(&self) -> bool {
+        matches!(self, DriverType::OpenAI)
+    }
+
+    /// Does this driver support `--upload-media-over`?
+    pub fn supports_file_uploads(&self) -> bool {
+        matches!(self, DriverType::Native)
+    }
+
+    /// Does this driver need images inline, instead of as remote URLs which
+    /// the provider fetches itself?
+    pub fn needs_inline_images(&self) -> bool {
+        matches!(
+            self,
+            DriverType::Bedrock | DriverType::Native | DriverType::Vertex
+        )
+    }
+
+    /// Does this driver support `--tag`?
+    pub fn supports_tags(&self) -> bool {
+        matches!(
+            self,
+            DriverType::OpenAI | DriverType::Bedrock | DriverType::Vertex
+        )
+    }
+
+    /// Does this driver support `--detect-loops`?
+    pub fn supports_loop_detection(&self) -> bool {
+        matches!(self, DriverType::OpenAI)
+    }
+
+    /// Does this driver support `--connect-timeout`?
+    pub fn supports_connect_timeout(&self) -> bool {
+        matches!(self, DriverType::OpenAI | DriverType::Bedrock)
+    }
+
+    /// Does this driver support `--system-role`?
+    pub fn supports_system_role(&self) -> bool {
+        matches!(self, DriverType::OpenAI)
+    }
+
+    /// Does this driver support prompts with `[[mcp_server]]` tools?
+    pub fn supports_mcp_tools(&self) -> bool {
+        matches!(self, DriverType::OpenAI)
+    }
+
     /// Instantiate an appropriate driver.
-    pub async fn create_driver(&self) -> Result<Box<dyn Driver>> {
+    pub async fn create_driver(&self, llm_opts: &LlmOpts) -> Result<Box<dyn Driver>> {
+        let connect_timeout = llm_opts.connect_timeout.map(Duration::from_secs);
         match self {
-            DriverType::OpenAI => Ok(Box::new(openai::OpenAiDriver::new().await?)),
-            DriverType::Bedrock => Ok(Box::new(bedrock::BedrockDriver::new().await?)),
+            DriverType::OpenAI => {
+                Ok(Box::new(openai::OpenAiDriver::new(connect_timeout).await?))
+            }
+            DriverType::Bedrock => Ok(Box::new(
+                bedrock::BedrockDriver::new(connect_timeout).await?,
+            )),
             DriverType::Echo => Ok(Box::new(echo::EchoDriver::new())),
             DriverType::Native => Ok(Box::new(native::NativeDriver::new().await?)),
             DriverType::Vertex => Ok(Box::new(vertex::VertexDriver::new().await?)),
@@ -63,12 +141,55 @@ impl DriverType {
     }
 }
 
+impl fmt::Display for DriverType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriverType::OpenAI => write!(f, "openai"),
+            DriverType::Bedrock => write!(f, "bedrock"),
+            DriverType::Echo => write!(f, "echo"),
+            DriverType::Native => write!(f, "native"),
+            DriverType::Vertex => write!(f, "vertex"),
+        }
+    }
+}
+
+/// The role used to send a prompt's `developer` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum SystemRole {
+    /// The `system` role, which all chat models accept.
+    System,
+
+    /// The `developer` role, which newer OpenAI models use instead of `system`.
+    Developer,
+}
+
+/// What to do with a request which might cost more than
+/// `--max-cost-per-record`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum OnCostCap {
+    /// Don't send the request, and fail the record.
+    #[default]
+    Skip,
+
+    /// Lower the completion token limit for the request, so that it fits.
+    Clip,
+}
+
 /// Our chat-related options.
 #[derive(Args, Clone, Debug)]
+#[clap(next_help_heading = "LLM options")]
 pub struct LlmOpts {
-    /// EXPERIMENTAL: The LLM driver to use. This defaults to `openai`, which
-    /// works with OpenAI, LiteLLM and Ollama-based models.
-    #[clap(long, value_enum, default_value_t = DriverType::default())]
+    /// EXPERIMENTAL: The LLM driver to use: `openai`, `bedrock`, `echo`,
+    /// `native` or `vertex`. This defaults to `openai`, which works with
+    /// OpenAI, LiteLLM and Ollama-based models.
+    #[clap(
+        long,
+        value_name = "DRIVER",
+        value_parser = DriverType::parse,
+        default_value_t = DriverType::default()
+    )]
     pub driver: DriverType,
 
     /// An upper limit on the number of completion tokens to generate. This may
@@ -77,6 +198,27 @@ pub struct LlmOpts {
     #[clap(long)]
     pub max_completion_tokens: Option<u32>,
 
+    /// Don't spend more than this many US dollars on any one record. Before
+    /// each request, we add what we've already spent on the record (including
+    /// retries, hedged requests and fallback models) to the most the request
+    /// might cost, based on its prompt tokens and the completion token limit
+    /// (from `--max-completion-tokens`, or the model's maximum). This needs
+    /// pricing from LiteLLM for every model. Failed records have a
+    /// `cost_capped` error.
+    #[clap(long, value_name = "USD")]
+    pub max_cost_per_record: Option<f64>,
+
+    /// What to do with requests over `--max-cost-per-record`: skip them, or
+    /// clip their completion token limit to fit. Clipped responses may be cut
+    /// off, and fail validation.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "skip",
+        requires = "max_cost_per_record"
+    )]
+    pub on_cost_cap: OnCostCap,
+
     /// The temperature to use for sampling, between 0.0 and 2.0. Higher values
     /// may the output more random, while lower values may make it more
     /// deterministic. Defaults to the model's default.
@@ -89,19 +231,246 @@ pub struct LlmOpts {
     #[clap(long)]
     pub top_p: Option<f32>,
 
-    /// A timeout, in seconds, for the LLM to return a complete response.
+    /// A random seed to use for sampling. Repeating a request with the same
+    /// seed and options should return the same result, as far as the provider
+    /// allows. Supported by the `openai` and `vertex` drivers.
+    #[clap(long)]
+    pub seed: Option<i64>,
+
+    /// Request token log probabilities, and record the mean token logprob of
+    /// each response as `mean_logprob`. This can be used as a rough confidence
+    /// score. Use `--logprobs=N` to also request the N most likely alternatives
+    /// for each token (0-20). Supported by the `openai` and `vertex` drivers.
+    #[clap(
+        long,
+        value_name = "TOP_K",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "0",
+        value_parser = clap::value_parser!(u8).range(0..=20)
+    )]
+    pub logprobs: Option<u8>,
+
+    /// Send the response schema as a GBNF `grammar` instead of a JSON Schema
+    /// `response_format`. This constrains decoding on `llama.cpp` servers,
+    /// which otherwise often ignore the schema. Supported by the `openai`
+    /// driver.
+    #[clap(long)]
+    pub gbnf: bool,
+
+    /// Upload images of at least this many bytes to the Gemini Files API once
+    /// per run, and refer to them by URI instead of inlining them in every
+    /// request. Useful for few-shot example images and large documents.
+    /// Supported by the `native` driver with Gemini models, and requires
+    /// `GEMINI_API_KEY`.
+    #[clap(long, value_name = "BYTES")]
+    pub upload_media_over: Option<usize>,
+
+    /// The largest remote image to download, in bytes, for drivers which need
+    /// images inline (`bedrock`, `native` and `vertex`). Other drivers pass
+    /// `http` and `https` image URLs through for the provider to fetch.
+    #[clap(long, value_name = "BYTES", default_value_t = 20_000_000)]
+    pub max_remote_image_bytes: u64,
+
+    /// Don't check rendered prompts against the provider's published limits
+    /// on image size, image count and request size before sending them.
+    #[clap(long)]
+    pub skip_payload_checks: bool,
+
+    /// Don't check response schemas against the provider's published limits
+    /// on structured output, like OpenAI's limits on nesting and enum values,
+    /// before starting a run.
+    #[clap(long)]
+    pub skip_schema_checks: bool,
+
+    /// A timeout, in seconds, for the LLM to return a complete response,
+    /// including the time taken to connect.
     /// Note that even if a request times out, you'll probably still be charged.
     /// Useful dealing with runaway responses and overloaded servers.
     #[clap(long)]
     pub timeout: Option<u64>,
 
+    /// Stream each response, and stop early if the last `CHARS` characters
+    /// (2000 by default) are the same text repeated over and over. The request
+    /// is then retried. This is cheaper than waiting for a runaway response to
+    /// reach `--max-completion-tokens`. Supported by the `openai` driver.
+    #[clap(
+        long,
+        value_name = "CHARS",
+        num_args = 0..=1,
+        default_missing_value = "2000"
+    )]
+    pub detect_loops: Option<usize>,
+
+    /// A timeout, in seconds, for connecting to the LLM provider. Connection
+    /// timeouts are always retried, because the provider can't have started
+    /// work on (or billed for) the request. Supported by the `openai` and
+    /// `bedrock` drivers.
+    #[clap(long, value_name = "SECONDS")]
+    pub connect_timeout: Option<u64>,
+
+    /// A template field which may be truncated if the rendered prompt is too
+    /// large for the model's context window. May be specified more than once.
+    /// Without this, records which are too large fail with a `context_exceeded`
+    /// error.
+    #[clap(long = "truncate-field", value_name = "FIELD")]
+    pub truncate_fields: Vec<String>,
+
     /// A rate limit for LLM API requests, of the form "10/s" or "2000/m". This is
     /// applied separately from `--jobs`.
     #[clap(long)]
     pub rate_limit: Option<RateLimit>,
+
+    /// Share `--rate-limit` with every process using this Redis server, as
+    /// `redis://[[USER]:PASSWORD@]HOST[:PORT][/DB]`. Use this when several
+    /// machines share one API key. Percent-encode any special characters in
+    /// the password. We can't connect using TLS yet, so `rediss://` URLs are
+    /// rejected.
+    #[clap(long, value_name = "URL", requires = "rate_limit")]
+    pub rate_limit_backend: Option<RedisConfig>,
+
+    /// A tag to attach to each LLM request for cost attribution, as
+    /// `--tag client=acme`. May be repeated. Sent as OpenAI `metadata`,
+    /// LiteLLM `tags`, Bedrock `requestMetadata` or Vertex AI `labels`.
+    #[clap(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub tags: Vec<(String, String)>,
+
+    /// A model to try when a record fails on the main model, even after
+    /// retries. May be repeated, and each fallback model is tried in order.
+    /// Records blocked by a content filter are not retried on other models,
+    /// unless `--fallback-on-content-filter` is given.
+    #[clap(long = "fallback-model", value_name = "MODEL")]
+    pub fallback_models: Vec<String>,
+
+    /// Also try `--fallback-model` when a content filter blocks a response.
+    #[clap(long, requires = "fallback_models")]
+    pub fallback_on_content_filter: bool,
+
+    /// If a request hasn't finished after this many milliseconds, send an
+    /// identical "hedged" request, and use whichever response arrives first.
+    /// This cuts down on slow outliers, at the cost of extra requests.
+    #[clap(long, value_name = "MS")]
+    pub hedge_after: Option<u64>,
+
+    /// For prompts with `field_confidence = true`, mark records as incomplete
+    /// if the model's confidence in any field is below this value, from 0 to
+    /// 1. The response is still included, with an error naming the fields.
+    #[clap(long, value_name = "CONFIDENCE")]
+    pub min_confidence: Option<f64>,
+
+    /// The role used to send the prompt's `developer` message. By default, we
+    /// use `developer` for OpenAI o-series and GPT-5 models, and `system` for
+    /// everything else. Supported by the `openai` driver.
+    #[clap(long, value_enum)]
+    pub system_role: Option<SystemRole>,
+
+    /// Include the provider's raw response text, finish reason and refusal in
+    /// each output record as `raw_response`, to help debug parsing and
+    /// validation problems. The text is cut short after `BYTES` bytes (10000
+    /// by default), to keep output files small.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        num_args = 0..=1,
+        default_missing_value = "10000"
+    )]
+    pub include_raw_response: Option<usize>,
+}
+
+/// Parse a `--tag KEY=VALUE` argument.
+fn parse_tag(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected KEY=VALUE, got {arg:?}")),
+    }
 }
 
 impl LlmOpts {
+    /// Warn about any options which our driver will ignore.
+    pub fn warn_about_unsupported_options(&self) {
+        if self.seed.is_some() && !self.driver.supports_seed() {
+            warn!(driver = ?self.driver, "--seed is not supported by this driver");
+        }
+        if self.logprobs.is_some() && !self.driver.supports_logprobs() {
+            warn!(driver = ?self.driver, "--logprobs is not supported by this driver");
+        }
+        if self.gbnf && !self.driver.supports_gbnf() {
+            warn!(driver = ?self.driver, "--gbnf is not supported by this driver");
+        }
+        if self.upload_media_over.is_some() && !self.driver.supports_file_uploads() {
+            warn!(
+                driver = ?self.driver,
+                "--upload-media-over is not supported by this driver"
+            );
+        }
+        if !self.tags.is_empty() && !self.driver.supports_tags() {
+            warn!(driver = ?self.driver, "--tag is not supported by this driver");
+        }
+        if self.detect_loops.is_some() && !self.driver.supports_loop_detection() {
+            warn!(
+                driver = ?self.driver,
+                "--detect-loops is not supported by this driver"
+            );
+        }
+        if self.connect_timeout.is_some() && !self.driver.supports_connect_timeout() {
+            warn!(
+                driver = ?self.driver,
+                "--connect-timeout is not supported by this driver"
+            );
+        }
+        if self.system_role.is_some() && !self.driver.supports_system_role() {
+            warn!(
+                driver = ?self.driver,
+                "--system-role is not supported by this driver"
+            );
+        }
+    }
+
+    /// List the flags which have been set, and which only affect requests to
+    /// an LLM. `--timeout` and `--rate-limit` are left out, because other
+    /// engines use them too.
+    pub fn llm_only_flags_set(&self) -> Vec<&'static str> {
+        let flags = [
+            ("--driver", self.driver != DriverType::default()),
+            (
+                "--max-completion-tokens",
+                self.max_completion_tokens.is_some(),
+            ),
+            ("--max-cost-per-record", self.max_cost_per_record.is_some()),
+            ("--temperature", self.temperature.is_some()),
+            ("--top-p", self.top_p.is_some()),
+            ("--seed", self.seed.is_some()),
+            ("--logprobs", self.logprobs.is_some()),
+            ("--gbnf", self.gbnf),
+            ("--upload-media-over", self.upload_media_over.is_some()),
+            ("--truncate-field", !self.truncate_fields.is_empty()),
+            ("--tag", !self.tags.is_empty()),
+            ("--connect-timeout", self.connect_timeout.is_some()),
+            ("--detect-loops", self.detect_loops.is_some()),
+            ("--fallback-model", !self.fallback_models.is_empty()),
+            (
+                "--fallback-on-content-filter",
+                self.fallback_on_content_filter,
+            ),
+            ("--hedge-after", self.hedge_after.is_some()),
+            ("--min-confidence", self.min_confidence.is_some()),
+            ("--system-role", self.system_role.is_some()),
+            (
+                "--include-raw-response",
+                self.include_raw_response.is_some(),
+            ),
+        ];
+        flags
+            .into_iter()
+            .filter_map(|(flag, set)| set.then_some(flag))
+            .collect()
+    }
+
+    /// Get our `--tag` values as a map. Later values override earlier ones.
+    pub fn tag_map(&self) -> BTreeMap<String, String> {
+        self.tags.iter().cloned().collect()
+    }
+
     /// Apply a timeout to a future.
     ///
     /// Yes, this type signature is a bit complicated. It's possible that
@@ -125,9 +494,15 @@ impl LlmOpts {
     ) -> Pin<Box<dyn Future<Output = Result<T, LlmError<E>>> + Send + 'fut>>
     where
         T: Send + 'static,
-        E: Send + 'static,
+        E: IsConnectTimeout + Send + 'static,
     {
-        let future = future.map_err(LlmError::Native);
+        let future = future.map_err(|err| {
+            if err.is_connect_timeout() {
+                LlmError::ConnectTimeout(err)
+            } else {
+                LlmError::Native(err)
+            }
+        });
         if let Some(timeout) = self.timeout {
             time::timeout(Duration::from_secs(timeout), future)
                 // We have a `Result<Result<T, LlmError<E>>, Elapsed>` here, and
@@ -164,6 +539,12 @@ pub trait Driver: fmt::Debug + Send + Sync + 'static {
         schema: Value,
         llm_opts: &LlmOpts,
     ) -> LlmRetryResult<ChatCompletionResponse>;
+
+    /// Clean up anything we created on the provider's side, such as uploaded
+    /// files. Called once all requests have finished.
+    async fn cleanup(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// A chat completion response.
@@ -175,10 +556,103 @@ pub struct ChatCompletionResponse {
 
     /// Token usage.
     pub token_usage: Option<TokenUsage>,
+
+    /// The provider's fingerprint of the backend configuration used to
+    /// generate this response, if available. Changes here may explain
+    /// differences between seeded runs.
+    pub system_fingerprint: Option<String>,
+
+    /// The mean log probability of the response tokens, if `--logprobs` was
+    /// specified and the provider returned them.
+    pub mean_logprob: Option<f64>,
+
+    /// The provider's raw response, if `--include-raw-response` was specified.
+    pub raw_response: Option<RawResponse>,
+}
+
+/// The provider's raw response, before we parse it, for
+/// `--include-raw-response`.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+pub struct RawResponse {
+    /// The text of the response, cut short after `--include-raw-response`
+    /// bytes. For Bedrock, this is the input to our output tool, as JSON.
+    pub text: String,
+
+    /// Was `text` cut short?
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+
+    /// Why the model stopped generating, in the provider's own words.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+
+    /// The model's explanation of why it refused, for providers which report
+    /// refusals separately from the response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
+}
+
+impl RawResponse {
+    /// Capture a raw response, if `--include-raw-response` was specified.
+    pub fn capture(
+        llm_opts: &LlmOpts,
+        text: &str,
+        finish_reason: Option<String>,
+        refusal: Option<String>,
+    ) -> Option<Self> {
+        let max_bytes = llm_opts.include_raw_response?;
+        let mut end = text.len().min(max_bytes);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        Some(Self {
+            text: text[..end].to_owned(),
+            truncated: end < text.len(),
+            finish_reason,
+            refusal,
+        })
+    }
+}
+
+/// Compute the mean of a list of token log probabilities.
+pub fn mean_logprob(logprobs: impl IntoIterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = logprobs
+        .into_iter()
+        .fold((0.0, 0usize), |(sum, count), lp| (sum + lp, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+/// Parse the JSON reply from a model whose response was prefilled with
+/// `prefill`. The reply normally continues after the prefill, but some models
+/// repeat it, so we only add it back if it's missing.
+pub fn parse_prefilled_json(prefill: &str, text: &str) -> Result<Value> {
+    let prefill = prefill.trim();
+    let text = text.trim_start();
+    let json = if text.starts_with(prefill) {
+        text.to_owned()
+    } else {
+        format!("{prefill}{text}")
+    };
+    serde_json::from_str(&json)
+        .with_context(|| format!("Error parsing prefilled response: {json:?}"))
+}
+
+/// Reassemble the text reply from a model whose response was prefilled with
+/// `prefill`, like [`parse_prefilled_json`].
+pub fn prefilled_text(prefill: Option<&str>, text: &str) -> String {
+    let Some(prefill) = prefill.map(str::trim_end) else {
+        return text.to_owned();
+    };
+    let trimmed = text.trim_start();
+    if trimmed.starts_with(prefill) {
+        trimmed.to_owned()
+    } else {
+        format!("{prefill}{text}")
+    }
 }
 
 /// Token usage.
-#[derive(Clone, Debug, Default, JsonSchema, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, Serialize)]
 pub struct TokenUsage {
     /// How many tokens were used in the prompt?
     pub prompt_tokens: u64,
@@ -222,10 +696,25 @@ pub enum LlmError<E> {
     /// A native error.
     Native(E),
 
-    /// A timeout error.
+    /// We couldn't connect to the provider before `--connect-timeout`.
+    ConnectTimeout(E),
+
+    /// We didn't receive a complete response before `--timeout`.
     Timeout,
 }
 
+/// Errors which may have been caused by a connection timeout.
+pub trait IsConnectTimeout {
+    /// Did we time out while connecting?
+    fn is_connect_timeout(&self) -> bool;
+}
+
+impl IsConnectTimeout for reqwest::Error {
+    fn is_connect_timeout(&self) -> bool {
+        self.is_connect() && self.is_timeout()
+    }
+}
+
 impl<E> IsKnownTransient for LlmError<E>
 where
     E: IsKnownTransient,
@@ -234,6 +723,8 @@ where
     fn is_known_transient(&self) -> bool {
         match self {
             LlmError::Native(err) => err.is_known_transient(),
+            // Nothing reached the provider, so it's always safe to try again.
+            LlmError::ConnectTimeout(_) => true,
             // Runaway LLM responses and some kinds of network timeouts can be retried
             // with hope of a better result.
             LlmError::Timeout => true,
@@ -248,7 +739,10 @@ where
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             LlmError::Native(err) => write!(f, "LLM error: {err}"),
-            LlmError::Timeout => write!(f, "LLM request timed out"),
+            LlmError::ConnectTimeout(err) => {
+                write!(f, "LLM connection timed out: {err}")
+            }
+            LlmError::Timeout => write!(f, "LLM response timed out"),
         }
     }
 }
@@ -259,8 +753,88 @@ where
 {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
-            LlmError::Native(err) => Some(err),
+            LlmError::Native(err) | LlmError::ConnectTimeout(err) => Some(err),
             LlmError::Timeout => None,
         }
     }
 }
+
+/// A response which was blocked by the provider's content filter.
+#[derive(Debug)]
+pub struct ContentFilterError(pub String);
+
+impl fmt::Display for ContentFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "content_filter: {}", self.0)
+    }
+}
+
+impl error::Error for ContentFilterError {}
+
+/// A response which the model declined to give us, for a reason other than
+/// the provider's content filter. Retrying the same prompt rarely helps.
+#[derive(Debug)]
+pub enum RefusalError {
+    /// The model stopped because its output matched material it isn't allowed
+    /// to repeat, such as a Gemini `RECITATION` stop.
+    Recitation(String),
+
+    /// The model refused to answer.
+    Refused(String),
+}
+
+impl fmt::Display for RefusalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefusalError::Recitation(detail) => write!(f, "recitation: {detail}"),
+            RefusalError::Refused(detail) => write!(f, "refused: {detail}"),
+        }
+    }
+}
+
+impl error::Error for RefusalError {}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+
+    #[test]
+    fn prefilled_json_is_reassembled() {
+        let expected = json!({ "a": 1 });
+        assert_eq!(parse_prefilled_json("{", r#""a": 1}"#).unwrap(), expected);
+        assert_eq!(parse_prefilled_json("{", r#" {"a": 1}"#).unwrap(), expected);
+        assert!(parse_prefilled_json("{", "I can't do that.").is_err());
+    }
+
+    #[test]
+    fn prefilled_text_is_reassembled() {
+        assert_eq!(prefilled_text(None, "Summary: ok"), "Summary: ok");
+        assert_eq!(prefilled_text(Some("Summary: "), " ok"), "Summary: ok");
+        assert_eq!(
+            prefilled_text(Some("Summary:"), "Summary: ok"),
+            "Summary: ok"
+        );
+    }
+
+    #[test]
+    fn connect_timeouts_are_always_transient() {
+        let connect = LlmError::ConnectTimeout(StatusCode::BAD_REQUEST);
+        assert!(connect.is_known_transient());
+        assert!(!LlmError::Native(StatusCode::BAD_REQUEST).is_known_transient());
+        assert!(connect.to_string().starts_with("LLM connection timed out"));
+        assert_eq!(
+            LlmError::<StatusCode>::Timeout.to_string(),
+            "LLM response timed out"
+        );
+    }
+
+    #[test]
+    fn refusals_are_labelled_by_kind() {
+        let recitation = RefusalError::Recitation("too close to source".to_owned());
+        assert_eq!(recitation.to_string(), "recitation: too close to source");
+        let refused = RefusalError::Refused("no content".to_owned());
+        assert_eq!(refused.to_string(), "refused: no content");
+    }
+}
@@ -3,23 +3,21 @@
 //!
 //! For now, we use the [`genai`] crate, which seems reasonably popular.
 
-use std::sync::Arc;
-
 use async_trait::async_trait;
 use genai::{
     Client,
+    adapter::AdapterKind,
     chat::{
-        ChatMessage, ChatOptions, ChatRequest, ChatResponseFormat, ChatRole, ContentPart,
-        ImageSource, JsonSpec, MessageContent, Usage,
+        CacheControl, ChatMessage, ChatOptions, ChatRequest, ChatResponseFormat,
+        ChatRole, ContentPart, ImageSource, JsonSpec, MessageContent, Usage,
     },
     webc,
 };
 
 use crate::{
-    data_url::parse_data_url,
     litellm::LiteLlmModel,
     prelude::*,
-    prompt::{ChatPrompt, Message, Rendered},
+    prompt::{ChatPrompt, Message, Rendered, ResponseFormat, assistant_text},
     retry::{
         IsKnownTransient, retry_result_ok, try_fatal, try_potentially_transient,
         try_transient,
@@ -27,7 +25,12 @@ use crate::{
     schema::get_schema_title,
 };
 
-use super::{ChatCompletionResponse, Driver, LlmOpts, LlmRetryResult, TokenUsage};
+use super::{
+    ChatCompletionResponse, Driver, IsConnectTimeout, LlmError, LlmOpts, LlmRetryResult,
+    RawResponse, RefusalError, TokenUsage,
+    gemini_files::{GeminiFiles, MediaUris},
+    parse_prefilled_json, prefilled_text,
+};
 
 /// Our OpenAI driver, which we also use for LiteLLM, Ollama and other
 /// compatible gateways.
@@ -35,6 +38,9 @@ use super::{ChatCompletionResponse, Driver, LlmOpts, LlmRetryResult, TokenUsage}
 pub struct NativeDriver {
     /// The OpenAI client.
     pub client: Client,
+
+    /// Media uploaded to the Gemini Files API for `--upload-media-over`.
+    files: GeminiFiles,
 }
 
 impl NativeDriver {
@@ -42,6 +48,7 @@ impl NativeDriver {
     pub async fn new() -> Result<Self> {
         Ok(Self {
             client: Client::default(),
+            files: GeminiFiles::default(),
         })
     }
 }
@@ -54,67 +61,119 @@ impl Driver for NativeDriver {
         model: &str,
         _model_info: Option<&LiteLlmModel>,
         prompt: &ChatPrompt<Rendered>,
-        mut schema: Value,
+        schema: Value,
         llm_opts: &LlmOpts,
     ) -> LlmRetryResult<ChatCompletionResponse> {
         // Report what native driver we're using under the hood.
-        if let Ok(service_target) = self.client.resolve_service_target(model).await {
-            debug!(
-                adapter_kind = %service_target.model.adapter_kind,
-                model = model,
-                "Using native driver"
-            );
+        let adapter_kind = self
+            .client
+            .resolve_service_target(model)
+            .await
+            .ok()
+            .map(|service_target| service_target.model.adapter_kind);
+        if let Some(adapter_kind) = adapter_kind {
+            debug!(%adapter_kind, model = model, "Using native driver");
         }
 
-        // Fix our schema for compatibility.
-        {
-            let schema = try_fatal!(
-                schema
-                    .as_object_mut()
-                    .ok_or_else(|| { anyhow!("Expected schema to be an object") })
-            );
-            schema.remove("$schema");
-        }
+        // Only Anthropic supports prefilling the assistant's reply.
+        let prefill = prompt
+            .prefill
+            .as_deref()
+            .filter(|_| adapter_kind == Some(AdapterKind::Anthropic));
+
+        // Upload large media to the Gemini Files API, if requested. Upload
+        // failures are usually network problems, so we retry them.
+        let media_uris = match llm_opts.upload_media_over {
+            Some(min_bytes) if adapter_kind == Some(AdapterKind::Gemini) => {
+                try_transient!(self.files.upload_prompt_media(prompt, min_bytes).await)
+            }
+            _ => MediaUris::new(),
+        };
 
         // Convert our prompt to a genai request and build our options.
-        let req = try_fatal!(prompt.to_genai_request());
+        let mut req = try_fatal!(prompt.to_genai_request(&media_uris));
+
+        // Anthropic only caches prompt prefixes when asked to, so mark the end
+        // of our few-shot examples, which are the same for every record.
+        // OpenAI and Gemini cache long shared prefixes automatically.
+        if adapter_kind == Some(AdapterKind::Anthropic)
+            && let Some(last_example) = req.messages.len().checked_sub(2)
+        {
+            req.messages[last_example].options = Some(CacheControl::Ephemeral.into());
+        }
+        if let Some(prefill) = prefill {
+            // Anthropic rejects trailing whitespace in a prefill.
+            req.messages
+                .push(ChatMessage::assistant(prefill.trim_end().to_owned()));
+        }
         let opts = ChatOptions {
             temperature: llm_opts.temperature.map(f64::from),
             max_tokens: llm_opts.max_completion_tokens,
             top_p: llm_opts.top_p.map(f64::from),
-            response_format: Some(ChatResponseFormat::JsonSpec(JsonSpec {
-                name: get_schema_title(&schema),
-                description: None,
-                schema,
-            })),
+            response_format: (prompt.response_format == ResponseFormat::Json).then(
+                || {
+                    ChatResponseFormat::JsonSpec(JsonSpec {
+                        name: get_schema_title(&schema),
+                        description: None,
+                        schema,
+                    })
+                },
+            ),
             ..ChatOptions::default()
         };
 
         // Run our LLM request with a timeout.
         let future =
             llm_opts.apply_timeout(self.client.exec_chat(model, req, Some(&opts)));
-        let chat_res = try_potentially_transient!(future.await);
-
-        // Extract our response content.
-        let content = try_fatal!(
-            chat_res
-                .content
-                .as_ref()
-                .ok_or_else(|| anyhow!("No content in response: {:?}", chat_res))
-        );
+        let chat_res = match future.await {
+            Err(LlmError::Native(error)) if is_missing_gemini_parts(&error) => {
+                return LlmRetryResult::Fatal {
+                    input: (),
+                    error: RefusalError::Refused(format!(
+                        "Gemini returned a candidate without any content, which \
+                         usually means it stopped for RECITATION or SAFETY \
+                         ({error})"
+                    ))
+                    .into(),
+                };
+            }
+            result => try_potentially_transient!(result),
+        };
+
+        // Extract our response content. `genai` doesn't give us the refusal
+        // field or stop reason, but OpenAI and Anthropic refusals arrive
+        // without any content, so treat those as refusals.
+        let Some(content) = chat_res.content.as_ref() else {
+            return LlmRetryResult::Fatal {
+                input: (),
+                error: RefusalError::Refused(format!(
+                    "{model} returned no content, which usually means it refused \
+                     ({chat_res:?})"
+                ))
+                .into(),
+            };
+        };
         let content_str = try_fatal!(content.text_as_str().ok_or_else(|| anyhow!(
             "Expected text content in response, found: {:?}",
             content
         )));
+        let raw_response = RawResponse::capture(llm_opts, content_str, None, None);
 
-        // Extract JSON from our content.
-        let response = try_transient!(
-            // If we didn't get JSON here, it's because the model didn't
-            // generate JSON. So give it another chance with `try_transient!`.
-            serde_json::from_str::<Value>(content_str).with_context(|| format!(
-                "Error parsing OpenAI response content: {content:?}"
-            ))
-        );
+        // Extract JSON from our content, or keep it as text.
+        let response = match prompt.response_format {
+            ResponseFormat::Json => try_transient!(
+                // If we didn't get JSON here, it's because the model didn't
+                // generate JSON. So give it another chance with `try_transient!`.
+                match prefill {
+                    Some(prefill) => parse_prefilled_json(prefill, content_str),
+                    None =>
+                        serde_json::from_str::<Value>(content_str).with_context(|| {
+                            format!("Error parsing OpenAI response content: {content:?}")
+                        }),
+                }
+            ),
+            ResponseFormat::Text => Value::String(prefilled_text(prefill, content_str)),
+        };
         debug!(%response, "Response");
 
         // Compute our token usage.
@@ -135,8 +194,23 @@ impl Driver for NativeDriver {
         retry_result_ok(ChatCompletionResponse {
             response,
             token_usage,
+            system_fingerprint: None,
+            mean_logprob: None,
+            raw_response,
         })
     }
+
+    async fn cleanup(&self) -> Result<()> {
+        self.files.delete_all().await
+    }
+}
+
+/// Did Gemini return a candidate without any content parts? `genai` reports
+/// this as a missing JSON property, and it's usually caused by a `RECITATION`
+/// or `SAFETY` stop, which retrying won't fix.
+fn is_missing_gemini_parts(error: &genai::Error) -> bool {
+    let message = error.to_string();
+    message.contains("PropertyNotFound") && message.contains("/candidates/0/content")
 }
 
 impl IsKnownTransient for genai::Error {
@@ -157,6 +231,21 @@ impl IsKnownTransient for genai::Error {
     }
 }
 
+impl IsConnectTimeout for genai::Error {
+    fn is_connect_timeout(&self) -> bool {
+        match self {
+            genai::Error::WebAdapterCall { webc_error, .. }
+            | genai::Error::WebModelCall { webc_error, .. } => {
+                matches!(
+                    webc_error,
+                    webc::Error::Reqwest(error) if error.is_connect_timeout()
+                )
+            }
+            _ => false,
+        }
+    }
+}
+
 impl IsKnownTransient for webc::Error {
     fn is_known_transient(&self) -> bool {
         match self {
@@ -175,18 +264,19 @@ pub trait ToGenaiRequest {
     /// The type of the output.
     type Output;
 
-    /// Convert this value to something compatible with [`genai`].
-    fn to_genai_request(&self) -> Result<Self::Output>;
+    /// Convert this value to something compatible with [`genai`], referring to
+    /// any media in `media_uris` by URI.
+    fn to_genai_request(&self, media_uris: &MediaUris) -> Result<Self::Output>;
 }
 
 impl ToGenaiRequest for ChatPrompt<Rendered> {
     type Output = ChatRequest;
 
-    fn to_genai_request(&self) -> Result<Self::Output> {
+    fn to_genai_request(&self, media_uris: &MediaUris) -> Result<Self::Output> {
         let messages = self
             .messages
             .iter()
-            .map(|m| m.to_genai_request())
+            .map(|m| m.to_genai_request(media_uris))
             .collect::<Result<Vec<_>>>()?;
 
         Ok(ChatRequest {
@@ -200,7 +290,7 @@ impl ToGenaiRequest for ChatPrompt<Rendered> {
 impl ToGenaiRequest for Message {
     type Output = ChatMessage;
 
-    fn to_genai_request(&self) -> Result<Self::Output> {
+    fn to_genai_request(&self, media_uris: &MediaUris) -> Result<Self::Output> {
         match self {
             // We have images and maybe text.
             Message::User { text, images } if !images.is_empty() => {
@@ -209,12 +299,18 @@ impl ToGenaiRequest for Message {
                     parts.push(ContentPart::Text(text.clone()));
                 }
                 for image in images {
-                    if let Some((mime_type, data)) = parse_data_url(image) {
+                    if let Some(data) = image.data()
+                        && let Some(uri) = media_uris.get(&data.sha256())
+                    {
+                        parts.push(ContentPart::Image {
+                            content_type: data.mime_type.clone(),
+                            source: ImageSource::Url(uri.clone()),
+                        });
+                    } else if let Some(data) = image.data() {
+                        // This shares our Base64 data instead of copying it.
                         parts.push(ContentPart::Image {
-                            content_type: mime_type,
-                            // TODO: Avoid this copy by representing file paths
-                            // directly in messages.
-                            source: ImageSource::Base64(Arc::from(data)),
+                            content_type: data.mime_type.clone(),
+                            source: ImageSource::Base64(data.base64()),
                         });
                     } else {
                         return Err(anyhow!(
@@ -240,8 +336,7 @@ impl ToGenaiRequest for Message {
 
             // We have a fake assistant message, with a JSON value attached.
             Message::Assistant { json } => {
-                let json = json.to_string();
-                Ok(ChatMessage::assistant(json))
+                Ok(ChatMessage::assistant(assistant_text(json)))
             }
         }
     }
@@ -1,28 +1,47 @@
 //! Our OpenAI driver, which we also use for LiteLLM, Ollama and other
 //! compatible gateways.
 
+use std::{
+    fmt,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
 use async_openai::{
     Client,
-    config::OpenAIConfig,
-    error::OpenAIError,
+    config::{Config as _, OpenAIConfig},
+    error::{ApiError, OpenAIError},
     types::{
         ChatCompletionRequestAssistantMessageArgs,
-        ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestMessage,
+        ChatCompletionRequestAssistantMessageContent,
+        ChatCompletionRequestDeveloperMessageArgs,
+        ChatCompletionRequestDeveloperMessageContent, ChatCompletionRequestMessage,
         ChatCompletionRequestMessageContentPartImageArgs,
         ChatCompletionRequestMessageContentPartTextArgs,
         ChatCompletionRequestSystemMessageArgs,
         ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessageArgs,
         ChatCompletionRequestUserMessageContent,
         ChatCompletionRequestUserMessageContentPart, CreateChatCompletionRequestArgs,
-        CreateChatCompletionResponse, ResponseFormat, ResponseFormatJsonSchema,
+        CreateChatCompletionResponse, ImageDetail, ImageUrlArgs, ResponseFormat,
+        ResponseFormatJsonSchema,
     },
 };
+use futures::future;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 
 use crate::{
     drivers::TokenUsage,
+    gbnf::json_schema_to_gbnf,
+    latency::record_ttfb,
     litellm::LiteLlmModel,
+    loop_detector::LoopDetector,
     prelude::*,
-    prompt::{ChatPrompt, Message, Rendered},
+    prompt::{
+        ChatPrompt, Message, Rendered, ResponseFormat as PromptResponseFormat,
+        assistant_text,
+    },
+    prompt_image::{ImageDetail as PromptImageDetail, PromptImage},
+    rate_limit::{ProviderRateLimits, record_provider_rate_limits},
     retry::{
         IsKnownTransient, retry_result_fatal, retry_result_ok, try_fatal,
         try_potentially_transient, try_transient,
@@ -30,24 +49,99 @@ use crate::{
     schema::get_schema_title,
 };
 
-use super::{ChatCompletionResponse, Driver, LlmOpts, LlmRetryResult};
+use super::{
+    ChatCompletionResponse, ContentFilterError, Driver, IsConnectTimeout, LlmOpts,
+    LlmRetryResult, RawResponse, RefusalError, SystemRole, mean_logprob,
+};
+
+/// Extra HTTP headers to send to OpenAI-compatible gateways, from `--header`.
+static GATEWAY_HEADERS: OnceLock<HeaderMap> = OnceLock::new();
+
+/// An extra HTTP header for OpenAI-compatible gateways, specified as
+/// `--header 'Name: value'`.
+#[derive(Clone)]
+pub struct GatewayHeader {
+    /// The header name.
+    name: HeaderName,
+    /// The header value, which may contain a secret.
+    value: HeaderValue,
+}
+
+impl GatewayHeader {
+    /// Parse a `--header 'Name: value'` argument.
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let Some((name, value)) = arg.split_once(':') else {
+            return Err(format!("expected 'Name: value', got {arg:?}"));
+        };
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|err| format!("invalid header name {name:?}: {err}"))?;
+        let mut value = HeaderValue::from_str(value.trim())
+            .map_err(|err| format!("invalid value for header {name}: {err}"))?;
+        value.set_sensitive(true);
+        Ok(Self { name, value })
+    }
+}
+
+impl fmt::Debug for GatewayHeader {
+    // Never log header values, which are often API keys.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: <redacted>", self.name)
+    }
+}
+
+/// Set the extra headers to send to OpenAI-compatible gateways. This must be
+/// called at most once, before any clients are created.
+pub fn set_gateway_headers(headers: &[GatewayHeader]) -> Result<()> {
+    let mut header_map = HeaderMap::new();
+    for header in headers {
+        header_map.append(header.name.clone(), header.value.clone());
+    }
+    GATEWAY_HEADERS
+        .set(header_map)
+        .map_err(|_| anyhow!("gateway headers have already been set"))
+}
+
+/// Look up a gateway setting from the environment, preferring
+/// `LITELLM_{name}` to `OPENAI_{name}`.
+fn gateway_env_var(name: &str) -> Option<String> {
+    std::env::var(format!("LITELLM_{name}"))
+        .or_else(|_| std::env::var(format!("OPENAI_{name}")))
+        .ok()
+}
+
+/// Get the API key for our OpenAI-compatible gateway, if any.
+pub fn gateway_api_key() -> Option<String> {
+    gateway_env_var("API_KEY")
+}
+
+/// Create an HTTP client which sends our `--header` values with every request,
+/// and which gives up on connecting after `connect_timeout`.
+pub fn gateway_http_client(connect_timeout: Option<Duration>) -> Result<reqwest::Client> {
+    let headers = GATEWAY_HEADERS.get().cloned().unwrap_or_default();
+    let mut builder = reqwest::Client::builder().default_headers(headers);
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    builder.build().context("cannot create HTTP client")
+}
 
 /// Get OpenAI-compatible client configuration.
 pub fn get_openai_client_config() -> OpenAIConfig {
     let mut client_config = OpenAIConfig::new();
-    if let Ok(api_key) = std::env::var("OPENAI_API_KEY") {
+    if let Some(api_key) = gateway_api_key() {
         client_config = client_config.with_api_key(api_key);
     }
-    if let Ok(api_base) = std::env::var("OPENAI_API_BASE") {
+    if let Some(api_base) = gateway_env_var("API_BASE") {
         client_config = client_config.with_api_base(api_base);
     }
     client_config
 }
 
 /// Create an OpenAI-compatible client using the default configuration.
-fn create_llm_client() -> Result<Client<OpenAIConfig>> {
+pub fn create_llm_client() -> Result<Client<OpenAIConfig>> {
     let client_config = get_openai_client_config();
-    let client = Client::with_config(client_config);
+    let client =
+        Client::with_config(client_config).with_http_client(gateway_http_client(None)?);
     Ok(client)
 }
 
@@ -55,18 +149,196 @@ fn create_llm_client() -> Result<Client<OpenAIConfig>> {
 /// compatible gateways.
 #[derive(Debug)]
 pub struct OpenAiDriver {
-    /// The OpenAI client.
-    pub client: Client<OpenAIConfig>,
+    /// Our OpenAI-compatible client configuration.
+    config: OpenAIConfig,
+
+    /// The HTTP client. We make chat requests ourselves, rather than using
+    /// [`Client`], so that we can see the response headers.
+    http_client: reqwest::Client,
 }
 
 impl OpenAiDriver {
     /// Create a new OpenAI driver.
-    pub async fn new() -> Result<Self> {
-        let client = create_llm_client()?;
-        Ok(Self { client })
+    pub async fn new(connect_timeout: Option<Duration>) -> Result<Self> {
+        Ok(Self {
+            config: get_openai_client_config(),
+            http_client: gateway_http_client(connect_timeout)?,
+        })
+    }
+
+    /// Send a chat completion request to `model`, recording any rate limits
+    /// reported in the response headers, and the time to first byte. Returns
+    /// the response if it was successful.
+    async fn send_chat_request(
+        &self,
+        model: &str,
+        req: &Value,
+    ) -> Result<reqwest::Response, OpenAIError> {
+        let started = Instant::now();
+        let response = self
+            .http_client
+            .post(self.config.url("/chat/completions"))
+            .headers(self.config.headers())
+            .query(&self.config.query())
+            .json(req)
+            .send()
+            .await
+            .map_err(OpenAIError::Reqwest)?;
+        record_ttfb(model, started.elapsed());
+        if let Some(limits) = ProviderRateLimits::from_headers(response.headers()) {
+            record_provider_rate_limits(limits);
+        }
+
+        // Report statuses like 429 as HTTP errors, so that we retry them.
+        let status = response.status();
+        if status.is_known_transient() {
+            return Err(OpenAIError::Reqwest(
+                response
+                    .error_for_status()
+                    .expect_err("transient status should be an error"),
+            ));
+        }
+        if !status.is_success() {
+            let body = response.bytes().await.map_err(OpenAIError::Reqwest)?;
+            let wrapped = serde_json::from_slice::<ApiErrorResponse>(&body)
+                .map_err(OpenAIError::JSONDeserialize)?;
+            return Err(OpenAIError::ApiError(wrapped.error));
+        }
+        Ok(response)
+    }
+
+    /// Send a chat completion request to `model`.
+    async fn create_chat_completion(
+        &self,
+        model: &str,
+        req: &Value,
+    ) -> Result<Value, OpenAIError> {
+        let response = self.send_chat_request(model, req).await?;
+        let body = response.bytes().await.map_err(OpenAIError::Reqwest)?;
+        serde_json::from_slice(&body).map_err(OpenAIError::JSONDeserialize)
+    }
+
+    /// Stream a chat completion from `model`, giving up if the response starts
+    /// repeating itself. Returns the same JSON as [`Self::create_chat_completion`].
+    async fn create_streamed_chat_completion(
+        &self,
+        model: &str,
+        req: &Value,
+        loop_window: usize,
+    ) -> Result<Value, OpenAIError> {
+        let mut req = req.clone();
+        req["stream"] = Value::Bool(true);
+        req["stream_options"] = json!({ "include_usage": true });
+        let mut response = self.send_chat_request(model, &req).await?;
+
+        // Read server-sent events, one line at a time.
+        let mut completion = StreamedCompletion::default();
+        let mut detector = LoopDetector::new(loop_window);
+        let mut buf = Vec::new();
+        while let Some(bytes) = response.chunk().await.map_err(OpenAIError::Reqwest)? {
+            buf.extend_from_slice(&bytes);
+            while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+                let line = buf.drain(..=end).collect::<Vec<_>>();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    continue;
+                }
+                let chunk = serde_json::from_str::<Value>(data)
+                    .map_err(OpenAIError::JSONDeserialize)?;
+                if let Some(text) = completion.push(&chunk)
+                    && let Some(found) = detector.push(&text)
+                {
+                    // Dropping our response closes the connection, which
+                    // should stop the model from generating any more.
+                    return Err(OpenAIError::StreamError(format!(
+                        "response loop detected: {found}"
+                    )));
+                }
+            }
+        }
+        Ok(completion.into_response())
+    }
+}
+
+/// A streamed chat completion, assembled from its chunks.
+#[derive(Debug, Default)]
+struct StreamedCompletion {
+    /// Top-level fields like `id` and `model`.
+    fields: serde_json::Map<String, Value>,
+
+    /// The content of the first choice.
+    content: String,
+
+    /// Why the model stopped generating.
+    finish_reason: Option<Value>,
+
+    /// The model's explanation of why it refused, if it did.
+    refusal: Option<String>,
+
+    /// Token log probabilities, if requested.
+    logprobs: Vec<Value>,
+}
+
+impl StreamedCompletion {
+    /// Add a chunk of our response, returning any new content.
+    fn push(&mut self, chunk: &Value) -> Option<String> {
+        for key in ["id", "created", "model", "system_fingerprint", "usage"] {
+            if let Some(value) = chunk.get(key).filter(|value| !value.is_null()) {
+                self.fields.insert(key.to_owned(), value.clone());
+            }
+        }
+        let choice = chunk["choices"].get(0)?;
+        if let Some(reason) = choice.get("finish_reason").filter(|r| !r.is_null()) {
+            self.finish_reason = Some(reason.clone());
+        }
+        if let Some(tokens) = choice["logprobs"]["content"].as_array() {
+            self.logprobs.extend(tokens.iter().cloned());
+        }
+        if let Some(refusal) = choice["delta"]["refusal"].as_str() {
+            self.refusal.get_or_insert_default().push_str(refusal);
+        }
+        let text = choice["delta"]["content"].as_str()?;
+        self.content.push_str(text);
+        Some(text.to_owned())
+    }
+
+    /// Convert to the JSON of a non-streamed response.
+    fn into_response(self) -> Value {
+        let logprobs = if self.logprobs.is_empty() {
+            Value::Null
+        } else {
+            json!({ "content": self.logprobs })
+        };
+        let mut response = self.fields;
+        response.insert("object".to_owned(), json!("chat.completion"));
+        response.insert(
+            "choices".to_owned(),
+            json!([{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": self.content,
+                    "refusal": self.refusal,
+                },
+                "finish_reason": self.finish_reason,
+                "logprobs": logprobs,
+            }]),
+        );
+        Value::Object(response)
     }
 }
 
+/// An error response from an OpenAI-compatible API.
+#[derive(Debug, Deserialize)]
+struct ApiErrorResponse {
+    /// The error.
+    error: ApiError,
+}
+
 #[async_trait]
 impl Driver for OpenAiDriver {
     #[instrument(level = "debug", skip_all)]
@@ -78,21 +350,34 @@ impl Driver for OpenAiDriver {
         schema: Value,
         llm_opts: &LlmOpts,
     ) -> LlmRetryResult<ChatCompletionResponse> {
-        let messages = try_fatal!(prompt.to_openai_prompt());
-
-        // Build our JSON Schema options.
-        let json_schema = ResponseFormatJsonSchema {
-            name: get_schema_title(&schema),
-            schema: Some(schema),
-            strict: Some(true),
-            description: None,
+        let system_role = llm_opts.system_role.unwrap_or_else(|| {
+            // LiteLLM model names may be aliases, so check the real model.
+            let real_model = model_info.map_or(model, |info| &info.litellm_params.model);
+            default_system_role(real_model)
+        });
+        let messages = try_fatal!(openai_messages(prompt, system_role));
+        let wants_json = prompt.response_format == PromptResponseFormat::Json;
+
+        // Convert our schema to a grammar, if requested, before we give it away.
+        let grammar = if llm_opts.gbnf && wants_json {
+            Some(try_fatal!(json_schema_to_gbnf(&schema)))
+        } else {
+            None
         };
 
-        // Turn our prompt into a chat request.
+        // Turn our prompt into a chat request, asking for JSON Schema output
+        // unless we want text.
         let mut req = CreateChatCompletionRequestArgs::default();
-        req.model(model.to_owned())
-            .messages(messages)
-            .response_format(ResponseFormat::JsonSchema { json_schema });
+        req.model(model.to_owned()).messages(messages);
+        if wants_json {
+            let json_schema = ResponseFormatJsonSchema {
+                name: get_schema_title(&schema),
+                schema: Some(schema),
+                strict: Some(true),
+                description: None,
+            };
+            req.response_format(ResponseFormat::JsonSchema { json_schema });
+        }
         let mut need_to_disable_store = true;
         if let Some(model_info) = model_info {
             debug!("Provider: {}", model_info.model_info.litellm_provider);
@@ -121,24 +406,116 @@ impl Driver for OpenAiDriver {
         if let Some(top_p) = llm_opts.top_p {
             req.top_p(top_p);
         }
+        if let Some(seed) = llm_opts.seed {
+            req.seed(seed);
+        }
+        if let Some(top_k) = llm_opts.logprobs {
+            req.logprobs(true);
+            if top_k > 0 {
+                req.top_logprobs(top_k);
+            }
+        }
         let req = try_fatal!(req.build().context("Error building request"));
-        trace!(?req, "Request");
-
-        // Call OpenAI.
-        let chat = self.client.chat();
-        let chat_future = llm_opts.apply_timeout(chat.create_byot(req));
-        let chat_result: Value = try_potentially_transient!(chat_future.await);
-        debug!(%chat_result, "OpenAI response");
-        let response = try_fatal!(
-            serde_json::from_value::<CreateChatCompletionResponse>(chat_result)
-                .context("Error parsing OpenAI response")
-        );
+        let mut req =
+            try_fatal!(serde_json::to_value(req).context("Error serializing request"));
+        if !llm_opts.tags.is_empty() {
+            req["metadata"] = tags_metadata(llm_opts, model_info.is_some());
+        }
+        if let Some(grammar) = grammar {
+            // `llama.cpp` refuses requests with both a grammar and a schema.
+            if let Some(req) = req.as_object_mut() {
+                req.remove("response_format");
+            }
+            req["grammar"] = Value::String(grammar);
+        }
+        trace!(%req, "Request");
+
+        // Offer the model any MCP tools. We don't stream requests which may
+        // return tool calls, because we don't reassemble streamed tool calls.
+        let tools = try_fatal!(prompt.mcp_tools().await);
+        if let Some(tools) = tools {
+            req["tools"] = Value::Array(tools.openai_tools());
+        }
+        let max_tool_rounds = prompt.max_tool_rounds();
+        let mut tool_rounds = 0;
+        let mut tool_usage: Option<TokenUsage> = None;
+        let response = loop {
+            // On our last round, insist on an answer.
+            let may_call_tools = tools.is_some() && tool_rounds < max_tool_rounds;
+            if tools.is_some() {
+                req["tool_choice"] = json!(if may_call_tools { "auto" } else { "none" });
+            }
 
-        // How many tokens did we use?
-        let token_usage = response.usage.map(|usage| TokenUsage {
+            // Call OpenAI, streaming the response if we're watching for loops.
+            let chat_future = match llm_opts.detect_loops {
+                Some(window) if !may_call_tools => llm_opts.apply_timeout(
+                    self.create_streamed_chat_completion(model, &req, window),
+                ),
+                _ => llm_opts.apply_timeout(self.create_chat_completion(model, &req)),
+            };
+            let chat_result: Value = try_potentially_transient!(chat_future.await);
+            debug!(%chat_result, "OpenAI response");
+            let response = try_fatal!(
+                serde_json::from_value::<CreateChatCompletionResponse>(chat_result)
+                    .context("Error parsing OpenAI response")
+            );
+            let Some(tools) = tools.filter(|_| may_call_tools) else {
+                break response;
+            };
+            let Some(message) = response.choices.first().map(|choice| &choice.message)
+            else {
+                break response;
+            };
+            let Some(tool_calls) = message.tool_calls.as_ref().filter(|c| !c.is_empty())
+            else {
+                break response;
+            };
+
+            // Run the tool calls, and send back the results. Tool errors are
+            // results for the model, but if we can't reach an MCP server, try
+            // the whole record again.
+            tool_rounds += 1;
+            if let Some(usage) = &response.usage {
+                *tool_usage.get_or_insert_default() += TokenUsage {
+                    prompt_tokens: u64::from(usage.prompt_tokens),
+                    completion_tokens: u64::from(usage.completion_tokens),
+                };
+            }
+            let results = try_transient!(
+                future::try_join_all(tool_calls.iter().map(|call| {
+                    tools.call(&call.function.name, &call.function.arguments)
+                }))
+                .await
+            );
+            let Some(messages) = req["messages"].as_array_mut() else {
+                return retry_result_fatal(anyhow!("OpenAI request has no messages"));
+            };
+            messages.push(json!({
+                "role": "assistant",
+                "content": message.content,
+                "tool_calls": tool_calls,
+            }));
+            for (call, result) in tool_calls.iter().zip(results) {
+                debug!(tool = %call.function.name, %result, "Tool result");
+                messages.push(json!({
+                    "role": "tool",
+                    "tool_call_id": call.id,
+                    "content": result,
+                }));
+            }
+        };
+
+        // How many tokens did we use, including any tool-calling rounds?
+        let mut token_usage = response.usage.as_ref().map(|usage| TokenUsage {
             prompt_tokens: u64::from(usage.prompt_tokens),
             completion_tokens: u64::from(usage.completion_tokens),
         });
+        if let Some(tool_usage) = tool_usage {
+            *token_usage.get_or_insert_default() += tool_usage;
+        }
+
+        // Record which backend configuration generated this response.
+        let system_fingerprint = response.system_fingerprint.clone();
 
         // Get the content from our response & parse as JSON.
         let choice = match response.choices.first() {
@@ -149,30 +526,100 @@ impl Driver for OpenAiDriver {
         };
         if choice.finish_reason == Some(async_openai::types::FinishReason::ContentFilter)
         {
-            return retry_result_fatal(anyhow!(
-                "Content filter triggered (may also be a RECITATION error for Gemini models)"
-            ));
+            return retry_result_fatal(
+                ContentFilterError(
+                    "Content filter triggered (may also be a RECITATION error for Gemini models)"
+                        .to_owned(),
+                )
+                .into(),
+            );
         }
+
+        // Newer models report refusals separately from the content, which is
+        // usually empty. Asking again rarely changes their mind.
+        if let Some(refusal) = choice
+            .message
+            .refusal
+            .as_deref()
+            .filter(|refusal| !refusal.trim().is_empty())
+        {
+            return retry_result_fatal(RefusalError::Refused(refusal.to_owned()).into());
+        }
+        let mean_logprob = choice
+            .logprobs
+            .as_ref()
+            .and_then(|logprobs| logprobs.content.as_ref())
+            .and_then(|tokens| mean_logprob(tokens.iter().map(|t| f64::from(t.logprob))));
         let content = choice.message.content.as_deref().unwrap_or_default();
-        let response = try_transient!(
-            // If we didn't get JSON here, it's because the model didn't
-            // generate JSON. So give it another chance with `try_transient!`.
-            serde_json::from_str::<Value>(content).with_context(|| format!(
-                "Error parsing OpenAI response content: {content:?}"
-            ))
+        let raw_response = RawResponse::capture(
+            llm_opts,
+            content,
+            choice
+                .finish_reason
+                .and_then(|reason| serde_json::to_value(reason).ok())
+                .and_then(|reason| reason.as_str().map(str::to_owned)),
+            choice.message.refusal.clone(),
         );
+        let response = if wants_json {
+            try_transient!(
+                // If we didn't get JSON here, it's because the model didn't
+                // generate JSON. So give it another chance with `try_transient!`.
+                serde_json::from_str::<Value>(content).with_context(|| format!(
+                    "Error parsing OpenAI response content: {content:?}"
+                ))
+            )
+        } else {
+            Value::String(content.to_owned())
+        };
         debug!(%response, "Response");
         retry_result_ok(ChatCompletionResponse {
             response,
             token_usage,
+            system_fingerprint,
+            mean_logprob,
+            raw_response,
         })
     }
 }
 
+/// Convert our `--tag` values to request `metadata`.
+///
+/// OpenAI accepts a flat map of strings. If we have model info, we're talking
+/// to LiteLLM, which also reads a list of `tags` from `metadata` for spend
+/// tracking, and doesn't forward `metadata` to the provider.
+fn tags_metadata(llm_opts: &LlmOpts, is_litellm: bool) -> Value {
+    let tags = llm_opts.tag_map();
+    let mut metadata = tags
+        .iter()
+        .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+        .collect::<serde_json::Map<_, _>>();
+    if is_litellm {
+        metadata.insert(
+            "tags".to_owned(),
+            tags.iter()
+                .map(|(key, value)| Value::String(format!("{key}:{value}")))
+                .collect(),
+        );
+    }
+    Value::Object(metadata)
+}
+
 impl IsKnownTransient for OpenAIError {
     fn is_known_transient(&self) -> bool {
         match self {
             OpenAIError::Reqwest(error) => error.is_known_transient(),
+            // Streams which break off, or which we abandon because the model
+            // is repeating itself, are worth another try.
+            OpenAIError::StreamError(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl IsConnectTimeout for OpenAIError {
+    fn is_connect_timeout(&self) -> bool {
+        match self {
+            OpenAIError::Reqwest(error) => error.is_connect_timeout(),
             _ => false,
         }
     }
@@ -186,42 +633,74 @@ pub trait ToOpenAiPrompt {
     fn to_openai_prompt(&self) -> Result<Self::Output>;
 }
 
-impl ToOpenAiPrompt for ChatPrompt<Rendered> {
-    type Output = Vec<ChatCompletionRequestMessage>;
-
-    fn to_openai_prompt(&self) -> Result<Self::Output> {
-        // Make sure our messages appear in the order ((user, assistant)*, user).
-        if self.messages.is_empty() {
-            return Err(anyhow!("No messages in prompt"));
-        }
-        let mut expect_user_message = true;
-        for message in &self.messages {
-            let ok = match message {
-                Message::User { .. } if expect_user_message => true,
-                Message::Assistant { .. } if !expect_user_message => true,
-                _ => false,
-            };
-            if !ok {
-                return Err(anyhow!(
-                    "Expected alternating user and assistant messages in prompt, found {:?}",
-                    message
-                ));
-            }
-            expect_user_message = !expect_user_message;
-        }
-        if self.messages.len().is_multiple_of(2) {
-            return Err(anyhow!("Prompt must end with a user message"));
+/// Convert a rendered prompt to OpenAI messages, sending our `developer`
+/// message with `system_role`.
+fn openai_messages(
+    prompt: &ChatPrompt<Rendered>,
+    system_role: SystemRole,
+) -> Result<Vec<ChatCompletionRequestMessage>> {
+    // Make sure our messages appear in the order ((user, assistant)*, user).
+    if prompt.messages.is_empty() {
+        return Err(anyhow!("No messages in prompt"));
+    }
+    let mut expect_user_message = true;
+    for message in &prompt.messages {
+        let ok = match message {
+            Message::User { .. } if expect_user_message => true,
+            Message::Assistant { .. } if !expect_user_message => true,
+            _ => false,
+        };
+        if !ok {
+            return Err(anyhow!(
+                "Expected alternating user and assistant messages in prompt, found {:?}",
+                message
+            ));
         }
+        expect_user_message = !expect_user_message;
+    }
+    if prompt.messages.len().is_multiple_of(2) {
+        return Err(anyhow!("Prompt must end with a user message"));
+    }
 
-        // Render our prompt.
-        let mut messages = Vec::new();
-        if let Some(developer) = &self.developer {
-            messages.push(system_message(developer.to_owned())?);
-        }
-        for message in &self.messages {
-            messages.push(message.to_openai_prompt()?);
-        }
-        Ok(messages)
+    // Render our prompt.
+    let mut messages = Vec::new();
+    if let Some(developer) = &prompt.developer {
+        messages.push(match system_role {
+            SystemRole::System => system_message(developer.to_owned())?,
+            SystemRole::Developer => developer_message(developer.to_owned())?,
+        });
+    }
+    for message in &prompt.messages {
+        messages.push(message.to_openai_prompt()?);
+    }
+    Ok(messages)
+}
+
+/// Choose a role for our `developer` message, based on the model family. OpenAI
+/// reasoning models (o1 and later) and GPT-5 and later expect `developer`, but
+/// older chat models and most other providers only understand `system`. The
+/// early `o1-mini` and `o1-preview` models don't accept `developer` messages.
+fn default_system_role(model: &str) -> SystemRole {
+    // Strip any gateway prefix, like `openai/` or `azure/`.
+    let name = model
+        .rsplit('/')
+        .next()
+        .unwrap_or(model)
+        .to_ascii_lowercase();
+    let is_o_series = name
+        .strip_prefix('o')
+        .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+        && !name.starts_with("o1-mini")
+        && !name.starts_with("o1-preview");
+    // Only look at the first digit, because Azure calls GPT-3.5 `gpt-35`.
+    let is_gpt_5_or_later = name
+        .strip_prefix("gpt-")
+        .and_then(|rest| rest.chars().next()?.to_digit(10))
+        .is_some_and(|major| major >= 5);
+    if is_o_series || is_gpt_5_or_later {
+        SystemRole::Developer
+    } else {
+        SystemRole::System
     }
 }
 
@@ -246,11 +725,11 @@ impl ToOpenAiPrompt for Message {
                     parts.push(user_message_text_part(text.to_owned())?);
                 }
                 for image in images {
-                    parts.push(user_message_image_part(image.to_owned())?);
+                    parts.push(user_message_image_part(image)?);
                 }
                 user_message_multi_part(parts)
             }
-            Message::Assistant { json } => assistant_message(json.to_string()),
+            Message::Assistant { json } => assistant_message(assistant_text(json)),
         }
     }
 }
@@ -264,6 +743,16 @@ fn system_message(content: String) -> Result<ChatCompletionRequestMessage> {
     ))
 }
 
+/// Build a developer message, which newer OpenAI models use in place of a
+/// system message.
+fn developer_message(content: String) -> Result<ChatCompletionRequestMessage> {
+    Ok(ChatCompletionRequestMessage::Developer(
+        ChatCompletionRequestDeveloperMessageArgs::default()
+            .content(ChatCompletionRequestDeveloperMessageContent::Text(content))
+            .build()?,
+    ))
+}
+
 /// Build a simple user message.
 fn user_message(content: String) -> Result<ChatCompletionRequestMessage> {
     Ok(ChatCompletionRequestMessage::User(
@@ -297,11 +786,20 @@ fn user_message_text_part(
 
 // Build a user message image part.
 fn user_message_image_part(
-    url: String,
+    image: &PromptImage,
 ) -> Result<ChatCompletionRequestUserMessageContentPart> {
+    let mut image_url = ImageUrlArgs::default();
+    image_url.url(&*image.to_url());
+    if let Some(detail) = image.detail {
+        image_url.detail(match detail {
+            PromptImageDetail::Auto => ImageDetail::Auto,
+            PromptImageDetail::Low => ImageDetail::Low,
+            PromptImageDetail::High => ImageDetail::High,
+        });
+    }
     Ok(ChatCompletionRequestUserMessageContentPart::ImageUrl(
         ChatCompletionRequestMessageContentPartImageArgs::default()
-            .image_url(url)
+            .image_url(image_url.build()?)
             .build()?,
     ))
 }
@@ -314,3 +812,97 @@ fn assistant_message(content: String) -> Result<ChatCompletionRequestMessage> {
             .build()?,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_role_depends_on_model_family() {
+        for model in ["o1", "o3-mini", "openai/o4-mini", "gpt-5", "gpt-5.1-mini"] {
+            assert_eq!(default_system_role(model), SystemRole::Developer, "{model}");
+        }
+        for model in [
+            "gpt-4o",
+            "gpt-4.1",
+            "o1-mini",
+            "azure/gpt-35-turbo",
+            "ollama/llama3",
+            "claude-sonnet-4",
+        ] {
+            assert_eq!(default_system_role(model), SystemRole::System, "{model}");
+        }
+    }
+
+    #[test]
+    fn streamed_chunks_are_assembled_into_a_response() {
+        let chunk = |choices: Value| {
+            json!({
+                "id": "c1",
+                "object": "chat.completion.chunk",
+                "created": 1,
+                "model": "m",
+                "choices": choices,
+            })
+        };
+        let mut usage = chunk(json!([]));
+        usage["usage"] = json!({
+            "prompt_tokens": 5,
+            "completion_tokens": 2,
+            "total_tokens": 7,
+        });
+        let chunks = [
+            chunk(json!([{
+                "index": 0,
+                "delta": { "role": "assistant", "content": "{\"a\":" },
+            }])),
+            chunk(json!([{
+                "index": 0,
+                "delta": { "content": " 1}" },
+                "finish_reason": "stop",
+            }])),
+            usage,
+        ];
+        let mut completion = StreamedCompletion::default();
+        let new_text = chunks
+            .iter()
+            .filter_map(|chunk| completion.push(chunk))
+            .collect::<Vec<_>>();
+        assert_eq!(new_text, vec!["{\"a\":", " 1}"]);
+
+        let response = serde_json::from_value::<CreateChatCompletionResponse>(
+            completion.into_response(),
+        )
+        .unwrap();
+        assert_eq!(
+            response.choices[0].message.content.as_deref(),
+            Some("{\"a\": 1}")
+        );
+        assert_eq!(
+            response.choices[0].finish_reason,
+            Some(async_openai::types::FinishReason::Stop)
+        );
+        assert_eq!(response.usage.unwrap().completion_tokens, 2);
+    }
+
+    #[test]
+    fn streamed_refusals_are_kept() {
+        let mut completion = StreamedCompletion::default();
+        for refusal in ["I can't ", "help with that."] {
+            completion.push(&json!({
+                "id": "c1",
+                "created": 1,
+                "model": "m",
+                "choices": [{ "index": 0, "delta": { "refusal": refusal } }],
+            }));
+        }
+        let response = serde_json::from_value::<CreateChatCompletionResponse>(
+            completion.into_response(),
+        )
+        .unwrap();
+        assert_eq!(
+            response.choices[0].message.refusal.as_deref(),
+            Some("I can't help with that.")
+        );
+    }
+}
@@ -3,20 +3,26 @@
 use std::env;
 
 use async_trait::async_trait;
-use base64::{Engine, prelude::BASE64_STANDARD};
 use google_cloud_aiplatform_v1 as vertexai;
+use google_cloud_auth::credentials::api_key_credentials;
 use google_cloud_gax::error::rpc::Code;
 use vertexai::{
     client::PredictionService,
-    model::{Blob, Content, GenerationConfig, Part},
+    model::{
+        Blob, Candidate, Content, GenerateContentResponse, GenerationConfig, Part,
+        candidate::FinishReason,
+        generate_content_response::prompt_feedback::BlockedReason,
+    },
 };
 
 use crate::{
-    data_url::parse_data_url,
-    drivers::{ChatCompletionResponse, Driver, LlmOpts, LlmRetryResult, TokenUsage},
+    drivers::{
+        ChatCompletionResponse, ContentFilterError, Driver, LlmOpts, LlmRetryResult,
+        RawResponse, RefusalError, TokenUsage,
+    },
     litellm::LiteLlmModel,
     prelude::*,
-    prompt::{ChatPrompt, Message, Rendered},
+    prompt::{ChatPrompt, Message, Rendered, ResponseFormat, assistant_text},
     retry::{
         IsKnownTransient, retry_result_ok, try_fatal, try_potentially_transient,
         try_transient,
@@ -29,20 +35,51 @@ pub struct VertexDriver {
     /// The Vertex AI client.
     pub client: PredictionService,
 
-    /// Our GCP project ID.
-    pub project_id: String,
+    /// Our GCP project ID, or `None` if we're using an express-mode API key.
+    pub project_id: Option<String>,
 }
 
 impl VertexDriver {
     /// Create a new Vertex AI driver.
+    ///
+    /// If `VERTEX_API_KEY` is set, we use Vertex AI "express mode", which
+    /// needs no GCP project or Application Default Credentials. Otherwise, we
+    /// use ADC with the project in `GCP_PROJECT`.
     pub async fn new() -> Result<Self> {
+        if let Ok(api_key) = env::var("VERTEX_API_KEY") {
+            let credentials = api_key_credentials::Builder::new(api_key).build();
+            let client = PredictionService::builder()
+                .with_credentials(credentials)
+                .build()
+                .await
+                .context("Failed to create Vertex AI client")?;
+            return Ok(Self {
+                client,
+                project_id: None,
+            });
+        }
         let client = PredictionService::builder()
             .build()
             .await
             .context("Failed to create Vertex AI client")?;
-        let project_id = env::var("GCP_PROJECT")
-            .context("GCP_PROJECT environment variable is not set")?;
-        Ok(Self { client, project_id })
+        let project_id = env::var("GCP_PROJECT").context(
+            "GCP_PROJECT environment variable is not set (or set VERTEX_API_KEY)",
+        )?;
+        Ok(Self {
+            client,
+            project_id: Some(project_id),
+        })
+    }
+
+    /// Get the full resource name for `model`.
+    fn model_path(&self, model: &str) -> String {
+        match &self.project_id {
+            Some(project_id) => format!(
+                "projects/{project_id}/locations/global/publishers/google/models/{model}"
+            ),
+            // Express mode doesn't use projects or locations.
+            None => format!("publishers/google/models/{model}"),
+        }
     }
 }
 
@@ -63,9 +100,12 @@ impl Driver for VertexDriver {
         trace!(?contents, "Vertex request");
 
         // Set up generation config.
-        let mut generation_config = GenerationConfig::new()
-            .set_response_mime_type("application/json")
-            .set_response_json_schema(schema);
+        let mut generation_config = GenerationConfig::new();
+        if prompt.response_format == ResponseFormat::Json {
+            generation_config = generation_config
+                .set_response_mime_type("application/json")
+                .set_response_json_schema(schema);
+        }
         if let Some(max_tokens) = llm_opts.max_completion_tokens {
             generation_config =
                 generation_config.set_max_output_tokens(max_tokens as i32);
@@ -76,12 +116,22 @@ impl Driver for VertexDriver {
         if let Some(top_p) = llm_opts.top_p {
             generation_config = generation_config.set_top_p(top_p);
         }
+        if let Some(seed) = llm_opts.seed {
+            // Vertex only supports 32-bit seeds.
+            let seed = try_fatal!(i32::try_from(seed).with_context(|| format!(
+                "Vertex AI seed {seed} does not fit in 32 bits"
+            )));
+            generation_config = generation_config.set_seed(seed);
+        }
+        if let Some(top_k) = llm_opts.logprobs {
+            generation_config = generation_config.set_response_logprobs(true);
+            if top_k > 0 {
+                generation_config = generation_config.set_logprobs(i32::from(top_k));
+            }
+        }
 
         // Get our full model name.
-        let model_path = format!(
-            "projects/{project_id}/locations/global/publishers/google/models/{model}",
-            project_id = self.project_id,
-        );
+        let model_path = self.model_path(model);
 
         // Send the request.
         let request = self
@@ -90,32 +140,56 @@ impl Driver for VertexDriver {
             .set_model(model_path)
             .set_or_clear_system_instruction(system_instruction)
             .set_contents(contents)
-            .set_generation_config(generation_config);
+            .set_generation_config(generation_config)
+            .set_labels(llm_opts.tag_map());
 
         let response = try_potentially_transient!(request.send().await);
         trace!(?response, "Vertex response");
 
         // Extract the response. Some of these fatal errors might in fact be transients, but
         // we'll only find that out by running the code on large numbers of inputs.
-        let candidate = try_fatal!(response.candidates.first().ok_or_else(|| {
-            anyhow!("Vertex AI response did not contain any candidates")
-        }));
-        let response_content =
-            try_fatal!(candidate.content.as_ref().ok_or_else(|| anyhow!(
-                "Vertex AI response did not contain any content"
-            )));
+        let Some(candidate) = response.candidates.first() else {
+            return LlmRetryResult::Fatal {
+                input: (),
+                error: missing_candidates_error(&response),
+            };
+        };
+        let Some(response_content) = candidate
+            .content
+            .as_ref()
+            .filter(|content| !content.parts.is_empty())
+        else {
+            // Gemini omits the content entirely when it stops for safety or
+            // recitation reasons, so explain why instead.
+            return LlmRetryResult::Fatal {
+                input: (),
+                error: missing_content_error(candidate),
+            };
+        };
+
+        // Vertex computes the mean logprob for us.
+        let mean_logprob = llm_opts.logprobs.map(|_| candidate.avg_logprobs);
 
         // Find the assistant's text response.
         let response_text = try_transient!(extract_assistant_text(response_content));
+        let raw_response = RawResponse::capture(
+            llm_opts,
+            &response_text,
+            Some(format!("{:?}", candidate.finish_reason)),
+            None,
+        );
 
         // Parse the response as JSON. If this fails, it means Google didn't
         // follow our schema, which is weird. But we'll retry it.
-        let response_json = try_transient!(
-            serde_json::from_str::<Value>(&response_text).with_context(|| format!(
-                "Failed to parse Vertex AI response as JSON: {}",
-                response_text
-            ))
-        );
+        let response_json = match prompt.response_format {
+            ResponseFormat::Json => try_transient!(
+                serde_json::from_str::<Value>(&response_text).with_context(|| format!(
+                    "Failed to parse Vertex AI response as JSON: {}",
+                    response_text
+                ))
+            ),
+            ResponseFormat::Text => Value::String(response_text),
+        };
         debug!(json = %response_json, "Vertex response JSON");
 
         // Get token usage if available.
@@ -128,10 +202,72 @@ impl Driver for VertexDriver {
         retry_result_ok(ChatCompletionResponse {
             response: response_json,
             token_usage,
+            system_fingerprint: None,
+            mean_logprob,
+            raw_response,
         })
     }
 }
 
+/// Explain why a Vertex AI response contained no candidates.
+fn missing_candidates_error(response: &GenerateContentResponse) -> anyhow::Error {
+    match &response.prompt_feedback {
+        Some(feedback) if feedback.block_reason != BlockedReason::Unspecified => {
+            ContentFilterError(format!(
+                "Vertex AI blocked the prompt with {:?}: {}",
+                feedback.block_reason, feedback.block_reason_message
+            ))
+            .into()
+        }
+        _ => anyhow!("Vertex AI response did not contain any candidates"),
+    }
+}
+
+/// Explain why a Vertex AI candidate contained no content, using its finish
+/// reason, safety ratings and citations.
+fn missing_content_error(candidate: &Candidate) -> anyhow::Error {
+    let finish_reason = &candidate.finish_reason;
+    match finish_reason {
+        FinishReason::Recitation => {
+            let sources = candidate
+                .citation_metadata
+                .iter()
+                .flat_map(|metadata| &metadata.citations)
+                .map(|citation| citation.uri.as_str())
+                .filter(|uri| !uri.is_empty())
+                .collect::<Vec<_>>();
+            RefusalError::Recitation(format!(
+                "Vertex AI withheld a response which quoted its sources too closely \
+                 (citations: [{}])",
+                sources.join(", ")
+            ))
+            .into()
+        }
+        FinishReason::Safety
+        | FinishReason::Blocklist
+        | FinishReason::ProhibitedContent
+        | FinishReason::Spii => {
+            let blocked = candidate
+                .safety_ratings
+                .iter()
+                .filter(|rating| rating.blocked)
+                .map(|rating| format!("{:?}", rating.category))
+                .collect::<Vec<_>>();
+            ContentFilterError(format!(
+                "Vertex AI stopped with {finish_reason:?} (blocked categories: [{}])",
+                blocked.join(", ")
+            ))
+            .into()
+        }
+        _ => RefusalError::Refused(format!(
+            "Vertex AI response did not contain any content (finish reason: \
+             {finish_reason:?}, message: {:?})",
+            candidate.finish_message
+        ))
+        .into(),
+    }
+}
+
 impl IsKnownTransient for vertexai::Error {
     fn is_known_transient(&self) -> bool {
         if let Some(status) = self.status()
@@ -209,18 +345,17 @@ impl ToVertexContents for Message {
 
                 // Add images if present.
                 for image in images {
-                    if image.starts_with("data:")
-                        && let Some((mime_type, base64_data)) = parse_data_url(image)
-                    {
-                        let bytes = BASE64_STANDARD
-                            .decode(base64_data)
-                            .context("Failed to decode base64 image data")?;
-                        parts.push(Part::new().set_inline_data(
-                            Blob::new().set_mime_type(mime_type).set_data(bytes),
-                        ));
+                    if let Some(data) = image.data() {
+                        parts.push(
+                            Part::new().set_inline_data(
+                                Blob::new()
+                                    .set_mime_type(&data.mime_type)
+                                    .set_data(data.bytes.clone()),
+                            ),
+                        );
                     } else {
                         return Err(anyhow!(
-                            "Only data URLs are supported for images in Vertex driver, got: {}",
+                            "Only data, http and https URLs are supported for images in Vertex driver, got: {:?}",
                             image
                         ));
                     }
@@ -232,13 +367,10 @@ impl ToVertexContents for Message {
                 contents.push(Content::new().set_role("user").set_parts(parts));
             }
             Message::Assistant { json } => {
-                // Convert JSON to string for the assistant response
-                let json_str = serde_json::to_string(json)
-                    .context("Failed to serialize assistant response as JSON")?;
                 contents.push(
                     Content::new()
                         .set_role("model")
-                        .set_parts([Part::new().set_text(&json_str)]),
+                        .set_parts([Part::new().set_text(assistant_text(json))]),
                 );
             }
         }
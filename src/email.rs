@@ -0,0 +1,497 @@
+//! Parsing `.eml` and `.msg` emails, and fanning out their attachments.
+//!
+//! Legal document collections often contain raw emails. With `--email-input`,
+//! each input record's `path` names an email. We parse it into an `email`
+//! template binding, and turn each PDF or image attachment into a child record
+//! of its own, so the whole collection can be processed in one run.
+
+use std::{fs, iter, sync::Arc};
+
+use futures::{StreamExt as _, stream};
+use mail_parser::{MessageParser, MimeHeaders as _};
+use tempfile::{TempDir, TempPath};
+
+use crate::{
+    async_utils::{
+        BoxedStream, blocking_iter_streams::spawn_blocking_propagating_panics, io::id_key,
+    },
+    document::html_to_text,
+    prelude::*,
+    queues::{chat::ChatInput, work::WorkInput},
+};
+
+/// A parsed email.
+#[derive(Debug)]
+pub struct ParsedEmail {
+    /// The email's headers and body, as exposed to templates.
+    pub summary: EmailSummary,
+
+    /// The email's attachments.
+    pub attachments: Vec<EmailAttachment>,
+}
+
+/// The parts of an email we expose to templates, as `{{email.subject}}` and so
+/// on.
+#[derive(Debug, Default, Serialize)]
+pub struct EmailSummary {
+    /// The sender, as `Name <address>`.
+    pub from: Option<String>,
+    /// The `To:` recipients.
+    pub to: Vec<String>,
+    /// The `Cc:` recipients.
+    pub cc: Vec<String>,
+    /// The subject line.
+    pub subject: Option<String>,
+    /// The date the email was sent.
+    pub date: Option<String>,
+    /// The `Message-ID` header.
+    pub message_id: Option<String>,
+    /// The plain text body, or the HTML body converted to text.
+    pub body: String,
+    /// The file names of all attachments, including ones we don't fan out.
+    pub attachments: Vec<String>,
+}
+
+/// An email attachment.
+#[derive(Debug)]
+pub struct EmailAttachment {
+    /// The attachment's file name, if it has one.
+    pub filename: Option<String>,
+
+    /// The attachment's MIME type.
+    pub mime_type: String,
+
+    /// The attachment's contents.
+    pub data: Vec<u8>,
+}
+
+impl EmailAttachment {
+    /// Should this attachment become a child record? We only fan out
+    /// attachments which our pipelines know how to read.
+    fn is_document(&self) -> bool {
+        self.mime_type == "application/pdf" || self.mime_type.starts_with("image/")
+    }
+}
+
+/// Parse the `.eml` or `.msg` file at `path`.
+///
+/// This performs blocking I/O.
+pub fn parse_email(path: &Path) -> Result<ParsedEmail> {
+    let is_msg = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("msg"));
+    if is_msg {
+        parse_msg(path)
+    } else {
+        let data = fs::read(path)
+            .with_context(|| format!("failed to read email {:?}", path.display()))?;
+        parse_eml(&data)
+            .with_context(|| format!("failed to parse email {:?}", path.display()))
+    }
+}
+
+/// Parse an RFC 5322 (`.eml`) email.
+fn parse_eml(data: &[u8]) -> Result<ParsedEmail> {
+    let message = MessageParser::default()
+        .parse(data)
+        .ok_or_else(|| anyhow!("not a valid email"))?;
+    let body = match message.body_text(0) {
+        Some(text) => text.trim().to_owned(),
+        None => match message.body_html(0) {
+            Some(html) => html_to_text(&html)?,
+            None => String::new(),
+        },
+    };
+
+    let attachments = message
+        .attachments()
+        .map(|part| {
+            let filename = part.attachment_name().map(|name| name.to_owned());
+            let mime_type = match part.content_type() {
+                Some(ct) => match ct.subtype() {
+                    Some(subtype) => format!("{}/{}", ct.ctype(), subtype),
+                    None => ct.ctype().to_owned(),
+                },
+                None => guess_mime_type(filename.as_deref()),
+            };
+            EmailAttachment {
+                filename,
+                mime_type: mime_type.to_ascii_lowercase(),
+                data: part.contents().to_vec(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(ParsedEmail {
+        summary: EmailSummary {
+            from: address_list(message.from()).into_iter().next(),
+            to: address_list(message.to()),
+            cc: address_list(message.cc()),
+            subject: message.subject().map(|s| s.to_owned()),
+            date: message.date().map(|date| date.to_rfc3339()),
+            message_id: message.message_id().map(|id| id.to_owned()),
+            body,
+            attachments: attachment_names(&attachments),
+        },
+        attachments,
+    })
+}
+
+/// Parse an Outlook (`.msg`) email.
+fn parse_msg(path: &Path) -> Result<ParsedEmail> {
+    let outlook = msg_parser::Outlook::from_path(path).map_err(|err| {
+        anyhow!("failed to parse Outlook email {:?}: {err}", path.display())
+    })?;
+    let person = |person: &msg_parser::Person| {
+        format_address(Some(&person.name), Some(&person.email))
+    };
+    let non_empty = |s: &str| Some(s.trim().to_owned()).filter(|s| !s.is_empty());
+
+    let attachments = outlook
+        .attachments
+        .iter()
+        .map(|attachment| {
+            let filename = non_empty(&attachment.file_name)
+                .or_else(|| non_empty(&attachment.display_name));
+            let mime_type = non_empty(&attachment.mime_tag)
+                .unwrap_or_else(|| guess_mime_type(filename.as_deref()));
+            // Attachment payloads are hex-encoded.
+            let data = hex::decode(&attachment.payload).with_context(|| {
+                format!("invalid attachment data in {:?}", path.display())
+            })?;
+            Ok(EmailAttachment {
+                filename,
+                mime_type: mime_type.to_ascii_lowercase(),
+                data,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(ParsedEmail {
+        summary: EmailSummary {
+            from: Some(person(&outlook.sender)).filter(|s| !s.is_empty()),
+            to: outlook.to.iter().map(person).collect(),
+            cc: outlook.cc.iter().map(person).collect(),
+            subject: non_empty(&outlook.subject),
+            date: non_empty(&outlook.headers.date),
+            message_id: non_empty(&outlook.headers.message_id),
+            body: outlook.body.trim().to_owned(),
+            attachments: attachment_names(&attachments),
+        },
+        attachments,
+    })
+}
+
+/// Format each address in a header.
+fn address_list(address: Option<&mail_parser::Address<'_>>) -> Vec<String> {
+    address
+        .into_iter()
+        .flat_map(|address| address.iter())
+        .map(|addr| format_address(addr.name(), addr.address()))
+        .collect()
+}
+
+/// Format an address as `Name <address>`, or whichever part we have.
+fn format_address(name: Option<&str>, address: Option<&str>) -> String {
+    let name = name.map(str::trim).filter(|n| !n.is_empty());
+    let address = address.map(str::trim).filter(|a| !a.is_empty());
+    match (name, address) {
+        (Some(name), Some(address)) => format!("{name} <{address}>"),
+        (Some(name), None) => name.to_owned(),
+        (None, Some(address)) => address.to_owned(),
+        (None, None) => String::new(),
+    }
+}
+
+/// Guess a MIME type from a file name.
+fn guess_mime_type(filename: Option<&str>) -> String {
+    filename
+        .and_then(|name| mime_guess::from_path(name).first())
+        .map(|mime| mime.essence_str().to_owned())
+        .unwrap_or_else(|| "application/octet-stream".to_owned())
+}
+
+/// Get the names of all attachments, for use in templates.
+fn attachment_names(attachments: &[EmailAttachment]) -> Vec<String> {
+    attachments
+        .iter()
+        .enumerate()
+        .map(|(idx, attachment)| {
+            attachment
+                .filename
+                .clone()
+                .unwrap_or_else(|| format!("attachment-{}", idx + 1))
+        })
+        .collect()
+}
+
+/// Replace each input record with a parent record for the email named by its
+/// `path` binding, followed by one child record per PDF or image attachment.
+///
+/// Attachments are written to `scratch_dir`, which must outlive processing.
+/// Each child record owns its attachment's file, which is deleted once the
+/// record is done. Child records get the ID `"{parent_id}/{n}"`, and their parent's ID is
+/// recorded in `passthrough_data.parent_id`. Parent records list their
+/// children in `passthrough_data.child_ids`.
+pub fn expand_email_inputs(
+    input: BoxedStream<Result<WorkInput<ChatInput>>>,
+    scratch_dir: Arc<TempDir>,
+) -> BoxedStream<Result<WorkInput<ChatInput>>> {
+    input
+        .then(move |record| {
+            let scratch_dir = scratch_dir.clone();
+            async move {
+                let record = record?;
+                Ok(spawn_blocking_propagating_panics(move || {
+                    expand_email_input(record, scratch_dir.path())
+                })
+                .await)
+            }
+        })
+        .flat_map(|records| match records {
+            Ok(records) => stream::iter(records.into_iter().map(Ok)).boxed(),
+            Err(err) => stream::once(async { Err(err) }).boxed(),
+        })
+        .boxed()
+}
+
+/// Expand a single email record. If we can't read the email, we return the
+/// record with an `input_error`, so that only that record fails.
+///
+/// This performs blocking I/O.
+fn expand_email_input(
+    mut parent: WorkInput<ChatInput>,
+    scratch_dir: &Path,
+) -> Vec<WorkInput<ChatInput>> {
+    match expand_email_children(&mut parent, scratch_dir) {
+        Ok(children) => iter::once(parent).chain(children).collect(),
+        Err(err) => {
+            warn!(
+                "Could not read email for record {}: {err:#}",
+                id_key(&parent.id)
+            );
+            parent.data.input_error = Some(format!("email_unreadable: {err:#}"));
+            vec![parent]
+        }
+    }
+}
+
+/// Read the email for `parent`, add its bindings, and return a child record
+/// for each document attachment. `parent` is only changed if we succeed.
+fn expand_email_children(
+    parent: &mut WorkInput<ChatInput>,
+    scratch_dir: &Path,
+) -> Result<Vec<WorkInput<ChatInput>>> {
+    let path = parent
+        .data
+        .template_bindings
+        .get("path")
+        .and_then(|path| path.as_str())
+        .ok_or_else(|| {
+            anyhow!(
+                "--email-input requires a string `path` in record {}",
+                parent.id
+            )
+        })?
+        .to_owned();
+    let email = parse_email(Path::new(&path))?;
+    let email_binding = serde_json::to_value(&email.summary)?;
+
+    let mut children = vec![];
+    for (idx, attachment) in email.attachments.into_iter().enumerate() {
+        if !attachment.is_document() {
+            continue;
+        }
+        let filename = &email.summary.attachments[idx];
+        let child_id = Value::String(format!("{}/{}", id_key(&parent.id), idx + 1));
+        let attachment_path = scratch_dir.join(format!(
+            "{}-{}",
+            uuid::Uuid::new_v4(),
+            sanitize_filename(filename)
+        ));
+        let attachment_file = Arc::new(TempPath::from_path(&attachment_path));
+        fs::write(&attachment_path, &attachment.data).with_context(|| {
+            format!("failed to write attachment {:?}", attachment_path.display())
+        })?;
+
+        let mut data = parent.data.clone();
+        data.offloaded_files.push(attachment_file);
+        data.template_bindings
+            .insert("email".to_owned(), email_binding.clone());
+        data.template_bindings.insert(
+            "attachment".to_owned(),
+            json!({
+                "filename": filename,
+                "mime_type": attachment.mime_type,
+                "path": attachment_path.display().to_string(),
+            }),
+        );
+        children.push(WorkInput {
+            id: child_id,
+            skip_processing: parent.skip_processing,
+            passthrough_data: Some(with_passthrough_field(
+                parent.passthrough_data.clone(),
+                "parent_id",
+                parent.id.clone(),
+            )?),
+            ack_token: None,
+            data,
+        });
+    }
+
+    let child_ids = children.iter().map(|child| child.id.clone()).collect();
+    parent.passthrough_data = Some(with_passthrough_field(
+        parent.passthrough_data.clone(),
+        "child_ids",
+        Value::Array(child_ids),
+    )?);
+    parent
+        .data
+        .template_bindings
+        .insert("email".to_owned(), email_binding);
+    Ok(children)
+}
+
+/// Add `key` to our passthrough data, which must be an object if present.
+fn with_passthrough_field(
+    passthrough_data: Option<Value>,
+    key: &str,
+    value: Value,
+) -> Result<Value> {
+    let mut passthrough_data = match passthrough_data {
+        None => serde_json::Map::new(),
+        Some(Value::Object(map)) => map,
+        Some(_) => {
+            return Err(anyhow!(
+                "--email-input requires passthrough_data to be an object"
+            ));
+        }
+    };
+    passthrough_data.insert(key.to_owned(), value);
+    Ok(Value::Object(passthrough_data))
+}
+
+/// Make an attachment name safe to use as a file name.
+fn sanitize_filename(filename: &str) -> String {
+    let filename = Path::new(filename)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("attachment");
+    filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TEST_EML: &str = "From: Alice Example <alice@example.com>\r
+To: bob@example.com\r
+Subject: Contract\r
+Message-ID: <1234@example.com>\r
+MIME-Version: 1.0\r
+Content-Type: multipart/mixed; boundary=\"XXX\"\r
+\r
+--XXX\r
+Content-Type: text/plain\r
+\r
+Please see attached.\r
+--XXX\r
+Content-Type: application/pdf\r
+Content-Disposition: attachment; filename=\"contract.pdf\"\r
+Content-Transfer-Encoding: base64\r
+\r
+JVBERi0xLjQK\r
+--XXX\r
+Content-Type: text/csv\r
+Content-Disposition: attachment; filename=\"notes.csv\"\r
+\r
+a,b\r
+--XXX--\r
+";
+
+    #[test]
+    fn parse_eml_extracts_headers_body_and_attachments() {
+        let email = parse_eml(TEST_EML.as_bytes()).unwrap();
+        assert_eq!(
+            email.summary.from.as_deref(),
+            Some("Alice Example <alice@example.com>")
+        );
+        assert_eq!(email.summary.to, vec!["bob@example.com"]);
+        assert_eq!(email.summary.subject.as_deref(), Some("Contract"));
+        assert_eq!(email.summary.body, "Please see attached.");
+        assert_eq!(email.summary.attachments, vec!["contract.pdf", "notes.csv"]);
+        assert_eq!(email.attachments[0].mime_type, "application/pdf");
+        assert_eq!(email.attachments[0].data, b"%PDF-1.4\n");
+        assert!(email.attachments[0].is_document());
+        assert!(!email.attachments[1].is_document());
+    }
+
+    #[test]
+    fn expand_email_input_fans_out_documents() {
+        let dir = TempDir::new().unwrap();
+        let eml_path = dir.path().join("email.eml");
+        fs::write(&eml_path, TEST_EML).unwrap();
+        let record = WorkInput::<ChatInput>::from_json(json!({
+            "id": 7,
+            "path": eml_path.display().to_string(),
+        }))
+        .unwrap();
+
+        let records = expand_email_input(record, dir.path());
+        assert_eq!(records.len(), 2);
+        let (parent, child) = (&records[0], &records[1]);
+        assert_eq!(parent.id, json!(7));
+        assert_eq!(
+            parent.passthrough_data,
+            Some(json!({ "child_ids": ["7/1"] }))
+        );
+        assert_eq!(
+            parent.data.template_bindings["email"]["subject"],
+            "Contract"
+        );
+        assert_eq!(child.id, json!("7/1"));
+        assert_eq!(child.passthrough_data, Some(json!({ "parent_id": 7 })));
+        let attachment_path = child.data.template_bindings["attachment"]["path"]
+            .as_str()
+            .unwrap();
+        assert_eq!(fs::read(attachment_path).unwrap(), b"%PDF-1.4\n");
+
+        // Attachments are deleted along with their records.
+        let attachment_path = PathBuf::from(attachment_path);
+        drop(records);
+        assert!(!attachment_path.exists());
+    }
+
+    #[test]
+    fn unreadable_email_fails_only_its_record() {
+        let dir = TempDir::new().unwrap();
+        let record = WorkInput::<ChatInput>::from_json(json!({
+            "id": 8,
+            "path": dir.path().join("missing.eml").display().to_string(),
+        }))
+        .unwrap();
+
+        let records = expand_email_input(record, dir.path());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, json!(8));
+        assert!(records[0].passthrough_data.is_none());
+        assert!(
+            records[0]
+                .data
+                .input_error
+                .as_deref()
+                .unwrap()
+                .starts_with("email_unreadable: ")
+        );
+    }
+}
@@ -0,0 +1,256 @@
+//! Convert JSON Schemas to GBNF grammars, for grammar-constrained decoding.
+//!
+//! Local servers like `llama.cpp` often follow a JSON Schema `response_format`
+//! poorly, or ignore it. But they can constrain sampling to a GBNF grammar, so
+//! that the model can only generate JSON of the right shape. See
+//! <https://github.com/ggml-org/llama.cpp/blob/master/grammars/README.md>.
+//!
+//! We support the subset of JSON Schema that we generate ourselves: objects
+//! (whose properties are all treated as required, as with OpenAI's strict
+//! mode), arrays, scalars, `enum`, `const`, `anyOf`, `oneOf` and local `$ref`s.
+//! Anything else is allowed to be any JSON value.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::prelude::*;
+
+/// Rules for JSON primitives, adapted from `llama.cpp`'s own schema converter.
+const PRIMITIVE_RULES: &[(&str, &str)] = &[
+    ("space", r#"| " " | "\n" [ \t]{0,20}"#),
+    (
+        "char",
+        r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\bfnrt] | "u" [0-9a-fA-F]{4})"#,
+    ),
+    ("string", r#""\"" char* "\"" space"#),
+    ("integral-part", r#"[0] | [1-9] [0-9]{0,15}"#),
+    ("decimal-part", r#"[0-9]{1,16}"#),
+    (
+        "number",
+        r#"("-"? integral-part) ("." decimal-part)? ([eE] [-+]? integral-part)? space"#,
+    ),
+    ("integer", r#"("-"? integral-part) space"#),
+    ("boolean", r#"("true" | "false") space"#),
+    ("null", r#""null" space"#),
+    (
+        "object",
+        r#""{" space ( string ":" space value ("," space string ":" space value)* )? "}" space"#,
+    ),
+    (
+        "array",
+        r#""[" space ( value ("," space value)* )? "]" space"#,
+    ),
+    (
+        "value",
+        r#"object | array | string | number | boolean | null"#,
+    ),
+];
+
+/// Convert a JSON Schema to a GBNF grammar whose root rule matches JSON
+/// values described by the schema.
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String> {
+    let mut converter = Converter {
+        root: schema,
+        rules: BTreeMap::new(),
+        refs: HashMap::new(),
+    };
+    for (name, rule) in PRIMITIVE_RULES {
+        converter
+            .rules
+            .insert((*name).to_owned(), (*rule).to_owned());
+    }
+    let root = converter.visit(schema, "root")?;
+
+    let mut grammar = format!("root ::= {root}\n");
+    for (name, rule) in &converter.rules {
+        grammar.push_str(&format!("{name} ::= {rule}\n"));
+    }
+    Ok(grammar)
+}
+
+/// State for converting a schema.
+struct Converter<'a> {
+    /// The root schema, used to resolve `$ref`s.
+    root: &'a Value,
+
+    /// Named rules we have generated so far, not including `root`.
+    rules: BTreeMap<String, String>,
+
+    /// Rule names for `$ref`s we have already seen, which allows recursion.
+    refs: HashMap<String, String>,
+}
+
+impl Converter<'_> {
+    /// Return a GBNF expression matching `schema`. `name` is used as the base
+    /// name of any rules we need to create.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            return self.visit_ref(reference);
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(Value::Array(alternatives)) = schema.get(key) {
+                let alternatives = alternatives
+                    .iter()
+                    .enumerate()
+                    .map(|(i, alt)| self.visit(alt, &format!("{name}-{i}")))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(format!("( {} )", alternatives.join(" | ")));
+            }
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(format!("{} space", json_literal(value)?));
+        }
+        if let Some(Value::Array(values)) = schema.get("enum") {
+            let values = values
+                .iter()
+                .map(|value| Ok(format!("{} space", json_literal(value)?)))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(format!("( {} )", values.join(" | ")));
+        }
+        match schema.get("type") {
+            Some(Value::Array(types)) => {
+                let alternatives = types
+                    .iter()
+                    .map(|ty| {
+                        let mut schema = schema.clone();
+                        schema["type"] = ty.clone();
+                        self.visit(&schema, name)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("( {} )", alternatives.join(" | ")))
+            }
+            Some(Value::String(ty)) => match ty.as_str() {
+                "object" => self.visit_object(schema, name),
+                "array" => self.visit_array(schema, name),
+                "string" | "number" | "integer" | "boolean" | "null" => Ok(ty.clone()),
+                _ => Err(anyhow!("unsupported JSON Schema type {ty:?}")),
+            },
+            _ => Ok("value".to_owned()),
+        }
+    }
+
+    /// Convert a `$ref` to a rule name.
+    fn visit_ref(&mut self, reference: &str) -> Result<String> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+        let root = self.root;
+        let target = reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+            .ok_or_else(|| anyhow!("cannot resolve JSON Schema $ref {reference:?}"))?;
+        // Reserve our name before visiting, in case the schema is recursive.
+        let base = reference.rsplit('/').next().unwrap_or("ref");
+        let name = self.add_rule(base, "");
+        self.refs.insert(reference.to_owned(), name.clone());
+        let expr = self.visit(target, &name)?;
+        self.rules.insert(name.clone(), expr);
+        Ok(name)
+    }
+
+    /// Convert an object schema.
+    fn visit_object(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let Some(Value::Object(properties)) = schema.get("properties") else {
+            return Ok("object".to_owned());
+        };
+        let mut fields = vec![];
+        for (key, property) in properties {
+            let value = self.visit(property, &format!("{name}-{key}"))?;
+            fields.push(format!(
+                "{} space \":\" space {value}",
+                json_literal(&Value::String(key.clone()))?
+            ));
+        }
+        Ok(format!(
+            "\"{{\" space {} \"}}\" space",
+            fields.join(" \",\" space ")
+        ))
+    }
+
+    /// Convert an array schema.
+    fn visit_array(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let Some(items) = schema.get("items") else {
+            return Ok("array".to_owned());
+        };
+        let item_name = format!("{name}-item");
+        let item = self.visit(items, &item_name)?;
+        let item = self.add_rule(&item_name, &item);
+        Ok(format!(
+            "\"[\" space ( {item} (\",\" space {item})* )? \"]\" space"
+        ))
+    }
+
+    /// Add a rule for `expr`, with a name based on `name`, and return the
+    /// actual name. If `expr` is already a rule name, just return it.
+    fn add_rule(&mut self, name: &str, expr: &str) -> String {
+        if self.rules.contains_key(expr) {
+            return expr.to_owned();
+        }
+        let base = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect::<String>();
+        let mut name = base.clone();
+        let mut i = 1;
+        while name == "root" || self.rules.contains_key(&name) {
+            name = format!("{base}-{i}");
+            i += 1;
+        }
+        self.rules.insert(name.clone(), expr.to_owned());
+        name
+    }
+}
+
+/// Convert a JSON value to a GBNF string literal matching its JSON encoding.
+fn json_literal(value: &Value) -> Result<String> {
+    let json = serde_json::to_string(value)?;
+    let mut literal = String::with_capacity(json.len() + 2);
+    literal.push('"');
+    for c in json.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            _ => literal.push(c),
+        }
+    }
+    literal.push('"');
+    Ok(literal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schemas_are_converted_to_gbnf() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "answer": { "type": "string", "enum": ["yes", "no"] },
+                "items": { "type": "array", "items": { "$ref": "#/definitions/Item" } },
+            },
+            "definitions": {
+                "Item": {
+                    "type": "object",
+                    "properties": { "score": { "type": ["number", "null"] } },
+                },
+            },
+        });
+        let grammar = json_schema_to_gbnf(&schema).unwrap();
+        let lines = grammar.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            concat!(
+                r#"root ::= "{" space "\"answer\"" space ":" space "#,
+                r#"( "\"yes\"" space | "\"no\"" space ) "," space "#,
+                r#""\"items\"" space ":" space "#,
+                r#""[" space ( Item ("," space Item)* )? "]" space "}" space"#,
+            )
+        );
+        assert!(lines.contains(
+            &r#"Item ::= "{" space "\"score\"" space ":" space ( number | null ) "}" space"#
+        ));
+        assert!(lines.contains(&"string ::= \"\\\"\" char* \"\\\"\" space"));
+
+        assert!(json_schema_to_gbnf(&json!({ "$ref": "#/missing" })).is_err());
+    }
+}
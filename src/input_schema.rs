@@ -0,0 +1,219 @@
+//! Declared input bindings for a prompt.
+//!
+//! A misspelled CSV header or a missing column normally shows up as a confusing
+//! Handlebars error partway through a long run. Prompts may list the bindings
+//! they expect in an `[input_schema]` table, and we check the first few input
+//! records against it before making any LLM requests.
+
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use toml_span::{
+    DeserError,
+    de_helpers::{TableHelper, expected},
+    value::ValueInner,
+};
+
+use crate::{async_utils::io::JsonObject, prelude::*, toml_utils::custom_deser_error};
+
+/// The expected type of an input binding.
+///
+/// CSV input only contains strings, so strings which parse as the expected
+/// type are also accepted.
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InputType {
+    /// A string.
+    #[default]
+    String,
+
+    /// A number.
+    Number,
+
+    /// An integer.
+    Integer,
+
+    /// A boolean.
+    Boolean,
+
+    /// An array. Only available from JSON or YAML input.
+    Array,
+
+    /// An object. Only available from JSON or YAML input.
+    Object,
+}
+
+impl InputType {
+    /// Does `value` have this type?
+    fn matches(self, value: &Value) -> bool {
+        match (self, value) {
+            (InputType::String, Value::String(_)) => true,
+            (InputType::Number, Value::Number(_)) => true,
+            (InputType::Number, Value::String(s)) => s.trim().parse::<f64>().is_ok(),
+            (InputType::Integer, Value::Number(n)) => n.is_i64() || n.is_u64(),
+            (InputType::Integer, Value::String(s)) => s.trim().parse::<i64>().is_ok(),
+            (InputType::Boolean, Value::Bool(_)) => true,
+            (InputType::Boolean, Value::String(s)) => {
+                matches!(s.trim(), "true" | "false")
+            }
+            (InputType::Array, Value::Array(_)) => true,
+            (InputType::Object, Value::Object(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// A human-readable name for this type, for error messages.
+    fn description(self) -> &'static str {
+        match self {
+            InputType::String => "a string",
+            InputType::Number => "a number",
+            InputType::Integer => "an integer",
+            InputType::Boolean => "a boolean",
+            InputType::Array => "an array",
+            InputType::Object => "an object",
+        }
+    }
+}
+
+impl<'de> toml_span::Deserialize<'de> for InputType {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        match value.take_string(None)?.as_ref() {
+            "string" => Ok(InputType::String),
+            "number" => Ok(InputType::Number),
+            "integer" => Ok(InputType::Integer),
+            "boolean" => Ok(InputType::Boolean),
+            "array" => Ok(InputType::Array),
+            "object" => Ok(InputType::Object),
+            other => Err(custom_deser_error(
+                value.span,
+                format!("Unsupported input type: {other}"),
+            )),
+        }
+    }
+}
+
+/// A single declared input binding.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct InputField {
+    /// A description of this binding, for people reading the prompt.
+    #[serde(default)]
+    pub description: Option<String>,
+
+    /// The expected type. Defaults to `string`.
+    #[serde(default, rename = "type")]
+    pub r#type: InputType,
+
+    /// Must this binding be present and non-null? Defaults to `true`.
+    #[serde(default = "default_required")]
+    pub required: bool,
+}
+
+/// Input bindings are required unless stated otherwise.
+fn default_required() -> bool {
+    true
+}
+
+impl<'de> toml_span::Deserialize<'de> for InputField {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        let mut th = TableHelper::new(value)?;
+        let description = th.optional("description");
+        let r#type = th.optional("type").unwrap_or_default();
+        let required = th.optional("required").unwrap_or_else(default_required);
+        th.finalize(None)?;
+        Ok(Self {
+            description,
+            r#type,
+            required,
+        })
+    }
+}
+
+/// The input bindings expected by a prompt, keyed by name.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct InputSchema {
+    /// Our declared bindings.
+    fields: BTreeMap<String, InputField>,
+}
+
+impl InputSchema {
+    /// Check a record's `bindings`, plus any prompt `constants`, returning a
+    /// description of each missing or mistyped binding.
+    pub fn check(&self, bindings: &JsonObject, constants: &JsonObject) -> Vec<String> {
+        let mut problems = vec![];
+        for (name, field) in &self.fields {
+            match bindings.get(name).or_else(|| constants.get(name)) {
+                None | Some(Value::Null) => {
+                    if field.required {
+                        problems.push(format!("missing column {name:?}"));
+                    }
+                }
+                Some(value) if !field.r#type.matches(value) => {
+                    problems.push(format!(
+                        "column {name:?} should be {}, found {value}",
+                        field.r#type.description()
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+        problems
+    }
+}
+
+impl<'de> toml_span::Deserialize<'de> for InputSchema {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        let value_inner = value.take();
+        let ValueInner::Table(table) = value_inner else {
+            return Err(
+                expected("a table of input bindings", value_inner, value.span).into(),
+            );
+        };
+        let mut fields = BTreeMap::new();
+        for (k, mut v) in table {
+            fields.insert(
+                k.name.into_owned(),
+                <InputField as toml_span::Deserialize>::deserialize(&mut v)?,
+            );
+        }
+        Ok(Self { fields })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_schemas_report_missing_and_mistyped_bindings() {
+        let schema = serde_json::from_value::<InputSchema>(json!({
+            "name": {},
+            "amount": { "type": "number" },
+            "notes": { "required": false },
+            "tags": { "type": "array", "required": false },
+        }))
+        .unwrap();
+        let bindings = |value: Value| value.as_object().unwrap().clone();
+        let no_constants = JsonObject::new();
+
+        // CSV-style strings are accepted if they parse as the expected type.
+        let ok = bindings(json!({ "name": "Ann", "amount": "12.5", "extra": 1 }));
+        assert!(schema.check(&ok, &no_constants).is_empty());
+
+        // Constants can supply missing bindings.
+        let constants = bindings(json!({ "name": "Default" }));
+        let ok = bindings(json!({ "amount": 3 }));
+        assert!(schema.check(&ok, &constants).is_empty());
+
+        let bad = bindings(json!({ "amount": "lots", "tags": "a,b" }));
+        assert_eq!(
+            schema.check(&bad, &no_constants),
+            vec![
+                r#"column "amount" should be a number, found "lots""#.to_owned(),
+                r#"missing column "name""#.to_owned(),
+                r#"column "tags" should be an array, found "a,b""#.to_owned(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! Keeping very large input fields out of memory.
+//!
+//! A record with a huge text field is held in memory several times: as the
+//! parsed input record, while it waits in our queues, and again in the
+//! rendered prompt. With `--offload-fields-over BYTES`, we write any larger
+//! top-level string field to a temporary file as soon as the record is read,
+//! and replace the field with the file's path. Prompts include the text using
+//! `{{text-file-contents FIELD}}`, so it's only read back while rendering.
+//! Fields which a prompt uses any other way, such as `{{FIELD}}`, are never
+//! offloaded, because the prompt would see the path instead of the text. Each
+//! file is deleted once its record has been processed.
+//!
+//! Without that option, we warn about fields large enough to cause trouble.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use futures::StreamExt as _;
+use tempfile::{TempDir, TempPath};
+
+use crate::{
+    async_utils::{BoxedStream, io::id_key},
+    prelude::*,
+    queues::{chat::ChatInput, work::WorkInput},
+};
+
+/// Warn about string fields at least this large, unless we're offloading
+/// them.
+const LARGE_FIELD_WARNING_BYTES: usize = 16 * 1024 * 1024;
+
+/// Where to offload large fields.
+#[derive(Clone, Debug)]
+pub struct FieldOffload {
+    /// Offload string fields larger than this many bytes.
+    pub threshold_bytes: u64,
+
+    /// Where to write offloaded fields. This must outlive processing.
+    pub scratch_dir: Arc<TempDir>,
+
+    /// Fields our prompts use directly, which we must not offload.
+    pub fields_used_directly: HashSet<String>,
+
+    /// Fields we've already warned about not offloading.
+    pub warned_fields: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Offload large fields in each input record, if we have `offload`, and warn
+/// about any others which are very large.
+pub fn offload_large_fields(
+    input: BoxedStream<Result<WorkInput<ChatInput>>>,
+    offload: Option<FieldOffload>,
+) -> BoxedStream<Result<WorkInput<ChatInput>>> {
+    input
+        .then(move |record| {
+            let offload = offload.clone();
+            async move {
+                let mut record = record?;
+                match &offload {
+                    Some(offload) => offload.offload_fields(&mut record).await?,
+                    None => warn_about_large_fields(&record),
+                }
+                Ok(record)
+            }
+        })
+        .boxed()
+}
+
+impl FieldOffload {
+    /// Write each large string field in `record` to a file, and replace the
+    /// field with the file's path. The record owns the file, which is deleted
+    /// along with the record.
+    async fn offload_fields(&self, record: &mut WorkInput<ChatInput>) -> Result<()> {
+        for (name, value) in &mut record.data.template_bindings {
+            let Value::String(text) = value else {
+                continue;
+            };
+            if u64::try_from(text.len()).unwrap_or(u64::MAX) <= self.threshold_bytes {
+                continue;
+            }
+            if self.fields_used_directly.contains(name) {
+                let mut warned_fields = self.warned_fields.lock().expect("lock poisoned");
+                if warned_fields.insert(name.clone()) {
+                    warn!(
+                        "Not offloading large field {name:?}, because a prompt uses it \
+                         directly. Use {{{{text-file-contents {name}}}}} instead"
+                    );
+                }
+                continue;
+            }
+            let path = self
+                .scratch_dir
+                .path()
+                .join(format!("{}.txt", uuid::Uuid::new_v4()));
+            let temp_path = Arc::new(TempPath::from_path(&path));
+            record.data.offloaded_files.push(temp_path);
+            tokio::fs::write(&path, text.as_bytes())
+                .await
+                .with_context(|| format!("cannot offload field {name:?} to {path:?}"))?;
+            debug!(
+                id = %id_key(&record.id),
+                field = %name,
+                bytes = text.len(),
+                "Offloaded large field"
+            );
+            *value = Value::String(
+                path.to_str()
+                    .ok_or_else(|| {
+                        anyhow!("temporary path is not valid UTF-8: {path:?}")
+                    })?
+                    .to_owned(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Warn about any very large string fields in `record`.
+fn warn_about_large_fields(record: &WorkInput<ChatInput>) {
+    for (name, value) in &record.data.template_bindings {
+        if let Value::String(text) = value
+            && text.len() >= LARGE_FIELD_WARNING_BYTES
+        {
+            warn!(
+                "Record {} has a {} MB field {name:?}, which will be held in memory \
+                 several times. Consider --offload-fields-over",
+                id_key(&record.id),
+                text.len() / (1024 * 1024),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn large_fields_are_offloaded() {
+        let record = WorkInput::<ChatInput>::from_json(json!({
+            "id": 1,
+            "small": "hello",
+            "large": "x".repeat(100),
+            "direct": "y".repeat(100),
+            "count": 1000,
+        }))
+        .unwrap();
+        let offload = FieldOffload {
+            threshold_bytes: 10,
+            scratch_dir: Arc::new(TempDir::new().unwrap()),
+            fields_used_directly: HashSet::from(["direct".to_owned()]),
+            warned_fields: Arc::default(),
+        };
+        let mut output = offload_large_fields(
+            stream::once(async { Ok(record) }).boxed(),
+            Some(offload.clone()),
+        );
+        let record = output.next().await.unwrap().unwrap();
+        let bindings = &record.data.template_bindings;
+        assert_eq!(bindings["small"], "hello");
+        assert_eq!(bindings["count"], 1000);
+        assert_eq!(bindings["direct"], "y".repeat(100));
+        let path = bindings["large"].as_str().unwrap().to_owned();
+        assert!(Path::new(&path).starts_with(offload.scratch_dir.path()));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "x".repeat(100));
+
+        // Once we're done with the record, its file goes away.
+        drop(record);
+        assert!(!Path::new(&path).exists());
+    }
+}
@@ -0,0 +1,102 @@
+//! Per-model LLM request latency, for comparing providers and gateways.
+//!
+//! Every driver records how long each successful request takes, end to end.
+//! The `openai` driver also records the time to first byte, when the response
+//! headers arrive. [`reqwest`] doesn't expose DNS or connection timings, so
+//! those are included in the time to first byte.
+
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+/// Latencies recorded for a single model, in milliseconds.
+#[derive(Debug, Default)]
+struct ModelLatencies {
+    /// Time to first byte of each response.
+    ttfb_ms: Vec<u32>,
+
+    /// Time to receive each complete response.
+    total_ms: Vec<u32>,
+}
+
+/// Latencies recorded so far, by model.
+static LATENCIES: Mutex<BTreeMap<String, ModelLatencies>> = Mutex::new(BTreeMap::new());
+
+/// Convert a duration to whole milliseconds.
+fn to_ms(duration: Duration) -> u32 {
+    u32::try_from(duration.as_millis()).unwrap_or(u32::MAX)
+}
+
+/// Record the time until the response headers for a request to `model`
+/// arrived.
+pub fn record_ttfb(model: &str, ttfb: Duration) {
+    let mut latencies = LATENCIES.lock().expect("latency lock poisoned");
+    let entry = latencies.entry(model.to_owned()).or_default();
+    entry.ttfb_ms.push(to_ms(ttfb));
+}
+
+/// Record the time taken to get a complete response from `model`.
+pub fn record_total(model: &str, total: Duration) {
+    let mut latencies = LATENCIES.lock().expect("latency lock poisoned");
+    let entry = latencies.entry(model.to_owned()).or_default();
+    entry.total_ms.push(to_ms(total));
+}
+
+/// Summarize the latencies recorded so far, with one line per model. Returns
+/// an empty list if no requests have been recorded.
+pub fn latency_report() -> Vec<String> {
+    let mut latencies = LATENCIES.lock().expect("latency lock poisoned");
+    latencies
+        .iter_mut()
+        .filter(|(_, latencies)| !latencies.total_ms.is_empty())
+        .map(|(model, latencies)| {
+            let mut line = format!(
+                "{model}: {} requests, total {}",
+                latencies.total_ms.len(),
+                percentiles(&mut latencies.total_ms)
+            );
+            if !latencies.ttfb_ms.is_empty() {
+                line.push_str(&format!(", TTFB {}", percentiles(&mut latencies.ttfb_ms)));
+            }
+            line
+        })
+        .collect()
+}
+
+/// Format the p50, p95 and p99 of `values`, sorting them in place.
+fn percentiles(values: &mut [u32]) -> String {
+    values.sort_unstable();
+    let percentile = |p: usize| {
+        // Nearest-rank percentile.
+        let rank = (values.len() * p).div_ceil(100).max(1);
+        format_ms(values[rank - 1])
+    };
+    format!(
+        "p50 {} / p95 {} / p99 {}",
+        percentile(50),
+        percentile(95),
+        percentile(99)
+    )
+}
+
+/// Format milliseconds for humans.
+fn format_ms(ms: u32) -> String {
+    if ms < 1_000 {
+        format!("{ms}ms")
+    } else {
+        format!("{:.2}s", f64::from(ms) / 1_000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let mut values = (1..=100).rev().collect::<Vec<u32>>();
+        assert_eq!(percentiles(&mut values), "p50 50ms / p95 95ms / p99 99ms");
+        assert_eq!(
+            percentiles(&mut [1_500]),
+            "p50 1.50s / p95 1.50s / p99 1.50s"
+        );
+    }
+}
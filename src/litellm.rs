@@ -10,7 +10,10 @@ use async_openai::config::Config as _;
 use serde_json::Map;
 use tokio::sync::OnceCell;
 
-use crate::{drivers::openai::get_openai_client_config, prelude::*};
+use crate::{
+    drivers::openai::{gateway_api_key, gateway_http_client, get_openai_client_config},
+    prelude::*,
+};
 
 /// Information about a LiteLLM model.
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -60,6 +63,16 @@ pub struct LiteLlmModelInfo {
     #[serde(default)]
     pub output_cost_per_token: f64,
 
+    /// Cost per second of audio input, for transcription models.
+    #[serde(default)]
+    pub input_cost_per_second: Option<f64>,
+
+    /// The maximum number of input tokens this model accepts, if known.
+    pub max_input_tokens: Option<u64>,
+
+    /// The maximum number of tokens this model generates, if known.
+    pub max_output_tokens: Option<u64>,
+
     /// The provider. Useful for ironing out minor parameter differences.
     pub litellm_provider: String,
 
@@ -132,26 +145,78 @@ pub struct LiteLlmResponse<T> {
     pub data: T,
 }
 
-/// Get data for our LiteLLM model cache.
-///
-/// This will fail for non-LiteLLM endpoints, which is normal. In this case,
-/// we fill the cache with an empty map.
-#[instrument(level = "debug", skip_all)]
-async fn build_model_cache() -> Result<BTreeMap<String, LiteLlmModel>> {
-    // TODO: Consider preferring `LITELLM_*` versions of the creds.
-    let client_config = get_openai_client_config();
-    let client = reqwest::Client::new();
+/// A LiteLLM spend log entry, describing a single request.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiteLlmSpendLog {
+    /// The cost of the request, in US dollars.
+    #[serde(default)]
+    pub spend: f64,
+
+    /// When the request started, as an ISO 8601 timestamp in the proxy's
+    /// time zone (normally UTC).
+    #[serde(rename = "startTime")]
+    pub start_time: String,
+
+    /// The model used by the provider.
+    #[serde(default)]
+    pub model: String,
+
+    /// The model name we asked for, if known.
+    #[serde(default)]
+    pub model_group: Option<String>,
+
+    /// The request's tags, normally a list of strings. Some LiteLLM versions
+    /// send this as a JSON-encoded string instead.
+    #[serde(default)]
+    pub request_tags: Value,
+}
+
+impl LiteLlmSpendLog {
+    /// Was this request tagged with `tag`?
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let tags = match &self.request_tags {
+            Value::String(json) => serde_json::from_str(json).unwrap_or_default(),
+            tags => tags.clone(),
+        };
+        tags.as_array()
+            .is_some_and(|tags| tags.iter().any(|t| t.as_str() == Some(tag)))
+    }
+}
 
-    // Build a URL for the LiteLLM-specific endpoint.
+/// A page of LiteLLM spend logs.
+#[derive(Debug, Deserialize)]
+struct LiteLlmSpendLogPage {
+    /// The logs on this page.
+    data: Vec<LiteLlmSpendLog>,
+
+    /// How many pages of logs there are in total.
+    #[serde(default)]
+    total_pages: u32,
+}
+
+/// Build a `GET` request for a LiteLLM-specific endpoint, like `model/info`.
+fn litellm_get(path: &str) -> Result<reqwest::RequestBuilder> {
+    let client_config = get_openai_client_config();
+    let client = gateway_http_client(None)?;
     let mut url = client_config.api_base().to_owned();
     if !url.ends_with('/') {
         url.push('/');
     }
-    url.push_str("model/info");
+    url.push_str(path);
+    let mut request = client.get(&url);
+    if let Some(api_key) = gateway_api_key() {
+        request = request.bearer_auth(api_key);
+    }
+    Ok(request)
+}
 
-    // Get the model information.
-    let response = client
-        .get(&url)
+/// Get data for our LiteLLM model cache.
+///
+/// This will fail for non-LiteLLM endpoints, which is normal. In this case,
+/// we fill the cache with an empty map.
+#[instrument(level = "debug", skip_all)]
+async fn build_model_cache() -> Result<BTreeMap<String, LiteLlmModel>> {
+    let response = litellm_get("model/info")?
         .send()
         .await
         .context("Failed to get model information")?;
@@ -210,3 +275,54 @@ pub async fn litellm_model_info(model_name: &str) -> Option<&'static LiteLlmMode
         .await
         .and_then(|cache| cache.get(model_name))
 }
+
+/// Do we have LiteLLM model information? This is false for endpoints which
+/// aren't LiteLLM.
+pub async fn litellm_model_info_available() -> bool {
+    get_litellm_model_info_cache().await.is_some()
+}
+
+/// How many spend logs to ask LiteLLM for at once. LiteLLM allows at most 100.
+const SPEND_LOG_PAGE_SIZE: u32 = 100;
+
+/// Get LiteLLM's log of each request made between `start` and `end`, formatted
+/// as `YYYY-MM-DD HH:MM:SS` in UTC. We fetch the logs a page
+/// at a time.
+///
+/// This needs a LiteLLM proxy with a database, and an API key which is
+/// allowed to read spend logs.
+#[instrument(level = "debug", skip_all)]
+pub async fn litellm_spend_logs(start: &str, end: &str) -> Result<Vec<LiteLlmSpendLog>> {
+    let mut logs = vec![];
+    let mut page = 1;
+    loop {
+        let response = litellm_get("spend/logs/ui")?
+            .query(&[("start_date", start), ("end_date", end)])
+            .query(&[("page", page), ("page_size", SPEND_LOG_PAGE_SIZE)])
+            .send()
+            .await
+            .context("Failed to get spend logs")?;
+        let status = response.status();
+        if !status.is_success() {
+            return if let Ok(body) = response.json::<LiteLlmErrorResponse>().await {
+                Err(anyhow!(
+                    "Failed to get spend logs (status {}): {}",
+                    status,
+                    body.detail.error
+                ))
+            } else {
+                Err(anyhow!("Failed to get spend logs (status {})", status))
+            };
+        }
+        let page_logs = response
+            .json::<LiteLlmSpendLogPage>()
+            .await
+            .context("Failed to parse spend logs")?;
+        let last_page = page_logs.data.is_empty() || page >= page_logs.total_pages;
+        logs.extend(page_logs.data);
+        if last_page {
+            return Ok(logs);
+        }
+        page += 1;
+    }
+}
@@ -0,0 +1,123 @@
+//! Detect runaway responses which repeat the same text over and over.
+//!
+//! Models sometimes get stuck repeating a sentence until they hit
+//! `--max-completion-tokens`, which is slow and expensive. When we stream a
+//! response, we watch the last few thousand characters, and give up as soon as
+//! they're almost entirely made of one repeated chunk of text.
+
+use crate::num_utils::{f64_to_usize, usize_to_f64};
+
+/// The smallest number of times a chunk must repeat to count as a loop.
+const MIN_REPEATS: usize = 4;
+
+/// The fraction of characters which must match the repeated chunk.
+const MIN_MATCH_RATIO: f64 = 0.98;
+
+/// Watches streamed text for loops.
+#[derive(Debug)]
+pub struct LoopDetector {
+    /// How many characters to look at.
+    window: usize,
+
+    /// The most recent characters we've seen. This may hold up to twice
+    /// `window` characters, so that we don't need to trim it on every push.
+    tail: Vec<char>,
+
+    /// How many characters we've added since we last checked.
+    unchecked: usize,
+}
+
+impl LoopDetector {
+    /// Create a detector which looks at the last `window` characters.
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            tail: Vec::with_capacity(window * 2),
+            unchecked: 0,
+        }
+    }
+
+    /// Add newly streamed text. If the text now ends in a loop, describe it.
+    pub fn push(&mut self, text: &str) -> Option<String> {
+        let before = self.tail.len();
+        self.tail.extend(text.chars());
+        self.unchecked += self.tail.len() - before;
+        if self.tail.len() > self.window * 2 {
+            self.tail.drain(..self.tail.len() - self.window);
+        }
+
+        // Checking is fairly expensive, so only do it every so often.
+        if self.tail.len() < self.window || self.unchecked < (self.window / 8).max(1) {
+            return None;
+        }
+        self.unchecked = 0;
+        let recent = &self.tail[self.tail.len() - self.window..];
+        let period = find_period(recent)?;
+        let chunk = recent[..period].iter().take(80).collect::<String>();
+        Some(format!(
+            "the last {} characters repeat every {period} characters: {chunk:?}",
+            self.window
+        ))
+    }
+}
+
+/// If `text` is almost entirely one chunk repeated at least [`MIN_REPEATS`]
+/// times, return the length of that chunk.
+fn find_period(text: &[char]) -> Option<usize> {
+    for period in 1..=text.len() / MIN_REPEATS {
+        let compared = text.len() - period;
+        // If `compared` is too large to convert, we insist on an exact match.
+        let max_mismatches = usize_to_f64(compared)
+            .and_then(|compared| {
+                f64_to_usize(((1.0 - MIN_MATCH_RATIO) * compared).floor())
+            })
+            .unwrap_or(0);
+        let mut mismatches = 0;
+        for idx in period..text.len() {
+            if text[idx] != text[idx - period] {
+                mismatches += 1;
+                if mismatches > max_mismatches {
+                    break;
+                }
+            }
+        }
+        if mismatches <= max_mismatches {
+            return Some(period);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Push `text` in small pieces, as if it were streamed.
+    fn detect(window: usize, text: &str) -> Option<String> {
+        let mut detector = LoopDetector::new(window);
+        let chars = text.chars().collect::<Vec<_>>();
+        chars
+            .chunks(7)
+            .find_map(|piece| detector.push(&piece.iter().collect::<String>()))
+    }
+
+    #[test]
+    fn detects_repeated_sentences() {
+        let text = format!(
+            "{{\"summary\": \"The parties agree. {}",
+            "The tenant shall pay rent monthly. ".repeat(100)
+        );
+        let found = detect(1000, &text).expect("should detect loop");
+        assert!(found.contains("repeat every 35 characters"), "{found}");
+        assert!(detect(1000, &" ".repeat(2000)).is_some());
+    }
+
+    #[test]
+    fn ignores_ordinary_text() {
+        let text = (0..400)
+            .map(|n| format!("Clause {n} covers item {}. ", n * 7 % 13))
+            .collect::<String>();
+        assert!(detect(1000, &text).is_none());
+        assert!(detect(1000, "short").is_none());
+    }
+}
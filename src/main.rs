@@ -9,7 +9,14 @@
 // in a separate crate).
 #![forbid(unsafe_code)]
 
-use std::{process::exit, str::FromStr};
+use std::{
+    env,
+    io::{self, IsTerminal as _},
+    iter, path,
+    process::exit,
+    str::FromStr,
+    time::Duration,
+};
 
 use clap::{Parser, Subcommand};
 use tracing_subscriber::{
@@ -19,24 +26,55 @@ use tracing_subscriber::{
 
 use crate::async_utils::io::DiagnosticsError;
 
-use self::{prelude::*, ui::Ui};
+use self::{
+    prelude::*,
+    run_dir::{RecordedCommand, prepare_run_dir},
+    ui::{ProgressMode, Ui},
+};
 
+mod assertions;
 mod async_utils;
 mod aws;
 mod cmd;
 mod cpu_limit;
 mod data_url;
+mod document;
 mod drivers;
+mod email;
+mod gbnf;
+mod input_schema;
+mod large_fields;
+mod latency;
 mod litellm;
+mod loop_detector;
+mod manifest;
+mod mcp;
+mod model_weights;
+mod num_utils;
 mod page_iter;
+mod page_spool;
+mod payload_limits;
+mod postgres;
 mod prelude;
+mod process_limits;
 mod prompt;
+mod prompt_image;
+mod prompt_router;
 mod queues;
 mod rate_limit;
+mod result_store;
 mod retry;
+mod run_dir;
+mod s3;
 mod schema;
+mod searchable_pdf;
+mod spend_ledger;
+mod sqs;
+mod streaming;
+mod tokens;
 mod toml_utils;
 mod ui;
+mod webhook;
 
 /// Run LLM prompts at scale.
 #[derive(Debug, Parser)]
@@ -47,6 +85,8 @@ mod ui;
 Environment Variables:
   - OPENAI_API_BASE (optional): Override the server URL.
   - OPENAI_API_KEY: The OpenAI key to use.
+  - LITELLM_API_BASE, LITELLM_API_KEY (optional): Used instead of the
+    OPENAI_* variables when set.
 
   Standard AWS environment variables and credential files
   are used for AWS-based tools like Textract.
@@ -55,6 +95,46 @@ Environment Variables:
 "#
 )]
 struct Opts {
+    /// Max number of CPU-heavy local processes (such as `pdftocairo`,
+    /// `tesseract` and `ffmpeg`) to run at once. This is separate from
+    /// `--jobs`, which limits API requests. Defaults to the number of CPUs.
+    #[clap(long, global = true)]
+    cpu_jobs: Option<usize>,
+
+    /// Limit the virtual memory of each local process (such as `pdftocairo`)
+    /// to this many megabytes. Runaway processes fail their record instead of
+    /// running the machine out of memory.
+    #[clap(long, value_name = "MB", global = true)]
+    process_max_memory_mb: Option<u64>,
+
+    /// Limit the CPU time of each local process to this many seconds.
+    #[clap(long, value_name = "SECS", global = true)]
+    process_max_cpu_secs: Option<u64>,
+
+    /// Kill any local process which runs for longer than this many seconds.
+    #[clap(long, value_name = "SECS", global = true)]
+    process_timeout: Option<u64>,
+
+    /// How to show progress. `auto` draws progress bars when stderr is a
+    /// terminal, and otherwise logs a progress line every
+    /// `--progress-interval` seconds.
+    #[clap(long, value_enum, default_value_t = ProgressMode::default(), global = true)]
+    progress: ProgressMode,
+
+    /// How often to log progress with `--progress log`, in seconds.
+    #[clap(long, value_name = "SECS", default_value = "30", global = true)]
+    progress_interval: u64,
+
+    /// Send an extra HTTP header to OpenAI-compatible gateways like LiteLLM,
+    /// as `--header 'x-litellm-api-key: sk-...'`. May be repeated.
+    #[clap(
+        long = "header",
+        value_name = "NAME: VALUE",
+        global = true,
+        value_parser = drivers::openai::GatewayHeader::parse
+    )]
+    headers: Vec<drivers::openai::GatewayHeader>,
+
     #[clap(subcommand)]
     subcmd: Cmd,
 }
@@ -64,10 +144,24 @@ struct Opts {
 enum Cmd {
     /// Prompt using the "/chat/completions" endpoint.
     Chat(cmd::chat::ChatOpts),
+    /// Check external tools, credentials and model access before a long run.
+    Doctor(cmd::doctor::DoctorOpts),
     /// OCR images and PDFs. The input file should have `id` and `path` fields.
     Ocr(cmd::ocr::OcrOpts),
+    /// Run SQL over DuckDB output from previous runs.
+    Query(cmd::query::QueryOpts),
+    /// Continue a `chat`, `ocr` or `transcribe` run started with `--run-dir`,
+    /// skipping records which have already been written.
+    Resume(cmd::resume::ResumeOpts),
     /// Print schemas for input and output formats.
     Schema(cmd::schema::SchemaOpts),
+    /// Print spend totals from a `--spend-ledger` file.
+    Spend(cmd::spend::SpendOpts),
+    /// Count prompt tokens for sample input records, without calling a model.
+    Tokens(cmd::tokens::TokensOpts),
+    /// Transcribe audio files. The input file should have `id` and `path`
+    /// fields.
+    Transcribe(cmd::transcribe::TranscribeOpts),
 }
 
 impl Cmd {
@@ -75,10 +169,53 @@ impl Cmd {
     fn using_stdout_for_output(&self) -> bool {
         match self {
             Cmd::Chat(opts) => opts.output_path.is_none(),
+            Cmd::Doctor(opts) => opts.output_path.is_none(),
             Cmd::Ocr(opts) => opts.output_path.is_none(),
+            Cmd::Query(opts) => opts.output_path.is_none(),
+            Cmd::Resume(_) => false,
             Cmd::Schema(opts) => opts.output_path.is_none(),
+            Cmd::Spend(opts) => opts.output_path.is_none(),
+            Cmd::Tokens(opts) => opts.output_path.is_none(),
+            Cmd::Transcribe(opts) => opts.output_path.is_none(),
         }
     }
+
+    /// Get our stream options and output path, if we support `--run-dir`.
+    fn run_dir_opts_mut(
+        &mut self,
+    ) -> Option<(&mut cmd::StreamOpts, &mut Option<PathBuf>)> {
+        match self {
+            Cmd::Chat(opts) => Some((&mut opts.stream_opts, &mut opts.output_path)),
+            Cmd::Ocr(opts) => Some((&mut opts.stream_opts, &mut opts.output_path)),
+            Cmd::Transcribe(opts) => Some((&mut opts.stream_opts, &mut opts.output_path)),
+            Cmd::Doctor(_)
+            | Cmd::Query(_)
+            | Cmd::Resume(_)
+            | Cmd::Schema(_)
+            | Cmd::Spend(_)
+            | Cmd::Tokens(_) => None,
+        }
+    }
+}
+
+/// Reconstruct the options of the run in `run_dir`, so that we can resume it.
+/// We also switch to the run's original working directory, so that relative
+/// paths mean the same thing as before.
+async fn resumed_opts(run_dir: &Path) -> Result<Opts> {
+    let run_dir = path::absolute(run_dir)
+        .with_context(|| format!("cannot find run directory {run_dir:?}"))?;
+    let command = RecordedCommand::load(&run_dir).await?;
+    env::set_current_dir(&command.cwd)
+        .with_context(|| format!("cannot change directory to {:?}", command.cwd))?;
+    let mut opts =
+        Opts::try_parse_from(iter::once("prompt-scaler".to_owned()).chain(command.args))
+            .with_context(|| format!("cannot parse command line from {run_dir:?}"))?;
+    let Some((stream_opts, _)) = opts.subcmd.run_dir_opts_mut() else {
+        return Err(anyhow!("cannot resume the command in {run_dir:?}"));
+    };
+    stream_opts.run_dir = Some(run_dir);
+    stream_opts.resume = true;
+    Ok(opts)
 }
 
 /// Our entry point, which can return an error. [`anyhow::Result`] will
@@ -120,26 +257,80 @@ async fn real_main(ui: &Ui) -> Result<()> {
     // Load environment variables from a `.env` file, if it exists.
     dotenvy::dotenv().ok();
 
-    // Parse command-line arguments.
-    let opts = Opts::parse();
+    // Parse command-line arguments, replacing them with the original run's
+    // arguments if we're resuming.
+    let mut opts = Opts::parse();
+    if let Cmd::Resume(resume_opts) = &opts.subcmd {
+        opts = resumed_opts(&resume_opts.run_dir).await?;
+    }
     debug!("Parsed options: {:?}", opts);
 
+    // Set up our run directory, which may change our output paths, and make
+    // sure we won't accidentally replace earlier output.
+    if let Some((stream_opts, output_path)) = opts.subcmd.run_dir_opts_mut() {
+        prepare_run_dir(stream_opts, output_path).await?;
+        stream_opts
+            .check_output_mode(output_path.as_deref())
+            .await?;
+    }
+
+    // Configure our CPU and process limits before anything uses them.
+    if let Some(cpu_jobs) = opts.cpu_jobs {
+        cpu_limit::set_cpu_job_count(cpu_jobs)?;
+    }
+    process_limits::set_process_limits(process_limits::ProcessLimits {
+        max_memory_mb: opts.process_max_memory_mb,
+        max_cpu_secs: opts.process_max_cpu_secs,
+        timeout_secs: opts.process_timeout,
+    })?;
+
+    // Configure extra gateway headers before we create any clients.
+    drivers::openai::set_gateway_headers(&opts.headers)?;
+
     // Hide the progress bar if we're using stdout for output.
     if opts.subcmd.using_stdout_for_output() {
         ui.hide_progress_bars();
     }
 
+    // Log progress instead of drawing progress bars if nobody can see them.
+    let log_progress = match opts.progress {
+        ProgressMode::Auto => !io::stderr().is_terminal(),
+        ProgressMode::Bar => false,
+        ProgressMode::Log => true,
+    };
+    if log_progress {
+        ui.log_progress(Duration::from_secs(opts.progress_interval.max(1)));
+    }
+
     // Run the appropriate subcommand.
     match &opts.subcmd {
         Cmd::Chat(opts) => {
             cmd::chat::cmd_chat(ui, opts).await?;
         }
+        Cmd::Doctor(opts) => {
+            cmd::doctor::cmd_doctor(ui, opts).await?;
+        }
         Cmd::Ocr(opts) => {
             cmd::ocr::cmd_ocr(ui, opts).await?;
         }
+        Cmd::Query(query_opts) => {
+            cmd::query::cmd_query(query_opts).await?;
+        }
+        Cmd::Resume(_) => {
+            return Err(anyhow!("a run directory cannot contain a `resume` command"));
+        }
         Cmd::Schema(schema_opts) => {
             cmd::schema::cmd_schema(schema_opts).await?;
         }
+        Cmd::Spend(spend_opts) => {
+            cmd::spend::cmd_spend(spend_opts).await?;
+        }
+        Cmd::Tokens(tokens_opts) => {
+            cmd::tokens::cmd_tokens(ui, tokens_opts).await?;
+        }
+        Cmd::Transcribe(opts) => {
+            cmd::transcribe::cmd_transcribe(ui, opts).await?;
+        }
     }
     Ok(())
 }
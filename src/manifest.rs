@@ -0,0 +1,69 @@
+//! Run manifests.
+//!
+//! A manifest records how a run was configured, so that its output can be
+//! traced back to a model and attributed to a client later. We deliberately
+//! don't record the full command line, which may contain secrets.
+
+use std::collections::BTreeMap;
+
+use crate::{cmd::StreamOpts, drivers::LlmOpts, prelude::*};
+
+/// A description of a run, written with `--manifest`.
+#[derive(Debug, Serialize)]
+pub struct RunManifest {
+    /// The version of `prompt-scaler` which performed the run.
+    pub version: &'static str,
+
+    /// The subcommand, such as `chat` or `ocr`.
+    pub command: &'static str,
+
+    /// The model used.
+    pub model: String,
+
+    /// Cost attribution tags sent with each LLM request.
+    pub tags: BTreeMap<String, String>,
+
+    /// How record IDs were generated, if they didn't come from the input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_from: Option<String>,
+}
+
+impl RunManifest {
+    /// Create a manifest for `command`, using `model`.
+    pub fn new(command: &'static str, model: &str) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            command,
+            model: model.to_owned(),
+            tags: BTreeMap::new(),
+            id_from: None,
+        }
+    }
+
+    /// Record any relevant stream options.
+    pub fn with_stream_opts(mut self, stream_opts: &StreamOpts) -> Self {
+        self.id_from = stream_opts
+            .id_from
+            .as_ref()
+            .map(|id_from| id_from.to_string());
+        self
+    }
+
+    /// Record the tags from our LLM options.
+    pub fn with_llm_opts(mut self, llm_opts: &LlmOpts) -> Self {
+        self.tags = llm_opts.tag_map();
+        self
+    }
+
+    /// Write this manifest to `path` as JSON, if we have a path.
+    pub async fn write(&self, path: Option<&Path>) -> Result<()> {
+        let Some(path) = path else {
+            return Ok(());
+        };
+        let json =
+            serde_json::to_vec_pretty(self).context("cannot serialize manifest")?;
+        tokio::fs::write(path, json)
+            .await
+            .with_context(|| format!("cannot write manifest {:?}", path.display()))
+    }
+}
@@ -0,0 +1,503 @@
+//! A minimal [Model Context Protocol][mcp] client, so that prompts can offer
+//! the model tools backed by MCP servers.
+//!
+//! We only support servers which we launch ourselves and talk to over stdio,
+//! using newline-delimited JSON-RPC. Each server is started once per run and
+//! shared by every request, so responses are matched to requests by ID.
+//!
+//! [mcp]: https://modelcontextprotocol.io/
+
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use schemars::JsonSchema;
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
+    process::{Child, ChildStdin, Command},
+    sync::{Mutex as AsyncMutex, oneshot},
+    time,
+};
+use toml_span::{DeserError, de_helpers::TableHelper};
+
+use crate::{
+    prelude::*,
+    toml_utils::{JsonValue, custom_deser_error},
+};
+
+/// The version of MCP we speak.
+const PROTOCOL_VERSION: &str = "2025-06-18";
+
+/// How long to wait for an MCP server to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How many rounds of tool calls a model may make by default, before we insist
+/// on an answer.
+pub const DEFAULT_MAX_TOOL_ROUNDS: usize = 5;
+
+/// An MCP server to launch, from a prompt's `[[mcp_server]]` table.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct McpServerConfig {
+    /// A name for this server, used in logs and error messages.
+    pub name: String,
+
+    /// The command which runs the server.
+    pub command: String,
+
+    /// Arguments to `command`.
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    /// Extra environment variables for the server.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+
+    /// The tools we offer the model. By default, we offer all of them.
+    #[serde(default)]
+    pub tools: Option<Vec<String>>,
+}
+
+impl<'de> toml_span::Deserialize<'de> for McpServerConfig {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        let mut th = TableHelper::new(value)?;
+        let name = th.required("name")?;
+        let command = th.required("command")?;
+        let args = th.optional("args").unwrap_or_default();
+        let env = match th.take("env") {
+            None => HashMap::new(),
+            Some((_, mut env)) => {
+                let span = env.span;
+                let env = <JsonValue as toml_span::Deserialize>::deserialize(&mut env)?
+                    .into_json();
+                serde_json::from_value(env).map_err(|_| {
+                    custom_deser_error(span, "env must be a table of strings")
+                })?
+            }
+        };
+        let tools = th.optional("tools");
+        th.finalize(None)?;
+        Ok(Self {
+            name,
+            command,
+            args,
+            env,
+            tools,
+        })
+    }
+}
+
+/// A tool offered by an MCP server.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct McpTool {
+    /// The tool's name.
+    name: String,
+
+    /// What the tool does, for the model.
+    #[serde(default)]
+    description: Option<String>,
+
+    /// A JSON Schema for the tool's arguments.
+    input_schema: Value,
+}
+
+/// Requests we're waiting for a server to answer, by ID.
+type PendingRequests = Arc<Mutex<HashMap<u64, oneshot::Sender<Result<Value, Value>>>>>;
+
+/// A running MCP server.
+#[derive(Debug)]
+struct McpServer {
+    /// The server's name, from our config.
+    name: String,
+
+    /// The server's standard input, where we send requests.
+    stdin: AsyncMutex<ChildStdin>,
+
+    /// Requests waiting for an answer.
+    pending: PendingRequests,
+
+    /// The ID of our next request.
+    next_id: AtomicU64,
+
+    /// The server process, which is killed when we're dropped.
+    _child: Child,
+}
+
+impl McpServer {
+    /// Launch a server and introduce ourselves.
+    async fn start(config: &McpServerConfig) -> Result<Self> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Could not start MCP server {:?}", config.name))?;
+        let stdin = child.stdin.take().expect("stdin should be piped");
+        let stdout = child.stdout.take().expect("stdout should be piped");
+        let stderr = child.stderr.take().expect("stderr should be piped");
+
+        // Dispatch responses to whoever is waiting for them. When the server
+        // exits, dropping our pending requests tells everyone waiting.
+        let pending = PendingRequests::default();
+        let name = config.name.clone();
+        let reader_pending = pending.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let Ok(mut message) = serde_json::from_str::<Value>(&line) else {
+                    warn!(server = %name, %line, "Ignoring invalid MCP message");
+                    continue;
+                };
+                let Some(id) = message["id"]
+                    .as_u64()
+                    .filter(|_| message["method"].is_null())
+                else {
+                    // We don't offer the server any features, so we can ignore
+                    // its notifications and requests.
+                    trace!(server = %name, %message, "Ignoring MCP server message");
+                    continue;
+                };
+                let sender = reader_pending.lock().expect("lock poisoned").remove(&id);
+                if let Some(sender) = sender {
+                    let result = match message["error"].take() {
+                        Value::Null => Ok(message["result"].take()),
+                        error => Err(error),
+                    };
+                    let _ = sender.send(result);
+                }
+            }
+            debug!(server = %name, "MCP server closed its output");
+            reader_pending.lock().expect("lock poisoned").clear();
+        });
+
+        // Keep the server's logs, in case something goes wrong.
+        let name = config.name.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                debug!(server = %name, "{line}");
+            }
+        });
+
+        let server = Self {
+            name: config.name.clone(),
+            stdin: AsyncMutex::new(stdin),
+            pending,
+            next_id: AtomicU64::new(0),
+            _child: child,
+        };
+        server
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                }),
+            )
+            .await?;
+        server
+            .send(&json!({
+                "jsonrpc": "2.0",
+                "method": "notifications/initialized",
+            }))
+            .await?;
+        Ok(server)
+    }
+
+    /// List the tools this server offers.
+    async fn list_tools(&self) -> Result<Vec<McpTool>> {
+        let mut tools = vec![];
+        let mut cursor = Value::Null;
+        loop {
+            let params = match cursor {
+                Value::Null => json!({}),
+                cursor => json!({ "cursor": cursor }),
+            };
+            let mut result = self.request("tools/list", params).await?;
+            let page = serde_json::from_value::<Vec<McpTool>>(result["tools"].take())
+                .with_context(|| {
+                    format!("MCP server {:?} returned an invalid tool list", self.name)
+                })?;
+            tools.extend(page);
+            cursor = result["nextCursor"].take();
+            if cursor.is_null() {
+                return Ok(tools);
+            }
+        }
+    }
+
+    /// Call a tool, returning its result as text for the model. If the server
+    /// rejects the call, we tell the model why, so that it can try something
+    /// else.
+    async fn call_tool(&self, name: &str, arguments: Value) -> Result<String> {
+        let params = json!({ "name": name, "arguments": arguments });
+        match self.try_request("tools/call", params).await? {
+            Ok(result) => Ok(tool_result_text(&result)),
+            Err(error) => {
+                debug!(server = %self.name, tool = %name, %error, "Tool call failed");
+                Ok(format!("Error: {}", rpc_error_text(&error)))
+            }
+        }
+    }
+
+    /// Send a request and wait for the result, failing if the server returns
+    /// an error.
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        self.try_request(method, params).await?.map_err(|error| {
+            anyhow!("MCP server {:?} failed {method}: {error}", self.name)
+        })
+    }
+
+    /// Send a request and wait for the result. Errors returned by the server
+    /// are returned as the inner `Err`, so that callers can decide what to do
+    /// with them. The outer `Err` means we couldn't talk to the server.
+    async fn try_request(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<Result<Value, Value>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("lock poisoned")
+            .insert(id, sender);
+        let message = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+        if let Err(err) = self.send(&message).await {
+            self.pending.lock().expect("lock poisoned").remove(&id);
+            return Err(err);
+        }
+        match time::timeout(REQUEST_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) => Err(anyhow!(
+                "MCP server {:?} exited before answering {method}",
+                self.name
+            )),
+            Err(_) => {
+                self.pending.lock().expect("lock poisoned").remove(&id);
+                Err(anyhow!(
+                    "MCP server {:?} did not answer {method} within {} seconds",
+                    self.name,
+                    REQUEST_TIMEOUT.as_secs()
+                ))
+            }
+        }
+    }
+
+    /// Send a message to the server.
+    async fn send(&self, message: &Value) -> Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        let context = || format!("Could not write to MCP server {:?}", self.name);
+        let mut stdin = self.stdin.lock().await;
+        stdin.write_all(&line).await.with_context(context)?;
+        stdin.flush().await.with_context(context)
+    }
+}
+
+/// Convert the result of a tool call to text for the model. Errors reported
+/// by the tool are passed on, so that the model can try something else.
+fn tool_result_text(result: &Value) -> String {
+    let mut parts = vec![];
+    for item in result["content"].as_array().into_iter().flatten() {
+        match item["text"].as_str() {
+            Some(text) if item["type"] == "text" => parts.push(text.to_owned()),
+            _ => parts.push(item.to_string()),
+        }
+    }
+    if parts.is_empty() && !result["structuredContent"].is_null() {
+        parts.push(result["structuredContent"].to_string());
+    }
+    let text = parts.join("\n");
+    if result["isError"] == true {
+        format!("Error: {text}")
+    } else {
+        text
+    }
+}
+
+/// Convert a JSON-RPC error object to text for the model.
+fn rpc_error_text(error: &Value) -> String {
+    match error["message"].as_str() {
+        Some(message) => message.to_owned(),
+        None => error.to_string(),
+    }
+}
+
+/// The tools offered by a prompt's MCP servers.
+#[derive(Debug)]
+pub struct McpTools {
+    /// Our servers.
+    servers: Vec<McpServer>,
+
+    /// Our tools, and the index of the server which offers each one.
+    tools: Vec<(McpTool, usize)>,
+}
+
+impl McpTools {
+    /// Start each server, and find out which tools it offers.
+    pub async fn start(configs: &[McpServerConfig]) -> Result<Self> {
+        let mut servers = vec![];
+        let mut tools = Vec::<(McpTool, usize)>::new();
+        for config in configs {
+            let server = McpServer::start(config).await?;
+            for tool in server.list_tools().await? {
+                let wanted = config
+                    .tools
+                    .as_ref()
+                    .is_none_or(|wanted| wanted.contains(&tool.name));
+                if !wanted {
+                    continue;
+                }
+                if let Some((_, other)) = tools.iter().find(|(t, _)| t.name == tool.name)
+                {
+                    return Err(anyhow!(
+                        "MCP servers {:?} and {:?} both offer a tool named {:?}",
+                        configs[*other].name,
+                        config.name,
+                        tool.name
+                    ));
+                }
+                tools.push((tool, servers.len()));
+            }
+            if let Some(wanted) = &config.tools {
+                for name in wanted {
+                    let offered = tools
+                        .iter()
+                        .any(|(tool, idx)| *idx == servers.len() && &tool.name == name);
+                    if !offered {
+                        return Err(anyhow!(
+                            "MCP server {:?} has no tool named {name:?}",
+                            config.name
+                        ));
+                    }
+                }
+            }
+            servers.push(server);
+        }
+        let names = tools.iter().map(|(tool, _)| &tool.name).collect::<Vec<_>>();
+        debug!(tools = ?names, "Started MCP servers");
+        Ok(Self { servers, tools })
+    }
+
+    /// Our tools, in OpenAI's `tools` format.
+    pub fn openai_tools(&self) -> Vec<Value> {
+        self.tools
+            .iter()
+            .map(|(tool, _)| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name,
+                        "description": tool.description,
+                        "parameters": tool.input_schema,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Call the tool named `name` with `arguments`, which the model gave us as
+    /// a JSON string. Mistakes by the model, and errors returned by the server,
+    /// are returned as text, so that the model can correct them. We only fail
+    /// if we can't talk to the server.
+    pub async fn call(&self, name: &str, arguments: &str) -> Result<String> {
+        let Some((_, server)) = self.tools.iter().find(|(tool, _)| tool.name == name)
+        else {
+            return Ok(format!("Error: there is no tool named {name:?}"));
+        };
+        let arguments = match arguments.trim() {
+            "" => json!({}),
+            arguments => match serde_json::from_str::<Value>(arguments) {
+                Ok(arguments) => arguments,
+                Err(err) => return Ok(format!("Error: arguments are not JSON: {err}")),
+            },
+        };
+        self.servers[*server].call_tool(name, arguments).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_results_are_converted_to_text() {
+        let result = json!({
+            "content": [
+                { "type": "text", "text": "Acme Corp" },
+                { "type": "text", "text": "id: 42" },
+            ],
+        });
+        assert_eq!(tool_result_text(&result), "Acme Corp\nid: 42");
+
+        let result = json!({ "content": [], "structuredContent": { "id": 42 } });
+        assert_eq!(tool_result_text(&result), r#"{"id":42}"#);
+
+        let result = json!({
+            "content": [{ "type": "text", "text": "no such entity" }],
+            "isError": true,
+        });
+        assert_eq!(tool_result_text(&result), "Error: no such entity");
+    }
+
+    #[tokio::test]
+    async fn tools_are_listed_and_called() {
+        // A fake server, which answers our requests in order.
+        let script = r#"
+            read line
+            echo '{"jsonrpc":"2.0","id":0,"result":{"protocolVersion":"2025-06-18","capabilities":{},"serverInfo":{"name":"fake","version":"1"}}}'
+            read line
+            read line
+            echo '{"jsonrpc":"2.0","method":"notifications/message","params":{}}'
+            echo '{"jsonrpc":"2.0","id":1,"result":{"tools":[{"name":"lookup","description":"Look up an entity.","inputSchema":{"type":"object"}},{"name":"delete","inputSchema":{"type":"object"}}]}}'
+            read line
+            echo '{"jsonrpc":"2.0","id":2,"result":{"content":[{"type":"text","text":"Acme Corp"}]}}'
+            read line
+            echo '{"jsonrpc":"2.0","id":3,"error":{"code":-32602,"message":"Unknown entity"}}'
+            read line
+        "#;
+        let config = McpServerConfig {
+            name: "fake".to_owned(),
+            command: "sh".to_owned(),
+            args: vec!["-c".to_owned(), script.to_owned()],
+            env: HashMap::new(),
+            tools: Some(vec!["lookup".to_owned()]),
+        };
+        let tools = McpTools::start(&[config]).await.unwrap();
+        let openai_tools = tools.openai_tools();
+        assert_eq!(openai_tools.len(), 1);
+        assert_eq!(openai_tools[0]["function"]["name"], "lookup");
+        assert_eq!(
+            tools.call("lookup", r#"{"name": "acme"}"#).await.unwrap(),
+            "Acme Corp"
+        );
+        assert_eq!(
+            tools.call("lookup", r#"{"name": "?"}"#).await.unwrap(),
+            "Error: Unknown entity"
+        );
+        assert_eq!(
+            tools.call("delete", "{}").await.unwrap(),
+            "Error: there is no tool named \"delete\""
+        );
+    }
+}
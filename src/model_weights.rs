@@ -0,0 +1,166 @@
+//! Weighted random choice between models, for `--model-weights`.
+//!
+//! This lets a single run send a fraction of its records to a new model (a
+//! "canary"), so that we can compare it against the model we already trust.
+//!
+//! Our choice is a hash of the record ID and `--seed`, so retrying or resuming
+//! a run sends each record to the same model as before.
+
+use std::{fmt, str::FromStr};
+
+use serde_json::Value;
+use sha2::{Digest as _, Sha256};
+
+/// Models to choose between, each with a relative weight.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelWeights {
+    /// Each model and its weight. Weights are positive, but need not add up
+    /// to 1.
+    models: Vec<(String, f64)>,
+}
+
+impl ModelWeights {
+    /// Always use a single model.
+    pub fn single(model: String) -> Self {
+        Self {
+            models: vec![(model, 1.0)],
+        }
+    }
+
+    /// Do we only have one model?
+    pub fn is_single(&self) -> bool {
+        self.models.len() == 1
+    }
+
+    /// The names of our models, in the order they were given.
+    pub fn models(&self) -> impl Iterator<Item = &str> {
+        self.models.iter().map(|(model, _)| model.as_str())
+    }
+
+    /// Choose a model for the record with `id`, returning its index in
+    /// [`Self::models`]. The same `id` and `seed` always choose the same
+    /// model.
+    pub fn choose(&self, id: &Value, seed: Option<i64>) -> usize {
+        let mut hasher = Sha256::new();
+        hasher.update(seed.unwrap_or(0).to_be_bytes());
+        hasher.update(id.to_string());
+        let digest = hasher.finalize();
+        let bits = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+        self.choose_at(f64::from(bits) / (f64::from(u32::MAX) + 1.0))
+    }
+
+    /// Choose the model at `point`, where `point` is in `0.0..1.0`.
+    fn choose_at(&self, point: f64) -> usize {
+        let total = self.models.iter().map(|(_, weight)| weight).sum::<f64>();
+        let mut remaining = point * total;
+        for (idx, (_, weight)) in self.models.iter().enumerate() {
+            if remaining < *weight {
+                return idx;
+            }
+            remaining -= weight;
+        }
+        // Rounding may leave us just past the end.
+        self.models.len() - 1
+    }
+}
+
+impl FromStr for ModelWeights {
+    type Err = String;
+
+    /// Parse a list like `gpt-4o-mini=0.9,gpt-4.1=0.1`.
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let mut models = Vec::<(String, f64)>::new();
+        for entry in arg.split(',') {
+            let Some((model, weight)) = entry.trim().rsplit_once('=') else {
+                return Err(format!("expected MODEL=WEIGHT, got {entry:?}"));
+            };
+            let weight = weight
+                .parse::<f64>()
+                .ok()
+                .filter(|weight| weight.is_finite() && *weight > 0.0)
+                .ok_or_else(|| format!("expected a positive weight for {model:?}"))?;
+            if model.is_empty() {
+                return Err(format!("expected MODEL=WEIGHT, got {entry:?}"));
+            }
+            if models.iter().any(|(m, _)| m == model) {
+                return Err(format!("model {model:?} is listed more than once"));
+            }
+            models.push((model.to_owned(), weight));
+        }
+        Ok(Self { models })
+    }
+}
+
+impl fmt::Display for ModelWeights {
+    /// Display a single model as just its name, and anything else as a list of
+    /// weights, for use in manifests and spend ledgers.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let [(model, _)] = self.models.as_slice() {
+            return write!(f, "{model}");
+        }
+        for (idx, (model, weight)) in self.models.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{model}={weight}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let weights = "gpt-4o-mini=0.9, gpt-4.1=0.1"
+            .parse::<ModelWeights>()
+            .unwrap();
+        assert_eq!(
+            weights.models().collect::<Vec<_>>(),
+            vec!["gpt-4o-mini", "gpt-4.1"]
+        );
+        assert!(!weights.is_single());
+        assert_eq!(weights.to_string(), "gpt-4o-mini=0.9,gpt-4.1=0.1");
+        assert_eq!(
+            ModelWeights::single("gpt-4o".to_owned()).to_string(),
+            "gpt-4o"
+        );
+
+        for bad in ["", "gpt-4o", "=1", "a=0", "a=-1", "a=x", "a=1,a=2"] {
+            assert!(bad.parse::<ModelWeights>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn test_choose_at() {
+        let weights = "a=3,b=1".parse::<ModelWeights>().unwrap();
+        assert_eq!(weights.choose_at(0.0), 0);
+        assert_eq!(weights.choose_at(0.74), 0);
+        assert_eq!(weights.choose_at(0.75), 1);
+        assert_eq!(weights.choose_at(0.999), 1);
+        assert_eq!(
+            ModelWeights::single("a".to_owned()).choose(&json!(1), None),
+            0
+        );
+    }
+
+    #[test]
+    fn test_choose_is_deterministic() {
+        let weights = "a=1,b=1".parse::<ModelWeights>().unwrap();
+        let choices = |seed| {
+            (0..100)
+                .map(|id| weights.choose(&json!(id), seed))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(choices(None), choices(None));
+        assert_eq!(choices(Some(7)), choices(Some(7)));
+        assert_ne!(choices(Some(7)), choices(Some(8)));
+        // Both models get a share of the records.
+        assert!(choices(None).contains(&0));
+        assert!(choices(None).contains(&1));
+    }
+}
@@ -0,0 +1,43 @@
+//! Checked conversions from floating point numbers to integers.
+//!
+//! The standard library has no `TryFrom` for these, and `as` quietly saturates
+//! out-of-range values and turns NaN into zero.
+
+/// Convert `value` to a `u32`, if it's a whole number in range.
+pub fn f64_to_u32(value: f64) -> Option<u32> {
+    if value.fract() == 0.0 && (0.0..=f64::from(u32::MAX)).contains(&value) {
+        // We checked the range above, so this is exact.
+        Some(value as u32)
+    } else {
+        None
+    }
+}
+
+/// Convert `value` to a `usize`, if it's a whole number which fits in a `u32`.
+pub fn f64_to_usize(value: f64) -> Option<usize> {
+    f64_to_u32(value).and_then(|value| usize::try_from(value).ok())
+}
+
+/// Convert a count to an `f64`, if it's small enough to fit in a `u32`.
+pub fn usize_to_f64(value: usize) -> Option<f64> {
+    u32::try_from(value).ok().map(f64::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_only_exact_values() {
+        assert_eq!(f64_to_u32(42.0), Some(42));
+        assert_eq!(f64_to_u32(f64::from(u32::MAX)), Some(u32::MAX));
+        assert_eq!(f64_to_u32(1.5), None);
+        assert_eq!(f64_to_u32(-1.0), None);
+        assert_eq!(f64_to_u32(f64::from(u32::MAX) + 1.0), None);
+        assert_eq!(f64_to_u32(f64::NAN), None);
+        assert_eq!(f64_to_u32(f64::INFINITY), None);
+        assert_eq!(f64_to_usize(3.0), Some(3));
+        assert_eq!(f64_to_usize(2.5), None);
+        assert_eq!(usize_to_f64(7), Some(7.0));
+    }
+}
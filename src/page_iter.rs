@@ -1,6 +1,12 @@
-//! Iterate over "pages" in an image.
-
-use std::{collections::BTreeMap, process::Output, sync::LazyLock, vec};
+//! Iterate over "pages" in an image, PDF or video.
+
+use std::{
+    collections::BTreeMap,
+    error, fmt, iter,
+    process::Output,
+    sync::{Arc, LazyLock, Mutex, mpsc},
+    vec,
+};
 
 use anyhow::anyhow;
 use clap::Args;
@@ -8,8 +14,13 @@ use regex::Regex;
 use tokio::process::Command;
 
 use crate::{
-    async_utils::check_for_command_failure, cpu_limit::with_cpu_semaphore,
-    data_url::data_url, prelude::*,
+    async_utils::check_for_command_failure,
+    cpu_limit::with_cpu_semaphore,
+    data_url::data_url,
+    num_utils::f64_to_usize,
+    page_spool::{PageData, PageSpool, PageSpoolOptions},
+    prelude::*,
+    process_limits::{limited_command, limited_output},
 };
 
 /// Image types supported as-is.
@@ -35,19 +46,23 @@ pub struct Page {
     /// The MIME type of our data. Must be one of [`SUPPORTED_IMAGE_TYPES`] or
     /// (if no rasterization has been requested) `application/pdf`.
     pub mime_type: String,
-    /// The data for our page.
-    pub data: Vec<u8>,
+    /// The data for our page, which may be in memory or on disk.
+    pub data: PageData,
 }
 
 impl Page {
-    /// Convert to a data URL.
-    pub fn to_data_url(&self) -> String {
-        data_url(&self.mime_type, &self.data)
+    /// Convert to a data URL, reading our data from disk if necessary.
+    pub async fn to_data_url(&self) -> Result<String> {
+        match &self.data {
+            PageData::InMemory { data, .. } => Ok(data_url(&self.mime_type, data)),
+            data => Ok(data_url(&self.mime_type, &data.read().await?)),
+        }
     }
 }
 
 /// Options for constructing a [`PageIter`].
 #[derive(Args, Clone, Debug)]
+#[clap(next_help_heading = "Page options")]
 pub struct PageIterOptions {
     /// Should we rasterize any PDFs to images? Required for most models
     /// except Gemini.
@@ -58,10 +73,41 @@ pub struct PageIterOptions {
     #[clap(long, default_value = "300")]
     pub rasterize_dpi: u32,
 
+    /// Rasterize PDFs this many pages at a time, so that early pages can be
+    /// OCRed while later pages are still rendering. Use 0 to render each
+    /// document all at once.
+    #[clap(long, default_value = "16")]
+    pub render_chunk_pages: usize,
+
     /// The maximum number of pages to process. If this is set, we will
     /// stop processing after this many pages and record an error.
     #[clap(long)]
     pub max_pages: Option<usize>,
+
+    /// For video inputs, extract one frame every this many seconds.
+    #[clap(long, default_value = "5")]
+    pub frame_interval: f64,
+
+    /// For video inputs, the maximum number of frames to extract. Frames
+    /// after this point will be skipped with a warning.
+    #[clap(long, default_value = "100")]
+    pub max_frames: usize,
+
+    /// If Poppler can't read a PDF, try repairing it with `qpdf` before
+    /// giving up. Repaired documents are reported in the output warnings.
+    #[clap(long)]
+    pub pdf_repair: bool,
+
+    /// Mark PDFs which can't be opened without a password as `skipped`
+    /// instead of `failed`, so they don't count towards
+    /// `--allowed-failure-rate`. Either way, they have an `encrypted_pdf`
+    /// error.
+    #[clap(long)]
+    pub skip_encrypted_pdfs: bool,
+
+    /// Options controlling how much page data we keep in memory.
+    #[clap(flatten)]
+    pub spool_opts: PageSpoolOptions,
 }
 
 /// An stream over PDF pages as PNG images, using Poppler's `pdftocairo` CLI
@@ -72,27 +118,40 @@ pub struct PageIter {
     /// This is released by [`Drop`].
     #[allow(dead_code)]
     tmpdir: Option<tempfile::TempDir>,
+    /// A repaired copy of our PDF, if we needed one. We keep this until we're
+    /// done, because pages may still be rendering from it in the background.
+    #[allow(dead_code)]
+    repaired_pdf: Option<tempfile::NamedTempFile>,
     /// The MIME type of our outputs.
     mime_type: String,
     /// Iterator over the page files in the temporary directory.
     dir_iter: vec::IntoIter<PathBuf>,
+    /// Chunks of pages which are still being rendered in the background.
+    pending_chunks: Option<mpsc::Receiver<Result<RenderedChunk>>>,
     /// Expected number of pages in the document.
     total_pages: usize,
     /// The maximum number of pages we are allowed to process.
     max_pages: Option<usize>,
-    /// Any warnings that occurred while processing the document.
-    warnings: Vec<String>,
+    /// Any warnings that occurred while processing the document. This may
+    /// grow as background chunks are rendered.
+    warnings: Arc<Mutex<Vec<String>>>,
+    /// Decides which pages to keep in memory.
+    spool: PageSpool,
 }
 
 impl PageIter {
     /// Create a new [`PageIter`] from a path, based on the detected MIME type.
     ///
+    /// Videos are converted to a sequence of JPEG frames, one every
+    /// [`PageIterOptions::frame_interval`] seconds.
+    ///
     /// TODO: Handle animated image types, either by erroring or by splitting
     /// the frames into pages.
     #[instrument(level = "debug", skip_all, fields(path = %path.display()))]
     pub async fn from_path(
         path: &Path,
         options: &PageIterOptions,
+        spool: &PageSpool,
         password: Option<&str>,
     ) -> Result<Self> {
         // Get our MIME type.
@@ -103,19 +162,29 @@ impl PageIter {
             // We have a supported image type. Return a single-item iterator.
             Ok(Self {
                 tmpdir: None,
+                repaired_pdf: None,
                 mime_type,
                 dir_iter: vec![path.to_owned()].into_iter(),
+                pending_chunks: None,
                 total_pages: 1,
                 max_pages: options.max_pages,
-                warnings: vec![],
+                warnings: Arc::new(Mutex::new(vec![])),
+                spool: spool.clone(),
             })
         } else if mime_type == "application/pdf" {
-            // We have a PDF file. If we need to rasterize, do that.
-            if options.rasterize {
-                Self::from_rasterized_pdf(path, options, password).await
-            } else {
-                Self::from_split_pdf(path, options, password).await
+            match Self::from_pdf(path, options, spool, password).await {
+                Ok(page_iter) => Ok(page_iter),
+                // There's no point in repairing a PDF we can't decrypt.
+                Err(err)
+                    if options.pdf_repair
+                        && err.downcast_ref::<EncryptedPdfError>().is_none() =>
+                {
+                    Self::from_repaired_pdf(path, options, spool, password, err).await
+                }
+                Err(err) => Err(err),
             }
+        } else if mime_type.starts_with("video/") {
+            Self::from_video(path, options, spool).await
         } else {
             Err(anyhow!(
                 "unsupported image or PDF MIME type {} for {:?}",
@@ -125,12 +194,52 @@ impl PageIter {
         }
     }
 
+    /// Create a new [`PageIter`] from a PDF file.
+    async fn from_pdf(
+        path: &Path,
+        options: &PageIterOptions,
+        spool: &PageSpool,
+        password: Option<&str>,
+    ) -> Result<Self> {
+        // If we need to rasterize, do that.
+        if options.rasterize {
+            Self::from_rasterized_pdf(path, options, spool, password).await
+        } else {
+            Self::from_split_pdf(path, options, spool, password).await
+        }
+    }
+
+    /// Repair a PDF which failed with `err` using `qpdf`, and try again.
+    #[instrument(level = "debug", skip_all, fields(path = %path.display()))]
+    async fn from_repaired_pdf(
+        path: &Path,
+        options: &PageIterOptions,
+        spool: &PageSpool,
+        password: Option<&str>,
+        err: anyhow::Error,
+    ) -> Result<Self> {
+        warn!("Attempting to repair PDF after error: {err:#}");
+        let (repaired_pdf, repair_warnings) = repair_pdf(path, password)
+            .await
+            .with_context(|| format!("could not repair PDF after error: {err:#}"))?;
+        let mut page_iter = Self::from_pdf(repaired_pdf.path(), options, spool, password)
+            .await
+            .with_context(|| format!("repaired PDF still failed after error: {err:#}"))?;
+        page_iter.add_warnings(
+            iter::once(format!("PDF was repaired with qpdf after error: {err:#}"))
+                .chain(repair_warnings),
+        );
+        page_iter.repaired_pdf = Some(repaired_pdf);
+        Ok(page_iter)
+    }
+
     /// Create a new [`PageIter`] from a PDF file, splitting out each page
     /// as an individual PDF file.
     #[instrument(level = "debug", skip_all, fields(path = %path.display()))]
     async fn from_split_pdf(
         path: &Path,
         options: &PageIterOptions,
+        spool: &PageSpool,
         password: Option<&str>,
     ) -> Result<Self> {
         // For now, if we have a password, we need to rasterize the PDF.
@@ -139,11 +248,11 @@ impl PageIter {
         //
         //     pdftops -upw <password> <file_name>.pdf <new_file_name>.pdf
         if password.is_some() {
-            return Self::from_rasterized_pdf(path, options, password).await;
+            return Self::from_rasterized_pdf(path, options, spool, password).await;
         }
 
         // Count the number of pages in the PDF.
-        let total_pages = get_pdf_page_count(path).await?;
+        let total_pages = get_pdf_page_count(path, password).await?;
 
         // Construct an output filename. pdfseparate will add digits to
         // this if there is more than one page.
@@ -162,18 +271,21 @@ impl PageIter {
         // We use `with_cpu_semaphore` because `pdfseparate` will use 100% of a
         // CPU, and we don't want to run 200 copies of it at once by mistake.
         let out_path = tmpdir_path.join(format!("{}-%d.pdf", filename.to_string_lossy()));
-        let mut cmd = Command::new("pdfseparate");
+        let mut cmd = limited_command("pdfseparate");
         add_last_page_arg_if_needed(options, total_pages, &mut cmd)?;
         let output = with_cpu_semaphore(|| async {
-            cmd.arg(path).arg(out_path).output().await.with_context(|| {
-                format!("failed to run pdfseparate on {:?}", path.display())
-            })
+            limited_output(cmd.arg(path).arg(out_path))
+                .await
+                .with_context(|| {
+                    format!("failed to run pdfseparate on {:?}", path.display())
+                })
         })
         .await?;
         check_for_command_failure("pdfseparate", &output, Some(&is_error_line))?;
 
         Self::from_tempdir(
             options,
+            spool,
             tmpdir,
             "application/pdf".to_string(),
             total_pages,
@@ -182,117 +294,190 @@ impl PageIter {
         .await
     }
 
-    /// Create a new [`PdfPageStream`] from a PDF file, rasterizing each page.
+    /// Create a new [`PageIter`] from a PDF file, rasterizing each page.
+    ///
+    /// Pages are rendered in chunks of [`PageIterOptions::render_chunk_pages`].
+    /// We wait for the first chunk, and render the rest in the background, so
+    /// that callers can start work on early pages while later pages are still
+    /// rendering.
     #[instrument(level = "debug", skip_all, fields(path = %path.display(), dpi))]
     async fn from_rasterized_pdf(
         path: &Path,
         options: &PageIterOptions,
+        spool: &PageSpool,
         password: Option<&str>,
     ) -> Result<Self> {
-        // Count the number of pages in the PDF.
-        let total_pages = get_pdf_page_count(path).await?;
-
-        // Construct an output filename. pdftocairo will add digits to this if
-        // there is more than one page.
-        let filename = path
-            .file_name()
-            .context("failed to get filename from PDF path")?;
+        // Count the number of pages in the PDF, and figure out which ones we
+        // want to render.
+        let total_pages = get_pdf_page_count(path, password).await?;
+        let last_page = options
+            .max_pages
+            .map_or(total_pages, |max_pages| max_pages.min(total_pages));
+        let chunk_pages = match options.render_chunk_pages {
+            0 => last_page.max(1),
+            chunk_pages => chunk_pages,
+        };
+        let mut ranges = (1..=last_page)
+            .step_by(chunk_pages)
+            .map(|first| (first, first.saturating_add(chunk_pages - 1).min(last_page)))
+            .collect::<Vec<_>>()
+            .into_iter();
 
         // Create a temporary directory to hold the PNG files.
         let tmpdir = tempfile::TempDir::with_prefix("pages")?;
-        let tmpdir_path = tmpdir.path().to_owned();
+        let renderer = PdfRasterizer {
+            path: path.to_owned(),
+            dpi: options.rasterize_dpi,
+            password: password.map(|p| p.to_owned()),
+            tmpdir_path: tmpdir.path().to_owned(),
+        };
+
+        // Render our first chunk before returning, so that fatal errors are
+        // reported immediately.
+        let first_chunk = match ranges.next() {
+            Some((first, last)) => renderer.render(first, last).await?,
+            None => RenderedChunk::default(),
+        };
+
+        // Render the remaining chunks in the background, one at a time. If
+        // our receiver is dropped, we stop early.
+        let pending_chunks = if !ranges.as_slice().is_empty() {
+            let (tx, rx) = mpsc::channel();
+            tokio::spawn(async move {
+                for (first, last) in ranges {
+                    let result = renderer.render(first, last).await;
+                    let failed = result.is_err();
+                    if tx.send(result).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+            Some(rx)
+        } else {
+            None
+        };
 
-        // Run pdftocairo to convert the PDF to PNG files.
-        //
-        // We use `with_cpu_semaphore` because `pdftocairo` will use _at least_
-        // 100% of a CPU, and we don't want to run 200 copies of it at once by
-        // mistake.
-        let out_path = tmpdir_path.join(filename).with_extension("png");
-        let mut cmd = Command::new("pdftocairo");
-        cmd.arg("-png")
-            .arg("-r")
-            .arg(options.rasterize_dpi.to_string());
-        if let Some(password) = password {
-            cmd.arg("-opw").arg(password);
+        Ok(Self {
+            tmpdir: Some(tmpdir),
+            repaired_pdf: None,
+            mime_type: "image/png".to_string(),
+            dir_iter: first_chunk.paths.into_iter(),
+            pending_chunks,
+            total_pages,
+            max_pages: options.max_pages,
+            warnings: Arc::new(Mutex::new(first_chunk.warnings)),
+            spool: spool.clone(),
+        })
+    }
+
+    /// Create a new [`PageIter`] from a video file, extracting frames at
+    /// regular intervals.
+    #[instrument(level = "debug", skip_all, fields(path = %path.display()))]
+    async fn from_video(
+        path: &Path,
+        options: &PageIterOptions,
+        spool: &PageSpool,
+    ) -> Result<Self> {
+        if options.frame_interval.is_nan() || options.frame_interval <= 0.0 {
+            return Err(anyhow!("--frame-interval must be greater than 0"));
         }
-        add_last_page_arg_if_needed(options, total_pages, &mut cmd)?;
+
+        // Figure out how many frames we want.
+        let duration = get_media_duration(path).await?;
+        let total_frames = f64_to_usize((duration / options.frame_interval).ceil())
+            .ok_or_else(|| {
+                anyhow!("cannot extract frames from a video lasting {duration} seconds")
+            })?
+            .max(1);
+        let frame_limit = options.max_pages.map_or(options.max_frames, |max_pages| {
+            max_pages.min(options.max_frames)
+        });
+        let frame_count = total_frames.min(frame_limit);
+
+        // Create a temporary directory to hold the JPEG files.
+        let tmpdir = tempfile::TempDir::with_prefix("frames")?;
+        let out_path = tmpdir.path().join("frame-%05d.jpg");
+
+        // Run ffmpeg to extract our frames.
+        //
+        // We use `with_cpu_semaphore` because `ffmpeg` will use 100% of a CPU
+        // while decoding.
+        let mut cmd = limited_command("ffmpeg");
+        cmd.args(["-nostdin", "-hide_banner", "-loglevel", "error", "-i"])
+            .arg(path)
+            .arg("-vf")
+            .arg(format!("fps=1/{}", options.frame_interval))
+            .arg("-frames:v")
+            .arg(frame_count.to_string())
+            .args(["-q:v", "2"]);
         let output = with_cpu_semaphore(|| async {
-            cmd.arg(path).arg(out_path).output().await.with_context(|| {
-                format!("failed to run pdftocairo on {:?}", path.display())
-            })
+            limited_output(cmd.arg(out_path))
+                .await
+                .with_context(|| format!("failed to run ffmpeg on {:?}", path.display()))
         })
         .await?;
-        check_for_command_failure("pdftocairo", &output, Some(&is_error_line))?;
-        Self::from_tempdir(
+        check_for_command_failure("ffmpeg", &output, None)?;
+
+        let mut page_iter = Self::from_tempdir(
             options,
+            spool,
             tmpdir,
-            "image/png".to_string(),
-            total_pages,
+            "image/jpeg".to_string(),
+            total_frames,
             &output,
         )
-        .await
+        .await?;
+        if frame_count < total_frames && options.max_frames < total_frames {
+            page_iter.add_warnings([format!(
+                "Only {frame_count}/{total_frames} video frames processed (because of --max-frames)"
+            )]);
+        }
+        Ok(page_iter)
     }
 
     /// Create a [`PageIter`] from a [`tempdir::TempDir`] full of files
     /// named in lexixal order, plus a MIME type.
     async fn from_tempdir(
         options: &PageIterOptions,
+        spool: &PageSpool,
         tmpdir: tempfile::TempDir,
         mime_type: String,
         total_pages: usize,
         output: &Output,
     ) -> Result<Self> {
-        // Get the path to the temporary directory.
-        let tmpdir_path = tmpdir.path();
-
-        // Get the list of PNG files in the temporary directory.
-        let mut dir_paths = tmpdir_path
-            .read_dir()
-            .with_context(|| {
-                format!(
-                    "failed to read temporary directory {:?}",
-                    tmpdir_path.display()
-                )
-            })?
-            .map(|entry| {
-                let entry = entry.with_context(|| {
-                    format!(
-                        "failed to read entry in temporary directory {:?}",
-                        tmpdir_path.display()
-                    )
-                })?;
-                let path = entry.path();
-                Ok(path)
-            })
-            .collect::<Result<Vec<_>>>()?;
-        dir_paths.sort();
-        let dir_iter = dir_paths.into_iter();
-
-        // Get the output of the command, and save as warnings.
-        let mut warnings = vec![];
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        for line in stdout.lines() {
-            warnings.push(line.trim().to_string());
-        }
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        for line in stderr.lines() {
-            warnings.push(line.trim().to_string());
-        }
+        // Get the list of files in the temporary directory, and save the
+        // command output as warnings.
+        let dir_iter = sorted_dir_paths(tmpdir.path())?.into_iter();
+        let warnings = command_output_lines(output);
 
         // Return our iterator.
         Ok(Self {
             tmpdir: Some(tmpdir),
+            repaired_pdf: None,
             mime_type,
             dir_iter,
+            pending_chunks: None,
             total_pages,
             max_pages: options.max_pages,
-            warnings,
+            warnings: Arc::new(Mutex::new(warnings)),
+            spool: spool.clone(),
         })
     }
 
     /// Get any warnings that occurred while processing the document.
-    pub fn warnings(&self) -> &[String] {
-        &self.warnings
+    ///
+    /// Pages may be rendered in the background, so more warnings may be added
+    /// until this iterator is finished.
+    pub fn warnings(&self) -> Arc<Mutex<Vec<String>>> {
+        self.warnings.clone()
+    }
+
+    /// Add warnings to our list.
+    fn add_warnings(&self, warnings: impl IntoIterator<Item = String>) {
+        self.warnings
+            .lock()
+            .expect("page warnings lock poisoned")
+            .extend(warnings);
     }
 
     /// Will this iterator return only an incomplete set of pages?
@@ -337,47 +522,235 @@ impl Drop for PageIter {
 impl Iterator for PageIter {
     type Item = Result<Page>;
 
+    /// Get the next page. If pages are still being rendered in the
+    /// background, this blocks until the next chunk is ready, so it should not
+    /// be called from an async context.
     fn next(&mut self) -> Option<Self::Item> {
-        use std::fs;
-        if let Some(path) = self.dir_iter.next() {
-            // Read the PNG file into a byte vector.
-            let result = fs::read(&path)
-                .with_context(|| format!("failed to read file {:?}", path.display()));
-            let bytes = match result {
-                Ok(bytes) => bytes,
-                Err(err) => return Some(Err(err)),
-            };
-
-            // Delete the file to recover space a bit early.
-            if self.tmpdir.is_some() {
-                let result = fs::remove_file(&path).with_context(|| {
-                    format!("failed to delete file {:?}", path.display())
-                });
-                if let Err(err) = result {
+        loop {
+            if let Some(path) = self.dir_iter.next() {
+                // Load the page, either into memory or as a spilled file. If
+                // we have a temporary directory, the file is ours to move or
+                // delete, which recovers space a bit early.
+                let data = match self.spool.load(&path, self.tmpdir.is_some()) {
+                    Ok(data) => data,
+                    Err(err) => return Some(Err(err)),
+                };
+                return Some(Ok(Page {
+                    mime_type: self.mime_type.clone(),
+                    data,
+                }));
+            }
+
+            // Wait for our next chunk, if we have one.
+            match self.pending_chunks.as_ref()?.recv() {
+                Ok(Ok(chunk)) => {
+                    self.add_warnings(chunk.warnings);
+                    self.dir_iter = chunk.paths.into_iter();
+                }
+                Ok(Err(err)) => {
+                    self.pending_chunks = None;
                     return Some(Err(err));
                 }
+                Err(mpsc::RecvError) => {
+                    self.pending_chunks = None;
+                    return None;
+                }
             }
+        }
+    }
+}
+
+/// Pages rendered by [`PdfRasterizer`].
+#[derive(Debug, Default)]
+struct RenderedChunk {
+    /// The rendered pages, in order.
+    paths: Vec<PathBuf>,
+    /// Any warnings printed while rendering.
+    warnings: Vec<String>,
+}
+
+/// Rasterizes ranges of PDF pages using `pdftocairo`.
+struct PdfRasterizer {
+    /// The PDF to render.
+    path: PathBuf,
+    /// The DPI to render at.
+    dpi: u32,
+    /// The "owner" password for the PDF, if any.
+    password: Option<String>,
+    /// The directory to render into.
+    tmpdir_path: PathBuf,
+}
+
+impl PdfRasterizer {
+    /// Render pages `first..=last` (1-based) into a new subdirectory of our
+    /// temporary directory.
+    #[instrument(level = "debug", skip(self))]
+    async fn render(&self, first: usize, last: usize) -> Result<RenderedChunk> {
+        let path = &self.path;
+        let out_dir = self.tmpdir_path.join(format!("chunk-{first:06}"));
+        tokio::fs::create_dir(&out_dir)
+            .await
+            .with_context(|| format!("failed to create {:?}", out_dir.display()))?;
+
+        // Construct an output filename. pdftocairo will add digits to this if
+        // there is more than one page.
+        let filename = path
+            .file_name()
+            .context("failed to get filename from PDF path")?;
+        let out_path = out_dir.join(filename).with_extension("png");
 
-            Some(Ok(Page {
-                mime_type: self.mime_type.clone(),
-                data: bytes,
-            }))
+        // Run pdftocairo to convert the PDF to PNG files.
+        //
+        // We use `with_cpu_semaphore` because `pdftocairo` will use _at least_
+        // 100% of a CPU, and we don't want to run 200 copies of it at once by
+        // mistake.
+        let mut cmd = limited_command("pdftocairo");
+        cmd.arg("-png").arg("-r").arg(self.dpi.to_string());
+        if let Some(password) = &self.password {
+            cmd.arg("-opw").arg(password);
+        }
+        cmd.arg("-f")
+            .arg(first.to_string())
+            .arg("-l")
+            .arg(last.to_string());
+        let output = with_cpu_semaphore(|| async {
+            limited_output(cmd.arg(path).arg(out_path))
+                .await
+                .with_context(|| {
+                    format!("failed to run pdftocairo on {:?}", path.display())
+                })
+        })
+        .await?;
+        check_for_command_failure("pdftocairo", &output, Some(&is_error_line))?;
+
+        Ok(RenderedChunk {
+            paths: sorted_dir_paths(&out_dir)?,
+            warnings: command_output_lines(&output),
+        })
+    }
+}
+
+/// List the files in a directory, sorted by name.
+fn sorted_dir_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dir_paths = dir
+        .read_dir()
+        .with_context(|| {
+            format!("failed to read temporary directory {:?}", dir.display())
+        })?
+        .map(|entry| {
+            let entry = entry.with_context(|| {
+                format!(
+                    "failed to read entry in temporary directory {:?}",
+                    dir.display()
+                )
+            })?;
+            Ok(entry.path())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    dir_paths.sort();
+    Ok(dir_paths)
+}
+
+/// Get the lines of output from a command, so we can report them as warnings.
+fn command_output_lines(output: &Output) -> Vec<String> {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stdout
+        .lines()
+        .chain(stderr.lines())
+        .map(|line| line.trim().to_string())
+        .collect()
+}
+
+/// Rewrite a damaged PDF using `qpdf`, which reconstructs broken cross-reference
+/// tables and streams. Returns the repaired copy, plus any warnings.
+#[instrument(level = "debug", skip_all, fields(path = %path.display()))]
+async fn repair_pdf(
+    path: &Path,
+    password: Option<&str>,
+) -> Result<(tempfile::NamedTempFile, Vec<String>)> {
+    let repaired = tempfile::Builder::new()
+        .prefix("repaired")
+        .suffix(".pdf")
+        .tempfile()
+        .context("failed to create temporary file for repaired PDF")?;
+    let mut cmd = limited_command("qpdf");
+    if let Some(password) = password {
+        cmd.arg(format!("--password={password}")).arg("--decrypt");
+    }
+    cmd.arg(path).arg(repaired.path());
+    let output = with_cpu_semaphore(|| async {
+        limited_output(&mut cmd)
+            .await
+            .with_context(|| format!("failed to run qpdf on {:?}", path.display()))
+    })
+    .await?;
+    // qpdf exits with 3 when it succeeds with warnings, which is normal for
+    // damaged files.
+    if output.status.code() != Some(3) {
+        check_for_command_failure("qpdf", &output, None)?;
+    }
+    Ok((repaired, command_output_lines(&output)))
+}
+
+/// An error for PDFs which we can't open without a password.
+#[derive(Debug)]
+pub struct EncryptedPdfError {
+    /// The path to the PDF.
+    path: PathBuf,
+    /// Did we have a password?
+    had_password: bool,
+}
+
+impl fmt::Display for EncryptedPdfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = if self.had_password {
+            "the supplied password was incorrect"
         } else {
-            None
+            "no password was supplied"
+        };
+        write!(
+            f,
+            "encrypted_pdf: {:?} is encrypted, and {reason}",
+            self.path.display()
+        )
+    }
+}
+
+impl error::Error for EncryptedPdfError {}
+
+/// If a Poppler tool failed because it couldn't decrypt the PDF at `path`,
+/// return an [`EncryptedPdfError`].
+pub fn check_for_encrypted_pdf(
+    path: &Path,
+    password: Option<&str>,
+    output: &Output,
+) -> Result<()> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() && stderr.contains("Incorrect password") {
+        Err(EncryptedPdfError {
+            path: path.to_owned(),
+            had_password: password.is_some(),
         }
+        .into())
+    } else {
+        Ok(())
     }
 }
 
 /// Get the number of pages in a PDF file.
 #[instrument(level = "debug", skip_all, fields(path = %path.display()))]
-pub async fn get_pdf_page_count(path: &Path) -> Result<usize> {
-    // Run pdfinfo to get the number of pages.
-    let mut cmd = Command::new("pdfinfo");
-    let output = cmd
-        .arg(path)
-        .output()
+pub async fn get_pdf_page_count(path: &Path, password: Option<&str>) -> Result<usize> {
+    // Run pdfinfo to get the number of pages. This is cheap, so it's a good
+    // place to find out that a PDF is encrypted.
+    let mut cmd = limited_command("pdfinfo");
+    if let Some(password) = password {
+        cmd.arg("-opw").arg(password);
+    }
+    let output = limited_output(cmd.arg(path))
         .await
         .with_context(|| format!("failed to run pdfinfo on {:?}", path.display()))?;
+    check_for_encrypted_pdf(path, password, &output)?;
     check_for_command_failure("pdfinfo", &output, None)?;
 
     // Parse the output of pdfinfo into properties.
@@ -403,6 +776,24 @@ pub async fn get_pdf_page_count(path: &Path) -> Result<usize> {
     })
 }
 
+/// Get the duration of an audio or video file, in seconds, using `ffprobe`.
+#[instrument(level = "debug", skip_all, fields(path = %path.display()))]
+pub async fn get_media_duration(path: &Path) -> Result<f64> {
+    let mut cmd = limited_command("ffprobe");
+    cmd.args(["-v", "error", "-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path);
+    let output = limited_output(&mut cmd)
+        .await
+        .with_context(|| format!("failed to run ffprobe on {:?}", path.display()))?;
+    check_for_command_failure("ffprobe", &output, None)?;
+    let duration = String::from_utf8_lossy(&output.stdout);
+    duration
+        .trim()
+        .parse::<f64>()
+        .with_context(|| format!("ffprobe returned invalid duration {duration:?}"))
+}
+
 /// Add a "last page" argument to a [`Command`].
 fn add_last_page_arg_if_needed(
     options: &PageIterOptions,
@@ -435,6 +826,13 @@ mod tests {
 
     static TEST_PDF_PATH: &str = "tests/fixtures/ocr/two_pages.pdf";
 
+    fn spool_opts() -> PageSpoolOptions {
+        PageSpoolOptions {
+            page_spill_threshold_mb: 4,
+            page_memory_budget_mb: None,
+        }
+    }
+
     #[test]
     fn is_error_line_works() {
         assert!(is_error_line("error: something went wrong"));
@@ -448,7 +846,7 @@ mod tests {
     #[tokio::test]
     #[ignore = "Requires poppler-utils to be installed"]
     async fn page_count_returns_correct_number_of_pages() -> Result<()> {
-        let page_count = get_pdf_page_count(Path::new(TEST_PDF_PATH)).await?;
+        let page_count = get_pdf_page_count(Path::new(TEST_PDF_PATH), None).await?;
         assert_eq!(page_count, 2);
         Ok(())
     }
@@ -461,8 +859,15 @@ mod tests {
             &PageIterOptions {
                 rasterize: true,
                 rasterize_dpi: 300,
+                render_chunk_pages: 16,
                 max_pages: None,
+                frame_interval: 5.0,
+                max_frames: 100,
+                pdf_repair: false,
+                skip_encrypted_pdfs: false,
+                spool_opts: spool_opts(),
             },
+            &PageSpool::new(&spool_opts()),
             None,
         )
         .await?;
@@ -479,8 +884,15 @@ mod tests {
             &PageIterOptions {
                 rasterize: false,
                 rasterize_dpi: 300,
+                render_chunk_pages: 16,
                 max_pages: Some(1),
+                frame_interval: 5.0,
+                max_frames: 100,
+                pdf_repair: false,
+                skip_encrypted_pdfs: false,
+                spool_opts: spool_opts(),
             },
+            &PageSpool::new(&spool_opts()),
             None,
         )
         .await?;
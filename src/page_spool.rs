@@ -0,0 +1,262 @@
+//! Keep large pages on disk until we actually need them.
+//!
+//! Rasterized pages can be several megabytes each, and with many jobs in
+//! flight, holding all of them in memory adds up quickly. Instead, we leave
+//! large pages (and any pages over our memory budget) in temporary files, and
+//! only read them back when building a request.
+
+use std::{
+    fs,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use clap::Args;
+use tempfile::TempPath;
+
+use crate::prelude::*;
+
+/// Bytes per megabyte, for our command-line options.
+const BYTES_PER_MB: usize = 1024 * 1024;
+
+/// Options controlling how much page data we keep in memory.
+#[derive(Args, Clone, Debug)]
+pub struct PageSpoolOptions {
+    /// Keep pages larger than this many megabytes in temporary files until
+    /// they're needed.
+    #[clap(long, default_value = "4")]
+    pub page_spill_threshold_mb: usize,
+
+    /// The maximum number of megabytes of page data to hold in memory at
+    /// once. Pages beyond this budget are kept in temporary files. Defaults
+    /// to no limit.
+    #[clap(long)]
+    pub page_memory_budget_mb: Option<usize>,
+}
+
+/// Decides whether pages should be kept in memory or on disk. Clones share
+/// the same memory budget.
+#[derive(Clone, Debug)]
+pub struct PageSpool {
+    /// Pages larger than this are always spilled.
+    threshold: usize,
+
+    /// Our shared memory budget.
+    budget: Arc<MemoryBudget>,
+}
+
+impl PageSpool {
+    /// Create a new page spool.
+    pub fn new(options: &PageSpoolOptions) -> Self {
+        Self {
+            threshold: options.page_spill_threshold_mb.saturating_mul(BYTES_PER_MB),
+            budget: Arc::new(MemoryBudget {
+                limit: options
+                    .page_memory_budget_mb
+                    .map(|mb| mb.saturating_mul(BYTES_PER_MB)),
+                used: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Load page data from `path`.
+    ///
+    /// If `owned` is true, `path` is a scratch file which we may move or
+    /// delete. Otherwise, it belongs to the user, and we'll leave it alone.
+    ///
+    /// This performs blocking I/O.
+    pub fn load(&self, path: &Path, owned: bool) -> Result<PageData> {
+        let len = fs::metadata(path)
+            .with_context(|| format!("failed to stat file {:?}", path.display()))?
+            .len();
+        let len = usize::try_from(len).context("page too large")?;
+
+        // Keep small pages in memory, if we can afford to.
+        if len <= self.threshold
+            && let Some(reservation) = self.budget.try_reserve(len)
+        {
+            let data = fs::read(path)
+                .with_context(|| format!("failed to read file {:?}", path.display()))?;
+            if owned {
+                fs::remove_file(path).with_context(|| {
+                    format!("failed to delete file {:?}", path.display())
+                })?;
+            }
+            return Ok(PageData::InMemory {
+                data,
+                _reservation: reservation,
+            });
+        }
+
+        trace!(path = %path.display(), len, "Spilling page to disk");
+        if owned {
+            // Move the page out of its parent directory, which may be deleted
+            // before we're done with it.
+            let spilled = tempfile::Builder::new()
+                .prefix("page")
+                .tempfile()
+                .context("cannot create page spill file")?
+                .into_temp_path();
+            fs::rename(path, &spilled).with_context(|| {
+                format!("failed to move {:?} to {:?}", path.display(), spilled)
+            })?;
+            Ok(PageData::Spilled { path: spilled, len })
+        } else {
+            Ok(PageData::InFile {
+                path: path.to_owned(),
+                len,
+            })
+        }
+    }
+}
+
+/// A limit on how many bytes of page data we hold in memory.
+#[derive(Debug)]
+struct MemoryBudget {
+    /// Our limit, if any.
+    limit: Option<usize>,
+
+    /// How many bytes are currently reserved.
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    /// Reserve `bytes` from our budget, or return `None` if that would put us
+    /// over our limit.
+    fn try_reserve(self: &Arc<Self>, bytes: usize) -> Option<MemoryReservation> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                let new_used = used.checked_add(bytes)?;
+                match self.limit {
+                    Some(limit) if new_used > limit => None,
+                    _ => Some(new_used),
+                }
+            })
+            .ok()?;
+        Some(MemoryReservation {
+            budget: self.clone(),
+            bytes,
+        })
+    }
+}
+
+/// Bytes reserved from a [`MemoryBudget`], which are released on drop.
+#[derive(Debug)]
+pub struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+/// The data for a page, either in memory or in a file.
+#[derive(Debug)]
+pub enum PageData {
+    /// Page data held in memory.
+    InMemory {
+        data: Vec<u8>,
+        _reservation: MemoryReservation,
+    },
+
+    /// Page data in a temporary file, which will be deleted on drop.
+    Spilled { path: TempPath, len: usize },
+
+    /// Page data in an input file, which we don't own.
+    InFile { path: PathBuf, len: usize },
+}
+
+impl PageData {
+    /// The size of our data, in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            PageData::InMemory { data, .. } => data.len(),
+            PageData::Spilled { len, .. } | PageData::InFile { len, .. } => *len,
+        }
+    }
+
+    /// Do we have no data?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Read our data into memory.
+    pub async fn read(&self) -> Result<Vec<u8>> {
+        let path: &Path = match self {
+            PageData::InMemory { data, .. } => return Ok(data.clone()),
+            PageData::Spilled { path, .. } => path,
+            PageData::InFile { path, .. } => path,
+        };
+        tokio::fs::read(path)
+            .await
+            .with_context(|| format!("failed to read page from {:?}", path.display()))
+    }
+
+    /// Convert into bytes, avoiding a copy if we're already in memory.
+    pub async fn into_bytes(self) -> Result<Vec<u8>> {
+        match self {
+            PageData::InMemory { data, .. } => Ok(data),
+            other => other.read().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use super::*;
+
+    fn spool(threshold_mb: usize, budget_mb: Option<usize>) -> PageSpool {
+        PageSpool::new(&PageSpoolOptions {
+            page_spill_threshold_mb: threshold_mb,
+            page_memory_budget_mb: budget_mb,
+        })
+    }
+
+    fn page_file(len: usize) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&vec![0; len]).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn small_pages_stay_in_memory_until_over_budget() {
+        let spool = spool(1, Some(1));
+        let first_file = page_file(BYTES_PER_MB / 2 + 1);
+        let second_file = page_file(BYTES_PER_MB / 2 + 1);
+
+        let first = spool.load(first_file.path(), false).unwrap();
+        assert!(matches!(first, PageData::InMemory { .. }));
+        let second = spool.load(second_file.path(), false).unwrap();
+        assert!(matches!(second, PageData::InFile { .. }));
+        assert_eq!(
+            second.into_bytes().await.unwrap().len(),
+            BYTES_PER_MB / 2 + 1
+        );
+
+        // Dropping a page releases its memory.
+        drop(first);
+        let second = spool.load(second_file.path(), false).unwrap();
+        assert!(matches!(second, PageData::InMemory { .. }));
+    }
+
+    #[tokio::test]
+    async fn large_owned_pages_are_spilled() {
+        let spool = spool(0, None);
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("page.png");
+        fs::write(&path, b"page").unwrap();
+
+        let page = spool.load(&path, true).unwrap();
+        drop(dir);
+        assert!(matches!(page, PageData::Spilled { .. }));
+        assert_eq!(page.len(), 4);
+        assert_eq!(page.read().await.unwrap(), b"page");
+    }
+}
@@ -0,0 +1,242 @@
+//! Pre-flight checks against provider request size limits.
+//!
+//! Providers reject oversized images and requests with opaque 400 errors,
+//! usually after we've spent time uploading megabytes of data. We check each
+//! rendered prompt against the provider's published limits first, so that the
+//! record fails with a clear explanation instead.
+//!
+//! These limits change from time to time. They are deliberately conservative,
+//! and `--skip-payload-checks` turns them off.
+
+use std::fmt;
+
+use crate::{
+    drivers::DriverType,
+    litellm::LiteLlmModel,
+    prelude::*,
+    prompt::{ChatPrompt, Message, Rendered, assistant_text},
+    tokens::ModelFamily,
+};
+
+/// Request size limits for a provider.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PayloadLimits {
+    /// A human-readable name for the provider.
+    pub provider: &'static str,
+
+    /// The largest image, in bytes, before Base64 encoding.
+    pub max_image_bytes: usize,
+
+    /// The most images in a single request.
+    pub max_images: usize,
+
+    /// The largest request, in bytes, counting images as Base64.
+    pub max_request_bytes: usize,
+}
+
+/// One megabyte. Providers are vague about whether they mean MB or MiB, so we
+/// use the smaller.
+const MB: usize = 1_000_000;
+
+/// Anthropic's API.
+const ANTHROPIC: PayloadLimits = PayloadLimits {
+    provider: "Anthropic",
+    max_image_bytes: 5 * MB,
+    max_images: 100,
+    max_request_bytes: 32 * MB,
+};
+
+/// AWS Bedrock's Converse API.
+const BEDROCK: PayloadLimits = PayloadLimits {
+    provider: "Bedrock",
+    max_image_bytes: 3_750_000,
+    max_images: 20,
+    max_request_bytes: 25 * MB,
+};
+
+/// OpenAI's API.
+const OPENAI: PayloadLimits = PayloadLimits {
+    provider: "OpenAI",
+    max_image_bytes: 20 * MB,
+    max_images: 500,
+    max_request_bytes: 50 * MB,
+};
+
+/// The Gemini API, with inline data.
+const GEMINI: PayloadLimits = PayloadLimits {
+    provider: "Gemini",
+    max_image_bytes: 20 * MB,
+    max_images: 3_000,
+    max_request_bytes: 20 * MB,
+};
+
+/// Vertex AI, with inline data.
+const VERTEX: PayloadLimits = PayloadLimits {
+    provider: "Vertex AI",
+    max_image_bytes: 7 * MB,
+    max_images: 3_000,
+    max_request_bytes: 500 * MB,
+};
+
+impl PayloadLimits {
+    /// Look up the limits which apply to `model` when called via `driver`,
+    /// using LiteLLM's `litellm_provider` if we have it. Returns `None` if we
+    /// don't know the provider.
+    pub fn for_model(
+        driver: DriverType,
+        model: &str,
+        model_info: Option<&LiteLlmModel>,
+    ) -> Option<Self> {
+        match driver {
+            DriverType::Bedrock => return Some(BEDROCK),
+            DriverType::Vertex => return Some(VERTEX),
+            DriverType::Echo => return None,
+            DriverType::OpenAI | DriverType::Native => {}
+        }
+        let provider = model_info.map(|info| info.model_info.litellm_provider.as_str());
+        match provider {
+            Some("anthropic") => return Some(ANTHROPIC),
+            Some(p) if p.starts_with("bedrock") => return Some(BEDROCK),
+            Some(p) if p.starts_with("vertex_ai") => return Some(VERTEX),
+            Some("gemini") => return Some(GEMINI),
+            Some("openai" | "azure") => return Some(OPENAI),
+            Some(_) => return None,
+            None => {}
+        }
+        match ModelFamily::for_model(model) {
+            ModelFamily::Anthropic => Some(ANTHROPIC),
+            ModelFamily::Gemini => Some(GEMINI),
+            ModelFamily::OpenAi => Some(OPENAI),
+            ModelFamily::Other => None,
+        }
+    }
+
+    /// Check a rendered prompt against our limits.
+    pub fn check(&self, prompt: &ChatPrompt<Rendered>) -> Result<(), PayloadTooLarge> {
+        let mut text_bytes = prompt.developer.as_ref().map_or(0, |d| d.len());
+        let mut image_bytes = vec![];
+        for message in &prompt.messages {
+            match message {
+                Message::User { text, images } => {
+                    text_bytes += text.as_ref().map_or(0, |t| t.len());
+                    image_bytes.extend(
+                        images
+                            .iter()
+                            .filter_map(|image| image.data())
+                            .map(|data| data.bytes.len()),
+                    );
+                }
+                Message::Assistant { json } => text_bytes += assistant_text(json).len(),
+            }
+        }
+        self.check_sizes(text_bytes, &image_bytes)
+    }
+
+    /// Check a request with `text_bytes` of text and images of the given sizes.
+    fn check_sizes(
+        &self,
+        text_bytes: usize,
+        image_bytes: &[usize],
+    ) -> Result<(), PayloadTooLarge> {
+        if image_bytes.len() > self.max_images {
+            return Err(PayloadTooLarge(format!(
+                "{} images exceeds {} limit of {} images per request",
+                image_bytes.len(),
+                self.provider,
+                self.max_images
+            )));
+        }
+        for (i, &bytes) in image_bytes.iter().enumerate() {
+            if bytes > self.max_image_bytes {
+                return Err(PayloadTooLarge(format!(
+                    "image {} is {}, which exceeds {} limit of {} per image",
+                    i + 1,
+                    format_bytes(bytes),
+                    self.provider,
+                    format_bytes(self.max_image_bytes)
+                )));
+            }
+        }
+        let request_bytes = text_bytes
+            + image_bytes
+                .iter()
+                .map(|bytes| bytes.div_ceil(3) * 4)
+                .sum::<usize>();
+        if request_bytes > self.max_request_bytes {
+            return Err(PayloadTooLarge(format!(
+                "request is about {}, which exceeds {} limit of {} per request",
+                format_bytes(request_bytes),
+                self.provider,
+                format_bytes(self.max_request_bytes)
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A request which exceeds a provider's limits.
+#[derive(Debug)]
+pub struct PayloadTooLarge(String);
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "payload_too_large: {}", self.0)
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+/// Format a size in megabytes, for humans.
+fn format_bytes(bytes: usize) -> String {
+    let mb = format!("{:.2}", bytes as f64 / MB as f64);
+    format!("{}MB", mb.trim_end_matches('0').trim_end_matches('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn providers_are_chosen_by_driver_then_model() {
+        let limits = |driver, model| {
+            PayloadLimits::for_model(driver, model, None).map(|limits| limits.provider)
+        };
+        assert_eq!(
+            limits(DriverType::Bedrock, "anthropic.claude-3"),
+            Some("Bedrock")
+        );
+        assert_eq!(
+            limits(DriverType::Native, "claude-3-5-sonnet"),
+            Some("Anthropic")
+        );
+        assert_eq!(limits(DriverType::OpenAI, "gpt-4o-mini"), Some("OpenAI"));
+        assert_eq!(limits(DriverType::OpenAI, "llama3"), None);
+        assert_eq!(limits(DriverType::Echo, "gpt-4o-mini"), None);
+    }
+
+    #[test]
+    fn oversized_payloads_are_explained() {
+        assert!(ANTHROPIC.check_sizes(1_000, &[4 * MB]).is_ok());
+        assert_eq!(
+            ANTHROPIC
+                .check_sizes(1_000, &[1_000, 14 * MB])
+                .unwrap_err()
+                .to_string(),
+            "payload_too_large: image 2 is 14MB, which exceeds Anthropic limit of 5MB per image"
+        );
+        assert_eq!(
+            BEDROCK
+                .check_sizes(0, &[1_000; 21])
+                .unwrap_err()
+                .to_string(),
+            "payload_too_large: 21 images exceeds Bedrock limit of 20 images per request"
+        );
+        assert_eq!(
+            GEMINI
+                .check_sizes(0, &[9 * MB, 9 * MB])
+                .unwrap_err()
+                .to_string(),
+            "payload_too_large: request is about 24MB, which exceeds Gemini limit of 20MB per request"
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Postgres input and output.
+//!
+//! Input uses URLs of the form `postgres://user@host/db?query=SELECT id, ...`,
+//! where each row returned by `query` becomes an input record. Output is
+//! written to `--output-table` in the database specified by `--out
+//! postgres://...`, using batched upserts keyed on `id`, or on `id` and
+//! `prompt_name` if the table has a `prompt_name` column.
+//!
+//! We don't support TLS yet, so `sslmode=require` and stricter modes are
+//! rejected up front, for both input and output URLs.
+
+use std::collections::{BTreeMap, HashMap, hash_map::Entry};
+
+use futures::StreamExt as _;
+use reqwest::Url;
+use tokio_postgres::{Client, NoTls, Row, Statement, types::Type};
+
+use crate::{
+    async_utils::io::{JsonObject, JsonStream, OutputAck, id_key},
+    prelude::*,
+};
+
+/// The maximum number of output records to upsert in a single statement.
+const UPSERT_BATCH_SIZE: usize = 500;
+
+/// Is this path a Postgres URL?
+pub fn is_postgres_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| {
+        path.starts_with("postgres://") || path.starts_with("postgresql://")
+    })
+}
+
+/// Split a Postgres URL into a connection string and an optional `query`
+/// parameter.
+fn split_postgres_url(path: &Path) -> Result<(String, Option<String>)> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("Postgres URL is not valid UTF-8"))?;
+    let mut url = Url::parse(path).context("Failed to parse Postgres URL")?;
+    let mut query = None;
+    let mut other_params = vec![];
+    for (key, value) in url.query_pairs() {
+        if key == "query" {
+            query = Some(value.into_owned());
+        } else {
+            if key == "sslmode" && !matches!(&*value, "disable" | "prefer") {
+                return Err(anyhow!(
+                    "Postgres sslmode={value} is not supported, because we can't \
+                     connect using TLS yet"
+                ));
+            }
+            other_params.push((key.into_owned(), value.into_owned()));
+        }
+    }
+    if other_params.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(other_params);
+    }
+    Ok((url.into(), query))
+}
+
+/// Connect to Postgres, running the connection in a background task.
+async fn connect(conn_str: &str) -> Result<Client> {
+    let (client, connection) = tokio_postgres::connect(conn_str, NoTls)
+        .await
+        .context("Failed to connect to Postgres")?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            error!("Postgres connection error: {err}");
+        }
+    });
+    Ok(client)
+}
+
+/// Read input records by running the `query` parameter of a Postgres URL.
+#[instrument(level = "debug", skip_all)]
+pub async fn read_postgres(path: &Path) -> Result<JsonStream> {
+    let (conn_str, query) = split_postgres_url(path)?;
+    let query = query.ok_or_else(|| {
+        anyhow!("Postgres input URLs need a `?query=SELECT id, ...` parameter")
+    })?;
+    let client = connect(&conn_str).await?;
+
+    // We don't count our rows first, because that would mean running the
+    // query twice, and it may be slow or have side effects.
+    let rows = client
+        .query_raw(&query, std::iter::empty::<&str>())
+        .await
+        .context("Failed to run Postgres input query")?;
+    Ok(Box::pin(rows)
+        .map(move |row| {
+            // Keep our client alive until we've read all our rows.
+            let _client = &client;
+            row_to_json(&row.context("Failed to read Postgres row")?)
+        })
+        .boxed())
+}
+
+/// Convert a Postgres row to JSON.
+fn row_to_json(row: &Row) -> Result<Value> {
+    let mut map = JsonObject::new();
+    for (idx, column) in row.columns().iter().enumerate() {
+        let value = match *column.type_() {
+            Type::BOOL => json!(row.try_get::<_, Option<bool>>(idx)?),
+            Type::INT2 => json!(row.try_get::<_, Option<i16>>(idx)?),
+            Type::INT4 => json!(row.try_get::<_, Option<i32>>(idx)?),
+            Type::INT8 => json!(row.try_get::<_, Option<i64>>(idx)?),
+            Type::FLOAT4 => json!(row.try_get::<_, Option<f32>>(idx)?),
+            Type::FLOAT8 => json!(row.try_get::<_, Option<f64>>(idx)?),
+            Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME => {
+                json!(row.try_get::<_, Option<String>>(idx)?)
+            }
+            Type::JSON | Type::JSONB => {
+                row.try_get::<_, Option<Value>>(idx)?.unwrap_or(Value::Null)
+            }
+            ref other => {
+                return Err(anyhow!(
+                    "Postgres column {:?} has unsupported type {other}; try casting it to text",
+                    column.name()
+                ));
+            }
+        };
+        map.insert(column.name().to_owned(), value);
+    }
+    Ok(Value::Object(map))
+}
+
+/// Quote a Postgres identifier.
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Quote a table name, which may be qualified with a schema, as in
+/// `public.results`.
+fn quote_table(table: &str) -> String {
+    table
+        .split('.')
+        .map(quote_ident)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Build an upsert into the quoted `table` for records with the given
+/// `columns`, which must include `id`. Other columns are left alone, so new
+/// rows get their defaults, and existing rows keep their values. `key` lists
+/// the columns of the table's unique constraint.
+fn upsert_sql(table: &str, columns: &[&str], key: &[&str]) -> String {
+    let column_list = columns
+        .iter()
+        .map(|c| quote_ident(c))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let updates = columns
+        .iter()
+        .filter(|c| !key.contains(c))
+        .map(|c| format!("{col} = EXCLUDED.{col}", col = quote_ident(c)))
+        .collect::<Vec<_>>();
+    let on_conflict = if updates.is_empty() {
+        "DO NOTHING".to_owned()
+    } else {
+        format!("DO UPDATE SET {}", updates.join(", "))
+    };
+    let key_list = key
+        .iter()
+        .map(|c| quote_ident(c))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "INSERT INTO {table} ({column_list}) \
+         SELECT {column_list} FROM jsonb_populate_recordset(NULL::{table}, $1::text::jsonb) \
+         ON CONFLICT ({key_list}) {on_conflict}"
+    )
+}
+
+/// Keep only the last record with each value of `key`, because a single
+/// upsert can't update the same row twice. This only drops records which would
+/// have overwritten each other anyway, such as messages delivered twice.
+fn last_record_per_key<'a>(batch: &'a [Value], key: &[&str]) -> Vec<&'a Value> {
+    let mut positions = HashMap::<Vec<String>, usize>::new();
+    let mut records = Vec::<&Value>::with_capacity(batch.len());
+    for record in batch {
+        let key_values = key.iter().map(|c| id_key(&record[*c])).collect();
+        match positions.entry(key_values) {
+            Entry::Occupied(entry) => records[*entry.get()] = record,
+            Entry::Vacant(entry) => {
+                entry.insert(records.len());
+                records.push(record);
+            }
+        }
+    }
+    records
+}
+
+/// Write output records to `table`, upserting on `id`, or on `id` and
+/// `prompt_name` if the table has a `prompt_name` column.
+///
+/// Top-level fields of each output record are matched to table columns by
+/// name. Fields without a matching column are ignored, and nested values are
+/// stored as JSON. Columns missing from a record are left alone.
+#[instrument(level = "debug", skip(path, stream, ack))]
+pub async fn write_postgres(
+    path: &Path,
+    table: &str,
+    stream: JsonStream,
+    ack: Option<&dyn OutputAck>,
+) -> Result<()> {
+    let (conn_str, _) = split_postgres_url(path)?;
+    let client = connect(&conn_str).await?;
+    let quoted_table = quote_table(table);
+
+    // Look up our columns, so we know which record fields to write.
+    let columns = client
+        .query(
+            "SELECT attname::text FROM pg_attribute \
+             WHERE attrelid = $1::text::regclass AND attnum > 0 AND NOT attisdropped \
+             ORDER BY attnum",
+            &[&quoted_table],
+        )
+        .await
+        .with_context(|| format!("Failed to look up columns of {table:?}"))?
+        .iter()
+        .map(|row| row.try_get::<_, String>(0))
+        .collect::<Result<Vec<_>, _>>()?;
+    if !columns.iter().any(|c| c == "id") {
+        return Err(anyhow!(
+            "Postgres output table {table:?} needs an `id` column"
+        ));
+    }
+
+    // Named prompts produce several records with the same `id`, which we can
+    // only keep apart if the table has somewhere to put the prompt name.
+    let key = if columns.iter().any(|c| c == "prompt_name") {
+        vec!["id", "prompt_name"]
+    } else {
+        vec!["id"]
+    };
+
+    // Records usually all have the same fields, so we only need a few
+    // different upserts.
+    let mut upserts = HashMap::<Vec<&str>, Statement>::new();
+    let mut batches = stream.ready_chunks(UPSERT_BATCH_SIZE);
+    while let Some(batch) = batches.next().await {
+        let batch = batch.into_iter().collect::<Result<Vec<_>>>()?;
+        if !key.contains(&"prompt_name")
+            && batch.iter().any(|record| !record["prompt_name"].is_null())
+        {
+            return Err(anyhow!(
+                "Postgres output table {table:?} needs a `prompt_name` column, \
+                 with a unique constraint on (id, prompt_name), to hold output \
+                 from named prompts"
+            ));
+        }
+
+        // Group our records by which columns they have.
+        let mut groups = BTreeMap::<Vec<&str>, Vec<&Value>>::new();
+        for record in last_record_per_key(&batch, &key) {
+            let present = columns
+                .iter()
+                .map(String::as_str)
+                .filter(|c| record.get(*c).is_some())
+                .collect::<Vec<_>>();
+            groups.entry(present).or_default().push(record);
+        }
+
+        for (present, records) in groups {
+            let upsert = match upserts.get(&present) {
+                Some(upsert) => upsert.clone(),
+                None => {
+                    let upsert = client
+                        .prepare(&upsert_sql(&quoted_table, &present, &key))
+                        .await
+                        .with_context(|| {
+                            format!("Failed to prepare upsert into {table:?}")
+                        })?;
+                    upserts.insert(present, upsert.clone());
+                    upsert
+                }
+            };
+            let json = serde_json::to_string(&records)?;
+            client
+                .execute(&upsert, &[&json])
+                .await
+                .with_context(|| format!("Failed to upsert records into {table:?}"))?;
+        }
+        if let Some(ack) = ack {
+            for record in &batch {
+                ack.ack(record).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+
+    #[test]
+    fn test_split_postgres_url() {
+        let (conn_str, query) = split_postgres_url(Path::new(
+            "postgres://me@localhost/db?sslmode=disable&query=SELECT%20id%20FROM%20docs",
+        ))
+        .unwrap();
+        assert_eq!(conn_str, "postgres://me@localhost/db?sslmode=disable");
+        assert_eq!(query.as_deref(), Some("SELECT id FROM docs"));
+
+        let (conn_str, query) =
+            split_postgres_url(Path::new("postgres://me@localhost/db")).unwrap();
+        assert_eq!(conn_str, "postgres://me@localhost/db");
+        assert_eq!(query, None);
+
+        assert!(
+            split_postgres_url(Path::new("postgres://me@localhost/db?sslmode=require"))
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sslmode_is_checked_before_connecting() {
+        // Nothing listens on port 1, so we'd see a connection error if we got
+        // past our `sslmode` check.
+        let url =
+            Path::new("postgres://me@127.0.0.1:1/db?sslmode=require&query=SELECT%201");
+        let Err(err) = read_postgres(url).await else {
+            panic!("expected read_postgres to fail");
+        };
+        assert!(err.to_string().contains("sslmode=require"), "{err:?}");
+
+        let err = write_postgres(url, "results", stream::empty().boxed(), None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("sslmode=require"), "{err:?}");
+    }
+
+    #[test]
+    fn test_quote_ident() {
+        assert_eq!(quote_ident("text"), "\"text\"");
+        assert_eq!(quote_ident("a\"b"), "\"a\"\"b\"");
+        assert_eq!(quote_table("public.results"), "\"public\".\"results\"");
+        assert_eq!(quote_table("x; DROP TABLE y"), "\"x; DROP TABLE y\"");
+    }
+
+    #[test]
+    fn test_upsert_sql_only_sets_present_columns() {
+        let sql = upsert_sql("\"results\"", &["id", "status"], &["id"]);
+        assert_eq!(
+            sql,
+            "INSERT INTO \"results\" (\"id\", \"status\") \
+             SELECT \"id\", \"status\" FROM jsonb_populate_recordset(NULL::\"results\", $1::text::jsonb) \
+             ON CONFLICT (\"id\") DO UPDATE SET \"status\" = EXCLUDED.\"status\""
+        );
+        assert!(upsert_sql("\"results\"", &["id"], &["id"]).ends_with("DO NOTHING"));
+        assert!(
+            upsert_sql(
+                "\"results\"",
+                &["id", "prompt_name"],
+                &["id", "prompt_name"]
+            )
+            .ends_with("ON CONFLICT (\"id\", \"prompt_name\") DO NOTHING")
+        );
+    }
+
+    #[test]
+    fn test_last_record_per_key() {
+        let batch = vec![
+            json!({ "id": 1, "status": "failed" }),
+            json!({ "id": 2 }),
+            json!({ "id": 1, "status": "ok" }),
+        ];
+        let records = last_record_per_key(&batch, &["id"]);
+        assert_eq!(
+            records,
+            vec![&json!({ "id": 1, "status": "ok" }), &json!({ "id": 2 })]
+        );
+
+        // Different prompts are kept apart.
+        let batch = vec![
+            json!({ "id": 1, "prompt_name": "a" }),
+            json!({ "id": 1, "prompt_name": "b" }),
+        ];
+        assert_eq!(last_record_per_key(&batch, &["id", "prompt_name"]).len(), 2);
+    }
+}
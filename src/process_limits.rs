@@ -0,0 +1,91 @@
+//! Resource limits for external tools like `pdftocairo` and `tesseract`.
+//!
+//! Hostile or corrupt inputs occasionally make these tools balloon in memory or
+//! spin forever. We'd rather fail the one record than have the OOM killer take
+//! out the whole process, so we can cap each child's memory and CPU time, and
+//! kill it after a wall-clock timeout.
+//!
+//! Since we forbid `unsafe` code, we can't call `setrlimit` between `fork` and
+//! `exec` ourselves. Instead, we ask `sh` to apply the limits with `ulimit`,
+//! and then `exec` the real tool.
+
+use std::{ffi::OsStr, io, process::Output, sync::OnceLock, time::Duration};
+
+use tokio::{process::Command, time};
+
+use crate::prelude::*;
+
+/// Limits applied to each external process.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessLimits {
+    /// Maximum virtual memory, in megabytes.
+    pub max_memory_mb: Option<u64>,
+
+    /// Maximum CPU time, in seconds.
+    pub max_cpu_secs: Option<u64>,
+
+    /// Maximum wall-clock time, in seconds.
+    pub timeout_secs: Option<u64>,
+}
+
+/// Our process limits, if set by command-line options.
+static PROCESS_LIMITS: OnceLock<ProcessLimits> = OnceLock::new();
+
+/// Set the limits for external processes. This must be called at most once,
+/// before any external processes are started.
+pub fn set_process_limits(limits: ProcessLimits) -> Result<()> {
+    PROCESS_LIMITS
+        .set(limits)
+        .map_err(|_| anyhow!("process limits have already been set"))
+}
+
+/// Our process limits. Defaults to no limits.
+fn process_limits() -> ProcessLimits {
+    *PROCESS_LIMITS.get_or_init(ProcessLimits::default)
+}
+
+/// Create a [`Command`] for an external tool, which will run subject to our
+/// process limits. Arguments may be added normally. Run it with
+/// [`limited_output`].
+pub fn limited_command(program: impl AsRef<OsStr>) -> Command {
+    let limits = process_limits();
+    let mut ulimits = vec![];
+    if let Some(max_memory_mb) = limits.max_memory_mb {
+        ulimits.push(format!("ulimit -v {}", max_memory_mb.saturating_mul(1024)));
+    }
+    if let Some(max_cpu_secs) = limits.max_cpu_secs {
+        ulimits.push(format!("ulimit -t {max_cpu_secs}"));
+    }
+    let mut cmd = if ulimits.is_empty() {
+        Command::new(program)
+    } else {
+        // `sh -c SCRIPT NAME ARGS...` sets `$0` to `NAME` and `$@` to `ARGS`,
+        // so our program and any later arguments end up in `$@`.
+        ulimits.push("exec \"$@\"".to_owned());
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(ulimits.join(" && "))
+            .arg("sh")
+            .arg(program);
+        cmd
+    };
+    // Make sure that timeouts (including `--record-timeout`) actually stop
+    // the process.
+    cmd.kill_on_drop(true);
+    cmd
+}
+
+/// Run a command created by [`limited_command`] and collect its output,
+/// killing it if it runs longer than `--process-timeout`.
+pub async fn limited_output(cmd: &mut Command) -> io::Result<Output> {
+    let Some(timeout_secs) = process_limits().timeout_secs else {
+        return cmd.output().await;
+    };
+    match time::timeout(Duration::from_secs(timeout_secs), cmd.output()).await {
+        Ok(output) => output,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("killed after running longer than --process-timeout {timeout_secs}s"),
+        )),
+    }
+}
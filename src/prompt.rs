@@ -1,14 +1,25 @@
 //! Our prompt data type.
 
-use std::{fmt, fs, marker::PhantomData};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt, fs,
+    marker::PhantomData,
+    sync::{Arc, Mutex, OnceLock},
+};
 
 use handlebars::{
-    Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason,
+    Context, Handlebars, Helper, HelperResult, Output, Path as HandlebarsPath,
+    RenderContext, RenderErrorReason,
+    template::{
+        HelperTemplate, Parameter, Template as HandlebarsTemplate, TemplateElement,
+    },
 };
 use handlebars_concat::HandlebarsConcat;
 use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
 use serde_json::Map;
+use sha2::{Digest as _, Sha256};
+use tokio::sync::OnceCell as AsyncOnceCell;
 use toml_span::{
     DeserError,
     de_helpers::{TableHelper, expected},
@@ -16,10 +27,32 @@ use toml_span::{
 };
 
 use crate::{
-    async_utils::io::JsonObject, data_url::data_url, page_iter::get_mime_type,
-    prelude::*, schema::Schema, toml_utils::JsonValue,
+    assertions::{ResponseAssertion, ResponseInvariant},
+    async_utils::io::{JsonObject, JsonOrTomlText},
+    data_url::data_url,
+    document::{DocumentKind, document_text},
+    input_schema::InputSchema,
+    mcp::{DEFAULT_MAX_TOOL_ROUNDS, McpServerConfig, McpTools},
+    num_utils::f64_to_u32,
+    page_iter::get_mime_type,
+    prelude::*,
+    prompt_image::{ImageSpec, PromptImage},
+    schema::{Companion, Schema, add_companion_fields},
+    toml_utils::{JsonValue, custom_deser_error, from_toml_str},
 };
 
+/// Rough number of bytes per token, used when estimating prompt size. For
+/// English, many models have around 4 bytes per token.
+pub const ESTIMATED_BYTES_PER_TOKEN: usize = 4;
+
+/// The `env` template helper may only read environment variables starting
+/// with this prefix.
+const ENV_HELPER_PREFIX: &str = "PROMPT_VAR_";
+
+/// Rough number of tokens per image, used when estimating prompt size. This
+/// varies a lot between providers, so we err on the high side.
+pub const ESTIMATED_TOKENS_PER_IMAGE: usize = 1_600;
+
 /// Super-type of allowable prompt states. This is using the popular "type
 /// state" pattern, where we use Rust types to represent allowable states and
 /// transitions for a type. This all happens at compile time, in the type
@@ -42,15 +75,110 @@ impl PromptState for Rendered {}
 #[derive(Clone, Debug, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ChatPrompt<State: PromptState = Template> {
+    /// A version label for this prompt, like `"contracts-v3"`, copied into
+    /// each output record as `prompt_version`.
+    #[serde(default)]
+    pub version: Option<String>,
+
     /// The developer (aka "system") message, if any.
     pub developer: Option<String>,
 
-    /// Our schema.
+    /// Constant values, which are available to every template as bindings.
+    /// Input fields with the same name take precedence.
+    #[serde(default)]
+    pub constants: JsonObject,
+
+    /// The input bindings this prompt expects. If present, we check the first
+    /// few input records against it before starting.
+    #[serde(default)]
+    pub input_schema: Option<InputSchema>,
+
+    /// How the model should reply: with JSON matching `response_schema`, or
+    /// with plain text.
+    #[serde(default)]
+    pub response_format: ResponseFormat,
+
+    /// Our schema. Text prompts don't need one, and always use
+    /// [`Schema::text`].
+    #[serde(default = "Schema::text")]
     pub response_schema: Schema,
 
+    /// Ask the model how confident it is in each leaf field of the response,
+    /// by adding a `{field}_confidence` number from 0 to 1 after each one.
+    #[serde(default)]
+    pub field_confidence: bool,
+
+    /// The name of an input binding containing source text. If present, we
+    /// ask the model for a `{field}_quote` from the source after each leaf
+    /// field of the response, and fail records whose quotes don't appear in
+    /// the source.
+    #[serde(default)]
+    pub cite_source: Option<String>,
+
     /// Messages.
     pub messages: Vec<Message>,
 
+    /// Text to start the assistant's reply with, like `"{"`. This is only sent
+    /// by drivers for providers which support prefilling (Anthropic models via
+    /// `native` and `bedrock`), and is ignored by other drivers.
+    #[serde(default)]
+    pub prefill: Option<String>,
+
+    /// A directory of few-shot examples, one `.toml` or `.json` file per
+    /// example, each with an `input` table of bindings and a `response`. Each
+    /// example becomes a user message (rendered from our final user message)
+    /// and an assistant message, inserted before our final user message.
+    #[serde(default)]
+    pub examples_dir: Option<PathBuf>,
+
+    /// Extra checks on each response, applied after schema validation.
+    #[serde(default, rename = "assert")]
+    pub assertions: Vec<ResponseAssertion>,
+
+    /// Checks comparing response fields to input fields, applied after
+    /// assertions. These record mismatches as warnings or failures, but never
+    /// ask the model again.
+    #[serde(default, rename = "invariant")]
+    pub invariants: Vec<ResponseInvariant>,
+
+    /// Dotted paths of response fields to remove before writing output, like
+    /// `reasoning` or `parties.notes`. These are removed after validation and
+    /// assertions. Paths through arrays apply to every item.
+    #[serde(default)]
+    pub omit_response_fields: Vec<String>,
+
+    /// MCP servers whose tools the model may call before answering. These are
+    /// started once and shared by every request using this prompt.
+    #[serde(default, rename = "mcp_server")]
+    pub mcp_servers: Vec<McpServerConfig>,
+
+    /// How many rounds of tool calls the model may make before it must answer.
+    /// Defaults to [`DEFAULT_MAX_TOOL_ROUNDS`].
+    #[serde(default)]
+    pub max_tool_rounds: Option<usize>,
+
+    /// Messages generated from `examples_dir`, loaded on first use and shared
+    /// between clones.
+    #[serde(default, skip)]
+    example_messages: Arc<OnceLock<Vec<Message>>>,
+
+    /// Images whose template is just a reference to one of our constants,
+    /// like `"{{example_data_url}}"`, keyed by constant name. These are
+    /// decoded on first use and shared between clones, so every rendered
+    /// prompt points at the same image data.
+    #[serde(default, skip)]
+    constant_images: Arc<Mutex<HashMap<String, PromptImage>>>,
+
+    /// Our running `mcp_servers`, started on first use and shared between
+    /// clones and rendered prompts.
+    #[serde(default, skip)]
+    mcp_tools: Arc<AsyncOnceCell<McpTools>>,
+
+    /// A hash of the text this prompt was parsed from, copied into each output
+    /// record as `prompt_hash`.
+    #[serde(default, skip)]
+    hash: Option<String>,
+
     /// Zero-size placeholder to keep Rust happy by using [`State`] _somewhere_
     /// in this type.
     #[serde(default, skip)]
@@ -58,6 +186,70 @@ pub struct ChatPrompt<State: PromptState = Template> {
 }
 
 impl ChatPrompt<Template> {
+    /// Read a prompt from a TOML or JSON file, or from standard input if `path`
+    /// is `-`.
+    pub async fn read(path: &Path) -> Result<Self> {
+        Self::from_text(JsonOrTomlText::read(path).await?)
+    }
+
+    /// Parse a prompt, and record a hash of its text, so that outputs from
+    /// different versions of a prompt can be told apart.
+    pub fn from_text(text: JsonOrTomlText) -> Result<Self> {
+        let digest = Sha256::digest(&text.data);
+        let mut prompt = text.parse::<Self>()?;
+        prompt.check_response_format()?;
+        prompt.hash = Some(hex::encode(&digest[..16]));
+        Ok(prompt)
+    }
+
+    /// The input fields our templates use directly, rather than through
+    /// `{{text-file-contents FIELD}}`. Returns `None` if a template might use
+    /// any field, as with `{{this}}` or a partial, so we can't tell.
+    pub fn fields_used_directly(&self) -> Result<Option<HashSet<String>>> {
+        let mut templates = vec![];
+        templates.extend(self.developer.as_deref());
+        for message in &self.messages {
+            match message {
+                Message::User { text, images } => {
+                    templates.extend(text.as_deref());
+                    templates
+                        .extend(images.iter().filter_map(|image| image.template().ok()));
+                }
+                Message::Assistant { json } => {
+                    collect_json_templates(json, &mut templates)
+                }
+            }
+        }
+
+        let mut fields = Some(HashSet::new());
+        for template in templates {
+            let template = HandlebarsTemplate::compile(template)
+                .with_context(|| format!("Could not parse template {template:?}"))?;
+            collect_template_fields(&template, &mut fields);
+        }
+        Ok(fields)
+    }
+
+    /// Make sure we have a `response_schema` if, and only if, we expect JSON.
+    fn check_response_format(&self) -> Result<()> {
+        let has_schema = self.response_schema != Schema::text();
+        match self.response_format {
+            ResponseFormat::Json if !has_schema => {
+                Err(anyhow!("Prompt must have a response_schema"))
+            }
+            ResponseFormat::Text if has_schema => Err(anyhow!(
+                "Prompt cannot have a response_schema with response_format = \"text\""
+            )),
+            ResponseFormat::Text if self.field_confidence => Err(anyhow!(
+                "Prompt cannot use field_confidence with response_format = \"text\""
+            )),
+            ResponseFormat::Text if self.cite_source.is_some() => Err(anyhow!(
+                "Prompt cannot use cite_source with response_format = \"text\""
+            )),
+            _ => Ok(()),
+        }
+    }
+
     /// Make sure our messages appear in the order ((user, assistant)*, user).
     fn validate(&self) -> Result<()> {
         if self.messages.is_empty() {
@@ -95,31 +287,294 @@ impl ChatPrompt<Template> {
         let mut handlebars = Handlebars::new();
         handlebars.register_escape_fn(|s| s.to_owned());
         handlebars.register_helper("concat", Box::new(HandlebarsConcat));
+        handlebars.register_helper("document-text", Box::new(document_text_helper));
+        handlebars.register_helper("env", Box::new(env_helper));
         handlebars.register_helper("image-data-url", Box::new(image_data_url_helper));
+        handlebars.register_helper("image-crop", Box::new(image_crop_helper));
         handlebars
             .register_helper("text-file-contents", Box::new(text_file_contents_helper));
         handlebars.register_helper("to-string", Box::new(to_string_helper));
-        self.render_template(&handlebars, bindings)
-            .context("Could not render prompt")
+        handlebars.register_helper("video-frame", Box::new(video_frame_helper));
+        if self.constants.is_empty() {
+            self.render_template(&handlebars, bindings)
+        } else {
+            let mut all_bindings = self.constants.clone();
+            all_bindings.extend(bindings.iter().map(|(k, v)| (k.clone(), v.clone())));
+            self.render_template(&handlebars, &all_bindings)
+        }
+        .context("Could not render prompt")
+    }
+}
+
+impl<State: PromptState> ChatPrompt<State> {
+    /// A hash of the text this prompt was parsed from, if we know it.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    /// The extra fields we add after each leaf field of the response, based
+    /// on `field_confidence` and `cite_source`.
+    pub fn companions(&self) -> Vec<Companion> {
+        let mut companions = vec![];
+        if self.field_confidence {
+            companions.push(Companion::Confidence);
+        }
+        if self.cite_source.is_some() {
+            companions.push(Companion::Quote);
+        }
+        companions
+    }
+
+    /// The tools offered by our `mcp_servers`, starting the servers if we
+    /// haven't already. Returns `None` if we have no servers.
+    pub async fn mcp_tools(&self) -> Result<Option<&McpTools>> {
+        if self.mcp_servers.is_empty() {
+            return Ok(None);
+        }
+        let tools = self
+            .mcp_tools
+            .get_or_try_init(|| McpTools::start(&self.mcp_servers))
+            .await?;
+        Ok(Some(tools))
+    }
+
+    /// How many rounds of tool calls the model may make before it must answer.
+    pub fn max_tool_rounds(&self) -> usize {
+        self.max_tool_rounds.unwrap_or(DEFAULT_MAX_TOOL_ROUNDS)
+    }
+
+    /// Our response schema, as JSON Schema, including any fields added by
+    /// `field_confidence` or `cite_source`.
+    pub async fn response_json_schema(&self) -> Result<Value> {
+        let mut schema = self.response_schema.to_json_schema().await?;
+        let companions = self.companions();
+        if !companions.is_empty() {
+            add_companion_fields(&mut schema, &companions);
+        }
+        Ok(schema)
+    }
+
+    /// Get the rendered messages for our `examples_dir`, loading them on first
+    /// use.
+    fn example_messages(&self, handlebars: &Handlebars) -> Result<&[Message]> {
+        let Some(examples_dir) = &self.examples_dir else {
+            return Ok(&[]);
+        };
+        if let Some(messages) = self.example_messages.get() {
+            return Ok(messages);
+        }
+        let Some(template) = self.messages.last() else {
+            return Err(anyhow!("No messages in prompt"));
+        };
+
+        let mut messages = vec![];
+        for (path, example) in load_examples(examples_dir)? {
+            let mut bindings = self.constants.clone();
+            bindings.extend(example.input);
+            let user = template
+                .render_template(handlebars, &bindings)
+                .with_context(|| format!("Could not render example {path:?}"))?;
+            messages.push(user);
+            messages.push(Message::Assistant {
+                json: example.response,
+            });
+        }
+        Ok(self.example_messages.get_or_init(|| messages))
+    }
+
+    /// Render an image. If the image's template is just a reference to one of
+    /// our constants, and `bindings` doesn't override it, reuse our shared
+    /// decoded copy instead of rendering and decoding it again.
+    fn render_image(
+        &self,
+        image: &PromptImage,
+        handlebars: &Handlebars,
+        bindings: &JsonObject,
+    ) -> Result<PromptImage> {
+        let template = image.template()?;
+        if let Some(name) = constant_reference(template)
+            && let Some(constant @ Value::String(url)) = self.constants.get(name)
+            && bindings.get(name) == Some(constant)
+        {
+            let mut constant_images = self.constant_images.lock().expect("lock poisoned");
+            let mut rendered = constant_images
+                .entry(name.to_owned())
+                .or_insert_with(|| PromptImage::from_rendered_url(url.clone()))
+                .clone();
+            rendered.detail = image.detail;
+            return Ok(rendered);
+        }
+        let url = render_template(handlebars, template, bindings)?;
+        Ok(image.rendered(url))
+    }
+}
+
+/// If `template` is just `{{name}}`, return `name`.
+fn constant_reference(template: &str) -> Option<&str> {
+    let name = template
+        .trim()
+        .strip_prefix("{{")?
+        .strip_suffix("}}")?
+        .trim();
+    let is_identifier = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    is_identifier.then_some(name)
+}
+
+/// A few-shot example, loaded from a prompt's `examples_dir`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Example {
+    /// The bindings to use when rendering the user message.
+    input: JsonObject,
+
+    /// The expected response.
+    response: Value,
+}
+
+/// Load all the examples in `dir`, sorted by file name.
+///
+/// This performs blocking I/O, but only once per prompt.
+fn load_examples(dir: &Path) -> Result<Vec<(PathBuf, Example)>> {
+    let mut paths = fs::read_dir(dir)
+        .with_context(|| format!("Could not read examples_dir {:?}", dir.display()))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    paths.retain(|path| {
+        path.extension()
+            .is_some_and(|ext| ext == "json" || ext == "toml")
+    });
+    paths.sort();
+    if paths.is_empty() {
+        return Err(anyhow!("No .json or .toml examples in {:?}", dir.display()));
+    }
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let data = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read example {path:?}"))?;
+            let value = if path.extension().is_some_and(|ext| ext == "json") {
+                serde_json::from_str::<Value>(&data)
+                    .with_context(|| format!("Could not parse example {path:?}"))?
+            } else {
+                from_toml_str::<JsonValue>(&data)
+                    .with_context(|| format!("Could not parse example {path:?}"))?
+                    .into_json()
+            };
+            let example = serde_json::from_value::<Example>(value)
+                .with_context(|| format!("Invalid example {path:?}"))?;
+            Ok((path, example))
+        })
+        .collect()
+}
+
+impl ChatPrompt<Rendered> {
+    /// Download any remote `http` or `https` images, for drivers which need
+    /// image data inline. Images larger than `max_bytes` are an error.
+    pub async fn inline_remote_images(&mut self, max_bytes: u64) -> Result<()> {
+        for message in &mut self.messages {
+            if let Message::User { images, .. } = message {
+                for image in images {
+                    image.inline_remote(max_bytes).await?;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
 impl<'de> toml_span::Deserialize<'de> for ChatPrompt<Template> {
     fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
         let mut th = TableHelper::new(value)?;
+        let version = th.optional("version");
         let developer = th.optional("developer");
-        let response_schema = th.required("response_schema")?;
+        let constants = match th.take("constants") {
+            None => JsonObject::new(),
+            Some((_, mut constants)) if constants.as_table().is_some() => {
+                match <JsonValue as toml_span::Deserialize>::deserialize(&mut constants)?
+                    .into_json()
+                {
+                    Value::Object(constants) => constants,
+                    _ => unreachable!("TOML table should convert to a JSON object"),
+                }
+            }
+            Some((_, mut constants)) => {
+                let span = constants.span;
+                return Err(
+                    expected("a table of constants", constants.take(), span).into()
+                );
+            }
+        };
+        let input_schema = th.optional("input_schema");
+        let response_format = th.optional("response_format").unwrap_or_default();
+        let response_schema = th.optional("response_schema").unwrap_or_else(Schema::text);
+        let field_confidence = th.optional("field_confidence").unwrap_or_default();
+        let cite_source = th.optional("cite_source");
         let messages = th.required("messages")?;
+        let prefill = th.optional("prefill");
+        let examples_dir = th.optional::<String>("examples_dir").map(PathBuf::from);
+        let assertions = th.optional("assert").unwrap_or_default();
+        let invariants = th.optional("invariant").unwrap_or_default();
+        let omit_response_fields =
+            th.optional("omit_response_fields").unwrap_or_default();
+        let mcp_servers = th.optional("mcp_server").unwrap_or_default();
+        let max_tool_rounds = th.optional("max_tool_rounds");
         th.finalize(None)?;
         Ok(ChatPrompt {
+            version,
             developer,
+            constants,
+            input_schema,
+            response_format,
             response_schema,
+            field_confidence,
+            cite_source,
             messages,
+            prefill,
+            examples_dir,
+            assertions,
+            invariants,
+            omit_response_fields,
+            mcp_servers,
+            max_tool_rounds,
+            example_messages: Arc::default(),
+            constant_images: Arc::default(),
+            mcp_tools: Arc::default(),
+            hash: None,
             _phantom: PhantomData,
         })
     }
 }
 
+/// How a prompt asks the model to reply.
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// JSON matching the prompt's `response_schema`.
+    #[default]
+    Json,
+
+    /// Plain text (or Markdown), stored as a JSON string. This suits tasks like
+    /// summaries and translations, where a schema adds nothing.
+    Text,
+}
+
+impl<'de> toml_span::Deserialize<'de> for ResponseFormat {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        match value.take_string(None)?.as_ref() {
+            "json" => Ok(ResponseFormat::Json),
+            "text" => Ok(ResponseFormat::Text),
+            other => Err(custom_deser_error(
+                value.span,
+                format!("Unsupported response format: {other}"),
+            )),
+        }
+    }
+}
+
 /// A message, and optionally a response (represented as a JSON object).
 ///
 /// We would also have a `State: PromptState` field here, but that interacts badly
@@ -134,18 +589,30 @@ pub enum Message {
         #[serde(default)]
         text: Option<String>,
 
-        /// Images to include with the user message, provided as URLs.
+        /// Images to include with the user message, provided as URLs, or as
+        /// tables with a `url` and options like `detail`.
         #[serde(default)]
-        images: Vec<String>,
+        #[schemars(with = "Vec<ImageSpec>")]
+        images: Vec<PromptImage>,
     },
 
     /// An assistant message.
     Assistant {
-        /// The assistant response. This is always a JSON [`Value`].
+        /// The assistant response. This is always a JSON [`Value`], but for
+        /// text prompts, it will be a string.
         json: Value,
     },
 }
 
+/// The text of an assistant response, as the model would have written it.
+/// Strings are text responses, and anything else is JSON.
+pub fn assistant_text(json: &Value) -> String {
+    match json {
+        Value::String(text) => text.clone(),
+        _ => json.to_string(),
+    }
+}
+
 impl<'de> toml_span::Deserialize<'de> for Message {
     fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
         let value_inner = value.take();
@@ -166,7 +633,12 @@ impl<'de> toml_span::Deserialize<'de> for Message {
 
             let mut th = TableHelper::new(user)?;
             let text = th.optional("text");
-            let images = th.optional::<Vec<_>>("images").unwrap_or_default();
+            let images = th
+                .optional::<Vec<ImageSpec>>("images")
+                .unwrap_or_default()
+                .into_iter()
+                .map(PromptImage::from)
+                .collect::<Vec<_>>();
             th.finalize(None)?;
             if images.is_empty() && text.is_none() {
                 return Err(expected(
@@ -224,6 +696,219 @@ fn image_data_url_helper(
     Ok(())
 }
 
+/// Handlebars helper for cropping an image and converting it to a data URL.
+///
+/// Usage: `{{image-crop path x y width height}}`. Coordinates are in pixels by
+/// default, or fractions of the image size with `units="fraction"`. Numeric
+/// strings are accepted, so coordinates can come from CSV input columns.
+fn image_crop_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    // Get our path parameter.
+    let path = h
+        .param(0)
+        .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("image-crop", 0))?
+        .value()
+        .as_str()
+        .ok_or_else(|| RenderErrorReason::InvalidParamType("string"))?;
+
+    // Get our crop region.
+    let mut region = [0.0; 4];
+    for (idx, coord) in region.iter_mut().enumerate() {
+        let value = h
+            .param(idx + 1)
+            .ok_or_else(|| {
+                RenderErrorReason::ParamNotFoundForIndex("image-crop", idx + 1)
+            })?
+            .value();
+        *coord = match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.trim().parse::<f64>().ok(),
+            _ => None,
+        }
+        .filter(|coord| coord.is_finite() && *coord >= 0.0)
+        .ok_or_else(|| {
+            RenderErrorReason::InvalidParamType("finite, non-negative number")
+        })?;
+    }
+    let fractional = match h.hash_get("units").map(|units| units.value()) {
+        None => false,
+        Some(Value::String(units)) if units == "px" => false,
+        Some(Value::String(units)) if units == "fraction" => true,
+        Some(_) => {
+            return Err(RenderErrorReason::Other(
+                "image-crop units must be \"px\" or \"fraction\"".to_owned(),
+            )
+            .into());
+        }
+    };
+
+    // Load our image.
+    let image = image::ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|err| {
+            RenderErrorReason::Other(format!("error reading {path}: {err}"))
+        })?;
+    let format = image.format();
+    let image = image.decode().map_err(|err| {
+        RenderErrorReason::Other(format!("error decoding {path}: {err}"))
+    })?;
+
+    // Convert our region to pixels, and clamp it to the image.
+    let (img_w, img_h) = (image.width(), image.height());
+    let [x, y, width, height] = if fractional {
+        let (img_w, img_h) = (f64::from(img_w), f64::from(img_h));
+        [
+            region[0] * img_w,
+            region[1] * img_h,
+            region[2] * img_w,
+            region[3] * img_h,
+        ]
+    } else {
+        region
+    };
+    let to_pixels = |value: f64, limit: u32| {
+        // We clamp before converting, so this only fails on values we rejected
+        // above.
+        f64_to_u32(value.round().min(f64::from(limit))).ok_or_else(|| {
+            RenderErrorReason::Other(format!("invalid image-crop coordinate {value}"))
+        })
+    };
+    let x = to_pixels(x, img_w)?;
+    let y = to_pixels(y, img_h)?;
+    let width = to_pixels(width, img_w - x)?;
+    let height = to_pixels(height, img_h - y)?;
+    if width == 0 || height == 0 {
+        return Err(RenderErrorReason::Other(format!(
+            "image-crop region is empty for {path} ({img_w}x{img_h})"
+        ))
+        .into());
+    }
+    let cropped = image.crop_imm(x, y, width, height);
+
+    // Re-encode. We keep JPEGs as JPEGs, because photos are much larger as
+    // PNGs, and use lossless PNG for everything else.
+    let (format, mime) = match format {
+        Some(image::ImageFormat::Jpeg) => (image::ImageFormat::Jpeg, "image/jpeg"),
+        _ => (image::ImageFormat::Png, "image/png"),
+    };
+    let mut bytes = std::io::Cursor::new(vec![]);
+    cropped.write_to(&mut bytes, format).map_err(|err| {
+        RenderErrorReason::Other(format!("error encoding {path}: {err}"))
+    })?;
+    out.write(&data_url(mime, bytes.get_ref()))?;
+    Ok(())
+}
+
+/// Handlebars helper for extracting a single video frame as a JPEG data URL.
+///
+/// Usage: `{{video-frame path seconds}}`. This runs `ffmpeg` synchronously, so
+/// for more than a handful of frames, consider the `ocr` subcommand, which
+/// samples frames using `--frame-interval`.
+fn video_frame_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    // Get our parameters.
+    let path = h
+        .param(0)
+        .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("video-frame", 0))?
+        .value()
+        .as_str()
+        .ok_or_else(|| RenderErrorReason::InvalidParamType("string"))?;
+    let seconds = match h
+        .param(1)
+        .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("video-frame", 1))?
+        .value()
+    {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+    .filter(|seconds| *seconds >= 0.0)
+    .ok_or_else(|| RenderErrorReason::InvalidParamType("non-negative number"))?;
+
+    // Ask ffmpeg to write a single JPEG frame to standard output.
+    let output = std::process::Command::new("ffmpeg")
+        .args(["-nostdin", "-hide_banner", "-loglevel", "error", "-ss"])
+        .arg(seconds.to_string())
+        .arg("-i")
+        .arg(path)
+        .args([
+            "-frames:v",
+            "1",
+            "-q:v",
+            "2",
+            "-f",
+            "image2pipe",
+            "-c:v",
+            "mjpeg",
+        ])
+        .arg("-")
+        .output()
+        .map_err(|err| {
+            RenderErrorReason::Other(format!("error running ffmpeg: {err}"))
+        })?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(RenderErrorReason::Other(format!(
+            "could not extract frame at {seconds}s from {path}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+    out.write(&data_url("image/jpeg", &output.stdout))?;
+    Ok(())
+}
+
+/// Handlebars helper for reading an environment variable.
+///
+/// Usage: `{{env "PROMPT_VAR_NAME"}}`, or `{{env "PROMPT_VAR_NAME" default="x"}}`.
+/// Only variables starting with [`ENV_HELPER_PREFIX`] may be read, so that a
+/// prompt can't leak API keys or other secrets to a model.
+fn env_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    // Get our name parameter.
+    let name = h
+        .param(0)
+        .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("env", 0))?
+        .value()
+        .as_str()
+        .ok_or_else(|| RenderErrorReason::InvalidParamType("string"))?;
+    if !name.starts_with(ENV_HELPER_PREFIX) {
+        return Err(RenderErrorReason::Other(format!(
+            "env can only read variables starting with {ENV_HELPER_PREFIX}, not {name}"
+        ))
+        .into());
+    }
+
+    // Look up the variable, falling back to our default.
+    let default = h
+        .hash_get("default")
+        .and_then(|default| default.value().as_str());
+    match (env::var(name), default) {
+        (Ok(value), _) => out.write(&value)?,
+        (Err(_), Some(default)) => out.write(default)?,
+        (Err(err), None) => {
+            return Err(
+                RenderErrorReason::Other(format!("cannot read {name}: {err}")).into(),
+            );
+        }
+    }
+    Ok(())
+}
+
 /// Handlebars helper for reading the contents of a text file and returning it
 /// as a string.
 fn text_file_contents_helper(
@@ -249,6 +934,33 @@ fn text_file_contents_helper(
     Ok(())
 }
 
+/// Handlebars helper for converting an HTML or EPUB file to Markdown-style
+/// text, with navigation and other boilerplate removed.
+fn document_text_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    // Get our path parameter.
+    let path = h
+        .param(0)
+        .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("document-text", 0))?
+        .value()
+        .as_str()
+        .ok_or_else(|| RenderErrorReason::InvalidParamType("string"))?;
+
+    // Convert the document.
+    let kind = DocumentKind::for_path(Path::new(path)).ok_or_else(|| {
+        RenderErrorReason::Other(format!("{path} is not an HTML or EPUB file"))
+    })?;
+    let text = document_text(kind, Path::new(path))
+        .map_err(|err| RenderErrorReason::Other(format!("{err:#}")))?;
+    out.write(&text)?;
+    Ok(())
+}
+
 /// Convert a value into a string, using Rust's [`std::fmt::Display`] trait.
 fn to_string_helper(
     h: &Helper,
@@ -287,31 +999,59 @@ impl RenderTemplate for ChatPrompt<Template> {
         handlebars: &Handlebars,
         bindings: &JsonObject,
     ) -> Result<Self::Output> {
+        let mut messages = self
+            .messages
+            .iter()
+            .map(|message| {
+                message.render_with_images(handlebars, bindings, |image| {
+                    self.render_image(image, handlebars, bindings)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let examples = self.example_messages(handlebars)?;
+        if !examples.is_empty() {
+            let last = messages.pop();
+            messages.extend(examples.iter().cloned());
+            messages.extend(last);
+        }
         Ok(ChatPrompt {
+            version: self.version.clone(),
             developer: self
                 .developer
                 .as_deref()
                 .map(|developer| render_template(handlebars, developer, bindings))
                 .transpose()?,
+            constants: self.constants.clone(),
+            input_schema: self.input_schema.clone(),
+            response_format: self.response_format,
             response_schema: self.response_schema.clone(),
-            messages: self
-                .messages
-                .iter()
-                .map(|message| message.render_template(handlebars, bindings))
-                .collect::<Result<Vec<_>>>()?,
+            field_confidence: self.field_confidence,
+            cite_source: self.cite_source.clone(),
+            messages,
+            prefill: self.prefill.clone(),
+            examples_dir: None,
+            assertions: self.assertions.clone(),
+            invariants: self.invariants.clone(),
+            omit_response_fields: self.omit_response_fields.clone(),
+            mcp_servers: self.mcp_servers.clone(),
+            max_tool_rounds: self.max_tool_rounds,
+            example_messages: Arc::default(),
+            constant_images: Arc::default(),
+            mcp_tools: self.mcp_tools.clone(),
+            hash: self.hash.clone(),
             _phantom: PhantomData,
         })
     }
 }
 
-impl RenderTemplate for Message {
-    type Output = Message;
-
-    fn render_template(
+impl Message {
+    /// Render this message, using `render_image` to render each image.
+    fn render_with_images(
         &self,
         handlebars: &Handlebars,
         bindings: &JsonObject,
-    ) -> Result<Self::Output> {
+        render_image: impl Fn(&PromptImage) -> Result<PromptImage>,
+    ) -> Result<Message> {
         match self {
             Message::User { text, images } => Ok(Message::User {
                 text: text
@@ -320,8 +1060,8 @@ impl RenderTemplate for Message {
                     .transpose()?,
                 images: images
                     .iter()
-                    .map(|image| render_template(handlebars, image, bindings))
-                    .collect::<Result<Vec<_>, _>>()?,
+                    .map(render_image)
+                    .collect::<Result<Vec<_>>>()?,
             }),
             Message::Assistant { json } => {
                 let json = json.render_template(handlebars, bindings)?;
@@ -331,6 +1071,21 @@ impl RenderTemplate for Message {
     }
 }
 
+impl RenderTemplate for Message {
+    type Output = Message;
+
+    fn render_template(
+        &self,
+        handlebars: &Handlebars,
+        bindings: &JsonObject,
+    ) -> Result<Self::Output> {
+        self.render_with_images(handlebars, bindings, |image| {
+            let url = render_template(handlebars, image.template()?, bindings)?;
+            Ok(image.rendered(url))
+        })
+    }
+}
+
 impl RenderTemplate for Value {
     type Output = Value;
 
@@ -376,6 +1131,104 @@ impl RenderTemplate for JsonObject {
     }
 }
 
+/// Collect every template string in `json`, including object keys.
+fn collect_json_templates<'a>(json: &'a Value, templates: &mut Vec<&'a str>) {
+    match json {
+        Value::String(s) => templates.push(s),
+        Value::Array(items) => {
+            for item in items {
+                collect_json_templates(item, templates);
+            }
+        }
+        Value::Object(obj) => {
+            for (key, value) in obj {
+                templates.push(key);
+                collect_json_templates(value, templates);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Add the fields `template` uses directly to `fields`, or set it to `None` if
+/// we can't tell which fields it uses.
+fn collect_template_fields(
+    template: &HandlebarsTemplate,
+    fields: &mut Option<HashSet<String>>,
+) {
+    for element in &template.elements {
+        collect_element_fields(element, fields);
+    }
+}
+
+/// Add the fields `element` uses directly to `fields`. See
+/// [`collect_template_fields`].
+fn collect_element_fields(
+    element: &TemplateElement,
+    fields: &mut Option<HashSet<String>>,
+) {
+    match element {
+        TemplateElement::RawString(_) | TemplateElement::Comment(_) => {}
+        TemplateElement::Expression(helper)
+        | TemplateElement::HtmlExpression(helper)
+        | TemplateElement::HelperBlock(helper) => collect_helper_fields(helper, fields),
+        // Decorators and partials can see the whole record.
+        _ => *fields = None,
+    }
+}
+
+/// Add the fields `helper` uses directly to `fields`. The path passed to
+/// `text-file-contents` doesn't count. See [`collect_template_fields`].
+fn collect_helper_fields(helper: &HelperTemplate, fields: &mut Option<HashSet<String>>) {
+    let reads_file =
+        matches!(&helper.name, Parameter::Name(name) if name == "text-file-contents");
+    if !reads_file {
+        collect_parameter_fields(&helper.name, fields);
+    }
+    for (i, param) in helper.params.iter().enumerate() {
+        if reads_file && i == 0 && matches!(param, Parameter::Path(_)) {
+            continue;
+        }
+        collect_parameter_fields(param, fields);
+    }
+    for param in helper.hash.values() {
+        collect_parameter_fields(param, fields);
+    }
+    for template in helper.template.iter().chain(&helper.inverse) {
+        collect_template_fields(template, fields);
+    }
+}
+
+/// Add the field `param` refers to, if any, to `fields`. See
+/// [`collect_template_fields`].
+fn collect_parameter_fields(param: &Parameter, fields: &mut Option<HashSet<String>>) {
+    let name = match param {
+        Parameter::Name(name) | Parameter::Path(HandlebarsPath::Relative((_, name))) => {
+            name
+        }
+        Parameter::Subexpression(subexpression) => {
+            collect_element_fields(subexpression.as_element(), fields);
+            return;
+        }
+        // Literals and local variables like `@index` aren't fields.
+        _ => return,
+    };
+    let field = name
+        .split(['.', '/'])
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    match field {
+        "" | "this" | ".." | "@root" => *fields = None,
+        field => {
+            if let Some(fields) = fields {
+                fields.insert(field.to_owned());
+            }
+        }
+    }
+}
+
 /// Render a template, returning a helpful error message if it fails.
 fn render_template(
     handlebars: &Handlebars,
@@ -395,3 +1248,206 @@ fn render_template(
             )
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn examples_dir_expands_into_messages() {
+        let prompt = from_toml_str::<ChatPrompt>(
+            &fs::read_to_string("tests/fixtures/texts/prompt_examples_dir.toml").unwrap(),
+        )
+        .unwrap();
+        let mut bindings = JsonObject::new();
+        bindings.insert(
+            "joke".to_owned(),
+            json!("Why did the chicken cross the road?"),
+        );
+        let rendered = prompt.render(&bindings).unwrap();
+
+        let summary = rendered
+            .messages
+            .iter()
+            .map(|message| match message {
+                Message::User { text, .. } => text.clone().unwrap(),
+                Message::Assistant { json } => {
+                    json["punchline"].as_str().unwrap().to_owned()
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                "Why did the scarecrow win an award?",
+                "Because he was outstanding in his field.",
+                "I’m reading a book on anti-gravity.",
+                "It’s impossible to put down.",
+                "Why did the chicken cross the road?",
+            ]
+        );
+    }
+
+    #[test]
+    fn fields_used_directly_skips_text_file_contents() {
+        let prompt = |text: &str| {
+            from_toml_str::<ChatPrompt>(&format!(
+                r#"
+developer = "You are reading a {{{{kind}}}}."
+
+[response_schema]
+description = "A summary."
+
+[response_schema.properties.summary]
+description = "The summary."
+
+[[messages]]
+user.text = '{text}'
+"#
+            ))
+            .unwrap()
+        };
+
+        let fields = prompt(
+            r#"{{text-file-contents contract}} {{#if (eq party "a")}}{{title}}{{/if}}"#,
+        )
+        .fields_used_directly()
+        .unwrap()
+        .unwrap();
+        assert!(fields.contains("kind"));
+        assert!(fields.contains("party"));
+        assert!(fields.contains("title"));
+        assert!(!fields.contains("contract"));
+
+        assert!(
+            prompt("{{text-file-contents contract}} {{contract}}")
+                .fields_used_directly()
+                .unwrap()
+                .unwrap()
+                .contains("contract")
+        );
+        assert!(prompt("{{this}}").fields_used_directly().unwrap().is_none());
+    }
+
+    #[test]
+    fn constant_images_are_decoded_once() {
+        let prompt = from_toml_str::<ChatPrompt>(&format!(
+            r#"
+constants.example_url = "{}"
+
+[response_schema]
+description = "A caption."
+
+[response_schema.properties.caption]
+description = "The caption."
+
+[[messages]]
+user.images = ["{{{{example_url}}}}", "{{{{page_url}}}}"]
+"#,
+            data_url("image/png", b"example"),
+        ))
+        .unwrap();
+        let render = |page: &[u8]| {
+            let mut bindings = JsonObject::new();
+            bindings.insert("page_url".to_owned(), json!(data_url("image/png", page)));
+            let rendered = prompt.render(&bindings).unwrap();
+            let Message::User { images, .. } = &rendered.messages[0] else {
+                panic!("expected a user message");
+            };
+            images.clone()
+        };
+        let first = render(b"page 1");
+        let second = render(b"page 2");
+
+        // The constant image is shared, and the per-page images are not.
+        assert!(std::ptr::eq(
+            first[0].data().unwrap(),
+            second[0].data().unwrap()
+        ));
+        assert_eq!(&*first[0].data().unwrap().bytes, b"example");
+        assert_eq!(&*first[1].data().unwrap().bytes, b"page 1");
+        assert_eq!(&*second[1].data().unwrap().bytes, b"page 2");
+    }
+
+    #[test]
+    fn only_json_prompts_have_schemas() {
+        let parse = |text: &str| {
+            ChatPrompt::from_text(JsonOrTomlText::new("test".to_owned(), text.to_owned()))
+        };
+        let schema = "[response_schema.properties.summary]\ntype = \"string\"\n";
+        let messages = "[[messages]]\nuser.text = \"Summarize this.\"\n";
+
+        let text = parse(&format!("response_format = \"text\"\n{messages}")).unwrap();
+        assert_eq!(text.response_format, ResponseFormat::Text);
+        assert_eq!(text.response_schema, Schema::text());
+        let json = parse(&format!("{messages}{schema}")).unwrap();
+        assert_eq!(json.response_format, ResponseFormat::Json);
+
+        assert!(parse(messages).is_err());
+        assert!(
+            parse(&format!("response_format = \"text\"\n{messages}{schema}")).is_err()
+        );
+        assert!(parse(&format!("response_format = \"xml\"\n{messages}")).is_err());
+        assert!(
+            parse(&format!(
+                "response_format = \"text\"\ncite_source = \"text\"\n{messages}"
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn mcp_servers_are_parsed() {
+        let text = r#"
+max_tool_rounds = 2
+
+[[mcp_server]]
+name = "entities"
+command = "entity-server"
+args = ["--readonly"]
+env = { ENTITY_DB = "entities.db" }
+tools = ["lookup"]
+
+[[messages]]
+user.text = "Who is {{name}}?"
+
+[response_schema.properties.entity_id]
+type = "string"
+"#;
+        let prompt = ChatPrompt::from_text(JsonOrTomlText::new(
+            "test".to_owned(),
+            text.to_owned(),
+        ))
+        .unwrap();
+        assert_eq!(prompt.max_tool_rounds(), 2);
+        let server = &prompt.mcp_servers[0];
+        assert_eq!(server.name, "entities");
+        assert_eq!(server.args, ["--readonly"]);
+        assert_eq!(server.env["ENTITY_DB"], "entities.db");
+        assert_eq!(server.tools.as_deref(), Some(&["lookup".to_owned()][..]));
+    }
+
+    #[test]
+    fn image_crop_rejects_non_finite_coordinates() {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_helper("image-crop", Box::new(image_crop_helper));
+        let render = |args: &str| {
+            handlebars.render_template(
+                &format!(
+                    "{{{{image-crop \"tests/fixtures/images/turtle.jpg\" {args}}}}}"
+                ),
+                &json!({}),
+            )
+        };
+        assert!(
+            render("0 0 10 10")
+                .unwrap()
+                .starts_with("data:image/jpeg;base64,")
+        );
+        // Huge sizes are clamped to the image.
+        assert!(render("0 0 99999999999 99999999999").is_ok());
+        assert!(render("\"inf\" 0 10 10").is_err());
+        assert!(render("0 0 \"NaN\" 10").is_err());
+        assert!(render("0 0 -10 10").is_err());
+    }
+}
@@ -0,0 +1,449 @@
+//! Images attached to prompt messages.
+//!
+//! Prompt templates produce images as `data:` URLs, which can be many
+//! megabytes each. Once a prompt is rendered, we decode each URL exactly once
+//! and share the bytes between all clones of the prompt, so retries don't
+//! need to copy or re-encode anything. Each driver then asks for the
+//! representation it needs, which is computed at most once per image.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use bytes::Bytes;
+use schemars::JsonSchema;
+use sha2::{Digest as _, Sha256};
+use tokio::sync::OnceCell;
+use toml_span::{DeserError, de_helpers::TableHelper};
+
+use crate::{data_url::parse_data_url, prelude::*, toml_utils::custom_deser_error};
+
+/// How much detail should the model see in an image? Lower detail is much
+/// cheaper. Sent as `detail` by the `openai` driver, and ignored by others.
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageDetail {
+    /// Let the model decide.
+    Auto,
+
+    /// A low-resolution version of the image, for a fixed, small number of
+    /// tokens.
+    Low,
+
+    /// The full-resolution image.
+    High,
+}
+
+impl<'de> toml_span::Deserialize<'de> for ImageDetail {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        match value.take_string(None)?.as_ref() {
+            "auto" => Ok(ImageDetail::Auto),
+            "low" => Ok(ImageDetail::Low),
+            "high" => Ok(ImageDetail::High),
+            other => Err(custom_deser_error(
+                value.span,
+                format!("Unsupported image detail: {other}"),
+            )),
+        }
+    }
+}
+
+/// An image as written in a prompt: either a URL, or a table with a URL and
+/// per-image options.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ImageSpec {
+    /// Just a URL.
+    Url(String),
+
+    /// A URL with options.
+    WithOptions(ImageOptions),
+}
+
+/// An image URL with per-image options.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ImageOptions {
+    /// The image URL. This may be a template.
+    pub url: String,
+
+    /// How much detail should the model see?
+    #[serde(default)]
+    pub detail: Option<ImageDetail>,
+}
+
+impl<'de> toml_span::Deserialize<'de> for ImageSpec {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        if value.as_str().is_some() {
+            return Ok(ImageSpec::Url(value.take_string(None)?.into_owned()));
+        }
+        let mut th = TableHelper::new(value)?;
+        let url = th.required("url")?;
+        let detail = th.optional("detail");
+        th.finalize(None)?;
+        Ok(ImageSpec::WithOptions(ImageOptions { url, detail }))
+    }
+}
+
+/// An image in a user message.
+#[derive(Clone, Deserialize)]
+#[serde(from = "ImageSpec")]
+pub struct PromptImage {
+    /// Where the image comes from.
+    source: ImageSource,
+
+    /// How much detail should the model see?
+    pub detail: Option<ImageDetail>,
+}
+
+/// The source of a [`PromptImage`].
+#[derive(Clone)]
+enum ImageSource {
+    /// An image URL. In a [`crate::prompt::Template`], this is an unrendered
+    /// template. After rendering, this is any URL other than a valid `data:`
+    /// URL.
+    Url(String),
+
+    /// Decoded image data, from a rendered `data:` URL.
+    Data(Arc<ImageData>),
+}
+
+impl PromptImage {
+    /// Create an image from a rendered URL, decoding it if it's a `data:`
+    /// URL.
+    pub fn from_rendered_url(url: String) -> Self {
+        let source = match parse_data_url(&url) {
+            Some((mime_type, base64_data)) => match BASE64_STANDARD.decode(base64_data) {
+                Ok(bytes) => {
+                    ImageSource::Data(Arc::new(ImageData::new(mime_type, bytes)))
+                }
+                // Leave it to the driver to complain about invalid data.
+                Err(_) => ImageSource::Url(url),
+            },
+            None => ImageSource::Url(url),
+        };
+        Self {
+            source,
+            detail: None,
+        }
+    }
+
+    /// Create a rendered copy of this image from `url`, keeping our options.
+    pub fn rendered(&self, url: String) -> Self {
+        Self {
+            detail: self.detail,
+            ..Self::from_rendered_url(url)
+        }
+    }
+
+    /// If we're a remote `http` or `https` image, download it (or reuse a
+    /// cached copy) and replace our URL with the image data. Images larger than
+    /// `max_bytes` are an error.
+    pub async fn inline_remote(&mut self, max_bytes: u64) -> Result<()> {
+        let ImageSource::Url(url) = &self.source else {
+            return Ok(());
+        };
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Ok(());
+        }
+        let data = remote_image_data(url, max_bytes).await?;
+        self.source = ImageSource::Data(data);
+        Ok(())
+    }
+
+    /// Get our template, if we haven't been rendered yet.
+    pub fn template(&self) -> Result<&str> {
+        match &self.source {
+            ImageSource::Url(url) => Ok(url),
+            ImageSource::Data(_) => Err(anyhow!("image has already been rendered")),
+        }
+    }
+
+    /// Get our decoded image data, if we have any.
+    pub fn data(&self) -> Option<&ImageData> {
+        match &self.source {
+            ImageSource::Url(_) => None,
+            ImageSource::Data(data) => Some(data),
+        }
+    }
+
+    /// Get our URL, encoding a `data:` URL if necessary.
+    pub fn to_url(&self) -> Arc<str> {
+        match &self.source {
+            ImageSource::Url(url) => Arc::from(url.as_str()),
+            ImageSource::Data(data) => data.data_url(),
+        }
+    }
+}
+
+impl From<ImageSpec> for PromptImage {
+    fn from(spec: ImageSpec) -> Self {
+        match spec {
+            ImageSpec::Url(url) => Self {
+                source: ImageSource::Url(url),
+                detail: None,
+            },
+            ImageSpec::WithOptions(ImageOptions { url, detail }) => Self {
+                source: ImageSource::Url(url),
+                detail,
+            },
+        }
+    }
+}
+
+impl fmt::Debug for PromptImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = match &self.source {
+            ImageSource::Url(url) => {
+                let mut f = f.debug_struct("Url");
+                f.field("url", url);
+                f
+            }
+            // Don't dump megabytes of image data into our logs.
+            ImageSource::Data(data) => {
+                let mut f = f.debug_struct("Data");
+                f.field("mime_type", &data.mime_type)
+                    .field("len", &data.bytes.len());
+                f
+            }
+        };
+        if let Some(detail) = self.detail {
+            f.field("detail", &detail);
+        }
+        f.finish()
+    }
+}
+
+/// Decoded image data, shared between all copies of a prompt.
+pub struct ImageData {
+    /// The MIME type of the image.
+    pub mime_type: String,
+
+    /// The raw image bytes. Cloning these doesn't copy them.
+    pub bytes: Bytes,
+
+    /// The Base64-encoded image, computed on first use.
+    base64: OnceLock<Arc<str>>,
+
+    /// The image as a `data:` URL, computed on first use.
+    data_url: OnceLock<Arc<str>>,
+
+    /// The SHA-256 digest of the image, computed on first use.
+    sha256: OnceLock<[u8; 32]>,
+}
+
+impl ImageData {
+    /// Create new image data from raw bytes.
+    fn new(mime_type: String, bytes: Vec<u8>) -> Self {
+        Self {
+            mime_type,
+            bytes: Bytes::from(bytes),
+            base64: OnceLock::new(),
+            data_url: OnceLock::new(),
+            sha256: OnceLock::new(),
+        }
+    }
+
+    /// Get our image as Base64, encoding it only once.
+    pub fn base64(&self) -> Arc<str> {
+        self.base64
+            .get_or_init(|| Arc::from(BASE64_STANDARD.encode(&self.bytes)))
+            .clone()
+    }
+
+    /// Get our image as a `data:` URL, encoding it only once.
+    pub fn data_url(&self) -> Arc<str> {
+        self.data_url
+            .get_or_init(|| {
+                Arc::from(format!("data:{};base64,{}", self.mime_type, self.base64()))
+            })
+            .clone()
+    }
+
+    /// Get the SHA-256 digest of our image, hashing it only once.
+    pub fn sha256(&self) -> [u8; 32] {
+        *self
+            .sha256
+            .get_or_init(|| Sha256::digest(&self.bytes).into())
+    }
+}
+
+/// Downloaded remote images, keyed by URL.
+struct RemoteImageCache {
+    /// Our images. Each URL is downloaded at most once at a time, even if
+    /// several records need it at once.
+    entries: BTreeMap<String, Arc<OnceCell<Arc<ImageData>>>>,
+
+    /// The total size of the cached images, in bytes.
+    bytes: usize,
+}
+
+/// Stop caching downloaded images once they add up to this many bytes.
+const REMOTE_IMAGE_CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Remote images we have downloaded so far.
+static REMOTE_IMAGES: Mutex<RemoteImageCache> = Mutex::new(RemoteImageCache {
+    entries: BTreeMap::new(),
+    bytes: 0,
+});
+
+/// How long to wait when connecting to a server to download an image.
+const DOWNLOAD_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to allow for downloading a single image.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Our HTTP client for downloading remote images.
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Get our HTTP client for downloading remote images, creating it if needed.
+fn http_client() -> Result<&'static reqwest::Client> {
+    if let Some(client) = HTTP_CLIENT.get() {
+        return Ok(client);
+    }
+    let client = reqwest::Client::builder()
+        .connect_timeout(DOWNLOAD_CONNECT_TIMEOUT)
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .context("cannot create HTTP client for downloading images")?;
+    Ok(HTTP_CLIENT.get_or_init(|| client))
+}
+
+/// Get the data for a remote image, downloading it if it isn't cached.
+async fn remote_image_data(url: &str, max_bytes: u64) -> Result<Arc<ImageData>> {
+    let cell = REMOTE_IMAGES
+        .lock()
+        .expect("lock poisoned")
+        .entries
+        .entry(url.to_owned())
+        .or_default()
+        .clone();
+    let mut downloaded = false;
+    let data = cell
+        .get_or_try_init(|| {
+            downloaded = true;
+            download_image(url, max_bytes)
+        })
+        .await?
+        .clone();
+
+    // Once our cache is full, stop caching new images, but keep the ones we
+    // have, which are most likely to be shared examples.
+    if downloaded {
+        let mut cache = REMOTE_IMAGES.lock().expect("lock poisoned");
+        if cache.bytes + data.bytes.len() > REMOTE_IMAGE_CACHE_BYTES {
+            cache.entries.remove(url);
+        } else {
+            cache.bytes += data.bytes.len();
+        }
+    }
+    Ok(data)
+}
+
+/// Download an image, failing if it's larger than `max_bytes`.
+#[instrument(level = "debug", skip(max_bytes))]
+async fn download_image(url: &str, max_bytes: u64) -> Result<Arc<ImageData>> {
+    let too_large = || anyhow!("image {url} is larger than {max_bytes} bytes");
+    let mut response = http_client()?
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("cannot download image {url}"))?;
+    if response.content_length().is_some_and(|len| len > max_bytes) {
+        return Err(too_large());
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_owned());
+
+    // Read the body in chunks, in case the server lied about its length.
+    let mut bytes = vec![];
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .with_context(|| format!("cannot download image {url}"))?
+    {
+        let len = u64::try_from(bytes.len() + chunk.len()).map_err(|_| too_large())?;
+        if len > max_bytes {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    // Trust the data over the server's `Content-Type`, which is often
+    // `application/octet-stream`.
+    let mime_type = infer::get(&bytes)
+        .map(|kind| kind.mime_type().to_owned())
+        .or(content_type)
+        .ok_or_else(|| anyhow!("unknown MIME type for image {url}"))?;
+    debug!(%mime_type, len = bytes.len(), "Downloaded remote image");
+    Ok(Arc::new(ImageData::new(mime_type, bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_urls_are_decoded_once() {
+        let image =
+            PromptImage::from_rendered_url("data:image/png;base64,AQID".to_owned());
+        let data = image.data().expect("should decode data URL");
+        assert_eq!(data.mime_type, "image/png");
+        assert_eq!(&data.bytes[..], &[1, 2, 3]);
+        assert!(Arc::ptr_eq(&data.base64(), &data.base64()));
+        assert_eq!(&*image.to_url(), "data:image/png;base64,AQID");
+        assert!(Arc::ptr_eq(&image.to_url(), &image.to_url()));
+
+        let clone = image.clone();
+        assert_eq!(
+            clone.data().unwrap().bytes.as_ptr(),
+            image.data().unwrap().bytes.as_ptr()
+        );
+    }
+
+    #[test]
+    fn image_options_are_kept_when_rendering() {
+        let image = serde_json::from_value::<PromptImage>(json!({
+            "url": "{{url}}",
+            "detail": "low",
+        }))
+        .unwrap();
+        assert_eq!(image.template().unwrap(), "{{url}}");
+        let rendered = image.rendered("data:image/png;base64,AQID".to_owned());
+        assert_eq!(rendered.detail, Some(ImageDetail::Low));
+        assert!(rendered.data().is_some());
+
+        let image = serde_json::from_value::<PromptImage>(json!("{{url}}")).unwrap();
+        assert_eq!(image.detail, None);
+    }
+
+    #[test]
+    fn other_urls_are_left_alone() {
+        let url = "https://example.com/image.png".to_owned();
+        let image = PromptImage::from_rendered_url(url.clone());
+        assert!(image.data().is_none());
+        assert_eq!(&*image.to_url(), url);
+    }
+
+    #[tokio::test]
+    async fn only_http_urls_are_downloaded() {
+        let url = "gs://bucket/image.png".to_owned();
+        let mut image = PromptImage::from_rendered_url(url.clone());
+        image.inline_remote(1_000).await.unwrap();
+        assert_eq!(&*image.to_url(), url);
+
+        let mut image =
+            PromptImage::from_rendered_url("data:image/png;base64,AQID".into());
+        image.inline_remote(1).await.unwrap();
+        assert_eq!(image.data().unwrap().bytes.len(), 3);
+    }
+}
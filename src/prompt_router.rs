@@ -0,0 +1,244 @@
+//! Choosing a prompt for each input record.
+//!
+//! A prompt router is a TOML or JSON file which maps the values of an input
+//! field to prompt files:
+//!
+//! ```toml
+//! field = "doc_type"
+//! default = "prompts/generic.toml"
+//!
+//! [routes]
+//! invoice = "prompts/invoice.toml"
+//! contract = "prompts/contract.toml"
+//! ```
+//!
+//! This allows a single run to use different prompts for different kinds of
+//! records, while sharing the same work queue, rate limits and output file.
+//!
+//! A router may instead hold several named prompts, from `--prompt NAME=PATH`,
+//! in which case every record is run against each of them.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{
+    async_utils::io::{JsonObject, read_json_or_toml_as_json_value},
+    prelude::*,
+    prompt::ChatPrompt,
+};
+
+/// The contents of a prompt router file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PromptRouterConfig {
+    /// The input field used to choose a prompt.
+    field: String,
+
+    /// The prompt to use when `field` is missing or has no matching route.
+    #[serde(default)]
+    default: Option<PathBuf>,
+
+    /// Prompt files, keyed by the value of `field`.
+    routes: BTreeMap<String, PathBuf>,
+}
+
+/// Chooses a prompt (or other per-prompt data) for each input record.
+#[derive(Debug)]
+pub struct PromptRouter<P = ChatPrompt> {
+    /// The input field used to choose a prompt. If this is `None`, we always
+    /// use `default`, or (if we have no default) records choose a prompt from
+    /// `routes` by name.
+    field: Option<String>,
+
+    /// The prompt to use when no route matches.
+    default: Option<P>,
+
+    /// Prompts, keyed by the value of `field`, or by name.
+    routes: BTreeMap<String, P>,
+}
+
+impl PromptRouter<ChatPrompt> {
+    /// Create a router which always uses a single prompt.
+    pub fn single(prompt: ChatPrompt) -> Self {
+        Self {
+            field: None,
+            default: Some(prompt),
+            routes: BTreeMap::new(),
+        }
+    }
+
+    /// Create a router with several named prompts. Every record should be run
+    /// against each of them, as listed by [`PromptRouter::names`].
+    pub fn named(prompts: BTreeMap<String, ChatPrompt>) -> Self {
+        Self {
+            field: None,
+            default: None,
+            routes: prompts,
+        }
+    }
+
+    /// Load a prompt router file, and all the prompts it refers to.
+    #[instrument(level = "debug")]
+    pub async fn from_path(path: &Path) -> Result<Self> {
+        let config = read_json_or_toml_as_json_value(path).await?;
+        let config = serde_json::from_value::<PromptRouterConfig>(config)
+            .with_context(|| format!("Invalid prompt router {path:?}"))?;
+        if config.routes.is_empty() {
+            return Err(anyhow!("Prompt router {path:?} has no routes"));
+        }
+        let default = match &config.default {
+            Some(prompt_path) => Some(ChatPrompt::read(prompt_path).await?),
+            None => None,
+        };
+        let mut routes = BTreeMap::new();
+        for (value, prompt_path) in config.routes {
+            routes.insert(value, ChatPrompt::read(&prompt_path).await?);
+        }
+        Ok(Self {
+            field: Some(config.field),
+            default,
+            routes,
+        })
+    }
+
+    /// The input fields our prompts and routes use directly, rather than
+    /// through `{{text-file-contents FIELD}}`. Returns `None` if we can't
+    /// tell. See [`ChatPrompt::fields_used_directly`].
+    pub fn fields_used_directly(&self) -> Result<Option<HashSet<String>>> {
+        let mut fields = self.field.iter().cloned().collect::<HashSet<_>>();
+        for prompt in self.prompts() {
+            match prompt.fields_used_directly()? {
+                Some(used) => fields.extend(used),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(fields))
+    }
+}
+
+impl<P> PromptRouter<P> {
+    /// Iterate over all our prompts.
+    pub fn prompts(&self) -> impl Iterator<Item = &P> {
+        self.default.iter().chain(self.routes.values())
+    }
+
+    /// Asynchronously convert each of our prompts into something else.
+    pub async fn try_map<Q, F, Fut>(self, mut f: F) -> Result<PromptRouter<Q>>
+    where
+        F: FnMut(P) -> Fut,
+        Fut: Future<Output = Result<Q>>,
+    {
+        let default = match self.default {
+            Some(prompt) => Some(f(prompt).await?),
+            None => None,
+        };
+        let mut routes = BTreeMap::new();
+        for (value, prompt) in self.routes {
+            routes.insert(value, f(prompt).await?);
+        }
+        Ok(PromptRouter {
+            field: self.field,
+            default,
+            routes,
+        })
+    }
+
+    /// If every record should be run against each of several named prompts,
+    /// list their names.
+    pub fn names(&self) -> Option<Vec<String>> {
+        (self.field.is_none() && self.default.is_none())
+            .then(|| self.routes.keys().cloned().collect())
+    }
+
+    /// Choose the prompt for a record. Records which have been fanned out to
+    /// several named prompts give us a `name`, and others are routed based on
+    /// their template bindings.
+    pub fn route_named(&self, name: Option<&str>, bindings: &JsonObject) -> Result<&P> {
+        match name {
+            Some(name) => self
+                .routes
+                .get(name)
+                .filter(|_| self.field.is_none())
+                .ok_or_else(|| anyhow!("No prompt named {name:?}")),
+            None => self.route(bindings),
+        }
+    }
+
+    /// Choose the prompt for a record, based on its template bindings.
+    pub fn route(&self, bindings: &JsonObject) -> Result<&P> {
+        let Some(field) = &self.field else {
+            return self
+                .default
+                .as_ref()
+                .ok_or_else(|| anyhow!("No prompt available"));
+        };
+        let value = match bindings.get(field) {
+            None | Some(Value::Null) => None,
+            Some(Value::String(s)) => Some(s.to_owned()),
+            Some(other) => Some(other.to_string()),
+        };
+        value
+            .as_ref()
+            .and_then(|value| self.routes.get(value))
+            .or(self.default.as_ref())
+            .ok_or_else(|| {
+                anyhow!("No prompt route for {field} = {value:?}, and no default prompt")
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        let router = PromptRouter {
+            field: Some("doc_type".to_owned()),
+            default: Some("generic"),
+            routes: BTreeMap::from([
+                ("invoice".to_owned(), "invoice"),
+                ("1".to_owned(), "one"),
+            ]),
+        };
+        let bindings =
+            |value: Value| json!({ "doc_type": value }).as_object().unwrap().to_owned();
+        assert_eq!(
+            *router.route(&bindings(json!("invoice"))).unwrap(),
+            "invoice"
+        );
+        assert_eq!(*router.route(&bindings(json!(1))).unwrap(), "one");
+        assert_eq!(*router.route(&bindings(json!("memo"))).unwrap(), "generic");
+        assert_eq!(*router.route(&JsonObject::new()).unwrap(), "generic");
+
+        let router = PromptRouter {
+            default: None,
+            ..router
+        };
+        assert!(router.route(&bindings(json!("memo"))).is_err());
+        assert!(router.names().is_none());
+        assert!(
+            router
+                .route_named(Some("invoice"), &JsonObject::new())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_route_named() {
+        let router = PromptRouter {
+            field: None,
+            default: None,
+            routes: BTreeMap::from([
+                ("a".to_owned(), "first"),
+                ("b".to_owned(), "second"),
+            ]),
+        };
+        assert_eq!(router.names(), Some(vec!["a".to_owned(), "b".to_owned()]));
+        assert_eq!(
+            *router.route_named(Some("b"), &JsonObject::new()).unwrap(),
+            "second"
+        );
+        assert!(router.route_named(Some("c"), &JsonObject::new()).is_err());
+        assert!(router.route_named(None, &JsonObject::new()).is_err());
+    }
+}
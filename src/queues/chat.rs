@@ -1,25 +1,80 @@
 //! Concurrent chat requests implemented as an async stream.
 
-use std::{iter, sync::Arc};
+use std::{
+    collections::HashMap,
+    iter,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
-use futures::FutureExt as _;
-use keen_retry::{ExponentialJitter, ResolvedResult};
-use leaky_bucket::RateLimiter;
+use futures::{FutureExt as _, StreamExt as _};
+use keen_retry::{ExponentialJitter, ResolvedResult, RetryResult};
 use schemars::JsonSchema;
+use tempfile::TempPath;
 
-use super::work::{WorkInput, WorkOutput, WorkQueue, WorkStatus};
+use super::work::{
+    RecordLimits, WorkInput, WorkOutput, WorkOutputCounters, WorkQueue, WorkStatus,
+};
 use crate::{
-    async_utils::{BoxedFuture, BoxedStream, JoinWorker, io::JsonObject},
-    drivers::{ChatCompletionResponse, Driver, LlmOpts, LlmRetryResult, TokenUsage},
+    assertions::{CompiledAssertion, OnAssertionFailure, OnInvariantFailure},
+    async_utils::{
+        BoxedFuture, BoxedStream, JoinWorker,
+        io::{JsonObject, OutputAck, read_json_or_toml_as_json_value, write_output_csv},
+    },
+    cmd::StreamOpts,
+    drivers::{
+        ChatCompletionResponse, ContentFilterError, Driver, LlmOpts, LlmRetryResult,
+        OnCostCap, RawResponse, TokenUsage,
+    },
+    latency::record_total,
     litellm::{LiteLlmModel, litellm_model_info},
+    model_weights::ModelWeights,
+    num_utils::{f64_to_u32, usize_to_f64},
+    payload_limits::PayloadLimits,
     prelude::*,
-    prompt::{ChatPrompt, Rendered},
-    retry::{retry_result_ok, retry_with_backoff, try_retry_result, try_transient},
+    prompt::{ChatPrompt, ESTIMATED_BYTES_PER_TOKEN, Rendered, ResponseFormat},
+    prompt_router::PromptRouter,
+    rate_limit::RequestLimiter,
+    retry::{
+        retry_result_ok, retry_with_backoff, try_fatal, try_retry_result, try_transient,
+    },
+    schema::{
+        Companion, add_companion_fields,
+        compat::{SchemaRules, SchemaTransforms},
+        low_confidence_fields, order_like_schema, unsupported_quotes,
+    },
+    tokens::TokenCounter,
+    ui::Ui,
 };
 
 /// An input record.
 #[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct ChatInput {
+    /// A response schema to use for this record, overriding the prompt's
+    /// schema. This may be a path to a JSON or TOML schema file, a JSON Schema
+    /// object, or a string containing a JSON Schema object.
+    #[serde(default)]
+    pub response_schema: Option<Value>,
+
+    /// The name of the prompt to use for this record, when each record is run
+    /// against several `--prompt NAME=PATH` prompts.
+    #[serde(skip)]
+    pub prompt_name: Option<String>,
+
+    /// A problem found while preparing this record, such as an email we
+    /// couldn't read. If present, the record fails without being sent.
+    #[serde(skip)]
+    pub input_error: Option<String>,
+
+    /// Temporary files holding fields moved out of `template_bindings` by
+    /// `--offload-fields-over`, or email attachments from `--email-input`.
+    /// Each is deleted once we're done with every copy of this record.
+    #[serde(skip)]
+    pub offloaded_files: Vec<Arc<TempPath>>,
+
     /// Other fields. We keep these "flattened" in the record because they're
     /// under the control of the caller, and because our input format may be a
     /// CSV file, which is inherently "flat".
@@ -28,17 +83,68 @@ pub struct ChatInput {
 }
 
 /// An output record.
-#[derive(Clone, Debug, JsonSchema, Serialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 pub struct ChatOutput {
+    /// The name of the prompt used for this record, when each record is run
+    /// against several `--prompt NAME=PATH` prompts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_name: Option<String>,
+
+    /// The model which served this record, when `--model-weights` chooses
+    /// between several models.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    /// Which model in our fallback chain served this record, when using
+    /// `--fallback-model`. The main model is 0, and the first fallback is 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_position: Option<usize>,
+
+    /// How many extra requests `--hedge-after` sent for this record, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hedged_requests: Option<usize>,
+
+    /// Input tokens sent in requests which `--hedge-after` cancelled, if any.
+    /// Providers usually bill for these, but they aren't part of
+    /// `token_usage`, which only covers the request we used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hedged_prompt_tokens: Option<u64>,
+
     /// The response from the LLM. If this is present, the request succeeded.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub response: Option<Value>,
+
+    /// The provider's fingerprint of the backend configuration which generated
+    /// `response`, if available. Useful when trying to reproduce a run with
+    /// `--seed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+
+    /// The mean log probability of the tokens in `response`, if `--logprobs`
+    /// was specified. Values closer to 0.0 indicate a more confident response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mean_logprob: Option<f64>,
+
+    /// The provider's raw response, if `--include-raw-response` was specified.
+    /// For failed records, this is the last response we received.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_response: Option<RawResponse>,
 }
 
 impl ChatOutput {
     /// Create an empty chat output record for use when an error occurs.
     pub fn empty_for_error() -> Self {
-        Self { response: None }
+        Self {
+            prompt_name: None,
+            model: None,
+            fallback_position: None,
+            hedged_requests: None,
+            hedged_prompt_tokens: None,
+            response: None,
+            system_fingerprint: None,
+            mean_logprob: None,
+            raw_response: None,
+        }
     }
 }
 
@@ -59,6 +165,9 @@ impl WorkOutput<ChatOutput> {
                     ChatCompletionResponse {
                         response,
                         token_usage,
+                        system_fingerprint,
+                        mean_logprob,
+                        raw_response,
                     },
                 ..
             } => WorkOutput {
@@ -68,8 +177,20 @@ impl WorkOutput<ChatOutput> {
                 estimated_cost: estimate_cost(token_usage.as_ref()),
                 token_usage,
                 passthrough_data,
+                ack_token: None,
+                prompt_version: None,
+                prompt_hash: None,
+                model: None,
                 data: ChatOutput {
+                    prompt_name: None,
+                    model: None,
+                    fallback_position: None,
+                    hedged_requests: None,
+                    hedged_prompt_tokens: None,
                     response: Some(response),
+                    system_fingerprint,
+                    mean_logprob,
+                    raw_response,
                 },
             },
             ResolvedResult::Fatal { error, .. } => WorkOutput::new_failed(
@@ -83,6 +204,9 @@ impl WorkOutput<ChatOutput> {
                     ChatCompletionResponse {
                         response,
                         token_usage,
+                        system_fingerprint,
+                        mean_logprob,
+                        raw_response,
                     },
                 retry_errors,
                 ..
@@ -93,8 +217,20 @@ impl WorkOutput<ChatOutput> {
                 estimated_cost: estimate_cost(token_usage.as_ref()),
                 token_usage,
                 passthrough_data,
+                ack_token: None,
+                prompt_version: None,
+                prompt_hash: None,
+                model: None,
                 data: ChatOutput {
+                    prompt_name: None,
+                    model: None,
+                    fallback_position: None,
+                    hedged_requests: None,
+                    hedged_prompt_tokens: None,
                     response: Some(response),
+                    system_fingerprint,
+                    mean_logprob,
+                    raw_response,
                 },
             },
             ResolvedResult::GivenUp {
@@ -118,6 +254,72 @@ impl WorkOutput<ChatOutput> {
             ),
         }
     }
+
+    /// Convert this output record to a flat version for CSV output. This only
+    /// makes sense for `classify` prompts.
+    fn to_flat(&self) -> FlatChatOutput {
+        FlatChatOutput {
+            id: if let Value::String(id) = &self.id {
+                id.clone()
+            } else {
+                serde_json::to_string(&self.id).expect("failed to convert ID to string")
+            },
+            status: self.status,
+            errors: if self.errors.is_empty() {
+                None
+            } else {
+                Some(self.errors.join("\n\n"))
+            },
+            label: self
+                .data
+                .response
+                .as_ref()
+                .and_then(|response| response["label"].as_str())
+                .map(str::to_owned),
+        }
+    }
+
+    /// Write a stream of outputs to a [`Path`] or to standard output.
+    pub async fn write_stream_to_csv(
+        ui: &Ui,
+        path: Option<&Path>,
+        stream: BoxedStream<Result<Self>>,
+        stream_opts: &StreamOpts,
+        ack: Option<&dyn OutputAck>,
+    ) -> Result<()> {
+        if stream_opts.partition_by.is_some() {
+            return Err(anyhow!("--partition-by only supports JSONL output"));
+        }
+        let (stream, counters) = WorkOutputCounters::wrap_stream(ui, stream);
+        let output = stream.map(|output| Ok(output?.to_flat())).boxed();
+        write_output_csv(
+            path,
+            stream_opts.compress,
+            output,
+            ack,
+            stream_opts.write_opts(),
+        )
+        .await?;
+        counters.finish(ui, stream_opts)
+    }
+}
+
+/// Flat version of [`WorkOutput<ChatOutput>`], for CSV output from `classify`
+/// prompts.
+#[derive(Clone, Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct FlatChatOutput {
+    /// The ID of the input record.
+    pub id: String,
+
+    /// The status of the output record.
+    pub status: WorkStatus,
+
+    /// Any errors that occurred during processing.
+    pub errors: Option<String>,
+
+    /// The label chosen by the model.
+    pub label: Option<String>,
 }
 
 /// Return value of [`process_chat_stream`].
@@ -126,22 +328,29 @@ pub struct ChatStreamInfo {
     pub worker: JoinWorker,
 }
 
-/// Process a stream of input records, using `prompt` and `model` to generate
+/// Process a stream of input records, using `prompts` and `models` to generate
 /// responses.
 ///
 /// We take our arguments by value, not reference, because we'll need to hold
 /// onto them while we process the stream.
-#[instrument(level = "debug", skip(input, prompt))]
+#[instrument(level = "debug", skip(input, prompts))]
 pub async fn process_chat_stream(
     concurrency_limit: usize,
     input: BoxedStream<Result<WorkInput<ChatInput>>>,
-    prompt: ChatPrompt,
-    model: String,
+    prompts: PromptRouter,
+    models: ModelWeights,
     llm_opts: LlmOpts,
+    record_limits: RecordLimits,
 ) -> Result<ChatStreamInfo> {
     // Create our work queue.
-    let (queue, worker) =
-        create_chat_work_queue(concurrency_limit, prompt, model, llm_opts).await?;
+    let (queue, worker) = create_chat_work_queue(
+        concurrency_limit,
+        prompts,
+        models,
+        llm_opts,
+        record_limits,
+    )
+    .await?;
     let handle = queue.handle();
     Ok(ChatStreamInfo {
         stream: handle.process_stream(input).await,
@@ -149,54 +358,176 @@ pub async fn process_chat_stream(
     })
 }
 
-/// Make a [`WorkQueue`] that handles chats.
+/// Make a [`WorkQueue`] that handles chats, applying `record_limits` to each
+/// record. Each record is sent to a model chosen at random from `models`.
 pub async fn create_chat_work_queue(
     concurrency_limit: usize,
-    prompt: ChatPrompt,
-    model: String,
+    prompts: PromptRouter,
+    models: ModelWeights,
     llm_opts: LlmOpts,
+    record_limits: RecordLimits,
 ) -> Result<(WorkQueue<ChatInput, ChatOutput>, JoinWorker)> {
     // Create our OpenAI client.
-    let driver = llm_opts.driver.create_driver().await?;
+    let driver = llm_opts.driver.create_driver(&llm_opts).await?;
 
-    // See if we can get LiteLLM info for this model.
-    let model_info = litellm_model_info(&model).await;
-    if let Some(model_info) = model_info {
-        debug!(model_info = %model_info.to_string(), "Model info");
-    } else {
-        debug!(model = %model, "Model info not available");
+    // Look up what we know about each of our models.
+    let mut model_states = vec![];
+    for model in models.models() {
+        model_states.push(Arc::new(ModelState::new(&llm_opts, model).await?));
+    }
+    let mut fallback_models = vec![];
+    for model in &llm_opts.fallback_models {
+        fallback_models.push(Arc::new(ModelState::new(&llm_opts, model).await?));
     }
 
-    // Read our schema.
+    // Read the schema for each of our prompts.
     //
     // TODO: Make sure `description` fields are present?
-    let schema = prompt.response_schema.to_json_schema().await?;
-    debug!(%schema, "Schema");
-    let validator = jsonschema::validator_for(&schema)?;
+    let prompts = prompts
+        .try_map(|prompt| async move {
+            let schema = prompt.response_json_schema().await?;
+            debug!(%schema, "Schema");
+            let schema = Arc::new(ResponseSchema::new(schema)?);
+            let assertions = prompt
+                .assertions
+                .iter()
+                .map(CompiledAssertion::new)
+                .collect::<Result<Arc<[_]>>>()?;
+            let omit_fields = prompt.omit_response_fields.iter().cloned().collect();
+            Ok(RoutedPrompt {
+                prompt,
+                schema,
+                assertions,
+                omit_fields,
+            })
+        })
+        .await?;
+
+    // Make sure every model will accept each prompt's schema, before we send
+    // any requests.
+    for routed in prompts.prompts() {
+        if routed.prompt.response_format != ResponseFormat::Json {
+            continue;
+        }
+        for model in model_states.iter().chain(&fallback_models) {
+            model
+                .provider_schema(&routed.schema.schema)
+                .with_context(|| {
+                    format!(
+                        "Response schema won't work with {} (use --skip-schema-checks \
+                     to try anyway)",
+                        model.name
+                    )
+                })?;
+        }
+    }
+
+    // We can only enforce `--max-cost-per-record` if we know each model's
+    // prices, and how long its responses might be.
+    if llm_opts.max_cost_per_record.is_some() {
+        for model in model_states.iter().chain(&fallback_models) {
+            let Some(info) = model.info else {
+                return Err(anyhow!(
+                    "--max-cost-per-record needs pricing from LiteLLM, but we have none for {}",
+                    model.name
+                ));
+            };
+            if llm_opts.on_cost_cap == OnCostCap::Skip
+                && llm_opts.max_completion_tokens.is_none()
+                && info.model_info.max_output_tokens.is_none()
+                && info.model_info.output_cost_per_token > 0.0
+            {
+                return Err(anyhow!(
+                    "--max-cost-per-record can't estimate response costs for {}, because LiteLLM doesn't know its maximum output tokens (pass --max-completion-tokens or --on-cost-cap clip)",
+                    model.name
+                ));
+            }
+        }
+    }
+
+    llm_opts.warn_about_unsupported_options();
+    if llm_opts.min_confidence.is_some()
+        && !prompts
+            .prompts()
+            .any(|routed| routed.prompt.field_confidence)
+    {
+        warn!("--min-confidence has no effect without field_confidence = true");
+    }
 
     // Construct a rate limiter to control the rate of API requests.
-    let rate_limiter = llm_opts.rate_limit.as_ref().map(|rl| rl.to_rate_limiter());
+    let rate_limiter = match &llm_opts.rate_limit {
+        Some(rate_limit) => Some(
+            rate_limit
+                .to_request_limiter(
+                    llm_opts.rate_limit_backend.as_ref(),
+                    &format!("{}/{models}", llm_opts.driver),
+                )
+                .await?,
+        ),
+        None => None,
+    };
 
     // Build our shared state.
     let state = Arc::new(ProcessorState {
         driver,
         rate_limiter,
-        model,
-        prompt,
-        schema,
-        validator,
+        models: model_states,
+        model_weights: models,
+        fallback_models,
+        prompts,
+        record_schemas: Mutex::new(HashMap::new()),
         llm_opts,
-        model_info,
     });
 
     // Define worker function.
-    let work_fn = move |input| {
+    let cleanup_state = state.clone();
+    let work_fn = move |input: WorkInput<ChatInput>| {
         let state = state.clone();
-        run_chat(state, input).boxed()
+        // Only measure our input if we need to.
+        let input_bytes = record_limits.max_bytes.map(|_| {
+            serde_json::to_vec(&input.data.template_bindings)
+                .map_or(0, |json| json.len() as u64)
+        });
+        let failed = WorkOutput::new_failed(
+            input.id.clone(),
+            vec![],
+            ChatOutput::empty_for_error(),
+            input.passthrough_data.clone(),
+        );
+        async move {
+            let model_idx = state.model_weights.choose(&input.id, state.llm_opts.seed);
+            let model = state.models[model_idx].clone();
+            // Look up our prompt now, because `run_chat` consumes our bindings.
+            let prompt_name = input.data.prompt_name.clone();
+            let routed = state
+                .prompts
+                .route_named(prompt_name.as_deref(), &input.data.template_bindings)
+                .ok();
+            let mut output = record_limits
+                .run(
+                    input_bytes,
+                    failed,
+                    run_chat(state.clone(), model.clone(), input),
+                )
+                .await?;
+            // Records which never reached a model (because they were skipped
+            // or timed out, say) are still counted against the one we chose.
+            if output.model.is_none() {
+                state.set_model(&mut output, &model, 0);
+            }
+            if let Some(routed) = routed {
+                output.set_prompt(&routed.prompt);
+            }
+            output.data.prompt_name = prompt_name;
+            Ok(output)
+        }
+        .boxed()
     };
 
-    // Create our work queue.
-    WorkQueue::new(concurrency_limit, Arc::new(work_fn))
+    // Create our work queue, and let our driver clean up once it's done.
+    let (queue, worker) = WorkQueue::new(concurrency_limit, Arc::new(work_fn))?;
+    let worker = worker.and_then(async move { cleanup_state.driver.cleanup().await });
+    Ok((queue, worker))
 }
 
 /// Shared processor state.
@@ -206,31 +537,226 @@ struct ProcessorState {
     driver: Box<dyn Driver>,
 
     /// A rate limiter to control API request rate.
-    rate_limiter: Option<RateLimiter>,
+    rate_limiter: Option<Arc<RequestLimiter>>,
+
+    /// The models to use.
+    models: Vec<Arc<ModelState>>,
+
+    /// How often to use each of `models`.
+    model_weights: ModelWeights,
 
-    /// The model to use.
-    model: String,
+    /// Models to try in turn when a record fails on the model we chose.
+    fallback_models: Vec<Arc<ModelState>>,
 
-    /// The prompt to use.
+    /// The prompts to use.
+    prompts: PromptRouter<RoutedPrompt>,
+
+    /// Response schemas specified by individual input records, keyed by the
+    /// path or JSON text used to specify them.
+    record_schemas: Mutex<HashMap<String, Arc<ResponseSchema>>>,
+
+    /// The LLM options to use.
+    llm_opts: LlmOpts,
+}
+
+/// A model we can send requests to, and what we know about it.
+#[derive(Debug)]
+struct ModelState {
+    /// The name of the model.
+    name: String,
+
+    /// Model information, if available.
+    info: Option<&'static LiteLlmModel>,
+
+    /// Our provider's request size limits, if we know them.
+    payload_limits: Option<PayloadLimits>,
+
+    /// Our provider's structured output rules, if we know them.
+    schema_rules: Option<SchemaRules>,
+
+    /// Changes to make to response schemas for our provider.
+    schema_transforms: SchemaTransforms,
+
+    /// Counts prompt tokens for this model, exactly if we have its tokenizer.
+    token_counter: TokenCounter,
+}
+
+impl ModelState {
+    /// Look up LiteLLM info and request size limits for `name`.
+    async fn new(llm_opts: &LlmOpts, name: &str) -> Result<Self> {
+        // See if we can get LiteLLM info for this model.
+        let info = litellm_model_info(name).await;
+        if let Some(info) = info {
+            debug!(model_info = %info.to_string(), "Model info");
+        } else {
+            debug!(model = %name, "Model info not available");
+        }
+
+        // Look up our provider's request size limits.
+        let payload_limits = if llm_opts.skip_payload_checks {
+            None
+        } else {
+            PayloadLimits::for_model(llm_opts.driver, name, info)
+        };
+
+        // Look up our provider's structured output rules.
+        let schema_rules = if llm_opts.skip_schema_checks {
+            None
+        } else {
+            SchemaRules::for_model(llm_opts.driver, name, info)
+        };
+        let schema_transforms = SchemaTransforms::for_model(llm_opts.driver, name, info);
+
+        Ok(Self {
+            name: name.to_owned(),
+            info,
+            payload_limits,
+            schema_rules,
+            schema_transforms,
+            token_counter: TokenCounter::new(name)?,
+        })
+    }
+
+    /// Rewrite `schema` for our provider, and make sure the provider will
+    /// accept the result.
+    fn provider_schema(&self, schema: &Value) -> Result<Value> {
+        let schema = self.schema_transforms.apply(schema)?;
+        if let Some(rules) = &self.schema_rules {
+            rules.check(&schema)?;
+        }
+        Ok(schema)
+    }
+}
+
+impl ProcessorState {
+    /// Record which model served a record, and its position in our chain of
+    /// fallback models.
+    fn set_model(
+        &self,
+        output: &mut WorkOutput<ChatOutput>,
+        model: &ModelState,
+        fallback_position: usize,
+    ) {
+        output.model = Some(model.name.clone());
+        if !self.model_weights.is_single() || !self.fallback_models.is_empty() {
+            output.data.model = Some(model.name.clone());
+        }
+        if !self.fallback_models.is_empty() {
+            output.data.fallback_position = Some(fallback_position);
+        }
+    }
+
+    /// If `result` failed in a way which another model might handle, return
+    /// the error.
+    fn fallback_error<'result>(
+        &self,
+        result: &'result ResolvedResult<(), (), ChatCompletionResponse, anyhow::Error>,
+    ) -> Option<&'result anyhow::Error> {
+        let error = match result {
+            ResolvedResult::Ok { .. } | ResolvedResult::Recovered { .. } => return None,
+            ResolvedResult::Fatal { error, .. } => error,
+            ResolvedResult::GivenUp { fatal_error, .. }
+            | ResolvedResult::Unrecoverable { fatal_error, .. } => fatal_error,
+        };
+        let filtered = error.downcast_ref::<ContentFilterError>().is_some();
+        (!filtered || self.llm_opts.fallback_on_content_filter).then_some(error)
+    }
+
+    /// Look up the response schema to use for a record, falling back to
+    /// `default` if the record doesn't specify one. Add any `companions` to
+    /// the record's schema, as we did for the prompt's.
+    async fn response_schema_for(
+        &self,
+        default: &Arc<ResponseSchema>,
+        record_schema: Option<&Value>,
+        companions: &[Companion],
+    ) -> Result<Arc<ResponseSchema>> {
+        // Missing values and empty CSV cells mean "use the prompt's schema".
+        let Some(spec) = record_schema.filter(|spec| match spec {
+            Value::Null => false,
+            Value::String(s) => !s.trim().is_empty(),
+            _ => true,
+        }) else {
+            return Ok(default.clone());
+        };
+        let mut key = match spec {
+            Value::String(s) => s.to_owned(),
+            other => other.to_string(),
+        };
+        if !companions.is_empty() {
+            key.insert_str(0, &format!("{companions:?}:"));
+        }
+        if let Some(schema) = self.record_schemas.lock().expect("lock poisoned").get(&key)
+        {
+            return Ok(schema.clone());
+        }
+
+        // We don't hold our lock while loading, so we may occasionally load
+        // the same schema twice. This is harmless.
+        let mut schema = match spec {
+            Value::String(s) if s.trim_start().starts_with('{') => {
+                serde_json::from_str(s).context("Failed to parse response_schema")?
+            }
+            Value::String(path) => {
+                read_json_or_toml_as_json_value(Path::new(path)).await?
+            }
+            other => other.clone(),
+        };
+        if !companions.is_empty() {
+            add_companion_fields(&mut schema, companions);
+        }
+        debug!(%schema, "Record schema");
+        let schema = Arc::new(
+            ResponseSchema::new(schema)
+                .with_context(|| format!("Invalid response_schema {key:?}"))?,
+        );
+        self.record_schemas
+            .lock()
+            .expect("lock poisoned")
+            .insert(key, schema.clone());
+        Ok(schema)
+    }
+}
+
+/// A prompt, plus its response schema and assertions.
+#[derive(Debug)]
+struct RoutedPrompt {
+    /// The prompt.
     prompt: ChatPrompt,
 
+    /// The prompt's response schema.
+    schema: Arc<ResponseSchema>,
+
+    /// The prompt's response assertions, compiled.
+    assertions: Arc<[CompiledAssertion]>,
+
+    /// Dotted paths of response fields to remove before output.
+    omit_fields: Arc<[String]>,
+}
+
+/// A JSON Schema for responses, plus a validator.
+#[derive(Debug)]
+struct ResponseSchema {
     /// Our JSON Schema.
     schema: Value,
 
     /// Our JSON Schema validator.
     validator: jsonschema::Validator,
+}
 
-    /// The LLM options to use.
-    llm_opts: LlmOpts,
-
-    /// Model information, if available.
-    model_info: Option<&'static LiteLlmModel>,
+impl ResponseSchema {
+    /// Create a new response schema, compiling a validator.
+    fn new(schema: Value) -> Result<Self> {
+        let validator = jsonschema::validator_for(&schema)?;
+        Ok(Self { schema, validator })
+    }
 }
 
 /// Process a single JSON Object.
 #[instrument(level = "debug", skip_all, fields(id = %input_record.id))]
 async fn run_chat(
     state: Arc<ProcessorState>,
+    model: Arc<ModelState>,
     mut input_record: WorkInput<ChatInput>,
 ) -> Result<WorkOutput<ChatOutput>> {
     let id = input_record.id.clone();
@@ -246,20 +772,290 @@ async fn run_chat(
             estimated_cost: None,
             token_usage: None,
             passthrough_data,
-            data: ChatOutput { response: None },
+            ack_token: None,
+            prompt_version: None,
+            prompt_hash: None,
+            model: None,
+            data: ChatOutput {
+                prompt_name: None,
+                model: None,
+                fallback_position: None,
+                hedged_requests: None,
+                hedged_prompt_tokens: None,
+                response: None,
+                system_fingerprint: None,
+                mean_logprob: None,
+                raw_response: None,
+            },
         });
     }
 
-    // Render our prompt.
+    // Fail any record which we couldn't prepare.
+    if let Some(err) = input_record.data.input_error.take() {
+        return Ok(WorkOutput::new_failed(
+            id,
+            vec![err],
+            ChatOutput::empty_for_error(),
+            passthrough_data,
+        ));
+    }
+
+    // Choose our prompt and response schema. If a record has no matching
+    // prompt or specifies a bad schema, we only fail that record.
+    let choice = match state.prompts.route_named(
+        input_record.data.prompt_name.as_deref(),
+        &input_record.data.template_bindings,
+    ) {
+        Ok(routed) => state
+            .response_schema_for(
+                &routed.schema,
+                input_record.data.response_schema.as_ref(),
+                &routed.prompt.companions(),
+            )
+            .await
+            .map(|schema| (routed, schema)),
+        Err(err) => Err(err),
+    };
+    let (routed, schema) = match choice {
+        Ok(choice) => choice,
+        Err(err) => {
+            return Ok(WorkOutput::new_failed(
+                id,
+                vec![format!("{err:?}")],
+                ChatOutput::empty_for_error(),
+                passthrough_data,
+            ));
+        }
+    };
+
+    // Check any schema from the record itself, as we did for our prompts.
+    if !Arc::ptr_eq(&schema, &routed.schema)
+        && let Err(err) = model.provider_schema(&schema.schema)
+    {
+        return Ok(WorkOutput::new_failed(
+            id,
+            vec![format!("{err:#}")],
+            ChatOutput::empty_for_error(),
+            passthrough_data,
+        ));
+    }
+
+    // Render our prompt, making sure it fits in the model's context window.
+    // A record which can't be rendered (because of a missing file, say) only
+    // fails that record, and counts against `--allowed-failure-rate`.
     trace!(
         template_bindings = ?input_record.data.template_bindings,
         "Template bindings"
     );
-    let prompt = state.prompt.render(&input_record.data.template_bindings)?;
+    let mut prompt = match render_within_context_window(
+        &state,
+        &model,
+        &routed.prompt,
+        &schema,
+        &mut input_record.data.template_bindings,
+    ) {
+        Ok(ContextCheck::Fits(prompt)) => prompt,
+        Ok(ContextCheck::Exceeded(err)) => {
+            return Ok(WorkOutput::new_failed(
+                id,
+                vec![err],
+                ChatOutput::empty_for_error(),
+                passthrough_data,
+            ));
+        }
+        Err(err) => {
+            return Ok(WorkOutput::new_failed(
+                id,
+                vec![format!("render_failed: {err:#}")],
+                ChatOutput::empty_for_error(),
+                passthrough_data,
+            ));
+        }
+    };
+
+    // Download any remote images, if our driver can't pass their URLs along.
+    if state.llm_opts.driver.needs_inline_images()
+        && let Err(err) = prompt
+            .inline_remote_images(state.llm_opts.max_remote_image_bytes)
+            .await
+    {
+        return Ok(WorkOutput::new_failed(
+            id,
+            vec![format!("image_download_failed: {err:#}")],
+            ChatOutput::empty_for_error(),
+            passthrough_data,
+        ));
+    }
 
-    // Release the input data, because it adds up, especially for images.
+    // Check our request size before sending any data.
+    if let Some(limits) = &model.payload_limits
+        && let Err(err) = limits.check(&prompt)
+    {
+        return Ok(WorkOutput::new_failed(
+            id,
+            vec![err.to_string()],
+            ChatOutput::empty_for_error(),
+            passthrough_data,
+        ));
+    }
+
+    // Remember what our invariants expect, and then release the input data,
+    // because it adds up, especially for images.
+    let invariants = routed
+        .prompt
+        .invariants
+        .iter()
+        .filter_map(|invariant| {
+            let expected = invariant.expected(&input_record.data.template_bindings)?;
+            Some((invariant, expected))
+        })
+        .collect::<Vec<_>>();
+    let cite_source = match &routed.prompt.cite_source {
+        None => None,
+        Some(name) => match input_record.data.template_bindings.get(name) {
+            Some(Value::String(source)) => Some((name, source.clone())),
+            Some(other) => Some((name, other.to_string())),
+            None => {
+                return Ok(WorkOutput::new_failed(
+                    id,
+                    vec![format!("cite_source: missing input field {name:?}")],
+                    ChatOutput::empty_for_error(),
+                    passthrough_data,
+                ));
+            }
+        },
+    };
     drop(std::mem::take(&mut input_record.data.template_bindings));
 
+    let assertions = routed.assertions.clone();
+
+    // Share our rendered prompt between attempts. Each driver builds its own
+    // request from this, so retries don't need to re-render or copy images.
+    let prompt = Arc::new(prompt);
+
+    // Do our real work. If it fails, try each of our fallback models in turn.
+    let attempts = Arc::new(RecordAttempts::default());
+    let mut served_by = model;
+    let mut fallback_position = 0;
+    let mut fallback_errors = vec![];
+    let mut result = run_chat_with_retries(
+        &state,
+        &served_by,
+        &schema,
+        &assertions,
+        &prompt,
+        &attempts,
+    )
+    .await;
+    for fallback in &state.fallback_models {
+        let Some(error) = state.fallback_error(&result) else {
+            break;
+        };
+        warn!(
+            from = %served_by.name,
+            to = %fallback.name,
+            "Falling back to another model"
+        );
+        fallback_errors.push(format!("{}: {error:?}", served_by.name));
+        served_by = fallback.clone();
+        fallback_position += 1;
+        result = run_chat_with_retries(
+            &state,
+            &served_by,
+            &schema,
+            &assertions,
+            &prompt,
+            &attempts,
+        )
+        .await;
+    }
+
+    let mut output = WorkOutput::<ChatOutput>::from_resolved_result(
+        id,
+        served_by.info,
+        result,
+        passthrough_data,
+    );
+    output.errors.splice(0..0, fallback_errors);
+    state.set_model(&mut output, &served_by, fallback_position);
+    let hedged_requests = attempts.hedged_requests.load(Ordering::Relaxed);
+    if hedged_requests > 0 {
+        output.data.hedged_requests = Some(hedged_requests);
+        output.data.hedged_prompt_tokens =
+            Some(attempts.cancelled_prompt_tokens.load(Ordering::Relaxed));
+    }
+    if output.data.raw_response.is_none() {
+        // We didn't use the last response, but it may explain why.
+        output.data.raw_response = attempts
+            .last_raw_response
+            .lock()
+            .expect("lock poisoned")
+            .take();
+    }
+    if let Some(response) = &mut output.data.response {
+        // Compare our response to the input record.
+        let mut failed = false;
+        for (invariant, expected) in &invariants {
+            if let Err(err) = invariant.check(response, expected) {
+                output.errors.push(err.to_string());
+                failed |= invariant.on_failure == OnInvariantFailure::Fail;
+            }
+        }
+
+        // Make sure each quote actually appears in the source.
+        if let Some((name, source)) = &cite_source {
+            for (path, quote) in unsupported_quotes(response, source) {
+                output.errors.push(format!(
+                    "hallucinated_citation: {path}: {quote:?} not found in {name}"
+                ));
+                failed = true;
+            }
+        }
+
+        // Flag any fields the model wasn't confident about.
+        if routed.prompt.field_confidence
+            && let Some(min_confidence) = state.llm_opts.min_confidence
+        {
+            let low = low_confidence_fields(response, min_confidence);
+            if !low.is_empty() {
+                let fields = low
+                    .iter()
+                    .map(|(path, confidence)| format!("{path} ({confidence})"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                output.errors.push(format!(
+                    "low_confidence: {fields} below --min-confidence {min_confidence}"
+                ));
+                if output.status == WorkStatus::Ok {
+                    output.status = WorkStatus::Incomplete;
+                }
+            }
+        }
+
+        // Drop any fields we don't want to store, and put the rest in schema
+        // order, so that output is stable.
+        for path in routed.omit_fields.iter() {
+            omit_field(response, path);
+        }
+        order_like_schema(response, &schema.schema);
+
+        if failed {
+            output.status = WorkStatus::Failed;
+            output.data.response = None;
+        }
+    }
+    Ok(output)
+}
+
+/// Send a rendered prompt to `model`, retrying transient failures.
+async fn run_chat_with_retries(
+    state: &Arc<ProcessorState>,
+    model: &Arc<ModelState>,
+    schema: &Arc<ResponseSchema>,
+    assertions: &Arc<[CompiledAssertion]>,
+    prompt: &Arc<ChatPrompt<Rendered>>,
+    attempts: &Arc<RecordAttempts>,
+) -> ResolvedResult<(), (), ChatCompletionResponse, anyhow::Error> {
     // If we have a transient failure, back off exponentially.
     let jitter = ExponentialJitter::FromBackoffRange {
         backoff_range_millis: 1..=30_000,
@@ -268,44 +1064,348 @@ async fn run_chat(
     };
 
     // Do our real work, retrying as specified.
-    let result = retry_with_backoff(jitter, || {
-        let prompt = prompt.clone();
-        run_chat_inner(state.clone(), prompt)
+    retry_with_backoff(jitter, || {
+        run_chat_inner(
+            state.clone(),
+            model.clone(),
+            schema.clone(),
+            assertions.clone(),
+            prompt.clone(),
+            attempts.clone(),
+        )
     })
-    .await;
+    .await
+}
 
-    Ok(WorkOutput::<ChatOutput>::from_resolved_result(
-        id,
-        state.model_info,
-        result,
-        passthrough_data,
-    ))
+/// What happened to a record across all the requests we sent for it,
+/// including retries, hedged requests and fallback models.
+#[derive(Debug, Default)]
+struct RecordAttempts {
+    /// How many hedged requests we sent.
+    hedged_requests: AtomicUsize,
+
+    /// Input tokens sent in requests we cancelled, as reported for the request
+    /// we kept. The requests were identical, so their inputs match.
+    cancelled_prompt_tokens: AtomicU64,
+
+    /// The estimated cost of every response we've received, and of every
+    /// cancelled request, in US$.
+    spent: Mutex<f64>,
+
+    /// The last raw response we received, in case we reject it.
+    last_raw_response: Mutex<Option<RawResponse>>,
+}
+
+impl RecordAttempts {
+    /// Add the estimated cost of `usage` on `model` to what we've spent.
+    fn record_spend(&self, model: &ModelState, usage: &TokenUsage) {
+        if let Some(cost) = usage.estimate_cost(model.info) {
+            *self.spent.lock().expect("lock poisoned") += cost;
+        }
+    }
+
+    /// What we've spent on this record so far, in US$.
+    fn spent(&self) -> f64 {
+        *self.spent.lock().expect("lock poisoned")
+    }
+}
+
+/// Send `prompt` to `model`. If `--hedge-after` is set and the request takes
+/// too long, send an identical request, and use whichever succeeds first. The
+/// other request is cancelled.
+async fn hedged_chat_completion(
+    state: &ProcessorState,
+    model: &ModelState,
+    prompt: &ChatPrompt<Rendered>,
+    schema: &ResponseSchema,
+    max_completion_tokens: Option<u32>,
+    attempts: &RecordAttempts,
+) -> LlmRetryResult<ChatCompletionResponse> {
+    let schema = try_fatal!(model.schema_transforms.apply(&schema.schema));
+    let clipped_opts;
+    let llm_opts = if max_completion_tokens == state.llm_opts.max_completion_tokens {
+        &state.llm_opts
+    } else {
+        clipped_opts = LlmOpts {
+            max_completion_tokens,
+            ..state.llm_opts.clone()
+        };
+        &clipped_opts
+    };
+    let request = || {
+        state.driver.chat_completion(
+            &model.name,
+            model.info,
+            prompt,
+            schema.clone(),
+            llm_opts,
+        )
+    };
+    let Some(hedge_after) = state.llm_opts.hedge_after else {
+        return request().await;
+    };
+
+    // Wait for our first request, or until it's time to hedge.
+    let mut primary = request();
+    let hedge_ready = async {
+        tokio::time::sleep(Duration::from_millis(hedge_after)).await;
+        if let Some(rate_limiter) = state.rate_limiter.as_ref() {
+            rate_limiter.acquire_one().await;
+        }
+    };
+    tokio::select! {
+        result = &mut primary => return result,
+        () = hedge_ready => {}
+    }
+    debug!(model = %model.name, "Sending hedged request");
+    attempts.hedged_requests.fetch_add(1, Ordering::Relaxed);
+    let mut hedge = request();
+
+    // Take the first success. If one request fails, wait for the other.
+    let (first, other) = tokio::select! {
+        result = &mut primary => (result, hedge),
+        result = &mut hedge => (result, primary),
+    };
+    match first {
+        RetryResult::Ok { output, .. } => {
+            // Providers usually bill for the input tokens of a cancelled
+            // request, so record them separately from our real usage.
+            drop(other);
+            if let Some(usage) = &output.token_usage {
+                attempts
+                    .cancelled_prompt_tokens
+                    .fetch_add(usage.prompt_tokens, Ordering::Relaxed);
+                let cancelled = TokenUsage {
+                    prompt_tokens: usage.prompt_tokens,
+                    completion_tokens: 0,
+                };
+                attempts.record_spend(model, &cancelled);
+            }
+            retry_result_ok(output)
+        }
+        _ => other.await,
+    }
+}
+
+/// The result of [`render_within_context_window`].
+enum ContextCheck {
+    /// The rendered prompt should fit in the model's context window.
+    Fits(ChatPrompt<Rendered>),
+
+    /// The rendered prompt is too large, for the reason given.
+    Exceeded(String),
+}
+
+/// Render our prompt, and check its size against the model's context window.
+/// If it's too large, try truncating any `--truncate-field` bindings.
+///
+/// We count tokens with the model's tokenizer when we have it, and estimate
+/// them otherwise. We can only do this if LiteLLM tells us the model's
+/// `max_input_tokens`. Otherwise, we assume the prompt fits.
+fn render_within_context_window(
+    state: &ProcessorState,
+    model: &ModelState,
+    template: &ChatPrompt,
+    schema: &ResponseSchema,
+    bindings: &mut JsonObject,
+) -> Result<ContextCheck> {
+    let mut prompt = template.render(bindings)?;
+    let Some(max_input_tokens) = model
+        .info
+        .and_then(|info| info.model_info.max_input_tokens)
+        .and_then(|max| usize::try_from(max).ok())
+    else {
+        return Ok(ContextCheck::Fits(prompt));
+    };
+    loop {
+        let input_tokens = model
+            .token_counter
+            .count_prompt(&prompt, &schema.schema)
+            .total_tokens;
+        if input_tokens <= max_input_tokens {
+            return Ok(ContextCheck::Fits(prompt));
+        }
+
+        // Trim our excess from the end of each truncatable field, in order.
+        // We don't know exactly how many bytes each token uses, so we guess,
+        // and then check again. Fields may also appear more than once (or not
+        // at all) in the rendered prompt.
+        let mut excess_bytes =
+            (input_tokens - max_input_tokens) * ESTIMATED_BYTES_PER_TOKEN;
+        let mut truncated = false;
+        for field in &state.llm_opts.truncate_fields {
+            if excess_bytes == 0 {
+                break;
+            }
+            let Some(Value::String(text)) = bindings.get_mut(field) else {
+                continue;
+            };
+            let mut new_len = text.len().saturating_sub(excess_bytes);
+            while !text.is_char_boundary(new_len) {
+                new_len -= 1;
+            }
+            if new_len == text.len() {
+                continue;
+            }
+            warn!(
+                field,
+                from = text.len(),
+                to = new_len,
+                "Truncating field to fit context window"
+            );
+            excess_bytes = excess_bytes.saturating_sub(text.len() - new_len);
+            text.truncate(new_len);
+            truncated = true;
+        }
+        if !truncated {
+            let counted = if model.token_counter.is_exact() {
+                "counted"
+            } else {
+                "estimated"
+            };
+            return Ok(ContextCheck::Exceeded(format!(
+                "context_exceeded: prompt is {counted} at {input_tokens} tokens, but {} accepts at most {max_input_tokens} input tokens",
+                model.name
+            )));
+        }
+        prompt = template.render(bindings)?;
+    }
+}
+
+/// Check the most a request to `model` might cost against what's left of
+/// `--max-cost-per-record`, after the `spent` US$ we've already spent on this
+/// record, and return the completion token limit to use. When clipping, this
+/// may be lower than `--max-completion-tokens`.
+///
+/// [`create_chat_work_queue`] makes sure we know each model's prices.
+fn apply_cost_cap(
+    llm_opts: &LlmOpts,
+    model: &ModelState,
+    prompt: &ChatPrompt<Rendered>,
+    schema: &ResponseSchema,
+    spent: f64,
+) -> Result<Option<u32>> {
+    let max_completion_tokens = llm_opts.max_completion_tokens;
+    let Some(cap) = llm_opts.max_cost_per_record else {
+        return Ok(max_completion_tokens);
+    };
+    let info = &model
+        .info
+        .ok_or_else(|| anyhow!("no LiteLLM pricing for {}", model.name))?
+        .model_info;
+    let budget = cap - spent;
+    let input_tokens = model
+        .token_counter
+        .count_prompt(prompt, &schema.schema)
+        .total_tokens;
+    let input_cost = usize_to_f64(input_tokens)
+        .ok_or_else(|| anyhow!("prompt has too many tokens: {input_tokens}"))?
+        * info.input_cost_per_token;
+    let output_tokens = max_completion_tokens.or_else(|| {
+        info.max_output_tokens
+            .map(|max| u32::try_from(max).unwrap_or(u32::MAX))
+    });
+    // Without an output limit, we can't bound the cost of a paid response,
+    // so we always clip.
+    let estimated_cost = match output_tokens {
+        Some(output_tokens) => {
+            Some(input_cost + f64::from(output_tokens) * info.output_cost_per_token)
+        }
+        None if info.output_cost_per_token == 0.0 => Some(input_cost),
+        None => None,
+    };
+    if estimated_cost.is_some_and(|estimated_cost| estimated_cost <= budget) {
+        return Ok(max_completion_tokens);
+    }
+    let capped = || {
+        let spent_note = if spent > 0.0 {
+            format!(" (US${spent:.4} already spent on this record)")
+        } else {
+            String::new()
+        };
+        match (estimated_cost, output_tokens) {
+            (Some(estimated_cost), Some(output_tokens)) => anyhow!(
+                "cost_capped: request to {} might cost US${estimated_cost:.4} ({input_tokens} input tokens, {output_tokens} output tokens), more than is left of --max-cost-per-record US${cap}{spent_note}",
+                model.name
+            ),
+            _ => anyhow!(
+                "cost_capped: request to {} has {input_tokens} input tokens, leaving nothing for the response under --max-cost-per-record US${cap}{spent_note}",
+                model.name
+            ),
+        }
+    };
+    match llm_opts.on_cost_cap {
+        OnCostCap::Skip => Err(capped()),
+        OnCostCap::Clip => {
+            // Spend whatever the prompt leaves us on the response.
+            let affordable_tokens = ((budget - input_cost) / info.output_cost_per_token)
+                .floor()
+                .min(f64::from(u32::MAX));
+            let clipped = match f64_to_u32(affordable_tokens) {
+                Some(clipped) if clipped >= 1 => clipped,
+                _ => return Err(capped()),
+            };
+            debug!(
+                model = %model.name,
+                from = ?output_tokens,
+                to = clipped,
+                "Clipping completion tokens to fit --max-cost-per-record"
+            );
+            Ok(Some(clipped))
+        }
+    }
 }
 
 /// Process the data portion of a record.
 #[instrument(level = "debug", skip_all)]
 async fn run_chat_inner(
     state: Arc<ProcessorState>,
-    prompt: ChatPrompt<Rendered>,
+    model: Arc<ModelState>,
+    schema: Arc<ResponseSchema>,
+    assertions: Arc<[CompiledAssertion]>,
+    prompt: Arc<ChatPrompt<Rendered>>,
+    attempts: Arc<RecordAttempts>,
 ) -> LlmRetryResult<ChatCompletionResponse> {
+    // Make sure this request can't take our spending on this record over
+    // `--max-cost-per-record`. This fails the request for this model, so any
+    // fallback models can try with whatever budget is left.
+    let max_completion_tokens = try_fatal!(apply_cost_cap(
+        &state.llm_opts,
+        &model,
+        &prompt,
+        &schema,
+        attempts.spent(),
+    ));
+
     // If we have a rate limiter, acquire a permit for one request.
     if let Some(rate_limiter) = state.rate_limiter.as_ref() {
-        rate_limiter.acquire(1).await;
-    }
-
-    // Call OpenAI.
-    let completion_response = try_retry_result!(
-        state
-            .driver
-            .chat_completion(
-                &state.model,
-                state.model_info,
-                &prompt,
-                state.schema.clone(),
-                &state.llm_opts,
-            )
-            .await
-    );
+        rate_limiter.acquire_one().await;
+    }
+
+    // Call OpenAI, recording how long successful requests take.
+    let started = Instant::now();
+    let result = hedged_chat_completion(
+        &state,
+        &model,
+        &prompt,
+        &schema,
+        max_completion_tokens,
+        &attempts,
+    )
+    .await;
+    if matches!(result, RetryResult::Ok { .. }) {
+        record_total(&model.name, started.elapsed());
+    }
+    let completion_response = try_retry_result!(result);
+
+    // Remember what we received and what it cost, in case we reject it below.
+    if let Some(usage) = &completion_response.token_usage {
+        attempts.record_spend(&model, usage);
+    }
+    if let Some(raw_response) = &completion_response.raw_response {
+        *attempts.last_raw_response.lock().expect("lock poisoned") =
+            Some(raw_response.clone());
+    }
 
     // Validate the result using JSON Schema. Schema validation failure is
     // treated as a transient retry failure, because it may be caused by a dodgy
@@ -313,7 +1413,7 @@ async fn run_chat_inner(
     try_transient!(
         // Invalid JSON means the model didn't follow the schema. Let it try
         // again using `try_transient!`.
-        state
+        schema
             .validator
             .validate(&completion_response.response)
             .map_err(|err| err.to_owned())
@@ -323,5 +1423,41 @@ async fn run_chat_inner(
             ))
     );
 
+    // Check any assertions from our prompt. Depending on the assertion, a
+    // failure either asks the model again, or fails the record.
+    for assertion in assertions.iter() {
+        let result = assertion.check(&completion_response.response);
+        match assertion.on_failure() {
+            OnAssertionFailure::Retry => try_transient!(result),
+            OnAssertionFailure::Fail => try_fatal!(result),
+        }
+    }
     retry_result_ok(completion_response)
 }
+
+/// Remove the field at the dotted `path` from `value`, if present. Arrays
+/// along the way have the rest of the path removed from each item.
+fn omit_field(value: &mut Value, path: &str) {
+    let (key, rest) = match path.split_once('.') {
+        Some((key, rest)) => (key, Some(rest)),
+        None => (path, None),
+    };
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                omit_field(item, path);
+            }
+        }
+        Value::Object(obj) => match rest {
+            None => {
+                obj.shift_remove(key);
+            }
+            Some(rest) => {
+                if let Some(child) = obj.get_mut(key) {
+                    omit_field(child, rest);
+                }
+            }
+        },
+        _ => {}
+    }
+}
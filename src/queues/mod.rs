@@ -2,4 +2,5 @@
 
 pub mod chat;
 pub mod ocr;
+pub mod transcribe;
 pub mod work;
@@ -0,0 +1,213 @@
+//! OCR engine which runs an external command on each page, selected with
+//! `--model command:engine.toml`.
+//!
+//! This lets teams plug in OCR tools we don't support directly. The command is
+//! described by a TOML (or JSON) file:
+//!
+//! ```toml
+//! # `{page}` is replaced with the path of the page image.
+//! command = ["my-ocr", "--lang", "eng", "{page}"]
+//!
+//! # How to read standard output: "text" (the default), or "json" for an
+//! # object with a `text` field.
+//! output = "text"
+//! ```
+
+use std::sync::Arc;
+
+use crate::{
+    async_utils::{
+        JoinWorker, check_for_command_failure, io::read_json_or_toml_as_json_value,
+    },
+    cpu_limit::with_cpu_semaphore,
+    prelude::*,
+    process_limits::{limited_command, limited_output},
+};
+
+use super::page::{OcrPageEngine, OcrPageInput, OcrPageOutput};
+
+/// `--model` prefix which selects this engine.
+pub const COMMAND_PREFIX: &str = "command:";
+
+/// The placeholder for the page image path in our command.
+const PAGE_PLACEHOLDER: &str = "{page}";
+
+/// How to read the command's standard output.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum CommandOutputFormat {
+    /// The page's text.
+    #[default]
+    Text,
+
+    /// A JSON object with a `text` field.
+    Json,
+}
+
+/// How to run an OCR command, read from `--model command:PATH`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommandOcrConfig {
+    /// The program and its arguments. Each `{page}` is replaced with the path
+    /// of the page image.
+    command: Vec<String>,
+
+    /// How to read standard output.
+    #[serde(default)]
+    output: CommandOutputFormat,
+}
+
+/// The JSON output of a command using `output = "json"`.
+#[derive(Debug, Deserialize)]
+struct CommandJsonOutput {
+    /// The page's text.
+    text: String,
+}
+
+/// OCR engine which runs an external command on each page.
+#[non_exhaustive]
+pub struct CommandOcrPageEngine {
+    /// How to run our command.
+    config: CommandOcrConfig,
+}
+
+impl CommandOcrPageEngine {
+    /// Create a new engine from the config file at `path`. We will run at
+    /// most `--cpu-jobs` commands at once.
+    #[allow(clippy::new_ret_no_self)]
+    pub async fn new(path: &Path) -> Result<(Arc<dyn OcrPageEngine>, JoinWorker)> {
+        let config = read_json_or_toml_as_json_value(path).await?;
+        let config = serde_json::from_value::<CommandOcrConfig>(config)
+            .with_context(|| format!("Invalid OCR command config {path:?}"))?;
+        if config.command.is_empty() {
+            return Err(anyhow!("OCR command config {path:?} has an empty command"));
+        }
+        if !config.command[1..]
+            .iter()
+            .any(|arg| arg.contains(PAGE_PLACEHOLDER))
+        {
+            return Err(anyhow!(
+                "OCR command in {path:?} must pass the page using {PAGE_PLACEHOLDER}"
+            ));
+        }
+        Ok((Arc::new(Self { config }), JoinWorker::noop()))
+    }
+
+    /// The name of our program, for error messages.
+    fn program(&self) -> &str {
+        &self.config.command[0]
+    }
+
+    /// Parse the standard output of our command.
+    fn parse_output(&self, stdout: &[u8]) -> Result<String> {
+        let stdout = String::from_utf8_lossy(stdout);
+        match self.config.output {
+            CommandOutputFormat::Text => Ok(stdout.into_owned()),
+            CommandOutputFormat::Json => {
+                let output = serde_json::from_str::<CommandJsonOutput>(&stdout)
+                    .with_context(|| {
+                        format!("{} printed invalid JSON: {stdout:?}", self.program())
+                    })?;
+                Ok(output.text)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl OcrPageEngine for CommandOcrPageEngine {
+    #[instrument(level = "debug", skip_all, fields(id = %input.id, page = %input.page_idx))]
+    async fn ocr_page(&self, input: OcrPageInput) -> Result<OcrPageOutput> {
+        let extension = mime_guess::get_mime_extensions_str(&input.page.mime_type)
+            .and_then(|o| o.first())
+            .ok_or_else(|| {
+                anyhow!("cannot determine extension for {}", input.page.mime_type)
+            })?;
+
+        // Write our page to a temporary file.
+        let tmpdir = tempfile::TempDir::with_prefix("ocr-command")?;
+        let page_path = tmpdir.path().join(format!("page.{extension}"));
+        tokio::fs::write(&page_path, input.page.data.into_bytes().await?)
+            .await
+            .context("cannot write OCR command input file")?;
+        let page_path = page_path
+            .to_str()
+            .ok_or_else(|| anyhow!("temporary path is not valid UTF-8: {page_path:?}"))?;
+
+        // Run our command. We assume it's CPU-heavy, like most OCR tools.
+        let output = with_cpu_semaphore(|| async {
+            let mut command = limited_command(self.program());
+            command.args(
+                self.config.command[1..]
+                    .iter()
+                    .map(|arg| arg.replace(PAGE_PLACEHOLDER, page_path)),
+            );
+            limited_output(&mut command)
+                .await
+                .with_context(|| format!("cannot run {}", self.program()))
+        })
+        .await?;
+        check_for_command_failure(self.program(), &output, None)?;
+
+        Ok(OcrPageOutput {
+            text: Some(self.parse_output(&output.stdout)?),
+            errors: vec![],
+            analysis: None,
+            estimated_cost: None,
+            token_usage: None,
+            words: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn configs_are_checked() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let write = |name: &str, text: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, text).unwrap();
+            path
+        };
+
+        let good = write("good.toml", "command = [\"my-ocr\", \"--in={page}\"]\n");
+        assert!(CommandOcrPageEngine::new(&good).await.is_ok());
+        let no_page = write("no_page.toml", "command = [\"my-ocr\"]\n");
+        assert!(CommandOcrPageEngine::new(&no_page).await.is_err());
+        let bad_output = write(
+            "bad.toml",
+            "command = [\"my-ocr\", \"{page}\"]\noutput = \"xml\"\n",
+        );
+        assert!(CommandOcrPageEngine::new(&bad_output).await.is_err());
+    }
+
+    #[test]
+    fn output_is_parsed() {
+        let engine = |output| CommandOcrPageEngine {
+            config: CommandOcrConfig {
+                command: vec!["my-ocr".to_owned(), "{page}".to_owned()],
+                output,
+            },
+        };
+        assert_eq!(
+            engine(CommandOutputFormat::Text)
+                .parse_output(b"Hello\n")
+                .unwrap(),
+            "Hello\n"
+        );
+        assert_eq!(
+            engine(CommandOutputFormat::Json)
+                .parse_output(br#"{"text": "Hello", "confidence": 0.9}"#)
+                .unwrap(),
+            "Hello"
+        );
+        assert!(
+            engine(CommandOutputFormat::Json)
+                .parse_output(b"Hello")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! An "OCR" engine for HTML and EPUB documents, which already contain text.
+
+use std::sync::Arc;
+
+use super::{
+    super::{OcrInput, OcrOutput},
+    file::OcrFileEngine,
+};
+use crate::{
+    async_utils::blocking_iter_streams::spawn_blocking_propagating_panics,
+    document::{DocumentKind, document_sections},
+    prelude::*,
+    queues::work::{WorkInput, WorkOutput, WorkStatus},
+    s3::is_s3_uri,
+};
+
+/// Converts local HTML and EPUB files to text directly, treating each HTML
+/// page or EPUB chapter as a "page". All other inputs are passed through to
+/// the wrapped engine.
+pub struct DocumentOcrFileEngine {
+    include_page_breaks: bool,
+    engine: Arc<dyn OcrFileEngine>,
+}
+
+impl DocumentOcrFileEngine {
+    /// Wrap `engine`, handling HTML and EPUB files ourselves.
+    pub fn new(include_page_breaks: bool, engine: Arc<dyn OcrFileEngine>) -> Self {
+        Self {
+            include_page_breaks,
+            engine,
+        }
+    }
+}
+
+#[async_trait]
+impl OcrFileEngine for DocumentOcrFileEngine {
+    #[instrument(level = "debug", skip_all, fields(id = %ocr_input.id))]
+    async fn ocr_file(
+        &self,
+        ocr_input: WorkInput<OcrInput>,
+    ) -> Result<WorkOutput<OcrOutput>> {
+        let kind = if is_s3_uri(&ocr_input.data.path) {
+            None
+        } else {
+            DocumentKind::for_path(ocr_input.data.path())
+        };
+        let Some(kind) = kind else {
+            return self.engine.ocr_file(ocr_input).await;
+        };
+
+        let path = ocr_input.data.path.clone();
+        let sections = spawn_blocking_propagating_panics(move || {
+            document_sections(kind, Path::new(&path))
+        })
+        .await;
+        let sections = match sections {
+            Ok(sections) => sections,
+            Err(err) => {
+                return Ok(WorkOutput::new_failed(
+                    ocr_input.id,
+                    vec![format!("{err:#}")],
+                    OcrOutput::empty_for_error(ocr_input.data.path.clone()),
+                    ocr_input.passthrough_data,
+                ));
+            }
+        };
+
+        let separator = if self.include_page_breaks {
+            "\n\x0C\n"
+        } else {
+            "\n\n"
+        };
+        Ok(WorkOutput {
+            id: ocr_input.id,
+            status: WorkStatus::Ok,
+            estimated_cost: None,
+            token_usage: None,
+            errors: vec![],
+            passthrough_data: ocr_input.passthrough_data,
+            ack_token: None,
+            prompt_version: None,
+            prompt_hash: None,
+            model: None,
+            data: OcrOutput {
+                path: ocr_input.data.path.clone(),
+                text: Some(sections.join(separator)),
+                page_count: Some(sections.len()),
+                analysis: None,
+                searchable_pdf_path: None,
+            },
+        })
+    }
+}
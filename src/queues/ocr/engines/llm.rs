@@ -6,18 +6,19 @@ use schemars::JsonSchema;
 use serde_json::Map;
 
 use crate::{
-    async_utils::JoinWorker,
+    async_utils::{JoinWorker, io::JsonOrTomlText},
     data_url::data_url,
     drivers::LlmOpts,
+    model_weights::ModelWeights,
     prelude::*,
-    prompt::ChatPrompt,
+    prompt::{ChatPrompt, ResponseFormat},
+    prompt_router::PromptRouter,
     queues::{
         chat::{ChatInput, ChatOutput, create_chat_work_queue},
         ocr::OcrAnalysis,
-        work::{WorkInput, WorkItemProcessor as _, WorkQueue},
+        work::{RecordLimits, WorkInput, WorkItemProcessor as _, WorkQueue},
     },
     schema::Schema,
-    toml_utils::from_toml_str,
 };
 
 use super::page::{OcrPageEngine, OcrPageInput, OcrPageOutput};
@@ -33,8 +34,11 @@ const EXAMPLE_OUTPUT: &str = include_str!("llm/example_output.md");
 
 /// Get our default OCR prompt.
 pub fn default_ocr_prompt() -> ChatPrompt {
-    from_toml_str::<ChatPrompt>(DEFAULT_OCR_PROMPT)
-        .expect("failed to parse built-in OCR prompt")
+    let text = JsonOrTomlText::new(
+        "built-in OCR prompt".to_owned(),
+        DEFAULT_OCR_PROMPT.to_owned(),
+    );
+    ChatPrompt::from_text(text).expect("failed to parse built-in OCR prompt")
 }
 
 /// Information to extract from each page
@@ -63,12 +67,33 @@ impl LlmOcrPageEngine {
         model: String,
         llm_opts: LlmOpts,
     ) -> Result<(Arc<dyn OcrPageEngine>, JoinWorker)> {
-        // Add our schema to our prompt.
+        // Add our schema to our prompt. We always want JSON, even if the
+        // prompt asked for text.
+        prompt.response_format = ResponseFormat::Json;
         prompt.response_schema = Schema::from_type::<PageChatResponse>();
 
-        // Create a new chat queue to handle all our LLM requests.
-        let (chat_queue, worker) =
-            create_chat_work_queue(concurrency_limit, prompt, model, llm_opts).await?;
+        // Make our examples available as constants, unless the prompt defines
+        // its own. We encode the example image once here, and the prompt
+        // shares a single decoded copy between all pages.
+        prompt
+            .constants
+            .entry("example_input_data_url")
+            .or_insert_with(|| Value::String(data_url("image/png", EXAMPLE_INPUT)));
+        prompt
+            .constants
+            .entry("example_output")
+            .or_insert_with(|| Value::String(EXAMPLE_OUTPUT.to_owned()));
+
+        // Create a new chat queue to handle all our LLM requests. Record limits
+        // apply to whole documents, not individual pages.
+        let (chat_queue, worker) = create_chat_work_queue(
+            concurrency_limit,
+            PromptRouter::single(prompt),
+            ModelWeights::single(model),
+            llm_opts,
+            RecordLimits::default(),
+        )
+        .await?;
 
         Ok((Arc::new(Self { chat_queue }), worker))
     }
@@ -77,24 +102,16 @@ impl LlmOcrPageEngine {
 #[async_trait]
 impl OcrPageEngine for LlmOcrPageEngine {
     #[instrument(level = "debug", skip_all, fields(id = %input.id, page = %input.page_idx))]
-    async fn ocr_page(&self, mut input: OcrPageInput) -> Result<OcrPageOutput> {
+    async fn ocr_page(&self, input: OcrPageInput) -> Result<OcrPageOutput> {
         // Get a chat handle.
         let chat_handle = self.chat_queue.handle();
 
         let mut template_bindings = Map::new();
         template_bindings.insert(
             "page_data_url".to_string(),
-            Value::String(input.page.to_data_url()),
-        );
-        input.page.data = vec![]; // Release memory, because it adds up.
-        template_bindings.insert(
-            "example_input_data_url".to_string(),
-            Value::String(data_url("image/png", EXAMPLE_INPUT)),
-        );
-        template_bindings.insert(
-            "example_output".to_string(),
-            Value::String(EXAMPLE_OUTPUT.to_string()),
+            Value::String(input.page.to_data_url().await?),
         );
+        drop(input.page); // Release memory, because it adds up.
 
         let input = WorkInput {
             id: Value::Array(vec![
@@ -103,7 +120,14 @@ impl OcrPageEngine for LlmOcrPageEngine {
             ]),
             skip_processing: None,
             passthrough_data: None,
-            data: ChatInput { template_bindings },
+            ack_token: None,
+            data: ChatInput {
+                response_schema: None,
+                prompt_name: None,
+                input_error: None,
+                offloaded_files: vec![],
+                template_bindings,
+            },
         };
         let chat_output = chat_handle.process_blocking(input).await?;
         let errors = chat_output.errors;
@@ -118,6 +142,7 @@ impl OcrPageEngine for LlmOcrPageEngine {
             analysis: response.map(|r| r.analysis),
             estimated_cost: chat_output.estimated_cost,
             token_usage: chat_output.token_usage,
+            words: None,
         })
     }
 }
@@ -7,8 +7,13 @@ use crate::{
     prompt::ChatPrompt,
 };
 
-use self::{file::OcrFileEngine, split_pages::SplitPagesOcrEngine};
+use self::{
+    command::COMMAND_PREFIX, document::DocumentOcrFileEngine, file::OcrFileEngine,
+    split_pages::SplitPagesOcrEngine, textract::TextractOpts,
+};
 
+pub mod command;
+pub mod document;
 pub mod file;
 pub mod llm;
 pub mod page;
@@ -17,9 +22,119 @@ pub mod split_pages;
 pub mod tesseract;
 pub mod textract;
 
+/// The kinds of OCR engine, chosen by `--model`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OcrEngineKind {
+    /// `pdftotext`, which reads the text layer of a PDF.
+    PdfToText,
+    /// `tesseract`, which OCRs rasterized pages locally.
+    Tesseract,
+    /// `textract`, which sends individual pages to AWS Textract.
+    Textract,
+    /// `textract-async`, which sends whole documents to AWS Textract.
+    TextractAsync,
+    /// `command:PATH`, which runs the external command described by `PATH`.
+    Command,
+    /// Any other model, which we assume is an LLM.
+    Llm,
+}
+
+impl OcrEngineKind {
+    /// Which kind of engine does `model` use?
+    fn for_model(model: &str) -> Self {
+        match model {
+            "pdftotext" => OcrEngineKind::PdfToText,
+            "tesseract" => OcrEngineKind::Tesseract,
+            "textract" => OcrEngineKind::Textract,
+            "textract-async" => OcrEngineKind::TextractAsync,
+            _ if model.starts_with(COMMAND_PREFIX) => OcrEngineKind::Command,
+            _ => OcrEngineKind::Llm,
+        }
+    }
+
+    /// Can this engine tell us where each word is?
+    fn reports_word_positions(self) -> bool {
+        matches!(
+            self,
+            OcrEngineKind::Tesseract
+                | OcrEngineKind::Textract
+                | OcrEngineKind::TextractAsync
+        )
+    }
+}
+
+/// Does the OCR engine for `model` use our prompt?
+pub fn uses_prompt(model: &str) -> bool {
+    OcrEngineKind::for_model(model) == OcrEngineKind::Llm
+}
+
+/// Check that our options make sense for the OCR engine used by `model`.
+///
+/// This is called before we read any input or create any clients, so that
+/// conflicting flags are reported immediately.
+pub fn check_ocr_engine_opts(
+    model: &str,
+    page_iter_opts: &PageIterOptions,
+    llm_opts: &LlmOpts,
+    textract_opts: &TextractOpts,
+    searchable_pdf: bool,
+) -> Result<()> {
+    let kind = OcrEngineKind::for_model(model);
+    if searchable_pdf && !kind.reports_word_positions() {
+        return Err(anyhow!(
+            "--write-searchable-pdf requires --model tesseract, textract or \
+             textract-async, not {model:?}"
+        ));
+    }
+    if kind != OcrEngineKind::Llm
+        && let Some(flag) = llm_opts.llm_only_flags_set().first()
+    {
+        return Err(anyhow!(
+            "{flag} only applies to LLM models, not --model {model}"
+        ));
+    }
+    if !matches!(kind, OcrEngineKind::Textract | OcrEngineKind::TextractAsync)
+        && let Some(flag) = textract_opts.flags_set().first()
+    {
+        return Err(anyhow!(
+            "{flag} only applies to --model textract or textract-async, not \
+             --model {model}"
+        ));
+    }
+
+    match kind {
+        OcrEngineKind::PdfToText if page_iter_opts.rasterize => Err(anyhow!(
+            "--rasterize does not work with --model pdftotext, which reads the \
+             PDF's text layer directly"
+        )),
+        OcrEngineKind::Tesseract if !page_iter_opts.rasterize => {
+            Err(anyhow!("--model tesseract requires --rasterize"))
+        }
+        OcrEngineKind::Textract if textract_opts.ocr_timeout.is_some() => Err(anyhow!(
+            "--ocr-timeout only applies to --model textract-async, not textract"
+        )),
+        OcrEngineKind::TextractAsync if page_iter_opts.rasterize => Err(anyhow!(
+            "--rasterize does not work with --model textract-async, which sends \
+             whole documents to Textract. Use --model textract to OCR \
+             rasterized pages"
+        )),
+        OcrEngineKind::TextractAsync if page_iter_opts.max_pages.is_some() => {
+            Err(anyhow!(
+                "--max-pages does not work with --model textract-async, which \
+                 sends whole documents to Textract. Use --model textract to OCR \
+                 individual pages"
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Get the OCR engine for the specified model.
 ///
-/// For non-LLM models, `prompt` will be ignored.
+/// For non-LLM models, `prompt` will be ignored. If `searchable_pdf_dir` is
+/// specified, the engine must be able to report word positions. Options
+/// should already have been checked with [`check_ocr_engine_opts`].
+#[allow(clippy::too_many_arguments)]
 pub async fn ocr_engine_for_model(
     concurrency_limit: usize,
     prompt: ChatPrompt,
@@ -27,6 +142,8 @@ pub async fn ocr_engine_for_model(
     include_page_breaks: bool,
     page_iter_opts: &PageIterOptions,
     llm_opts: LlmOpts,
+    textract_opts: &TextractOpts,
+    searchable_pdf_dir: Option<PathBuf>,
 ) -> Result<(Arc<dyn OcrFileEngine>, JoinWorker)> {
     // Helper function wrap an OcrPageEngine.
     let split_pages = |(page_engine, worker)| {
@@ -36,36 +153,50 @@ pub async fn ocr_engine_for_model(
                 concurrency_limit,
                 include_page_breaks,
                 page_engine,
+                searchable_pdf_dir.clone(),
             )) as Arc<dyn OcrFileEngine>,
             worker,
         )
     };
 
     // Choose our engine.
-    let (file_engine, worker) = match model.as_str() {
-        "pdftotext" => {
+    let (file_engine, worker) = match OcrEngineKind::for_model(&model) {
+        OcrEngineKind::PdfToText => {
             pdftotext::PdfToTextOcrFileEngine::new(page_iter_opts, include_page_breaks)?
         }
-        "tesseract" => {
-            split_pages(tesseract::TesseractOcrPageEngine::new(page_iter_opts)?)
-        }
-        "textract" => split_pages(
-            textract::TextractOcrPageEngine::new(concurrency_limit, &llm_opts).await?,
+        OcrEngineKind::Tesseract => split_pages(tesseract::TesseractOcrPageEngine::new(
+            searchable_pdf_dir.is_some(),
+        )?),
+        OcrEngineKind::Textract => split_pages(
+            textract::TextractOcrPageEngine::new(
+                concurrency_limit,
+                &llm_opts,
+                textract_opts,
+            )
+            .await?,
         ),
-        "textract-async" => {
+        OcrEngineKind::TextractAsync => {
             textract::TextractOcrFileEngine::new(
-                page_iter_opts,
                 concurrency_limit,
                 include_page_breaks,
                 &llm_opts,
+                textract_opts,
+                searchable_pdf_dir.clone(),
             )
             .await?
         }
-        // Assume all other OCR models are LLMs.
-        _ => split_pages(
+        OcrEngineKind::Command => split_pages(
+            command::CommandOcrPageEngine::new(Path::new(&model[COMMAND_PREFIX.len()..]))
+                .await?,
+        ),
+        OcrEngineKind::Llm => split_pages(
             llm::LlmOcrPageEngine::new(concurrency_limit, prompt, model, llm_opts)
                 .await?,
         ),
     };
+
+    // HTML and EPUB files already contain text, so convert them directly.
+    let file_engine: Arc<dyn OcrFileEngine> =
+        Arc::new(DocumentOcrFileEngine::new(include_page_breaks, file_engine));
     Ok((file_engine, worker))
 }
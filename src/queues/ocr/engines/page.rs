@@ -1,6 +1,6 @@
 //! Interface for OCRing a single page.
 
-use crate::{drivers::TokenUsage, page_iter::Page, prelude::*};
+use crate::{drivers::TokenUsage, page_iter::Page, prelude::*, searchable_pdf::OcrWord};
 
 use super::super::OcrAnalysis;
 
@@ -32,6 +32,9 @@ pub struct OcrPageOutput {
 
     /// How many tokens did the LLM use?
     pub token_usage: Option<TokenUsage>,
+
+    /// The position of each word on the page, if the engine reports them.
+    pub words: Option<Vec<OcrWord>>,
 }
 
 /// Interface to an OCR engine.
@@ -2,12 +2,11 @@
 
 use std::{fs::read_to_string, sync::Arc};
 
-use tokio::process::Command;
-
 use crate::{
     async_utils::{JoinWorker, check_for_command_failure},
-    page_iter::{PageIterOptions, get_mime_type},
+    page_iter::{PageIterOptions, check_for_encrypted_pdf, get_mime_type},
     prelude::*,
+    process_limits::{limited_command, limited_output},
     queues::{
         ocr::{OcrInput, OcrOutput, engines::file::OcrFileEngine},
         work::{WorkInput, WorkOutput, WorkStatus},
@@ -31,17 +30,13 @@ impl PdfToTextOcrFileEngine {
         page_iter_opts: &PageIterOptions,
         include_page_breaks: bool,
     ) -> Result<(Arc<dyn OcrFileEngine>, JoinWorker)> {
-        if page_iter_opts.rasterize {
-            Err(anyhow!("pdftotext does not work with --rasterize"))
-        } else {
-            Ok((
-                Arc::new(Self {
-                    page_iter_opts: page_iter_opts.clone(),
-                    include_page_breaks,
-                }),
-                JoinWorker::noop(),
-            ))
-        }
+        Ok((
+            Arc::new(Self {
+                page_iter_opts: page_iter_opts.clone(),
+                include_page_breaks,
+            }),
+            JoinWorker::noop(),
+        ))
     }
 }
 
@@ -66,7 +61,7 @@ impl OcrFileEngine for PdfToTextOcrFileEngine {
         // Run pdftotext on the input file.
         let tmpdir = tempfile::TempDir::with_prefix("pdftotext")?;
         let output_path = tmpdir.path().join("output.txt");
-        let mut cmd = Command::new("pdftotext");
+        let mut cmd = limited_command("pdftotext");
         cmd.arg("-layout")
             .arg(&ocr_input.data.path)
             .arg(&output_path);
@@ -81,7 +76,14 @@ impl OcrFileEngine for PdfToTextOcrFileEngine {
         if let Some(password) = &ocr_input.data.password {
             cmd.arg("-upw").arg(password);
         }
-        let output = cmd.output().await.context("cannot run pdftotext")?;
+        let output = limited_output(&mut cmd)
+            .await
+            .context("cannot run pdftotext")?;
+        check_for_encrypted_pdf(
+            ocr_input.data.path(),
+            ocr_input.data.password.as_deref(),
+            &output,
+        )?;
         check_for_command_failure("pdftotext", &output, None)?;
 
         // Read the output file, trimming the final page feed if present for consistency
@@ -102,11 +104,16 @@ impl OcrFileEngine for PdfToTextOcrFileEngine {
             token_usage: None,
             errors,
             passthrough_data: ocr_input.passthrough_data,
+            ack_token: None,
+            prompt_version: None,
+            prompt_hash: None,
+            model: None,
             data: OcrOutput {
                 path: ocr_input.data.path.clone(),
                 text: Some(text),
                 page_count: None,
                 analysis: None,
+                searchable_pdf_path: None,
             },
         })
     }
@@ -1,31 +1,64 @@
 //! An OCR engine that splits a document into pages, and OCRs each page.
 
-use std::{env, sync::Arc};
+use std::{
+    env,
+    sync::{Arc, OnceLock},
+};
 
-use futures::StreamExt as _;
+use futures::{
+    SinkExt as _, StreamExt as _,
+    channel::{mpsc, oneshot},
+};
+use tokio::sync::OnceCell;
 
 use super::{
     super::{OcrInput, OcrOutput},
     file::OcrFileEngine,
-    page::{OcrPageEngine, OcrPageInput},
+    page::{OcrPageEngine, OcrPageInput, OcrPageOutput},
 };
 use crate::{
     async_utils::blocking_iter_streams::BlockingIterStream,
     drivers::TokenUsage,
     page_iter::{PageIter, PageIterOptions},
+    page_spool::PageSpool,
     prelude::*,
     queues::{
         ocr::OcrAnalysis,
         work::{WorkInput, WorkOutput, WorkStatus},
     },
+    s3::{create_s3_client, download_to_tempfile, is_s3_uri},
+    searchable_pdf::write_searchable_pdf_to_dir,
 };
 
+/// A page waiting in our shared page queue, and where to send its result.
+struct PageJob {
+    /// The page to OCR.
+    input: OcrPageInput,
+
+    /// Where to send the result.
+    tx: oneshot::Sender<Result<OcrPageOutput>>,
+}
+
 /// An OCR engine that splits a document into pages, and OCRs each page.
+///
+/// Pages from all documents go into a single queue, which is served by
+/// `concurrency_limit` workers. Each document only queues one page at a time,
+/// and waiting documents take turns, so one huge document can't starve smaller
+/// documents that arrive after it.
 pub struct SplitPagesOcrEngine {
     page_iter_opts: PageIterOptions,
     concurrency_limit: usize,
+    /// Our shared page queue, started on first use. Its workers exit once
+    /// this engine is dropped.
+    page_queue: OnceLock<mpsc::Sender<PageJob>>,
     include_page_breaks: bool,
     engine: Arc<dyn OcrPageEngine>,
+    /// Decides which pages to keep in memory, shared across all documents.
+    spool: PageSpool,
+    /// An S3 client for downloading `s3://` inputs, created on first use.
+    s3_client: OnceCell<aws_sdk_s3::Client>,
+    /// Where to write searchable PDFs, if anywhere.
+    searchable_pdf_dir: Option<PathBuf>,
 }
 
 impl SplitPagesOcrEngine {
@@ -35,14 +68,46 @@ impl SplitPagesOcrEngine {
         concurrency_limit: usize,
         include_page_breaks: bool,
         engine: Arc<dyn OcrPageEngine>,
+        searchable_pdf_dir: Option<PathBuf>,
     ) -> Self {
         Self {
+            spool: PageSpool::new(&page_iter_opts.spool_opts),
             page_iter_opts,
             concurrency_limit,
+            page_queue: OnceLock::new(),
             include_page_breaks,
             engine,
+            s3_client: OnceCell::new(),
+            searchable_pdf_dir,
         }
     }
+
+    /// Get a sender for our shared page queue, starting its workers if
+    /// needed.
+    fn page_queue(&self) -> mpsc::Sender<PageJob> {
+        self.page_queue
+            .get_or_init(|| {
+                let concurrency_limit = self.concurrency_limit.max(1);
+                let (tx, rx) = mpsc::channel::<PageJob>(concurrency_limit);
+                let engine = self.engine.clone();
+                tokio::spawn(async move {
+                    rx.for_each_concurrent(concurrency_limit, |job| {
+                        let engine = engine.clone();
+                        async move {
+                            let result = engine.ocr_page(job.input).await;
+                            if job.tx.send(result).is_err() {
+                                debug!(
+                                    "OCR page result dropped because document gave up"
+                                );
+                            }
+                        }
+                    })
+                    .await;
+                });
+                tx
+            })
+            .clone()
+    }
 }
 
 #[async_trait]
@@ -54,11 +119,25 @@ impl OcrFileEngine for SplitPagesOcrEngine {
     ) -> Result<WorkOutput<OcrOutput>> {
         let id = ocr_input.id.clone();
 
+        // Download `s3://` inputs to a temporary file, which will be deleted
+        // when we're done.
+        let downloaded = if is_s3_uri(&ocr_input.data.path) {
+            let client = self.s3_client.get_or_try_init(create_s3_client).await?;
+            Some(download_to_tempfile(client, &ocr_input.data.path).await?)
+        } else {
+            None
+        };
+        let path = match &downloaded {
+            Some(tmp) => tmp.path(),
+            None => ocr_input.data.path(),
+        };
+
         // Create a page stream, using BlockingIterStream to avoid blocking the
         // async executor with slow PDF processing.
         let page_iter = PageIter::from_path(
-            ocr_input.data.path(),
+            path,
             &self.page_iter_opts,
+            &self.spool,
             ocr_input.data.password.as_deref(),
         )
         .await
@@ -66,39 +145,58 @@ impl OcrFileEngine for SplitPagesOcrEngine {
             format!("Failed to separate {:?} into pages", ocr_input.data.path)
         })?;
         let check_complete_result = page_iter.check_complete();
-        let warnings = page_iter.warnings().to_owned();
+        let warnings = page_iter.warnings();
         let page_stream = BlockingIterStream::new(page_iter);
 
-        let page_outputs = page_stream
+        // Add our pages to the shared page queue one at a time, so that we
+        // take turns with any other documents that are waiting.
+        let page_queue = self.page_queue();
+        let receivers = page_stream
             .enumerate()
-            .map(move |(page_idx, page)| {
+            .then(|(page_idx, page)| {
                 let id = ocr_input.id.clone();
-                let engine = self.engine.clone();
+                let mut page_queue = page_queue.clone();
                 async move {
-                    let page = page?;
-                    engine.ocr_page(OcrPageInput { id, page_idx, page }).await
+                    let (tx, rx) = oneshot::channel();
+                    let input = OcrPageInput {
+                        id,
+                        page_idx,
+                        page: page?,
+                    };
+                    page_queue
+                        .send(PageJob { input, tx })
+                        .await
+                        .context("OCR page queue has shut down")?;
+                    Ok::<_, anyhow::Error>(rx)
                 }
             })
-            // Process all the pages concurrently, up to the concurrency limit.
-            .buffered(self.concurrency_limit)
-            // Collect all the results (including fatal errors) into a single vector.
             .collect::<Vec<_>>()
-            .await
-            // Convert from `Vec<Result<ChatOutput>>` to `Result<Vec<ChatOutput>>`,
-            // and exit early if we have any fatal errors.
-            .into_iter()
-            .collect::<Result<Vec<_>>>()?;
+            .await;
+
+        // Wait for all our pages, and exit early if we have any fatal errors.
+        let mut page_outputs = Vec::with_capacity(receivers.len());
+        for rx in receivers {
+            let output = rx?.await.context("OCR page worker exited")??;
+            page_outputs.push(output);
+        }
 
         // Turn our `ChatResponse`s into a `PdfOutput` record.
         let mut errors = vec![];
-        errors.extend(warnings);
+        errors.extend(
+            warnings
+                .lock()
+                .expect("page warnings lock poisoned")
+                .drain(..),
+        );
         let mut pages = vec![];
+        let mut page_words = vec![];
         let mut analysis = OcrAnalysis::default();
         let mut analysis_present = false;
         let mut estimated_cost = 0.0;
         let mut token_usage = TokenUsage::default();
         for page_output in page_outputs {
             errors.extend(page_output.errors);
+            page_words.push(page_output.words.filter(|_| page_output.text.is_some()));
             if let Some(text) = page_output.text {
                 pages.push(Some(text));
                 if let Some(a) = page_output.analysis {
@@ -128,6 +226,30 @@ impl OcrFileEngine for SplitPagesOcrEngine {
         };
 
         let good_page_count = pages.iter().filter(|p| p.is_some()).count();
+
+        // Write a searchable PDF, if requested.
+        let mut searchable_pdf_path = None;
+        if let Some(dir) = &self.searchable_pdf_dir
+            && good_page_count > 0
+        {
+            match write_searchable_pdf_to_dir(
+                dir,
+                &id,
+                path,
+                ocr_input.data.password.clone(),
+                page_words,
+            )
+            .await
+            {
+                Ok(written) => searchable_pdf_path = Some(written),
+                Err(err) => {
+                    errors.push(format!("Could not write searchable PDF: {err:#}"));
+                }
+            }
+        }
+        let searchable_pdf_failed =
+            self.searchable_pdf_dir.is_some() && searchable_pdf_path.is_none();
+
         let total_page_count = pages.len();
         let text = pages
             .into_iter()
@@ -138,6 +260,7 @@ impl OcrFileEngine for SplitPagesOcrEngine {
             id,
             status: if check_complete_result.is_ok()
                 && good_page_count == total_page_count
+                && !searchable_pdf_failed
             {
                 WorkStatus::Ok
             } else if good_page_count > 0 {
@@ -157,6 +280,10 @@ impl OcrFileEngine for SplitPagesOcrEngine {
                 Some(token_usage)
             },
             passthrough_data: ocr_input.passthrough_data,
+            ack_token: None,
+            prompt_version: None,
+            prompt_hash: None,
+            model: None,
             data: OcrOutput {
                 path: ocr_input.data.path,
                 text: if good_page_count > 0 {
@@ -172,6 +299,7 @@ impl OcrFileEngine for SplitPagesOcrEngine {
                 } else {
                     None
                 },
+                searchable_pdf_path,
             },
         })
     }
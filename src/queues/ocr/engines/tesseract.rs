@@ -1,45 +1,77 @@
 //! Tesseract OCR engine.
+//!
+//! By default, we run the `tesseract` CLI once per page. When built with
+//! `--features tesseract-lib`, we instead keep a pool of warm in-process
+//! `libtesseract` engines, which avoids paying process startup and model
+//! loading costs on every page.
 
-//! An "OCR" engine that calls `pdftotext`.
-
+use std::sync::Arc;
+#[cfg(not(feature = "tesseract-lib"))]
 use std::{
     fs::{File, read_to_string},
     io::Write as _,
-    sync::Arc,
 };
 
-use tokio::process::Command;
-
+#[cfg(feature = "tesseract-lib")]
+use crate::cpu_limit::cpu_job_count;
+use crate::{
+    async_utils::JoinWorker, prelude::*, searchable_pdf::words_from_tesseract_tsv,
+};
+#[cfg(not(feature = "tesseract-lib"))]
 use crate::{
-    async_utils::{JoinWorker, check_for_command_failure},
-    page_iter::PageIterOptions,
-    prelude::*,
+    async_utils::check_for_command_failure,
+    cpu_limit::with_cpu_semaphore,
+    process_limits::{limited_command, limited_output},
 };
 
 use super::page::{OcrPageEngine, OcrPageInput, OcrPageOutput};
 
-/// OCR engine wrapping the `tesseract` CLI tool.
+/// OCR engine wrapping the `tesseract` CLI tool, or `libtesseract`.
 #[non_exhaustive]
-pub struct TesseractOcrPageEngine {}
+pub struct TesseractOcrPageEngine {
+    /// Should we report the position of each word?
+    word_boxes: bool,
+
+    /// Our pool of warm engines.
+    #[cfg(feature = "tesseract-lib")]
+    pool: pool::TesseractPool,
+}
 
 impl TesseractOcrPageEngine {
-    /// Create a new `tesseract` engine.
+    /// Create a new `tesseract` engine. We will OCR at most `--cpu-jobs`
+    /// pages at once. If `word_boxes` is set, we also report the position of
+    /// each word.
     #[allow(clippy::new_ret_no_self)]
-    pub fn new(
-        page_iter_opts: &PageIterOptions,
-    ) -> Result<(Arc<dyn OcrPageEngine>, JoinWorker)> {
-        if page_iter_opts.rasterize {
-            Ok((Arc::new(Self {}), JoinWorker::noop()))
-        } else {
-            Err(anyhow!("tesseract requires --rasterize"))
-        }
+    pub fn new(word_boxes: bool) -> Result<(Arc<dyn OcrPageEngine>, JoinWorker)> {
+        Ok((
+            Arc::new(Self {
+                word_boxes,
+                #[cfg(feature = "tesseract-lib")]
+                pool: pool::TesseractPool::new(cpu_job_count()),
+            }),
+            JoinWorker::noop(),
+        ))
     }
-}
 
-#[async_trait]
-impl OcrPageEngine for TesseractOcrPageEngine {
-    #[instrument(level = "debug", skip_all, fields(id = %input.id, page = %input.page_idx))]
-    async fn ocr_page(&self, input: OcrPageInput) -> Result<OcrPageOutput> {
+    /// OCR a page using our pool of `libtesseract` engines, returning the
+    /// text and (if requested) the `tsv` output.
+    #[cfg(feature = "tesseract-lib")]
+    async fn ocr_page_text(
+        &self,
+        input: OcrPageInput,
+    ) -> Result<(String, Option<String>)> {
+        self.pool
+            .ocr(input.page.data.into_bytes().await?, self.word_boxes)
+            .await
+    }
+
+    /// OCR a page by running the `tesseract` CLI, returning the text and (if
+    /// requested) the `tsv` output.
+    #[cfg(not(feature = "tesseract-lib"))]
+    async fn ocr_page_text(
+        &self,
+        input: OcrPageInput,
+    ) -> Result<(String, Option<String>)> {
         let extension = mime_guess::get_mime_extensions_str(&input.page.mime_type)
             .and_then(|o| o.first())
             .ok_or_else(|| {
@@ -53,24 +85,50 @@ impl OcrPageEngine for TesseractOcrPageEngine {
         let mut input_file =
             File::create(&input_path).context("cannot create tesseract input file")?;
         input_file
-            .write_all(&input.page.data)
+            .write_all(&input.page.data.into_bytes().await?)
             .context("cannot write tesseract input file")?;
         input_file
             .flush()
             .context("cannot flush tesseract input file")?;
 
         // Run tesseract on the input file.
-        let output = Command::new("tesseract")
-            .arg(input_path)
-            .arg(output_path.with_extension(""))
-            .output()
-            .await
-            .context("cannot run tesseract")?;
+        //
+        // We use `with_cpu_semaphore` because `tesseract` will use 100% of a
+        // CPU.
+        let output = with_cpu_semaphore(|| async {
+            let mut command = limited_command("tesseract");
+            command.arg(input_path).arg(output_path.with_extension(""));
+            if self.word_boxes {
+                command.args(["txt", "tsv"]);
+            }
+            limited_output(&mut command)
+                .await
+                .context("cannot run tesseract")
+        })
+        .await?;
         check_for_command_failure("tesseract", &output, None)?;
 
-        // Read the output file.
+        // Read the output files.
         let text =
             read_to_string(&output_path).context("cannot read tesseract output file")?;
+        let tsv = if self.word_boxes {
+            Some(
+                read_to_string(output_path.with_extension("tsv"))
+                    .context("cannot read tesseract TSV output file")?,
+            )
+        } else {
+            None
+        };
+        Ok((text, tsv))
+    }
+}
+
+#[async_trait]
+impl OcrPageEngine for TesseractOcrPageEngine {
+    #[instrument(level = "debug", skip_all, fields(id = %input.id, page = %input.page_idx))]
+    async fn ocr_page(&self, input: OcrPageInput) -> Result<OcrPageOutput> {
+        let (text, tsv) = self.ocr_page_text(input).await?;
+        let words = tsv.as_deref().map(words_from_tesseract_tsv).transpose()?;
         let errors = vec![];
         Ok(OcrPageOutput {
             text: Some(text),
@@ -78,6 +136,96 @@ impl OcrPageEngine for TesseractOcrPageEngine {
             analysis: None,
             estimated_cost: None,
             token_usage: None,
+            words,
         })
     }
 }
+
+#[cfg(feature = "tesseract-lib")]
+mod pool {
+    //! A pool of in-process `libtesseract` engines.
+
+    use std::sync::{Arc, Mutex};
+
+    use leptess::LepTess;
+    use tokio::sync::Semaphore;
+
+    use crate::{
+        async_utils::blocking_iter_streams::spawn_blocking_propagating_panics, prelude::*,
+    };
+
+    /// The language to OCR. This matches the `tesseract` CLI default.
+    const LANGUAGE: &str = "eng";
+
+    /// A pool of warm `libtesseract` engines, reused across pages.
+    ///
+    /// Engines are created lazily, so we never load more models than we need.
+    pub struct TesseractPool {
+        /// Limits how many pages we OCR at once.
+        semaphore: Semaphore,
+
+        /// Idle engines, ready for reuse.
+        idle: Arc<Mutex<Vec<LepTess>>>,
+    }
+
+    impl TesseractPool {
+        /// Create a pool which will run at most `size` engines at once.
+        pub fn new(size: usize) -> Self {
+            Self {
+                semaphore: Semaphore::new(size.max(1)),
+                idle: Arc::new(Mutex::new(vec![])),
+            }
+        }
+
+        /// OCR an image, returning its text, and optionally its `tsv` output
+        /// with word positions.
+        pub async fn ocr(
+            &self,
+            image: Vec<u8>,
+            word_boxes: bool,
+        ) -> Result<(String, Option<String>)> {
+            let _permit = self
+                .semaphore
+                .acquire()
+                .await
+                .context("Could not acquire tesseract permit")?;
+            let idle = self.idle.clone();
+            spawn_blocking_propagating_panics(move || {
+                let engine = idle.lock().expect("tesseract pool lock poisoned").pop();
+                let mut engine = match engine {
+                    Some(engine) => engine,
+                    None => {
+                        debug!("Starting new libtesseract engine");
+                        LepTess::new(None, LANGUAGE)
+                            .context("cannot initialize libtesseract")?
+                    }
+                };
+                let text = engine
+                    .set_image_from_mem(&image)
+                    .context("cannot load image into libtesseract")
+                    .and_then(|()| {
+                        engine
+                            .get_utf8_text()
+                            .context("cannot get text from libtesseract")
+                    })
+                    .and_then(|text| {
+                        let tsv = if word_boxes {
+                            Some(
+                                engine
+                                    .get_tsv_text(0)
+                                    .context("cannot get TSV from libtesseract")?,
+                            )
+                        } else {
+                            None
+                        };
+                        Ok((text, tsv))
+                    });
+                idle.lock()
+                    .expect("tesseract pool lock poisoned")
+                    .push(engine);
+                text
+            })
+            .await
+        }
+    }
+}
@@ -1,40 +1,278 @@
 //! OCR using AWS Textract.
 
 use std::collections::{HashMap, HashSet};
-use std::env;
 use std::sync::Arc;
+use std::{env, fmt, str::FromStr};
 
 use aws_sdk_textract::operation::get_document_analysis::GetDocumentAnalysisError;
-use aws_sdk_textract::operation::start_document_analysis::{
-    StartDocumentAnalysisError, StartDocumentAnalysisOutput,
-};
+use aws_sdk_textract::operation::get_document_text_detection::GetDocumentTextDetectionError;
+use aws_sdk_textract::operation::start_document_analysis::StartDocumentAnalysisError;
+use aws_sdk_textract::operation::start_document_text_detection::StartDocumentTextDetectionError;
 use aws_sdk_textract::types::{
     Block, DocumentLocation, FeatureType, JobStatus, RelationshipType, S3Object,
 };
 use aws_sdk_textract::{primitives::Blob, types::BlockType};
-use leaky_bucket::RateLimiter;
-use tokio::time::{Duration, sleep};
+use clap::Args;
+use tokio::time::{Duration, Instant, sleep};
 use uuid::Uuid;
 
 use crate::aws::load_aws_config;
 use crate::drivers::LlmOpts;
-use crate::page_iter::PageIterOptions;
 use crate::prelude::*;
 
 use crate::async_utils::JoinWorker;
-use crate::rate_limit::{RateLimit, RateLimitPeriod};
+use crate::rate_limit::{RateLimit, RateLimitPeriod, RequestLimiter};
 use crate::retry::{
     DEFAULT_JITTER, IsKnownTransient, retry_result_ok, retry_with_backoff,
     try_potentially_transient,
 };
+use crate::s3::{
+    ScratchBucket, ScratchObject, create_s3_client, download_to_tempfile, is_s3_uri,
+    parse_s3_uri,
+};
+use crate::searchable_pdf::{OcrWord, write_searchable_pdf_to_dir};
 
 use super::file::OcrFileEngine;
 use super::page::{OcrPageEngine, OcrPageInput, OcrPageOutput};
 use crate::queues::ocr::{OcrInput, OcrOutput};
 use crate::queues::work::{WorkInput, WorkOutput, WorkStatus};
 
-/// Our estimated page cost, based on the options we use.
-const ESTIMATED_PAGE_COST: f64 = 0.004;
+/// The largest document we can pass to the synchronous Textract API as bytes.
+const MAX_INLINE_DOCUMENT_BYTES: usize = 10 * 1024 * 1024;
+
+/// Which Textract API to call, and which analysis features to request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextractMode {
+    /// Plain text detection, using `DetectDocumentText`. This is the cheapest
+    /// option, but returns lines in reading order without any layout.
+    Detect,
+    /// Document analysis, using `AnalyzeDocument` with `LAYOUT`, plus
+    /// optional table and form extraction.
+    Analyze {
+        /// Also request `TABLES`.
+        tables: bool,
+        /// Also request `FORMS`.
+        forms: bool,
+    },
+}
+
+impl TextractMode {
+    /// The feature types to request from the analysis APIs, or `None` if we
+    /// should use the text detection APIs.
+    fn feature_types(self) -> Option<Vec<FeatureType>> {
+        match self {
+            TextractMode::Detect => None,
+            TextractMode::Analyze { tables, forms } => {
+                let mut features = vec![FeatureType::Layout];
+                if tables {
+                    features.push(FeatureType::Tables);
+                }
+                if forms {
+                    features.push(FeatureType::Forms);
+                }
+                Some(features)
+            }
+        }
+    }
+
+    /// Our estimated cost per page, based on US list prices. Layout is
+    /// included for free when combined with tables or forms.
+    fn estimated_page_cost(self) -> f64 {
+        match self {
+            TextractMode::Detect => 0.0015,
+            TextractMode::Analyze { tables, forms } => match (tables, forms) {
+                (false, false) => 0.004,
+                (true, false) => 0.015,
+                (false, true) => 0.05,
+                (true, true) => 0.065,
+            },
+        }
+    }
+}
+
+impl Default for TextractMode {
+    fn default() -> Self {
+        TextractMode::Analyze {
+            tables: false,
+            forms: false,
+        }
+    }
+}
+
+impl fmt::Display for TextractMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextractMode::Detect => write!(f, "detect"),
+            TextractMode::Analyze { tables, forms } => {
+                write!(f, "analyze")?;
+                if *tables {
+                    write!(f, ",tables")?;
+                }
+                if *forms {
+                    write!(f, ",forms")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for TextractMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(',').map(str::trim);
+        match parts.next() {
+            Some("detect") => match parts.next() {
+                None => Ok(TextractMode::Detect),
+                Some(feature) => Err(anyhow!(
+                    "Textract mode \"detect\" does not support {feature:?}"
+                )),
+            },
+            Some("analyze") => {
+                let (mut tables, mut forms) = (false, false);
+                for feature in parts {
+                    match feature {
+                        "tables" => tables = true,
+                        "forms" => forms = true,
+                        _ => {
+                            return Err(anyhow!("Unknown Textract feature: {feature:?}"));
+                        }
+                    }
+                }
+                Ok(TextractMode::Analyze { tables, forms })
+            }
+            _ => Err(anyhow!(
+                "Textract mode must be \"detect\" or \"analyze[,tables][,forms]\", got {s:?}"
+            )),
+        }
+    }
+}
+
+/// Textract-specific options.
+#[derive(Args, Clone, Debug, Default)]
+#[clap(next_help_heading = "Textract options")]
+pub struct TextractOpts {
+    /// Textract mode: `detect` for cheaper plain text detection, or
+    /// `analyze[,tables][,forms]` for layout analysis, optionally with
+    /// table and form extraction.
+    #[clap(long, default_value_t)]
+    pub textract_mode: TextractMode,
+
+    /// An S3 bucket (optionally with a key prefix, as in `s3://bucket/prefix`)
+    /// used to upload local files for `textract-async`, and pages too large to
+    /// send directly to `textract`. Uploaded objects are deleted after use,
+    /// but you may also want an S3 lifecycle rule to clean up after crashes.
+    #[clap(long)]
+    pub textract_scratch_bucket: Option<String>,
+
+    /// How long to wait for each `textract-async` job to finish, in seconds.
+    /// Defaults to `--timeout`, or 300 seconds if that isn't set.
+    #[clap(long, value_name = "SECS")]
+    pub ocr_timeout: Option<u64>,
+}
+
+impl TextractOpts {
+    /// List the flags which have been set to something other than their
+    /// defaults. These only affect the Textract engines.
+    pub fn flags_set(&self) -> Vec<&'static str> {
+        let flags = [
+            (
+                "--textract-mode",
+                self.textract_mode != TextractMode::default(),
+            ),
+            (
+                "--textract-scratch-bucket",
+                self.textract_scratch_bucket.is_some(),
+            ),
+            ("--ocr-timeout", self.ocr_timeout.is_some()),
+        ];
+        flags
+            .into_iter()
+            .filter_map(|(flag, set)| set.then_some(flag))
+            .collect()
+    }
+
+    /// How long should we wait for an asynchronous job?
+    fn job_timeout(&self, llm_opts: &LlmOpts) -> Duration {
+        Duration::from_secs(
+            self.ocr_timeout
+                .or(llm_opts.timeout)
+                .unwrap_or(DEFAULT_JOB_TIMEOUT_SECS),
+        )
+    }
+
+    /// Create our scratch bucket, if one was specified.
+    async fn scratch_bucket(&self) -> Result<Option<ScratchBucket>> {
+        match &self.textract_scratch_bucket {
+            Some(spec) => Ok(Some(ScratchBucket::new(spec).await?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// How long to wait for an asynchronous job by default, in seconds.
+const DEFAULT_JOB_TIMEOUT_SECS: u64 = 300;
+
+/// How long to wait before first checking on an asynchronous job.
+const FIRST_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The longest we wait between checks on an asynchronous job.
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When to check on an asynchronous job. We start checking quickly, because
+/// small documents finish in seconds, and back off exponentially with jitter,
+/// so that many large jobs don't all poll at once.
+#[derive(Debug)]
+struct PollSchedule {
+    /// When we started waiting.
+    started: Instant,
+
+    /// How long we may wait in total.
+    timeout: Duration,
+
+    /// Our next interval, before jitter.
+    interval: Duration,
+
+    /// How many times we've polled.
+    polls: u32,
+}
+
+impl PollSchedule {
+    /// Create a schedule which gives up after `timeout`.
+    fn new(timeout: Duration) -> Self {
+        Self {
+            started: Instant::now(),
+            timeout,
+            interval: FIRST_POLL_INTERVAL,
+            polls: 0,
+        }
+    }
+
+    /// Record a poll, and return how long to wait before the next one, or
+    /// `None` if we're out of time. `jitter` should be between 0.8 and 1.2.
+    fn next_delay(&mut self, jitter: f64) -> Option<Duration> {
+        self.polls += 1;
+        let remaining = self.timeout.checked_sub(self.started.elapsed())?;
+        if remaining.is_zero() {
+            return None;
+        }
+        let delay = self.interval.mul_f64(jitter).min(remaining);
+        self.interval = (self.interval * 2).min(MAX_POLL_INTERVAL);
+        Some(delay)
+    }
+
+    /// Explain why we gave up.
+    fn timeout_error(&self, job_id: &str) -> anyhow::Error {
+        anyhow!(
+            "Textract job {job_id} was still in progress after {:.0}s ({} checks), \
+             exceeding the {}s job timeout (see --ocr-timeout)",
+            self.started.elapsed().as_secs_f64(),
+            self.polls,
+            self.timeout.as_secs()
+        )
+    }
+}
 
 /// Create a Textract client.
 async fn create_textract_client() -> Result<aws_sdk_textract::Client> {
@@ -43,7 +281,10 @@ async fn create_textract_client() -> Result<aws_sdk_textract::Client> {
 }
 
 /// Create a rate limiter, defaulting as best we can.
-fn create_rate_limiter(concurrency_limit: usize, llm_opts: &LlmOpts) -> RateLimiter {
+async fn create_rate_limiter(
+    concurrency_limit: usize,
+    llm_opts: &LlmOpts,
+) -> Result<Arc<RequestLimiter>> {
     // If we don't have a rate limit, set one based on the concurrency limit.
     //
     // TODO: FUTURE BREAKING: We may want to remove the default rate limit, but
@@ -52,10 +293,13 @@ fn create_rate_limiter(concurrency_limit: usize, llm_opts: &LlmOpts) -> RateLimi
         .rate_limit
         .clone()
         .unwrap_or_else(|| RateLimit::new(concurrency_limit, RateLimitPeriod::Second));
-    rate_limit.to_rate_limiter()
+    rate_limit
+        .to_request_limiter(llm_opts.rate_limit_backend.as_ref(), "textract")
+        .await
 }
 
-/// OCR engine wrapping the synchronous AWS Textract `analyze_document` API.
+/// OCR engine wrapping the synchronous AWS Textract `analyze_document` and
+/// `detect_document_text` APIs.
 ///
 /// This needs to be a page engine because Textract does not support multi-page
 /// PDFs in this mode.
@@ -64,7 +308,13 @@ pub struct TextractOcrPageEngine {
     client: aws_sdk_textract::Client,
 
     /// A rate limiter to avoid hitting API limits.
-    rate_limiter: RateLimiter,
+    rate_limiter: Arc<RequestLimiter>,
+
+    /// Where to upload pages which are too large to send directly.
+    scratch_bucket: Option<ScratchBucket>,
+
+    /// Which Textract API to use.
+    mode: TextractMode,
 }
 
 impl TextractOcrPageEngine {
@@ -73,14 +323,18 @@ impl TextractOcrPageEngine {
     pub async fn new(
         concurrency_limit: usize,
         llm_opts: &LlmOpts,
+        textract_opts: &TextractOpts,
     ) -> Result<(Arc<dyn OcrPageEngine>, JoinWorker)> {
         let client = create_textract_client().await?;
-        let rate_limiter = create_rate_limiter(concurrency_limit, llm_opts);
+        let rate_limiter = create_rate_limiter(concurrency_limit, llm_opts).await?;
+        let scratch_bucket = textract_opts.scratch_bucket().await?;
 
         Ok((
             Arc::new(Self {
                 client,
                 rate_limiter,
+                scratch_bucket,
+                mode: textract_opts.textract_mode,
             }),
             JoinWorker::noop(),
         ))
@@ -96,43 +350,86 @@ impl OcrPageEngine for TextractOcrPageEngine {
 
         // TODO: We may need to convert GIF and WEBP to a supported format.
 
-        // Build our document.
-        let document = aws_sdk_textract::types::Document::builder()
-            .bytes(Blob::new(input.page.data.clone()))
-            .build();
+        // Build our document. Large pages need to be uploaded to S3 first.
+        let mut scratch_object = None;
+        let document = if input.page.data.len() <= MAX_INLINE_DOCUMENT_BYTES {
+            aws_sdk_textract::types::Document::builder()
+                .bytes(Blob::new(input.page.data.read().await?))
+                .build()
+        } else if let Some(scratch_bucket) = &self.scratch_bucket {
+            let object = scratch_bucket
+                .upload_bytes(input.page.data.read().await?)
+                .await?;
+            let document = aws_sdk_textract::types::Document::builder()
+                .s3_object(scratch_s3_object(&object))
+                .build();
+            scratch_object = Some(object);
+            document
+        } else {
+            return Ok(OcrPageOutput {
+                text: None,
+                errors: vec![format!(
+                    "Page is {} bytes, which is too large to send to Textract directly; try --textract-scratch-bucket",
+                    input.page.data.len()
+                )],
+                analysis: None,
+                estimated_cost: None,
+                token_usage: None,
+                words: None,
+            });
+        };
 
         // Use the Textract API to process the image.
-        let response = self
-            .client
-            .analyze_document()
-            .document(document)
-            .set_feature_types(Some(vec![FeatureType::Layout]))
-            .send()
-            .await;
+        let response = match self.mode.feature_types() {
+            None => self
+                .client
+                .detect_document_text()
+                .document(document)
+                .send()
+                .await
+                .map(|output| output.blocks.unwrap_or_default())
+                .map_err(|e| format!("{e:?}")),
+            Some(feature_types) => self
+                .client
+                .analyze_document()
+                .document(document)
+                .set_feature_types(Some(feature_types))
+                .send()
+                .await
+                .map(|output| output.blocks.unwrap_or_default())
+                .map_err(|e| format!("{e:?}")),
+        };
+        if let Some(scratch_object) = scratch_object
+            && let Err(err) = scratch_object.delete().await
+        {
+            warn!("Failed to delete scratch object: {err:?}");
+        }
         match response {
             Err(e) => {
                 return Ok(OcrPageOutput {
                     text: None,
-                    errors: vec![format!("AWS Textract error: {e:?}")],
+                    errors: vec![format!("AWS Textract error: {e}")],
                     analysis: None,
                     estimated_cost: None,
                     token_usage: None,
+                    words: None,
                 });
             }
-            Ok(document) => {
-                trace!("Document response: {document:#?}");
+            Ok(blocks) => {
+                trace!("Document blocks: {blocks:#?}");
 
                 // Create our output state and get our text.
-                let mut output = OutputState::new(document.blocks(), false);
-                output.write_analyzed_document()?;
+                let mut output = OutputState::new(&blocks, false);
+                output.write_document(self.mode)?;
                 let text = output.output;
                 debug!(%text, "Extracted text");
                 Ok(OcrPageOutput {
                     text: Some(text),
                     errors: vec![],
                     analysis: None,
-                    estimated_cost: Some(ESTIMATED_PAGE_COST),
+                    estimated_cost: Some(self.mode.estimated_page_cost()),
                     token_usage: None,
+                    words: words_by_page(&blocks).into_iter().next(),
                 })
             }
         }
@@ -140,10 +437,10 @@ impl OcrPageEngine for TextractOcrPageEngine {
 }
 
 /// OCR engine wrapping the asynchronous AWS Textract `start_document_analysis`
-/// and `get_document_analysis` APIs.
+/// and `get_document_analysis` APIs (or their text detection equivalents).
 ///
-/// For now, this can only operate on files stored in S3. All passed-in file
-/// paths must be valid S3 URIs.
+/// Textract can only read files stored in S3. Local files will be uploaded to
+/// `--textract-scratch-bucket` if it is specified.
 pub struct TextractOcrFileEngine {
     /// Should we include page breaks between pages using a form-feed character?
     include_page_breaks: bool,
@@ -152,36 +449,170 @@ pub struct TextractOcrFileEngine {
     client: aws_sdk_textract::Client,
 
     /// A rate limiter to avoid hitting API limits.
-    rate_limiter: RateLimiter,
+    rate_limiter: Arc<RequestLimiter>,
+
+    /// Where to upload local files, if anywhere.
+    scratch_bucket: Option<ScratchBucket>,
+
+    /// Which Textract API to use.
+    mode: TextractMode,
+
+    /// Where to write searchable PDFs, if anywhere.
+    searchable_pdf_dir: Option<PathBuf>,
+
+    /// How long to wait for each job.
+    job_timeout: Duration,
+}
+
+/// The state of an asynchronous Textract job.
+struct TextractJobState {
+    /// The job status, if known.
+    status: Option<JobStatus>,
+
+    /// Any blocks returned so far.
+    blocks: Vec<Block>,
+
+    /// The number of pages in the document, if known.
+    page_count: Option<i32>,
 }
 
 impl TextractOcrFileEngine {
     /// Create a new `textract` engine.
     #[allow(clippy::new_ret_no_self)]
     pub async fn new(
-        page_iter_opts: &PageIterOptions,
         concurrency_limit: usize,
         include_page_breaks: bool,
         llm_opts: &LlmOpts,
+        textract_opts: &TextractOpts,
+        searchable_pdf_dir: Option<PathBuf>,
     ) -> Result<(Arc<dyn OcrFileEngine>, JoinWorker)> {
         let client = create_textract_client().await?;
-        let rate_limiter = create_rate_limiter(concurrency_limit, llm_opts);
-
-        if page_iter_opts.max_pages.is_some() || page_iter_opts.rasterize {
-            return Err(anyhow!(
-                "textract-async does not work with --max-pages or --rasterize"
-            ));
-        }
+        let rate_limiter = create_rate_limiter(concurrency_limit, llm_opts).await?;
+        let scratch_bucket = textract_opts.scratch_bucket().await?;
 
         Ok((
             Arc::new(Self {
                 include_page_breaks,
                 client,
                 rate_limiter,
+                scratch_bucket,
+                mode: textract_opts.textract_mode,
+                searchable_pdf_dir,
+                job_timeout: textract_opts.job_timeout(llm_opts),
             }) as Arc<dyn OcrFileEngine>,
             JoinWorker::noop(),
         ))
     }
+
+    /// Write a searchable copy of our input PDF, downloading it from S3 if
+    /// necessary.
+    async fn write_searchable_pdf(
+        &self,
+        dir: &Path,
+        id: &Value,
+        input: &OcrInput,
+        pages: Vec<Option<Vec<OcrWord>>>,
+    ) -> Result<String> {
+        let downloaded = if is_s3_uri(&input.path) {
+            let client = create_s3_client().await?;
+            Some(download_to_tempfile(&client, &input.path).await?)
+        } else {
+            None
+        };
+        let path = match &downloaded {
+            Some(tmp) => tmp.path(),
+            None => input.path(),
+        };
+        write_searchable_pdf_to_dir(dir, id, path, input.password.clone(), pages).await
+    }
+
+    /// Start an asynchronous Textract job, returning the job ID.
+    async fn start_job(&self, document_location: DocumentLocation) -> Result<String> {
+        let idempotency_id = Uuid::new_v4();
+        let job_id = match self.mode.feature_types() {
+            None => {
+                retry_with_backoff(DEFAULT_JITTER, move || {
+                    let document_location = document_location.clone();
+                    async move {
+                        retry_result_ok(try_potentially_transient!(
+                            self.client
+                                .start_document_text_detection()
+                                .document_location(document_location)
+                                .client_request_token(idempotency_id)
+                                .send()
+                                .await
+                        ))
+                    }
+                })
+                .await
+                .into_result()?
+                .job_id
+            }
+            Some(feature_types) => {
+                retry_with_backoff(DEFAULT_JITTER, move || {
+                    let document_location = document_location.clone();
+                    let feature_types = feature_types.clone();
+                    async move {
+                        retry_result_ok(try_potentially_transient!(
+                            self.client
+                                .start_document_analysis()
+                                .document_location(document_location)
+                                .client_request_token(idempotency_id)
+                                .set_feature_types(Some(feature_types))
+                                .send()
+                                .await
+                        ))
+                    }
+                })
+                .await
+                .into_result()?
+                .job_id
+            }
+        };
+        job_id.ok_or_else(|| anyhow!("No job ID returned when starting Textract job"))
+    }
+
+    /// Get the current state of an asynchronous Textract job.
+    async fn get_job(&self, job_id: &str) -> Result<TextractJobState> {
+        match self.mode.feature_types() {
+            None => {
+                let response = retry_with_backoff(DEFAULT_JITTER, move || async move {
+                    retry_result_ok(try_potentially_transient!(
+                        self.client
+                            .get_document_text_detection()
+                            .job_id(job_id)
+                            .send()
+                            .await
+                    ))
+                })
+                .await
+                .into_result()?;
+                Ok(TextractJobState {
+                    status: response.job_status,
+                    blocks: response.blocks.unwrap_or_default(),
+                    page_count: response.document_metadata.and_then(|m| m.pages),
+                })
+            }
+            Some(_) => {
+                let response = retry_with_backoff(DEFAULT_JITTER, move || async move {
+                    retry_result_ok(try_potentially_transient!(
+                        self.client
+                            .get_document_analysis()
+                            .job_id(job_id)
+                            .send()
+                            .await
+                    ))
+                })
+                .await
+                .into_result()?;
+                Ok(TextractJobState {
+                    status: response.job_status,
+                    blocks: response.blocks.unwrap_or_default(),
+                    page_count: response.document_metadata.and_then(|m| m.pages),
+                })
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -196,75 +627,43 @@ impl OcrFileEngine for TextractOcrFileEngine {
         // Rate limit the request.
         self.rate_limiter.acquire_one().await;
 
-        // Parse the S3 URI.
-        let s3_uri = &ocr_input.data.path;
-        let (bucket, key) = parse_s3_uri(s3_uri)
-            .with_context(|| format!("Failed to parse S3 URI: {}", s3_uri))?;
-
-        // Start document analysis.
-        let document_location = DocumentLocation::builder()
-            .s3_object(
-                S3Object::builder()
-                    .bucket(bucket.clone())
-                    .name(key.clone())
-                    .build(),
-            )
-            .build();
-        let idempotency_id = Uuid::new_v4();
-        let start_response: StartDocumentAnalysisOutput =
-            retry_with_backoff(DEFAULT_JITTER, move || {
-                let document_location = document_location.clone();
-                async move {
-                    retry_result_ok(try_potentially_transient!(
-                        self.client
-                            .start_document_analysis()
-                            .document_location(document_location)
-                            .client_request_token(idempotency_id)
-                            .set_feature_types(Some(vec![FeatureType::Layout]))
-                            .send()
-                            .await
-                    ))
-                }
-            })
-            .await
-            .into_result()?;
-
-        let job_id = start_response
-            .job_id()
-            .ok_or_else(|| anyhow!("No job ID returned from start_document_analysis"))?;
-
-        // Poll for results.
-        //
-        // TODO: Integrate crate::retry support here.
-        let max_retries = 60; // 5 minutes max with 5-second intervals
-        let mut retry_count = 0;
+        // Find our document in S3, uploading it if necessary. Our scratch
+        // object will be deleted when it goes out of scope.
+        let path = &ocr_input.data.path;
+        let (s3_object, _scratch_object) = if is_s3_uri(path) {
+            let (bucket, key) = parse_s3_uri(path)
+                .with_context(|| format!("Failed to parse S3 URI: {}", path))?;
+            (S3Object::builder().bucket(bucket).name(key).build(), None)
+        } else if let Some(scratch_bucket) = &self.scratch_bucket {
+            let object = scratch_bucket.upload_file(ocr_input.data.path()).await?;
+            (scratch_s3_object(&object), Some(object))
+        } else {
+            return Err(anyhow!(
+                "textract-async needs an s3:// path or --textract-scratch-bucket, got {path:?}"
+            ));
+        };
+
+        // Start our Textract job.
+        let document_location = DocumentLocation::builder().s3_object(s3_object).build();
+        let job_id = self.start_job(document_location).await?;
+        let job_id = job_id.as_str();
+
+        // Poll for results until our job finishes or we run out of time.
+        let mut schedule = PollSchedule::new(self.job_timeout);
         let (status, response) = loop {
-            let response = retry_with_backoff(DEFAULT_JITTER, move || async move {
-                retry_result_ok(try_potentially_transient!(
-                    self.client
-                        .get_document_analysis()
-                        .job_id(job_id)
-                        .send()
-                        .await
-                ))
-            })
-            .await
-            .into_result()?;
-
-            if let Some(status) = response.job_status() {
+            let response = self.get_job(job_id).await?;
+
+            if let Some(status) = &response.status {
                 match status {
                     JobStatus::InProgress => {
                         trace!("Job {} still in progress", job_id);
-                        retry_count += 1;
-                        if retry_count >= max_retries {
-                            return Err(anyhow!(
-                                "Textract job {} timed out after {} retries",
-                                job_id,
-                                max_retries
-                            ));
-                        } else {
-                            sleep(Duration::from_secs(5)).await;
-                            continue;
+                        let jitter = rand::random_range(0.8..1.2);
+                        match schedule.next_delay(jitter) {
+                            Some(delay) => {
+                                sleep(delay).await;
+                                continue;
+                            }
+                            None => return Err(schedule.timeout_error(job_id)),
                         }
                     }
                     JobStatus::Succeeded => {
@@ -289,33 +688,56 @@ impl OcrFileEngine for TextractOcrFileEngine {
             }
         };
 
-        trace!("Document analysis response: {:#?}", response);
+        trace!("Document blocks: {:#?}", response.blocks);
 
         // Create our output state and get our text.
-        let mut output = OutputState::new(response.blocks(), self.include_page_breaks);
-        output.write_analyzed_document()?;
+        let mut output = OutputState::new(&response.blocks, self.include_page_breaks);
+        output.write_document(self.mode)?;
         let text = output.output;
         debug!(%text, "Extracted text");
 
         // Calculate estimated cost based on pages.
-        let page_count = response
-            .document_metadata()
-            .and_then(|metadata| metadata.pages())
-            .unwrap_or(0);
-        let estimated_cost = ESTIMATED_PAGE_COST * f64::from(page_count);
+        let page_count = response.page_count.unwrap_or(0);
+        let estimated_cost = self.mode.estimated_page_cost() * f64::from(page_count);
+
+        // Write a searchable PDF, if requested.
+        let mut status = status;
+        let mut errors = vec![];
+        let mut searchable_pdf_path = None;
+        if let Some(dir) = &self.searchable_pdf_dir {
+            let pages = words_by_page(&response.blocks)
+                .into_iter()
+                .map(Some)
+                .collect();
+            match self
+                .write_searchable_pdf(dir, &id, &ocr_input.data, pages)
+                .await
+            {
+                Ok(written) => searchable_pdf_path = Some(written),
+                Err(err) => {
+                    errors.push(format!("Could not write searchable PDF: {err:#}"));
+                    status = WorkStatus::Incomplete;
+                }
+            }
+        }
 
         Ok(WorkOutput {
             id,
             status,
-            errors: vec![],
+            errors,
             estimated_cost: Some(estimated_cost),
             token_usage: None,
             passthrough_data: ocr_input.passthrough_data,
+            ack_token: None,
+            prompt_version: None,
+            prompt_hash: None,
+            model: None,
             data: OcrOutput {
                 path: ocr_input.data.path,
                 text: Some(text),
                 page_count: usize::try_from(page_count).ok(),
                 analysis: None,
+                searchable_pdf_path,
             },
         })
     }
@@ -332,6 +754,32 @@ impl IsKnownTransient for StartDocumentAnalysisError {
     }
 }
 
+impl IsKnownTransient for StartDocumentTextDetectionError {
+    fn is_known_transient(&self) -> bool {
+        matches!(
+            self,
+            StartDocumentTextDetectionError::LimitExceededException(_)
+                | StartDocumentTextDetectionError::ProvisionedThroughputExceededException(
+                    _
+                )
+                | StartDocumentTextDetectionError::ThrottlingException(_)
+        )
+    }
+}
+
+impl IsKnownTransient for GetDocumentTextDetectionError {
+    fn is_known_transient(&self) -> bool {
+        matches!(
+            self,
+            GetDocumentTextDetectionError::InternalServerError(_)
+                | GetDocumentTextDetectionError::ProvisionedThroughputExceededException(
+                    _
+                )
+                | GetDocumentTextDetectionError::ThrottlingException(_)
+        )
+    }
+}
+
 impl IsKnownTransient for GetDocumentAnalysisError {
     fn is_known_transient(&self) -> bool {
         matches!(
@@ -343,16 +791,48 @@ impl IsKnownTransient for GetDocumentAnalysisError {
     }
 }
 
-/// Parse an S3 URI into bucket and key.
-fn parse_s3_uri(uri: &str) -> Result<(String, String)> {
-    let uri = uri
-        .strip_prefix("s3://")
-        .ok_or_else(|| anyhow!("S3 URI must start with s3://"))?;
-    let parts: Vec<&str> = uri.splitn(2, '/').collect();
-    if parts.len() != 2 {
-        return Err(anyhow!("S3 URI must be in the format s3://bucket/key"));
+/// Build an [`S3Object`] pointing at a [`ScratchObject`].
+fn scratch_s3_object(object: &ScratchObject) -> S3Object {
+    S3Object::builder()
+        .bucket(object.bucket.clone())
+        .name(object.key.clone())
+        .build()
+}
+
+/// Get the position of each word, grouped by page. Textract already reports
+/// bounding boxes as fractions of the page size.
+fn words_by_page(blocks: &[Block]) -> Vec<Vec<OcrWord>> {
+    let mut pages: Vec<Vec<OcrWord>> = vec![];
+    for block in blocks {
+        // Synchronous responses don't always include page numbers, but they
+        // only ever contain one page.
+        let page_idx = match block.page() {
+            Some(page) => usize::try_from(page - 1).unwrap_or(0),
+            None => pages.len().saturating_sub(1),
+        };
+        if pages.len() <= page_idx {
+            pages.resize_with(page_idx + 1, Vec::new);
+        }
+        if block.block_type() != Some(&BlockType::Word) {
+            continue;
+        }
+        let (Some(text), Some(bbox)) = (
+            block.text(),
+            block
+                .geometry()
+                .and_then(|geometry| geometry.bounding_box()),
+        ) else {
+            continue;
+        };
+        pages[page_idx].push(OcrWord {
+            text: text.to_owned(),
+            left: f64::from(bbox.left()),
+            top: f64::from(bbox.top()),
+            width: f64::from(bbox.width()),
+            height: f64::from(bbox.height()),
+        });
     }
-    Ok((parts[0].to_string(), parts[1].to_string()))
+    pages
 }
 
 /// Our output state.
@@ -419,8 +899,56 @@ impl<'a> OutputState<'a> {
         self.output.push_str(text);
     }
 
+    /// Write a document returned by Textract in the specified mode.
+    fn write_document(&mut self, mode: TextractMode) -> Result<()> {
+        match mode {
+            TextractMode::Detect => {
+                self.write_detected_text();
+                Ok(())
+            }
+            TextractMode::Analyze { .. } => self.write_analyzed_document(),
+        }
+    }
+
+    /// Insert a page break, if requested and this isn't the first page.
+    fn write_page_break(&mut self) {
+        if !self.include_page_breaks {
+            return;
+        }
+        if self.first_page {
+            trace!("No break before first page");
+            self.first_page = false;
+        } else {
+            trace!("Inserting page break");
+            if !self.output.ends_with('\n') {
+                self.write_text("\n");
+            }
+            self.write_text("\x0C");
+        }
+    }
+
+    /// Write a document from the text detection APIs, which has no layout
+    /// blocks. We output each line in the order Textract returns them.
+    fn write_detected_text(&mut self) {
+        for block in self.all_blocks {
+            trace!(?block, "Textract block");
+            match block.block_type() {
+                Some(BlockType::Page) => self.write_page_break(),
+                Some(BlockType::Line) => {
+                    self.block_start(block);
+                    if let Some(text) = block.text() {
+                        self.write_text(text);
+                    }
+                    self.block_end(block);
+                    self.write_text("\n");
+                }
+                _ => {}
+            }
+        }
+    }
+
     /// Write an analyzed document.
-    fn write_analyzed_document<'d: 'a>(&mut self) -> Result<()> {
+    fn write_analyzed_document(&mut self) -> Result<()> {
         // Iterate over layout blocks and extract their child text.
         for block in self.all_blocks {
             trace!(?block, "Textract block");
@@ -428,18 +956,9 @@ impl<'a> OutputState<'a> {
                 continue;
             };
 
-            // Handle page breaks, if requested.
-            if self.include_page_breaks && block_type == &BlockType::Page {
-                if self.first_page {
-                    trace!("No break before first page");
-                    self.first_page = false;
-                } else {
-                    trace!("Inserting page break");
-                    if !self.output.ends_with('\n') {
-                        self.write_text("\n");
-                    }
-                    self.write_text("\x0C");
-                }
+            // Handle page breaks.
+            if block_type == &BlockType::Page {
+                self.write_page_break();
                 continue;
             }
 
@@ -539,3 +1058,46 @@ impl<'a> OutputState<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_schedule_backs_off_until_timeout() {
+        let mut schedule = PollSchedule::new(Duration::from_secs(60));
+        let mut delays = vec![];
+        while let Some(delay) = schedule.next_delay(1.0) {
+            delays.push(delay.as_secs());
+            tokio::time::advance(delay).await;
+        }
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 29]);
+        let err = schedule.timeout_error("job-1").to_string();
+        assert!(err.contains("after 60s (7 checks)"), "{err}");
+        assert!(err.contains("the 60s job timeout"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_textract_mode() {
+        assert_eq!(
+            TextractMode::from_str("detect").unwrap(),
+            TextractMode::Detect
+        );
+        assert_eq!(
+            TextractMode::from_str("analyze").unwrap(),
+            TextractMode::default()
+        );
+        let mode = TextractMode::from_str("analyze,tables,forms").unwrap();
+        assert_eq!(
+            mode,
+            TextractMode::Analyze {
+                tables: true,
+                forms: true
+            }
+        );
+        assert_eq!(mode.to_string(), "analyze,tables,forms");
+        assert!(TextractMode::from_str("detect,tables").is_err());
+        assert!(TextractMode::from_str("analyze,queries").is_err());
+        assert!(TextractMode::from_str("layout").is_err());
+    }
+}
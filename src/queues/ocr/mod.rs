@@ -7,15 +7,21 @@ use std::{sync::Arc, vec};
 use futures::{FutureExt as _, StreamExt as _};
 use schemars::JsonSchema;
 
-use self::engines::{file::OcrFileEngine, ocr_engine_for_model};
+use self::engines::{
+    file::OcrFileEngine, ocr_engine_for_model, textract::TextractOpts, uses_prompt,
+};
 use super::work::{
-    WorkInput, WorkItemCounterExt as _, WorkOutput, WorkOutputCounters, WorkStatus,
+    RecordLimits, WorkInput, WorkItemCounterExt as _, WorkOutput, WorkOutputCounters,
+    WorkStatus, local_file_size,
 };
 use crate::{
-    async_utils::{BoxedFuture, BoxedStream, JoinWorker, io::write_output_csv},
+    async_utils::{
+        BoxedFuture, BoxedStream, JoinWorker,
+        io::{OutputAck, write_output_csv},
+    },
     cmd::StreamOpts,
     drivers::LlmOpts,
-    page_iter::PageIterOptions,
+    page_iter::{EncryptedPdfError, PageIterOptions},
     prelude::*,
     prompt::ChatPrompt,
     ui::Ui,
@@ -41,7 +47,7 @@ impl OcrInput {
 }
 
 /// An output record describing an OCRed PDF.
-#[derive(Clone, Debug, JsonSchema, Serialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct OcrOutput {
     /// The input path.
@@ -57,6 +63,11 @@ pub struct OcrOutput {
     /// Any defects in the page that make it difficult to OCR.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub analysis: Option<OcrAnalysis>,
+
+    /// A searchable copy of the PDF, with the OCR text added as an invisible
+    /// text layer. Only present when using `--write-searchable-pdf`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub searchable_pdf_path: Option<String>,
 }
 
 impl OcrOutput {
@@ -68,6 +79,7 @@ impl OcrOutput {
             text: None,
             page_count: None,
             analysis: None,
+            searchable_pdf_path: None,
         }
     }
 }
@@ -98,10 +110,21 @@ impl WorkOutput<OcrOutput> {
         path: Option<&Path>,
         stream: BoxedStream<Result<Self>>,
         stream_opts: &StreamOpts,
+        ack: Option<&dyn OutputAck>,
     ) -> Result<()> {
-        let (stream, counters) = WorkOutputCounters::wrap_stream(stream);
+        if stream_opts.partition_by.is_some() {
+            return Err(anyhow!("--partition-by only supports JSONL output"));
+        }
+        let (stream, counters) = WorkOutputCounters::wrap_stream(ui, stream);
         let output = stream.map(|output| Ok(output?.to_flat())).boxed();
-        write_output_csv(path, output).await?;
+        write_output_csv(
+            path,
+            stream_opts.compress,
+            output,
+            ack,
+            stream_opts.write_opts(),
+        )
+        .await?;
         counters.finish(ui, stream_opts)
     }
 }
@@ -231,6 +254,7 @@ pub struct OcrStreamInfo {
 /// a single [`ChatStream`] instance for all requests, which it tries to keep
 /// filled at all times.
 #[instrument(level = "debug", skip_all)]
+#[allow(clippy::too_many_arguments)]
 pub async fn ocr_files(
     input: BoxedStream<Result<WorkInput<OcrInput>>>,
     job_count: usize,
@@ -239,7 +263,13 @@ pub async fn ocr_files(
     include_page_breaks: bool,
     page_iter_opts: PageIterOptions,
     llm_opts: LlmOpts,
+    textract_opts: TextractOpts,
+    searchable_pdf_dir: Option<PathBuf>,
+    record_limits: RecordLimits,
 ) -> Result<OcrStreamInfo> {
+    // Only LLM engines use our prompt, so only they should record it.
+    let used_prompt = uses_prompt(&model).then(|| Arc::new(prompt.clone()));
+
     // Create an OCR engine.
     let (engine, worker) = ocr_engine_for_model(
         job_count,
@@ -248,15 +278,38 @@ pub async fn ocr_files(
         include_page_breaks,
         &page_iter_opts,
         llm_opts,
+        &textract_opts,
+        searchable_pdf_dir,
     )
     .await?;
+    let skip_encrypted_pdfs = page_iter_opts.skip_encrypted_pdfs;
 
     let output = input
         .map(move |pdf_input| {
             let engine = engine.clone();
+            let used_prompt = used_prompt.clone();
             async move {
                 let pdf_input = pdf_input?;
-                ocr_file(pdf_input, engine).await
+                let ack_token = pdf_input.ack_token;
+                let input_bytes = local_file_size(pdf_input.data.path()).await;
+                let failed = WorkOutput::new_failed(
+                    pdf_input.id.clone(),
+                    vec![],
+                    OcrOutput::empty_for_error(pdf_input.data.path.clone()),
+                    pdf_input.passthrough_data.clone(),
+                );
+                let mut output = record_limits
+                    .run(
+                        input_bytes,
+                        failed,
+                        ocr_file(pdf_input, engine, skip_encrypted_pdfs),
+                    )
+                    .await?;
+                if let Some(prompt) = &used_prompt {
+                    output.set_prompt(prompt);
+                }
+                output.ack_token = ack_token;
+                Ok(output)
             }
             .boxed()
         })
@@ -269,10 +322,14 @@ pub async fn ocr_files(
 }
 
 /// Process a PDF file and extract text from it. The text is returned as an array of pages.
+///
+/// If `skip_encrypted_pdfs` is set, PDFs we can't decrypt are marked as
+/// skipped instead of failed.
 #[instrument(level = "debug", skip_all, fields(id = %ocr_input.id))]
 pub async fn ocr_file(
     ocr_input: WorkInput<OcrInput>,
     engine: Arc<dyn OcrFileEngine>,
+    skip_encrypted_pdfs: bool,
 ) -> Result<WorkOutput<OcrOutput>> {
     let id = ocr_input.id.clone();
     let path = ocr_input.data.path.clone();
@@ -288,6 +345,10 @@ pub async fn ocr_file(
             estimated_cost: None,
             token_usage: None,
             passthrough_data,
+            ack_token: None,
+            prompt_version: None,
+            prompt_hash: None,
+            model: None,
             data: OcrOutput::empty_for_error(path),
         });
     }
@@ -300,6 +361,20 @@ pub async fn ocr_file(
     // PDF file is corrupt.
     match result {
         Ok(output) => Ok(output),
+        // Report encrypted PDFs using just our `encrypted_pdf` error, so they
+        // can be found and triaged easily.
+        Err(err) if err.downcast_ref::<EncryptedPdfError>().is_some() => {
+            let mut output = WorkOutput::new_failed(
+                id,
+                vec![err.root_cause().to_string()],
+                OcrOutput::empty_for_error(path),
+                passthrough_data,
+            );
+            if skip_encrypted_pdfs {
+                output.status = WorkStatus::Skipped;
+            }
+            Ok(output)
+        }
         Err(err) => {
             let errors = vec![format!("{:?}", err)];
             Ok(WorkOutput::new_failed(
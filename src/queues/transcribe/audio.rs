@@ -0,0 +1,96 @@
+//! Audio utilities, using `ffprobe` and `ffmpeg`.
+
+use tempfile::TempDir;
+
+use crate::{
+    async_utils::check_for_command_failure,
+    cpu_limit::with_cpu_semaphore,
+    page_iter::get_media_duration,
+    prelude::*,
+    process_limits::{limited_command, limited_output},
+};
+
+/// A chunk of a longer audio file.
+#[derive(Debug)]
+pub struct AudioChunk {
+    /// The path to this chunk, as 16 kHz mono WAV.
+    pub path: PathBuf,
+
+    /// The offset of this chunk from the start of the original file, in
+    /// seconds.
+    pub start_seconds: f64,
+}
+
+/// An audio file split into chunks.
+///
+/// The chunks will be deleted when this is dropped.
+#[derive(Debug)]
+pub struct AudioChunks {
+    /// The directory containing our chunks.
+    _tmpdir: TempDir,
+
+    /// The duration of the original file, in seconds.
+    pub duration_seconds: f64,
+
+    /// Our chunks, in order.
+    pub chunks: Vec<AudioChunk>,
+}
+
+/// Split an audio file into chunks of at most `chunk_seconds`, converting
+/// them to 16 kHz mono WAV, which is what Whisper models expect anyway.
+///
+/// At this sample rate, a 10 minute chunk is about 19 MB, which fits within
+/// OpenAI's 25 MB upload limit.
+#[instrument(level = "debug")]
+pub async fn split_audio(path: &Path, chunk_seconds: u32) -> Result<AudioChunks> {
+    if chunk_seconds == 0 {
+        return Err(anyhow!("audio chunk length must be greater than 0"));
+    }
+    let duration_seconds = get_media_duration(path).await?;
+
+    // Run ffmpeg to convert and split the file.
+    //
+    // We use `with_cpu_semaphore` because `ffmpeg` may use 100% of a CPU.
+    let tmpdir = TempDir::with_prefix("transcribe")?;
+    let out_pattern = tmpdir.path().join("chunk-%05d.wav");
+    let output = with_cpu_semaphore(|| async {
+        let mut cmd = limited_command("ffmpeg");
+        cmd.args(["-nostdin", "-hide_banner", "-loglevel", "error", "-i"])
+            .arg(path)
+            .args(["-vn", "-ac", "1", "-ar", "16000", "-c:a", "pcm_s16le"])
+            .args(["-f", "segment", "-segment_time"])
+            .arg(chunk_seconds.to_string())
+            .arg(&out_pattern);
+        limited_output(&mut cmd)
+            .await
+            .with_context(|| format!("failed to run ffmpeg on {:?}", path.display()))
+    })
+    .await?;
+    check_for_command_failure("ffmpeg", &output, None)?;
+
+    // Collect our chunks. Since we're splitting uncompressed audio, segment
+    // boundaries fall exactly on multiples of `chunk_seconds`.
+    let mut paths = vec![];
+    let mut entries = tokio::fs::read_dir(tmpdir.path()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        paths.push(entry.path());
+    }
+    paths.sort();
+    let chunks = paths
+        .into_iter()
+        .enumerate()
+        .map(|(idx, path)| AudioChunk {
+            path,
+            start_seconds: f64::from(chunk_seconds) * idx as f64,
+        })
+        .collect::<Vec<_>>();
+    if chunks.is_empty() {
+        return Err(anyhow!("ffmpeg produced no audio for {:?}", path.display()));
+    }
+
+    Ok(AudioChunks {
+        _tmpdir: tmpdir,
+        duration_seconds,
+        chunks,
+    })
+}
@@ -0,0 +1,271 @@
+//! Transcription backends.
+
+use std::sync::Arc;
+
+use async_openai::{
+    Client,
+    config::OpenAIConfig,
+    types::{AudioResponseFormat, CreateTranscriptionRequestArgs, TimestampGranularity},
+};
+
+use super::TranscriptSegment;
+use crate::{
+    async_utils::check_for_command_failure,
+    cpu_limit::with_cpu_semaphore,
+    drivers::openai::create_llm_client,
+    litellm::litellm_model_info,
+    prelude::*,
+    process_limits::{limited_command, limited_output},
+    rate_limit::{RateLimit, RateLimitPeriod, RedisConfig, RequestLimiter},
+    retry::{
+        DEFAULT_JITTER, retry_result_ok, retry_with_backoff, try_potentially_transient,
+    },
+};
+
+/// Model prefix used to select the local `whisper.cpp` backend.
+const WHISPER_CPP_PREFIX: &str = "whisper.cpp:";
+
+/// Estimated cost of OpenAI's `whisper-1`, per second of audio, used when
+/// LiteLLM doesn't know better.
+const DEFAULT_OPENAI_COST_PER_SECOND: f64 = 0.0001;
+
+/// A transcript of a single audio chunk.
+#[derive(Debug)]
+pub struct ChunkTranscript {
+    /// The full text of the chunk.
+    pub text: String,
+
+    /// Timestamped segments, relative to the start of the chunk.
+    pub segments: Vec<TranscriptSegment>,
+
+    /// The estimated cost of transcribing this chunk, if known.
+    pub estimated_cost: Option<f64>,
+}
+
+/// Interface to a speech-to-text model.
+#[async_trait]
+pub trait TranscriptionBackend: Send + Sync + 'static {
+    /// Transcribe a chunk of audio, which will be 16 kHz mono WAV.
+    async fn transcribe_chunk(
+        &self,
+        path: &Path,
+        duration_seconds: f64,
+        language: Option<&str>,
+    ) -> Result<ChunkTranscript>;
+}
+
+/// Get the transcription backend for the specified model.
+///
+/// Models of the form `whisper.cpp:/path/to/ggml-model.bin` use a local
+/// `whisper.cpp` installation. Anything else is sent to the OpenAI-compatible
+/// `/audio/transcriptions` endpoint.
+pub async fn transcription_backend_for_model(
+    model: &str,
+    concurrency_limit: usize,
+    rate_limit: Option<&RateLimit>,
+    rate_limit_backend: Option<&RedisConfig>,
+) -> Result<Arc<dyn TranscriptionBackend>> {
+    if let Some(model_path) = model.strip_prefix(WHISPER_CPP_PREFIX) {
+        Ok(Arc::new(WhisperCppBackend {
+            model_path: PathBuf::from(model_path),
+        }))
+    } else {
+        Ok(Arc::new(
+            OpenAiTranscriptionBackend::new(
+                model,
+                concurrency_limit,
+                rate_limit,
+                rate_limit_backend,
+            )
+            .await?,
+        ))
+    }
+}
+
+/// Transcription using an OpenAI-compatible `/audio/transcriptions` endpoint,
+/// including LiteLLM.
+pub struct OpenAiTranscriptionBackend {
+    /// The OpenAI client.
+    client: Client<OpenAIConfig>,
+
+    /// The model to use.
+    model: String,
+
+    /// The cost per second of audio.
+    cost_per_second: f64,
+
+    /// A rate limiter to avoid hitting API limits.
+    rate_limiter: Arc<RequestLimiter>,
+}
+
+impl OpenAiTranscriptionBackend {
+    /// Create a new OpenAI transcription backend.
+    async fn new(
+        model: &str,
+        concurrency_limit: usize,
+        rate_limit: Option<&RateLimit>,
+        rate_limit_backend: Option<&RedisConfig>,
+    ) -> Result<Self> {
+        let cost_per_second = litellm_model_info(model)
+            .await
+            .and_then(|info| info.model_info.input_cost_per_second)
+            .unwrap_or(DEFAULT_OPENAI_COST_PER_SECOND);
+        let rate_limiter = rate_limit
+            .cloned()
+            .unwrap_or_else(|| RateLimit::new(concurrency_limit, RateLimitPeriod::Second))
+            .to_request_limiter(rate_limit_backend, &format!("transcribe/{model}"))
+            .await?;
+        Ok(Self {
+            client: create_llm_client()?,
+            model: model.to_owned(),
+            cost_per_second,
+            rate_limiter,
+        })
+    }
+}
+
+#[async_trait]
+impl TranscriptionBackend for OpenAiTranscriptionBackend {
+    #[instrument(level = "debug", skip(self))]
+    async fn transcribe_chunk(
+        &self,
+        path: &Path,
+        duration_seconds: f64,
+        language: Option<&str>,
+    ) -> Result<ChunkTranscript> {
+        let mut request = CreateTranscriptionRequestArgs::default();
+        request
+            .file(path)
+            .model(&self.model)
+            .response_format(AudioResponseFormat::VerboseJson)
+            .timestamp_granularities(vec![TimestampGranularity::Segment]);
+        if let Some(language) = language {
+            request.language(language);
+        }
+        let request = request.build()?;
+
+        self.rate_limiter.acquire_one().await;
+        let request = &request;
+        let response = retry_with_backoff(DEFAULT_JITTER, move || async move {
+            retry_result_ok(try_potentially_transient!(
+                self.client
+                    .audio()
+                    .transcribe_verbose_json(request.clone())
+                    .await
+            ))
+        })
+        .await
+        .into_result()
+        .context("transcription request failed")?;
+
+        let segments = response
+            .segments
+            .unwrap_or_default()
+            .into_iter()
+            .map(|segment| TranscriptSegment {
+                start: f64::from(segment.start),
+                end: f64::from(segment.end),
+                text: segment.text.trim().to_owned(),
+            })
+            .collect();
+        Ok(ChunkTranscript {
+            text: response.text.trim().to_owned(),
+            segments,
+            estimated_cost: Some(self.cost_per_second * duration_seconds),
+        })
+    }
+}
+
+/// Transcription using a local `whisper.cpp` installation.
+///
+/// We run the `whisper-cli` binary, or whatever `WHISPER_CPP_BIN` points to.
+pub struct WhisperCppBackend {
+    /// The path to the `ggml` model file.
+    model_path: PathBuf,
+}
+
+/// `whisper.cpp` JSON output.
+#[derive(Debug, Deserialize)]
+struct WhisperCppOutput {
+    /// Our transcribed segments.
+    transcription: Vec<WhisperCppSegment>,
+}
+
+/// A `whisper.cpp` segment.
+#[derive(Debug, Deserialize)]
+struct WhisperCppSegment {
+    /// Segment offsets, in milliseconds.
+    offsets: WhisperCppOffsets,
+
+    /// The segment text.
+    text: String,
+}
+
+/// `whisper.cpp` segment offsets, in milliseconds.
+#[derive(Debug, Deserialize)]
+struct WhisperCppOffsets {
+    from: u64,
+    to: u64,
+}
+
+#[async_trait]
+impl TranscriptionBackend for WhisperCppBackend {
+    #[instrument(level = "debug", skip(self))]
+    async fn transcribe_chunk(
+        &self,
+        path: &Path,
+        _duration_seconds: f64,
+        language: Option<&str>,
+    ) -> Result<ChunkTranscript> {
+        let bin =
+            std::env::var("WHISPER_CPP_BIN").unwrap_or_else(|_| "whisper-cli".to_owned());
+        let out_prefix = path.with_extension("");
+        let mut cmd = limited_command(&bin);
+        cmd.arg("--model")
+            .arg(&self.model_path)
+            .arg("--file")
+            .arg(path)
+            .arg("--output-json")
+            .arg("--output-file")
+            .arg(&out_prefix)
+            .arg("--no-prints");
+        if let Some(language) = language {
+            cmd.arg("--language").arg(language);
+        }
+        let output = with_cpu_semaphore(|| async {
+            limited_output(&mut cmd)
+                .await
+                .with_context(|| format!("failed to run {bin}"))
+        })
+        .await?;
+        check_for_command_failure(&bin, &output, None)?;
+
+        let json_path = out_prefix.with_extension("json");
+        let json = tokio::fs::read(&json_path)
+            .await
+            .with_context(|| format!("failed to read {json_path:?}"))?;
+        let parsed = serde_json::from_slice::<WhisperCppOutput>(&json)
+            .with_context(|| format!("failed to parse {json_path:?}"))?;
+
+        let segments = parsed
+            .transcription
+            .into_iter()
+            .map(|segment| TranscriptSegment {
+                start: segment.offsets.from as f64 / 1000.0,
+                end: segment.offsets.to as f64 / 1000.0,
+                text: segment.text.trim().to_owned(),
+            })
+            .filter(|segment| !segment.text.is_empty())
+            .collect::<Vec<_>>();
+        let text = segments
+            .iter()
+            .map(|segment| segment.text.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(ChunkTranscript {
+            text,
+            segments,
+            estimated_cost: None,
+        })
+    }
+}
@@ -0,0 +1,333 @@
+//! Audio transcription.
+//!
+//! This mirrors our OCR pipeline: long audio files are split into chunks,
+//! each chunk is transcribed separately, and the results are merged back into
+//! a single transcript with timestamps relative to the original file.
+
+pub mod audio;
+pub mod backends;
+
+use std::sync::Arc;
+
+use futures::{FutureExt as _, StreamExt as _};
+use schemars::JsonSchema;
+
+use self::{
+    audio::split_audio,
+    backends::{TranscriptionBackend, transcription_backend_for_model},
+};
+use super::work::{
+    RecordLimits, WorkInput, WorkOutput, WorkOutputCounters, WorkStatus, local_file_size,
+};
+use crate::{
+    async_utils::{
+        BoxedFuture, BoxedStream, JoinWorker,
+        io::{OutputAck, write_output_csv},
+    },
+    cmd::StreamOpts,
+    prelude::*,
+    rate_limit::{RateLimit, RedisConfig},
+    ui::Ui,
+};
+
+/// An input record describing an audio file to transcribe.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct TranscribeInput {
+    /// The path to the audio file. Any format supported by `ffmpeg` may be
+    /// used, including video files.
+    pub path: String,
+
+    /// The language spoken in the audio, as an ISO-639-1 code such as `en`.
+    /// Defaults to automatic detection.
+    #[serde(default)]
+    pub language: Option<String>,
+}
+
+impl TranscribeInput {
+    /// Get `path` as a `&Path`.
+    pub fn path(&self) -> &Path {
+        Path::new(&self.path)
+    }
+}
+
+/// A timestamped segment of a transcript.
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct TranscriptSegment {
+    /// The start of this segment, in seconds.
+    pub start: f64,
+
+    /// The end of this segment, in seconds.
+    pub end: f64,
+
+    /// The text of this segment.
+    pub text: String,
+}
+
+/// An output record describing a transcribed audio file.
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct TranscribeOutput {
+    /// The input path.
+    pub path: String,
+
+    /// The full transcript. If a chunk fails to transcribe, it will be
+    /// replaced with `**COULD_NOT_TRANSCRIBE_CHUNK**`.
+    pub text: Option<String>,
+
+    /// The duration of the audio, in seconds, if known.
+    pub duration_seconds: Option<f64>,
+
+    /// Timestamped segments, relative to the start of the audio.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<TranscriptSegment>,
+}
+
+impl TranscribeOutput {
+    /// Create an empty output record for use when an error occurs.
+    pub fn empty_for_error(path: String) -> Self {
+        Self {
+            path,
+            text: None,
+            duration_seconds: None,
+            segments: vec![],
+        }
+    }
+}
+
+impl WorkOutput<TranscribeOutput> {
+    /// Convert this output record to a flat version for CSV output.
+    fn to_flat(&self) -> FlatTranscribeOutput {
+        FlatTranscribeOutput {
+            id: if let Value::String(id) = &self.id {
+                id.clone()
+            } else {
+                serde_json::to_string(&self.id).expect("failed to convert ID to string")
+            },
+            status: self.status,
+            errors: if self.errors.is_empty() {
+                None
+            } else {
+                Some(self.errors.join("\n\n"))
+            },
+            path: self.data.path.clone(),
+            duration_seconds: self.data.duration_seconds,
+            text: self.data.text.clone(),
+        }
+    }
+
+    /// Write a stream of outputs to a [`Path`] or to standard output.
+    pub async fn write_stream_to_csv(
+        ui: &Ui,
+        path: Option<&Path>,
+        stream: BoxedStream<Result<Self>>,
+        stream_opts: &StreamOpts,
+        ack: Option<&dyn OutputAck>,
+    ) -> Result<()> {
+        let (stream, counters) = WorkOutputCounters::wrap_stream(ui, stream);
+        let output = stream.map(|output| Ok(output?.to_flat())).boxed();
+        write_output_csv(
+            path,
+            stream_opts.compress,
+            output,
+            ack,
+            stream_opts.write_opts(),
+        )
+        .await?;
+        counters.finish(ui, stream_opts)
+    }
+}
+
+/// Flat version of [`WorkOutput<TranscribeOutput>`], for CSV output.
+///
+/// Does not contain anything but essential fields.
+#[derive(Clone, Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct FlatTranscribeOutput {
+    /// The ID of the input record.
+    pub id: String,
+
+    /// The status of the output record.
+    pub status: WorkStatus,
+
+    /// Any errors that occurred during processing.
+    pub errors: Option<String>,
+
+    /// The path to the audio file.
+    pub path: String,
+
+    /// The duration of the audio, in seconds, if known.
+    pub duration_seconds: Option<f64>,
+
+    /// The full transcript.
+    pub text: Option<String>,
+}
+
+/// Return value of [`transcribe_files`].
+pub struct TranscribeStreamInfo {
+    pub stream: BoxedStream<BoxedFuture<Result<WorkOutput<TranscribeOutput>>>>,
+    pub worker: JoinWorker,
+}
+
+/// Transcribe a stream of audio files.
+#[instrument(level = "debug", skip_all)]
+pub async fn transcribe_files(
+    input: BoxedStream<Result<WorkInput<TranscribeInput>>>,
+    job_count: usize,
+    model: String,
+    chunk_seconds: u32,
+    rate_limit: Option<RateLimit>,
+    rate_limit_backend: Option<RedisConfig>,
+    record_limits: RecordLimits,
+) -> Result<TranscribeStreamInfo> {
+    let backend = transcription_backend_for_model(
+        &model,
+        job_count,
+        rate_limit.as_ref(),
+        rate_limit_backend.as_ref(),
+    )
+    .await?;
+
+    let output = input
+        .map(move |input| {
+            let backend = backend.clone();
+            async move {
+                let input = input?;
+                let ack_token = input.ack_token;
+                let input_bytes = local_file_size(Path::new(&input.data.path)).await;
+                let failed = WorkOutput::new_failed(
+                    input.id.clone(),
+                    vec![],
+                    TranscribeOutput::empty_for_error(input.data.path.clone()),
+                    input.passthrough_data.clone(),
+                );
+                let mut output = record_limits
+                    .run(
+                        input_bytes,
+                        failed,
+                        transcribe_file(input, backend, chunk_seconds),
+                    )
+                    .await?;
+                output.ack_token = ack_token;
+                Ok(output)
+            }
+            .boxed()
+        })
+        .boxed();
+
+    Ok(TranscribeStreamInfo {
+        stream: output,
+        worker: JoinWorker::noop(),
+    })
+}
+
+/// Transcribe an audio file, converting any errors into a failed output
+/// record.
+#[instrument(level = "debug", skip_all, fields(id = %input.id))]
+pub async fn transcribe_file(
+    input: WorkInput<TranscribeInput>,
+    backend: Arc<dyn TranscriptionBackend>,
+    chunk_seconds: u32,
+) -> Result<WorkOutput<TranscribeOutput>> {
+    let id = input.id.clone();
+    let path = input.data.path.clone();
+    let passthrough_data = input.passthrough_data.clone();
+
+    // Early return if skip_processing is true.
+    if input.skip_processing.unwrap_or(false) {
+        return Ok(WorkOutput {
+            id,
+            status: WorkStatus::Skipped,
+            errors: vec![],
+            estimated_cost: None,
+            token_usage: None,
+            passthrough_data,
+            ack_token: None,
+            prompt_version: None,
+            prompt_hash: None,
+            model: None,
+            data: TranscribeOutput::empty_for_error(path),
+        });
+    }
+
+    // If we have an error, output an appropriate record and continue, so that
+    // one corrupt file doesn't abort an entire batch.
+    match transcribe_file_inner(input, backend.as_ref(), chunk_seconds).await {
+        Ok(output) => Ok(output),
+        Err(err) => Ok(WorkOutput::new_failed(
+            id,
+            vec![format!("{:?}", err)],
+            TranscribeOutput::empty_for_error(path),
+            passthrough_data,
+        )),
+    }
+}
+
+/// Split an audio file into chunks, transcribe each chunk, and merge the
+/// results.
+async fn transcribe_file_inner(
+    input: WorkInput<TranscribeInput>,
+    backend: &dyn TranscriptionBackend,
+    chunk_seconds: u32,
+) -> Result<WorkOutput<TranscribeOutput>> {
+    let chunks = split_audio(input.data.path(), chunk_seconds).await?;
+    let language = input.data.language.as_deref();
+
+    let mut texts = vec![];
+    let mut segments = vec![];
+    let mut errors = vec![];
+    let mut estimated_cost = None;
+    for (idx, chunk) in chunks.chunks.iter().enumerate() {
+        let chunk_duration = (chunks.duration_seconds - chunk.start_seconds)
+            .clamp(0.0, chunk_seconds.into());
+        match backend
+            .transcribe_chunk(&chunk.path, chunk_duration, language)
+            .await
+        {
+            Ok(transcript) => {
+                texts.push(transcript.text);
+                segments.extend(transcript.segments.into_iter().map(|segment| {
+                    TranscriptSegment {
+                        start: segment.start + chunk.start_seconds,
+                        end: segment.end + chunk.start_seconds,
+                        text: segment.text,
+                    }
+                }));
+                if let Some(cost) = transcript.estimated_cost {
+                    *estimated_cost.get_or_insert(0.0) += cost;
+                }
+            }
+            Err(err) => {
+                errors.push(format!("chunk {idx}: {err:?}"));
+                texts.push("**COULD_NOT_TRANSCRIBE_CHUNK**".to_owned());
+            }
+        }
+    }
+
+    let status = if errors.is_empty() {
+        WorkStatus::Ok
+    } else if errors.len() < chunks.chunks.len() {
+        WorkStatus::Incomplete
+    } else {
+        WorkStatus::Failed
+    };
+    Ok(WorkOutput {
+        id: input.id,
+        status,
+        errors,
+        estimated_cost,
+        token_usage: None,
+        passthrough_data: input.passthrough_data,
+        ack_token: None,
+        prompt_version: None,
+        prompt_hash: None,
+        model: None,
+        data: TranscribeOutput {
+            path: input.data.path,
+            text: Some(texts.join("\n")),
+            duration_seconds: Some(chunks.duration_seconds),
+            segments,
+        },
+    })
+}
@@ -19,7 +19,13 @@
 //! which are much more agnostic about what's going on. You won't normally need
 //! to work with these directly.
 
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use futures::{
     FutureExt, SinkExt as _, StreamExt,
@@ -27,15 +33,27 @@ use futures::{
 };
 use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
+use sha2::{Digest as _, Sha256};
+use tokio::time;
+use uuid::Uuid;
 
 use crate::{
     async_utils::{
         BoxedFuture, BoxedStream, JoinWorker,
-        io::{read_jsonl_or_csv, write_output},
+        io::{
+            ACK_TOKEN_FIELD, OutputAck, append_output, id_key, read_jsonl_or_csv,
+            write_output_with_opts, write_partitioned_output,
+        },
     },
     cmd::StreamOpts,
     drivers::TokenUsage,
+    latency::latency_report,
+    postgres::{is_postgres_url, read_postgres, write_postgres},
     prelude::*,
+    prompt::ChatPrompt,
+    result_store::{Column, DEFAULT_DUCKDB_TABLE, write_duckdb},
+    sqs::{read_sqs, sqs_queue_url},
+    streaming::{StreamingUrl, read_streaming_input, write_streaming_output},
     ui::Ui,
 };
 
@@ -56,6 +74,12 @@ where
     #[serde(default)]
     pub passthrough_data: Option<Value>,
 
+    /// Identifies the queue message this record came from, so we can
+    /// acknowledge the right message even if IDs repeat. See
+    /// [`ACK_TOKEN_FIELD`].
+    #[serde(skip)]
+    pub ack_token: Option<u64>,
+
     /// The input data for the work item.
     #[serde(flatten)]
     pub data: T,
@@ -70,20 +94,306 @@ where
         serde_json::from_value::<Self>(value).context("failed to deserialize input")
     }
 
-    /// Read a stream from a [`Path`] or from standard input.
+    /// Read a stream from a [`Path`], an `sqs://` queue URL, a `kafka://` or
+    /// `nats://` URL, a `postgres://` URL, or from standard input.
     pub async fn read_stream(
         ui: Ui,
         path: Option<&Path>,
-    ) -> Result<BoxedStream<Result<Self>>> {
-        Ok(read_jsonl_or_csv(ui, path)
-            .await?
-            .map(|value| Self::from_json(value?))
-            .boxed())
+        stream_opts: &StreamOpts,
+    ) -> Result<WorkInputStreamInfo<T>> {
+        let streaming_url = path.map(StreamingUrl::from_path).transpose()?.flatten();
+
+        // Queue inputs never end, and only let go of each message once its
+        // output has been written, so we can't hold output back to sort it.
+        let is_queue = streaming_url.is_some() || path.and_then(sqs_queue_url).is_some();
+        if is_queue && stream_opts.sort_output_by.is_some() {
+            return Err(anyhow!("--sort-output-by cannot be used with queue input"));
+        }
+
+        let (stream, ack) = if let Some(queue_url) = path.and_then(sqs_queue_url) {
+            let (stream, ack) = read_sqs(queue_url, &stream_opts.sqs_opts).await?;
+            (stream, Some(ack))
+        } else if let Some(url) = streaming_url {
+            let (stream, ack) = read_streaming_input(&url).await?;
+            (stream, Some(ack))
+        } else if let Some(path) = path.filter(|path| is_postgres_url(path)) {
+            (read_postgres(path).await?, None)
+        } else {
+            (
+                read_jsonl_or_csv(
+                    ui,
+                    path,
+                    stream_opts.csv_delimiter,
+                    stream_opts.yaml_stdin,
+                )
+                .await?,
+                None,
+            )
+        };
+        let mappings = stream_opts.column_mappings.clone();
+        let passthrough_columns = stream_opts.passthrough_columns.clone();
+        let id_from = stream_opts.id_from.clone();
+        Ok(WorkInputStreamInfo {
+            stream: stream
+                .enumerate()
+                .map(move |(index, value)| {
+                    let mut value = value?;
+                    let ack_token = take_ack_token(&mut value);
+                    let mut value =
+                        apply_passthrough_columns(value, &passthrough_columns)?;
+                    if let Some(id_from) = &id_from {
+                        id_from.apply(&mut value, index + 1)?;
+                    }
+                    let mut input =
+                        Self::from_json(apply_column_mappings(value, &mappings)?)?;
+                    input.ack_token = ack_token;
+                    Ok(input)
+                })
+                .boxed(),
+            ack,
+        })
+    }
+}
+
+/// How to generate record IDs, for `--id-from`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdFrom {
+    /// Copy the ID from this column.
+    Column(String),
+
+    /// Use the 1-based position of the record in the input.
+    RowNumber,
+
+    /// Generate a random UUID. These are not stable between runs.
+    Uuid,
+
+    /// Hash these columns, so that identical records get identical IDs.
+    Hash(Vec<String>),
+}
+
+impl IdFrom {
+    /// Set the `id` of `record`, which is row number `row_number` in our input.
+    fn apply(&self, record: &mut Value, row_number: usize) -> Result<()> {
+        let record = record
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("--id-from requires input records to be objects"))?;
+        let id = match self {
+            IdFrom::Column(column) => record.get(column).cloned().ok_or_else(|| {
+                anyhow!("input record has no {column:?} column for --id-from")
+            })?,
+            IdFrom::RowNumber => Value::from(row_number),
+            IdFrom::Uuid => Value::String(Uuid::new_v4().to_string()),
+            IdFrom::Hash(columns) => {
+                let values = columns
+                    .iter()
+                    .map(|column| record.get(column).cloned().unwrap_or(Value::Null))
+                    .collect::<Vec<_>>();
+                let digest = Sha256::digest(Value::Array(values).to_string());
+                Value::String(hex::encode(&digest[..16]))
+            }
+        };
+        record.insert("id".to_owned(), id);
+        Ok(())
+    }
+}
+
+impl FromStr for IdFrom {
+    type Err = String;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        match arg {
+            "row-number" => Ok(IdFrom::RowNumber),
+            "uuid" => Ok(IdFrom::Uuid),
+            _ if arg.starts_with("hash(") && arg.ends_with(')') => {
+                let columns = arg["hash(".len()..arg.len() - 1]
+                    .split(',')
+                    .map(|column| column.trim().to_owned())
+                    .filter(|column| !column.is_empty())
+                    .collect::<Vec<_>>();
+                if columns.is_empty() {
+                    Err("hash(...) needs at least one column".to_owned())
+                } else {
+                    Ok(IdFrom::Hash(columns))
+                }
+            }
+            "" => Err("expected a column, row-number, uuid or hash(...)".to_owned()),
+            column => Ok(IdFrom::Column(column.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for IdFrom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdFrom::Column(column) => write!(f, "{column}"),
+            IdFrom::RowNumber => write!(f, "row-number"),
+            IdFrom::Uuid => write!(f, "uuid"),
+            IdFrom::Hash(columns) => write!(f, "hash({})", columns.join(",")),
+        }
+    }
+}
+
+/// Copy input columns into `passthrough_data` for `--passthrough-columns`.
+fn apply_passthrough_columns(mut value: Value, columns: &[String]) -> Result<Value> {
+    if columns.is_empty() {
+        return Ok(value);
+    }
+    let record = value.as_object_mut().ok_or_else(|| {
+        anyhow!("--passthrough-columns requires input records to be objects")
+    })?;
+    let copied = columns
+        .iter()
+        .map(|column| {
+            let value = record.get(column).cloned().ok_or_else(|| {
+                anyhow!("input record has no {column:?} column for --passthrough-columns")
+            })?;
+            Ok((column.to_owned(), value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    match record.entry("passthrough_data").or_insert(Value::Null) {
+        passthrough @ Value::Null => {
+            *passthrough = Value::Object(copied.into_iter().collect());
+        }
+        Value::Object(passthrough) => passthrough.extend(copied),
+        _ => {
+            return Err(anyhow!(
+                "--passthrough-columns requires passthrough_data to be an object"
+            ));
+        }
+    }
+    Ok(value)
+}
+
+/// Rename input columns according to `--map FIELD=COLUMN` options.
+fn apply_column_mappings(
+    mut value: Value,
+    mappings: &[(String, String)],
+) -> Result<Value> {
+    if mappings.is_empty() {
+        return Ok(value);
+    }
+    let record = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("--map requires input records to be objects"))?;
+    // Remove all our columns before inserting anything, so that mappings like
+    // `--map a=b --map b=a` work.
+    let mapped = mappings
+        .iter()
+        .map(|(field, column)| {
+            let value = record.shift_remove(column).ok_or_else(|| {
+                anyhow!("input record has no {column:?} column for --map")
+            })?;
+            Ok((field.to_owned(), value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    record.extend(mapped);
+    Ok(value)
+}
+
+/// Return value of [`WorkInput::read_stream`].
+pub struct WorkInputStreamInfo<T>
+where
+    T: 'static,
+{
+    /// Our input records.
+    pub stream: BoxedStream<Result<WorkInput<T>>>,
+
+    /// If our input comes from a message queue, this must be notified after
+    /// each output record has been written.
+    pub ack: Option<Arc<dyn OutputAck>>,
+}
+
+/// Remove the [`ACK_TOKEN_FIELD`] added by a queue input, if any.
+fn take_ack_token(value: &mut Value) -> Option<u64> {
+    value
+        .as_object_mut()?
+        .remove(ACK_TOKEN_FIELD)
+        .and_then(|token| token.as_u64())
+}
+
+/// Prepare to write `stream` with `ack`, so that each output record is
+/// acknowledged using the [`WorkOutput::ack_token`] of its input.
+///
+/// Writers only pass the written record to [`OutputAck::ack`], so we remember
+/// each output's token, keyed by [`id_key`], and add it back to the record as
+/// [`ACK_TOKEN_FIELD`] before acknowledging it. Writers acknowledge records
+/// in the order they receive them, so even repeated IDs get the right token.
+pub fn track_ack_tokens<T>(
+    stream: BoxedStream<Result<WorkOutput<T>>>,
+    ack: Option<Arc<dyn OutputAck>>,
+) -> (
+    BoxedStream<Result<WorkOutput<T>>>,
+    Option<Arc<dyn OutputAck>>,
+)
+where
+    T: Send + 'static,
+{
+    let Some(inner) = ack else {
+        return (stream, None);
+    };
+    let tokens = Arc::new(Mutex::new(HashMap::<String, VecDeque<u64>>::new()));
+    let ack = Arc::new(AckWithTokens {
+        inner,
+        tokens: tokens.clone(),
+    });
+    let stream = stream
+        .inspect(move |output| {
+            if let Ok(WorkOutput {
+                id,
+                ack_token: Some(token),
+                ..
+            }) = output
+            {
+                tokens
+                    .lock()
+                    .expect("lock poisoned")
+                    .entry(id_key(id))
+                    .or_default()
+                    .push_back(*token);
+            }
+        })
+        .boxed();
+    (stream, Some(ack))
+}
+
+/// Adds each record's ack token back before acknowledging it. See
+/// [`track_ack_tokens`].
+struct AckWithTokens {
+    /// The acknowledgement for our input.
+    inner: Arc<dyn OutputAck>,
+
+    /// Tokens for records we haven't acknowledged yet, by [`id_key`], in
+    /// output order.
+    tokens: Arc<Mutex<HashMap<String, VecDeque<u64>>>>,
+}
+
+#[async_trait]
+impl OutputAck for AckWithTokens {
+    async fn ack(&self, record: &Value) -> Result<()> {
+        let token = {
+            let mut tokens = self.tokens.lock().expect("lock poisoned");
+            let key = id_key(&record["id"]);
+            let token = tokens.get_mut(&key).and_then(VecDeque::pop_front);
+            if tokens.get(&key).is_some_and(VecDeque::is_empty) {
+                tokens.remove(&key);
+            }
+            token
+        };
+        match (token, record) {
+            (Some(token), Value::Object(map)) => {
+                let mut map = map.clone();
+                map.insert(ACK_TOKEN_FIELD.to_owned(), Value::from(token));
+                self.inner.ack(&Value::Object(map)).await
+            }
+            _ => self.inner.ack(record).await,
+        }
     }
 }
 
 /// Output status of a work item.
-#[derive(Clone, Copy, Debug, JsonSchema, Serialize, PartialEq, Eq)]
+#[derive(
+    Clone, Copy, Debug, Deserialize, JsonSchema, Serialize, PartialEq, Eq, PartialOrd, Ord,
+)]
 #[serde(rename_all = "snake_case")]
 pub enum WorkStatus {
     // The work item was successful.
@@ -100,6 +410,16 @@ pub enum WorkStatus {
 }
 
 impl WorkStatus {
+    /// The name of this status, as used in our output.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WorkStatus::Ok => "ok",
+            WorkStatus::Skipped => "skipped",
+            WorkStatus::Incomplete => "incomplete",
+            WorkStatus::Failed => "failed",
+        }
+    }
+
     /// Returns true if this status represents a successful outcome.
     pub fn is_success(self) -> bool {
         matches!(self, WorkStatus::Ok | WorkStatus::Skipped)
@@ -107,7 +427,10 @@ impl WorkStatus {
 }
 
 /// Output record from a [`WorkItemProcessor`].
-#[derive(Clone, Debug, JsonSchema, Serialize)]
+///
+/// Fields are written to JSON in the order declared here, followed by the
+/// fields of `data`. Downstream tools may rely on this order.
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 pub struct WorkOutput<T>
 where
     T: 'static,
@@ -133,6 +456,25 @@ where
     #[serde(skip_serializing_if = "Option::is_none")]
     pub passthrough_data: Option<Value>,
 
+    /// The [`WorkInput::ack_token`] of our input, if any. Not included in the
+    /// output.
+    #[serde(skip)]
+    pub ack_token: Option<u64>,
+
+    /// The `version` of the prompt used for this work item, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_version: Option<String>,
+
+    /// A hash of the text of the prompt used for this work item, so that
+    /// outputs from different versions of a prompt can be told apart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_hash: Option<String>,
+
+    /// The model which processed this work item, if any. Only used for
+    /// counters, and not included in the output.
+    #[serde(skip)]
+    pub model: Option<String>,
+
     /// The output data for the work item.
     #[serde(flatten)]
     pub data: T,
@@ -157,38 +499,188 @@ where
             token_usage: None,
             errors,
             passthrough_data,
+            ack_token: None,
+            prompt_version: None,
+            prompt_hash: None,
+            model: None,
             data,
         }
     }
 
+    /// Record which prompt was used for this work item.
+    pub fn set_prompt(&mut self, prompt: &ChatPrompt) {
+        self.prompt_version = prompt.version.clone();
+        self.prompt_hash = prompt.hash().map(str::to_owned);
+    }
+
     /// Convert from the output type to a JSON value.
     pub fn to_json(&self) -> Result<Value> {
         serde_json::to_value::<Self>((*self).to_owned())
             .context("failed to serialize output")
     }
 
-    /// Write a stream of outputs to a [`Path`] or to standard output.
+    /// Write a stream of outputs to a [`Path`], a `kafka://` or `nats://` URL,
+    /// a `postgres://` table, or to standard output.
     pub async fn write_stream(
         ui: &Ui,
         path: Option<&Path>,
         stream: BoxedStream<Result<Self>>,
         stream_opts: &StreamOpts,
+        ack: Option<&dyn OutputAck>,
     ) -> Result<()> {
-        let (stream, counters) = WorkOutputCounters::wrap_stream(stream);
+        let (stream, counters) = WorkOutputCounters::wrap_stream(ui, stream);
         let output = stream
             .map(|value| {
                 let value = value?;
                 value.to_json()
             })
             .boxed();
-        write_output(path, output).await?;
+        let streaming_url = path.map(StreamingUrl::from_path).transpose()?.flatten();
+        let is_postgres = path.is_some_and(is_postgres_url);
+        if stream_opts.partition_by.is_some() && (streaming_url.is_some() || is_postgres)
+        {
+            return Err(anyhow!("--partition-by only supports JSONL file output"));
+        }
+        if stream_opts.pretty && (streaming_url.is_some() || is_postgres) {
+            return Err(anyhow!("--pretty only supports JSONL output"));
+        }
+        if stream_opts.atomic_output && (streaming_url.is_some() || is_postgres) {
+            return Err(anyhow!("--atomic-output only supports JSONL and CSV files"));
+        }
+        if let Some(url) = streaming_url {
+            write_streaming_output(&url, output, ack).await?;
+        } else if let Some(path) = path.filter(|_| is_postgres) {
+            let table = stream_opts
+                .output_table
+                .as_deref()
+                .ok_or_else(|| anyhow!("--out postgres://... requires --output-table"))?;
+            write_postgres(path, table, output, ack).await?;
+        } else if let Some(partition_by) = &stream_opts.partition_by {
+            let path_template = path
+                .and_then(|path| path.to_str())
+                .ok_or_else(|| anyhow!("--partition-by requires --out"))?;
+            write_partitioned_output(
+                path_template,
+                stream_opts.compress,
+                partition_by,
+                stream_opts.partition_max_mb.saturating_mul(1024 * 1024),
+                output,
+                ack,
+                stream_opts.write_opts(),
+            )
+            .await?;
+        } else if let Some(path) = path.filter(|_| stream_opts.appends_output()) {
+            // Keep any existing output, such as from earlier attempts at this
+            // run.
+            append_output(
+                path,
+                stream_opts.compress,
+                stream_opts.pretty,
+                output,
+                ack,
+                stream_opts.write_opts(),
+            )
+            .await?;
+        } else {
+            write_output_with_opts(
+                path,
+                stream_opts.compress,
+                stream_opts.pretty,
+                output,
+                ack,
+                stream_opts.write_opts(),
+            )
+            .await?;
+        }
+        counters.finish(ui, stream_opts)
+    }
+
+    /// Write a stream of outputs to a DuckDB file, using `columns`.
+    pub async fn write_stream_to_duckdb(
+        ui: &Ui,
+        path: Option<&Path>,
+        stream: BoxedStream<Result<Self>>,
+        stream_opts: &StreamOpts,
+        columns: &[Column],
+        ack: Option<&dyn OutputAck>,
+    ) -> Result<()> {
+        let path = path.ok_or_else(|| anyhow!("DuckDB output requires --out"))?;
+        if stream_opts.partition_by.is_some() {
+            return Err(anyhow!("--partition-by only supports JSONL output"));
+        }
+        if stream_opts.atomic_output {
+            return Err(anyhow!("--atomic-output only supports JSONL and CSV files"));
+        }
+        let table = stream_opts
+            .output_table
+            .as_deref()
+            .unwrap_or(DEFAULT_DUCKDB_TABLE);
+        let (stream, counters) = WorkOutputCounters::wrap_stream(ui, stream);
+        let output = stream.map(|value| value?.to_json()).boxed();
+        write_duckdb(path, table, columns, output, ack).await?;
         counters.finish(ui, stream_opts)
     }
 }
 
+/// Per-record limits. These fail a single pathological record cleanly, instead
+/// of letting it hold a worker slot indefinitely.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RecordLimits {
+    /// How long may we spend processing a record?
+    pub timeout: Option<Duration>,
+
+    /// How large may a record's input be, in bytes?
+    pub max_bytes: Option<u64>,
+}
+
+impl RecordLimits {
+    /// Process a record using `future`, subject to our limits. `input_bytes`
+    /// is the size of the record's input, if known. If a limit is exceeded,
+    /// we add an error to `failed` and return it instead.
+    pub async fn run<T>(
+        &self,
+        input_bytes: Option<u64>,
+        mut failed: WorkOutput<T>,
+        future: impl Future<Output = Result<WorkOutput<T>>>,
+    ) -> Result<WorkOutput<T>> {
+        if let (Some(max_bytes), Some(input_bytes)) = (self.max_bytes, input_bytes)
+            && input_bytes > max_bytes
+        {
+            failed.errors.push(format!(
+                "record_too_large: input is {input_bytes} bytes, but --max-record-bytes is {max_bytes}"
+            ));
+            return Ok(failed);
+        }
+        let Some(timeout) = self.timeout else {
+            return future.await;
+        };
+        match time::timeout(timeout, future).await {
+            Ok(result) => result,
+            Err(_) => {
+                failed.errors.push(format!(
+                    "record_timeout: record took longer than --record-timeout {}s",
+                    timeout.as_secs()
+                ));
+                Ok(failed)
+            }
+        }
+    }
+}
+
+/// Get the size of a local file, or `None` if it isn't one.
+pub async fn local_file_size(path: &Path) -> Option<u64> {
+    tokio::fs::metadata(path)
+        .await
+        .ok()
+        .map(|metadata| metadata.len())
+}
+
 /// Counters associated with a work item.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct WorkOutputCounters {
+    /// When did we start counting?
+    pub started_at: Instant,
+
     /// How many records did we process?
     pub total_record_count: usize,
 
@@ -203,11 +695,62 @@ pub struct WorkOutputCounters {
 
     /// How many tokens did we use?
     pub token_usage: TokenUsage,
+
+    /// Counters for each [`WorkStatus`].
+    pub by_status: BTreeMap<WorkStatus, CounterBreakdown>,
+
+    /// Counters for each model, for records which were processed by a model.
+    pub by_model: BTreeMap<String, CounterBreakdown>,
+}
+
+impl Default for WorkOutputCounters {
+    /// Create new counters, starting the clock now.
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            total_record_count: 0,
+            failure_count: 0,
+            non_fatal_error_count: 0,
+            cost_estimate: 0.0,
+            token_usage: TokenUsage::default(),
+            by_status: BTreeMap::new(),
+            by_model: BTreeMap::new(),
+        }
+    }
 }
 
 impl WorkOutputCounters {
-    /// Wrap a stream with counters.
+    /// Summarize our counters for `--summary-json`.
+    pub fn summary(&self, allowed_failure_rate: f32) -> RunSummary {
+        let duration_seconds = self.started_at.elapsed().as_secs_f64();
+        let failure_rate = if self.total_record_count == 0 {
+            0.0
+        } else {
+            self.failure_count as f64 / self.total_record_count as f64
+        };
+        RunSummary {
+            passed: failure_rate <= f64::from(allowed_failure_rate),
+            total_record_count: self.total_record_count,
+            failure_count: self.failure_count,
+            failure_rate,
+            non_fatal_error_count: self.non_fatal_error_count,
+            cost_estimate: self.cost_estimate,
+            token_usage: self.token_usage.clone(),
+            by_status: self.by_status.clone(),
+            by_model: self.by_model.clone(),
+            duration_seconds,
+            records_per_second: if duration_seconds > 0.0 {
+                self.total_record_count as f64 / duration_seconds
+            } else {
+                0.0
+            },
+        }
+    }
+
+    /// Wrap a stream with counters, which are also reported to `ui` for
+    /// progress logging.
     pub fn wrap_stream<T>(
+        ui: &Ui,
         stream: BoxedStream<Result<WorkOutput<T>>>,
     ) -> (
         BoxedStream<Result<WorkOutput<T>>>,
@@ -215,10 +758,13 @@ impl WorkOutputCounters {
     ) {
         let counters = Arc::new(Mutex::new(Self::default()));
         let counters_clone = counters.clone();
+        let ui = ui.clone();
         let stream = stream
             .map(move |value| {
                 let value = value?;
                 counters_clone.update(&value);
+                let counters = counters_clone.lock().expect("lock poisoned");
+                ui.set_progress_counts(counters.failure_count, counters.cost_estimate);
                 Ok(value)
             })
             .boxed();
@@ -226,6 +772,90 @@ impl WorkOutputCounters {
     }
 }
 
+/// Counts, tokens and cost for a subset of the records in a run.
+#[derive(Clone, Debug, Default, JsonSchema, Serialize)]
+pub struct CounterBreakdown {
+    /// How many records are in this subset?
+    pub record_count: usize,
+
+    /// How many of these records failed?
+    pub failure_count: usize,
+
+    /// How much money do we think we spent on these records, in US dollars?
+    pub cost_estimate: f64,
+
+    /// How many tokens did these records use?
+    pub token_usage: TokenUsage,
+}
+
+impl CounterBreakdown {
+    /// Add a work item to this breakdown.
+    fn update<T>(&mut self, item: &WorkOutput<T>) {
+        self.record_count += 1;
+        if !item.status.is_success() {
+            self.failure_count += 1;
+        }
+        if let Some(cost) = item.estimated_cost {
+            self.cost_estimate += cost;
+        }
+        if let Some(token_usage) = &item.token_usage {
+            self.token_usage += token_usage.clone();
+        }
+    }
+}
+
+/// A machine-readable summary of a run, written by `--summary-json`.
+#[derive(Clone, Debug, JsonSchema, Serialize)]
+pub struct RunSummary {
+    /// Was the failure rate within `--allowed-failure-rate`?
+    pub passed: bool,
+
+    /// How many records did we process?
+    pub total_record_count: usize,
+
+    /// How many records did we fail to process?
+    pub failure_count: usize,
+
+    /// The portion of records which failed, between 0.0 and 1.0.
+    pub failure_rate: f64,
+
+    /// How many non-fatal errors did we encounter?
+    pub non_fatal_error_count: usize,
+
+    /// How much money do we think we spent, in US dollars?
+    pub cost_estimate: f64,
+
+    /// How many tokens did we use?
+    pub token_usage: TokenUsage,
+
+    /// Counters for each record status.
+    pub by_status: BTreeMap<WorkStatus, CounterBreakdown>,
+
+    /// Counters for each model, for records which were processed by a model.
+    pub by_model: BTreeMap<String, CounterBreakdown>,
+
+    /// How long did it take to process and write our records?
+    pub duration_seconds: f64,
+
+    /// How many records did we process per second?
+    pub records_per_second: f64,
+}
+
+impl RunSummary {
+    /// Write this summary as JSON to `path`, or to standard output if `path`
+    /// is `-`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        if path == Path::new("-") {
+            println!("{json}");
+            Ok(())
+        } else {
+            std::fs::write(path, format!("{json}\n"))
+                .with_context(|| format!("cannot write run summary to {path:?}"))
+        }
+    }
+}
+
 /// We actually want to put methods in `Mutex<WorkOutputCounters>`, because
 /// that's the type we actually work with. To do that, we need to define an
 /// extension trait with the methods we want.
@@ -253,10 +883,27 @@ impl WorkItemCounterExt for Mutex<WorkOutputCounters> {
         if let Some(token_usage) = &item.token_usage {
             counters.token_usage += token_usage.clone();
         }
+        counters
+            .by_status
+            .entry(item.status)
+            .or_default()
+            .update(item);
+        if let Some(model) = &item.model {
+            counters
+                .by_model
+                .entry(model.clone())
+                .or_default()
+                .update(item);
+        }
     }
 
     fn finish(self: Arc<Self>, ui: &Ui, stream_opts: &StreamOpts) -> Result<()> {
         let counters = self.lock().expect("lock poisoned").to_owned();
+        if let Some(path) = &stream_opts.summary_json {
+            counters
+                .summary(stream_opts.allowed_failure_rate)
+                .write(path)?;
+        }
         if !counters.token_usage.is_zero() {
             ui.display_message(
                 "📈",
@@ -273,6 +920,36 @@ impl WorkItemCounterExt for Mutex<WorkOutputCounters> {
                 &format!("Estimated cost: US${:.8}", counters.cost_estimate),
             );
         }
+        if counters.by_status.len() > 1 {
+            let statuses = counters
+                .by_status
+                .iter()
+                .map(|(status, breakdown)| {
+                    format!("{} {}", breakdown.record_count, status.as_str())
+                })
+                .collect::<Vec<_>>();
+            ui.display_message("📊", &format!("Records: {}", statuses.join(", ")));
+        }
+        if counters.by_model.len() > 1 {
+            for (model, breakdown) in &counters.by_model {
+                ui.display_message(
+                    "🤖",
+                    &format!(
+                        "{model}: {} records ({} failed), {} tokens in, {} out, US${:.8}",
+                        breakdown.record_count,
+                        breakdown.failure_count,
+                        breakdown.token_usage.prompt_tokens,
+                        breakdown.token_usage.completion_tokens,
+                        breakdown.cost_estimate,
+                    ),
+                );
+            }
+        }
+        if stream_opts.latency_report {
+            for line in latency_report() {
+                ui.display_message("⏱️", &line);
+            }
+        }
         let failure_rate =
             counters.failure_count as f32 / counters.total_record_count as f32;
         if failure_rate > stream_opts.allowed_failure_rate {
@@ -409,7 +1086,10 @@ where
                 let handle = handle.clone();
                 async move {
                     let input = input?;
-                    handle.process_blocking(input).await
+                    let ack_token = input.ack_token;
+                    let mut output = handle.process_blocking(input).await?;
+                    output.ack_token = ack_token;
+                    Ok(output)
                 }
                 .boxed()
             })
@@ -449,6 +1129,10 @@ where
 ///
 /// We maintain backpressure by limiting the number of work items queued, and the number currently
 /// being processed.
+///
+/// To stop a queue, use [`JoinWorker::drain`] or [`JoinWorker::abort`] on the worker returned by
+/// [`WorkQueue::new`]. Aborting it cancels any queued or in-progress work items, and anyone
+/// waiting on their results gets an error.
 pub struct WorkQueue<InputData, OutputData>
 where
     InputData: 'static,
@@ -498,3 +1182,47 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+
+    /// Records the ack token of each record it's asked to acknowledge.
+    #[derive(Default)]
+    struct RecordingAck(Mutex<Vec<Option<u64>>>);
+
+    #[async_trait]
+    impl OutputAck for RecordingAck {
+        async fn ack(&self, record: &Value) -> Result<()> {
+            let token = record.get(ACK_TOKEN_FIELD).and_then(Value::as_u64);
+            self.0.lock().expect("lock poisoned").push(token);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn acks_use_each_outputs_own_token() {
+        // Two inputs share an ID, and finish in the opposite order.
+        let outputs = [("a", 2), ("b", 3), ("a", 1)].map(|(id, token)| {
+            let mut output = WorkOutput::new_failed(json!(id), vec![], json!({}), None);
+            output.ack_token = Some(token);
+            Ok(output)
+        });
+        let recording = Arc::new(RecordingAck::default());
+        let (stream, ack) =
+            track_ack_tokens(stream::iter(outputs).boxed(), Some(recording.clone()));
+        let ack = ack.unwrap();
+        let records = stream.collect::<Vec<_>>().await;
+        for record in records {
+            let record = record.unwrap().to_json().unwrap();
+            assert!(record.get(ACK_TOKEN_FIELD).is_none());
+            ack.ack(&record).await.unwrap();
+        }
+        assert_eq!(
+            *recording.0.lock().unwrap(),
+            vec![Some(2), Some(3), Some(1)]
+        );
+    }
+}
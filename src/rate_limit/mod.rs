@@ -0,0 +1,327 @@
+//! Support for specifying rate limits for calling various APIs.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, LazyLock, Mutex, Weak},
+    time::Duration,
+};
+
+use leaky_bucket::RateLimiter;
+use reqwest::header::HeaderMap;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::prelude::*;
+
+pub use self::redis::RedisConfig;
+use self::redis::RedisRateLimiter;
+
+mod redis;
+
+/// The period over which the rate limit is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateLimitPeriod {
+    /// Per second.
+    Second,
+    /// Per minute.
+    Minute,
+}
+
+impl RateLimitPeriod {
+    /// Convert this period to a number of seconds.
+    pub fn to_duration(self) -> Duration {
+        match self {
+            RateLimitPeriod::Second => Duration::from_secs(1),
+            RateLimitPeriod::Minute => Duration::from_secs(60),
+        }
+    }
+}
+
+impl fmt::Display for RateLimitPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitPeriod::Second => write!(f, "s"),
+            RateLimitPeriod::Minute => write!(f, "m"),
+        }
+    }
+}
+
+impl FromStr for RateLimitPeriod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "s" => Ok(RateLimitPeriod::Second),
+            "m" => Ok(RateLimitPeriod::Minute),
+            _ => Err(anyhow!("Unsupported rate limit period: {:?}", s)),
+        }
+    }
+}
+
+/// A rate limit for an API.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RateLimit {
+    /// The maximum number of requests allowed in the period.
+    pub max_requests: usize,
+    /// The period over which the rate limit is applied.
+    pub per_period: RateLimitPeriod,
+}
+
+impl RateLimit {
+    /// Create a new [`RateLimit`].
+    pub fn new(max_requests: usize, per_period: RateLimitPeriod) -> Self {
+        Self {
+            max_requests,
+            per_period,
+        }
+    }
+
+    /// Create a [`RateLimiter`] for this rate limit.
+    pub fn to_rate_limiter(&self) -> RateLimiter {
+        // We only refill once every `interval` seconds, so for
+        // `RateLimitPeriod::Minute`, we may want to do some math to calculate
+        // per-second limits instead. For now, just start with a full bucket and
+        // hope the user doesn't call `prompt-scaler` twice in rapid succession.
+        RateLimiter::builder()
+            .initial(self.max_requests)
+            .refill(self.max_requests)
+            .max(self.max_requests)
+            .interval(self.per_period.to_duration())
+            .build()
+    }
+
+    /// Get a [`RequestLimiter`] for this rate limit, shared by every queue in
+    /// this process using the same `scope`, such as `openai/gpt-4o`. If we
+    /// have a `--rate-limit-backend`, the limit is also shared with other
+    /// processes.
+    pub async fn to_request_limiter(
+        &self,
+        backend: Option<&RedisConfig>,
+        scope: &str,
+    ) -> Result<Arc<RequestLimiter>> {
+        // Hold our lock while creating a new limiter, so that two queues
+        // starting at once can't both create one.
+        let mut limiters = REQUEST_LIMITERS.lock().await;
+        if let Some((rate_limit, limiter)) = limiters.get(scope)
+            && let Some(limiter) = limiter.upgrade()
+        {
+            if rate_limit != self {
+                warn!(
+                    "Requests for {scope} are already limited to {rate_limit}, \
+                     ignoring {self}"
+                );
+            }
+            return Ok(limiter);
+        }
+        let limiter = Arc::new(match backend {
+            Some(config) => {
+                RequestLimiter::Redis(RedisRateLimiter::new(config, self, scope).await?)
+            }
+            None => RequestLimiter::Local(self.to_rate_limiter()),
+        });
+        limiters.insert(
+            scope.to_owned(),
+            (self.to_owned(), Arc::downgrade(&limiter)),
+        );
+        Ok(limiter)
+    }
+}
+
+/// The request limiters in use by this process, by scope.
+///
+/// We only keep weak references, so that limiters go away with the last queue
+/// using them.
+static REQUEST_LIMITERS: LazyLock<
+    AsyncMutex<HashMap<String, (RateLimit, Weak<RequestLimiter>)>>,
+> = LazyLock::new(AsyncMutex::default);
+
+/// Limits how often we send requests.
+pub enum RequestLimiter {
+    /// Limit requests from this process.
+    Local(RateLimiter),
+
+    /// Limit requests from all processes using a `--rate-limit-backend`.
+    Redis(RedisRateLimiter),
+}
+
+impl RequestLimiter {
+    /// Wait until we may send another request.
+    pub async fn acquire_one(&self) {
+        match self {
+            RequestLimiter::Local(rate_limiter) => rate_limiter.acquire_one().await,
+            RequestLimiter::Redis(rate_limiter) => rate_limiter.acquire_one().await,
+        }
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.max_requests, self.per_period)
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = |s: &str| -> Result<_> {
+            let mut parts = s.splitn(2, '/');
+            let max_requests = parts
+                .next()
+                .ok_or_else(|| anyhow!("Missing max requests"))?
+                .parse::<usize>()?;
+            let per_period = parts
+                .next()
+                .ok_or_else(|| anyhow!("Missing period"))?
+                .parse::<RateLimitPeriod>()?;
+            Ok(Self {
+                max_requests,
+                per_period,
+            })
+        };
+        parse(s).with_context(|| format!("Failed to parse rate limit: {s:?}"))
+    }
+}
+
+/// Rate limits reported by a provider in `x-ratelimit-*` response headers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProviderRateLimits {
+    /// The maximum number of requests allowed in the provider's window.
+    pub limit_requests: Option<u64>,
+    /// The number of requests remaining in the provider's window.
+    pub remaining_requests: Option<u64>,
+    /// The maximum number of tokens allowed in the provider's window.
+    pub limit_tokens: Option<u64>,
+    /// The number of tokens remaining in the provider's window.
+    pub remaining_tokens: Option<u64>,
+}
+
+impl ProviderRateLimits {
+    /// Extract rate limits from response headers, if the provider sent any.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let get = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+        let limits = Self {
+            limit_requests: get("x-ratelimit-limit-requests"),
+            remaining_requests: get("x-ratelimit-remaining-requests"),
+            limit_tokens: get("x-ratelimit-limit-tokens"),
+            remaining_tokens: get("x-ratelimit-remaining-tokens"),
+        };
+        (limits != Self::default()).then_some(limits)
+    }
+}
+
+impl fmt::Display for ProviderRateLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(remaining) = self.remaining_requests {
+            parts.push(format!("{remaining} req"));
+        }
+        if let Some(remaining) = self.remaining_tokens {
+            parts.push(format!("{remaining} tok"));
+        }
+        write!(f, "{} left", parts.join(", "))
+    }
+}
+
+/// The most recent rate limits reported by any provider.
+static LATEST_PROVIDER_RATE_LIMITS: Mutex<Option<ProviderRateLimits>> = Mutex::new(None);
+
+/// Record the rate limits from a provider response.
+pub fn record_provider_rate_limits(limits: ProviderRateLimits) {
+    debug!(
+        limit_requests = ?limits.limit_requests,
+        remaining_requests = ?limits.remaining_requests,
+        limit_tokens = ?limits.limit_tokens,
+        remaining_tokens = ?limits.remaining_tokens,
+        "Provider rate limits"
+    );
+    *LATEST_PROVIDER_RATE_LIMITS
+        .lock()
+        .expect("rate limit lock poisoned") = Some(limits);
+}
+
+/// The most recent rate limits reported by any provider, for display.
+pub fn latest_provider_rate_limits() -> Option<ProviderRateLimits> {
+    LATEST_PROVIDER_RATE_LIMITS
+        .lock()
+        .expect("rate limit lock poisoned")
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let rate_limit = RateLimit::from_str("10/s").unwrap();
+        assert_eq!(rate_limit.max_requests, 10);
+        assert_eq!(rate_limit.per_period, RateLimitPeriod::Second);
+
+        let rate_limit = RateLimit::from_str("5/m").unwrap();
+        assert_eq!(rate_limit.max_requests, 5);
+        assert_eq!(rate_limit.per_period, RateLimitPeriod::Minute);
+    }
+
+    #[test]
+    fn test_failed_parse() {
+        assert!(RateLimit::from_str("10/invalid").is_err());
+        assert!(RateLimit::from_str("invalid").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let rate_limit = RateLimit::from_str("10/s").unwrap();
+        assert_eq!(rate_limit.to_string(), "10/s");
+
+        let rate_limit = RateLimit::from_str("5/m").unwrap();
+        assert_eq!(rate_limit.to_string(), "5/m");
+    }
+
+    #[tokio::test]
+    async fn test_request_limiters_are_shared_by_scope() {
+        let rate_limit = RateLimit::from_str("10/s").unwrap();
+        let first = rate_limit
+            .to_request_limiter(None, "test/model-a")
+            .await
+            .unwrap();
+        let second = RateLimit::from_str("20/s")
+            .unwrap()
+            .to_request_limiter(None, "test/model-a")
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        let other = rate_limit
+            .to_request_limiter(None, "test/model-b")
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &other));
+
+        // Once nobody is using a limiter, we let it go.
+        drop((first, second));
+        let limiters = REQUEST_LIMITERS.lock().await;
+        assert!(limiters["test/model-a"].1.upgrade().is_none());
+        assert!(limiters["test/model-b"].1.upgrade().is_some());
+    }
+
+    #[test]
+    fn test_provider_rate_limits_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(ProviderRateLimits::from_headers(&headers), None);
+
+        headers.insert("x-ratelimit-limit-requests", "5000".parse().unwrap());
+        headers.insert("x-ratelimit-remaining-requests", "4999".parse().unwrap());
+        headers.insert("x-ratelimit-remaining-tokens", "159976".parse().unwrap());
+        headers.insert("x-ratelimit-reset-tokens", "6m0s".parse().unwrap());
+        let limits = ProviderRateLimits::from_headers(&headers).unwrap();
+        assert_eq!(limits.limit_requests, Some(5000));
+        assert_eq!(limits.limit_tokens, None);
+        assert_eq!(limits.to_string(), "4999 req, 159976 tok left");
+    }
+}
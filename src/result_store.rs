@@ -0,0 +1,623 @@
+//! DuckDB-based local result store.
+//!
+//! Outputs can be written to a DuckDB file using `--output-format duckdb`,
+//! with typed columns based on the output schema. Each run is appended to the
+//! same table with its own `run_id`, and the `query` subcommand can be used to
+//! run SQL over previous runs.
+//!
+//! DuckDB support requires building with `--features duckdb`, because the
+//! bundled DuckDB library takes a long time to compile.
+
+#[cfg(feature = "duckdb")]
+use crate::spend_ledger::civil_from_days;
+use crate::{
+    async_utils::io::{JsonStream, OutputAck},
+    prelude::*,
+};
+
+/// Default table to write DuckDB output to.
+pub const DEFAULT_DUCKDB_TABLE: &str = "outputs";
+
+/// The SQL type of a DuckDB column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    /// A string.
+    Varchar,
+    /// An integer.
+    BigInt,
+    /// A floating-point number.
+    Double,
+    /// A boolean.
+    Boolean,
+    /// Anything we can't represent more precisely.
+    Json,
+}
+
+impl ColumnType {
+    /// Choose a column type for a JSON Schema.
+    fn from_json_schema(schema: &Value) -> Self {
+        // Treat `["string", "null"]` the same as `"string"`.
+        let ty = match &schema["type"] {
+            Value::Array(types) => {
+                let mut non_null = types.iter().filter(|ty| *ty != "null");
+                match (non_null.next(), non_null.next()) {
+                    (Some(ty), None) => ty.as_str(),
+                    _ => None,
+                }
+            }
+            ty => ty.as_str(),
+        };
+        match ty {
+            Some("string") => ColumnType::Varchar,
+            Some("integer") => ColumnType::BigInt,
+            Some("number") => ColumnType::Double,
+            Some("boolean") => ColumnType::Boolean,
+            _ => ColumnType::Json,
+        }
+    }
+
+    /// The SQL name of this type.
+    #[cfg_attr(not(feature = "duckdb"), allow(dead_code))]
+    fn sql(self) -> &'static str {
+        match self {
+            ColumnType::Varchar => "VARCHAR",
+            ColumnType::BigInt => "BIGINT",
+            ColumnType::Double => "DOUBLE",
+            ColumnType::Boolean => "BOOLEAN",
+            ColumnType::Json => "JSON",
+        }
+    }
+}
+
+/// A column in our output table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Column {
+    /// The column name.
+    pub name: String,
+
+    /// The column type.
+    pub column_type: ColumnType,
+
+    /// The path to this column's value in an output record.
+    pub path: Vec<String>,
+}
+
+impl Column {
+    /// Create a column for a top-level output field.
+    pub fn top_level(name: &str, column_type: ColumnType) -> Self {
+        Self {
+            name: name.to_owned(),
+            column_type,
+            path: vec![name.to_owned()],
+        }
+    }
+
+    /// Look up this column's value in an output record.
+    #[cfg_attr(not(feature = "duckdb"), allow(dead_code))]
+    fn value<'a>(&self, record: &'a Value) -> &'a Value {
+        self.path
+            .iter()
+            .try_fold(record, |value, key| value.get(key))
+            .unwrap_or(&Value::Null)
+    }
+}
+
+/// Compute the columns for our output table.
+///
+/// We always include the standard [`crate::queues::work::WorkOutput`] fields.
+/// Each top-level property of `data_schema` becomes an additional typed column.
+/// If `data_field` is specified, those properties are looked up inside that
+/// field of the output record (for example, `response` for chat output).
+pub fn output_columns(data_schema: &Value, data_field: Option<&str>) -> Vec<Column> {
+    let mut columns = vec![
+        Column::top_level("id", ColumnType::Varchar),
+        Column::top_level("status", ColumnType::Varchar),
+        Column::top_level("estimated_cost", ColumnType::Double),
+        Column::top_level("token_usage", ColumnType::Json),
+        Column::top_level("errors", ColumnType::Json),
+        Column::top_level("passthrough_data", ColumnType::Json),
+        Column::top_level("prompt_version", ColumnType::Varchar),
+        Column::top_level("prompt_hash", ColumnType::Varchar),
+    ];
+    let Some(properties) = data_schema["properties"].as_object() else {
+        return columns;
+    };
+    for (name, schema) in properties {
+        let column_type = ColumnType::from_json_schema(schema);
+        let column = match data_field {
+            Some(data_field) => {
+                // Avoid clashing with our standard columns.
+                let column_name = if columns.iter().any(|c| &c.name == name) {
+                    format!("{data_field}_{name}")
+                } else {
+                    name.to_owned()
+                };
+                Column {
+                    name: column_name,
+                    column_type,
+                    path: vec![data_field.to_owned(), name.to_owned()],
+                }
+            }
+            None if columns.iter().any(|c| &c.name == name) => continue,
+            None => Column::top_level(name, column_type),
+        };
+        columns.push(column);
+    }
+    columns
+}
+
+/// Quote a SQL identifier.
+#[cfg_attr(not(feature = "duckdb"), allow(dead_code))]
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// How many ready output records to append to DuckDB at once.
+#[cfg(feature = "duckdb")]
+const DUCKDB_BATCH_SIZE: usize = 500;
+
+/// Append output records to `table` in a DuckDB file, creating it if needed.
+#[cfg(feature = "duckdb")]
+#[instrument(level = "debug", skip(columns, stream, ack))]
+pub async fn write_duckdb(
+    path: &Path,
+    table: &str,
+    columns: &[Column],
+    stream: JsonStream,
+    ack: Option<&dyn OutputAck>,
+) -> Result<()> {
+    use futures::StreamExt as _;
+
+    use crate::async_utils::blocking_iter_streams::spawn_blocking_propagating_panics;
+
+    // DuckDB is synchronous, so we hand our writer to a blocking thread for
+    // each batch of records, and get it back once they've been appended.
+    let mut writer = {
+        let path = path.to_owned();
+        let table = table.to_owned();
+        let columns = columns.to_vec();
+        spawn_blocking_propagating_panics(move || {
+            DuckDbWriter::open(&path, table, columns)
+        })
+        .await?
+    };
+    let mut batches = stream.ready_chunks(DUCKDB_BATCH_SIZE);
+    while let Some(batch) = batches.next().await {
+        let batch = batch.into_iter().collect::<Result<Vec<_>>>()?;
+        let (returned, batch) = spawn_blocking_propagating_panics(move || {
+            writer.append(&batch)?;
+            Ok::<_, anyhow::Error>((writer, batch))
+        })
+        .await?;
+        writer = returned;
+        if let Some(ack) = ack {
+            for record in &batch {
+                ack.ack(record).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Appends output records to a DuckDB table. All methods perform blocking
+/// I/O.
+#[cfg(feature = "duckdb")]
+struct DuckDbWriter {
+    /// Our DuckDB connection.
+    conn: duckdb::Connection,
+
+    /// The table to append to.
+    table: String,
+
+    /// Our columns, not including `run_id`.
+    columns: Vec<Column>,
+
+    /// The ID of this run, stored with each record.
+    run_id: String,
+
+    /// Columns for which we've already warned about values of the wrong type.
+    warned_columns: std::collections::HashSet<String>,
+}
+
+#[cfg(feature = "duckdb")]
+impl DuckDbWriter {
+    /// Open `path`, creating `table` if needed, and checking that it has the
+    /// columns we expect if it already exists.
+    fn open(path: &Path, table: String, columns: Vec<Column>) -> Result<Self> {
+        let conn = duckdb::Connection::open(path)
+            .with_context(|| format!("Failed to open DuckDB file {path:?}"))?;
+        let column_defs = std::iter::once("run_id VARCHAR".to_owned())
+            .chain(
+                columns
+                    .iter()
+                    .map(|c| format!("{} {}", quote_ident(&c.name), c.column_type.sql())),
+            )
+            .collect::<Vec<_>>()
+            .join(", ");
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} ({column_defs})",
+            quote_ident(&table)
+        ))
+        .with_context(|| format!("Failed to create DuckDB table {table:?}"))?;
+
+        // An earlier run may have created this table with a different schema,
+        // in which case the appender would fail with a confusing error.
+        let existing = conn
+            .prepare(
+                "SELECT column_name FROM information_schema.columns \
+                 WHERE table_schema = current_schema() AND table_name = ? \
+                 ORDER BY ordinal_position",
+            )?
+            .query_map([table.as_str()], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to look up columns of {table:?}"))?;
+        let expected = std::iter::once("run_id")
+            .chain(columns.iter().map(|c| c.name.as_str()))
+            .collect::<Vec<_>>();
+        if existing != expected {
+            return Err(anyhow!(
+                "DuckDB table {table:?} has columns ({}), but this run needs ({}); \
+                 use a different --output-table or output file",
+                existing.join(", "),
+                expected.join(", "),
+            ));
+        }
+
+        Ok(Self {
+            conn,
+            table,
+            columns,
+            run_id: uuid::Uuid::new_v4().to_string(),
+            warned_columns: Default::default(),
+        })
+    }
+
+    /// Append `records` to our table, and flush them.
+    fn append(&mut self, records: &[Value]) -> Result<()> {
+        use duckdb::{appender_params_from_iter, types::Value as DuckValue};
+
+        let mut appender = self.conn.appender(&self.table).with_context(|| {
+            format!("Failed to append to DuckDB table {:?}", self.table)
+        })?;
+        for record in records {
+            let values = std::iter::once(DuckValue::Text(self.run_id.clone())).chain(
+                self.columns.iter().map(|column| {
+                    let value = column.value(record);
+                    let converted = match (column.column_type, value) {
+                        (_, Value::Null) => return DuckValue::Null,
+                        (ColumnType::Varchar, Value::String(s)) => {
+                            Some(DuckValue::Text(s.clone()))
+                        }
+                        (ColumnType::BigInt, value) => {
+                            value.as_i64().map(DuckValue::BigInt)
+                        }
+                        (ColumnType::Double, value) => {
+                            value.as_f64().map(DuckValue::Double)
+                        }
+                        (ColumnType::Boolean, value) => {
+                            value.as_bool().map(DuckValue::Boolean)
+                        }
+                        (ColumnType::Varchar | ColumnType::Json, value) => {
+                            Some(DuckValue::Text(value.to_string()))
+                        }
+                    };
+                    converted.unwrap_or_else(|| {
+                        if self.warned_columns.insert(column.name.clone()) {
+                            warn!(
+                                "DuckDB column {:?} is {}, but record {} has {value}; \
+                                 writing NULL (we only warn once per column)",
+                                column.name,
+                                column.column_type.sql(),
+                                record["id"],
+                            );
+                        }
+                        DuckValue::Null
+                    })
+                }),
+            );
+            appender
+                .append_row(appender_params_from_iter(values))
+                .context("Failed to append DuckDB row")?;
+        }
+        appender.flush().context("Failed to flush DuckDB output")?;
+        Ok(())
+    }
+}
+
+/// Stub used when we're built without DuckDB support.
+#[cfg(not(feature = "duckdb"))]
+pub async fn write_duckdb(
+    _path: &Path,
+    _table: &str,
+    _columns: &[Column],
+    _stream: JsonStream,
+    _ack: Option<&dyn OutputAck>,
+) -> Result<()> {
+    Err(not_built_with_duckdb())
+}
+
+/// How many query results to read ahead of our output.
+#[cfg(feature = "duckdb")]
+const QUERY_ROW_BUFFER: usize = 500;
+
+/// Run a SQL query against a DuckDB file, streaming each row as a JSON object.
+///
+/// Integers and decimals become JSON numbers, timestamps and dates become
+/// RFC 3339 strings, and `JSON` columns are parsed.
+#[cfg(feature = "duckdb")]
+#[instrument(level = "debug")]
+pub fn query_duckdb(path: &Path, sql: &str) -> JsonStream {
+    use futures::{StreamExt as _, stream};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+
+    // DuckDB is synchronous, so we run our query on a blocking thread, which
+    // sends us each row as it is read.
+    let (sender, receiver) = mpsc::channel(QUERY_ROW_BUFFER);
+    let (path, sql) = (path.to_owned(), sql.to_owned());
+    let query = tokio::task::spawn_blocking(move || {
+        if let Err(err) = send_query_rows(&path, &sql, &sender) {
+            // If nobody is listening, our output has already failed.
+            let _ = sender.blocking_send(Err(err));
+        }
+    });
+    let panicked = stream::once(query).filter_map(|joined| async move {
+        joined
+            .err()
+            .map(|err| Err(anyhow::Error::new(err).context("DuckDB query panicked")))
+    });
+    ReceiverStream::new(receiver).chain(panicked).boxed()
+}
+
+/// Run `sql` against the DuckDB file at `path`, sending each row to `sender`.
+/// Performs blocking I/O.
+#[cfg(feature = "duckdb")]
+fn send_query_rows(
+    path: &Path,
+    sql: &str,
+    sender: &tokio::sync::mpsc::Sender<Result<Value>>,
+) -> Result<()> {
+    use duckdb::{AccessMode, Config, Connection, types::Value as DuckValue};
+
+    let config = Config::default().access_mode(AccessMode::ReadOnly)?;
+    let conn = Connection::open_with_flags(path, config)
+        .with_context(|| format!("Failed to open DuckDB file {path:?}"))?;
+
+    // DuckDB returns `JSON` columns as text, so ask which columns are JSON.
+    let json_columns = describe_json_columns(&conn, sql);
+
+    let mut stmt = conn.prepare(sql).context("Failed to prepare query")?;
+    let mut rows = stmt.query([]).context("Failed to run query")?;
+    let names = rows
+        .as_ref()
+        .map(|stmt| stmt.column_names())
+        .unwrap_or_default();
+    let is_json = names
+        .iter()
+        .map(|name| json_columns.contains(name))
+        .collect::<Vec<_>>();
+    while let Some(row) = rows.next().context("Failed to read query results")? {
+        let mut map = serde_json::Map::new();
+        for (idx, name) in names.iter().enumerate() {
+            let value = match row.get::<_, DuckValue>(idx)? {
+                DuckValue::Text(text) if is_json[idx] => {
+                    serde_json::from_str(&text).unwrap_or_else(|_| Value::String(text))
+                }
+                value => duck_value_to_json(value),
+            };
+            map.insert(name.clone(), value);
+        }
+        if sender.blocking_send(Ok(Value::Object(map))).is_err() {
+            // Our output has failed, and will report why.
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// The names of the columns returned by `sql` which have DuckDB's `JSON`
+/// type. If we can't describe `sql`, we assume there are none.
+#[cfg(feature = "duckdb")]
+fn describe_json_columns(conn: &duckdb::Connection, sql: &str) -> Vec<String> {
+    let describe = || -> duckdb::Result<Vec<String>> {
+        let mut stmt = conn.prepare(&format!("DESCRIBE {sql}"))?;
+        let columns = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<duckdb::Result<Vec<_>>>()?;
+        Ok(columns
+            .into_iter()
+            .filter(|(_, column_type)| column_type == "JSON")
+            .map(|(name, _)| name)
+            .collect())
+    };
+    describe().unwrap_or_else(|err| {
+        debug!("Cannot describe query, so not parsing JSON columns: {err}");
+        vec![]
+    })
+}
+
+/// Convert a DuckDB value to JSON.
+#[cfg(feature = "duckdb")]
+fn duck_value_to_json(value: duckdb::types::Value) -> Value {
+    use duckdb::types::{TimeUnit, Value as DuckValue};
+
+    /// Convert a time in `unit` to microseconds.
+    fn micros(unit: TimeUnit, value: i64) -> i64 {
+        match unit {
+            TimeUnit::Second => value.saturating_mul(1_000_000),
+            TimeUnit::Millisecond => value.saturating_mul(1_000),
+            TimeUnit::Microsecond => value,
+            TimeUnit::Nanosecond => value.div_euclid(1_000),
+        }
+    }
+
+    match value {
+        DuckValue::Null => Value::Null,
+        DuckValue::Boolean(b) => json!(b),
+        DuckValue::TinyInt(i) => json!(i),
+        DuckValue::SmallInt(i) => json!(i),
+        DuckValue::Int(i) => json!(i),
+        DuckValue::BigInt(i) => json!(i),
+        // JSON numbers can only hold 128-bit integers which fit in 64 bits.
+        DuckValue::HugeInt(i) => {
+            serde_json::to_value(i).unwrap_or_else(|_| json!(i.to_string()))
+        }
+        DuckValue::UTinyInt(i) => json!(i),
+        DuckValue::USmallInt(i) => json!(i),
+        DuckValue::UInt(i) => json!(i),
+        DuckValue::UBigInt(i) => json!(i),
+        DuckValue::Float(f) => json!(f),
+        DuckValue::Double(f) => json!(f),
+        DuckValue::Decimal(d) => {
+            let text = d.to_string();
+            match text.parse::<serde_json::Number>() {
+                Ok(number) => Value::Number(number),
+                Err(_) => Value::String(text),
+            }
+        }
+        DuckValue::Timestamp(unit, value) => {
+            json!(rfc3339_timestamp(micros(unit, value)))
+        }
+        DuckValue::Date32(days) => json!(rfc3339_date(i64::from(days))),
+        DuckValue::Time64(unit, value) => json!(time_of_day(micros(unit, value))),
+        DuckValue::Text(s) | DuckValue::Enum(s) => json!(s),
+        DuckValue::List(values) | DuckValue::Array(values) => {
+            Value::Array(values.into_iter().map(duck_value_to_json).collect())
+        }
+        other => json!(format!("{other:?}")),
+    }
+}
+
+/// Microseconds in a day.
+#[cfg(feature = "duckdb")]
+const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+/// Format microseconds since the Unix epoch as an RFC 3339 UTC timestamp.
+#[cfg(feature = "duckdb")]
+fn rfc3339_timestamp(micros: i64) -> String {
+    let date = rfc3339_date(micros.div_euclid(MICROS_PER_DAY));
+    let time = time_of_day(micros.rem_euclid(MICROS_PER_DAY));
+    format!("{date}T{time}Z")
+}
+
+/// Format days since the Unix epoch as an RFC 3339 date.
+#[cfg(feature = "duckdb")]
+fn rfc3339_date(days: i64) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Format microseconds since midnight as `HH:MM:SS`, with fractional seconds
+/// if there are any.
+#[cfg(feature = "duckdb")]
+fn time_of_day(micros: i64) -> String {
+    let secs = micros.div_euclid(1_000_000);
+    let fraction = micros.rem_euclid(1_000_000);
+    let time = format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3_600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    );
+    if fraction == 0 {
+        time
+    } else {
+        format!("{time}.{fraction:06}")
+    }
+}
+
+/// Stub used when we're built without DuckDB support.
+#[cfg(not(feature = "duckdb"))]
+pub fn query_duckdb(_path: &Path, _sql: &str) -> JsonStream {
+    use futures::{StreamExt as _, stream};
+
+    stream::once(async { Err(not_built_with_duckdb()) }).boxed()
+}
+
+/// Error to return when we weren't built with DuckDB support.
+#[cfg(not(feature = "duckdb"))]
+fn not_built_with_duckdb() -> anyhow::Error {
+    anyhow!("DuckDB support requires prompt-scaler to be built with `--features duckdb`")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_columns_from_response_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "summary": { "type": "string" },
+                "count": { "type": ["integer", "null"] },
+                "status": { "type": "boolean" },
+                "tags": { "type": "array", "items": { "type": "string" } },
+            },
+        });
+        let columns = output_columns(&schema, Some("response"));
+        let find = |name: &str| columns.iter().find(|c| c.name == name).unwrap();
+        assert_eq!(find("summary").column_type, ColumnType::Varchar);
+        assert_eq!(find("summary").path, vec!["response", "summary"]);
+        assert_eq!(find("count").column_type, ColumnType::BigInt);
+        assert_eq!(find("tags").column_type, ColumnType::Json);
+        assert_eq!(find("response_status").column_type, ColumnType::Boolean);
+        assert_eq!(find("status").column_type, ColumnType::Varchar);
+
+        let record = json!({ "id": 1, "response": { "summary": "hi" } });
+        assert_eq!(find("summary").value(&record), &json!("hi"));
+        assert_eq!(find("count").value(&record), &Value::Null);
+    }
+
+    #[cfg(feature = "duckdb")]
+    #[test]
+    fn duckdb_times_are_formatted_as_rfc_3339() {
+        assert_eq!(rfc3339_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            rfc3339_timestamp(19_782 * MICROS_PER_DAY + 3_723_000_500),
+            "2024-02-29T01:02:03.000500Z"
+        );
+        assert_eq!(rfc3339_timestamp(-1), "1969-12-31T23:59:59.999999Z");
+    }
+
+    #[cfg(feature = "duckdb")]
+    #[tokio::test]
+    async fn query_results_are_converted_to_json() {
+        use futures::TryStreamExt as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("results.duckdb");
+        let conn = duckdb::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (big HUGEINT, price DECIMAL(10, 2), at TIMESTAMP, \
+             day DATE, data JSON); \
+             INSERT INTO t VALUES (12345678901234567890, 19.99, \
+             '2024-02-29 01:02:03', '2024-02-29', '{\"a\": [1]}');",
+        )
+        .unwrap();
+        drop(conn);
+
+        let rows = query_duckdb(&path, "SELECT * FROM t")
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![json!({
+                "big": 12_345_678_901_234_567_890_u64,
+                "price": 19.99,
+                "at": "2024-02-29T01:02:03Z",
+                "day": "2024-02-29",
+                "data": { "a": [1] },
+            })]
+        );
+
+        let err = query_duckdb(&path, "SELECT * FROM missing")
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("Failed to prepare query"));
+    }
+}
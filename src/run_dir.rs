@@ -0,0 +1,541 @@
+//! Named run directories, which hold the state needed to resume a run.
+//!
+//! A run directory contains:
+//!
+//! - `command.json`: The original command line and working directory.
+//! - `checkpoint.jsonl`: The ID of each record whose output has been written,
+//!   one per line.
+//! - `manifest.json`, `summary.json` and `output.jsonl`: The usual
+//!   `--manifest`, `--summary-json` and `--out` files, unless those options
+//!   point somewhere else.
+//!
+//! `prompt-scaler resume DIR` parses the original command line again, skips
+//! any records listed in the checkpoint, and appends to the existing output.
+//!
+//! Each checkpoint entry is written as soon as its output record has been
+//! flushed, so nothing is lost if we're killed (even by `SIGKILL` or the OOM
+//! killer). Surviving a machine crash also requires the data to reach the
+//! disk, so we `fsync` the output and checkpoint every `--checkpoint-interval`.
+//! A crash in the middle of a write may leave a partial last line, which we
+//! repair before resuming. A partial output line is only kept if its record
+//! was checkpointed, since otherwise we'll write it again.
+
+use std::{
+    collections::HashSet,
+    env, fmt, future,
+    io::{ErrorKind, SeekFrom},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt as _;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _},
+    sync::Mutex,
+};
+
+use crate::{
+    async_utils::{
+        BoxedStream,
+        io::{Compression, OutputAck, id_key},
+    },
+    cmd::{OutputFormat, StreamOpts},
+    prelude::*,
+    queues::work::{IdFrom, WorkInput},
+};
+
+/// The original command line of a run.
+const COMMAND_FILE: &str = "command.json";
+
+/// The IDs of records whose output has been written.
+const CHECKPOINT_FILE: &str = "checkpoint.jsonl";
+
+/// The default `--manifest` path.
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// The default `--summary-json` path.
+const SUMMARY_FILE: &str = "summary.json";
+
+/// The default `--out` path.
+const OUTPUT_FILE: &str = "output.jsonl";
+
+/// The command which started a run.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecordedCommand {
+    /// The working directory, which relative paths in `args` are based on.
+    pub cwd: PathBuf,
+
+    /// Our command-line arguments, not including the program name. These may
+    /// include secrets passed on the command line, such as `--header` values,
+    /// so run directories should be treated like shell history.
+    pub args: Vec<String>,
+}
+
+impl RecordedCommand {
+    /// Load the command which started the run in `run_dir`.
+    pub async fn load(run_dir: &Path) -> Result<Self> {
+        let path = run_dir.join(COMMAND_FILE);
+        let json = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("cannot read {path:?}. Is this a --run-dir?"))?;
+        serde_json::from_slice(&json).with_context(|| format!("cannot parse {path:?}"))
+    }
+
+    /// Record our own command line in `run_dir`.
+    async fn record(run_dir: &Path) -> Result<()> {
+        let args = env::args_os()
+            .skip(1)
+            .map(|arg| {
+                arg.into_string()
+                    .map_err(|arg| anyhow!("cannot record non-UTF-8 argument {arg:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let command = Self {
+            cwd: env::current_dir().context("cannot get current directory")?,
+            args,
+        };
+        let path = run_dir.join(COMMAND_FILE);
+        let json = serde_json::to_vec_pretty(&command)
+            .context("cannot serialize command line")?;
+        tokio::fs::write(&path, json)
+            .await
+            .with_context(|| format!("cannot write {path:?}"))
+    }
+}
+
+/// How often to `fsync` our output and checkpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckpointInterval {
+    /// After this many records.
+    Records(u64),
+
+    /// After this much time has passed, checked as each record is written.
+    Time(Duration),
+}
+
+impl CheckpointInterval {
+    /// Is a sync due, if we've written `records` records since our last sync
+    /// at `last_sync`?
+    fn is_due(self, records: u64, last_sync: Instant) -> bool {
+        match self {
+            CheckpointInterval::Records(interval) => records >= interval,
+            CheckpointInterval::Time(interval) => last_sync.elapsed() >= interval,
+        }
+    }
+}
+
+impl FromStr for CheckpointInterval {
+    type Err = String;
+
+    /// Parse a number of records, like `100`, or of seconds, like `30s`.
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (count, seconds) = match arg.strip_suffix('s') {
+            Some(count) => (count, true),
+            None => (arg, false),
+        };
+        match count.parse::<u64>() {
+            Ok(count) if count > 0 && seconds => {
+                Ok(CheckpointInterval::Time(Duration::from_secs(count)))
+            }
+            Ok(count) if count > 0 => Ok(CheckpointInterval::Records(count)),
+            _ => Err(format!(
+                "expected a number of records (like 100) or seconds (like 30s), got {arg:?}"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CheckpointInterval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointInterval::Records(count) => write!(f, "{count}"),
+            CheckpointInterval::Time(interval) => write!(f, "{}s", interval.as_secs()),
+        }
+    }
+}
+
+/// An entry in our checkpoint journal.
+#[derive(Debug, Deserialize, Serialize)]
+struct CheckpointEntry {
+    /// The ID of a record whose output has been written.
+    id: Value,
+}
+
+/// Set up our `--run-dir`, if we have one, recording our command line and
+/// filling in default paths for our manifest, summary and output.
+pub async fn prepare_run_dir(
+    stream_opts: &mut StreamOpts,
+    output_path: &mut Option<PathBuf>,
+) -> Result<()> {
+    let Some(run_dir) = stream_opts.run_dir.clone() else {
+        return Ok(());
+    };
+    if matches!(stream_opts.id_from, Some(IdFrom::Uuid)) {
+        return Err(anyhow!(
+            "--run-dir needs stable record IDs, so it can't be used with --id-from uuid"
+        ));
+    }
+    tokio::fs::create_dir_all(&run_dir)
+        .await
+        .with_context(|| format!("cannot create run directory {run_dir:?}"))?;
+
+    if !stream_opts.resume {
+        let checkpoint_path = run_dir.join(CHECKPOINT_FILE);
+        let started = tokio::fs::try_exists(&checkpoint_path)
+            .await
+            .with_context(|| format!("cannot check for {checkpoint_path:?}"))?;
+        if started {
+            return Err(anyhow!(
+                "{run_dir:?} already contains a run. Use `prompt-scaler resume {}` to \
+                 continue it, or choose a new --run-dir",
+                run_dir.display()
+            ));
+        }
+        RecordedCommand::record(&run_dir).await?;
+    }
+
+    stream_opts
+        .manifest_path
+        .get_or_insert_with(|| run_dir.join(MANIFEST_FILE));
+    stream_opts
+        .summary_json
+        .get_or_insert_with(|| run_dir.join(SUMMARY_FILE));
+    let output_path = output_path.get_or_insert_with(|| run_dir.join(OUTPUT_FILE));
+
+    // We resume by appending, which doesn't work for every kind of output.
+    if stream_opts.resume {
+        if stream_opts.partition_by.is_some() {
+            return Err(anyhow!("cannot resume a run which used --partition-by"));
+        }
+        if stream_opts.output_format(Some(output_path)) == OutputFormat::Csv {
+            return Err(anyhow!("cannot resume a run with CSV output"));
+        }
+    }
+    Ok(())
+}
+
+/// Get our output path if it's a local, uncompressed JSONL file, whose lines
+/// we can repair.
+fn plain_jsonl_output<'a>(stream_opts: &StreamOpts, path: &'a Path) -> Option<&'a Path> {
+    let plain = local_output(path).is_some()
+        && stream_opts.output_format(Some(path)) == OutputFormat::Jsonl
+        && !stream_opts.pretty
+        && stream_opts.compress.is_none()
+        && Compression::from_path(path).is_none();
+    plain.then_some(path)
+}
+
+/// Get our output path if it's a local file, and not a URL.
+fn local_output(path: &Path) -> Option<&Path> {
+    (!path.to_string_lossy().contains("://")).then_some(path)
+}
+
+/// If the file at `path` ends with a partial line, left by a crash in the
+/// middle of a write, either finish it (if it's valid JSON and `keep` accepts
+/// it) or remove it, so that we can safely append to the file.
+async fn repair_last_line(path: &Path, keep: impl Fn(&Value) -> bool) -> Result<()> {
+    /// How much to read at once while looking for the start of the last line.
+    const CHUNK_BYTES: u64 = 64 * 1024;
+
+    let mut file = match OpenOptions::new().read(true).write(true).open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("cannot open {path:?}")),
+    };
+    let len = file
+        .metadata()
+        .await
+        .with_context(|| format!("cannot get size of {path:?}"))?
+        .len();
+
+    // Look backwards for the newline which ends our last complete line.
+    let mut line_start = 0;
+    let mut end = len;
+    let mut buf = vec![];
+    while end > 0 {
+        let start = end.saturating_sub(CHUNK_BYTES);
+        buf.resize(usize::try_from(end - start)?, 0);
+        file.seek(SeekFrom::Start(start)).await?;
+        file.read_exact(&mut buf)
+            .await
+            .with_context(|| format!("cannot read {path:?}"))?;
+        if end == len && buf.last() == Some(&b'\n') {
+            // The file is already complete.
+            return Ok(());
+        }
+        if let Some(pos) = buf.iter().rposition(|&b| b == b'\n') {
+            line_start = start + u64::try_from(pos)? + 1;
+            break;
+        }
+        end = start;
+    }
+    if line_start == len {
+        return Ok(());
+    }
+
+    file.seek(SeekFrom::Start(line_start)).await?;
+    let mut partial = vec![];
+    file.read_to_end(&mut partial)
+        .await
+        .with_context(|| format!("cannot read {path:?}"))?;
+    if serde_json::from_slice::<Value>(&partial).is_ok_and(|value| keep(&value)) {
+        warn!(path = %path.display(), "Finishing incomplete last line");
+        file.seek(SeekFrom::End(0)).await?;
+        file.write_all(b"\n").await?;
+    } else {
+        warn!(
+            path = %path.display(),
+            bytes = len - line_start,
+            "Removing partial last line"
+        );
+        file.set_len(line_start).await?;
+    }
+    file.sync_all()
+        .await
+        .with_context(|| format!("cannot repair {path:?}"))
+}
+
+/// If we have a `--run-dir`, skip any records which an earlier attempt at this
+/// run already finished, and record the ID of each record once its output has
+/// been written. Any existing `ack` is called first. `output_path` is synced
+/// to disk along with our checkpoint, if it's a local file.
+pub async fn apply_run_dir<T>(
+    stream_opts: &StreamOpts,
+    output_path: Option<&Path>,
+    input: BoxedStream<Result<WorkInput<T>>>,
+    ack: Option<Arc<dyn OutputAck>>,
+) -> Result<(
+    BoxedStream<Result<WorkInput<T>>>,
+    Option<Arc<dyn OutputAck>>,
+)>
+where
+    T: Send + 'static,
+{
+    let Some(run_dir) = &stream_opts.run_dir else {
+        return Ok((input, ack));
+    };
+    let checkpoint_path = run_dir.join(CHECKPOINT_FILE);
+
+    let finished = if stream_opts.resume {
+        // Any complete checkpoint entry means its output was written.
+        repair_last_line(&checkpoint_path, |_| true).await?;
+        let finished = read_checkpoint(&checkpoint_path).await?;
+
+        // A partial output line whose record wasn't checkpointed will be
+        // written again, so keeping it would duplicate the record.
+        if let Some(path) =
+            output_path.and_then(|path| plain_jsonl_output(stream_opts, path))
+        {
+            repair_last_line(path, |record| {
+                finished.contains(&id_key(record.get("id").unwrap_or(&Value::Null)))
+            })
+            .await?;
+        }
+        finished
+    } else {
+        HashSet::new()
+    };
+    let input = if finished.is_empty() {
+        input
+    } else {
+        info!(
+            count = finished.len(),
+            "Skipping records finished by an earlier attempt"
+        );
+        input
+            .filter(move |record| {
+                let done = matches!(
+                    record,
+                    Ok(record) if finished.contains(&id_key(&record.id))
+                );
+                future::ready(!done)
+            })
+            .boxed()
+    };
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&checkpoint_path)
+        .await
+        .with_context(|| format!("cannot open checkpoint {checkpoint_path:?}"))?;
+    let journal = CheckpointJournal {
+        state: Mutex::new(JournalState {
+            file,
+            unsynced_records: 0,
+            last_sync: Instant::now(),
+        }),
+        interval: stream_opts.checkpoint_interval,
+        output_path: output_path
+            .filter(|_| stream_opts.partition_by.is_none())
+            .and_then(local_output)
+            .map(Path::to_owned),
+        inner: ack,
+    };
+    Ok((input, Some(Arc::new(journal))))
+}
+
+/// Read the IDs of finished records from our checkpoint, if it exists.
+async fn read_checkpoint(path: &Path) -> Result<HashSet<String>> {
+    let text = match tokio::fs::read_to_string(path).await {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("cannot read checkpoint {path:?}"));
+        }
+    };
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let entry =
+                serde_json::from_str::<CheckpointEntry>(line).with_context(|| {
+                    format!("invalid checkpoint entry in {path:?}: {line}")
+                })?;
+            Ok(id_key(&entry.id))
+        })
+        .collect()
+}
+
+/// Records the ID of each output record in our checkpoint once it has been
+/// written.
+struct CheckpointJournal {
+    /// Our checkpoint file, and how far behind the disk it is.
+    state: Mutex<JournalState>,
+
+    /// How often to sync our output and checkpoint to disk.
+    interval: CheckpointInterval,
+
+    /// Our output file, if it's a local file we should sync.
+    output_path: Option<PathBuf>,
+
+    /// Another acknowledgement to send first, such as for an SQS input.
+    inner: Option<Arc<dyn OutputAck>>,
+}
+
+/// The mutable state of a [`CheckpointJournal`].
+struct JournalState {
+    /// Our checkpoint file, opened for appending.
+    file: File,
+
+    /// How many records have we written since our last sync?
+    unsynced_records: u64,
+
+    /// When did we last sync?
+    last_sync: Instant,
+}
+
+#[async_trait]
+impl OutputAck for CheckpointJournal {
+    async fn ack(&self, record: &Value) -> Result<()> {
+        if let Some(inner) = &self.inner {
+            inner.ack(record).await?;
+        }
+        let entry = CheckpointEntry {
+            id: record.get("id").cloned().unwrap_or(Value::Null),
+        };
+        let mut line =
+            serde_json::to_string(&entry).context("cannot serialize checkpoint")?;
+        line.push('\n');
+
+        // Hand each entry to the OS immediately, in a single write, so that it
+        // survives us being killed.
+        let mut state = self.state.lock().await;
+        state
+            .file
+            .write_all(line.as_bytes())
+            .await
+            .context("cannot write checkpoint")?;
+        state
+            .file
+            .flush()
+            .await
+            .context("cannot flush checkpoint")?;
+        state.unsynced_records += 1;
+        if !self
+            .interval
+            .is_due(state.unsynced_records, state.last_sync)
+        {
+            return Ok(());
+        }
+
+        // Sync our output before our checkpoint, so that the checkpoint never
+        // mentions a record whose output could be lost.
+        if let Some(path) = &self.output_path {
+            let output = File::open(path)
+                .await
+                .with_context(|| format!("cannot open {path:?} to sync it"))?;
+            output
+                .sync_data()
+                .await
+                .with_context(|| format!("cannot sync {path:?}"))?;
+        }
+        state
+            .file
+            .sync_data()
+            .await
+            .context("cannot sync checkpoint")?;
+        state.unsynced_records = 0;
+        state.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_intervals_are_parsed() {
+        assert_eq!(
+            "100".parse::<CheckpointInterval>(),
+            Ok(CheckpointInterval::Records(100))
+        );
+        assert_eq!(
+            "30s".parse::<CheckpointInterval>(),
+            Ok(CheckpointInterval::Time(Duration::from_secs(30)))
+        );
+        for invalid in ["", "0", "0s", "s", "-1", "1m"] {
+            assert!(invalid.parse::<CheckpointInterval>().is_err(), "{invalid}");
+        }
+        assert_eq!(
+            CheckpointInterval::Time(Duration::from_secs(5)).to_string(),
+            "5s"
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_last_lines_are_repaired() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("checkpoint.jsonl");
+        for (before, after) in [
+            ("", ""),
+            ("{\"id\":1}\n", "{\"id\":1}\n"),
+            ("{\"id\":1}\n{\"id\":2}", "{\"id\":1}\n{\"id\":2}\n"),
+            ("{\"id\":1}\n{\"id\"", "{\"id\":1}\n"),
+            ("{\"id\"", ""),
+        ] {
+            tokio::fs::write(&path, before).await.unwrap();
+            repair_last_line(&path, |_| true).await.unwrap();
+            assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), after);
+        }
+
+        // Complete records are removed if we don't want to keep them.
+        tokio::fs::write(&path, "{\"id\":1}\n{\"id\":2}")
+            .await
+            .unwrap();
+        repair_last_line(&path, |record| record["id"] == 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(&path).await.unwrap(),
+            "{\"id\":1}\n"
+        );
+
+        // Missing files are left alone.
+        repair_last_line(&dir.path().join("missing.jsonl"), |_| true)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! Amazon S3 support.
+//!
+//! We use S3 to read `s3://` input files for page-based OCR engines, and as a
+//! scratch area for uploading local files to Textract.
+
+use aws_sdk_s3::{
+    Client,
+    operation::{
+        delete_object::DeleteObjectError, get_object::GetObjectError,
+        put_object::PutObjectError,
+    },
+    primitives::{Blob, ByteStream},
+};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt as _;
+use uuid::Uuid;
+
+use crate::{
+    aws::load_aws_config,
+    prelude::*,
+    retry::{
+        DEFAULT_JITTER, IsKnownTransient, retry_result_ok, retry_with_backoff,
+        try_potentially_transient,
+    },
+};
+
+/// Create an S3 client.
+pub async fn create_s3_client() -> Result<Client> {
+    let config = load_aws_config().await?;
+    Ok(Client::new(&config))
+}
+
+/// Is this path an `s3://` URI?
+pub fn is_s3_uri(path: &str) -> bool {
+    path.starts_with("s3://")
+}
+
+/// Parse an S3 URI into bucket and key.
+pub fn parse_s3_uri(uri: &str) -> Result<(String, String)> {
+    let uri = uri
+        .strip_prefix("s3://")
+        .ok_or_else(|| anyhow!("S3 URI must start with s3://"))?;
+    let parts: Vec<&str> = uri.splitn(2, '/').collect();
+    if parts.len() != 2 {
+        return Err(anyhow!("S3 URI must be in the format s3://bucket/key"));
+    }
+    Ok((parts[0].to_string(), parts[1].to_string()))
+}
+
+/// Download an S3 object to a temporary file, which will be deleted when
+/// dropped.
+#[instrument(level = "debug", skip(client))]
+pub async fn download_to_tempfile(client: &Client, uri: &str) -> Result<NamedTempFile> {
+    let (bucket, key) = parse_s3_uri(uri)?;
+    let (bucket, key) = (&bucket, &key);
+    let response = retry_with_backoff(DEFAULT_JITTER, move || async move {
+        retry_result_ok(try_potentially_transient!(
+            client.get_object().bucket(bucket).key(key).send().await
+        ))
+    })
+    .await
+    .into_result()
+    .with_context(|| format!("Failed to download {uri}"))?;
+
+    let tmp = NamedTempFile::new().context("Failed to create temporary file")?;
+    let mut file = tokio::fs::File::create(tmp.path()).await?;
+    let mut body = response.body.into_async_read();
+    tokio::io::copy(&mut body, &mut file)
+        .await
+        .with_context(|| format!("Failed to download {uri}"))?;
+    file.flush().await?;
+    Ok(tmp)
+}
+
+/// A scratch bucket, used to upload local files for services which need to
+/// read from S3.
+///
+/// We delete objects once we're done with them, but if we crash, they may be
+/// left behind. We recommend configuring an S3 lifecycle rule to expire
+/// objects under the scratch prefix after a day or so.
+#[derive(Clone, Debug)]
+pub struct ScratchBucket {
+    /// Our S3 client.
+    client: Client,
+
+    /// The bucket to upload to.
+    bucket: String,
+
+    /// A key prefix for uploaded objects. Either empty, or ending in `/`.
+    prefix: String,
+}
+
+impl ScratchBucket {
+    /// Create a scratch bucket from `bucket`, `bucket/prefix` or
+    /// `s3://bucket/prefix`.
+    pub async fn new(spec: &str) -> Result<Self> {
+        let spec = spec.strip_prefix("s3://").unwrap_or(spec);
+        let (bucket, prefix) = spec.split_once('/').unwrap_or((spec, ""));
+        if bucket.is_empty() {
+            return Err(anyhow!("Invalid scratch bucket: {spec:?}"));
+        }
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => String::new(),
+            prefix => format!("{prefix}/"),
+        };
+        Ok(Self {
+            client: create_s3_client().await?,
+            bucket: bucket.to_owned(),
+            prefix,
+        })
+    }
+
+    /// Upload a local file.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn upload_file(&self, path: &Path) -> Result<ScratchObject> {
+        let bytes = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("Failed to read {path:?}"))?;
+        self.upload_bytes(bytes).await
+    }
+
+    /// Upload an in-memory file.
+    #[instrument(level = "debug", skip_all, fields(len = bytes.len()))]
+    pub async fn upload_bytes(&self, bytes: Vec<u8>) -> Result<ScratchObject> {
+        let key = format!("{}{}", self.prefix, Uuid::new_v4());
+        let bytes = Blob::new(bytes);
+        let key_ref = &key;
+        retry_with_backoff(DEFAULT_JITTER, move || {
+            let bytes = bytes.clone();
+            async move {
+                retry_result_ok(try_potentially_transient!(
+                    self.client
+                        .put_object()
+                        .bucket(&self.bucket)
+                        .key(key_ref)
+                        .body(ByteStream::from(bytes))
+                        .send()
+                        .await
+                ))
+            }
+        })
+        .await
+        .into_result()
+        .with_context(|| format!("Failed to upload to s3://{}/{key}", self.bucket))?;
+        debug!(bucket = %self.bucket, %key, "Uploaded scratch object");
+        Ok(ScratchObject {
+            client: self.client.clone(),
+            bucket: self.bucket.clone(),
+            key,
+            deleted: false,
+        })
+    }
+}
+
+/// An object in a [`ScratchBucket`]. This will be deleted in the background
+/// when dropped, unless [`ScratchObject::delete`] has already been called.
+#[derive(Debug)]
+pub struct ScratchObject {
+    /// Our S3 client.
+    client: Client,
+
+    /// The object's bucket.
+    pub bucket: String,
+
+    /// The object's key.
+    pub key: String,
+
+    /// Have we already deleted this object?
+    deleted: bool,
+}
+
+impl ScratchObject {
+    /// Delete this object, waiting until the deletion is complete.
+    pub async fn delete(mut self) -> Result<()> {
+        self.deleted = true;
+        delete_object(&self.client, &self.bucket, &self.key).await
+    }
+}
+
+impl Drop for ScratchObject {
+    fn drop(&mut self) {
+        if self.deleted {
+            return;
+        }
+        let (client, bucket, key) =
+            (self.client.clone(), self.bucket.clone(), self.key.clone());
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                if let Err(err) = delete_object(&client, &bucket, &key).await {
+                    warn!("Failed to delete scratch object: {err:?}");
+                }
+            });
+        } else {
+            warn!(%bucket, %key, "Could not delete scratch object outside of runtime");
+        }
+    }
+}
+
+/// Delete an S3 object.
+async fn delete_object(client: &Client, bucket: &str, key: &str) -> Result<()> {
+    retry_with_backoff(DEFAULT_JITTER, move || async move {
+        retry_result_ok(try_potentially_transient!(
+            client.delete_object().bucket(bucket).key(key).send().await
+        ))
+    })
+    .await
+    .into_result()
+    .with_context(|| format!("Failed to delete s3://{bucket}/{key}"))?;
+    Ok(())
+}
+
+impl IsKnownTransient for GetObjectError {
+    fn is_known_transient(&self) -> bool {
+        false
+    }
+}
+
+impl IsKnownTransient for PutObjectError {
+    fn is_known_transient(&self) -> bool {
+        false
+    }
+}
+
+impl IsKnownTransient for DeleteObjectError {
+    fn is_known_transient(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_s3_uri() {
+        assert_eq!(
+            parse_s3_uri("s3://bucket/path/to/file.pdf").unwrap(),
+            ("bucket".to_owned(), "path/to/file.pdf".to_owned())
+        );
+        assert!(parse_s3_uri("bucket/file.pdf").is_err());
+        assert!(parse_s3_uri("s3://bucket").is_err());
+    }
+}
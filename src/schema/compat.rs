@@ -0,0 +1,784 @@
+//! Provider-specific schema rewriting and pre-flight checks.
+//!
+//! Providers which support structured output only accept a subset of JSON
+//! Schema, and reject everything else with an opaque 400 error, often for every
+//! record in a run. We first rewrite each response schema to fix the problems
+//! we can fix automatically, such as `$ref`s for providers which don't support
+//! them. We then check the result against the provider's published rules, so
+//! that we can say exactly which parts of the schema need to change.
+//!
+//! These rules change from time to time, and `--skip-schema-checks` turns the
+//! checks off. Our rewrites only affect the schema we send to the provider. We
+//! still validate responses against the original schema.
+
+use std::fmt;
+
+use serde_json::Map;
+
+use crate::{
+    drivers::DriverType, litellm::LiteLlmModel, prelude::*, tokens::ModelFamily,
+};
+
+/// The structured output providers whose quirks we know about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Provider {
+    /// OpenAI's structured outputs, with `strict: true`.
+    OpenAi,
+
+    /// Gemini's `responseJsonSchema`, via the Gemini API or Vertex AI.
+    Gemini,
+}
+
+impl Provider {
+    /// Look up the provider which will see our schema when calling `model`
+    /// via `driver`, using LiteLLM's `litellm_provider` if we have it. Returns
+    /// `None` if we don't know the provider. Bedrock and Anthropic send our
+    /// schema as a tool definition, which accepts any JSON Schema.
+    fn for_model(
+        driver: DriverType,
+        model: &str,
+        model_info: Option<&LiteLlmModel>,
+    ) -> Option<Self> {
+        match driver {
+            DriverType::Bedrock | DriverType::Echo => {
+                return None;
+            }
+            DriverType::Vertex => return Some(Provider::Gemini),
+            DriverType::OpenAI | DriverType::Native => {}
+        }
+        let provider = model_info.map(|info| info.model_info.litellm_provider.as_str());
+        match provider {
+            Some(p) if p == "gemini" || p.starts_with("vertex_ai") => {
+                return Some(Provider::Gemini);
+            }
+            Some("openai" | "azure") => return Some(Provider::OpenAi),
+            Some(_) => return None,
+            None => {}
+        }
+        match ModelFamily::for_model(model) {
+            ModelFamily::Gemini => Some(Provider::Gemini),
+            ModelFamily::OpenAi => Some(Provider::OpenAi),
+            ModelFamily::Anthropic | ModelFamily::Other => None,
+        }
+    }
+}
+
+/// Changes we make to a response schema before sending it to a provider.
+///
+/// We don't convert `integer` to `number`, because all the providers we know
+/// about accept `integer`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SchemaTransforms {
+    /// Remove the top-level `$schema`, which some providers reject.
+    strip_schema_keyword: bool,
+
+    /// Replace each local `$ref` with a copy of its definition, and remove
+    /// `definitions` and `$defs`.
+    inline_refs: bool,
+
+    /// Set `additionalProperties: false` on every object which doesn't specify
+    /// it, and make every property `required`.
+    close_objects: bool,
+}
+
+impl SchemaTransforms {
+    /// Look up the changes needed for `model` when called via `driver`.
+    pub fn for_model(
+        driver: DriverType,
+        model: &str,
+        model_info: Option<&LiteLlmModel>,
+    ) -> Self {
+        let provider = Provider::for_model(driver, model, model_info);
+        SchemaTransforms {
+            // `genai` passes our schema through as-is, and not all of its
+            // providers understand `$schema`.
+            strip_schema_keyword: driver == DriverType::Native
+                || provider == Some(Provider::Gemini),
+            // Older Gemini models reject `definitions`.
+            inline_refs: provider == Some(Provider::Gemini),
+            close_objects: provider == Some(Provider::OpenAi),
+        }
+    }
+
+    /// Return a copy of `schema` with our changes applied. This fails if a
+    /// `$ref` can't be inlined.
+    pub fn apply(&self, schema: &Value) -> Result<Value> {
+        let mut schema = if self.inline_refs {
+            inline_refs(schema)?
+        } else {
+            schema.clone()
+        };
+        if self.strip_schema_keyword
+            && let Some(obj) = schema.as_object_mut()
+        {
+            obj.shift_remove("$schema");
+        }
+        if self.close_objects {
+            close_objects(&mut schema);
+        }
+        Ok(schema)
+    }
+}
+
+/// Replace each local `$ref` in `root` with a copy of its definition, and
+/// remove `definitions` and `$defs`. Recursive definitions can't be inlined.
+fn inline_refs(root: &Value) -> Result<Value> {
+    let mut schema = root.clone();
+    if let Some(obj) = schema.as_object_mut() {
+        obj.shift_remove("definitions");
+        obj.shift_remove("$defs");
+    }
+    inline_node(&schema, root, &mut vec![])
+}
+
+/// Helper for [`inline_refs`]. `stack` holds the `$ref`s we're inside, so
+/// that we can detect recursion.
+fn inline_node(node: &Value, root: &Value, stack: &mut Vec<String>) -> Result<Value> {
+    match node {
+        Value::Object(obj) => {
+            let mut inlined = match obj.get("$ref").and_then(Value::as_str) {
+                Some(reference) => {
+                    if stack.iter().any(|seen| seen == reference) {
+                        return Err(anyhow!(
+                            "cannot inline recursive JSON Schema $ref {reference:?}"
+                        ));
+                    }
+                    let target = reference
+                        .strip_prefix('#')
+                        .and_then(|pointer| root.pointer(pointer))
+                        .ok_or_else(|| {
+                            anyhow!("cannot resolve JSON Schema $ref {reference:?}")
+                        })?;
+                    stack.push(reference.to_owned());
+                    let inlined = inline_node(target, root, stack)?;
+                    stack.pop();
+                    match inlined {
+                        Value::Object(inlined) => inlined,
+                        other => return Ok(other),
+                    }
+                }
+                None => Map::new(),
+            };
+            // Keep any keywords next to a `$ref`, like `description`.
+            for (key, value) in obj {
+                if key != "$ref" {
+                    inlined.insert(key.clone(), inline_node(value, root, stack)?);
+                }
+            }
+            Ok(Value::Object(inlined))
+        }
+        Value::Array(items) => items
+            .iter()
+            .map(|item| inline_node(item, root, stack))
+            .collect::<Result<Vec<_>>>()
+            .map(Value::Array),
+        other => Ok(other.clone()),
+    }
+}
+
+/// Set `additionalProperties: false` on every object in `schema` which
+/// doesn't specify it, and add any missing properties to `required`.
+fn close_objects(schema: &mut Value) {
+    let Some(obj) = schema.as_object_mut() else {
+        return;
+    };
+    if let Some(Value::Object(properties)) = obj.get_mut("properties") {
+        let names = properties.keys().cloned().collect::<Vec<_>>();
+        for property in properties.values_mut() {
+            close_objects(property);
+        }
+        obj.entry("additionalProperties")
+            .or_insert(Value::Bool(false));
+        let required = obj
+            .entry("required")
+            .or_insert_with(|| Value::Array(vec![]));
+        if let Value::Array(required) = required {
+            for name in names {
+                if !required.iter().any(|r| r.as_str() == Some(name.as_str())) {
+                    required.push(Value::String(name));
+                }
+            }
+        }
+    }
+    match obj.get_mut("items") {
+        Some(Value::Array(items)) => items.iter_mut().for_each(close_objects),
+        Some(items) => close_objects(items),
+        None => {}
+    }
+    for keyword in ["prefixItems", "anyOf", "oneOf", "allOf"] {
+        if let Some(Value::Array(schemas)) = obj.get_mut(keyword) {
+            schemas.iter_mut().for_each(close_objects);
+        }
+    }
+    for keyword in ["additionalProperties", "not"] {
+        if let Some(schema) = obj.get_mut(keyword) {
+            close_objects(schema);
+        }
+    }
+    for keyword in ["$defs", "definitions"] {
+        if let Some(Value::Object(defs)) = obj.get_mut(keyword) {
+            defs.values_mut().for_each(close_objects);
+        }
+    }
+}
+
+/// The structured output rules for a provider.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchemaRules {
+    /// A human-readable name for the provider.
+    pub provider: &'static str,
+
+    /// JSON Schema keywords which the provider rejects or ignores.
+    unsupported_keywords: &'static [&'static str],
+
+    /// Must the top level of the schema be an object?
+    root_must_be_object: bool,
+
+    /// Must every object set `additionalProperties: false`, and list all its
+    /// properties as `required`?
+    closed_objects: bool,
+
+    /// The deepest allowed nesting of objects.
+    max_depth: Option<usize>,
+
+    /// The most object properties allowed, in total.
+    max_properties: Option<usize>,
+
+    /// The most enum values allowed, in total.
+    max_enum_values: Option<usize>,
+
+    /// Enums with more than this many values have a limit on their length.
+    large_enum_values: Option<usize>,
+
+    /// The most characters allowed in all the values of a large enum.
+    max_large_enum_chars: Option<usize>,
+
+    /// The most characters allowed in all property names, definition names,
+    /// and enum and const values.
+    max_chars: Option<usize>,
+}
+
+/// OpenAI's structured outputs, with `strict: true`.
+const OPENAI: SchemaRules = SchemaRules {
+    provider: "OpenAI",
+    unsupported_keywords: &[
+        "allOf",
+        "not",
+        "if",
+        "then",
+        "else",
+        "dependentRequired",
+        "dependentSchemas",
+        "patternProperties",
+        "unevaluatedProperties",
+        "propertyNames",
+        "minProperties",
+        "maxProperties",
+        "unevaluatedItems",
+        "contains",
+        "minContains",
+        "maxContains",
+        "uniqueItems",
+        "minLength",
+        "maxLength",
+    ],
+    root_must_be_object: true,
+    closed_objects: true,
+    max_depth: Some(10),
+    max_properties: Some(5_000),
+    max_enum_values: Some(1_000),
+    large_enum_values: Some(250),
+    max_large_enum_chars: Some(15_000),
+    max_chars: Some(120_000),
+};
+
+/// Gemini's `responseJsonSchema`, via the Gemini API or Vertex AI.
+const GEMINI: SchemaRules = SchemaRules {
+    provider: "Gemini",
+    unsupported_keywords: &[
+        "allOf",
+        "not",
+        "if",
+        "then",
+        "else",
+        "dependentRequired",
+        "dependentSchemas",
+        "patternProperties",
+        "unevaluatedProperties",
+        "propertyNames",
+        "unevaluatedItems",
+        "contains",
+        "uniqueItems",
+    ],
+    root_must_be_object: false,
+    closed_objects: false,
+    max_depth: None,
+    max_properties: None,
+    max_enum_values: None,
+    large_enum_values: None,
+    max_large_enum_chars: None,
+    max_chars: None,
+};
+
+impl SchemaRules {
+    /// Look up the rules which apply to `model` when called via `driver`.
+    /// Returns `None` if we don't know of any rules.
+    pub fn for_model(
+        driver: DriverType,
+        model: &str,
+        model_info: Option<&LiteLlmModel>,
+    ) -> Option<Self> {
+        Provider::for_model(driver, model, model_info).map(|provider| match provider {
+            Provider::OpenAi => OPENAI,
+            Provider::Gemini => GEMINI,
+        })
+    }
+
+    /// Check a JSON Schema against our rules, reporting every problem we find.
+    pub fn check(&self, schema: &Value) -> Result<(), SchemaIncompatible> {
+        let mut checker = Checker {
+            rules: self,
+            issues: vec![],
+            properties: 0,
+            enum_values: 0,
+            chars: 0,
+        };
+        if self.root_must_be_object && schema.get("type") != Some(&json!("object")) {
+            checker.issue(
+                "",
+                format!(
+                    "the top level must have `\"type\": \"object\"`, not {}",
+                    schema.get("type").unwrap_or(&Value::Null)
+                ),
+            );
+        }
+        checker.check_node(schema, "", 0);
+        checker.check_totals();
+        if checker.issues.is_empty() {
+            Ok(())
+        } else {
+            Err(SchemaIncompatible {
+                provider: self.provider,
+                issues: checker.issues,
+            })
+        }
+    }
+}
+
+/// Walks a schema, collecting problems.
+struct Checker<'a> {
+    /// The rules we're checking.
+    rules: &'a SchemaRules,
+
+    /// Problems found so far, as `(path, message)`.
+    issues: Vec<(String, String)>,
+
+    /// Object properties seen so far.
+    properties: usize,
+
+    /// Enum values seen so far.
+    enum_values: usize,
+
+    /// Characters in names and values seen so far.
+    chars: usize,
+}
+
+impl Checker<'_> {
+    /// Record a problem at `path`.
+    fn issue(&mut self, path: &str, message: String) {
+        self.issues.push((path.to_owned(), message));
+    }
+
+    /// Check `node`, which is `depth` objects deep, and everything below it.
+    /// We don't follow `$ref`s, but we do check `$defs` and `definitions`.
+    fn check_node(&mut self, node: &Value, path: &str, depth: usize) {
+        let Some(obj) = node.as_object() else {
+            return;
+        };
+        let rules = self.rules;
+        for key in obj.keys() {
+            if rules.unsupported_keywords.contains(&key.as_str()) {
+                self.issue(
+                    path,
+                    format!("`{key}` is not supported by {}", rules.provider),
+                );
+            }
+        }
+
+        // Check objects.
+        let properties = obj.get("properties").and_then(|p| p.as_object());
+        let depth = if properties.is_some() {
+            depth + 1
+        } else {
+            depth
+        };
+        if let Some(max_depth) = rules.max_depth
+            && depth > max_depth
+        {
+            self.issue(
+                path,
+                format!(
+                    "objects are nested {depth} deep, but {} allows at most {max_depth}",
+                    rules.provider
+                ),
+            );
+            return;
+        }
+        if let Some(properties) = properties {
+            self.properties += properties.len();
+            self.chars += properties.keys().map(|name| name.len()).sum::<usize>();
+            if rules.closed_objects {
+                if obj.get("additionalProperties") != Some(&Value::Bool(false)) {
+                    self.issue(
+                        path,
+                        format!(
+                            "{} requires `\"additionalProperties\": false`",
+                            rules.provider
+                        ),
+                    );
+                }
+                let required = obj
+                    .get("required")
+                    .and_then(|r| r.as_array())
+                    .map(|r| r.iter().filter_map(|name| name.as_str()).collect())
+                    .unwrap_or_default();
+                let missing = properties
+                    .keys()
+                    .filter(|name| !required.contains(&name.as_str()))
+                    .map(|name| format!("`{name}`"))
+                    .collect::<Vec<_>>();
+                if !missing.is_empty() {
+                    self.issue(
+                        path,
+                        format!(
+                            "{} requires every property to be `required`, but {} {} not",
+                            rules.provider,
+                            missing.join(", "),
+                            if missing.len() == 1 { "is" } else { "are" }
+                        ),
+                    );
+                }
+            }
+            for (name, property) in properties {
+                self.check_node(property, &join_path(path, name), depth);
+            }
+        }
+
+        // Check enums and constants.
+        if let Some(values) = obj.get("enum").and_then(|e| e.as_array()) {
+            self.enum_values += values.len();
+            let chars = values.iter().map(value_chars).sum::<usize>();
+            self.chars += chars;
+            if let (Some(large), Some(max_chars)) =
+                (rules.large_enum_values, rules.max_large_enum_chars)
+                && values.len() > large
+                && chars > max_chars
+            {
+                self.issue(
+                    path,
+                    format!(
+                        "enum has {} values with {chars} characters, but {} allows at \
+                         most {max_chars} characters in an enum with over {large} values",
+                        values.len(),
+                        rules.provider
+                    ),
+                );
+            }
+        }
+        if let Some(value) = obj.get("const") {
+            self.chars += value_chars(value);
+        }
+
+        // Check everything else which contains schemas.
+        match obj.get("items") {
+            Some(Value::Array(items)) => {
+                for (i, item) in items.iter().enumerate() {
+                    self.check_node(item, &format!("{path}[{i}]"), depth);
+                }
+            }
+            Some(items) => self.check_node(items, &format!("{path}[]"), depth),
+            None => {}
+        }
+        for keyword in ["prefixItems", "anyOf", "oneOf", "allOf"] {
+            if let Some(schemas) = obj.get(keyword).and_then(|s| s.as_array()) {
+                for (i, schema) in schemas.iter().enumerate() {
+                    let path = join_path(path, &format!("{keyword}[{i}]"));
+                    self.check_node(schema, &path, depth);
+                }
+            }
+        }
+        for keyword in ["additionalProperties", "not"] {
+            if let Some(schema) = obj.get(keyword) {
+                self.check_node(schema, &join_path(path, keyword), depth);
+            }
+        }
+        for keyword in ["$defs", "definitions"] {
+            if let Some(defs) = obj.get(keyword).and_then(|d| d.as_object()) {
+                for (name, schema) in defs {
+                    self.chars += name.len();
+                    self.check_node(schema, &format!("{keyword}.{name}"), 0);
+                }
+            }
+        }
+    }
+
+    /// Check our totals for the whole schema.
+    fn check_totals(&mut self) {
+        let rules = self.rules;
+        let totals = [
+            ("object properties", self.properties, rules.max_properties),
+            ("enum values", self.enum_values, rules.max_enum_values),
+            (
+                "characters in names and values",
+                self.chars,
+                rules.max_chars,
+            ),
+        ];
+        for (what, count, max) in totals {
+            if let Some(max) = max
+                && count > max
+            {
+                self.issue(
+                    "",
+                    format!(
+                        "schema has {count} {what}, but {} allows at most {max}",
+                        rules.provider
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Add a property name to a path.
+fn join_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{path}.{name}")
+    }
+}
+
+/// Count the characters in an enum or const value.
+fn value_chars(value: &Value) -> usize {
+    match value {
+        Value::String(s) => s.len(),
+        other => other.to_string().len(),
+    }
+}
+
+/// A response schema which a provider won't accept.
+#[derive(Debug)]
+pub struct SchemaIncompatible {
+    /// The provider.
+    provider: &'static str,
+
+    /// Problems, as `(path, message)`. An empty path means the whole schema.
+    issues: Vec<(String, String)>,
+}
+
+impl fmt::Display for SchemaIncompatible {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "schema_incompatible: {} rejects this schema:",
+            self.provider
+        )?;
+        for (path, message) in &self.issues {
+            if path.is_empty() {
+                write!(f, "\n- {message}")?;
+            } else {
+                write!(f, "\n- {path}: {message}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SchemaIncompatible {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_are_chosen_by_driver_then_model() {
+        let rules = |driver, model| {
+            SchemaRules::for_model(driver, model, None).map(|rules| rules.provider)
+        };
+        assert_eq!(rules(DriverType::OpenAI, "gpt-4o-mini"), Some("OpenAI"));
+        assert_eq!(
+            rules(DriverType::Native, "gemini-2.5-flash"),
+            Some("Gemini")
+        );
+        assert_eq!(rules(DriverType::Vertex, "gemini-2.5-pro"), Some("Gemini"));
+        assert_eq!(rules(DriverType::Native, "claude-sonnet-4"), None);
+        assert_eq!(rules(DriverType::Bedrock, "anthropic.claude-3"), None);
+        assert_eq!(rules(DriverType::OpenAI, "llama3"), None);
+    }
+
+    #[test]
+    fn schemas_are_rewritten_for_each_provider() {
+        let schema = json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "properties": {
+                "party": { "$ref": "#/definitions/Party", "description": "Buyer" },
+            },
+            "definitions": {
+                "Party": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "role": { "type": "string" },
+                    },
+                    "required": ["name"],
+                },
+            },
+        });
+        let transforms = |driver, model| {
+            SchemaTransforms::for_model(driver, model, None)
+                .apply(&schema)
+                .unwrap()
+        };
+
+        let party = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "role": { "type": "string" },
+            },
+            "required": ["name"],
+            "description": "Buyer",
+        });
+        assert_eq!(
+            transforms(DriverType::Vertex, "gemini-2.5-pro"),
+            json!({
+                "type": "object",
+                "properties": { "party": party },
+            })
+        );
+
+        let openai = transforms(DriverType::OpenAI, "gpt-4o");
+        assert_eq!(openai["$schema"], schema["$schema"]);
+        assert_eq!(openai["additionalProperties"], false);
+        assert_eq!(openai["required"], json!(["party"]));
+        let party = &openai["definitions"]["Party"];
+        assert_eq!(party["additionalProperties"], false);
+        assert_eq!(party["required"], json!(["name", "role"]));
+        assert!(OPENAI.check(&openai).is_ok());
+
+        let native = transforms(DriverType::Native, "claude-sonnet-4");
+        assert!(native.get("$schema").is_none());
+        assert_eq!(native["definitions"], schema["definitions"]);
+        assert_eq!(
+            transforms(DriverType::Bedrock, "anthropic.claude-3"),
+            schema
+        );
+    }
+
+    #[test]
+    fn recursive_refs_cannot_be_inlined() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "node": { "$ref": "#/$defs/Node" } },
+            "$defs": {
+                "Node": {
+                    "type": "object",
+                    "properties": { "child": { "$ref": "#/$defs/Node" } },
+                },
+            },
+        });
+        let transforms = SchemaTransforms::for_model(DriverType::Vertex, "gemini", None);
+        assert!(transforms.apply(&schema).is_err());
+        let missing = json!({ "$ref": "#/$defs/Missing" });
+        assert!(transforms.apply(&missing).is_err());
+    }
+
+    #[test]
+    fn compatible_schemas_pass() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "pattern": "^[A-Z]" },
+                "tags": { "type": "array", "items": { "type": "string" } },
+            },
+            "required": ["name", "tags"],
+            "additionalProperties": false,
+        });
+        assert!(OPENAI.check(&schema).is_ok());
+        assert!(GEMINI.check(&schema).is_ok());
+    }
+
+    #[test]
+    fn every_incompatibility_is_reported() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "minLength": 1 },
+                "parties": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": { "role": { "type": "string" } },
+                        "additionalProperties": false,
+                    },
+                    "uniqueItems": true,
+                },
+            },
+            "required": ["name", "parties"],
+        });
+        assert_eq!(
+            OPENAI.check(&schema).unwrap_err().to_string(),
+            "schema_incompatible: OpenAI rejects this schema:\n\
+             - OpenAI requires `\"additionalProperties\": false`\n\
+             - name: `minLength` is not supported by OpenAI\n\
+             - parties: `uniqueItems` is not supported by OpenAI\n\
+             - parties[]: OpenAI requires every property to be `required`, but \
+             `role` is not"
+        );
+        assert_eq!(
+            GEMINI.check(&schema).unwrap_err().to_string(),
+            "schema_incompatible: Gemini rejects this schema:\n\
+             - parties: `uniqueItems` is not supported by Gemini"
+        );
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let mut schema = json!({ "type": "string" });
+        for _ in 0..11 {
+            schema = json!({
+                "type": "object",
+                "properties": { "inner": schema },
+                "required": ["inner"],
+                "additionalProperties": false,
+            });
+        }
+        let err = OPENAI.check(&schema).unwrap_err().to_string();
+        assert!(
+            err.ends_with(
+                "inner.inner.inner.inner.inner.inner.inner.inner.inner.inner: objects \
+                 are nested 11 deep, but OpenAI allows at most 10"
+            ),
+            "{err}"
+        );
+
+        let labels = (0..300).map(|i| format!("{i:060}")).collect::<Vec<_>>();
+        let schema = json!({
+            "type": "object",
+            "properties": { "label": { "type": "string", "enum": labels } },
+            "required": ["label"],
+            "additionalProperties": false,
+        });
+        let err = OPENAI.check(&schema).unwrap_err().to_string();
+        assert!(err.contains("label: enum has 300 values"), "{err}");
+
+        let schema = json!({ "type": "array", "items": { "type": "string" } });
+        let err = OPENAI.check(&schema).unwrap_err().to_string();
+        assert!(
+            err.contains("the top level must have `\"type\": \"object\"`, not \"array\""),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,975 @@
+//! Schema support.
+
+use std::borrow::Cow;
+
+use indexmap::IndexMap;
+use schemars::{JsonSchema, SchemaGenerator, r#gen::SchemaSettings};
+use serde_json::Map;
+use toml_span::{
+    DeserError,
+    de_helpers::{TableHelper, expected},
+    value::ValueInner,
+};
+
+use crate::{
+    async_utils::io::read_json_or_toml_as_json_value,
+    prelude::*,
+    toml_utils::{JsonValue, custom_deser_error, unwrap_json_values},
+};
+
+pub mod compat;
+
+/// Get the title of a JSON Schema part, or `"ResponseFormat"` if not present.
+pub fn get_schema_title(schema: &Value) -> String {
+    schema
+        .get("title")
+        .and_then(|v| v.as_str())
+        .unwrap_or("ResponseFormat")
+        .to_owned()
+}
+
+/// Either an external or an internal schema.
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq)]
+#[serde(untagged, deny_unknown_fields, rename_all = "snake_case")]
+pub enum Schema {
+    /// A classification schema, like `classify = ["contract", "invoice"]`,
+    /// which asks for a single `label` from a fixed list.
+    Classify(ClassifySchema),
+
+    /// An internal schema (one stored in the prompt file), using a very
+    /// simplified version of JSON Schema format. If this is insufficient for
+    /// your needs, consider using an external schema.
+    Internal(InternalSchema),
+
+    /// An external schema, provided as a file path.
+    External(ExternalSchema),
+
+    /// A schema provided as a [`Value`]. This is only used by Rust code
+    /// that already has the schema in memory, and it cannot be deserialized.
+    #[serde(skip_deserializing)]
+    JsonValue(Value),
+}
+
+impl Schema {
+    /// Create a [`Schema`] from a Rust type using [`schemars`].
+    pub fn from_type<T>() -> Self
+    where
+        T: JsonSchema,
+    {
+        // Gemini 2.0 Flash doesn't like `definitions` in the schema,
+        // so inline all subschemas.
+        let mut settings = SchemaSettings::draft07();
+        settings.inline_subschemas = true;
+        let generator = SchemaGenerator::new(settings);
+
+        let schema = generator.into_root_schema_for::<T>();
+        let json =
+            serde_json::to_value(schema).expect("failed to convert schema to JSON");
+        Self::JsonValue(json)
+    }
+
+    /// The schema for a text response, which is just a string.
+    pub fn text() -> Self {
+        Self::JsonValue(json!({ "type": "string" }))
+    }
+
+    /// The labels we classify records into, if this is a classification
+    /// schema.
+    pub fn classify_labels(&self) -> Option<&[String]> {
+        match self {
+            Schema::Classify(schema) => Some(&schema.classify),
+            _ => None,
+        }
+    }
+
+    /// Convert to a JSON Schema.
+    pub async fn to_json_schema(&self) -> Result<Value> {
+        match self {
+            Schema::Classify(schema) => {
+                let mut schema_json = schema.to_internal_schema()?.to_json_schema()?;
+                schema_json["$schema"] =
+                    Value::String("http://json-schema.org/draft-07/schema#".to_string());
+                Ok(schema_json)
+            }
+            Schema::Internal(schema) => {
+                let mut schema_json = schema.to_json_schema()?;
+                schema_json["$schema"] =
+                    Value::String("http://json-schema.org/draft-07/schema#".to_string());
+                Ok(schema_json)
+            }
+            Schema::External(schema) => schema.to_json_schema().await,
+            Schema::JsonValue(json) => Ok(json.clone()),
+        }
+    }
+}
+
+// We implement [`toml_span::Deserialize`] because it provides much better error messages
+// than [`serde`].
+impl<'de> toml_span::Deserialize<'de> for Schema {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        if value.has_key("classify") {
+            let classify =
+                <ClassifySchema as toml_span::Deserialize>::deserialize(value)?;
+            Ok(Schema::Classify(classify))
+        } else if value.has_key("path") {
+            let external =
+                <ExternalSchema as toml_span::Deserialize>::deserialize(value)?;
+            Ok(Schema::External(external))
+        } else {
+            let internal =
+                <InternalSchema as toml_span::Deserialize>::deserialize(value)?;
+            Ok(Schema::Internal(internal))
+        }
+    }
+}
+
+/// A classification schema, which asks for a single `label`.
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub struct ClassifySchema {
+    /// The allowed labels.
+    classify: Vec<String>,
+
+    /// A description of the label, to help the model choose.
+    #[serde(default)]
+    description: Option<String>,
+}
+
+impl ClassifySchema {
+    /// Expand into the equivalent [`InternalSchema`].
+    fn to_internal_schema(&self) -> Result<InternalSchema> {
+        if self.classify.is_empty() {
+            return Err(anyhow!("classify must list at least one label"));
+        }
+        for (idx, label) in self.classify.iter().enumerate() {
+            if self.classify[..idx].contains(label) {
+                return Err(anyhow!("classify lists {label:?} more than once"));
+            }
+        }
+        let label = InternalSchema {
+            description: self.description.clone().unwrap_or_else(|| {
+                "The label which best describes the input.".to_owned()
+            }),
+            details: InternalSchemaDetails::Scalar {
+                r#type: ScalarType::String,
+                r#enum: Some(self.classify.iter().cloned().map(Value::String).collect()),
+            },
+        };
+        Ok(InternalSchema {
+            description: "A classification of the input.".to_owned(),
+            details: InternalSchemaDetails::Object {
+                properties: IndexMap::from([("label".to_owned(), label)]),
+                title: None,
+            },
+        })
+    }
+}
+
+impl<'de> toml_span::Deserialize<'de> for ClassifySchema {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        let mut helper = TableHelper::new(value)?;
+        let classify = helper.required::<Vec<String>>("classify")?;
+        let description = helper.optional::<String>("description");
+        helper.finalize(None)?;
+        Ok(ClassifySchema {
+            classify,
+            description,
+        })
+    }
+}
+
+/// An external schema, provided as a file path.
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub struct ExternalSchema {
+    /// The path to the schema.
+    path: PathBuf,
+}
+
+impl ExternalSchema {
+    /// Convert to a JSON Schema.
+    pub async fn to_json_schema(&self) -> Result<Value> {
+        read_json_or_toml_as_json_value(&self.path).await
+    }
+}
+
+impl<'de> toml_span::Deserialize<'de> for ExternalSchema {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        let mut helper = TableHelper::new(value)?;
+        let spanned_path = helper.required_s::<String>("path")?;
+        let path = PathBuf::from(spanned_path.value);
+        if !path.exists() {
+            let err_kind = toml_span::ErrorKind::Custom(Cow::Owned(format!(
+                "File not found: {}",
+                path.display()
+            )));
+            let err = toml_span::Error::from((err_kind, spanned_path.span));
+            return Err(DeserError::from(err));
+        }
+        helper.finalize(None)?;
+        Ok(ExternalSchema { path })
+    }
+}
+
+/// A simplified version of JSON Schema, used for validation.
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct InternalSchema {
+    /// A description of this value.
+    pub description: String,
+    /// The details of this schema.
+    #[serde(flatten)]
+    pub details: InternalSchemaDetails,
+}
+
+impl<'de> toml_span::Deserialize<'de> for InternalSchema {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        if value.has_key("items") {
+            let mut helper = TableHelper::new(value)?;
+            let description = helper.required::<String>("description")?;
+            let items = helper.required::<InternalSchema>("items")?;
+            helper.finalize(None)?;
+            Ok(InternalSchema {
+                description,
+                details: InternalSchemaDetails::Array {
+                    items: Box::new(items),
+                },
+            })
+        } else if value.has_key("properties") {
+            // Recursively deserialize `properties`, which is a bit clunky.
+            let value_inner = value.take();
+            let ValueInner::Table(mut value_table) = value_inner else {
+                return Err(expected("a table", value_inner, value.span).into());
+            };
+            let toml_properties = value_table
+                .remove("properties")
+                .expect("properties should be present")
+                .take();
+            let ValueInner::Table(toml_properties) = toml_properties else {
+                return Err(expected("a table", toml_properties, value.span).into());
+            };
+            // `toml_span` sorts keys alphabetically, so restore the order from
+            // the prompt file. We use this to order output fields.
+            let mut toml_properties = toml_properties.into_iter().collect::<Vec<_>>();
+            toml_properties.sort_by_key(|(k, _)| k.span.start);
+            let mut properties = IndexMap::new();
+            for (k, mut v) in toml_properties {
+                properties.insert(
+                    k.name.into_owned(),
+                    <InternalSchema as toml_span::Deserialize>::deserialize(&mut v)?,
+                );
+            }
+            // Put back the `value_table` without the `"properties"` key.
+            value.set(ValueInner::Table(value_table));
+
+            // Handle our other fields normally.
+            let mut helper = TableHelper::new(value)?;
+            let description = helper.required::<String>("description")?;
+            let title = helper.optional::<String>("title");
+            helper.finalize(None)?;
+            Ok(InternalSchema {
+                description,
+                details: InternalSchemaDetails::Object { properties, title },
+            })
+        } else {
+            let mut helper = TableHelper::new(value)?;
+            let description = helper.required::<String>("description")?;
+            let r#type = helper.optional::<ScalarType>("type");
+            let r#enum = helper.optional::<Vec<JsonValue>>("enum");
+            helper.finalize(None)?;
+            Ok(InternalSchema {
+                description,
+                details: InternalSchemaDetails::Scalar {
+                    r#type: r#type.unwrap_or_default(),
+                    r#enum: r#enum.map(unwrap_json_values),
+                },
+            })
+        }
+    }
+}
+
+/// The details of a schema.
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq)]
+#[serde(untagged, deny_unknown_fields, rename_all = "snake_case")]
+pub enum InternalSchemaDetails {
+    /// An array.
+    Array {
+        /// The items in the array.
+        items: Box<InternalSchema>,
+    },
+    /// A JSON object.
+    ///
+    /// All fields will be automatically marked as required, and
+    /// `additionalProperties` will be set to `false`.
+    Object {
+        /// The properties of the object, in the order they were declared.
+        properties: IndexMap<String, InternalSchema>,
+
+        /// The title of this object.
+        #[serde(default)]
+        title: Option<String>,
+    },
+    Scalar {
+        /// The type of this scalar.
+        #[serde(default)]
+        r#type: ScalarType,
+
+        /// Allowed enum values.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        r#enum: Option<Vec<Value>>,
+    },
+}
+
+/// Basic types we support.
+#[derive(Debug, Default, Clone, Deserialize, JsonSchema, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub enum ScalarType {
+    /// A string.
+    #[default]
+    String,
+
+    /// A number.
+    Number,
+
+    /// A boolean.
+    Boolean,
+}
+
+impl<'de> toml_span::Deserialize<'de> for ScalarType {
+    fn deserialize(value: &mut toml_span::Value<'de>) -> Result<Self, DeserError> {
+        let r#type = value.as_str().ok_or_else(|| {
+            custom_deser_error(value.span, "Expected a string for type")
+        })?;
+        match r#type {
+            "string" => Ok(ScalarType::String),
+            "number" => Ok(ScalarType::Number),
+            "boolean" => Ok(ScalarType::Boolean),
+            _ => Err(custom_deser_error(
+                value.span,
+                format!("Unsupported JSON Schema scalar type: {type}"),
+            )),
+        }
+    }
+}
+
+/// Convert to a JSON Schema.
+pub trait ToJsonSchema {
+    /// Convert this schema to a JSON Schema.
+    fn to_json_schema(&self) -> Result<Value>;
+}
+
+impl ToJsonSchema for InternalSchema {
+    fn to_json_schema(&self) -> Result<Value> {
+        let mut schema = match &self.details {
+            InternalSchemaDetails::Array { items } => {
+                json!({
+                    "type": "array",
+                    "items": items.to_json_schema()?,
+                })
+            }
+            InternalSchemaDetails::Object { title, properties } => {
+                let mut schema = json!({
+                    "type": "object",
+                    "properties": properties.to_json_schema()?,
+                    // OpenAI requires `additionalProperties` to be false.
+                    "additionalProperties": false,
+                    // OpenAI requires all properties to be required.
+                    "required": properties.keys().cloned().collect::<Vec<_>>(),
+                });
+                if let Some(title) = title {
+                    schema["title"] = Value::String(title.clone());
+                }
+                schema
+            }
+            InternalSchemaDetails::Scalar { r#type, r#enum } => {
+                let mut schema = json!({
+                    "type": r#type.to_json_schema()?,
+                });
+                if let Some(enum_values) = r#enum {
+                    schema["enum"] = Value::Array(enum_values.clone());
+                }
+                schema
+            }
+        };
+        schema["description"] = Value::String(self.description.clone());
+        Ok(schema)
+    }
+}
+
+impl ToJsonSchema for IndexMap<String, InternalSchema> {
+    fn to_json_schema(&self) -> Result<Value> {
+        let mut properties = Map::new();
+        for (key, value) in self {
+            properties.insert(key.clone(), value.to_json_schema()?);
+        }
+        Ok(Value::Object(properties))
+    }
+}
+
+impl ToJsonSchema for ScalarType {
+    fn to_json_schema(&self) -> Result<Value> {
+        let r#type = match self {
+            ScalarType::String => "string",
+            ScalarType::Number => "number",
+            ScalarType::Boolean => "boolean",
+        };
+        Ok(Value::String(r#type.to_string()))
+    }
+}
+
+/// Reorder the object keys in `value` to match the order of `properties` in
+/// `schema`, recursing into nested objects and arrays. Keys which aren't in the
+/// schema keep their relative order, after the keys which are.
+///
+/// LLMs don't always return fields in schema order, and downstream diff tools
+/// want a stable order.
+pub fn order_like_schema(value: &mut Value, schema: &Value) {
+    match value {
+        Value::Object(obj) => {
+            let Some(properties) = schema.get("properties").and_then(|p| p.as_object())
+            else {
+                return;
+            };
+            let mut ordered = Map::new();
+            for (key, property_schema) in properties {
+                if let Some(mut field) = obj.shift_remove(key) {
+                    order_like_schema(&mut field, property_schema);
+                    ordered.insert(key.to_owned(), field);
+                }
+            }
+            ordered.append(obj);
+            *obj = ordered;
+        }
+        Value::Array(items) => {
+            if let Some(items_schema) = schema.get("items") {
+                for item in items {
+                    order_like_schema(item, items_schema);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// An extra field which we can add after each leaf field of a response
+/// schema, asking the model to tell us more about that field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Companion {
+    /// `{field}_confidence`, a number from 0 to 1, for `field_confidence`.
+    Confidence,
+
+    /// `{field}_quote`, a quote from the source supporting the field, for
+    /// `cite_source`.
+    Quote,
+}
+
+impl Companion {
+    /// The suffix which turns a field name into the name of its companion.
+    fn suffix(self) -> &'static str {
+        match self {
+            Companion::Confidence => "_confidence",
+            Companion::Quote => "_quote",
+        }
+    }
+
+    /// The schema for the companion of `name`.
+    fn schema(self, name: &str) -> Value {
+        match self {
+            Companion::Confidence => json!({
+                "type": "number",
+                "minimum": 0,
+                "maximum": 1,
+                "description": format!(
+                    "How confident you are in {name}, from 0 (a guess) to 1 (certain)."
+                ),
+            }),
+            Companion::Quote => json!({
+                "type": "string",
+                "description": format!(
+                    "An exact quote from the source text which supports {name}, or \
+                     \"\" if the source doesn't mention it."
+                ),
+            }),
+        }
+    }
+}
+
+/// Add each of `companions` after each leaf field in `schema`. Scalars and
+/// arrays of scalars are leaves. We recurse into objects, including objects in
+/// arrays, but not into `definitions`.
+pub fn add_companion_fields(schema: &mut Value, companions: &[Companion]) {
+    if let Some(items) = schema.get_mut("items") {
+        add_companion_fields(items, companions);
+    }
+    let Some(properties) = schema
+        .get_mut("properties")
+        .and_then(|properties| properties.as_object_mut())
+    else {
+        return;
+    };
+    for property in properties.values_mut() {
+        add_companion_fields(property, companions);
+    }
+    // Don't clobber any fields which are already in the schema.
+    let existing = properties.keys().cloned().collect::<Vec<_>>();
+    let leaves = properties
+        .iter()
+        .filter(|(_, property)| {
+            property.get("properties").is_none()
+                && property
+                    .get("items")
+                    .is_none_or(|items| items.get("properties").is_none())
+        })
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    let mut added = vec![];
+    for (name, property) in std::mem::take(properties) {
+        let companion_fields = companions
+            .iter()
+            .map(|companion| (format!("{name}{}", companion.suffix()), companion))
+            .filter(|(companion_name, _)| {
+                leaves.contains(&name) && !existing.contains(companion_name)
+            })
+            .map(|(companion_name, companion)| (companion_name, companion.schema(&name)))
+            .collect::<Vec<_>>();
+        properties.insert(name, property);
+        for (companion_name, companion_schema) in companion_fields {
+            properties.insert(companion_name.clone(), companion_schema);
+            added.push(Value::String(companion_name));
+        }
+    }
+    // OpenAI requires all properties to be required.
+    match schema.get_mut("required") {
+        Some(Value::Array(required)) => required.extend(added),
+        _ => schema["required"] = Value::Array(added),
+    }
+}
+
+/// Find the `companion` fields added by [`add_companion_fields`] in
+/// `response`, returning the dotted path of the field each one describes,
+/// the value of that field, and the value of the companion.
+pub fn companion_fields<'a>(
+    response: &'a Value,
+    companion: Companion,
+) -> Vec<(String, &'a Value, &'a Value)> {
+    let mut found = vec![];
+    find_companion_fields(response, companion.suffix(), "", &mut found);
+    found
+}
+
+/// Helper for [`companion_fields`].
+fn find_companion_fields<'a>(
+    value: &'a Value,
+    suffix: &str,
+    prefix: &str,
+    found: &mut Vec<(String, &'a Value, &'a Value)>,
+) {
+    match value {
+        Value::Object(obj) => {
+            for (key, field) in obj {
+                if let Some(name) = key.strip_suffix(suffix)
+                    && let Some(described) = obj.get(name)
+                {
+                    found.push((format!("{prefix}{name}"), described, field));
+                } else {
+                    find_companion_fields(
+                        field,
+                        suffix,
+                        &format!("{prefix}{key}."),
+                        found,
+                    );
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                find_companion_fields(item, suffix, prefix, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Find the fields whose confidence is below `min`, returning their dotted
+/// paths and confidence.
+pub fn low_confidence_fields(response: &Value, min: f64) -> Vec<(String, f64)> {
+    companion_fields(response, Companion::Confidence)
+        .into_iter()
+        .filter_map(|(path, _, confidence)| {
+            let confidence = confidence.as_f64()?;
+            (confidence < min).then_some((path, confidence))
+        })
+        .collect()
+}
+
+/// Find the fields whose quotes don't appear in `source`, returning their
+/// dotted paths and quotes. We ignore case and differences in whitespace.
+/// Empty quotes are allowed, because not every field comes from the source.
+pub fn unsupported_quotes(response: &Value, source: &str) -> Vec<(String, String)> {
+    let normalize = |text: &str| {
+        text.split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase()
+    };
+    let source = normalize(source);
+    companion_fields(response, Companion::Quote)
+        .into_iter()
+        .filter_map(|(path, _, quote)| {
+            let quote = quote.as_str()?;
+            let normalized = normalize(quote);
+            (!normalized.is_empty() && !source.contains(&normalized))
+                .then(|| (path, quote.to_owned()))
+        })
+        .collect()
+}
+
+/// Prepare `schema` to be embedded inside `root`, moving any `definitions` or
+/// `$defs` into the root's `definitions` as `{prefix}{name}` and rewriting
+/// references to match. Returns the schema to embed.
+pub fn hoist_definitions(root: &mut Value, mut schema: Value, prefix: &str) -> Value {
+    let mut definitions = Map::new();
+    if let Value::Object(obj) = &mut schema {
+        obj.shift_remove("$schema");
+        for key in ["definitions", "$defs"] {
+            if let Some(Value::Object(defs)) = obj.shift_remove(key) {
+                for (name, def) in defs {
+                    definitions.insert(format!("{prefix}{name}"), def);
+                }
+            }
+        }
+    }
+    rewrite_definition_refs(&mut schema, prefix);
+    if !definitions.is_empty() {
+        for def in definitions.values_mut() {
+            rewrite_definition_refs(def, prefix);
+        }
+        if !root["definitions"].is_object() {
+            root["definitions"] = Value::Object(Map::new());
+        }
+        if let Some(root_definitions) = root["definitions"].as_object_mut() {
+            root_definitions.extend(definitions);
+        }
+    }
+    schema
+}
+
+/// Rewrite local `$ref`s to point at `#/definitions/{prefix}{name}`.
+fn rewrite_definition_refs(value: &mut Value, prefix: &str) {
+    match value {
+        Value::Object(obj) => {
+            for (key, value) in obj.iter_mut() {
+                match value {
+                    Value::String(reference) if key == "$ref" => {
+                        let name = reference
+                            .strip_prefix("#/definitions/")
+                            .or_else(|| reference.strip_prefix("#/$defs/"));
+                        if let Some(name) = name {
+                            *reference = format!("#/definitions/{prefix}{name}");
+                        }
+                    }
+                    _ => rewrite_definition_refs(value, prefix),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                rewrite_definition_refs(item, prefix);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::toml_utils::from_toml_str;
+
+    use super::*;
+
+    #[test]
+    fn test_external_schema() {
+        let schema = json!({
+            "path": "tests/fixtures/external_schemas/schema_ts.json",
+        });
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+        let expected = Schema::External(ExternalSchema {
+            path: "tests/fixtures/external_schemas/schema_ts.json".into(),
+        });
+        assert_eq!(schema, expected);
+    }
+
+    #[test]
+    fn test_internal_schema() {
+        let schema_toml = r#"
+description = "Information to extract from each image."
+
+[properties.sign_text]
+description = "Text appearing on the sign in the image."
+
+[properties.sign_holder]
+description = "A one-word description of the entity holding the sign."
+"#;
+        let schema = from_toml_str::<InternalSchema>(schema_toml).unwrap();
+        let mut schema_json = schema.to_json_schema().unwrap();
+        // Sort `required` properties to make the test deterministic.
+        if let required @ Value::Array(_) = &mut schema_json["required"] {
+            let mut strs =
+                serde_json::from_value::<Vec<String>>(required.clone()).unwrap();
+            strs.sort();
+            *required = serde_json::to_value(strs).unwrap();
+        }
+        let expected_json = json!({
+            "description": "Information to extract from each image.",
+            "type": "object",
+            "properties": {
+                "sign_text": {
+                    "description": "Text appearing on the sign in the image.",
+                    "type": "string"
+                },
+                "sign_holder": {
+                    "description": "A one-word description of the entity holding the sign.",
+                    "type": "string"
+                }
+            },
+            "additionalProperties": false,
+            "required": ["sign_holder", "sign_text"],
+        });
+        assert_eq!(schema_json, expected_json);
+    }
+
+    #[test]
+    fn internal_schema_properties_keep_declared_order() {
+        let schema_toml = r#"
+description = "A party."
+
+[properties.name]
+description = "The party's name."
+
+[properties.address]
+description = "The party's address."
+"#;
+        let schema = from_toml_str::<InternalSchema>(schema_toml).unwrap();
+        let schema_json = schema.to_json_schema().unwrap();
+        let keys = schema_json["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["name", "address"]);
+        assert_eq!(schema_json["required"], json!(["name", "address"]));
+    }
+
+    #[test]
+    fn responses_are_ordered_like_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "parties": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "role": { "type": "string" },
+                            "address": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        });
+        let mut response = json!({
+            "extra": true,
+            "parties": [{ "address": "1 Main St", "role": "buyer" }],
+            "name": "Acme",
+        });
+        order_like_schema(&mut response, &schema);
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"name":"Acme","parties":[{"role":"buyer","address":"1 Main St"}],"extra":true}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_classify_schema() {
+        let schema =
+            from_toml_str::<Schema>(r#"classify = ["contract", "invoice"]"#).unwrap();
+        assert_eq!(
+            schema.classify_labels(),
+            Some(&["contract".to_owned(), "invoice".to_owned()][..])
+        );
+        let schema_json = schema.to_json_schema().await.unwrap();
+        assert_eq!(schema_json["required"], json!(["label"]));
+        assert_eq!(
+            schema_json["properties"]["label"]["enum"],
+            json!(["contract", "invoice"])
+        );
+
+        let json_schema: Schema =
+            serde_json::from_value(json!({ "classify": ["a", "b"] })).unwrap();
+        assert!(json_schema.classify_labels().is_some());
+        for bad in [r#"classify = []"#, r#"classify = ["a", "a"]"#] {
+            let schema = from_toml_str::<Schema>(bad).unwrap();
+            assert!(schema.to_json_schema().await.is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn field_confidence_is_added_after_each_leaf() {
+        let mut schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "tags": { "type": "array", "items": { "type": "string" } },
+                "parties": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": { "role": { "type": "string" } },
+                        "required": ["role"],
+                    },
+                },
+            },
+            "required": ["name", "tags", "parties"],
+        });
+        add_companion_fields(&mut schema, &[Companion::Confidence]);
+        let keys = schema["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                "name",
+                "name_confidence",
+                "tags",
+                "tags_confidence",
+                "parties"
+            ]
+        );
+        assert_eq!(
+            schema["required"],
+            json!([
+                "name",
+                "tags",
+                "parties",
+                "name_confidence",
+                "tags_confidence"
+            ])
+        );
+        assert_eq!(
+            schema["properties"]["parties"]["items"]["required"],
+            json!(["role", "role_confidence"])
+        );
+
+        let response = json!({
+            "name": "Acme",
+            "name_confidence": 0.9,
+            "parties": [{ "role": "buyer", "role_confidence": 0.3 }],
+        });
+        assert_eq!(
+            low_confidence_fields(&response, 0.5),
+            vec![("parties.role".to_owned(), 0.3)]
+        );
+        assert!(low_confidence_fields(&response, 0.2).is_empty());
+    }
+
+    #[test]
+    fn quotes_must_appear_in_source() {
+        let mut schema = json!({
+            "type": "object",
+            "properties": {
+                "buyer": { "type": "string" },
+                "price": { "type": "number" },
+            },
+        });
+        add_companion_fields(&mut schema, &[Companion::Confidence, Companion::Quote]);
+        let keys = schema["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                "buyer",
+                "buyer_confidence",
+                "buyer_quote",
+                "price",
+                "price_confidence",
+                "price_quote"
+            ]
+        );
+
+        let source = "Sold to ACME Corp.\nfor   $100.";
+        let response = json!({
+            "buyer": "Acme Corp",
+            "buyer_quote": "acme corp",
+            "price": 100,
+            "price_quote": "for $100",
+        });
+        assert!(unsupported_quotes(&response, source).is_empty());
+
+        let response = json!({
+            "buyer": "Acme Corp",
+            "buyer_quote": "",
+            "price": 200,
+            "price_quote": "for $200",
+        });
+        assert_eq!(
+            unsupported_quotes(&response, source),
+            vec![("price".to_owned(), "for $200".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_internal_schema_error() {
+        let schema_toml = r#"
+description = "Information to extract from each image."
+
+[properties.sign_text]
+# No description.
+"#;
+        let result = from_toml_str::<InternalSchema>(schema_toml);
+        assert!(result.is_err(), "Expected error, got: {result:?}");
+        let msg = result.unwrap_err().to_string();
+        assert!(
+            msg.contains("description"),
+            "Unexpected error message: {msg}"
+        );
+    }
+
+    #[test]
+    fn test_hoist_definitions() {
+        let mut root = json!({
+            "properties": { "response": true },
+            "definitions": { "WorkStatus": { "type": "string" } },
+        });
+        let schema = json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "properties": { "party": { "$ref": "#/$defs/Party" } },
+            "$defs": {
+                "Party": {
+                    "type": "object",
+                    "properties": { "role": { "$ref": "#/$defs/Role" } },
+                },
+                "Role": { "type": "string" },
+            },
+        });
+        let schema = hoist_definitions(&mut root, schema, "Response_");
+        assert_eq!(
+            schema,
+            json!({
+                "type": "object",
+                "properties": { "party": { "$ref": "#/definitions/Response_Party" } },
+            })
+        );
+        assert_eq!(
+            root["definitions"]["Response_Party"]["properties"]["role"]["$ref"],
+            "#/definitions/Response_Role"
+        );
+        assert!(root["definitions"]["WorkStatus"].is_object());
+    }
+}
@@ -0,0 +1,484 @@
+//! Writing searchable PDFs.
+//!
+//! Once we've OCRed a scanned PDF, we can add the recognized text back to the
+//! original pages as an invisible text layer, the way `ocrmypdf` does. The
+//! pages look exactly the same, but the text can be searched, selected and
+//! copied.
+//!
+//! We only need to know where each word is. OCR engines report word boxes
+//! in different units, so we normalize them to fractions of the displayed
+//! page size before passing them here.
+//!
+//! We use the standard Helvetica font, which PDF readers must provide, so the
+//! output is not strictly PDF/A-conformant, which would require an embedded
+//! font.
+
+use lopdf::{
+    Dictionary, Document, Object, ObjectId, Stream, StringFormat,
+    content::{Content, Operation},
+    dictionary,
+};
+
+use crate::{
+    async_utils::blocking_iter_streams::spawn_blocking_propagating_panics,
+    num_utils::usize_to_f64, page_iter::get_mime_type, prelude::*,
+};
+
+/// The resource name we use for our font. This is unlikely to collide with any
+/// font already used by a page.
+const OCR_FONT_NAME: &str = "PromptScalerOcr";
+
+/// Rough average width of a Helvetica glyph, as a fraction of the font size.
+/// We use this to stretch each word to fit its box.
+const AVERAGE_GLYPH_WIDTH: f64 = 0.5;
+
+/// A word recognized by OCR, with a bounding box measured in fractions of the
+/// displayed page size, starting from the top left.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OcrWord {
+    /// The text of the word.
+    pub text: String,
+
+    /// The left edge of the word.
+    pub left: f64,
+
+    /// The top edge of the word.
+    pub top: f64,
+
+    /// The width of the word.
+    pub width: f64,
+
+    /// The height of the word.
+    pub height: f64,
+}
+
+/// Parse the `tsv` output of `tesseract` into words.
+pub fn words_from_tesseract_tsv(tsv: &str) -> Result<Vec<OcrWord>> {
+    let mut page_size = None;
+    let mut words = vec![];
+    for line in tsv.lines().skip(1) {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        if fields.len() < 12 {
+            continue;
+        }
+        let number = |idx: usize| -> Result<f64> {
+            fields[idx]
+                .parse::<f64>()
+                .with_context(|| format!("invalid tesseract TSV line: {line:?}"))
+        };
+        match fields[0] {
+            // A page, which tells us the size of the image.
+            "1" => page_size = Some((number(8)?, number(9)?)),
+            // A word.
+            "5" => {
+                let text = fields[11].trim();
+                if text.is_empty() {
+                    continue;
+                }
+                let (page_width, page_height) = page_size
+                    .filter(|&(w, h)| w > 0.0 && h > 0.0)
+                    .ok_or_else(|| anyhow!("tesseract TSV has no page size"))?;
+                words.push(OcrWord {
+                    text: text.to_owned(),
+                    left: number(6)? / page_width,
+                    top: number(7)? / page_height,
+                    width: number(8)? / page_width,
+                    height: number(9)? / page_height,
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(words)
+}
+
+/// Write a searchable copy of the PDF at `input` to `dir`, named after the
+/// record `id`. Returns the path of the new PDF.
+pub async fn write_searchable_pdf_to_dir(
+    dir: &Path,
+    id: &Value,
+    input: &Path,
+    password: Option<String>,
+    pages: Vec<Option<Vec<OcrWord>>>,
+) -> Result<String> {
+    let output = dir.join(searchable_pdf_file_name(id));
+    let input = input.to_owned();
+    let dir = dir.to_owned();
+    let written = output.clone();
+    spawn_blocking_propagating_panics(move || {
+        if get_mime_type(&input)? != "application/pdf" {
+            return Err(anyhow!("searchable PDF output only supports PDF inputs"));
+        }
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {:?}", dir.display()))?;
+        write_searchable_pdf(&input, password.as_deref(), &pages, &output)
+    })
+    .await?;
+    Ok(written.to_string_lossy().into_owned())
+}
+
+/// Add an invisible text layer to each page of the PDF at `input`, and write
+/// the result to `output`. `pages` must contain one entry for each page in the
+/// PDF. Pages which could not be OCRed should be `None`, and are left alone.
+///
+/// This performs blocking I/O.
+pub fn write_searchable_pdf(
+    input: &Path,
+    password: Option<&str>,
+    pages: &[Option<Vec<OcrWord>>],
+    output: &Path,
+) -> Result<()> {
+    let mut doc = Document::load(input)
+        .with_context(|| format!("failed to load PDF {:?}", input.display()))?;
+    if doc.is_encrypted() {
+        doc.decrypt(password.unwrap_or(""))
+            .with_context(|| format!("failed to decrypt PDF {:?}", input.display()))?;
+    }
+
+    let page_ids = doc.get_pages().into_values().collect::<Vec<_>>();
+    if page_ids.len() != pages.len() {
+        return Err(anyhow!(
+            "{:?} has {} pages, but OCR returned {} pages",
+            input.display(),
+            page_ids.len(),
+            pages.len()
+        ));
+    }
+
+    let font_id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding",
+    });
+    for (page_id, words) in page_ids.into_iter().zip(pages) {
+        let Some(words) = words.as_ref().filter(|words| !words.is_empty()) else {
+            continue;
+        };
+        let geometry = PageGeometry::for_page(&doc, page_id)?;
+        let content = text_layer_content(&geometry, words)?;
+        add_font_to_page(&mut doc, page_id, font_id)?;
+        append_page_content(&mut doc, page_id, content)?;
+    }
+
+    doc.compress();
+    doc.save(output)
+        .with_context(|| format!("failed to write PDF {:?}", output.display()))?;
+    Ok(())
+}
+
+/// The visible area of a page, and how it is rotated for display.
+struct PageGeometry {
+    /// The lower-left corner of the visible area, in PDF units.
+    x0: f64,
+    y0: f64,
+
+    /// The upper-right corner of the visible area, in PDF units.
+    x1: f64,
+    y1: f64,
+
+    /// The clockwise rotation applied when displaying the page, in degrees.
+    rotate: i64,
+}
+
+impl PageGeometry {
+    /// Look up the geometry of a page, including inherited attributes.
+    fn for_page(doc: &Document, page_id: ObjectId) -> Result<Self> {
+        let rect = inherited_page_attribute(doc, page_id, b"CropBox")
+            .or_else(|| inherited_page_attribute(doc, page_id, b"MediaBox"))
+            .ok_or_else(|| anyhow!("PDF page has no MediaBox"))?;
+        let rect = rect
+            .as_array()?
+            .iter()
+            .map(|n| Ok(f64::from(n.as_float()?)))
+            .collect::<Result<Vec<_>>>()?;
+        let [ax, ay, bx, by] = rect[..] else {
+            return Err(anyhow!("PDF page has invalid MediaBox {rect:?}"));
+        };
+        let rotate = inherited_page_attribute(doc, page_id, b"Rotate")
+            .map(|rotate| rotate.as_i64())
+            .transpose()?
+            .unwrap_or(0)
+            .rem_euclid(360);
+        Ok(Self {
+            x0: ax.min(bx),
+            y0: ay.min(by),
+            x1: ax.max(bx),
+            y1: ay.max(by),
+            rotate,
+        })
+    }
+
+    /// Convert a point in fractions of the displayed page to PDF units.
+    /// Returns the point, plus the size of one unit horizontally and
+    /// vertically on the displayed page, and the text direction in degrees.
+    fn to_pdf(&self, u: f64, v: f64) -> ((f64, f64), (f64, f64), f64) {
+        let (width, height) = (self.x1 - self.x0, self.y1 - self.y0);
+        match self.rotate {
+            90 => (
+                (self.x0 + v * width, self.y0 + u * height),
+                (height, width),
+                90.0,
+            ),
+            180 => (
+                (self.x1 - u * width, self.y0 + v * height),
+                (width, height),
+                180.0,
+            ),
+            270 => (
+                (self.x1 - v * width, self.y1 - u * height),
+                (height, width),
+                270.0,
+            ),
+            _ => (
+                (self.x0 + u * width, self.y1 - v * height),
+                (width, height),
+                0.0,
+            ),
+        }
+    }
+}
+
+/// Build a content stream which draws `words` as invisible text.
+fn text_layer_content(geometry: &PageGeometry, words: &[OcrWord]) -> Result<Vec<u8>> {
+    let mut operations = vec![
+        Operation::new("BT", vec![]),
+        // Text render mode 3 is invisible.
+        Operation::new("Tr", vec![Object::Integer(3)]),
+    ];
+    for word in words {
+        let encoded = encode_win_ansi(&word.text);
+        let Some(glyphs) = usize_to_f64(encoded.len()) else {
+            continue;
+        };
+        if encoded.is_empty() || word.width <= 0.0 || word.height <= 0.0 {
+            continue;
+        }
+        // Put our baseline at the bottom of the word box.
+        let ((x, y), (unit_x, unit_y), degrees) =
+            geometry.to_pdf(word.left, word.top + word.height);
+        let font_size = word.height * unit_y;
+        let box_width = word.width * unit_x;
+        let natural_width = font_size * AVERAGE_GLYPH_WIDTH * glyphs;
+        let scale = 100.0 * box_width / natural_width;
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        operations.extend([
+            Operation::new(
+                "Tf",
+                vec![Object::Name(OCR_FONT_NAME.into()), font_size.into()],
+            ),
+            Operation::new("Tz", vec![scale.into()]),
+            Operation::new(
+                "Tm",
+                vec![
+                    cos.into(),
+                    sin.into(),
+                    (-sin).into(),
+                    cos.into(),
+                    x.into(),
+                    y.into(),
+                ],
+            ),
+            Operation::new("Tj", vec![Object::String(encoded, StringFormat::Literal)]),
+        ]);
+    }
+    operations.push(Operation::new("ET", vec![]));
+    Content { operations }
+        .encode()
+        .context("failed to encode PDF text layer")
+}
+
+/// Encode text for Helvetica with `WinAnsiEncoding`. This covers Latin-1,
+/// which is enough for searching most Western European text. Other characters
+/// are replaced with `?`.
+fn encode_win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match u8::try_from(u32::from(c)) {
+            Ok(b @ (0x20..=0x7E | 0xA0..=0xFF)) => b,
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// Look up a page attribute, following the page tree for inherited attributes.
+fn inherited_page_attribute(
+    doc: &Document,
+    page_id: ObjectId,
+    key: &[u8],
+) -> Option<Object> {
+    let mut node = doc.get_dictionary(page_id).ok()?;
+    loop {
+        if let Ok(value) = node.get(key) {
+            return match value {
+                Object::Reference(id) => doc.get_object(*id).ok().cloned(),
+                other => Some(other.clone()),
+            };
+        }
+        let parent_id = node.get(b"Parent").ok()?.as_reference().ok()?;
+        node = doc.get_dictionary(parent_id).ok()?;
+    }
+}
+
+/// Make sure a page's resources include our font.
+fn add_font_to_page(
+    doc: &mut Document,
+    page_id: ObjectId,
+    font_id: ObjectId,
+) -> Result<()> {
+    // If the page inherits its resources, copy them to the page itself, so we
+    // don't hide them when we add our font.
+    let page = doc.get_dictionary(page_id)?;
+    if !page.has(b"Resources") {
+        let resources = inherited_page_attribute(doc, page_id, b"Resources")
+            .unwrap_or_else(|| Object::Dictionary(Dictionary::new()));
+        doc.get_dictionary_mut(page_id)?.set("Resources", resources);
+    }
+
+    let resources = dictionary_mut(doc, page_id, b"Resources")?;
+    if !resources.has(b"Font") {
+        resources.set("Font", Dictionary::new());
+    }
+    let fonts = match resources.get(b"Font")? {
+        Object::Reference(id) => {
+            let id = *id;
+            doc.get_dictionary_mut(id)?
+        }
+        _ => dictionary_mut(doc, page_id, b"Resources")?
+            .get_mut(b"Font")?
+            .as_dict_mut()?,
+    };
+    fonts.set(OCR_FONT_NAME, font_id);
+    Ok(())
+}
+
+/// Get a mutable dictionary stored under `key` in the object `id`, following
+/// an indirect reference if necessary.
+fn dictionary_mut<'a>(
+    doc: &'a mut Document,
+    id: ObjectId,
+    key: &[u8],
+) -> Result<&'a mut Dictionary> {
+    match doc.get_dictionary(id)?.get(key)? {
+        Object::Reference(ref_id) => {
+            let ref_id = *ref_id;
+            Ok(doc.get_dictionary_mut(ref_id)?)
+        }
+        _ => Ok(doc.get_dictionary_mut(id)?.get_mut(key)?.as_dict_mut()?),
+    }
+}
+
+/// Append a content stream to a page. We wrap the existing content in `q` and
+/// `Q`, so that any graphics state it leaves behind doesn't affect our text.
+fn append_page_content(
+    doc: &mut Document,
+    page_id: ObjectId,
+    content: Vec<u8>,
+) -> Result<()> {
+    let existing = match doc.get_dictionary(page_id)?.get(b"Contents") {
+        Ok(Object::Array(streams)) => streams.clone(),
+        Ok(Object::Reference(id)) => match doc.get_object(*id)? {
+            Object::Array(streams) => streams.clone(),
+            _ => vec![Object::Reference(*id)],
+        },
+        Ok(other) => {
+            return Err(anyhow!("unexpected PDF page Contents: {other:?}"));
+        }
+        Err(_) => vec![],
+    };
+    let save_id = doc.add_object(Stream::new(Dictionary::new(), b"q\n".to_vec()));
+    let mut restore_and_draw = b"Q\n".to_vec();
+    restore_and_draw.extend(content);
+    let content_id = doc.add_object(Stream::new(Dictionary::new(), restore_and_draw));
+
+    let mut contents = vec![Object::Reference(save_id)];
+    contents.extend(existing);
+    contents.push(Object::Reference(content_id));
+    doc.get_dictionary_mut(page_id)?
+        .set("Contents", Object::Array(contents));
+    Ok(())
+}
+
+/// Choose a file name for the searchable PDF for the record `id`.
+pub fn searchable_pdf_file_name(id: &Value) -> String {
+    let id = match id {
+        Value::String(id) => id.clone(),
+        other => other.to_string(),
+    };
+    let mut name = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if !name.to_ascii_lowercase().ends_with(".pdf") {
+        name.push_str(".pdf");
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tesseract_tsv_words() {
+        let tsv = "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext\n\
+                   1\t1\t0\t0\t0\t0\t0\t0\t200\t100\t-1\t\n\
+                   4\t1\t1\t1\t1\t0\t10\t20\t100\t10\t-1\t\n\
+                   5\t1\t1\t1\t1\t1\t10\t20\t40\t10\t96.5\tHello\n\
+                   5\t1\t1\t1\t1\t2\t60\t20\t50\t10\t95.1\t \n";
+        let words = words_from_tesseract_tsv(tsv).unwrap();
+        assert_eq!(
+            words,
+            vec![OcrWord {
+                text: "Hello".to_owned(),
+                left: 0.05,
+                top: 0.2,
+                width: 0.2,
+                height: 0.1,
+            }]
+        );
+    }
+
+    #[test]
+    fn rotated_pages_map_to_pdf_units() {
+        let geometry = |rotate| PageGeometry {
+            x0: 0.0,
+            y0: 0.0,
+            x1: 600.0,
+            y1: 800.0,
+            rotate,
+        };
+        assert_eq!(
+            geometry(0).to_pdf(0.0, 0.0),
+            ((0.0, 800.0), (600.0, 800.0), 0.0)
+        );
+        assert_eq!(
+            geometry(90).to_pdf(0.0, 0.0),
+            ((0.0, 0.0), (800.0, 600.0), 90.0)
+        );
+        assert_eq!(
+            geometry(180).to_pdf(0.0, 0.0),
+            ((600.0, 0.0), (600.0, 800.0), 180.0)
+        );
+        assert_eq!(
+            geometry(270).to_pdf(0.0, 0.0),
+            ((600.0, 800.0), (800.0, 600.0), 270.0)
+        );
+    }
+
+    #[test]
+    fn file_names_are_sanitized() {
+        assert_eq!(searchable_pdf_file_name(&json!("a/b c.pdf")), "a_b_c.pdf");
+        assert_eq!(searchable_pdf_file_name(&json!(12)), "12.pdf");
+    }
+
+    #[test]
+    fn win_ansi_replaces_unsupported_characters() {
+        assert_eq!(encode_win_ansi("café ☃"), b"caf\xE9 ?".to_vec());
+    }
+}
@@ -0,0 +1,416 @@
+//! A local ledger of LLM spend, persisted across runs.
+//!
+//! Each run adds its spend to a JSON file, keyed by UTC date and model. This
+//! lets us refuse to start a run once a daily or monthly budget has been used
+//! up, without needing a gateway to track spend for us.
+//!
+//! The ledger is read and rewritten at the start and end of each run, so runs
+//! which finish at the same moment may occasionally lose an update.
+//!
+//! Our estimates ignore cached tokens and provider surcharges, so with
+//! `--reconcile-spend`, we also ask LiteLLM what it thinks the run cost, and
+//! record that as the reported spend.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use futures::StreamExt as _;
+
+use crate::{
+    async_utils::BoxedStream,
+    cmd::StreamOpts,
+    litellm::{LiteLlmSpendLog, litellm_model_info_available, litellm_spend_logs},
+    prelude::*,
+    queues::work::WorkOutput,
+};
+
+/// The `--tag` key we use to find this run's requests in LiteLLM's spend logs.
+pub const RUN_TAG_KEY: &str = "prompt_scaler_run";
+
+/// How much clock skew between us and LiteLLM to allow for when fetching spend
+/// logs.
+const SPEND_LOG_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+/// Warn if LiteLLM's spend differs from our estimate by more than this
+/// fraction.
+const SPEND_DIVERGENCE_WARNING: f64 = 0.1;
+
+/// A period over which spend is totalled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BudgetPeriod {
+    /// A UTC calendar day.
+    #[default]
+    Day,
+    /// A UTC calendar month.
+    Month,
+}
+
+impl BudgetPeriod {
+    /// The key for the period containing `date`, which is formatted as
+    /// `YYYY-MM-DD`.
+    pub fn key(self, date: &str) -> &str {
+        match self {
+            BudgetPeriod::Day => date,
+            BudgetPeriod::Month => date.get(..7).unwrap_or(date),
+        }
+    }
+}
+
+/// Spend for a single model on a single day.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SpendEntry {
+    /// The UTC date, as `YYYY-MM-DD`.
+    pub date: String,
+
+    /// The model.
+    pub model: String,
+
+    /// How many runs have contributed to this entry.
+    pub runs: u64,
+
+    /// Our own estimate of the spend, in US dollars.
+    pub estimated_cost: f64,
+
+    /// The spend reported by the provider, in US dollars, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reported_cost: Option<f64>,
+}
+
+/// Our ledger file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SpendLedger {
+    /// Spend entries, in the order they were first created.
+    pub entries: Vec<SpendEntry>,
+}
+
+impl SpendLedger {
+    /// Load a ledger from `path`. A missing file is an empty ledger.
+    pub async fn load(path: &Path) -> Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(data) => serde_json::from_slice(&data).with_context(|| {
+                format!("cannot parse spend ledger {:?}", path.display())
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| {
+                format!("cannot read spend ledger {:?}", path.display())
+            }),
+        }
+    }
+
+    /// Save this ledger to `path`, replacing the old file only once the new one
+    /// has been written.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).context("cannot serialize ledger")?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        tokio::fs::write(&tmp_path, json)
+            .await
+            .with_context(|| format!("cannot write {:?}", tmp_path.display()))?;
+        tokio::fs::rename(&tmp_path, path)
+            .await
+            .with_context(|| format!("cannot replace {:?}", path.display()))
+    }
+
+    /// Add spend for `model` on `date`.
+    pub fn record(
+        &mut self,
+        date: &str,
+        model: &str,
+        estimated_cost: f64,
+        reported_cost: Option<f64>,
+    ) {
+        let entry = match self
+            .entries
+            .iter_mut()
+            .position(|e| e.date == date && e.model == model)
+        {
+            Some(idx) => &mut self.entries[idx],
+            None => {
+                self.entries.push(SpendEntry {
+                    date: date.to_owned(),
+                    model: model.to_owned(),
+                    runs: 0,
+                    estimated_cost: 0.0,
+                    reported_cost: None,
+                });
+                self.entries.last_mut().expect("just pushed an entry")
+            }
+        };
+        entry.runs += 1;
+        entry.estimated_cost += estimated_cost;
+        if let Some(reported_cost) = reported_cost {
+            *entry.reported_cost.get_or_insert(0.0) += reported_cost;
+        }
+    }
+
+    /// Total our spend by period and model. Where the provider reported
+    /// spend, we prefer it to our own estimate.
+    pub fn totals(&self, period: BudgetPeriod) -> Vec<SpendEntry> {
+        let mut totals = BTreeMap::<(&str, &str), SpendEntry>::new();
+        for entry in &self.entries {
+            let key = period.key(&entry.date);
+            let total = totals
+                .entry((key, &entry.model))
+                .or_insert_with(|| SpendEntry {
+                    date: key.to_owned(),
+                    model: entry.model.clone(),
+                    runs: 0,
+                    estimated_cost: 0.0,
+                    reported_cost: None,
+                });
+            total.runs += entry.runs;
+            total.estimated_cost += entry.estimated_cost;
+            if let Some(reported_cost) = entry.reported_cost {
+                *total.reported_cost.get_or_insert(0.0) += reported_cost;
+            }
+        }
+        totals.into_values().collect()
+    }
+
+    /// How much have we spent in the period containing `date`, across all
+    /// models?
+    pub fn period_spend(&self, period: BudgetPeriod, date: &str) -> f64 {
+        let key = period.key(date);
+        self.totals(period)
+            .iter()
+            .filter(|total| total.date == key)
+            .map(|total| total.reported_cost.unwrap_or(total.estimated_cost))
+            .sum()
+    }
+}
+
+/// Refuse to start a run if `--budget-period-cap` has already been reached.
+pub async fn check_budget(stream_opts: &StreamOpts) -> Result<()> {
+    let (Some(path), Some(cap)) =
+        (&stream_opts.spend_ledger, stream_opts.budget_period_cap)
+    else {
+        return Ok(());
+    };
+    let ledger = SpendLedger::load(path).await?;
+    let spent = ledger.period_spend(stream_opts.budget_period, &today_utc());
+    if spent >= cap {
+        let period = match stream_opts.budget_period {
+            BudgetPeriod::Day => "day",
+            BudgetPeriod::Month => "month",
+        };
+        return Err(anyhow!(
+            "spend ledger shows US${spent:.2} already spent this {period}, which reaches --budget-period-cap US${cap:.2}"
+        ));
+    }
+    Ok(())
+}
+
+/// Tracks the estimated cost of output records, so we can add it to the
+/// spend ledger at the end of a run.
+#[derive(Clone, Debug, Default)]
+pub struct SpendTracker {
+    estimated_cost: Arc<Mutex<f64>>,
+}
+
+impl SpendTracker {
+    /// Wrap a stream of outputs, adding up their estimated cost.
+    pub fn wrap_stream<T>(
+        &self,
+        stream: BoxedStream<Result<WorkOutput<T>>>,
+    ) -> BoxedStream<Result<WorkOutput<T>>>
+    where
+        T: Send + 'static,
+    {
+        let estimated_cost = self.estimated_cost.clone();
+        stream
+            .map(move |output| {
+                if let Ok(output) = &output
+                    && let Some(cost) = output.estimated_cost
+                {
+                    *estimated_cost.lock().expect("lock poisoned") += cost;
+                }
+                output
+            })
+            .boxed()
+    }
+
+    /// Our estimated cost so far.
+    fn estimated_cost(&self) -> f64 {
+        *self.estimated_cost.lock().expect("lock poisoned")
+    }
+
+    /// Add our spend to the ledger, if we have one, along with any spend
+    /// reported by the provider.
+    pub async fn record(
+        &self,
+        stream_opts: &StreamOpts,
+        model: &str,
+        reported_cost: Option<f64>,
+    ) -> Result<()> {
+        let Some(path) = &stream_opts.spend_ledger else {
+            return Ok(());
+        };
+        let mut ledger = SpendLedger::load(path).await?;
+        ledger.record(&today_utc(), model, self.estimated_cost(), reported_cost);
+        ledger.save(path).await
+    }
+
+    /// Compare our estimate with the spend LiteLLM logged for requests tagged
+    /// with `run_tag` since `started`, warning if they differ by much. We wait
+    /// `delay` first, because LiteLLM writes its logs in batches. Returns
+    /// LiteLLM's spend, or `None` if we couldn't get it.
+    pub async fn reconcile_with_litellm(
+        &self,
+        started: SystemTime,
+        run_tag: &str,
+        delay: Duration,
+    ) -> Option<f64> {
+        if !litellm_model_info_available().await {
+            warn!("Cannot reconcile spend, because we aren't talking to LiteLLM");
+            return None;
+        }
+        let finished = SystemTime::now();
+        info!("Waiting {}s for LiteLLM to log our spend", delay.as_secs());
+        tokio::time::sleep(delay).await;
+
+        // Our run tag picks out our requests, so the time range only needs to
+        // be wide enough to include them all.
+        let start = utc_timestamp(started - SPEND_LOG_CLOCK_SKEW).replace('T', " ");
+        let end = utc_timestamp(finished + SPEND_LOG_CLOCK_SKEW).replace('T', " ");
+        let logs = match litellm_spend_logs(&start, &end).await {
+            Ok(logs) => logs,
+            Err(err) => {
+                warn!("Cannot reconcile spend with LiteLLM: {err:?}");
+                return None;
+            }
+        };
+        let reported = reported_spend(&logs, run_tag);
+
+        let estimated = self.estimated_cost();
+        let divergence = if estimated > 0.0 {
+            (reported - estimated) / estimated
+        } else if reported > 0.0 {
+            f64::INFINITY
+        } else {
+            0.0
+        };
+        if divergence.abs() > SPEND_DIVERGENCE_WARNING {
+            warn!(
+                "LiteLLM reports US${reported:.4} spent, but we estimated \
+                 US${estimated:.4} ({:+.0}%)",
+                divergence * 100.0
+            );
+        } else {
+            info!(
+                "LiteLLM reports US${reported:.4} spent, and we estimated \
+                 US${estimated:.4}"
+            );
+        }
+        Some(reported)
+    }
+}
+
+/// Total the spend in `logs` for requests tagged with `run_tag`.
+fn reported_spend(logs: &[LiteLlmSpendLog], run_tag: &str) -> f64 {
+    logs.iter()
+        .filter(|log| log.has_tag(run_tag))
+        .map(|log| log.spend)
+        .sum()
+}
+
+/// Today's date in UTC, as `YYYY-MM-DD`.
+pub fn today_utc() -> String {
+    utc_timestamp(SystemTime::now())[..10].to_owned()
+}
+
+/// Format `time` in UTC, as `YYYY-MM-DDTHH:MM:SS`.
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = i64::try_from(secs / 86_400).unwrap_or(0);
+    let (year, month, day) = civil_from_days(days);
+    let secs = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+        secs / 3_600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date, using Howard
+/// Hinnant's `civil_from_days` algorithm.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_convert_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn timestamps_are_formatted_in_utc() {
+        let time = UNIX_EPOCH + Duration::from_secs(19_782 * 86_400 + 3_723);
+        assert_eq!(utc_timestamp(time), "2024-02-29T01:02:03");
+    }
+
+    #[test]
+    fn reported_spend_is_filtered_by_run_tag() {
+        let logs: Vec<LiteLlmSpendLog> = serde_json::from_value(json!([
+            {
+                "spend": 1.0,
+                "startTime": "2026-10-16T10:00:00.123000",
+                "model": "gpt-4o-2024-08-06",
+                "model_group": "gpt-4o",
+                "request_tags": ["team:legal", "prompt_scaler_run:a"],
+            },
+            {
+                "spend": 2.0,
+                "startTime": "2026-10-16 10:30:00",
+                "model": "claude",
+                "request_tags": "[\"prompt_scaler_run:a\"]",
+            },
+            {
+                "spend": 4.0,
+                "startTime": "2026-10-16T10:30:00Z",
+                "model": "gpt-4o",
+                "request_tags": ["prompt_scaler_run:b"],
+            },
+            { "spend": 8.0, "startTime": "2026-10-16T10:30:00", "model": "gpt-4o" },
+        ]))
+        .unwrap();
+        assert_eq!(reported_spend(&logs, "prompt_scaler_run:a"), 3.0);
+    }
+
+    #[test]
+    fn spend_is_totalled_by_period() {
+        let mut ledger = SpendLedger::default();
+        ledger.record("2026-10-15", "gpt-4o", 1.0, None);
+        ledger.record("2026-10-16", "gpt-4o", 2.0, None);
+        ledger.record("2026-10-16", "gpt-4o", 0.5, Some(3.0));
+        ledger.record("2026-10-16", "claude", 4.0, None);
+        ledger.record("2026-09-30", "claude", 8.0, None);
+
+        assert_eq!(ledger.entries.len(), 4);
+        assert_eq!(ledger.period_spend(BudgetPeriod::Day, "2026-10-16"), 7.0);
+        assert_eq!(ledger.period_spend(BudgetPeriod::Month, "2026-10-16"), 8.0);
+        let months = ledger.totals(BudgetPeriod::Month);
+        assert_eq!(months.len(), 3);
+        assert_eq!(months[0].date, "2026-09");
+    }
+}
@@ -0,0 +1,397 @@
+//! SQS-driven input source.
+//!
+//! This allows `prompt-scaler` to run as an always-on worker. Each message body
+//! is a JSON input record. Messages are deleted from the queue once their output
+//! has been written successfully. Failed records are left on the queue, where
+//! they will become visible again and eventually be dead-lettered by the
+//! queue's redrive policy.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use aws_sdk_sqs::{Client, operation::receive_message::ReceiveMessageError};
+use clap::Args;
+use futures::{StreamExt as _, stream};
+use tokio::time;
+
+use crate::{
+    async_utils::io::{JsonStream, OutputAck, id_key},
+    aws::load_aws_config,
+    prelude::*,
+    queues::work::WorkStatus,
+    retry::{
+        DEFAULT_JITTER, IsKnownTransient, retry_result_ok, retry_with_backoff,
+        try_potentially_transient,
+    },
+};
+
+/// Prefix for input "paths" which should be read from SQS.
+const SQS_URL_PREFIX: &str = "sqs://";
+
+/// Maximum number of messages SQS will return from one receive call.
+const MAX_MESSAGES_PER_RECEIVE: i32 = 10;
+
+/// How long to wait for messages when long-polling, in seconds.
+const LONG_POLL_WAIT_SECONDS: i32 = 20;
+
+/// SQS-related options.
+#[derive(Args, Clone, Debug)]
+pub struct SqsOpts {
+    /// When reading from `sqs://`, how many seconds received messages should
+    /// stay hidden from other consumers. This is extended automatically for
+    /// as long as a record is still being processed.
+    #[clap(long, default_value = "300")]
+    pub sqs_visibility_timeout: u32,
+
+    /// When reading from `sqs://`, exit once the queue is empty instead of
+    /// waiting for more messages.
+    #[clap(long)]
+    pub sqs_exit_when_empty: bool,
+}
+
+/// If `path` is an `sqs://` URL, return the corresponding HTTPS queue URL.
+///
+/// `sqs://sqs.us-east-1.amazonaws.com/123456789012/jobs` becomes
+/// `https://sqs.us-east-1.amazonaws.com/123456789012/jobs`.
+pub fn sqs_queue_url(path: &Path) -> Option<String> {
+    path.to_str()?
+        .strip_prefix(SQS_URL_PREFIX)
+        .map(|rest| format!("https://{rest}"))
+}
+
+/// Receipt handles for messages that we're still processing.
+#[derive(Debug, Default)]
+struct InFlight {
+    /// Receipt handles, keyed by [`id_key`]. We allow duplicate IDs, because
+    /// SQS may deliver the same message more than once.
+    handles_by_id: Mutex<HashMap<String, VecDeque<String>>>,
+}
+
+impl InFlight {
+    /// Record a receipt handle for a newly-received record.
+    fn insert(&self, id: &Value, receipt_handle: String) {
+        let mut handles_by_id = self.handles_by_id.lock().expect("lock poisoned");
+        handles_by_id
+            .entry(id_key(id))
+            .or_default()
+            .push_back(receipt_handle);
+    }
+
+    /// Stop tracking a record, returning its receipt handle.
+    fn take(&self, id: &Value) -> Option<String> {
+        let mut handles_by_id = self.handles_by_id.lock().expect("lock poisoned");
+        let key = id_key(id);
+        let handles = handles_by_id.get_mut(&key)?;
+        let receipt_handle = handles.pop_front();
+        if handles.is_empty() {
+            handles_by_id.remove(&key);
+        }
+        receipt_handle
+    }
+
+    /// Get all receipt handles that are currently in flight.
+    fn receipt_handles(&self) -> Vec<String> {
+        let handles_by_id = self.handles_by_id.lock().expect("lock poisoned");
+        handles_by_id.values().flatten().cloned().collect()
+    }
+}
+
+/// Read input records from an SQS queue.
+///
+/// Returns a stream of JSON records, plus an [`OutputAck`] which must be
+/// called once each record's output has been written.
+#[instrument(level = "debug", skip(opts))]
+pub async fn read_sqs(
+    queue_url: String,
+    opts: &SqsOpts,
+) -> Result<(JsonStream, Arc<dyn OutputAck>)> {
+    let visibility_timeout = i32::try_from(opts.sqs_visibility_timeout)
+        .context("--sqs-visibility-timeout is too large")?;
+    let config = load_aws_config().await?;
+    let client = Client::new(&config);
+    let in_flight = Arc::new(InFlight::default());
+
+    spawn_visibility_extender(
+        client.clone(),
+        queue_url.clone(),
+        Arc::downgrade(&in_flight),
+        visibility_timeout,
+    );
+
+    let receiver = SqsReceiver {
+        client: client.clone(),
+        queue_url: queue_url.clone(),
+        visibility_timeout,
+        exit_when_empty: opts.sqs_exit_when_empty,
+        in_flight: in_flight.clone(),
+        pending: VecDeque::new(),
+    };
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        match receiver.next_record().await {
+            Ok(Some(record)) => Some((Ok(record), receiver)),
+            Ok(None) => None,
+            Err(err) => Some((Err(err), receiver)),
+        }
+    })
+    .boxed();
+
+    let ack = Arc::new(SqsAck {
+        client,
+        queue_url,
+        in_flight,
+    });
+    Ok((stream, ack))
+}
+
+/// Receives messages from SQS and buffers them until they're needed.
+struct SqsReceiver {
+    /// Our SQS client.
+    client: Client,
+
+    /// The queue to read from.
+    queue_url: String,
+
+    /// Visibility timeout for received messages, in seconds.
+    visibility_timeout: i32,
+
+    /// Should we stop when the queue is empty?
+    exit_when_empty: bool,
+
+    /// Messages we're still processing.
+    in_flight: Arc<InFlight>,
+
+    /// Records we've received but not yet returned.
+    pending: VecDeque<Value>,
+}
+
+impl SqsReceiver {
+    /// Get the next record, waiting for SQS if necessary.
+    async fn next_record(&mut self) -> Result<Option<Value>> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Ok(Some(record));
+            }
+
+            let client = &self.client;
+            let queue_url = &self.queue_url;
+            let visibility_timeout = self.visibility_timeout;
+            let output = retry_with_backoff(DEFAULT_JITTER, move || async move {
+                retry_result_ok(try_potentially_transient!(
+                    client
+                        .receive_message()
+                        .queue_url(queue_url)
+                        .max_number_of_messages(MAX_MESSAGES_PER_RECEIVE)
+                        .wait_time_seconds(LONG_POLL_WAIT_SECONDS)
+                        .visibility_timeout(visibility_timeout)
+                        .send()
+                        .await
+                ))
+            })
+            .await
+            .into_result()?;
+
+            let messages = output.messages();
+            if messages.is_empty() {
+                if self.exit_when_empty {
+                    debug!("SQS queue is empty, stopping");
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            for message in messages {
+                let (Some(body), Some(receipt_handle)) =
+                    (message.body(), message.receipt_handle())
+                else {
+                    warn!(message_id = ?message.message_id(), "Ignoring SQS message without body");
+                    continue;
+                };
+                match serde_json::from_str::<Value>(body) {
+                    Ok(record) => {
+                        self.in_flight
+                            .insert(&record["id"], receipt_handle.to_owned());
+                        self.pending.push_back(record);
+                    }
+                    Err(err) => {
+                        // Leave this on the queue so the redrive policy can
+                        // dead-letter it.
+                        error!(
+                            message_id = ?message.message_id(),
+                            "Leaving unparseable SQS message on queue: {err}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SqsReceiver {
+    /// Release any messages we received but never returned, for example
+    /// because of `--limit`. Otherwise we would keep extending their
+    /// visibility until we exit, and nobody else could process them.
+    fn drop(&mut self) {
+        let in_flight = &self.in_flight;
+        let receipt_handles = self
+            .pending
+            .drain(..)
+            .filter_map(|record| in_flight.take(&record["id"]))
+            .collect::<Vec<_>>();
+        if receipt_handles.is_empty() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        debug!(
+            count = receipt_handles.len(),
+            "Releasing unused SQS messages"
+        );
+        let client = self.client.clone();
+        let queue_url = self.queue_url.clone();
+        runtime.spawn(async move {
+            for receipt_handle in receipt_handles {
+                // A visibility timeout of 0 makes the message available to
+                // other consumers at once.
+                let result = client
+                    .change_message_visibility()
+                    .queue_url(&queue_url)
+                    .receipt_handle(receipt_handle)
+                    .visibility_timeout(0)
+                    .send()
+                    .await;
+                if let Err(err) = result {
+                    warn!("Failed to release SQS message: {err:?}");
+                }
+            }
+        });
+    }
+}
+
+/// Periodically extend the visibility timeout of all in-flight messages, so
+/// that long-running OCR jobs don't get delivered to another consumer.
+///
+/// Exits once `in_flight` has been dropped.
+fn spawn_visibility_extender(
+    client: Client,
+    queue_url: String,
+    in_flight: Weak<InFlight>,
+    visibility_timeout: i32,
+) {
+    let period =
+        Duration::from_secs(u64::from(visibility_timeout.unsigned_abs() / 2).max(1));
+    tokio::spawn(async move {
+        let mut interval = time::interval(period);
+        // The first tick completes immediately.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(in_flight) = in_flight.upgrade() else {
+                break;
+            };
+            let receipt_handles = in_flight.receipt_handles();
+            drop(in_flight);
+            for receipt_handle in receipt_handles {
+                let result = client
+                    .change_message_visibility()
+                    .queue_url(&queue_url)
+                    .receipt_handle(receipt_handle)
+                    .visibility_timeout(visibility_timeout)
+                    .send()
+                    .await;
+                if let Err(err) = result {
+                    warn!("Failed to extend SQS visibility timeout: {err:?}");
+                }
+            }
+        }
+    });
+}
+
+/// Deletes messages from SQS once their output has been written.
+struct SqsAck {
+    /// Our SQS client.
+    client: Client,
+
+    /// The queue we're reading from.
+    queue_url: String,
+
+    /// Messages we're still processing.
+    in_flight: Arc<InFlight>,
+}
+
+#[async_trait]
+impl OutputAck for SqsAck {
+    #[instrument(level = "debug", skip_all, fields(id = %record["id"]))]
+    async fn ack(&self, record: &Value) -> Result<()> {
+        let Some(receipt_handle) = self.in_flight.take(&record["id"]) else {
+            warn!("No SQS message found for output record");
+            return Ok(());
+        };
+        let succeeded = serde_json::from_value::<WorkStatus>(record["status"].clone())
+            .is_ok_and(WorkStatus::is_success);
+        if !succeeded {
+            // Leave the message alone. Once the visibility timeout expires, it
+            // will be retried or dead-lettered.
+            debug!("Leaving failed record on SQS queue");
+            return Ok(());
+        }
+
+        // If this fails, the message will be delivered again, which is
+        // acceptable for an at-least-once queue.
+        let result = self
+            .client
+            .delete_message()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .send()
+            .await;
+        if let Err(err) = result {
+            warn!("Failed to delete SQS message: {err:?}");
+        }
+        Ok(())
+    }
+}
+
+impl IsKnownTransient for ReceiveMessageError {
+    fn is_known_transient(&self) -> bool {
+        matches!(
+            self,
+            ReceiveMessageError::KmsThrottled(_)
+                | ReceiveMessageError::RequestThrottled(_)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqs_queue_url() {
+        assert_eq!(
+            sqs_queue_url(Path::new(
+                "sqs://sqs.us-east-1.amazonaws.com/123456789012/jobs"
+            )),
+            Some("https://sqs.us-east-1.amazonaws.com/123456789012/jobs".to_owned())
+        );
+        assert_eq!(sqs_queue_url(Path::new("input.csv")), None);
+    }
+
+    #[test]
+    fn test_in_flight_matches_string_and_csv_ids() {
+        let in_flight = InFlight::default();
+        in_flight.insert(&json!(1), "a".to_owned());
+        in_flight.insert(&json!("x"), "b".to_owned());
+        in_flight.insert(&json!("x"), "c".to_owned());
+
+        // CSV output stringifies non-string IDs.
+        assert_eq!(in_flight.take(&json!("1")), Some("a".to_owned()));
+        assert_eq!(in_flight.take(&json!("x")), Some("b".to_owned()));
+        assert_eq!(in_flight.receipt_handles(), vec!["c".to_owned()]);
+        assert_eq!(in_flight.take(&json!("x")), Some("c".to_owned()));
+        assert_eq!(in_flight.take(&json!("x")), None);
+    }
+}
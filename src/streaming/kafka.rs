@@ -0,0 +1,290 @@
+//! Kafka source and sink.
+//!
+//! We disable automatic offset storage, and only store an offset once every
+//! earlier message in the same partition has had its output written. The
+//! stored offsets are then committed periodically by `librdkafka`.
+//!
+//! Each record carries an [`ACK_TOKEN_FIELD`] identifying its message, so we
+//! can find its partition and offset again even if IDs repeat.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use futures::{StreamExt as _, stream};
+use rdkafka::{
+    ClientConfig, Message as _, Offset, TopicPartitionList,
+    consumer::{Consumer as _, StreamConsumer},
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+};
+
+use crate::{
+    async_utils::io::{ACK_TOKEN_FIELD, JsonStream, OutputAck, id_key},
+    prelude::*,
+};
+
+use super::{StreamingSink, StreamingUrl};
+
+/// Default consumer group, if none is specified using `?group.id=...`.
+const DEFAULT_GROUP_ID: &str = "prompt-scaler";
+
+/// Build a [`ClientConfig`], passing through any URL parameters as
+/// `librdkafka` settings.
+fn client_config(url: &StreamingUrl) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config.set("bootstrap.servers", &url.servers);
+    for (key, value) in &url.params {
+        config.set(key, value);
+    }
+    config
+}
+
+/// Read input records from a Kafka topic.
+pub fn read_kafka(url: &StreamingUrl) -> Result<(JsonStream, Arc<dyn OutputAck>)> {
+    let mut config = client_config(url);
+    if url.param("group.id").is_none() {
+        config.set("group.id", DEFAULT_GROUP_ID);
+    }
+    if url.param("auto.offset.reset").is_none() {
+        config.set("auto.offset.reset", "earliest");
+    }
+    let consumer: StreamConsumer = config
+        .set("enable.auto.commit", "true")
+        .set("enable.auto.offset.store", "false")
+        .create()
+        .context("Failed to create Kafka consumer")?;
+    consumer
+        .subscribe(&[&url.topic])
+        .with_context(|| format!("Failed to subscribe to Kafka topic {:?}", url.topic))?;
+
+    let ack = Arc::new(KafkaAck {
+        consumer,
+        topic: url.topic.clone(),
+        tracker: OffsetTracker::default(),
+    });
+    let stream = stream::unfold(ack.clone(), |ack| async move {
+        let record = ack.next_record().await;
+        Some((record, ack))
+    })
+    .boxed();
+    Ok((stream, ack))
+}
+
+/// Offsets for a single partition.
+#[derive(Debug, Default)]
+struct PartitionOffsets {
+    /// Offsets we've received, but whose output hasn't been written.
+    in_flight: BTreeSet<i64>,
+
+    /// One past the highest offset we've completed.
+    completed_end: i64,
+
+    /// The last offset we stored for committing.
+    stored: i64,
+}
+
+/// Tracks in-flight Kafka messages.
+#[derive(Debug, Default)]
+struct OffsetTracker {
+    /// Our state, behind a lock.
+    state: Mutex<OffsetTrackerState>,
+}
+
+/// Internal state for [`OffsetTracker`].
+#[derive(Debug, Default)]
+struct OffsetTrackerState {
+    /// `(partition, offset)` for each in-flight record, keyed by ack token.
+    by_token: HashMap<u64, (i32, i64)>,
+
+    /// The ack token to give our next message.
+    next_token: u64,
+
+    /// Offsets for each partition.
+    partitions: HashMap<i32, PartitionOffsets>,
+}
+
+impl OffsetTracker {
+    /// Record a newly-received message, returning its ack token.
+    fn insert(&self, partition: i32, offset: i64) -> u64 {
+        let mut state = self.state.lock().expect("lock poisoned");
+        let token = state.next_token;
+        state.next_token += 1;
+        state.by_token.insert(token, (partition, offset));
+        state
+            .partitions
+            .entry(partition)
+            .or_default()
+            .in_flight
+            .insert(offset);
+        token
+    }
+
+    /// Mark the message with `token` as complete. If this allows us to advance
+    /// the stored offset for a partition, returns `(partition, offset)`.
+    fn complete(&self, token: u64) -> Option<(i32, i64)> {
+        let mut state = self.state.lock().expect("lock poisoned");
+        let (partition, offset) = state.by_token.remove(&token)?;
+        state.complete_offset(partition, offset)
+    }
+
+    /// Mark a message that we're not going to process as complete.
+    fn skip(&self, partition: i32, offset: i64) -> Option<(i32, i64)> {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.complete_offset(partition, offset)
+    }
+}
+
+impl OffsetTrackerState {
+    /// Mark an offset as complete, returning the new offset to store, if any.
+    fn complete_offset(&mut self, partition: i32, offset: i64) -> Option<(i32, i64)> {
+        let offsets = self.partitions.entry(partition).or_default();
+        offsets.in_flight.remove(&offset);
+        offsets.completed_end = offsets.completed_end.max(offset + 1);
+        let next = offsets
+            .in_flight
+            .first()
+            .copied()
+            .unwrap_or(offsets.completed_end);
+        if next > offsets.stored {
+            offsets.stored = next;
+            Some((partition, next))
+        } else {
+            None
+        }
+    }
+}
+
+/// Stores Kafka offsets once output has been written.
+struct KafkaAck {
+    /// Our consumer.
+    consumer: StreamConsumer,
+
+    /// The topic we're reading from.
+    topic: String,
+
+    /// Our in-flight messages.
+    tracker: OffsetTracker,
+}
+
+impl KafkaAck {
+    /// Receive the next parseable record from Kafka.
+    async fn next_record(&self) -> Result<Value> {
+        loop {
+            let message = self
+                .consumer
+                .recv()
+                .await
+                .context("Failed to receive Kafka message")?;
+            let (partition, offset) = (message.partition(), message.offset());
+            let payload = message.payload().unwrap_or_default();
+            let err = match serde_json::from_slice::<Value>(payload) {
+                Ok(Value::Object(mut record)) => {
+                    let token = self.tracker.insert(partition, offset);
+                    record.insert(ACK_TOKEN_FIELD.to_owned(), Value::from(token));
+                    return Ok(Value::Object(record));
+                }
+                Ok(_) => "expected a JSON object".to_owned(),
+                Err(err) => err.to_string(),
+            };
+
+            // This will never succeed, so skip it.
+            error!(
+                partition,
+                offset, "Skipping unparseable Kafka message: {err}"
+            );
+            if let Some((partition, offset)) = self.tracker.skip(partition, offset) {
+                self.store_offset(partition, offset)?;
+            }
+        }
+    }
+
+    /// Store an offset, to be committed by `librdkafka`.
+    fn store_offset(&self, partition: i32, offset: i64) -> Result<()> {
+        let mut tpl = TopicPartitionList::new();
+        tpl.add_partition_offset(&self.topic, partition, Offset::Offset(offset))
+            .context("Failed to build Kafka offset list")?;
+        // This may fail if our partition was reassigned. In that case, the new
+        // owner will re-process the message, which is acceptable.
+        if let Err(err) = self.consumer.store_offsets(&tpl) {
+            warn!(partition, offset, "Failed to store Kafka offset: {err}");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutputAck for KafkaAck {
+    #[instrument(level = "debug", skip_all, fields(id = %record["id"]))]
+    async fn ack(&self, record: &Value) -> Result<()> {
+        let Some(token) = record.get(ACK_TOKEN_FIELD).and_then(Value::as_u64) else {
+            warn!("No Kafka message found for output record");
+            return Ok(());
+        };
+        match self.tracker.complete(token) {
+            Some((partition, offset)) => self.store_offset(partition, offset),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes output records to a Kafka topic.
+pub struct KafkaSink {
+    /// Our producer.
+    producer: FutureProducer,
+
+    /// The topic to write to.
+    topic: String,
+}
+
+impl KafkaSink {
+    /// Create a new Kafka sink.
+    pub fn new(url: &StreamingUrl) -> Result<Self> {
+        let producer = client_config(url)
+            .create()
+            .context("Failed to create Kafka producer")?;
+        Ok(Self {
+            producer,
+            topic: url.topic.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl StreamingSink for KafkaSink {
+    async fn publish(&self, record: &Value) -> Result<()> {
+        let key = id_key(&record["id"]);
+        let payload =
+            serde_json::to_string(record).context("Failed to serialize output")?;
+        self.producer
+            .send(
+                FutureRecord::to(&self.topic).key(&key).payload(&payload),
+                Timeout::Never,
+            )
+            .await
+            .map_err(|(err, _)| err)
+            .context("Failed to write Kafka message")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offsets_wait_for_earlier_messages() {
+        let tracker = OffsetTracker::default();
+        let a = tracker.insert(0, 10);
+        let b = tracker.insert(0, 11);
+        let c = tracker.insert(1, 5);
+
+        // Completing a later message can't advance past an earlier one.
+        assert_eq!(tracker.complete(b), Some((0, 10)));
+        assert_eq!(tracker.complete(a), Some((0, 12)));
+        assert_eq!(tracker.complete(c), Some((1, 6)));
+        assert_eq!(tracker.complete(c), None);
+        assert_eq!(tracker.skip(1, 6), Some((1, 7)));
+    }
+}
@@ -0,0 +1,223 @@
+//! Streaming sources and sinks for event pipelines.
+//!
+//! These are optional, and are only available when `prompt-scaler` is built
+//! with the `kafka` or `nats` features. We use URLs of the form:
+//!
+//! - `kafka://broker1:9092,broker2:9092/topic?group.id=my-group`
+//! - `nats://localhost:4222/subject?durable=my-consumer`
+//!
+//! When reading, input positions are only committed (or messages
+//! acknowledged) after the corresponding output record has been written, so
+//! that we get at-least-once semantics across restarts.
+
+use std::sync::Arc;
+
+use futures::{StreamExt as _, TryStreamExt as _};
+
+use crate::{
+    async_utils::io::{JsonStream, OutputAck},
+    prelude::*,
+};
+
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "nats")]
+mod nats;
+
+/// How many output records may be in the process of being published at once.
+const SINK_CONCURRENCY: usize = 64;
+
+/// What kind of streaming system are we talking to?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamingScheme {
+    /// Apache Kafka.
+    Kafka,
+    /// NATS JetStream.
+    Nats,
+}
+
+impl StreamingScheme {
+    /// The URL prefix for this scheme.
+    fn prefix(self) -> &'static str {
+        match self {
+            StreamingScheme::Kafka => "kafka://",
+            StreamingScheme::Nats => "nats://",
+        }
+    }
+}
+
+/// A parsed `kafka://` or `nats://` URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamingUrl {
+    /// The kind of server we're talking to.
+    pub scheme: StreamingScheme,
+
+    /// A comma-separated list of `host:port` servers.
+    pub servers: String,
+
+    /// The Kafka topic or NATS subject.
+    pub topic: String,
+
+    /// Any query parameters.
+    pub params: Vec<(String, String)>,
+}
+
+impl StreamingUrl {
+    /// Parse a [`StreamingUrl`] from a path. Returns `None` if this is not a
+    /// streaming URL at all.
+    pub fn from_path(path: &Path) -> Result<Option<Self>> {
+        let Some(path) = path.to_str() else {
+            return Ok(None);
+        };
+        let Some((scheme, rest)) = [StreamingScheme::Kafka, StreamingScheme::Nats]
+            .into_iter()
+            .find_map(|scheme| Some((scheme, path.strip_prefix(scheme.prefix())?)))
+        else {
+            return Ok(None);
+        };
+
+        let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (servers, topic) = rest
+            .split_once('/')
+            .filter(|(servers, topic)| !servers.is_empty() && !topic.is_empty())
+            .ok_or_else(|| {
+                anyhow!("expected {}servers/topic, got {path:?}", scheme.prefix())
+            })?;
+        let params = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (key, value) = param
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("expected key=value in {path:?}"))?;
+                Ok((key.to_owned(), value.to_owned()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Self {
+            scheme,
+            servers: servers.to_owned(),
+            topic: topic.to_owned(),
+            params,
+        }))
+    }
+
+    /// Look up a query parameter.
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Something we can publish output records to.
+#[async_trait]
+trait StreamingSink: Send + Sync {
+    /// Publish a record, waiting until the server has accepted it.
+    async fn publish(&self, record: &Value) -> Result<()>;
+}
+
+/// Read input records from a streaming source.
+///
+/// Returns a stream of JSON records, plus an [`OutputAck`] which must be
+/// called once each record's output has been written.
+#[instrument(level = "debug")]
+pub async fn read_streaming_input(
+    url: &StreamingUrl,
+) -> Result<(JsonStream, Arc<dyn OutputAck>)> {
+    match url.scheme {
+        #[cfg(feature = "kafka")]
+        StreamingScheme::Kafka => kafka::read_kafka(url),
+        #[cfg(feature = "nats")]
+        StreamingScheme::Nats => nats::read_nats(url).await,
+        #[allow(unreachable_patterns)]
+        scheme => Err(not_built_with(scheme)),
+    }
+}
+
+/// Write output records to a streaming sink.
+///
+/// If `ack` is provided, each record is acknowledged once the server has
+/// accepted it. We publish several records at once, but acknowledge them in
+/// order, like our other writers.
+#[instrument(level = "debug", skip(stream, ack))]
+pub async fn write_streaming_output(
+    url: &StreamingUrl,
+    stream: JsonStream,
+    ack: Option<&dyn OutputAck>,
+) -> Result<()> {
+    let sink = open_sink(url).await?;
+    let sink = sink.as_ref();
+    stream
+        .map(|record| async move {
+            let record = record?;
+            sink.publish(&record).await?;
+            Ok::<_, anyhow::Error>(record)
+        })
+        .buffered(SINK_CONCURRENCY)
+        .try_for_each(|record| async move {
+            if let Some(ack) = ack {
+                ack.ack(&record).await?;
+            }
+            Ok(())
+        })
+        .await
+}
+
+/// Open a [`StreamingSink`] for `url`.
+async fn open_sink(url: &StreamingUrl) -> Result<Box<dyn StreamingSink>> {
+    match url.scheme {
+        #[cfg(feature = "kafka")]
+        StreamingScheme::Kafka => Ok(Box::new(kafka::KafkaSink::new(url)?)),
+        #[cfg(feature = "nats")]
+        StreamingScheme::Nats => Ok(Box::new(nats::NatsSink::new(url).await?)),
+        #[allow(unreachable_patterns)]
+        scheme => Err(not_built_with(scheme)),
+    }
+}
+
+/// Error to return when we weren't built with support for `scheme`.
+#[allow(dead_code)]
+fn not_built_with(scheme: StreamingScheme) -> anyhow::Error {
+    let feature = match scheme {
+        StreamingScheme::Kafka => "kafka",
+        StreamingScheme::Nats => "nats",
+    };
+    anyhow!(
+        "{}... URLs require prompt-scaler to be built with `--features {feature}`",
+        scheme.prefix()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_streaming_url() {
+        let url = StreamingUrl::from_path(Path::new(
+            "kafka://b1:9092,b2:9092/jobs?group.id=ocr&security.protocol=SSL",
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(url.scheme, StreamingScheme::Kafka);
+        assert_eq!(url.servers, "b1:9092,b2:9092");
+        assert_eq!(url.topic, "jobs");
+        assert_eq!(url.param("group.id"), Some("ocr"));
+        assert_eq!(url.param("security.protocol"), Some("SSL"));
+        assert_eq!(url.param("missing"), None);
+
+        let url = StreamingUrl::from_path(Path::new("nats://localhost:4222/jobs.in"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(url.scheme, StreamingScheme::Nats);
+        assert_eq!(url.topic, "jobs.in");
+        assert!(url.params.is_empty());
+
+        assert_eq!(
+            StreamingUrl::from_path(Path::new("in.jsonl")).unwrap(),
+            None
+        );
+        assert!(StreamingUrl::from_path(Path::new("kafka://b1:9092")).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! NATS JetStream source and sink.
+//!
+//! We use a durable pull consumer with explicit acknowledgements, and only
+//! acknowledge a message once its output has been written. Each record carries
+//! an [`ACK_TOKEN_FIELD`] identifying its message, so we acknowledge the right
+//! one even if IDs repeat.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_nats::jetstream::{
+    self, AckKind,
+    consumer::{AckPolicy, pull},
+};
+use futures::StreamExt as _;
+
+use crate::{
+    async_utils::io::{ACK_TOKEN_FIELD, JsonStream, OutputAck},
+    prelude::*,
+};
+
+use super::{StreamingSink, StreamingUrl};
+
+/// Default durable consumer name, if none is specified using `?durable=...`.
+const DEFAULT_DURABLE_NAME: &str = "prompt-scaler";
+
+/// Default time to wait for an acknowledgement before redelivering a message,
+/// in seconds. This needs to be long enough for slow OCR jobs.
+const DEFAULT_ACK_WAIT_SECS: u64 = 300;
+
+/// Connect to JetStream.
+async fn connect(url: &StreamingUrl) -> Result<jetstream::Context> {
+    let client = async_nats::connect(url.servers.as_str())
+        .await
+        .map_err(|err| {
+            anyhow!("Failed to connect to NATS at {:?}: {err}", url.servers)
+        })?;
+    Ok(jetstream::new(client))
+}
+
+/// Read input records from a NATS JetStream subject.
+pub async fn read_nats(url: &StreamingUrl) -> Result<(JsonStream, Arc<dyn OutputAck>)> {
+    let ack_wait = match url.param("ack_wait") {
+        Some(secs) => secs
+            .parse::<u64>()
+            .with_context(|| format!("Invalid ack_wait: {secs:?}"))?,
+        None => DEFAULT_ACK_WAIT_SECS,
+    };
+    let durable = url.param("durable").unwrap_or(DEFAULT_DURABLE_NAME);
+
+    let context = connect(url).await?;
+    let stream_name = context
+        .stream_by_subject(url.topic.as_str())
+        .await
+        .map_err(|err| anyhow!("No JetStream stream for {:?}: {err}", url.topic))?;
+    let stream = context.get_stream(&stream_name).await.map_err(|err| {
+        anyhow!("Failed to get JetStream stream {stream_name:?}: {err}")
+    })?;
+    let consumer: pull::Consumer = stream
+        .get_or_create_consumer(
+            durable,
+            pull::Config {
+                durable_name: Some(durable.to_owned()),
+                filter_subject: url.topic.clone(),
+                ack_policy: AckPolicy::Explicit,
+                ack_wait: Duration::from_secs(ack_wait),
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|err| {
+            anyhow!("Failed to create JetStream consumer {durable:?}: {err}")
+        })?;
+    let messages = consumer
+        .messages()
+        .await
+        .map_err(|err| anyhow!("Failed to read from JetStream: {err}"))?;
+
+    let ack = Arc::new(NatsAck::default());
+    let ack_for_stream = ack.clone();
+    let stream = messages
+        .filter_map(move |message| {
+            let ack = ack_for_stream.clone();
+            async move {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(err) => {
+                        return Some(Err(anyhow!(
+                            "Failed to receive NATS message: {err}"
+                        )));
+                    }
+                };
+                let err = match serde_json::from_slice::<Value>(&message.payload) {
+                    Ok(Value::Object(mut record)) => {
+                        let token = ack.insert(message);
+                        record.insert(ACK_TOKEN_FIELD.to_owned(), Value::from(token));
+                        return Some(Ok(Value::Object(record)));
+                    }
+                    Ok(_) => "expected a JSON object".to_owned(),
+                    Err(err) => err.to_string(),
+                };
+                // This will never succeed, so tell the server not to redeliver
+                // it.
+                error!("Terminating unparseable NATS message: {err}");
+                if let Err(err) = message.ack_with(AckKind::Term).await {
+                    warn!("Failed to terminate NATS message: {err}");
+                }
+                None
+            }
+        })
+        .boxed();
+    Ok((stream, ack))
+}
+
+/// Acknowledges NATS messages once output has been written.
+#[derive(Default)]
+struct NatsAck {
+    /// Our in-flight messages.
+    state: Mutex<NatsAckState>,
+}
+
+/// Internal state for [`NatsAck`].
+#[derive(Default)]
+struct NatsAckState {
+    /// Messages we're still processing, keyed by ack token.
+    messages: HashMap<u64, jetstream::Message>,
+
+    /// The ack token to give our next message.
+    next_token: u64,
+}
+
+impl NatsAck {
+    /// Record a newly-received message, returning its ack token.
+    fn insert(&self, message: jetstream::Message) -> u64 {
+        let mut state = self.state.lock().expect("lock poisoned");
+        let token = state.next_token;
+        state.next_token += 1;
+        state.messages.insert(token, message);
+        token
+    }
+
+    /// Stop tracking a message.
+    fn take(&self, token: u64) -> Option<jetstream::Message> {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.messages.remove(&token)
+    }
+}
+
+#[async_trait]
+impl OutputAck for NatsAck {
+    #[instrument(level = "debug", skip_all, fields(id = %record["id"]))]
+    async fn ack(&self, record: &Value) -> Result<()> {
+        let message = record
+            .get(ACK_TOKEN_FIELD)
+            .and_then(Value::as_u64)
+            .and_then(|token| self.take(token));
+        let Some(message) = message else {
+            warn!("No NATS message found for output record");
+            return Ok(());
+        };
+        // If this fails, the message will be redelivered, which is acceptable.
+        if let Err(err) = message.ack().await {
+            warn!("Failed to acknowledge NATS message: {err}");
+        }
+        Ok(())
+    }
+}
+
+/// Writes output records to a NATS JetStream subject.
+pub struct NatsSink {
+    /// Our JetStream context.
+    context: jetstream::Context,
+
+    /// The subject to publish to.
+    subject: String,
+}
+
+impl NatsSink {
+    /// Create a new NATS sink.
+    pub async fn new(url: &StreamingUrl) -> Result<Self> {
+        Ok(Self {
+            context: connect(url).await?,
+            subject: url.topic.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl StreamingSink for NatsSink {
+    async fn publish(&self, record: &Value) -> Result<()> {
+        let payload = serde_json::to_vec(record).context("Failed to serialize output")?;
+        self.context
+            .publish(self.subject.clone(), payload.into())
+            .await
+            .map_err(|err| anyhow!("Failed to publish NATS message: {err}"))?
+            .await
+            .map_err(|err| anyhow!("NATS did not acknowledge message: {err}"))?;
+        Ok(())
+    }
+}
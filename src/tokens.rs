@@ -0,0 +1,291 @@
+//! Prompt token counting.
+//!
+//! This lets us see how large a rendered prompt will be for a given model
+//! before we spend any money, so we can choose `--truncate-field` and DPI
+//! settings, and so `chat` can check context windows and cost caps. OpenAI
+//! text is counted exactly with `tiktoken`. Other providers don't publish
+//! their tokenizers, so we fall back to a rough estimate.
+
+use std::{fmt, io::Cursor};
+
+use image::ImageReader;
+use tiktoken_rs::CoreBPE;
+
+use crate::{
+    num_utils::f64_to_usize,
+    prelude::*,
+    prompt::{
+        ChatPrompt, ESTIMATED_BYTES_PER_TOKEN, ESTIMATED_TOKENS_PER_IMAGE, Message,
+        Rendered, assistant_text,
+    },
+    prompt_image::{ImageDetail, PromptImage},
+};
+
+/// Tokens added by OpenAI's chat format for each message.
+const OPENAI_TOKENS_PER_MESSAGE: usize = 3;
+
+/// Tokens added by OpenAI's chat format to prime the assistant's reply.
+const OPENAI_TOKENS_PER_REPLY: usize = 3;
+
+/// Tokens used by OpenAI for an image with `detail = "low"`, regardless of size.
+const OPENAI_LOW_DETAIL_IMAGE_TOKENS: usize = 85;
+
+/// A family of models which count tokens the same way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelFamily {
+    /// OpenAI GPT and `o`-series models.
+    OpenAi,
+    /// Anthropic Claude models, including via Bedrock.
+    Anthropic,
+    /// Google Gemini and Gemma models.
+    Gemini,
+    /// Anything else.
+    Other,
+}
+
+impl ModelFamily {
+    /// Guess the family of `model`, ignoring any `provider/` prefix.
+    pub fn for_model(model: &str) -> Self {
+        let name = model.rsplit('/').next().unwrap_or(model);
+        let is_o_series = name
+            .strip_prefix('o')
+            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()));
+        if name.starts_with("gpt-") || name.starts_with("chatgpt-") || is_o_series {
+            ModelFamily::OpenAi
+        } else if name.contains("claude") {
+            ModelFamily::Anthropic
+        } else if name.starts_with("gemini") || name.starts_with("gemma") {
+            ModelFamily::Gemini
+        } else {
+            ModelFamily::Other
+        }
+    }
+
+    /// Estimate the tokens used by an image of the given size, using each
+    /// provider's published formula. Without a size, we use our usual rough
+    /// estimate.
+    pub fn image_tokens(self, size: Option<(u32, u32)>) -> usize {
+        let Some((width, height)) = size else {
+            return ESTIMATED_TOKENS_PER_IMAGE;
+        };
+        let (width, height) = (f64::from(width), f64::from(height));
+        let tokens = match self {
+            ModelFamily::OpenAi => {
+                // Fit within 2048x2048, shrink the short side to 768, then
+                // count 512px tiles.
+                let scale = (2048.0 / width.max(height)).min(1.0);
+                let (width, height) = (width * scale, height * scale);
+                let scale = (768.0 / width.min(height)).min(1.0);
+                let (width, height) = (width * scale, height * scale);
+                let tiles = (width / 512.0).ceil() * (height / 512.0).ceil();
+                85.0 + 170.0 * tiles
+            }
+            ModelFamily::Anthropic => {
+                // Fit the long edge within 1568px, then 1 token per 750px.
+                let scale = (1568.0 / width.max(height)).min(1.0);
+                (width * scale * height * scale / 750.0).ceil()
+            }
+            ModelFamily::Gemini if width <= 384.0 && height <= 384.0 => 258.0,
+            ModelFamily::Gemini => {
+                // Larger images are cropped into 768px tiles.
+                (width / 768.0).ceil() * (height / 768.0).ceil() * 258.0
+            }
+            ModelFamily::Other => return ESTIMATED_TOKENS_PER_IMAGE,
+        };
+        // Each formula rounds up, so this only fails for images far too large
+        // for any provider to accept.
+        f64_to_usize(tokens).unwrap_or(ESTIMATED_TOKENS_PER_IMAGE)
+    }
+}
+
+/// Token counts for a rendered prompt.
+#[derive(Clone, Debug, Serialize)]
+pub struct PromptTokenCount {
+    /// The model we counted tokens for.
+    pub model: String,
+
+    /// The model's family.
+    pub family: ModelFamily,
+
+    /// Was `text_tokens` counted with the model's actual tokenizer?
+    pub exact_text: bool,
+
+    /// Tokens used by text, including the response schema.
+    pub text_tokens: usize,
+
+    /// The number of images.
+    pub images: usize,
+
+    /// Estimated tokens used by images.
+    pub image_tokens: usize,
+
+    /// Total input tokens.
+    pub total_tokens: usize,
+}
+
+/// Counts prompt tokens for a single model.
+pub struct TokenCounter {
+    /// The model.
+    model: String,
+
+    /// The model's family.
+    family: ModelFamily,
+
+    /// The model's tokenizer, if we have it.
+    bpe: Option<CoreBPE>,
+}
+
+impl fmt::Debug for TokenCounter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenCounter")
+            .field("model", &self.model)
+            .field("family", &self.family)
+            .field("exact", &self.bpe.is_some())
+            .finish()
+    }
+}
+
+impl TokenCounter {
+    /// Create a token counter for `model`.
+    pub fn new(model: &str) -> Result<Self> {
+        let family = ModelFamily::for_model(model);
+        let bpe = match family {
+            ModelFamily::OpenAi => {
+                let name = model.rsplit('/').next().unwrap_or(model);
+                // Newer models may not be known yet, but all use `o200k_base`.
+                Some(
+                    tiktoken_rs::get_bpe_from_model(name)
+                        .or_else(|_| tiktoken_rs::o200k_base())
+                        .map_err(|err| anyhow!("cannot load tokenizer: {err}"))?,
+                )
+            }
+            _ => None,
+        };
+        Ok(Self {
+            model: model.to_owned(),
+            family,
+            bpe,
+        })
+    }
+
+    /// Do we count text tokens exactly, using the model's own tokenizer?
+    pub fn is_exact(&self) -> bool {
+        self.bpe.is_some()
+    }
+
+    /// Count the tokens in `text`.
+    pub fn count_text(&self, text: &str) -> usize {
+        match &self.bpe {
+            Some(bpe) => bpe.encode_with_special_tokens(text).len(),
+            None => text.len().div_ceil(ESTIMATED_BYTES_PER_TOKEN),
+        }
+    }
+
+    /// Count the input tokens for `prompt`, including the response `schema`.
+    pub fn count_prompt(
+        &self,
+        prompt: &ChatPrompt<Rendered>,
+        schema: &Value,
+    ) -> PromptTokenCount {
+        let mut texts = vec![schema.to_string()];
+        texts.extend(prompt.developer.clone());
+        let mut images = 0;
+        let mut image_tokens = 0;
+        for message in &prompt.messages {
+            match message {
+                Message::User {
+                    text,
+                    images: msg_images,
+                } => {
+                    texts.extend(text.clone());
+                    for image in msg_images {
+                        images += 1;
+                        image_tokens += match image.detail {
+                            Some(ImageDetail::Low)
+                                if self.family == ModelFamily::OpenAi =>
+                            {
+                                OPENAI_LOW_DETAIL_IMAGE_TOKENS
+                            }
+                            _ => self.family.image_tokens(image_size(image)),
+                        };
+                    }
+                }
+                Message::Assistant { json } => texts.push(assistant_text(json)),
+            }
+        }
+
+        let mut text_tokens: usize = texts.iter().map(|text| self.count_text(text)).sum();
+        if self.bpe.is_some() {
+            text_tokens += OPENAI_TOKENS_PER_MESSAGE
+                * (prompt.messages.len() + usize::from(prompt.developer.is_some()))
+                + OPENAI_TOKENS_PER_REPLY;
+        }
+        PromptTokenCount {
+            model: self.model.clone(),
+            family: self.family,
+            exact_text: self.bpe.is_some(),
+            text_tokens,
+            images,
+            image_tokens,
+            total_tokens: text_tokens + image_tokens,
+        }
+    }
+}
+
+/// Get the size of an image, if it's embedded and in a format we understand.
+fn image_size(image: &PromptImage) -> Option<(u32, u32)> {
+    let data = image.data()?;
+    ImageReader::new(Cursor::new(&data.bytes[..]))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_families() {
+        assert_eq!(ModelFamily::for_model("gpt-4o-mini"), ModelFamily::OpenAi);
+        assert_eq!(
+            ModelFamily::for_model("openai/o3-mini"),
+            ModelFamily::OpenAi
+        );
+        assert_eq!(
+            ModelFamily::for_model("us.anthropic.claude-sonnet-4-20250514-v1:0"),
+            ModelFamily::Anthropic
+        );
+        assert_eq!(
+            ModelFamily::for_model("gemini-2.0-flash"),
+            ModelFamily::Gemini
+        );
+        assert_eq!(ModelFamily::for_model("ollama"), ModelFamily::Other);
+    }
+
+    #[test]
+    fn image_token_estimates() {
+        assert_eq!(ModelFamily::OpenAi.image_tokens(Some((1024, 1024))), 765);
+        assert_eq!(ModelFamily::OpenAi.image_tokens(Some((2048, 4096))), 1105);
+        assert_eq!(
+            ModelFamily::Anthropic.image_tokens(Some((1000, 1000))),
+            1334
+        );
+        assert_eq!(ModelFamily::Gemini.image_tokens(Some((300, 300))), 258);
+        assert_eq!(ModelFamily::Gemini.image_tokens(Some((1536, 800))), 1032);
+        assert_eq!(
+            ModelFamily::Other.image_tokens(Some((100, 100))),
+            ESTIMATED_TOKENS_PER_IMAGE
+        );
+    }
+
+    #[test]
+    fn openai_text_is_counted_exactly() {
+        let counter = TokenCounter::new("gpt-4o").unwrap();
+        assert_eq!(counter.count_text("Hello, world!"), 4);
+        let counter = TokenCounter::new("claude-3-5-haiku-20241022").unwrap();
+        assert_eq!(counter.count_text("Hello, world!"), 4);
+    }
+}
@@ -3,11 +3,41 @@
 //! This is adapted from `substudy` by Eric Kidd, which is licensed under
 //! Apache-2.0 OR MIT. Used with permission.
 
-use std::{borrow::Cow, io, sync::Arc, time::Duration};
+use std::{
+    borrow::Cow,
+    io,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
 
+use clap::ValueEnum;
 use indicatif::{
     MultiProgress, ProgressBar, ProgressDrawTarget, ProgressFinish, ProgressStyle,
 };
+use tracing::info;
+
+/// How to show progress.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    /// Draw progress bars if stderr is a terminal, and log otherwise.
+    #[default]
+    Auto,
+    /// Always draw progress bars.
+    Bar,
+    /// Periodically log progress lines, for CI and other non-interactive
+    /// environments where progress bars are invisible.
+    Log,
+}
+
+/// State for logging progress instead of drawing progress bars.
+#[derive(Debug, Default)]
+struct ProgressLog {
+    /// How often to log progress, if we're logging progress.
+    interval: OnceLock<Duration>,
+
+    /// Failures and estimated cost (in US dollars) so far.
+    counts: Mutex<(usize, f64)>,
+}
 
 /// Application UI state.
 #[derive(Clone)]
@@ -16,13 +46,19 @@ pub struct Ui {
     /// I'm playing it safe until I understand `MultiProgress` and `tokio`
     /// interactions better.
     multi_progress: Arc<MultiProgress>,
+
+    /// Our progress log state, shared between clones.
+    progress_log: Arc<ProgressLog>,
 }
 
 impl Ui {
     /// Create a new UI. This sets up logging and and progress bars.
     pub fn init() -> Ui {
         let multi_progress = Arc::new(MultiProgress::new());
-        Ui { multi_progress }
+        Ui {
+            multi_progress,
+            progress_log: Arc::default(),
+        }
     }
 
     /// Create a new UI for unit tests.returns_right_number_of_subs
@@ -31,7 +67,54 @@ impl Ui {
     pub fn init_for_tests() -> Ui {
         let multi_progress =
             Arc::new(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()));
-        Ui { multi_progress }
+        Ui {
+            multi_progress,
+            progress_log: Arc::default(),
+        }
+    }
+
+    /// Log progress every `interval` instead of drawing progress bars. This
+    /// should be called before creating any progress bars.
+    pub fn log_progress(&self, interval: Duration) {
+        self.hide_progress_bars();
+        self.progress_log.interval.get_or_init(|| interval);
+    }
+
+    /// Record failures and estimated cost so far, for progress log lines.
+    pub fn set_progress_counts(&self, failure_count: usize, cost_estimate: f64) {
+        *self.progress_log.counts.lock().expect("lock poisoned") =
+            (failure_count, cost_estimate);
+    }
+
+    /// If we're logging progress, log the progress of `pb` periodically until
+    /// it finishes.
+    fn spawn_progress_log(&self, pb: &ProgressBar, msg: &str) {
+        let Some(interval) = self.progress_log.interval.get().copied() else {
+            return;
+        };
+        let pb = pb.clone();
+        let msg = msg.to_owned();
+        let progress_log = self.progress_log.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let finished = pb.is_finished();
+                let (failures, cost) =
+                    *progress_log.counts.lock().expect("lock poisoned");
+                info!(
+                    done = pb.position(),
+                    total = ?pb.length(),
+                    failures,
+                    cost = format!("US${cost:.4}"),
+                    rate = format!("{:.2}/s", pb.per_sec()),
+                    elapsed_secs = pb.elapsed().as_secs(),
+                    "{msg}"
+                );
+                if finished {
+                    break;
+                }
+            }
+        });
     }
 
     /// Hide all our progress bars completely, for when we're writing actual
@@ -61,6 +144,7 @@ impl Ui {
         pb.set_prefix(config.emoji.to_owned());
         pb.set_message(config.msg.to_owned());
         pb.enable_steady_tick(Duration::from_millis(250));
+        self.spawn_progress_log(&pb, config.msg);
         pb.with_finish(ProgressFinish::WithMessage(Cow::Owned(
             config.done_msg.to_owned(),
         )))
@@ -75,6 +159,7 @@ impl Ui {
         sp.set_prefix(config.emoji.to_owned());
         sp.set_message(config.msg.to_owned());
         sp.enable_steady_tick(Duration::from_millis(250));
+        self.spawn_progress_log(&sp, config.msg);
         sp.with_finish(ProgressFinish::WithMessage(Cow::Owned(
             config.done_msg.to_owned(),
         )))
@@ -98,6 +183,10 @@ impl Ui {
     /// Display a message to the user. This is formatted like a spinner
     /// for consistency.
     pub fn display_message(&self, emoji: &str, msg: &str) {
+        if self.progress_log.interval.get().is_some() {
+            info!("{emoji} {msg}");
+            return;
+        }
         let mut sp = ProgressBar::new_spinner().with_style(default_spinner_style());
         sp = self.multi_progress.add(sp);
         #[cfg(test)]
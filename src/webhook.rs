@@ -0,0 +1,300 @@
+//! Deliver output records to an HTTP endpoint as they are completed.
+//!
+//! This lets downstream systems react to records during a long batch, instead
+//! of waiting for the final output file. Each `POST` has a JSON body of the
+//! form `{"records": [...]}`. If `--webhook-secret-env` is set, the body is
+//! signed using HMAC-SHA256, and the signature is sent as
+//! `X-Prompt-Scaler-Signature: sha256=HEX`.
+
+use std::time::Duration;
+
+use clap::Args;
+use futures::StreamExt as _;
+use hmac::{Hmac, Mac as _};
+use reqwest::{Url, header::CONTENT_TYPE};
+use sha2::Sha256;
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, timeout_at},
+};
+
+use crate::{
+    async_utils::BoxedStream,
+    prelude::*,
+    queues::work::WorkOutput,
+    retry::{
+        DEFAULT_JITTER, retry_result_ok, retry_with_backoff, try_potentially_transient,
+    },
+};
+
+/// The header containing our signature.
+const SIGNATURE_HEADER: &str = "X-Prompt-Scaler-Signature";
+
+/// How long to hold a partial batch before sending it anyway.
+const MAX_BATCH_WAIT: Duration = Duration::from_secs(5);
+
+/// How many records may be waiting for delivery before we slow down output.
+const MAX_QUEUED_RECORDS: usize = 1024;
+
+/// How long to wait when connecting to our webhook.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for our webhook to respond to each request, before
+/// retrying it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Webhook options.
+#[derive(Args, Clone, Debug)]
+pub struct WebhookOpts {
+    /// `POST` completed output records to this HTTP(S) URL, in addition to
+    /// writing them to `--out`.
+    #[clap(long, value_name = "URL")]
+    pub webhook_url: Option<Url>,
+
+    /// How many records to send in each webhook request. Partial batches are
+    /// sent after a few seconds.
+    #[clap(long, value_name = "N", default_value = "1", requires = "webhook_url")]
+    pub webhook_batch_size: usize,
+
+    /// Sign webhook requests using HMAC-SHA256, with the secret stored in this
+    /// environment variable.
+    #[clap(long, value_name = "VAR", requires = "webhook_url")]
+    pub webhook_secret_env: Option<String>,
+}
+
+/// Delivers output records to `--webhook-url`, if set.
+pub struct Webhook {
+    /// Where to send records for delivery.
+    sender: Option<mpsc::Sender<Value>>,
+
+    /// Our delivery task.
+    worker: Option<JoinHandle<Result<()>>>,
+}
+
+impl Webhook {
+    /// Start delivering records, if `--webhook-url` is set.
+    pub fn start(opts: &WebhookOpts) -> Result<Self> {
+        let Some(url) = opts.webhook_url.clone() else {
+            return Ok(Self {
+                sender: None,
+                worker: None,
+            });
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("--webhook-url must be an http:// or https:// URL"));
+        }
+        if opts.webhook_batch_size == 0 {
+            return Err(anyhow!("--webhook-batch-size must be at least 1"));
+        }
+        let secret = opts
+            .webhook_secret_env
+            .as_deref()
+            .map(|var| {
+                std::env::var(var).with_context(|| {
+                    format!(
+                        "--webhook-secret-env: cannot read environment variable {var}"
+                    )
+                })
+            })
+            .transpose()?;
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("cannot create webhook HTTP client")?;
+        let delivery = Delivery {
+            http_client,
+            url,
+            secret,
+        };
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED_RECORDS);
+        let worker = tokio::spawn(delivery.run(receiver, opts.webhook_batch_size));
+        Ok(Self {
+            sender: Some(sender),
+            worker: Some(worker),
+        })
+    }
+
+    /// Wrap a stream of outputs, queueing each one for delivery.
+    pub fn wrap_stream<T>(
+        &self,
+        stream: BoxedStream<Result<WorkOutput<T>>>,
+    ) -> BoxedStream<Result<WorkOutput<T>>>
+    where
+        T: Serialize + Send + 'static,
+    {
+        let Some(sender) = self.sender.clone() else {
+            return stream;
+        };
+        stream
+            .then(move |output| {
+                let sender = sender.clone();
+                async move {
+                    if let Ok(output) = &output {
+                        match serde_json::to_value(output) {
+                            // If our worker has stopped, `finish` will report why.
+                            Ok(value) => _ = sender.send(value).await,
+                            Err(err) => {
+                                warn!("Cannot serialize record for webhook: {err:?}")
+                            }
+                        }
+                    }
+                    output
+                }
+            })
+            .boxed()
+    }
+
+    /// Wait for all queued records to be delivered. Fails if any could not be.
+    pub async fn finish(self) -> Result<()> {
+        let Self { sender, worker } = self;
+        drop(sender);
+        match worker {
+            Some(worker) => worker.await.context("webhook task panicked")?,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Sends batches of records to our webhook.
+struct Delivery {
+    /// Our HTTP client.
+    http_client: reqwest::Client,
+
+    /// Where to send records.
+    url: Url,
+
+    /// The secret used to sign requests, if any.
+    secret: Option<String>,
+}
+
+impl Delivery {
+    /// Deliver records from `receiver` in batches, until it is closed.
+    async fn run(
+        self,
+        mut receiver: mpsc::Receiver<Value>,
+        batch_size: usize,
+    ) -> Result<()> {
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut deadline = Instant::now();
+        let mut failed_records = 0;
+        loop {
+            let record = if batch.is_empty() {
+                receiver.recv().await
+            } else {
+                match timeout_at(deadline, receiver.recv()).await {
+                    Ok(record) => record,
+                    Err(_) => {
+                        failed_records += self.send_batch(&mut batch).await;
+                        continue;
+                    }
+                }
+            };
+            let Some(record) = record else {
+                break;
+            };
+            if batch.is_empty() {
+                deadline = Instant::now() + MAX_BATCH_WAIT;
+            }
+            batch.push(record);
+            if batch.len() >= batch_size {
+                failed_records += self.send_batch(&mut batch).await;
+            }
+        }
+        if !batch.is_empty() {
+            failed_records += self.send_batch(&mut batch).await;
+        }
+        if failed_records > 0 {
+            return Err(anyhow!(
+                "could not deliver {failed_records} records to --webhook-url {}",
+                self.url
+            ));
+        }
+        Ok(())
+    }
+
+    /// Send and clear `batch`, returning the number of records we failed to
+    /// deliver.
+    async fn send_batch(&self, batch: &mut Vec<Value>) -> usize {
+        let count = batch.len();
+        let body = json!({ "records": batch }).to_string();
+        batch.clear();
+        match self.post(body).await {
+            Ok(()) => {
+                debug!(count, "Delivered records to webhook");
+                0
+            }
+            Err(err) => {
+                error!("Could not deliver {count} records to webhook: {err:?}");
+                count
+            }
+        }
+    }
+
+    /// `POST` a request body, retrying transient failures.
+    #[instrument(level = "debug", skip_all)]
+    async fn post(&self, body: String) -> Result<()> {
+        let signature = self
+            .secret
+            .as_deref()
+            .map(|secret| sign(secret.as_bytes(), body.as_bytes()));
+        let (body, signature) = (&body, &signature);
+        retry_with_backoff(DEFAULT_JITTER, move || async move {
+            let mut request = self
+                .http_client
+                .post(self.url.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.to_owned());
+            if let Some(signature) = signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+            try_potentially_transient!(
+                request
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+            );
+            retry_result_ok::<_, anyhow::Error>(())
+        })
+        .await
+        .into_result()
+    }
+}
+
+/// Compute the value of our signature header for `body`.
+fn sign(secret: &[u8], body: &[u8]) -> String {
+    format!("sha256={}", hex::encode(hmac_sha256(secret, body)))
+}
+
+/// Compute an HMAC-SHA256 (RFC 2104) of `message` using `key`.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex::encode(hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
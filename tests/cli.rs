@@ -94,6 +94,23 @@ fn test_chat_text_jsonl_input_litellm() {
         .success();
 }
 
+#[test]
+#[ignore = "Needs LiteLLM running"]
+fn test_chat_litellm_env_vars_and_headers() {
+    cmd()
+        .env("LITELLM_API_KEY", LITELLM_API_KEY)
+        .env("LITELLM_API_BASE", LITELLM_API_BASE)
+        .env("OPENAI_API_BASE", "http://localhost:1/v1")
+        .arg("--header")
+        .arg(format!("x-litellm-api-key: Bearer {LITELLM_API_KEY}"))
+        .arg("chat")
+        .arg("tests/fixtures/texts/input.jsonl")
+        .arg("--prompt")
+        .arg("tests/fixtures/texts/prompt.toml")
+        .assert()
+        .success();
+}
+
 #[test]
 #[ignore = "Needs LiteLLM running"]
 fn test_chat_text_csv_input_litellm() {
@@ -346,6 +363,57 @@ fn test_ocr_pdftotext() {
         .success();
 }
 
+#[test]
+fn test_ocr_pdftotext_glob() {
+    cmd()
+        .arg("ocr")
+        .arg("--glob")
+        .arg("tests/fixtures/ocr/*.pdf")
+        .arg("--model")
+        .arg("pdftotext")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(r#""id":"two_pages.pdf""#));
+}
+
+#[test]
+fn test_ocr_rejects_flags_for_other_engines() {
+    for (args, message) in [
+        (
+            &["--model", "textract-async", "--rasterize"][..],
+            "--rasterize does not work with --model textract-async",
+        ),
+        (
+            &["--model", "textract-async", "--max-pages", "2"][..],
+            "--max-pages does not work with --model textract-async",
+        ),
+        (
+            &["--model", "pdftotext", "--rasterize"][..],
+            "--rasterize does not work with --model pdftotext",
+        ),
+        (
+            &["--model", "tesseract"][..],
+            "--model tesseract requires --rasterize",
+        ),
+        (
+            &["--model", "pdftotext", "--temperature", "0.5"][..],
+            "--temperature only applies to LLM models",
+        ),
+        (
+            &["--model", "pdftotext", "--textract-mode", "detect"][..],
+            "--textract-mode only applies to --model textract or textract-async",
+        ),
+    ] {
+        cmd()
+            .arg("ocr")
+            .arg("tests/fixtures/ocr/input.csv")
+            .args(args)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains(message));
+    }
+}
+
 #[test]
 fn test_ocr_tesseract() {
     cmd()
@@ -516,3 +584,1407 @@ fn test_chat_echo_driver() {
     assert_eq!(record["status"], "ok");
     assert_eq!(record["response"]["echo"], "Hello world");
 }
+
+#[test]
+fn test_chat_sort_output_by_id_sorts_csv_ids_numerically() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/numeric_ids_input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--sort-output-by", "id"])
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let ids = stdout
+        .lines()
+        .map(|line| {
+            let record: Value = serde_json::from_str(line).expect("Failed to parse JSON");
+            record["id"].clone()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec!["9", "10", "100"]);
+}
+
+#[test]
+fn test_chat_sort_output_by_rejects_run_dir() {
+    // Sorting holds back all output, but checkpoints need it as it's ready.
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--sort-output-by", "id"])
+        .arg("--run-dir")
+        .arg(dir.path().join("run"))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("cannot be used with"));
+}
+
+#[test]
+fn test_chat_logprobs_arguments() {
+    // A bare `--logprobs` must not swallow the input path that follows it.
+    let output = cmd()
+        .arg("chat")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .arg("--logprobs")
+        .arg("tests/fixtures/echo/input.csv")
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    assert_eq!(stdout.lines().count(), 1);
+
+    // Providers only return up to 20 alternatives.
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .arg("--logprobs=21")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("21 is not in 0..=20"));
+}
+
+#[test]
+fn test_chat_echo_driver_prompt_version_and_hash() {
+    use serde_json::Value;
+
+    let run = |prompt: &str| {
+        let output = cmd()
+            .arg("chat")
+            .arg("tests/fixtures/echo/input.csv")
+            .arg("--prompt-inline")
+            .arg(prompt)
+            .args(["--driver", "echo", "--model", "test-model"])
+            .output()
+            .expect("Failed to execute command");
+        if !output.status.success() {
+            eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+        }
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+        serde_json::from_str::<Value>(stdout.trim()).expect("Failed to parse JSON")
+    };
+
+    let prompt = std::fs::read_to_string("tests/fixtures/echo/prompt.toml")
+        .expect("Failed to read prompt");
+    let unversioned = run(&prompt);
+    assert!(unversioned.get("prompt_version").is_none());
+    let hash = unversioned["prompt_hash"]
+        .as_str()
+        .expect("Missing prompt_hash");
+    assert_eq!(hash.len(), 32);
+    assert_eq!(run(&prompt)["prompt_hash"], hash);
+
+    let versioned = run(&format!("version = \"v2\"\n{prompt}"));
+    assert_eq!(versioned["prompt_version"], "v2");
+    assert_ne!(versioned["prompt_hash"], hash);
+}
+
+#[test]
+fn test_chat_echo_driver_model_weights() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args([
+            "--driver",
+            "echo",
+            "--model-weights",
+            "model-a=0.5,model-b=0.5",
+        ])
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let record =
+        serde_json::from_str::<Value>(stdout.trim()).expect("Failed to parse JSON");
+    assert_eq!(record["status"], "ok");
+    assert!(record["model"] == "model-a" || record["model"] == "model-b");
+
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model-weights", "model-a=0"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("expected a positive weight"));
+}
+
+#[test]
+fn test_chat_echo_driver_fallback_model() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "model-a"])
+        .args(["--fallback-model", "model-b"])
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let record =
+        serde_json::from_str::<Value>(stdout.trim()).expect("Failed to parse JSON");
+    assert_eq!(record["status"], "ok");
+    assert_eq!(record["model"], "model-a");
+    assert_eq!(record["fallback_position"], 0);
+}
+
+#[test]
+fn test_chat_echo_driver_hedge_after() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--hedge-after", "0"])
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let record =
+        serde_json::from_str::<Value>(stdout.trim()).expect("Failed to parse JSON");
+    assert_eq!(record["status"], "ok");
+    assert_eq!(record["response"]["echo"], "Hello world");
+    // The echo driver answers at once, so we may or may not have hedged.
+    assert!(record["hedged_requests"].is_null() || record["hedged_requests"] == 1);
+}
+
+#[test]
+fn test_chat_hedge_after_rejects_mcp_servers() {
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt_mcp.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--hedge-after", "0"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("tool calls would run twice"));
+}
+
+#[test]
+fn test_chat_reconcile_spend_requires_tag_support() {
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt_text.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--reconcile-spend", "--reconcile-spend-delay", "0"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("which supports --tag"));
+}
+
+#[test]
+fn test_chat_mcp_tools_must_come_from_their_own_server() {
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/mcp/prompt_wrong_server.toml"])
+        .args(["--driver", "openai", "--model", "test-model"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "MCP server \"search\" has no tool named \"lookup\"",
+        ));
+}
+
+#[test]
+fn test_chat_echo_driver_text_response() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt_text.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let record: Value =
+        serde_json::from_str(stdout.trim()).expect("Failed to parse JSON");
+    assert_eq!(record["status"], "ok");
+    assert_eq!(record["response"], "Summarize: Hello world");
+}
+
+#[test]
+fn test_chat_echo_driver_classify_csv() {
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt_classify.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--output-format", "csv"])
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines, vec!["id,status,errors,label", "1,ok,,greeting"]);
+
+    // Other prompts can't be written as CSV.
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--output-format", "csv"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("`classify` prompts"));
+}
+
+#[test]
+fn test_chat_echo_driver_field_confidence() {
+    use serde_json::Value;
+
+    let run = |min_confidence: &str| {
+        let output = cmd()
+            .arg("chat")
+            .arg("tests/fixtures/echo/input.csv")
+            .args(["--prompt", "tests/fixtures/echo/prompt_confidence.toml"])
+            .args(["--driver", "echo", "--model", "test-model"])
+            .args(["--min-confidence", min_confidence])
+            .output()
+            .expect("Failed to execute command");
+        if !output.status.success() {
+            eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+        }
+        let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+        serde_json::from_str::<Value>(stdout.trim()).expect("Failed to parse JSON")
+    };
+
+    let record = run("0.5");
+    assert_eq!(record["status"], "ok");
+    assert_eq!(record["response"]["echo"], "Hello world");
+    assert_eq!(record["response"]["echo_confidence"], 1.0);
+
+    // The echo driver is always certain, so we need an impossible threshold.
+    let record = run("1.5");
+    assert_eq!(record["status"], "incomplete");
+    assert_eq!(record["response"]["echo"], "Hello world");
+    assert!(
+        record["errors"][0]
+            .as_str()
+            .unwrap()
+            .starts_with("low_confidence: echo (1)")
+    );
+}
+
+#[test]
+fn test_chat_echo_driver_cite_source() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/cite_input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt_cite.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--allowed-failure-rate", "0.5"])
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+
+    // Quotes may differ from the source in case and whitespace.
+    assert_eq!(records[0]["status"], "ok");
+    assert_eq!(records[0]["response"]["echo_quote"], "Hello world");
+
+    // But they must actually appear in it.
+    assert_eq!(records[1]["status"], "failed");
+    assert!(records[1]["response"].is_null());
+    assert_eq!(
+        records[1]["errors"][0],
+        "hallucinated_citation: echo: \"Goodbye world\" not found in source"
+    );
+}
+
+#[test]
+fn test_chat_echo_driver_include_raw_response() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/cite_input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt_cite.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--include-raw-response=10", "--allowed-failure-rate", "0.5"])
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+
+    // Failed records keep the response we rejected.
+    for record in &records {
+        let raw_response = &record["raw_response"];
+        assert_eq!(raw_response["text"], "{\"echo_quo");
+        assert_eq!(raw_response["truncated"], true);
+        assert_eq!(raw_response["finish_reason"], "stop");
+    }
+    assert_eq!(records[1]["status"], "failed");
+}
+
+#[test]
+fn test_chat_echo_driver_webhook() {
+    use std::{
+        io::{BufRead as _, BufReader, Read as _, Write as _},
+        net::TcpListener,
+        thread,
+    };
+
+    use serde_json::Value;
+
+    // Accept a single webhook request, and return its headers and body.
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("Failed to accept");
+        let mut reader = BufReader::new(stream);
+        let mut headers = vec![];
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).expect("Failed to read header");
+            if line.trim().is_empty() {
+                break;
+            }
+            headers.push(line.trim().to_ascii_lowercase());
+        }
+        let content_length = headers
+            .iter()
+            .find_map(|h| h.strip_prefix("content-length: "))
+            .and_then(|len| len.parse::<usize>().ok())
+            .expect("Missing Content-Length");
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).expect("Failed to read body");
+        reader
+            .get_mut()
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            )
+            .expect("Failed to respond");
+        (headers, body)
+    });
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--webhook-url", &url, "--webhook-batch-size", "10"])
+        .args(["--webhook-secret-env", "PROMPT_SCALER_TEST_WEBHOOK_SECRET"])
+        .env("PROMPT_SCALER_TEST_WEBHOOK_SECRET", "test-secret")
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let (headers, body) = server.join().expect("Webhook server panicked");
+    assert!(headers.iter().any(|h| h.starts_with("post /hook ")));
+    assert!(
+        headers
+            .iter()
+            .any(|h| h.starts_with("x-prompt-scaler-signature: sha256="))
+    );
+    let body = serde_json::from_slice::<Value>(&body).expect("Failed to parse JSON");
+    let records = body["records"].as_array().expect("Missing records");
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["status"], "ok");
+}
+
+#[test]
+fn test_chat_echo_driver_output_mode() {
+    let out = NamedTempFile::new().expect("Failed to create temp file");
+    std::fs::write(out.path(), "{\"id\":0,\"status\":\"ok\"}\n").unwrap();
+    let run = |mode: Option<&str>| {
+        let mut cmd = cmd();
+        cmd.arg("chat")
+            .arg("tests/fixtures/echo/input.csv")
+            .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+            .args(["--driver", "echo", "--model", "test-model"])
+            .arg("--out")
+            .arg(out.path());
+        if let Some(mode) = mode {
+            cmd.args(["--output-mode", mode]);
+        }
+        cmd.assert()
+    };
+
+    // By default, we refuse to replace existing output.
+    run(None)
+        .failure()
+        .stderr(predicates::str::contains("already exists"));
+
+    run(Some("append")).success();
+    let output = std::fs::read_to_string(out.path()).unwrap();
+    assert_eq!(output.lines().count(), 2);
+
+    run(Some("overwrite")).success();
+    let output = std::fs::read_to_string(out.path()).unwrap();
+    assert_eq!(output.lines().count(), 1);
+}
+
+#[test]
+fn test_chat_echo_driver_named_prompts() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "a=tests/fixtures/echo/prompt.toml"])
+        .args(["--prompt", "b=tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let mut records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    records.sort_by_key(|record| record["prompt_name"].to_string());
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["prompt_name"], "a");
+    assert_eq!(records[1]["prompt_name"], "b");
+    for record in &records {
+        assert_eq!(record["id"], "1");
+        assert_eq!(record["status"], "ok");
+    }
+
+    // Mixing named and unnamed prompts is an error.
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "a=tests/fixtures/echo/prompt.toml"])
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("every --prompt needs a name"));
+
+    // Checkpoints track records by input ID, so they can't resume these.
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "a=tests/fixtures/echo/prompt.toml"])
+        .args(["--prompt", "b=tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .arg("--run-dir")
+        .arg(dir.path().join("run"))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "Named prompts cannot be used with --run-dir",
+        ));
+}
+
+#[test]
+fn test_chat_echo_driver_prompt_from_stdin_or_inline() {
+    use serde_json::Value;
+
+    let prompt = std::fs::read_to_string("tests/fixtures/echo/prompt.toml")
+        .expect("Failed to read prompt");
+    let from_stdin = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "-"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .write_stdin(prompt.clone())
+        .output()
+        .expect("Failed to execute command");
+    let inline = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .arg("--prompt-inline")
+        .arg(&prompt)
+        .args(["--driver", "echo", "--model", "test-model"])
+        .output()
+        .expect("Failed to execute command");
+    for output in [from_stdin, inline] {
+        if !output.status.success() {
+            eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+        }
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+        let record: Value =
+            serde_json::from_str(stdout.trim()).expect("Failed to parse JSON");
+        assert_eq!(record["response"]["echo"], "Hello world");
+    }
+
+    // Standard input can't hold both the prompt and the input records.
+    cmd()
+        .arg("chat")
+        .args(["--prompt", "-"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .write_stdin(prompt)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "input records must come from a file",
+        ));
+}
+
+#[test]
+fn test_chat_echo_driver_yaml_stdin() {
+    use serde_json::Value;
+
+    let yaml =
+        "- id: 1\n  message: &greeting Hello world\n- id: 2\n  message: *greeting\n";
+    let output = cmd()
+        .arg("chat")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .arg("--yaml-stdin")
+        .write_stdin(yaml)
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    for record in records {
+        assert_eq!(record["response"]["echo"], "Hello world");
+    }
+}
+
+#[test]
+fn test_chat_echo_driver_per_record_response_schema() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/response_schema_input.jsonl")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+
+    // A bad schema only fails the record which uses it.
+    assert_eq!(records[0]["id"], "default");
+    assert_eq!(records[0]["status"], "ok");
+    assert_eq!(records[1]["id"], "inline");
+    assert_eq!(records[1]["status"], "ok");
+    assert_eq!(records[1]["response"]["echo"], "Hello");
+    assert_eq!(records[2]["id"], "missing");
+    assert_eq!(records[2]["status"], "failed");
+}
+
+#[test]
+fn test_chat_echo_driver_assertions() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/assert_input.csv")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt_assert.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["status"], "ok");
+    assert_eq!(records[1]["status"], "failed");
+    assert!(
+        records[1]["errors"][0]
+            .as_str()
+            .unwrap()
+            .contains("assertion_failed")
+    );
+    assert_eq!(records[2]["status"], "ok");
+}
+
+#[test]
+fn test_chat_echo_driver_max_record_bytes() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/assert_input.csv")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .arg("--max-record-bytes")
+        .arg("30")
+        .arg("--allowed-failure-rate")
+        .arg("0.5")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["status"], "ok");
+    assert_eq!(records[1]["status"], "failed");
+    assert!(
+        records[1]["errors"][0]
+            .as_str()
+            .unwrap()
+            .starts_with("record_too_large")
+    );
+    assert_eq!(records[2]["status"], "ok");
+}
+
+#[test]
+fn test_chat_echo_driver_prompt_router() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/router_input.csv")
+        .arg("--prompt-router")
+        .arg("tests/fixtures/echo/router.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["response"]["echo"], "Hello!");
+    assert_eq!(records[1]["response"]["echo"], "Hello");
+    assert_eq!(records[2]["response"]["echo"], "Hello");
+}
+
+#[test]
+fn test_chat_echo_driver_column_mappings() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/mapped_input.csv")
+        .arg("--map")
+        .arg("id=document_id")
+        .arg("--map")
+        .arg("message=text")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let record: Value =
+        serde_json::from_str(stdout.trim()).expect("Failed to parse JSON");
+    assert_eq!(record["id"], "1");
+    assert_eq!(record["response"]["echo"], "Hello world");
+}
+
+#[test]
+fn test_chat_echo_driver_passthrough_columns() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/mapped_input.csv")
+        .arg("--map")
+        .arg("id=document_id")
+        .arg("--map")
+        .arg("message=text")
+        .arg("--passthrough-columns")
+        .arg("document_id,text")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let record: Value =
+        serde_json::from_str(stdout.trim()).expect("Failed to parse JSON");
+    assert_eq!(record["response"]["echo"], "Hello world");
+    assert_eq!(record["passthrough_data"]["document_id"], "1");
+    assert_eq!(record["passthrough_data"]["text"], "Hello world");
+}
+
+#[test]
+fn test_chat_echo_driver_omit_response_fields() {
+    use serde_json::{Value, json};
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .arg("--omit-response-fields")
+        .arg("echo")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    for line in stdout.lines() {
+        let record: Value = serde_json::from_str(line).expect("Failed to parse JSON");
+        assert_eq!(record["status"], "ok");
+        assert_eq!(record["response"], json!({}));
+    }
+}
+
+#[test]
+fn test_chat_echo_driver_invariants() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/invariant_input.csv")
+        .arg("--invariant")
+        .arg("echo=expected_echo")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    // Mismatches are warnings by default, and records without an expected
+    // value aren't checked.
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+    for record in &records {
+        assert_eq!(record["status"], "ok");
+        let errors = record["errors"].as_array().unwrap();
+        if record["id"] == "2" {
+            assert_eq!(errors.len(), 1);
+            assert!(
+                errors[0]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("invariant_failed: echo is \"Goodbye world\"")
+            );
+        } else {
+            assert!(errors.is_empty(), "unexpected errors: {errors:?}");
+        }
+    }
+}
+
+#[test]
+fn test_chat_echo_driver_id_from() {
+    use serde_json::Value;
+
+    let manifest = NamedTempFile::new().expect("Failed to create temp file");
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/no_id_input.csv")
+        .arg("--id-from")
+        .arg("row-number")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .arg("--manifest")
+        .arg(manifest.path())
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["id"], 1);
+    assert_eq!(records[1]["id"], 2);
+    assert_eq!(records[1]["response"]["echo"], "Goodbye world");
+
+    let manifest: Value = serde_json::from_str(
+        &std::fs::read_to_string(manifest.path()).expect("Failed to read manifest"),
+    )
+    .expect("Failed to parse manifest");
+    assert_eq!(manifest["id_from"], "row-number");
+}
+
+#[test]
+fn test_chat_echo_driver_manifest_tags() {
+    use serde_json::Value;
+
+    let manifest = NamedTempFile::new().expect("Failed to create temp file");
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .arg("--tag")
+        .arg("client=acme")
+        .arg("--tag")
+        .arg("matter=1234")
+        .arg("--manifest")
+        .arg(manifest.path())
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let manifest: Value = serde_json::from_str(
+        &std::fs::read_to_string(manifest.path()).expect("Failed to read manifest"),
+    )
+    .expect("Failed to parse manifest");
+    assert_eq!(manifest["command"], "chat");
+    assert_eq!(manifest["model"], "test-model");
+    assert_eq!(manifest["tags"]["client"], "acme");
+    assert_eq!(manifest["tags"]["matter"], "1234");
+}
+
+#[test]
+fn test_schema_list() {
+    let output = cmd()
+        .arg("schema")
+        .arg("--list")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let names = stdout.lines().collect::<Vec<_>>();
+    assert!(names.contains(&"ChatInput"));
+    assert!(names.contains(&"RunSummary"));
+}
+
+#[test]
+fn test_schema_chat_output_for_prompt() {
+    use serde_json::Value;
+
+    let passthrough = NamedTempFile::new().expect("Failed to create temp file");
+    std::fs::write(
+        passthrough.path(),
+        r#"{ "type": "object", "properties": { "customer": { "type": "string" } } }"#,
+    )
+    .expect("Failed to write passthrough schema");
+    let output = cmd()
+        .arg("schema")
+        .arg("ChatOutput")
+        .arg("--prompt")
+        .arg("tests/fixtures/texts/prompt.toml")
+        .arg("--passthrough-schema")
+        .arg(passthrough.path())
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let schema: Value =
+        serde_json::from_slice(&output.stdout).expect("Failed to parse schema");
+    let properties = &schema["properties"];
+    assert!(properties["response"]["properties"]["punchline"].is_object());
+    assert_eq!(
+        properties["passthrough_data"]["properties"]["customer"]["type"],
+        "string"
+    );
+    assert!(properties["status"].is_object());
+}
+
+#[test]
+fn test_tokens() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("tokens")
+        .arg("tests/fixtures/texts/input.jsonl")
+        .arg("--prompt")
+        .arg("tests/fixtures/texts/prompt.toml")
+        .arg("--model")
+        .arg("gpt-4o-mini")
+        .arg("--model")
+        .arg("claude-3-5-haiku-20241022")
+        .arg("--limit")
+        .arg("1")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let rows = String::from_utf8(output.stdout)
+        .expect("Output is not valid UTF-8")
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse row"))
+        .collect::<Vec<_>>();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["id"], "road");
+    assert_eq!(rows[0]["family"], "open_ai");
+    assert_eq!(rows[0]["exact_text"], true);
+    assert_eq!(rows[1]["family"], "anthropic");
+    assert!(rows[1]["total_tokens"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn test_chat_echo_driver_constants_and_env() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt_constants.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .env("PROMPT_VAR_SUFFIX", "?")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let record: Value =
+        serde_json::from_str(stdout.trim()).expect("Failed to parse JSON");
+    assert_eq!(record["response"]["echo"], "Echo: Hello world?");
+}
+
+#[test]
+fn test_chat_echo_driver_input_schema_fails_fast() {
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/assert_input.csv")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt_input_schema.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("input_schema"));
+    assert!(stderr.contains(r#"missing column "priority""#));
+}
+
+#[test]
+fn test_chat_echo_driver_input_schema_checked_with_run_dir() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/assert_input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt_input_schema.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .arg("--run-dir")
+        .arg(dir.path().join("run"))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(r#"missing column "priority""#));
+}
+
+#[test]
+fn test_chat_echo_driver_render_failure_fails_one_record() {
+    use serde_json::Value;
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/file_input.csv")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt_file.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .arg("--allowed-failure-rate")
+        .arg("0.5")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let records = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["status"], "ok");
+    assert_eq!(records[1]["status"], "failed");
+    let error = records[1]["errors"][0].as_str().unwrap();
+    assert!(error.starts_with("render_failed:"));
+    assert!(error.contains("does_not_exist.txt"));
+    assert_eq!(records[2]["status"], "ok");
+}
+
+#[test]
+fn test_chat_echo_driver_summary_json() {
+    use serde_json::Value;
+
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let summary_path = dir.path().join("summary.json");
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/assert_input.csv")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .arg("--summary-json")
+        .arg(&summary_path)
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let summary: Value = serde_json::from_str(
+        &std::fs::read_to_string(&summary_path).expect("Failed to read summary"),
+    )
+    .expect("Failed to parse summary");
+    assert_eq!(summary["passed"], true);
+    assert_eq!(summary["total_record_count"], 3);
+    assert_eq!(summary["failure_count"], 0);
+    assert_eq!(summary["by_status"]["ok"]["record_count"], 3);
+    assert_eq!(summary["by_model"]["test-model"]["record_count"], 3);
+    assert!(summary["duration_seconds"].as_f64().unwrap() >= 0.0);
+}
+
+#[test]
+fn test_chat_echo_driver_run_dir_resume() {
+    use serde_json::Value;
+
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let run_dir = dir.path().join("run");
+    let run = || {
+        cmd()
+            .arg("chat")
+            .arg("tests/fixtures/echo/assert_input.csv")
+            .arg("--prompt")
+            .arg("tests/fixtures/echo/prompt.toml")
+            .arg("--driver")
+            .arg("echo")
+            .arg("--model")
+            .arg("test-model")
+            .arg("--run-dir")
+            .arg(&run_dir)
+            .arg("--checkpoint-interval")
+            .arg("1")
+            .output()
+            .expect("Failed to execute command")
+    };
+    let output = run();
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+    for name in ["command.json", "manifest.json", "summary.json"] {
+        assert!(run_dir.join(name).exists(), "missing {name}");
+    }
+
+    // We won't start over in a directory which already contains a run.
+    assert!(!run().status.success());
+
+    // Pretend we were killed after the first record, part way through writing
+    // the second.
+    let first_line = |name: &str| {
+        let path = run_dir.join(name);
+        let text = std::fs::read_to_string(&path).expect("Failed to read file");
+        assert_eq!(text.lines().count(), 3, "{name}");
+        let mut lines = text.lines();
+        let first = lines.next().unwrap();
+        let second = lines.next().unwrap();
+        let partial = format!("{first}\n{}", &second[..second.len() / 2]);
+        std::fs::write(&path, partial).expect("Failed to write file");
+    };
+    first_line("output.jsonl");
+    first_line("checkpoint.jsonl");
+
+    let output = cmd()
+        .arg("resume")
+        .arg(&run_dir)
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let text =
+        std::fs::read_to_string(run_dir.join("output.jsonl")).expect("Failed to read");
+    let ids = text
+        .lines()
+        .map(|line| {
+            let record: Value = serde_json::from_str(line).expect("Failed to parse JSON");
+            record["id"].as_str().unwrap().to_owned()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec!["1", "2", "3"]);
+    let checkpoint = std::fs::read_to_string(run_dir.join("checkpoint.jsonl"))
+        .expect("Failed to read checkpoint");
+    assert_eq!(checkpoint.lines().count(), 3);
+}
+
+#[test]
+fn test_doctor_echo_driver() {
+    use serde_json::Value;
+
+    // External tools may be missing here, so don't insist on success.
+    let output = cmd()
+        .arg("doctor")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    let checks = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).expect("Failed to parse JSON"))
+        .collect::<Vec<_>>();
+    let driver_check = checks
+        .iter()
+        .find(|check| check["check"] == "echo driver")
+        .expect("No driver check");
+    assert_eq!(driver_check["status"], "pass");
+    assert!(checks.iter().any(|check| check["check"] == "pdftocairo"));
+}
+
+#[test]
+fn test_chat_echo_driver_placeholder_response() {
+    use serde_json::{Value, json};
+
+    let output = cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .arg("--prompt")
+        .arg("tests/fixtures/echo/prompt_placeholder.toml")
+        .arg("--driver")
+        .arg("echo")
+        .arg("--model")
+        .arg("test-model")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        eprintln!("STDERR:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).expect("Invalid UTF-8");
+    for line in stdout.lines() {
+        let record: Value = serde_json::from_str(line).expect("Failed to parse JSON");
+        assert_eq!(record["status"], "ok");
+        assert_eq!(
+            record["response"],
+            json!({ "summary": "x", "word_count": 0 })
+        );
+    }
+}
+
+#[test]
+fn test_chat_email_input_rejects_run_dir() {
+    // Attachments are written under child IDs, which our checkpoint can't
+    // match up with their parent email when resuming.
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .arg("--email-input")
+        .arg("--run-dir")
+        .arg(dir.path().join("run"))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "--email-input cannot be used with --run-dir",
+        ));
+}
+
+#[test]
+fn test_chat_max_cost_per_record_requires_pricing() {
+    // Without LiteLLM pricing, we can't enforce the cap, so don't pretend to.
+    cmd()
+        .arg("chat")
+        .arg("tests/fixtures/echo/input.csv")
+        .args(["--prompt", "tests/fixtures/echo/prompt.toml"])
+        .args(["--driver", "echo", "--model", "test-model"])
+        .args(["--max-cost-per-record", "0.50"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "--max-cost-per-record needs pricing from LiteLLM",
+        ));
+}
@@ -0,0 +1,9 @@
+#!/bin/sh
+# A fake MCP server for tests, which offers a single tool named by its first
+# argument. It answers our requests in the order we send them.
+read -r line
+echo '{"jsonrpc":"2.0","id":0,"result":{"protocolVersion":"2025-06-18","capabilities":{},"serverInfo":{"name":"fake","version":"1"}}}'
+read -r line
+read -r line
+echo "{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":{\"tools\":[{\"name\":\"$1\",\"inputSchema\":{\"type\":\"object\"}}]}}"
+while read -r line; do :; done